@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN priority BIGINT CHECK (priority >= 0) NOT NULL DEFAULT 0;
+UPDATE order_slice SET priority = id;
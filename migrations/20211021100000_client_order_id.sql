@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE order_slice ADD COLUMN client_order_id BIGINT CHECK (client_order_id >= 0);
+ALTER TABLE order_history ADD COLUMN client_order_id BIGINT CHECK (client_order_id >= 0);
+
+CREATE INDEX order_history_idx_user_client_order_id ON order_history (user_id, client_order_id);
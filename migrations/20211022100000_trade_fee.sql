@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE trade_fee (
+    time TIMESTAMP(0) NOT NULL,
+    trade_id BIGINT CHECK (trade_id >= 0) NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    order_id BIGINT CHECK (order_id >= 0) NOT NULL,
+    role SMALLINT CHECK (role >= 0) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    amount DECIMAL(30, 16) NOT NULL,
+    rate DECIMAL(30, 16) NOT NULL
+);
+
+CREATE INDEX trade_fee_idx_trade_id ON trade_fee (trade_id);
+CREATE INDEX trade_fee_idx_user_time ON trade_fee (user_id, time DESC);
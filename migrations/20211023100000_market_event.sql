@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE market_event (
+    time TIMESTAMP(0) NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    event VARCHAR(30) NOT NULL,
+    detail TEXT NOT NULL
+);
+
+CREATE INDEX market_event_idx_market_time ON market_event (market, time DESC);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE lock_slice (
+    slice_id BIGINT NOT NULL,
+    lock_id BIGINT CHECK (lock_id >= 0) NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    asset VARCHAR(30) NOT NULL,
+    amount DECIMAL(30, 16) NOT NULL,
+    PRIMARY KEY (slice_id, lock_id)
+);
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE order_history ADD COLUMN finish_reason VARCHAR(30);
+ALTER TABLE order_history ADD COLUMN finish_actor VARCHAR(10);
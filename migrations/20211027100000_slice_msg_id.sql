@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE slice_history ADD COLUMN end_msg_id BIGINT CHECK (end_msg_id >= 0) NOT NULL DEFAULT 0;
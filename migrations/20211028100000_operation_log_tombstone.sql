@@ -0,0 +1,8 @@
+-- the logged operations superseded by a rollback, skipped by the replays
+CREATE TABLE operation_log_tombstone (
+    time TIMESTAMP(0) NOT NULL,
+    from_id BIGINT NOT NULL,
+    to_id BIGINT NOT NULL,
+    end_order_id BIGINT NOT NULL,
+    end_trade_id BIGINT NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE nonce_slice (
+    slice_id BIGINT NOT NULL,
+    user_id INT CHECK (user_id >= 0) NOT NULL,
+    nonce BIGINT CHECK (nonce >= 0) NOT NULL,
+    PRIMARY KEY (slice_id, user_id)
+);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE operation_log ADD COLUMN failed BOOLEAN NOT NULL DEFAULT false;
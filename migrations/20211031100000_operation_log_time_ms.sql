@@ -0,0 +1,3 @@
+-- Add migration script here
+-- the operations are replayed at the time they are logged with, to the millisecond
+ALTER TABLE operation_log ALTER COLUMN time TYPE TIMESTAMP(3);
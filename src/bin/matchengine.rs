@@ -5,6 +5,7 @@
 #![allow(clippy::single_char_pattern)]
 //#![allow(clippy::await_holding_refcell_ref)] // FIXME
 
+use dingir_exchange::asset::HistoryDedupStore;
 use dingir_exchange::config;
 use dingir_exchange::controller::create_controller;
 use dingir_exchange::persist;
@@ -48,11 +49,53 @@ async fn prepare() -> anyhow::Result<GrpcHandler> {
         persist::MarketConfigs::new()
     };
 
+    // how far the history and kafka got, read before the wal is taken by the producer
+    let watermarks = if matches!(
+        settings.history_persist_policy,
+        config::PersistPolicy::Both | config::PersistPolicy::ToDB
+    ) {
+        let mut history_conn = ConnectionType::connect(&settings.db_history)
+            .await
+            .expect(&*format!("cannot connect to db at {}", settings.db_history));
+        let persisted = persist::load_persisted_ids(&mut conn, &mut history_conn).await?;
+        let mut watermarks = persist::Watermarks::new(persisted);
+        if !settings.kafka_wal.is_empty() {
+            watermarks = watermarks.with_wal(&settings.kafka_wal)?;
+        }
+        Some(watermarks)
+    } else {
+        None
+    };
+
     let mut grpc_stub = create_controller((settings.clone(), market_cfg));
     log::info!("grpc_stub created");
     grpc_stub.user_manager.load_users_from_db(&mut conn).await?;
-    persist::init_from_db(&mut conn, &mut grpc_stub).await?;
+    match &watermarks {
+        Some(watermarks) => {
+            persist::init_reconciled_from_db(&mut conn, watermarks, &mut grpc_stub).await?;
+            // the ids in the history must not be issued again, before any traffic is accepted
+            grpc_stub.sequencer.recover(&watermarks.db);
+        }
+        None => persist::init_from_db(&mut conn, &mut grpc_stub).await?,
+    }
     log::info!("init from db done");
+    // after the replay, which would be rejected as duplicates otherwise
+    if settings.balance_dedup.warm_hours > 0 {
+        let mut history_conn = ConnectionType::connect(&settings.db_history)
+            .await
+            .expect(&*format!("cannot connect to db at {}", settings.db_history));
+        let store = HistoryDedupStore::load(&mut history_conn, &settings.balance_dedup).await?;
+        grpc_stub.update_controller.set_store(Box::new(store));
+    }
+    #[cfg(feature = "metrics")]
+    if !settings.metrics_listen.is_empty() {
+        let addr = settings.metrics_listen.parse()?;
+        tokio::spawn(async move {
+            if let Err(e) = dingir_exchange::metrics::serve(addr).await {
+                log::error!("metrics server failed: {}", e);
+            }
+        });
+    }
     let grpc = GrpcHandler::new(grpc_stub, settings);
     Ok(grpc)
 }
@@ -65,6 +65,11 @@ fn main() {
 
         let persistor_user: DatabaseWriter<models::AccountDesc> = DatabaseWriter::new(&write_config).start_schedule(&pool).unwrap();
 
+        let persistor_fee: DatabaseWriter<models::TradeFee> = DatabaseWriter::new(&write_config).start_schedule(&pool).unwrap();
+
+        let persistor_market_event: DatabaseWriter<models::MarketEventHistory> =
+            DatabaseWriter::new(&write_config).start_schedule(&pool).unwrap();
+
         let trade_cfg = TopicConfig::<message::Trade>::new(message::TRADES_TOPIC)
             .persist_to(&persistor_kline)
             .persist_to(&persistor_trade)
@@ -82,12 +87,18 @@ fn main() {
 
         let user_cfg = TopicConfig::<message::UserMessage>::new(message::USER_TOPIC).persist_to(&persistor_user);
 
+        let fee_cfg = TopicConfig::<message::TradeFeeRecord>::new(message::FEES_TOPIC).persist_to(&persistor_fee);
+
+        let market_event_cfg = TopicConfig::<message::MarketEvent>::new(message::MARKETS_TOPIC).persist_to(&persistor_market_event);
+
         let auto_commit = vec![
             trade_cfg.auto_commit_start(consumer.clone()),
             order_cfg.auto_commit_start(consumer.clone()),
             balance_cfg.auto_commit_start(consumer.clone()),
             internaltx_cfg.auto_commit_start(consumer.clone()),
             user_cfg.auto_commit_start(consumer.clone()),
+            fee_cfg.auto_commit_start(consumer.clone()),
+            market_event_cfg.auto_commit_start(consumer.clone()),
         ];
         let consumer = consumer.as_ref();
 
@@ -98,6 +109,8 @@ fn main() {
                 .add_topic_config(&balance_cfg).unwrap()
                 .add_topic_config(&internaltx_cfg).unwrap()
                 .add_topic_config(&user_cfg).unwrap()
+                .add_topic_config(&fee_cfg).unwrap()
+                .add_topic_config(&market_event_cfg).unwrap()
 //                .add_topic(message::TRADES_TOPIC, MsgDataPersistor::new(&persistor).handle_message::<message::Trade>())
                 ;
 
@@ -120,6 +133,8 @@ fn main() {
             persistor_balance.finish(),
             persistor_transfer.finish(),
             persistor_user.finish(),
+            persistor_fee.finish(),
+            persistor_market_event.finish(),
         )
         .expect("all persistor should success finish");
         let final_commits: Vec<Pin<Box<dyn std::future::Future<Output = ()> + Send>>> = auto_commit
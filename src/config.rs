@@ -1,8 +1,10 @@
 use config_rs::{Config, File};
-use fluidex_common::rust_decimal::Decimal;
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::{Decimal, RoundingStrategy};
 use paperclip::actix::Apiv2Schema;
 use serde::de;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default, Apiv2Schema)]
@@ -26,6 +28,61 @@ pub struct MarketUnit {
     pub prec: u32,
 }
 
+// how the fee of a trade is rounded to the precision of the asset
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub enum FeeRounding {
+    ToZero,
+    AwayFromZero,
+    MidpointNearestEven,
+}
+
+impl Default for FeeRounding {
+    fn default() -> Self {
+        FeeRounding::ToZero
+    }
+}
+
+impl From<FeeRounding> for RoundingStrategy {
+    fn from(rounding: FeeRounding) -> Self {
+        match rounding {
+            FeeRounding::ToZero => RoundingStrategy::ToZero,
+            FeeRounding::AwayFromZero => RoundingStrategy::AwayFromZero,
+            FeeRounding::MidpointNearestEven => RoundingStrategy::MidpointNearestEven,
+        }
+    }
+}
+
+// the periods of the candles built inside the engine
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Apiv2Schema)]
+pub enum KlineInterval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl KlineInterval {
+    pub const ALL: [KlineInterval; 4] = [
+        KlineInterval::OneMinute,
+        KlineInterval::FiveMinutes,
+        KlineInterval::OneHour,
+        KlineInterval::OneDay,
+    ];
+
+    pub fn seconds(self) -> u64 {
+        match self {
+            KlineInterval::OneMinute => 60,
+            KlineInterval::FiveMinutes => 300,
+            KlineInterval::OneHour => 3600,
+            KlineInterval::OneDay => 86400,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Apiv2Schema)]
 #[serde(default)]
 pub struct Market {
@@ -36,6 +93,40 @@ pub struct Market {
     pub price_prec: u32,
     pub fee_prec: u32,
     pub min_amount: Decimal,
+    // max base amount of an order
+    pub max_amount: Option<Decimal>,
+    // max quote value of an order
+    pub max_quote_amount: Option<Decimal>,
+    // min quote value of an order, zero means no limit
+    pub min_notional: Decimal,
+    // reprice crossing post only orders to one tick away from the best counter price rather than cancel them
+    pub post_only_reprice: bool,
+    // finish resting orders whose remain drops below the tradable minimum after partial fills
+    pub finish_dust_orders: bool,
+    // max relative deviation from the last trade price, e.g. 0.2 means 20%
+    pub max_price_deviation: Option<Decimal>,
+    // overrides `Settings::max_open_orders_per_user` for this market
+    pub max_open_orders_per_user: Option<usize>,
+    // fees of the orders placed without fees, see `Settings::use_market_default_fees`
+    pub default_maker_fee: Decimal,
+    pub default_taker_fee: Decimal,
+    pub fee_rounding: FeeRounding,
+    // the least fee of a trade paying a positive fee, charged in the base asset from the bid
+    // and in the quote asset from the ask
+    pub min_base_fee: Option<Decimal>,
+    pub min_quote_fee: Option<Decimal>,
+    pub kline_intervals: Vec<KlineInterval>,
+    // how many candles are kept in memory for each interval
+    pub kline_history: usize,
+    // how many recent trades are kept in memory
+    pub recent_trades: usize,
+    // how many levels of each side the orderbook checksum covers
+    pub checksum_levels: usize,
+    // whether orders can still be canceled when the market is halted
+    pub cancel_when_halted: bool,
+    // other names routed to this market, e.g. the old name after a rename.
+    // the events and the history always carry `name`
+    pub aliases: Vec<String>,
 }
 
 impl Default for MarketUnit {
@@ -57,6 +148,24 @@ impl Default for Market {
             quote: Default::default(),
             amount_prec: 0,
             price_prec: 0,
+            max_amount: None,
+            max_quote_amount: None,
+            min_notional: Decimal::zero(),
+            post_only_reprice: false,
+            finish_dust_orders: false,
+            max_price_deviation: None,
+            max_open_orders_per_user: None,
+            default_maker_fee: Decimal::zero(),
+            default_taker_fee: Decimal::zero(),
+            fee_rounding: FeeRounding::ToZero,
+            min_base_fee: None,
+            min_quote_fee: None,
+            kline_intervals: KlineInterval::ALL.to_vec(),
+            kline_history: 1000,
+            recent_trades: 100,
+            checksum_levels: 25,
+            cancel_when_halted: false,
+            aliases: Vec::new(),
         }
     }
 }
@@ -89,6 +198,18 @@ pub enum OrderSignatrueCheck {
     // auto means check sig only if sig != ""
     Auto,
     Needed,
+    // checked like needed, but an invalid sig is only logged. for the migration of the clients
+    LogOnly,
+}
+
+impl OrderSignatrueCheck {
+    pub fn verifies(self, signature: &str) -> bool {
+        match self {
+            OrderSignatrueCheck::None => false,
+            OrderSignatrueCheck::Auto => !signature.is_empty(),
+            OrderSignatrueCheck::Needed | OrderSignatrueCheck::LogOnly => true,
+        }
+    }
 }
 
 impl<'de> de::Deserialize<'de> for OrderSignatrueCheck {
@@ -99,11 +220,116 @@ impl<'de> de::Deserialize<'de> for OrderSignatrueCheck {
             "true" => Ok(OrderSignatrueCheck::Needed),
             "false" => Ok(OrderSignatrueCheck::None),
             "auto" => Ok(OrderSignatrueCheck::Auto),
+            "log_only" => Ok(OrderSignatrueCheck::LogOnly),
             _ => Err(serde::de::Error::custom("unexpected specification for order sig check policy")),
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct FeeTier {
+    pub maker_fee: Decimal,
+    pub taker_fee: Decimal,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct UserFeeTier {
+    pub user_id: u32,
+    pub maker_fee: Decimal,
+    pub taker_fee: Decimal,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Default)]
+#[serde(default)]
+pub struct FeeDiscountSettings {
+    // the asset the opted in takers pay their fees in
+    pub asset: String,
+    // the ratio taken off the fee, e.g. 0.25 for 25% off
+    pub discount: Decimal,
+    // fee asset -> price in the discount asset, used when no market of the discount asset prices it
+    pub prices: HashMap<String, Decimal>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Default)]
+#[serde(default)]
+pub struct FeeSettings {
+    // determine the fees inside the engine, otherwise the fees of the order input are used
+    pub enabled: bool,
+    pub default_tier: FeeTier,
+    pub user_tiers: Vec<UserFeeTier>,
+    pub discount: Option<FeeDiscountSettings>,
+}
+
+// the processed balance updates are remembered to reject the duplicates
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BalanceDedupSettings {
+    // seconds an update is remembered
+    pub ttl: f64,
+    pub capacity: usize,
+    // seconds an update is remembered at least, even when there are more than `capacity` updates
+    pub min_age: f64,
+    // the deposits and withdraws of the last hours in the balance history are remembered on start, zero to disable
+    pub warm_hours: u32,
+}
+
+impl Default for BalanceDedupSettings {
+    fn default() -> Self {
+        BalanceDedupSettings {
+            ttl: 3600.0,
+            capacity: 1_000_000,
+            min_age: 300.0,
+            warm_hours: 0,
+        }
+    }
+}
+
+// the output file of the file persistor, used when there are no brokers
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct FilePersistSettings {
+    // the current segment is rotated once it reaches the size, zero to disable
+    pub max_bytes: u64,
+    // rotate the current segment when the hour changes
+    pub hourly: bool,
+    // compress the rotated segments
+    pub gzip: bool,
+    pub buffer_bytes: usize,
+    // seconds the written messages may stay in the buffer
+    pub flush_interval: f64,
+    // messages kept while the file can not be written, the oldest ones are dropped beyond it
+    pub max_pending: usize,
+}
+
+impl Default for FilePersistSettings {
+    fn default() -> Self {
+        FilePersistSettings {
+            max_bytes: 0,
+            hourly: false,
+            gzip: false,
+            buffer_bytes: 64 * 1024,
+            flush_interval: 1.0,
+            max_pending: 10_000,
+        }
+    }
+}
+
+// the encoding of the unify events, the other topics are always json
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    Json,
+    Protobuf,
+}
+
+impl Default for MessageFormat {
+    fn default() -> Self {
+        MessageFormat::Json
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(default)]
 pub struct Settings {
@@ -115,8 +341,42 @@ pub struct Settings {
     pub assets: Vec<Asset>,
     pub markets: Vec<Market>,
     pub brokers: String,
+    // the full order messages not delivered to kafka are kept here and sent on the next start, empty to disable
+    pub kafka_wal: String,
+    // the messages kafka did not accept are appended here besides kept in memory, empty to disable
+    pub kafka_dead_letters: String,
+    // the messages a kafka producer buffers for a topic before it stops taking new ones
+    pub kafka_queue_limit: usize,
+    // the service is unavailable when a kafka producer has so many messages not sent, zero to disable
+    pub kafka_high_watermark: usize,
+    // seconds a kafka producer takes to send the messages left on shutdown, the rest are spilled
+    pub kafka_shutdown_timeout: f64,
+    // extra settings of the kafka producers, e.g. security.protocol, sasl.username or linger.ms
+    pub kafka_producer: HashMap<String, String>,
+    // wrap the messages in envelopes with a seq and a timestamp, off for the consumers of the unwrapped ones
+    pub message_envelope: bool,
+    pub unify_message_format: MessageFormat,
     pub consumer_group: String,
     pub persist_interval: i32,
+    // a full snapshot is also written here on every persisting, and preferred to an older slice on start.
+    // empty to disable
+    pub snapshot_path: String,
+    // the latest snapshots kept in memory for the rollbacks, taken on every persisting
+    pub snapshot_history: usize,
+    // the state dump for the audits is written here by the debug dump, empty to disable
+    pub state_export_path: String,
+    // the events kept for the read replica fed in process, zero to run no replica
+    pub replica_feed_capacity: usize,
+    // the address of the prometheus `/metrics` endpoint, e.g. 0.0.0.0:9100. only served when built
+    // with the `metrics` feature, empty to disable
+    pub metrics_listen: String,
+    // the address of the json `/health` endpoint, e.g. 0.0.0.0:9101, empty to disable
+    pub health_listen: String,
+    // the address of the json api of the operator commands, e.g. 127.0.0.1:9102, empty to disable.
+    // it has no auth, so it should not be reachable by the users
+    pub admin_listen: String,
+    // every matching decision is appended here as a json line, empty to disable
+    pub trade_audit_path: String,
     pub slice_interval: i32,
     pub slice_keeptime: i32,
     pub history_thread: i32,
@@ -125,6 +385,18 @@ pub struct Settings {
     pub disable_market_order: bool,
     pub check_eddsa_signatue: OrderSignatrueCheck,
     pub user_order_num_limit: usize,
+    // max resting orders of a user in a single market, zero means no limit
+    pub max_open_orders_per_user: usize,
+    pub fees: FeeSettings,
+    // the account collecting trading fees and paying maker rebates
+    pub fee_account_id: u32,
+    // orders placed with zero fees take the default fees of the market,
+    // unless the fees are determined by the fee tiers
+    pub use_market_default_fees: bool,
+    // check that every command keeps the totals of the assets, scanning all the balances twice per command
+    pub verify_conservation: bool,
+    pub balance_dedup: BalanceDedupSettings,
+    pub file_persist: FilePersistSettings,
 }
 
 impl Default for Settings {
@@ -139,7 +411,23 @@ impl Default for Settings {
             markets: Vec::new(),
             consumer_group: "kline_data_fetcher".to_string(),
             brokers: "127.0.0.1:9092".to_string(),
+            kafka_wal: Default::default(),
+            kafka_dead_letters: Default::default(),
+            kafka_queue_limit: 100,
+            kafka_high_watermark: 0,
+            kafka_shutdown_timeout: 10.0,
+            kafka_producer: HashMap::new(),
+            message_envelope: false,
+            unify_message_format: MessageFormat::Json,
             persist_interval: 3600,
+            snapshot_path: Default::default(),
+            snapshot_history: 8,
+            state_export_path: Default::default(),
+            replica_feed_capacity: 0,
+            metrics_listen: Default::default(),
+            health_listen: Default::default(),
+            admin_listen: Default::default(),
+            trade_audit_path: Default::default(),
             slice_interval: 86400,
             slice_keeptime: 86400 * 3,
             history_thread: 10,
@@ -148,6 +436,13 @@ impl Default for Settings {
             disable_market_order: false,
             check_eddsa_signatue: OrderSignatrueCheck::None,
             user_order_num_limit: 1000,
+            max_open_orders_per_user: 0,
+            fees: FeeSettings::default(),
+            fee_account_id: 0,
+            use_market_default_fees: false,
+            verify_conservation: false,
+            balance_dedup: BalanceDedupSettings::default(),
+            file_persist: FilePersistSettings::default(),
         }
     }
 }
@@ -5,7 +5,10 @@
 #![allow(clippy::single_char_pattern)]
 
 pub mod matchengine;
-pub use matchengine::{asset, controller, dto, eth_guard, history, market, persist, sequencer, server, user_manager};
+pub use matchengine::{
+    admin, asset, audit, clock, controller, dto, eth_guard, fee, health, history, market, metrics, persist, replica, sequencer, server,
+    user_manager,
+};
 pub mod storage;
 pub use storage::{database, models, sqlxextend};
 pub mod config;
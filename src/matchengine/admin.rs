@@ -0,0 +1,226 @@
+// the operator commands which have no message in orchestra, served as json on `admin_listen`.
+// each path takes the json of its request as the body, e.g. `POST /market_fee_update` with a `MarketFeeUpdateRequest`.
+// the writes are dispatched to the scheduler like the rpc ones, so they are logged and replayed the same way
+use crate::controller::*;
+use crate::market::OrderPagination;
+use crate::server::{ControllerAction, ControllerDispatch, StubType};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use tokio::sync::mpsc;
+use tonic::{Code, Status};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserQuery {
+    pub user_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketQuery {
+    pub market: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpenOrdersQuery {
+    pub user_id: u32,
+    #[serde(flatten)]
+    pub pagination: OrderPagination,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecentTradesQuery {
+    pub market: String,
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BalanceUpdateProcessedQuery {
+    pub user_id: u32,
+    pub business: String,
+    pub business_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuditFrozenQuery {
+    #[serde(default)]
+    pub fail_on_mismatch: bool,
+}
+
+type AdminRet = Result<serde_json::Value, Status>;
+
+fn parse<Req: DeserializeOwned>(body: &[u8]) -> Result<Req, Status> {
+    serde_json::from_slice(body).map_err(|e| Status::invalid_argument(format!("invalid request: {}", e)))
+}
+
+fn to_json<Resp: Serialize>(resp: Resp) -> AdminRet {
+    serde_json::to_value(resp).map_err(|e| Status::internal(e.to_string()))
+}
+
+// runs a write on the scheduler, in order with the rpc commands
+async fn dispatch<Req, Resp, F>(dispatcher: &mpsc::Sender<ControllerAction>, body: &[u8], f: F) -> AdminRet
+where
+    Req: DeserializeOwned + Send + 'static,
+    Resp: Serialize + Debug + Send + 'static,
+    F: FnOnce(&mut Controller, Req) -> Result<Resp, Status> + Send + 'static,
+{
+    let req = parse::<Req>(body)?;
+    let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { f(ctrl, req) }));
+    dispatcher
+        .send(act)
+        .await
+        .map_err(|_| Status::unknown("Server temporary unavaliable"))?;
+    rt.await.map_err(|_| Status::unknown("Dispatch ret unreach"))?.and_then(to_json)
+}
+
+async fn query<Req, Resp, F>(stub: &StubType, body: &[u8], f: F) -> AdminRet
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    F: FnOnce(&Controller, Req) -> Result<Resp, Status>,
+{
+    let req = parse::<Req>(body)?;
+    f(&*stub.read().await, req).and_then(to_json)
+}
+
+pub async fn handle(path: &str, body: &[u8], stub: &StubType, dispatcher: &mpsc::Sender<ControllerAction>) -> AdminRet {
+    match path {
+        "/fee_discount_update" => {
+            dispatch(dispatcher, body, |ctrl, req: FeeDiscountUpdateRequest| {
+                ctrl.fee_discount_update(true, req)
+            })
+            .await
+        }
+        "/market_fee_update" => {
+            dispatch(dispatcher, body, |ctrl, req: MarketFeeUpdateRequest| {
+                ctrl.market_fee_update(true, req)
+            })
+            .await
+        }
+        "/market_state_update" => {
+            dispatch(dispatcher, body, |ctrl, req: MarketStateUpdateRequest| {
+                ctrl.market_state_update(true, req)
+            })
+            .await
+        }
+        "/market_params_update" => {
+            dispatch(dispatcher, body, |ctrl, req: MarketParamsUpdateRequest| {
+                ctrl.market_params_update(true, req)
+            })
+            .await
+        }
+        "/market_close" => dispatch(dispatcher, body, |ctrl, req: MarketCloseRequest| ctrl.close_market(true, req)).await,
+        "/withdraw_request" => dispatch(dispatcher, body, |ctrl, req: WithdrawRequest| ctrl.withdraw_request(true, req)).await,
+        "/withdraw_confirm" => {
+            dispatch(dispatcher, body, |ctrl, req: WithdrawFinishRequest| {
+                ctrl.withdraw_confirm(true, req)
+            })
+            .await
+        }
+        "/withdraw_reject" => dispatch(dispatcher, body, |ctrl, req: WithdrawFinishRequest| ctrl.withdraw_reject(true, req)).await,
+        "/internal_transfer" => {
+            dispatch(dispatcher, body, |ctrl, req: InternalTransferRequest| {
+                ctrl.internal_transfer(true, req)
+            })
+            .await
+        }
+        "/balance_lock" => dispatch(dispatcher, body, |ctrl, req: BalanceLockRequest| ctrl.balance_lock(true, req)).await,
+        "/balance_unlock" => dispatch(dispatcher, body, |ctrl, req: BalanceUnlockRequest| ctrl.balance_unlock(true, req)).await,
+        "/balance_lock_consume" => {
+            dispatch(dispatcher, body, |ctrl, req: BalanceLockConsumeRequest| {
+                ctrl.balance_lock_consume(true, req)
+            })
+            .await
+        }
+        "/admin_adjust_balance" => {
+            dispatch(dispatcher, body, |ctrl, req: AdminAdjustBalanceRequest| {
+                ctrl.admin_adjust_balance(true, req)
+            })
+            .await
+        }
+        "/frozen_reconcile" => {
+            dispatch(dispatcher, body, |ctrl, req: FrozenReconcileRequest| {
+                ctrl.reconcile_frozen(true, req)
+            })
+            .await
+        }
+        "/account_overview" => query(stub, body, |ctrl, req: UserQuery| Ok(ctrl.account_overview(req.user_id))).await,
+        "/open_orders" => {
+            query(stub, body, |ctrl, req: OpenOrdersQuery| {
+                Ok(ctrl.get_all_open_orders(req.user_id, &req.pagination))
+            })
+            .await
+        }
+        "/balance_locks" => query(stub, body, |ctrl, req: UserQuery| Ok(ctrl.balance_lock_query(req.user_id))).await,
+        "/balance_update_processed" => {
+            query(stub, body, |ctrl, req: BalanceUpdateProcessedQuery| {
+                Ok(ctrl.balance_update_processed(req.user_id, &req.business, req.business_id))
+            })
+            .await
+        }
+        "/market_ticker" => query(stub, body, |ctrl, req: MarketQuery| ctrl.market_ticker(&req.market)).await,
+        "/market_status" => query(stub, body, |ctrl, req: MarketQuery| ctrl.market_status(&req.market)).await,
+        "/recent_trades" => {
+            query(stub, body, |ctrl, req: RecentTradesQuery| {
+                ctrl.recent_trades(&req.market, req.limit)
+            })
+            .await
+        }
+        "/audit_frozen" => query(stub, body, |ctrl, req: AuditFrozenQuery| ctrl.audit_frozen(req.fail_on_mismatch)).await,
+        _ => Err(Status::not_found(format!("unknown path {}", path))),
+    }
+}
+
+fn http_status(code: Code) -> hyper::StatusCode {
+    use hyper::StatusCode;
+    match code {
+        Code::InvalidArgument | Code::AlreadyExists | Code::FailedPrecondition => StatusCode::BAD_REQUEST,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// an error is answered as `{"code", "message"}` with the status of its code
+pub async fn serve(addr: std::net::SocketAddr, stub: StubType, dispatcher: mpsc::Sender<ControllerAction>) -> anyhow::Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+    let make_service = make_service_fn(move |_| {
+        let (stub, dispatcher) = (stub.clone(), dispatcher.clone());
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let (stub, dispatcher) = (stub.clone(), dispatcher.clone());
+                async move {
+                    if req.method() != Method::POST {
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+                        return Ok::<_, hyper::Error>(response);
+                    }
+                    let path = req.uri().path().to_owned();
+                    let body = hyper::body::to_bytes(req.into_body()).await?;
+                    let mut response = match handle(&path, &body, &stub, &dispatcher).await {
+                        Ok(json) => Response::new(Body::from(json.to_string())),
+                        Err(status) => {
+                            log::warn!("admin {} failed: {:?}", path, status);
+                            let json = serde_json::json!({
+                                "code": format!("{:?}", status.code()),
+                                "message": status.message(),
+                            });
+                            let mut response = Response::new(Body::from(json.to_string()));
+                            *response.status_mut() = http_status(status.code());
+                            response
+                        }
+                    };
+                    response.headers_mut().insert(
+                        hyper::header::CONTENT_TYPE,
+                        hyper::header::HeaderValue::from_static("application/json"),
+                    );
+                    Ok(response)
+                }
+            }))
+        }
+    });
+    log::info!("serving admin on {}", addr);
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
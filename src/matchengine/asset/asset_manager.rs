@@ -1,5 +1,7 @@
+use super::WithdrawCommitment;
 use crate::config;
-use crate::market::{Market, OrderCommitment};
+use crate::market::{CancelCommitment, Market, OrderCommitment};
+use crate::utils::{intern_string, InternedString};
 use anyhow::{bail, Result};
 use fluidex_common::rust_decimal::{self, RoundingStrategy};
 use fluidex_common::types::{DecimalExt, FrExt};
@@ -11,6 +13,8 @@ use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash)]
 pub struct AssetInfo {
+    // the id of the asset, interned so the balance keys do not allocate
+    pub id: InternedString,
     pub prec_save: u32,
     pub prec_show: u32,
     pub inner_id: u32,
@@ -19,6 +23,33 @@ pub struct AssetInfo {
 #[derive(Clone)]
 pub struct AssetManager {
     pub assets: HashMap<String, AssetInfo>,
+    // inner_id -> id, kept along with `assets`
+    inner_ids: HashMap<u32, InternedString>,
+}
+
+fn asset_info(item: &config::Asset) -> AssetInfo {
+    AssetInfo {
+        id: intern_string(&item.id).into(),
+        prec_save: item.prec_save,
+        prec_show: item.prec_show,
+        inner_id: item.rollup_token_id as u32,
+    }
+}
+
+// the inner ids identify the assets in the order commitments, so no two assets can share one
+fn index_inner_ids(assets: &HashMap<String, AssetInfo>) -> Result<HashMap<u32, InternedString>> {
+    let mut inner_ids = HashMap::with_capacity(assets.len());
+    for info in assets.values() {
+        if let Some(other) = inner_ids.insert(info.inner_id, info.id) {
+            bail!(
+                "assets {} and {} have the same rollup_token_id {}",
+                &*other,
+                &*info.id,
+                info.inner_id
+            );
+        }
+    }
+    Ok(inner_ids)
 }
 
 impl AssetManager {
@@ -26,35 +57,28 @@ impl AssetManager {
         log::info!("asset {:?}", asset_config);
         let mut assets = HashMap::new();
         for item in asset_config.iter() {
-            assets.insert(
-                item.id.clone(),
-                AssetInfo {
-                    prec_save: item.prec_save,
-                    prec_show: item.prec_show,
-                    inner_id: item.rollup_token_id as u32,
-                },
-            );
+            assets.insert(item.id.clone(), asset_info(item));
         }
-        Ok(AssetManager { assets })
+        let inner_ids = index_inner_ids(&assets)?;
+        Ok(AssetManager { assets, inner_ids })
     }
 
-    pub fn append(&mut self, asset_config: &[config::Asset]) {
-        //log::info()
+    // nothing is changed if the assets after the append would share a rollup_token_id
+    pub fn append(&mut self, asset_config: &[config::Asset]) -> Result<()> {
+        let mut assets = self.assets.clone();
         for item in asset_config.iter() {
-            let ret = self.assets.insert(
-                item.id.clone(),
-                AssetInfo {
-                    prec_save: item.prec_save,
-                    prec_show: item.prec_show,
-                    inner_id: item.rollup_token_id as u32,
-                },
-            );
-            if ret.is_some() {
+            assets.insert(item.id.clone(), asset_info(item));
+        }
+        self.inner_ids = index_inner_ids(&assets)?;
+        for item in asset_config.iter() {
+            if self.assets.contains_key(&item.id) {
                 log::info!("Update asset {}", item.id);
             } else {
                 log::info!("Append new asset {}", item.id);
             }
         }
+        self.assets = assets;
+        Ok(())
     }
 
     pub fn asset_exist(&self, id: &str) -> bool {
@@ -69,8 +93,18 @@ impl AssetManager {
     pub fn asset_prec_show(&self, id: &str) -> u32 {
         self.asset_get(id).unwrap().prec_show
     }
+    // the config of every asset sorted by id
+    pub fn assets_overview(&self) -> Vec<AssetInfo> {
+        let mut assets: Vec<AssetInfo> = self.assets.values().cloned().collect();
+        assets.sort_by_key(|info| info.id);
+        assets
+    }
+    // the asset of an inner_id, e.g. to decode an order commitment
+    pub fn asset_by_inner_id(&self, inner_id: u32) -> Option<&str> {
+        self.inner_ids.get(&inner_id).map(|id| &**id)
+    }
 
-    pub fn commit_order(&self, o: &OrderPutRequest, market: &Market) -> Result<OrderCommitment> {
+    pub fn commit_order(&self, o: &OrderPutRequest, nonce: u64, market: &Market) -> Result<OrderCommitment> {
         let assets: Vec<&str> = o.market.split('_').collect();
         if assets.len() != 2 {
             bail!("market error");
@@ -83,6 +117,9 @@ impl AssetManager {
             Some(token) => token,
             None => bail!("market quote_token error"),
         };
+        // the tokens of the commitment must decode back to the assets of the market
+        debug_assert_eq!(self.asset_by_inner_id(base_token.inner_id), Some(assets[0]));
+        debug_assert_eq!(self.asset_by_inner_id(quote_token.inner_id), Some(assets[1]));
         let amount = match rust_decimal::Decimal::from_str(&o.amount) {
             Ok(d) => d.round_dp_with_strategy(market.amount_prec, RoundingStrategy::ToZero),
             _ => bail!("amount error"),
@@ -94,12 +131,16 @@ impl AssetManager {
 
         match OrderSide::from_i32(o.order_side) {
             Some(OrderSide::Ask) => Ok(OrderCommitment {
+                account_id: o.user_id,
+                nonce,
                 token_buy: Fr::from_u32(quote_token.inner_id),
                 token_sell: Fr::from_u32(base_token.inner_id),
                 total_buy: (amount * price).to_fr(market.amount_prec + market.price_prec),
                 total_sell: amount.to_fr(market.amount_prec),
             }),
             Some(OrderSide::Bid) => Ok(OrderCommitment {
+                account_id: o.user_id,
+                nonce,
                 token_buy: Fr::from_u32(base_token.inner_id),
                 token_sell: Fr::from_u32(quote_token.inner_id),
                 total_buy: amount.to_fr(market.amount_prec),
@@ -108,4 +149,85 @@ impl AssetManager {
             None => bail!("market error"),
         }
     }
+
+    pub fn commit_cancel(&self, user_id: u32, market: &Market, order_id: u64, nonce: u64) -> Result<CancelCommitment> {
+        let (base_token, quote_token) = match (self.asset_get(market.base), self.asset_get(market.quote)) {
+            (Some(base_token), Some(quote_token)) => (base_token, quote_token),
+            _ => bail!("market error"),
+        };
+        Ok(CancelCommitment {
+            account_id: user_id,
+            token_base: Fr::from_u32(base_token.inner_id),
+            token_quote: Fr::from_u32(quote_token.inner_id),
+            order_id,
+            nonce,
+        })
+    }
+
+    pub fn commit_withdraw(
+        &self,
+        user_id: u32,
+        asset: &str,
+        amount: &rust_decimal::Decimal,
+        business_id: u64,
+        nonce: u64,
+    ) -> Result<WithdrawCommitment> {
+        let token = match self.asset_get(asset) {
+            Some(token) => token,
+            None => bail!("asset error"),
+        };
+        Ok(WithdrawCommitment {
+            account_id: user_id,
+            token: Fr::from_u32(token.inner_id),
+            amount: amount.to_fr(token.prec_save),
+            business_id,
+            nonce,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchengine::mock::*;
+
+    fn btc(rollup_token_id: i32) -> config::Asset {
+        config::Asset {
+            id: "BTC".to_string(),
+            symbol: "BTC".to_string(),
+            rollup_token_id,
+            prec_save: 8,
+            prec_show: 8,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_inner_id() {
+        let mut assets = get_simple_asset_config(8);
+        assets.push(btc(MockAsset::USDT.rollup_token_id()));
+        assert!(AssetManager::new(&assets).is_err());
+
+        let mut asset_manager = get_simple_asset_manager(get_simple_asset_config(8));
+        assert_eq!(asset_manager.asset_by_inner_id(0), Some("ETH"));
+        assert_eq!(asset_manager.asset_by_inner_id(1), Some("USDT"));
+        assert_eq!(asset_manager.asset_by_inner_id(2), None);
+
+        // a rejected append changes nothing
+        assert!(asset_manager.append(&[btc(0)]).is_err());
+        assert!(!asset_manager.asset_exist("BTC"));
+        assert_eq!(asset_manager.asset_by_inner_id(0), Some("ETH"));
+
+        asset_manager.append(&[btc(2)]).unwrap();
+        assert_eq!(asset_manager.asset_by_inner_id(2), Some("BTC"));
+        // updating an asset moves its inner_id
+        asset_manager.append(&[btc(3)]).unwrap();
+        assert_eq!(asset_manager.asset_by_inner_id(2), None);
+        assert_eq!(asset_manager.asset_by_inner_id(3), Some("BTC"));
+        let overview = asset_manager.assets_overview();
+        assert_eq!(
+            overview.iter().map(|info| (&*info.id, info.inner_id)).collect::<Vec<_>>(),
+            vec![("BTC", 3), ("ETH", 0), ("USDT", 1)]
+        );
+    }
 }
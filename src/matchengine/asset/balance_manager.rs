@@ -1,27 +1,32 @@
 use super::asset_manager::AssetManager;
 use crate::config;
 pub use crate::models::BalanceHistory;
+use crate::utils::InternedString;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use fluidex_common::rust_decimal::prelude::Zero;
-use fluidex_common::rust_decimal::Decimal;
+use fluidex_common::rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
 
 use num_enum::TryFromPrimitive;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash, Copy, TryFromPrimitive)]
 #[repr(i16)]
 pub enum BalanceType {
     AVAILABLE = 1,
     FREEZE = 2,
+    // requested to be withdrawn, waiting for the result on chain
+    WITHDRAWING = 3,
+    // locked for an operation out of the engine, e.g. an otc settlement. never released by the orders
+    LOCK = 4,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash)]
 pub struct BalanceMapKey {
     pub user_id: u32,
     pub balance_type: BalanceType,
-    pub asset: String,
+    pub asset: InternedString,
 }
 
 #[derive(Default)]
@@ -31,12 +36,48 @@ pub struct BalanceStatus {
     pub available: Decimal,
     pub frozen_count: u32,
     pub frozen: Decimal,
+    pub withdrawing_count: u32,
+    pub withdrawing: Decimal,
+    pub locked_count: u32,
+    pub locked: Decimal,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UserBalance {
+    pub asset: String,
+    pub available: Decimal,
+    pub frozen: Decimal,
+    pub withdrawing: Decimal,
+    pub locked: Decimal,
+}
+
+// the balances of an asset for display, the shown values are truncated to prec_show so they never exceed
+// what the user has. `total_raw` is the exact sum of all the balances
+#[derive(Serialize, Debug, PartialEq)]
+pub struct UserBalanceSummary {
+    pub asset: String,
+    pub available_show: Decimal,
+    pub frozen_show: Decimal,
+    pub total_raw: Decimal,
+}
+
+// an amount moved from AVAILABLE to LOCK, until it is unlocked or consumed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LockRecord {
+    pub lock_id: u64,
+    pub user_id: u32,
+    pub asset: String,
+    pub amount: Decimal,
 }
 
 //#[derive(default)]
 pub struct BalanceManager {
     pub asset_manager: AssetManager,
     pub balances: HashMap<BalanceMapKey, Decimal>,
+    // user_id -> the assets the user has a balance entry of, kept along with `balances`
+    user_assets: HashMap<u32, BTreeSet<InternedString>>,
+    // lock_id -> lock, their amounts are in the LOCK balances
+    pub locks: HashMap<u64, LockRecord>,
 }
 
 impl BalanceManager {
@@ -45,19 +86,39 @@ impl BalanceManager {
         Ok(BalanceManager {
             asset_manager,
             balances: HashMap::new(),
+            user_assets: HashMap::new(),
+            locks: HashMap::new(),
         })
     }
 
     pub fn reset(&mut self) {
-        self.balances.clear()
+        self.balances.clear();
+        self.user_assets.clear();
+        self.locks.clear();
     }
-    pub fn get(&self, user_id: u32, balance_type: BalanceType, asset: &str) -> Decimal {
-        self.get_by_key(&BalanceMapKey {
-            user_id,
-            balance_type,
-            asset: asset.to_owned(),
+    // the interned id and the precision of a known asset, resolving it allocates nothing
+    fn asset_key(&self, asset: &str) -> (InternedString, u32) {
+        let info = self.asset_manager.asset_get(asset).unwrap();
+        (info.id, info.prec_save)
+    }
+    fn balance_entry(&mut self, key: BalanceMapKey) -> &mut Decimal {
+        let (user_id, asset) = (key.user_id, key.asset);
+        let user_assets = &mut self.user_assets;
+        self.balances.entry(key).or_insert_with(|| {
+            user_assets.entry(user_id).or_default().insert(asset);
+            Decimal::zero()
         })
     }
+    pub fn get(&self, user_id: u32, balance_type: BalanceType, asset: &str) -> Decimal {
+        match self.asset_manager.asset_get(asset) {
+            Some(info) => self.get_by_key(&BalanceMapKey {
+                user_id,
+                balance_type,
+                asset: info.id,
+            }),
+            None => Decimal::zero(),
+        }
+    }
     pub fn get_with_round(&self, user_id: u32, balance_type: BalanceType, asset: &str) -> Decimal {
         let balance: Decimal = self.get(user_id, balance_type, asset);
         let prec_save = self.asset_manager.asset_prec(asset);
@@ -73,17 +134,41 @@ impl BalanceManager {
         *self.balances.get(key).unwrap_or(&Decimal::zero())
     }
     pub fn del(&mut self, user_id: u32, balance_type: BalanceType, asset: &str) {
-        self.balances.remove(&BalanceMapKey {
-            user_id,
-            balance_type,
-            asset: asset.to_owned(),
-        });
+        if let Some(info) = self.asset_manager.asset_get(asset) {
+            let key = BalanceMapKey {
+                user_id,
+                balance_type,
+                asset: info.id,
+            };
+            self.balances.remove(&key);
+            let has_other = [
+                BalanceType::AVAILABLE,
+                BalanceType::FREEZE,
+                BalanceType::WITHDRAWING,
+                BalanceType::LOCK,
+            ]
+            .iter()
+            .any(|other| {
+                self.balances.contains_key(&BalanceMapKey {
+                    balance_type: *other,
+                    ..key
+                })
+            });
+            if !has_other {
+                if let Some(assets) = self.user_assets.get_mut(&user_id) {
+                    assets.remove(&info.id);
+                    if assets.is_empty() {
+                        self.user_assets.remove(&user_id);
+                    }
+                }
+            }
+        }
     }
     pub fn set(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) {
         let key = BalanceMapKey {
             user_id,
             balance_type,
-            asset: asset.to_owned(),
+            asset: self.asset_key(asset).0,
         };
         self.set_by_key(key, amount);
     }
@@ -91,92 +176,433 @@ impl BalanceManager {
         debug_assert!(amount.is_sign_positive());
         let amount = amount.round_dp(self.asset_manager.asset_prec(&key.asset));
         //log::debug!("set balance: {:?}, {}", key, amount);
-        self.balances.insert(key, amount);
+        let (user_id, asset) = (key.user_id, key.asset);
+        if self.balances.insert(key, amount).is_none() {
+            self.user_assets.entry(user_id).or_default().insert(asset);
+        }
     }
     pub fn add(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> Decimal {
         debug_assert!(amount.is_sign_positive());
-        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        let (asset, prec) = self.asset_key(asset);
+        let amount = amount.round_dp(prec);
         let key = BalanceMapKey {
             user_id,
             balance_type,
-            asset: asset.to_owned(),
+            asset,
         };
-        let old_value = self.get_by_key(&key);
-        let new_value = old_value + amount;
-        self.set_by_key(key, &new_value);
-        new_value
+        let balance = self.balance_entry(key);
+        *balance += amount;
+        *balance
     }
-    pub fn sub(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> Decimal {
-        debug_assert!(amount.is_sign_positive());
-        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+    // fails rather than overflowing, nothing is changed then
+    pub fn checked_add(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> Result<Decimal> {
+        if amount.is_sign_negative() {
+            bail!("add a negative amount {} of {} to user {}", amount, asset, user_id);
+        }
+        let (asset_key, prec) = self.asset_key(asset);
+        let amount = amount.round_dp(prec);
         let key = BalanceMapKey {
             user_id,
             balance_type,
-            asset: asset.to_owned(),
+            asset: asset_key,
         };
-        let old_value = self.get_by_key(&key);
-        debug_assert!(old_value.ge(&amount));
-        let new_value = old_value - amount;
-        debug_assert!(new_value.is_sign_positive());
-        // TODO don't remove it. Skip when sql insert
-        /*
-        if result.is_zero() {
-            self.balances.remove(&key);
-        } else {
-            self.balances.insert(key, result);
-        }
-        */
-        self.set_by_key(key, &new_value);
-        new_value
+        let balance = self
+            .get_by_key(&key)
+            .checked_add(amount)
+            .ok_or_else(|| anyhow!("add {} to the {:?} {} of user {} overflows", amount, balance_type, asset, user_id))?;
+        *self.balance_entry(key) = balance;
+        Ok(balance)
     }
-    pub fn frozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
-        debug_assert!(amount.is_sign_positive());
-        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+    // fails rather than leaving a negative balance, nothing is changed then
+    pub fn sub(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> Result<Decimal> {
+        if amount.is_sign_negative() {
+            bail!("sub a negative amount {} of {} from user {}", amount, asset, user_id);
+        }
+        let (asset_key, prec) = self.asset_key(asset);
+        let amount = amount.round_dp(prec);
         let key = BalanceMapKey {
             user_id,
-            balance_type: BalanceType::AVAILABLE,
-            asset: asset.to_owned(),
+            balance_type,
+            asset: asset_key,
         };
-        let old_available_value = self.get_by_key(&key);
-        debug_assert!(old_available_value.ge(&amount));
-        self.sub(user_id, BalanceType::AVAILABLE, asset, &amount);
-        self.add(user_id, BalanceType::FREEZE, asset, &amount);
+        let balance = self.get_by_key(&key);
+        if balance.lt(&amount) {
+            bail!(
+                "sub {} from the {:?} {} {} of user {} underflows",
+                amount,
+                balance_type,
+                balance,
+                asset,
+                user_id
+            );
+        }
+        // TODO don't remove it when it becomes zero. Skip when sql insert
+        let balance = self.balance_entry(key);
+        *balance -= amount;
+        Ok(*balance)
     }
-    pub fn unfrozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
-        debug_assert!(amount.is_sign_positive());
+    pub fn frozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) -> Result<()> {
         let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
-        let key = BalanceMapKey {
-            user_id,
-            balance_type: BalanceType::FREEZE,
-            asset: asset.to_owned(),
-        };
-        let old_frozen_value = self.get_by_key(&key);
-        debug_assert!(
-            old_frozen_value.ge(&amount),
-            "unfreeze larger than frozen {} > {}",
-            amount,
-            old_frozen_value
+        self.sub(user_id, BalanceType::AVAILABLE, asset, &amount)?;
+        if let Err(e) = self.checked_add(user_id, BalanceType::FREEZE, asset, &amount) {
+            self.add(user_id, BalanceType::AVAILABLE, asset, &amount);
+            return Err(e);
+        }
+        Ok(())
+    }
+    pub fn unfrozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) -> Result<()> {
+        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        self.sub(user_id, BalanceType::FREEZE, asset, &amount)?;
+        if let Err(e) = self.checked_add(user_id, BalanceType::AVAILABLE, asset, &amount) {
+            self.add(user_id, BalanceType::FREEZE, asset, &amount);
+            return Err(e);
+        }
+        Ok(())
+    }
+    // move the amount from AVAILABLE to LOCK
+    pub fn lock(&mut self, user_id: u32, asset: &str, amount: &Decimal, lock_id: u64) -> Result<()> {
+        if self.locks.contains_key(&lock_id) {
+            bail!("duplicate lock");
+        }
+        if !self.asset_manager.asset_exist(asset) {
+            bail!("invalid asset");
+        }
+        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        if !amount.is_sign_positive() || amount.is_zero() {
+            bail!("invalid amount");
+        }
+        if self.get(user_id, BalanceType::AVAILABLE, asset) < amount {
+            bail!("balance not enough");
+        }
+        self.sub(user_id, BalanceType::AVAILABLE, asset, &amount)?;
+        self.add(user_id, BalanceType::LOCK, asset, &amount);
+        self.locks.insert(
+            lock_id,
+            LockRecord {
+                lock_id,
+                user_id,
+                asset: asset.to_string(),
+                amount,
+            },
         );
-        self.add(user_id, BalanceType::AVAILABLE, asset, &amount);
-        self.sub(user_id, BalanceType::FREEZE, asset, &amount);
+        Ok(())
+    }
+    // return the whole remaining amount to AVAILABLE
+    pub fn unlock(&mut self, lock_id: u64) -> Result<LockRecord> {
+        let lock = self
+            .locks
+            .get(&lock_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("lock {} not found", lock_id))?;
+        self.sub(lock.user_id, BalanceType::LOCK, &lock.asset, &lock.amount)?;
+        self.locks.remove(&lock_id);
+        self.add(lock.user_id, BalanceType::AVAILABLE, &lock.asset, &lock.amount);
+        Ok(lock)
+    }
+    // debit a part or all of the locked amount permanently, the lock is dropped once it is empty.
+    // returns the lock as it is after the debit
+    pub fn consume(&mut self, lock_id: u64, amount: &Decimal) -> Result<LockRecord> {
+        let lock = self.locks.get(&lock_id).ok_or_else(|| anyhow!("lock {} not found", lock_id))?;
+        let amount = amount.round_dp(self.asset_manager.asset_prec(&lock.asset));
+        if !amount.is_sign_positive() || amount.is_zero() {
+            bail!("invalid amount");
+        }
+        if amount > lock.amount {
+            bail!("consume more than locked");
+        }
+        let (user_id, asset) = (lock.user_id, lock.asset.clone());
+        self.sub(user_id, BalanceType::LOCK, &asset, &amount)?;
+        let lock = self.locks.get_mut(&lock_id).unwrap();
+        lock.amount -= amount;
+        let lock = lock.clone();
+        if lock.amount.is_zero() {
+            self.locks.remove(&lock_id);
+        }
+        Ok(lock)
+    }
+    // the locks of a user sorted by id
+    pub fn get_locks(&self, user_id: u32) -> Vec<LockRecord> {
+        let mut locks: Vec<LockRecord> = self.locks.values().filter(|lock| lock.user_id == user_id).cloned().collect();
+        locks.sort_by_key(|lock| lock.lock_id);
+        locks
+    }
+    // the balances of a user sorted by asset, read from the index instead of querying every asset.
+    // `with_round` rounds them like `get_with_round`, and the assets with zero balances are skipped unless `include_zero`
+    pub fn get_all_for_user(&self, user_id: u32, with_round: bool, include_zero: bool) -> Vec<UserBalance> {
+        let assets = match self.user_assets.get(&user_id) {
+            Some(assets) => assets,
+            None => return Vec::new(),
+        };
+        let get = |balance_type, asset: &str| {
+            if with_round {
+                self.get_with_round(user_id, balance_type, asset)
+            } else {
+                self.get(user_id, balance_type, asset)
+            }
+        };
+        assets
+            .iter()
+            .map(|asset| UserBalance {
+                asset: asset.to_string(),
+                available: get(BalanceType::AVAILABLE, asset),
+                frozen: get(BalanceType::FREEZE, asset),
+                withdrawing: get(BalanceType::WITHDRAWING, asset),
+                locked: get(BalanceType::LOCK, asset),
+            })
+            .filter(|balance| {
+                include_zero
+                    || !balance.available.is_zero()
+                    || !balance.frozen.is_zero()
+                    || !balance.withdrawing.is_zero()
+                    || !balance.locked.is_zero()
+            })
+            .collect()
+    }
+    // the non zero balances of a user sorted by asset
+    pub fn user_summary(&self, user_id: u32) -> Vec<UserBalanceSummary> {
+        let assets = match self.user_assets.get(&user_id) {
+            Some(assets) => assets,
+            None => return Vec::new(),
+        };
+        assets
+            .iter()
+            .map(|asset| {
+                let prec_show = self.asset_manager.asset_prec_show(asset);
+                let show = |balance: Decimal| balance.round_dp_with_strategy(prec_show, RoundingStrategy::ToZero);
+                let available = self.get(user_id, BalanceType::AVAILABLE, asset);
+                let frozen = self.get(user_id, BalanceType::FREEZE, asset);
+                let total_raw =
+                    available + frozen + self.get(user_id, BalanceType::WITHDRAWING, asset) + self.get(user_id, BalanceType::LOCK, asset);
+                UserBalanceSummary {
+                    asset: asset.to_string(),
+                    available_show: show(available),
+                    frozen_show: show(frozen),
+                    total_raw,
+                }
+            })
+            .filter(|summary| !summary.total_raw.is_zero())
+            .collect()
     }
     pub fn total(&self, user_id: u32, asset: &str) -> Decimal {
         self.get(user_id, BalanceType::AVAILABLE, asset) + self.get(user_id, BalanceType::FREEZE, asset)
     }
+    // asset -> the total of all the balances, in one scan
+    pub fn snapshot_totals(&self) -> BTreeMap<String, Decimal> {
+        let mut totals = BTreeMap::new();
+        for (key, amount) in &self.balances {
+            *totals.entry(key.asset.to_string()).or_insert_with(Decimal::zero) += amount;
+        }
+        totals
+    }
     pub fn status(&self, asset: &str) -> BalanceStatus {
         let mut result = BalanceStatus::default();
         for (k, amount) in self.balances.iter() {
-            if k.asset.eq(asset) && !amount.is_zero() {
+            if &*k.asset == asset && !amount.is_zero() {
                 result.total += amount;
-                if k.balance_type == BalanceType::AVAILABLE {
-                    result.available_count += 1;
-                    result.available += amount;
-                } else {
-                    result.frozen_count += 1;
-                    result.frozen += amount;
+                match k.balance_type {
+                    BalanceType::AVAILABLE => {
+                        result.available_count += 1;
+                        result.available += amount;
+                    }
+                    BalanceType::FREEZE => {
+                        result.frozen_count += 1;
+                        result.frozen += amount;
+                    }
+                    BalanceType::WITHDRAWING => {
+                        result.withdrawing_count += 1;
+                        result.withdrawing += amount;
+                    }
+                    BalanceType::LOCK => {
+                        result.locked_count += 1;
+                        result.locked += amount;
+                    }
                 }
             }
         }
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchengine::mock::*;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_interned_keys() {
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = MockAsset::ETH.id();
+        balance_manager.add(101, BalanceType::AVAILABLE, &eth, &dec!(10));
+        balance_manager.frozen(101, &eth, &dec!(4)).unwrap();
+        balance_manager.sub(101, BalanceType::FREEZE, &eth, &dec!(1)).unwrap();
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &eth), dec!(6));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &eth), dec!(3));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, "UNKNOWN"), dec!(0));
+        assert_eq!(balance_manager.balances.len(), 2);
+
+        let status = balance_manager.status(&eth);
+        assert_eq!((status.total, status.available, status.frozen), (dec!(9), dec!(6), dec!(3)));
+        assert_eq!((status.available_count, status.frozen_count), (1, 1));
+
+        // the key is serialized as before, with the asset as a string
+        let key = balance_manager
+            .balances
+            .keys()
+            .find(|key| key.balance_type == BalanceType::FREEZE)
+            .unwrap();
+        assert_eq!(
+            serde_json::to_string(key).unwrap(),
+            r#"{"user_id":101,"balance_type":"FREEZE","asset":"ETH"}"#
+        );
+        assert_eq!(
+            &serde_json::from_str::<BalanceMapKey>(&serde_json::to_string(key).unwrap()).unwrap(),
+            key
+        );
+    }
+
+    #[test]
+    fn test_get_all_for_user() {
+        let mut assets = get_simple_asset_config(8);
+        assets.push(config::Asset {
+            id: "BTC".to_string(),
+            symbol: "BTC".to_string(),
+            name: "Bitcoin".to_string(),
+            rollup_token_id: 2,
+            prec_save: 8,
+            prec_show: 4,
+            ..Default::default()
+        });
+        let mut balance_manager = get_simple_balance_manager(assets);
+        let (eth, usdt) = (MockAsset::ETH.id(), MockAsset::USDT.id());
+        balance_manager.add(42, BalanceType::AVAILABLE, &usdt, &dec!(100));
+        balance_manager.add(42, BalanceType::AVAILABLE, &eth, &dec!(5));
+        balance_manager.frozen(42, &eth, &dec!(2)).unwrap();
+        balance_manager.add(42, BalanceType::AVAILABLE, "BTC", &dec!(0.12345678));
+        balance_manager.frozen(42, "BTC", &dec!(0.12345678)).unwrap();
+        // another user must not leak into the result
+        balance_manager.add(43, BalanceType::AVAILABLE, &eth, &dec!(7));
+        let balance = |asset: &str, available, frozen| UserBalance {
+            asset: asset.to_string(),
+            available,
+            frozen,
+            withdrawing: dec!(0),
+            locked: dec!(0),
+        };
+
+        assert_eq!(
+            balance_manager.get_all_for_user(42, false, false),
+            vec![
+                balance("BTC", dec!(0), dec!(0.12345678)),
+                balance(&eth, dec!(3), dec!(2)),
+                balance(&usdt, dec!(100), dec!(0)),
+            ]
+        );
+        assert_eq!(
+            balance_manager.get_all_for_user(42, true, false)[0],
+            balance("BTC", dec!(0), dec!(0.1235))
+        );
+        assert_eq!(
+            balance_manager.get_all_for_user(43, false, false),
+            vec![balance(&eth, dec!(7), dec!(0))]
+        );
+        assert!(balance_manager.get_all_for_user(44, false, true).is_empty());
+
+        // the zero balances are kept in the map, they are only listed on request
+        balance_manager.sub(42, BalanceType::AVAILABLE, &usdt, &dec!(100)).unwrap();
+        assert_eq!(balance_manager.get_all_for_user(42, false, false).len(), 2);
+        assert_eq!(
+            balance_manager.get_all_for_user(42, false, true)[2],
+            balance(&usdt, dec!(0), dec!(0))
+        );
+
+        // deleting both balances of an asset removes it from the index
+        balance_manager.del(42, BalanceType::FREEZE, &usdt);
+        assert_eq!(balance_manager.get_all_for_user(42, false, true).len(), 3);
+        balance_manager.del(42, BalanceType::AVAILABLE, &usdt);
+        assert_eq!(balance_manager.get_all_for_user(42, false, true).len(), 2);
+        balance_manager.set(42, BalanceType::FREEZE, &usdt, &dec!(1));
+        assert_eq!(
+            balance_manager.get_all_for_user(42, false, false)[2],
+            balance(&usdt, dec!(0), dec!(1))
+        );
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let usdt = &MockAsset::USDT.id();
+        balance_manager.add(5, BalanceType::AVAILABLE, usdt, &dec!(100));
+        balance_manager.lock(5, usdt, &dec!(30), 1).unwrap();
+        balance_manager.lock(5, usdt, &dec!(20), 2).unwrap();
+        assert_eq!(balance_manager.get(5, BalanceType::AVAILABLE, usdt), dec!(50));
+        assert_eq!(balance_manager.get(5, BalanceType::LOCK, usdt), dec!(50));
+        assert_eq!(balance_manager.get_all_for_user(5, false, false)[0].locked, dec!(50));
+        assert_eq!(
+            balance_manager.lock(5, usdt, &dec!(1), 1).unwrap_err().to_string(),
+            "duplicate lock"
+        );
+        assert_eq!(
+            balance_manager.lock(5, usdt, &dec!(51), 3).unwrap_err().to_string(),
+            "balance not enough"
+        );
+
+        // consume more than locked is rejected, a partial consume keeps the rest locked
+        assert_eq!(
+            balance_manager.consume(1, &dec!(31)).unwrap_err().to_string(),
+            "consume more than locked"
+        );
+        assert_eq!(balance_manager.consume(1, &dec!(10)).unwrap().amount, dec!(20));
+        assert_eq!(balance_manager.get(5, BalanceType::LOCK, usdt), dec!(40));
+        assert_eq!(balance_manager.consume(1, &dec!(20)).unwrap().amount, dec!(0));
+        assert!(balance_manager.consume(1, &dec!(1)).is_err());
+        assert_eq!(balance_manager.status(usdt).total, dec!(70));
+
+        // unlock returns the amount once
+        assert_eq!(balance_manager.unlock(2).unwrap().amount, dec!(20));
+        assert!(balance_manager.unlock(2).is_err());
+        assert_eq!(balance_manager.get(5, BalanceType::AVAILABLE, usdt), dec!(70));
+        assert_eq!(balance_manager.get(5, BalanceType::LOCK, usdt), dec!(0));
+        assert!(balance_manager.get_locks(5).is_empty());
+    }
+
+    #[test]
+    fn test_user_summary() {
+        let mut assets = get_simple_asset_config(8);
+        assets.push(config::Asset {
+            id: "BTC".to_string(),
+            symbol: "BTC".to_string(),
+            rollup_token_id: 2,
+            prec_save: 8,
+            prec_show: 4,
+            ..Default::default()
+        });
+        let mut balance_manager = get_simple_balance_manager(assets);
+        let usdt = &MockAsset::USDT.id();
+        balance_manager.add(8, BalanceType::AVAILABLE, "BTC", &dec!(1.99999999));
+        balance_manager.frozen(8, "BTC", &dec!(0.00000009)).unwrap();
+        balance_manager.add(8, BalanceType::AVAILABLE, usdt, &dec!(3));
+        balance_manager.lock(8, usdt, &dec!(1), 1).unwrap();
+
+        // truncated, not rounded up like `get_with_round`
+        assert_eq!(balance_manager.get_with_round(8, BalanceType::AVAILABLE, "BTC"), dec!(2.0000));
+        assert_eq!(
+            balance_manager.user_summary(8),
+            vec![
+                UserBalanceSummary {
+                    asset: "BTC".to_string(),
+                    available_show: dec!(1.9999),
+                    frozen_show: dec!(0),
+                    total_raw: dec!(1.99999999),
+                },
+                UserBalanceSummary {
+                    asset: usdt.to_string(),
+                    available_show: dec!(2),
+                    frozen_show: dec!(0),
+                    total_raw: dec!(3),
+                },
+            ]
+        );
+        assert!(balance_manager.user_summary(9).is_empty());
+    }
+}
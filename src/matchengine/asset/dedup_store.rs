@@ -0,0 +1,185 @@
+use super::update_controller::BusinessType;
+use crate::config;
+use crate::models::{tablenames, BalanceHistory, TimestampDbType};
+use crate::types::ConnectionType;
+use fluidex_common::rust_decimal::Decimal;
+use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
+use ttl_cache::TtlCache;
+
+use std::time::Duration;
+
+// a processed deposit or withdraw, identified like in the balance history
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DedupKey {
+    pub business_type: BusinessType,
+    pub user_id: u32,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+}
+
+// remembers the processed deposits and withdraws. unlike the dedup cache of all the balance updates,
+// a store may remember the ones processed before a restart
+pub trait DedupStore {
+    fn seen(&self, key: &DedupKey) -> bool;
+    fn record(&mut self, key: DedupKey);
+    fn clear(&mut self);
+}
+
+// forgets everything on restart
+pub struct MemDedupStore {
+    cache: TtlCache<DedupKey, ()>,
+    ttl: Duration,
+}
+
+impl MemDedupStore {
+    pub fn new(config: &config::BalanceDedupSettings) -> Self {
+        Self {
+            cache: TtlCache::new(config.capacity),
+            ttl: Duration::from_secs_f64(config.ttl),
+        }
+    }
+}
+
+impl DedupStore for MemDedupStore {
+    fn seen(&self, key: &DedupKey) -> bool {
+        self.cache.contains_key(key)
+    }
+    fn record(&mut self, key: DedupKey) {
+        self.cache.insert(key, (), self.ttl);
+    }
+    fn clear(&mut self) {
+        self.cache.clear()
+    }
+}
+
+// the deposits and withdraws of the balance history, loaded on start. the ones processed later are
+// remembered in memory, their histories are written to the db by the persistor
+pub struct HistoryDedupStore {
+    inner: MemDedupStore,
+}
+
+impl HistoryDedupStore {
+    pub fn from_histories<'a>(config: &config::BalanceDedupSettings, histories: impl IntoIterator<Item = &'a BalanceHistory>) -> Self {
+        let mut inner = MemDedupStore::new(config);
+        for history in histories {
+            // the deposits increase the balance, the withdraws decrease it
+            let business_type = if history.change.is_sign_positive() {
+                BusinessType::Deposit
+            } else {
+                BusinessType::Withdraw
+            };
+            inner.record(DedupKey {
+                business_type,
+                user_id: history.user_id as u32,
+                asset: history.asset.clone(),
+                business: history.business.clone(),
+                business_id: history.business_id as u64,
+            });
+        }
+        Self { inner }
+    }
+
+    // the histories of the last `config.warm_hours` hours, except the ones written by the engine itself
+    pub async fn load(conn: &mut ConnectionType, config: &config::BalanceDedupSettings) -> anyhow::Result<Self> {
+        let since: TimestampDbType = FTimestamp(current_timestamp() - config.warm_hours as f64 * 3600.0).into();
+        let engine_businesses = ENGINE_BUSINESSES
+            .iter()
+            .map(|business| format!("'{}'", business))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let query = format!(
+            "select * from {} where time >= $1 and business not in ({})",
+            tablenames::BALANCEHISTORY,
+            engine_businesses
+        );
+        let histories: Vec<BalanceHistory> = sqlx::query_as(&query).bind(since).fetch_all(conn).await?;
+        log::info!("{} deposits and withdraws loaded for dedup", histories.len());
+        Ok(Self::from_histories(config, histories.iter()))
+    }
+}
+
+// the businesses of the balance updates made by the engine, which are deduplicated in memory only
+const ENGINE_BUSINESSES: [&str; 5] = ["trade", "fee", "fee_discount", "maker_rebate", "transfer"];
+
+impl DedupStore for HistoryDedupStore {
+    fn seen(&self, key: &DedupKey) -> bool {
+        self.inner.seen(key)
+    }
+    fn record(&mut self, key: DedupKey) {
+        self.inner.record(key)
+    }
+    fn clear(&mut self) {
+        self.inner.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceType, BalanceUpdateController, BalanceUpdateParams};
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_dedup_across_restart() {
+        let config = config::BalanceDedupSettings::default();
+        let usdt = &MockAsset::USDT.id();
+        let params = |business_type, business_id, change| BalanceUpdateParams {
+            balance_type: BalanceType::AVAILABLE,
+            business_type,
+            user_id: 1,
+            business_id,
+            asset: usdt.to_string(),
+            business: "chain".to_string(),
+            market_price: dec!(0),
+            change,
+            detail: serde_json::json!({}),
+            signature: vec![],
+        };
+        let history = |business_id, change: Decimal| BalanceHistory {
+            time: FTimestamp(current_timestamp()).into(),
+            user_id: 1,
+            business_id,
+            asset: usdt.to_string(),
+            business: "chain".to_string(),
+            market_price: dec!(0),
+            change,
+            balance: dec!(0),
+            balance_available: dec!(0),
+            balance_frozen: dec!(0),
+            detail: "{}".to_string(),
+            signature: vec![],
+        };
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update = |update_controller: &mut BalanceUpdateController, business_type, business_id, change| {
+            update_controller.update_user_balance(
+                &mut balance_manager,
+                &mut DummyPersistor::default(),
+                params(business_type, business_id, change),
+            )
+        };
+        let mut update_controller = BalanceUpdateController::new();
+        update(&mut update_controller, BusinessType::Deposit, 1, dec!(10)).unwrap();
+        update(&mut update_controller, BusinessType::Withdraw, 2, dec!(-3)).unwrap();
+        // the histories written by the persistor
+        let histories = vec![history(1, dec!(10)), history(2, dec!(-3))];
+
+        // restart, the redelivered deposit and withdraw are rejected
+        let mut update_controller = BalanceUpdateController::new();
+        update_controller.set_store(Box::new(HistoryDedupStore::from_histories(&config, &histories)));
+        assert!(update(&mut update_controller, BusinessType::Deposit, 1, dec!(10)).is_err());
+        update(&mut update_controller, BusinessType::Deposit, 3, dec!(10)).unwrap();
+        assert!(update(&mut update_controller, BusinessType::Withdraw, 2, dec!(-3)).is_err());
+        // the same business id of another type is not a duplicate
+        update(&mut update_controller, BusinessType::Withdraw, 1, dec!(-3)).unwrap();
+        // neither are the trades checked against the store
+        update(&mut update_controller, BusinessType::Trade, 1, dec!(1)).unwrap();
+
+        // a fresh controller without the store applies a redelivered deposit again
+        let mut fresh = BalanceUpdateController::new();
+        update(&mut fresh, BusinessType::Deposit, 3, dec!(10)).unwrap();
+        assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, usdt), dec!(25));
+    }
+}
@@ -1,6 +1,10 @@
 pub mod asset_manager;
 pub mod balance_manager;
+pub mod dedup_store;
 pub mod update_controller;
+pub mod withdraw_manager;
 pub use asset_manager::*;
 pub use balance_manager::*;
+pub use dedup_store::*;
 pub use update_controller::*;
+pub use withdraw_manager::*;
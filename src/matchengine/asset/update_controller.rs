@@ -1,13 +1,16 @@
 use super::balance_manager::{BalanceManager, BalanceType};
-use crate::models;
+use super::dedup_store::{DedupKey, DedupStore, MemDedupStore};
+use crate::clock::{Clock, EngineClock};
+use crate::config;
+use crate::models::{self, InternalTx};
 use crate::persist::PersistExector;
-use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
 pub use models::BalanceHistory;
 
 use anyhow::{bail, Result};
+use fluidex_common::rust_decimal::prelude::Zero;
 use fluidex_common::rust_decimal::Decimal;
-use ttl_cache::TtlCache;
 
+use std::collections::{HashMap, VecDeque};
 use std::time::Duration;
 
 const BALANCE_MAP_INIT_SIZE_ASSET: usize = 64;
@@ -26,15 +29,39 @@ pub struct BalanceUpdateParams {
     pub signature: Vec<u8>,
 }
 
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct TransferParams {
+    pub from_user_id: u32,
+    pub to_user_id: u32,
+    pub business_id: u64,
+    pub asset: String,
+    pub business: String,
+    pub market_price: Decimal,
+    pub amount: Decimal,
+    pub detail: serde_json::Value,
+    pub signature: Vec<u8>,
+}
+
+// a manual correction of the AVAILABLE balance by the operations team, e.g. a chargeback or a compensation
+pub struct AdjustmentParams {
+    pub user_id: u32,
+    pub business_id: u64,
+    pub asset: String,
+    pub market_price: Decimal,
+    pub delta: Decimal,
+    pub reason: String,
+    pub operator_id: u32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum BusinessType {
     Deposit,
     Trade,
     Transfer,
     Withdraw,
+    Adjustment,
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Clone)]
 struct BalanceUpdateKey {
     pub balance_type: BalanceType,
     pub business_type: BusinessType,
@@ -44,6 +71,113 @@ struct BalanceUpdateKey {
     pub business_id: u64,
 }
 
+impl BalanceUpdateKey {
+    fn business_key(&self) -> BusinessKey {
+        BusinessKey {
+            business_type: self.business_type,
+            user_id: self.user_id,
+            business: self.business.clone(),
+            business_id: self.business_id,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct BusinessKey {
+    pub business_type: BusinessType,
+    pub user_id: u32,
+    pub business: String,
+    pub business_id: u64,
+}
+
+// the processed updates grouped by business, each update with the time it is processed.
+// an update expires after the ttl. when there are more than `capacity` updates the oldest are dropped,
+// but never the ones younger than `min_age`
+struct DedupCache {
+    config: config::BalanceDedupSettings,
+    businesses: HashMap<BusinessKey, HashMap<(BalanceType, String), f64>>,
+    // the updates in the order they are processed
+    queue: VecDeque<(f64, BalanceUpdateKey)>,
+    len: usize,
+}
+
+impl DedupCache {
+    fn new(config: &config::BalanceDedupSettings) -> Self {
+        Self {
+            config: config.clone(),
+            businesses: HashMap::new(),
+            queue: VecDeque::new(),
+            len: 0,
+        }
+    }
+    fn clear(&mut self) {
+        self.businesses.clear();
+        self.queue.clear();
+        self.len = 0;
+    }
+    fn contains_key(&self, key: &BalanceUpdateKey, now: f64) -> bool {
+        self.businesses
+            .get(&key.business_key())
+            .and_then(|updates| updates.get(&(key.balance_type, key.asset.clone())))
+            .map_or(false, |time| now - time < self.config.ttl)
+    }
+    fn contains_business(&self, key: &BusinessKey, now: f64) -> bool {
+        self.businesses
+            .get(key)
+            .map_or(false, |updates| updates.values().any(|time| now - time < self.config.ttl))
+    }
+    fn insert(&mut self, key: BalanceUpdateKey, now: f64) {
+        let updates = self.businesses.entry(key.business_key()).or_default();
+        if updates.insert((key.balance_type, key.asset.clone()), now).is_none() {
+            self.len += 1;
+        }
+        // an expired update processed again is queued again, the stale entry is skipped when popped
+        self.queue.push_back((now, key));
+        self.evict(now);
+    }
+    // drop the expired updates, then the oldest ones until there are at most `capacity` updates
+    fn evict(&mut self, now: f64) {
+        while let Some((time, _)) = self.queue.front() {
+            let age = now - time;
+            let expired = age >= self.config.ttl;
+            let pressured = self.len > self.config.capacity && age >= self.config.min_age;
+            if !expired && !pressured {
+                break;
+            }
+            let (time, key) = self.queue.pop_front().unwrap();
+            let business_key = key.business_key();
+            if let Some(updates) = self.businesses.get_mut(&business_key) {
+                let update_key = (key.balance_type, key.asset);
+                if updates.get(&update_key) == Some(&time) {
+                    updates.remove(&update_key);
+                    self.len -= 1;
+                    if updates.is_empty() {
+                        self.businesses.remove(&business_key);
+                    }
+                }
+            }
+        }
+        if self.len > self.config.capacity {
+            log::warn!(
+                "{} balance updates remembered, more than the capacity {}",
+                self.len,
+                self.config.capacity
+            );
+        }
+    }
+}
+
+// only the deposits and withdraws are looked up in the store, the other businesses never match
+fn dedup_key(params: &BalanceUpdateParams) -> DedupKey {
+    DedupKey {
+        business_type: params.business_type,
+        user_id: params.user_id,
+        asset: params.asset.clone(),
+        business: params.business.clone(),
+        business_id: params.business_id,
+    }
+}
+
 //pub trait BalanceUpdateValidator {
 //    pub fn is_valid()
 //}
@@ -51,82 +185,138 @@ struct BalanceUpdateKey {
 // TODO: this class needs to be refactored
 // Currently it has two purpose: (1) filter duplicate (2) generate message
 pub struct BalanceUpdateController {
-    cache: TtlCache<BalanceUpdateKey, bool>,
+    cache: DedupCache,
+    // the deposits and withdraws, which may be remembered across restarts
+    store: Box<dyn DedupStore>,
+    // the controller shares its clock, like the markets
+    pub clock: EngineClock,
 }
 
 impl BalanceUpdateController {
     pub fn new() -> BalanceUpdateController {
-        let capacity = 1_000_000;
+        Self::with_config(&config::BalanceDedupSettings::default())
+    }
+    pub fn with_config(config: &config::BalanceDedupSettings) -> BalanceUpdateController {
         BalanceUpdateController {
-            cache: TtlCache::new(capacity),
+            cache: DedupCache::new(config),
+            store: Box::new(MemDedupStore::new(config)),
+            clock: EngineClock::default(),
         }
     }
+    pub fn set_store(&mut self, store: Box<dyn DedupStore>) {
+        self.store = store;
+    }
     pub fn reset(&mut self) {
-        self.cache.clear()
+        self.cache.clear();
+        self.store.clear();
     }
+    // only the expired updates are dropped, the others are remembered for the whole ttl
     pub fn on_timer(&mut self) {
-        self.cache.clear()
+        self.cache.evict(self.clock.now().as_secs_f64())
     }
     pub fn timer_interval(&self) -> Duration {
         Duration::from_secs(60)
     }
+    // whether any balance update of the business is processed within the ttl
+    pub fn contains(&self, business_type: BusinessType, business: &str, business_id: u64, user_id: u32) -> bool {
+        let key = BusinessKey {
+            business_type,
+            user_id,
+            business: business.to_string(),
+            business_id,
+        };
+        self.cache.contains_business(&key, self.clock.now().as_secs_f64())
+    }
     // return false if duplicate
     pub fn update_user_balance(
         &mut self,
         balance_manager: &mut BalanceManager,
         persistor: &mut impl PersistExector,
-        mut params: BalanceUpdateParams,
+        params: BalanceUpdateParams,
     ) -> Result<()> {
-        let asset = params.asset;
-        let balance_type = params.balance_type;
-        let business = params.business;
-        let business_type = params.business_type;
-        let business_id = params.business_id;
-        let user_id = params.user_id;
-        let cache_key = BalanceUpdateKey {
-            balance_type,
-            business_type,
-            user_id,
-            asset: asset.clone(),
-            business: business.clone(),
-            business_id,
-        };
-        if self.cache.contains_key(&cache_key) {
-            bail!("duplicate request");
-        }
-        let old_balance = balance_manager.get(user_id, balance_type, &asset);
-        let change = params.change;
-        let abs_change = change.abs();
-        if change.is_sign_positive() {
-            balance_manager.add(user_id, balance_type, &asset, &abs_change);
-        } else if change.is_sign_negative() {
-            if old_balance < abs_change {
+        self.update_batch(balance_manager, persistor, vec![params])
+    }
+    // apply the legs of one operation, e.g. the settlement of a trade. all of them are validated before any is applied,
+    // so a failing leg leaves the balances untouched and persists nothing.
+    // legs of the same batch may update the same balance, only the requests processed before are duplicates
+    pub fn update_batch(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        legs: Vec<BalanceUpdateParams>,
+    ) -> Result<()> {
+        let now = self.clock.now();
+        let mut cache_keys = Vec::with_capacity(legs.len());
+        let mut balances: HashMap<(u32, BalanceType, &str), Decimal> = HashMap::new();
+        for params in &legs {
+            if !balance_manager.asset_manager.asset_exist(&params.asset) {
+                bail!("invalid asset");
+            }
+            let cache_key = BalanceUpdateKey {
+                balance_type: params.balance_type,
+                business_type: params.business_type,
+                user_id: params.user_id,
+                asset: params.asset.clone(),
+                business: params.business.clone(),
+                business_id: params.business_id,
+            };
+            if self.cache.contains_key(&cache_key, now.as_secs_f64()) || self.store.seen(&dedup_key(params)) {
+                crate::metrics::balance_update_duplicate();
+                bail!("duplicate request");
+            }
+            cache_keys.push(cache_key);
+            // the balance after the earlier legs
+            let balance = balances
+                .entry((params.user_id, params.balance_type, params.asset.as_str()))
+                .or_insert_with(|| balance_manager.get(params.user_id, params.balance_type, &params.asset));
+            if params.change.is_sign_negative() && *balance < params.change.abs() {
                 bail!("balance not enough");
             }
-            balance_manager.sub(user_id, balance_type, &asset, &abs_change);
-        }
-        log::debug!("change user balance: {} {} {}", user_id, asset, change);
-        self.cache.insert(cache_key, true, Duration::from_secs(3600));
-        if persistor.real_persist() && (PERSIST_ZERO_BALANCE_UPDATE || !change.is_zero()) {
-            params.detail["id"] = serde_json::Value::from(business_id);
-            let balance_available = balance_manager.get(user_id, BalanceType::AVAILABLE, &asset);
-            let balance_frozen = balance_manager.get(user_id, BalanceType::FREEZE, &asset);
-            let balance_history = BalanceHistory {
-                time: FTimestamp(current_timestamp()).into(),
-                user_id: user_id as i32,
-                business_id: business_id as i64,
-                asset,
-                business,
-                market_price: params.market_price,
-                change,
-                balance: balance_available + balance_frozen,
-                balance_available,
-                balance_frozen,
-                detail: params.detail.to_string(),
-                signature: params.signature,
+            *balance = match balance.checked_add(params.change) {
+                Some(balance) => balance,
+                None => bail!("balance overflow"),
             };
+        }
+
+        let mut histories = Vec::new();
+        for (mut params, cache_key) in legs.into_iter().zip(cache_keys) {
+            let (user_id, asset, change) = (params.user_id, params.asset.clone(), params.change);
+            let abs_change = change.abs();
+            if change.is_sign_positive() {
+                balance_manager.checked_add(user_id, params.balance_type, &asset, &abs_change)?;
+            } else if change.is_sign_negative() {
+                balance_manager.sub(user_id, params.balance_type, &asset, &abs_change)?;
+            }
+            log::debug!("change user balance: {} {} {}", user_id, asset, change);
+            if matches!(params.business_type, BusinessType::Deposit | BusinessType::Withdraw) {
+                self.store.record(dedup_key(&params));
+            }
+            self.cache.insert(cache_key, now.as_secs_f64());
+            if persistor.real_persist() && (PERSIST_ZERO_BALANCE_UPDATE || !change.is_zero()) {
+                params.detail["id"] = serde_json::Value::from(params.business_id);
+                let balance_available = balance_manager.get(user_id, BalanceType::AVAILABLE, &asset);
+                let balance_frozen = balance_manager.get(user_id, BalanceType::FREEZE, &asset);
+                let balance_history = BalanceHistory {
+                    time: now.into(),
+                    user_id: user_id as i32,
+                    business_id: params.business_id as i64,
+                    asset,
+                    business: params.business,
+                    market_price: params.market_price,
+                    change,
+                    balance: balance_available + balance_frozen,
+                    balance_available,
+                    balance_frozen,
+                    detail: params.detail.to_string(),
+                    signature: params.signature,
+                };
+                histories.push((params.business_type, balance_history));
+            }
+        }
+        // persisted after all the legs are applied
+        for (business_type, balance_history) in histories {
             persistor.put_balance(&balance_history);
-            match params.business_type {
+            match business_type {
                 BusinessType::Deposit => persistor.put_deposit(&balance_history),
                 BusinessType::Withdraw => persistor.put_withdraw(&balance_history),
                 _ => {}
@@ -134,6 +324,96 @@ impl BalanceUpdateController {
         }
         Ok(())
     }
+    // move AVAILABLE balance between two users, either both legs are applied or none of them.
+    // a retry with the same business_id is rejected as duplicate, one transfer is persisted for both legs
+    pub fn transfer(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        params: TransferParams,
+    ) -> Result<InternalTx> {
+        if params.from_user_id == params.to_user_id {
+            bail!("transfer to self");
+        }
+        if !balance_manager.asset_manager.asset_exist(&params.asset) {
+            bail!("invalid asset");
+        }
+        let amount = params.amount;
+        if !amount.is_sign_positive() || amount.is_zero() {
+            bail!("invalid amount");
+        }
+        if amount.round_dp(balance_manager.asset_manager.asset_prec(&params.asset)) != amount {
+            bail!("amount exceeds the precision of {}", params.asset);
+        }
+        let legs = [
+            (params.from_user_id, -amount, params.market_price),
+            (params.to_user_id, amount, Decimal::zero()),
+        ]
+        .iter()
+        .map(|(user_id, change, market_price)| BalanceUpdateParams {
+            balance_type: BalanceType::AVAILABLE,
+            business_type: BusinessType::Transfer,
+            user_id: *user_id,
+            business_id: params.business_id,
+            asset: params.asset.clone(),
+            business: params.business.clone(),
+            market_price: *market_price,
+            change: *change,
+            detail: params.detail.clone(),
+            signature: vec![],
+        })
+        .collect();
+        self.update_batch(balance_manager, persistor, legs)?;
+        let tx = InternalTx {
+            time: self.clock.now().into(),
+            user_from: params.from_user_id as i32,
+            user_to: params.to_user_id as i32,
+            asset: params.asset,
+            amount,
+            signature: params.signature,
+        };
+        if persistor.real_persist() {
+            persistor.put_transfer(tx.clone());
+        }
+        Ok(tx)
+    }
+    // the operator and the reason are kept in the detail of the balance history.
+    // an adjustment never makes AVAILABLE negative, and a retry with the same business_id is rejected as duplicate
+    pub fn adjust(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        params: AdjustmentParams,
+    ) -> Result<()> {
+        if params.reason.trim().is_empty() {
+            bail!("empty reason");
+        }
+        if !balance_manager.asset_manager.asset_exist(&params.asset) {
+            bail!("invalid asset");
+        }
+        if params.delta.is_zero() {
+            bail!("invalid amount");
+        }
+        if params.delta.round_dp(balance_manager.asset_manager.asset_prec(&params.asset)) != params.delta {
+            bail!("amount exceeds the precision of {}", params.asset);
+        }
+        self.update_user_balance(
+            balance_manager,
+            persistor,
+            BalanceUpdateParams {
+                balance_type: BalanceType::AVAILABLE,
+                business_type: BusinessType::Adjustment,
+                user_id: params.user_id,
+                business_id: params.business_id,
+                asset: params.asset,
+                business: "admin_adjustment".to_string(),
+                market_price: params.market_price,
+                change: params.delta,
+                detail: serde_json::json!({"reason": params.reason, "operator_id": params.operator_id}),
+                signature: vec![],
+            },
+        )
+    }
 }
 
 impl Default for BalanceUpdateController {
@@ -141,3 +421,254 @@ impl Default for BalanceUpdateController {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::matchengine::mock::*;
+    use crate::message::Message;
+    use crate::persist::{DummyPersistor, MemBasedPersistor};
+    use crate::types::TimestampMs;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_transfer() {
+        let mut update_controller = BalanceUpdateController::new();
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut persistor = MemBasedPersistor::default();
+        let usdt = &MockAsset::USDT.id();
+        balance_manager.add(1, BalanceType::AVAILABLE, usdt, &dec!(100));
+        let params = |from_user_id, to_user_id, business_id, amount| TransferParams {
+            from_user_id,
+            to_user_id,
+            business_id,
+            asset: usdt.to_string(),
+            business: "transfer".to_string(),
+            market_price: dec!(1),
+            amount,
+            detail: serde_json::json!({}),
+            signature: vec![],
+        };
+
+        let tx = update_controller
+            .transfer(&mut balance_manager, &mut persistor, params(1, 2, 10, dec!(30.5)))
+            .unwrap();
+        assert_eq!((tx.user_from, tx.user_to, tx.amount), (1, 2, dec!(30.5)));
+        assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, usdt), dec!(69.5));
+        assert_eq!(balance_manager.get(2, BalanceType::AVAILABLE, usdt), dec!(30.5));
+
+        // a retry is rejected without touching any balance
+        let err = update_controller
+            .transfer(&mut balance_manager, &mut persistor, params(1, 2, 10, dec!(30.5)))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "duplicate request");
+        assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, usdt), dec!(69.5));
+        assert_eq!(balance_manager.get(2, BalanceType::AVAILABLE, usdt), dec!(30.5));
+
+        // invalid transfers
+        for (from_user_id, to_user_id, amount) in [
+            (1, 1, dec!(1)),
+            (1, 2, dec!(0)),
+            (1, 2, dec!(-1)),
+            (1, 2, dec!(0.000000001)),
+            (1, 2, dec!(70)),
+        ] {
+            assert!(update_controller
+                .transfer(&mut balance_manager, &mut persistor, params(from_user_id, to_user_id, 11, amount))
+                .is_err());
+        }
+        assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, usdt), dec!(69.5));
+
+        // one transfer message for the two legs, each leg is a balance update
+        let count = |f: fn(&Message) -> bool| persistor.messages.iter().filter(|message| f(message)).count();
+        assert_eq!(count(|message| matches!(message, Message::TransferMessage(_))), 1);
+        assert_eq!(count(|message| matches!(message, Message::BalanceMessage(_))), 2);
+    }
+
+    #[test]
+    fn test_update_batch() {
+        let mut update_controller = BalanceUpdateController::new();
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut persistor = MemBasedPersistor::default();
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(1, BalanceType::AVAILABLE, usdt, &dec!(100));
+        balance_manager.add(2, BalanceType::AVAILABLE, eth, &dec!(1));
+        let leg = |user_id, asset: &str, change, business_id| BalanceUpdateParams {
+            balance_type: BalanceType::AVAILABLE,
+            business_type: BusinessType::Trade,
+            user_id,
+            business_id,
+            asset: asset.to_string(),
+            business: "trade".to_string(),
+            market_price: dec!(0),
+            change,
+            detail: serde_json::json!({}),
+            signature: vec![],
+        };
+        let balances_before = balance_manager.balances.clone();
+
+        // the third leg fails, nothing is applied or persisted
+        let err = update_controller
+            .update_batch(
+                &mut balance_manager,
+                &mut persistor,
+                vec![
+                    leg(1, usdt, dec!(-50), 1),
+                    leg(2, usdt, dec!(50), 1),
+                    leg(2, eth, dec!(-2), 1),
+                    leg(1, eth, dec!(2), 1),
+                ],
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "balance not enough");
+        assert_eq!(balance_manager.balances, balances_before);
+        assert!(persistor.messages.is_empty());
+
+        // the legs of a batch are validated against the balances left by the earlier legs
+        assert!(update_controller
+            .update_batch(
+                &mut balance_manager,
+                &mut persistor,
+                vec![leg(1, usdt, dec!(-60), 2), leg(1, usdt, dec!(-60), 2)],
+            )
+            .is_err());
+        assert_eq!(balance_manager.balances, balances_before);
+
+        // a leg updating the same balance as an earlier leg is not a duplicate
+        update_controller
+            .update_batch(
+                &mut balance_manager,
+                &mut persistor,
+                vec![leg(1, usdt, dec!(-60), 3), leg(1, usdt, dec!(20), 3), leg(2, eth, dec!(-1), 3)],
+            )
+            .unwrap();
+        assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, usdt), dec!(60));
+        assert_eq!(balance_manager.get(2, BalanceType::AVAILABLE, eth), dec!(0));
+        assert_eq!(persistor.messages.len(), 3);
+        // but a batch repeating a processed one is
+        assert!(update_controller
+            .update_batch(&mut balance_manager, &mut persistor, vec![leg(2, eth, dec!(1), 3)])
+            .is_err());
+        assert!(update_controller
+            .update_batch(&mut balance_manager, &mut persistor, vec![leg(9, "UNKNOWN", dec!(1), 4)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_dedup_ttl() {
+        let mut update_controller = BalanceUpdateController::with_config(&config::BalanceDedupSettings {
+            ttl: 3600.0,
+            capacity: 2,
+            min_age: 300.0,
+        });
+        let clock = ManualClock::new(TimestampMs(1_000_000));
+        update_controller.clock = EngineClock::new(clock.clone());
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let usdt = &MockAsset::USDT.id();
+        let deposit = |user_id, business_id| BalanceUpdateParams {
+            balance_type: BalanceType::AVAILABLE,
+            business_type: BusinessType::Deposit,
+            user_id,
+            business_id,
+            asset: usdt.to_string(),
+            business: "deposit".to_string(),
+            market_price: dec!(0),
+            change: dec!(10),
+            detail: serde_json::json!({}),
+            signature: vec![],
+        };
+        let mut update = |update_controller: &mut BalanceUpdateController, user_id, business_id| {
+            update_controller.update_user_balance(&mut balance_manager, &mut DummyPersistor::default(), deposit(user_id, business_id))
+        };
+
+        update(&mut update_controller, 1, 1).unwrap();
+        assert!(update_controller.contains(BusinessType::Deposit, "deposit", 1, 1));
+        assert!(!update_controller.contains(BusinessType::Withdraw, "deposit", 1, 1));
+        // the timer does not wipe the updates, the same deposit 90 seconds later is still a duplicate
+        clock.set(TimestampMs(1_060_000));
+        update_controller.on_timer();
+        clock.set(TimestampMs(1_090_000));
+        assert_eq!(update(&mut update_controller, 1, 1).unwrap_err().to_string(), "duplicate request");
+
+        // the young updates are kept over the capacity
+        update(&mut update_controller, 2, 2).unwrap();
+        update(&mut update_controller, 3, 3).unwrap();
+        assert!((1..=3).all(|id| update_controller.contains(BusinessType::Deposit, "deposit", id, id)));
+        // and the oldest are dropped once they are old enough
+        clock.set(TimestampMs(1_300_000));
+        update(&mut update_controller, 4, 4).unwrap();
+        assert!(!update_controller.contains(BusinessType::Deposit, "deposit", 1, 1));
+        assert!((2..=4).all(|id| update_controller.contains(BusinessType::Deposit, "deposit", id, id)));
+        clock.set(TimestampMs(1_395_000));
+        update_controller.on_timer();
+        assert!(!update_controller.contains(BusinessType::Deposit, "deposit", 2, 2));
+        assert!(update_controller.contains(BusinessType::Deposit, "deposit", 3, 3));
+
+        // an update is accepted again after the ttl
+        clock.set(TimestampMs(1_090_000 + 3_600_000));
+        assert!(!update_controller.contains(BusinessType::Deposit, "deposit", 3, 3));
+        update(&mut update_controller, 3, 3).unwrap();
+        assert_eq!(balance_manager.get(3, BalanceType::AVAILABLE, usdt), dec!(20));
+    }
+
+    #[test]
+    fn test_adjust() {
+        let mut update_controller = BalanceUpdateController::new();
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut persistor = MemBasedPersistor::default();
+        let usdt = &MockAsset::USDT.id();
+        let params = |business_id, delta, reason: &str| AdjustmentParams {
+            user_id: 3,
+            business_id,
+            asset: usdt.to_string(),
+            market_price: dec!(1),
+            delta,
+            reason: reason.to_string(),
+            operator_id: 9,
+        };
+
+        update_controller
+            .adjust(&mut balance_manager, &mut persistor, params(1, dec!(50), "compensation"))
+            .unwrap();
+        update_controller
+            .adjust(&mut balance_manager, &mut persistor, params(2, dec!(-20), "chargeback"))
+            .unwrap();
+        assert_eq!(balance_manager.get(3, BalanceType::AVAILABLE, usdt), dec!(30));
+
+        // rejected without touching the balance
+        let err = update_controller
+            .adjust(&mut balance_manager, &mut persistor, params(3, dec!(-31), "chargeback"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "balance not enough");
+        let err = update_controller
+            .adjust(&mut balance_manager, &mut persistor, params(4, dec!(1), " "))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "empty reason");
+        let err = update_controller
+            .adjust(&mut balance_manager, &mut persistor, params(2, dec!(-20), "chargeback"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "duplicate request");
+        assert_eq!(balance_manager.get(3, BalanceType::AVAILABLE, usdt), dec!(30));
+
+        // the operator and the reason are in the persisted histories, which are not deposits or withdraws
+        let details: Vec<serde_json::Value> = persistor
+            .messages
+            .iter()
+            .map(|message| match message {
+                Message::BalanceMessage(balance) => {
+                    assert_eq!(balance.business, "admin_adjustment");
+                    serde_json::from_str(&balance.detail).unwrap()
+                }
+                _ => panic!("unexpected message {:?}", message),
+            })
+            .collect();
+        assert_eq!(
+            details,
+            vec![
+                serde_json::json!({"id": 1, "reason": "compensation", "operator_id": 9}),
+                serde_json::json!({"id": 2, "reason": "chargeback", "operator_id": 9}),
+            ]
+        );
+    }
+}
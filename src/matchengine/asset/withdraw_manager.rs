@@ -0,0 +1,268 @@
+use super::balance_manager::{BalanceManager, BalanceType};
+use crate::clock::{Clock, EngineClock};
+use crate::models::BalanceHistory;
+use crate::persist::PersistExector;
+
+use anyhow::{anyhow, bail, Result};
+use fluidex_common::rust_decimal::Decimal;
+use fluidex_common::types::{BigInt, FrExt};
+use fluidex_common::Fr;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingWithdraw {
+    pub business_id: u64,
+    pub user_id: u32,
+    pub asset: String,
+    pub business: String,
+    pub amount: Decimal,
+}
+
+// what a user signs to request a withdrawal. the amount is scaled by the precision of the asset
+pub struct WithdrawCommitment {
+    pub account_id: u32,
+    pub token: Fr,
+    pub amount: Fr,
+    pub business_id: u64,
+    // see `OrderInput::nonce`
+    pub nonce: u64,
+}
+
+impl WithdrawCommitment {
+    pub fn hash(&self) -> BigInt {
+        // not a tx of the circuits, like `CancelCommitment`
+        let magic_head = Fr::from_u32(101);
+        let data = Fr::hash(&[
+            magic_head,
+            Fr::from_u32(self.account_id),
+            self.token,
+            self.amount,
+            Fr::from_u64(self.business_id),
+            Fr::from_u64(self.nonce),
+        ]);
+        data.to_bigint()
+    }
+}
+
+// the withdrawals waiting for the result on chain, their amounts are kept in WITHDRAWING until
+// they are confirmed (burnt) or rejected (returned to AVAILABLE)
+#[derive(Default)]
+pub struct WithdrawManager {
+    // business_id -> withdraw
+    pub pending: BTreeMap<u64, PendingWithdraw>,
+    // the controller shares its clock, like the markets
+    pub clock: EngineClock,
+}
+
+impl WithdrawManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn reset(&mut self) {
+        self.pending.clear()
+    }
+    pub fn get(&self, business_id: u64) -> Option<&PendingWithdraw> {
+        self.pending.get(&business_id)
+    }
+    // used when loading a slice, the balances are loaded separately
+    pub fn insert(&mut self, withdraw: PendingWithdraw) {
+        self.pending.insert(withdraw.business_id, withdraw);
+    }
+
+    // move the amount from AVAILABLE to WITHDRAWING. the verified signature of the request goes to the history
+    pub fn request(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        market_price: Decimal,
+        withdraw: PendingWithdraw,
+        signature: Vec<u8>,
+    ) -> Result<()> {
+        if self.pending.contains_key(&withdraw.business_id) {
+            bail!("duplicate request");
+        }
+        if !withdraw.amount.is_sign_positive() || withdraw.amount.is_zero() {
+            bail!("invalid amount");
+        }
+        if balance_manager.get(withdraw.user_id, BalanceType::AVAILABLE, &withdraw.asset) < withdraw.amount {
+            bail!("balance not enough");
+        }
+        balance_manager.sub(withdraw.user_id, BalanceType::AVAILABLE, &withdraw.asset, &withdraw.amount)?;
+        balance_manager.add(withdraw.user_id, BalanceType::WITHDRAWING, &withdraw.asset, &withdraw.amount);
+        if persistor.real_persist() {
+            let history = BalanceHistory {
+                signature,
+                ..self.balance_history(balance_manager, &withdraw, "request", market_price, -withdraw.amount)
+            };
+            persistor.put_balance(&history);
+        }
+        self.pending.insert(withdraw.business_id, withdraw);
+        Ok(())
+    }
+
+    // the withdrawal succeeded on chain, the amount leaves the exchange
+    pub fn confirm(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        market_price: Decimal,
+        business_id: u64,
+    ) -> Result<PendingWithdraw> {
+        let withdraw = self
+            .pending
+            .get(&business_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("withdraw {} not found", business_id))?;
+        balance_manager.sub(withdraw.user_id, BalanceType::WITHDRAWING, &withdraw.asset, &withdraw.amount)?;
+        self.pending.remove(&business_id);
+        if persistor.real_persist() {
+            let history = self.balance_history(balance_manager, &withdraw, "confirm", market_price, -withdraw.amount);
+            persistor.put_withdraw(&history);
+        }
+        Ok(withdraw)
+    }
+
+    // the withdrawal failed on chain, the amount is refunded
+    pub fn reject(
+        &mut self,
+        balance_manager: &mut BalanceManager,
+        persistor: &mut impl PersistExector,
+        market_price: Decimal,
+        business_id: u64,
+    ) -> Result<PendingWithdraw> {
+        let withdraw = self
+            .pending
+            .get(&business_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("withdraw {} not found", business_id))?;
+        balance_manager.sub(withdraw.user_id, BalanceType::WITHDRAWING, &withdraw.asset, &withdraw.amount)?;
+        self.pending.remove(&business_id);
+        balance_manager.add(withdraw.user_id, BalanceType::AVAILABLE, &withdraw.asset, &withdraw.amount);
+        if persistor.real_persist() {
+            let history = self.balance_history(balance_manager, &withdraw, "reject", market_price, withdraw.amount);
+            persistor.put_balance(&history);
+        }
+        Ok(withdraw)
+    }
+
+    // `change` is the change of AVAILABLE for a request or a reject, and the amount burnt for a confirm
+    fn balance_history(
+        &self,
+        balance_manager: &BalanceManager,
+        withdraw: &PendingWithdraw,
+        stage: &str,
+        market_price: Decimal,
+        change: Decimal,
+    ) -> BalanceHistory {
+        let balance_available = balance_manager.get(withdraw.user_id, BalanceType::AVAILABLE, &withdraw.asset);
+        let balance_frozen = balance_manager.get(withdraw.user_id, BalanceType::FREEZE, &withdraw.asset);
+        BalanceHistory {
+            time: self.clock.now().into(),
+            user_id: withdraw.user_id as i32,
+            business_id: withdraw.business_id as i64,
+            asset: withdraw.asset.clone(),
+            business: withdraw.business.clone(),
+            market_price,
+            change,
+            balance: balance_available + balance_frozen,
+            balance_available,
+            balance_frozen,
+            detail: json!({"id": withdraw.business_id, "stage": stage}).to_string(),
+            signature: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchengine::mock::*;
+    use crate::persist::{DummyPersistor, MemBasedPersistor};
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_two_phase_withdraw() {
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut withdraw_manager = WithdrawManager::new();
+        let mut persistor = MemBasedPersistor::default();
+        let usdt = &MockAsset::USDT.id();
+        balance_manager.add(7, BalanceType::AVAILABLE, usdt, &dec!(100));
+        let withdraw = |business_id, amount| PendingWithdraw {
+            business_id,
+            user_id: 7,
+            asset: usdt.to_string(),
+            business: "withdraw".to_string(),
+            amount,
+        };
+        let get = |balance_manager: &BalanceManager, balance_type| balance_manager.get(7, balance_type, usdt);
+
+        withdraw_manager
+            .request(&mut balance_manager, &mut persistor, dec!(1), withdraw(1, dec!(30)), vec![])
+            .unwrap();
+        withdraw_manager
+            .request(&mut balance_manager, &mut persistor, dec!(1), withdraw(2, dec!(50)), vec![])
+            .unwrap();
+        assert_eq!(get(&balance_manager, BalanceType::AVAILABLE), dec!(20));
+        assert_eq!(get(&balance_manager, BalanceType::WITHDRAWING), dec!(80));
+        // the pending amounts are still held by the exchange
+        assert_eq!(balance_manager.status(usdt).total, dec!(100));
+
+        // insufficient balance and a duplicated business id change nothing
+        let err = withdraw_manager
+            .request(&mut balance_manager, &mut persistor, dec!(1), withdraw(3, dec!(21)), vec![])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "balance not enough");
+        assert!(withdraw_manager
+            .request(&mut balance_manager, &mut persistor, dec!(1), withdraw(1, dec!(1)), vec![])
+            .is_err());
+        assert!(withdraw_manager
+            .request(&mut balance_manager, &mut persistor, dec!(1), withdraw(3, dec!(0)), vec![])
+            .is_err());
+        assert_eq!(get(&balance_manager, BalanceType::AVAILABLE), dec!(20));
+        assert_eq!(withdraw_manager.pending.len(), 2);
+
+        // confirm burns the amount
+        let confirmed = withdraw_manager.confirm(&mut balance_manager, &mut persistor, dec!(1), 1).unwrap();
+        assert_eq!(confirmed, withdraw(1, dec!(30)));
+        assert_eq!(get(&balance_manager, BalanceType::WITHDRAWING), dec!(50));
+        assert_eq!(balance_manager.status(usdt).total, dec!(70));
+        // and can not be repeated, neither can a confirmed withdraw be rejected
+        assert!(withdraw_manager.confirm(&mut balance_manager, &mut persistor, dec!(1), 1).is_err());
+        assert!(withdraw_manager.reject(&mut balance_manager, &mut persistor, dec!(1), 1).is_err());
+        assert_eq!(balance_manager.status(usdt).total, dec!(70));
+
+        // reject refunds the amount
+        withdraw_manager.reject(&mut balance_manager, &mut persistor, dec!(1), 2).unwrap();
+        assert_eq!(get(&balance_manager, BalanceType::AVAILABLE), dec!(70));
+        assert_eq!(get(&balance_manager, BalanceType::WITHDRAWING), dec!(0));
+        assert!(withdraw_manager.pending.is_empty());
+
+        // only the confirmed withdraw is published as a withdraw
+        let withdraws = persistor
+            .messages
+            .iter()
+            .filter(|message| matches!(message, crate::message::Message::WithdrawMessage(_)))
+            .count();
+        let balances = persistor
+            .messages
+            .iter()
+            .filter(|message| matches!(message, crate::message::Message::BalanceMessage(_)))
+            .count();
+        assert_eq!((withdraws, balances), (1, 3));
+
+        // nothing is published when replaying
+        withdraw_manager
+            .request(
+                &mut balance_manager,
+                &mut DummyPersistor::default(),
+                dec!(1),
+                withdraw(4, dec!(1)),
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(persistor.messages.len(), 4);
+    }
+}
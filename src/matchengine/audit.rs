@@ -0,0 +1,431 @@
+use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController, BalanceUpdateParams, BusinessType};
+use crate::market::Market;
+use crate::persist::PersistExector;
+use anyhow::Result;
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::Decimal;
+use fluidex_common::utils::timeutil::current_timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FrozenMismatch {
+    pub user_id: u32,
+    pub asset: String,
+    // the sum of the frozen amounts of the open orders, or of the locks
+    pub expected: Decimal,
+    // the FREEZE balance, or the LOCK balance
+    pub actual: Decimal,
+    // actual - expected
+    pub delta: Decimal,
+}
+
+// the FREEZE balance of every (user, asset) should equal the frozen amounts of the open orders in all the markets.
+// returns the mismatches sorted by user and asset, empty if the state is consistent
+pub fn audit_frozen<'a>(balance_manager: &BalanceManager, markets: impl IntoIterator<Item = &'a Market>) -> Vec<FrozenMismatch> {
+    let mut expected: BTreeMap<(u32, String), Decimal> = BTreeMap::new();
+    for market in markets {
+        for order_rc in market.orders.values() {
+            let order = order_rc.borrow();
+            let asset = if order.is_ask() { market.base } else { market.quote };
+            *expected.entry((order.user, asset.to_string())).or_insert_with(Decimal::zero) += order.frozen;
+        }
+    }
+    mismatches(expected, nonzero_balances(balance_manager, BalanceType::FREEZE))
+}
+
+// the LOCK balance of every (user, asset) should equal the amounts of the locks, they are audited apart from the
+// order freezes. returns the mismatches like `audit_frozen`
+pub fn audit_locks(balance_manager: &BalanceManager) -> Vec<FrozenMismatch> {
+    let mut expected: BTreeMap<(u32, String), Decimal> = BTreeMap::new();
+    for lock in balance_manager.locks.values() {
+        *expected.entry((lock.user_id, lock.asset.clone())).or_insert_with(Decimal::zero) += lock.amount;
+    }
+    mismatches(expected, nonzero_balances(balance_manager, BalanceType::LOCK))
+}
+
+fn nonzero_balances(balance_manager: &BalanceManager, balance_type: BalanceType) -> BTreeMap<(u32, String), Decimal> {
+    let mut balances = BTreeMap::new();
+    for (key, amount) in &balance_manager.balances {
+        if key.balance_type == balance_type && !amount.is_zero() {
+            balances.insert((key.user_id, key.asset.to_string()), *amount);
+        }
+    }
+    balances
+}
+
+fn mismatches(expected: BTreeMap<(u32, String), Decimal>, actual: BTreeMap<(u32, String), Decimal>) -> Vec<FrozenMismatch> {
+    let mut keys = expected.keys().chain(actual.keys()).cloned().collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter_map(|key| {
+            let expected = expected.get(&key).copied().unwrap_or_else(Decimal::zero);
+            let actual = actual.get(&key).copied().unwrap_or_else(Decimal::zero);
+            if expected == actual {
+                return None;
+            }
+            Some(FrozenMismatch {
+                user_id: key.0,
+                asset: key.1,
+                expected,
+                actual,
+                delta: actual - expected,
+            })
+        })
+        .collect()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum FrozenReconcile {
+    Consistent,
+    // the surplus is returned to AVAILABLE
+    Released(Decimal),
+    // the orders freeze more than the FREEZE balance, nothing is changed
+    Deficit(FrozenMismatch),
+}
+
+// repair the FREEZE balance of (user, asset) with the open orders of all the markets. a surplus is moved back to
+// AVAILABLE as an adjustment, a deficit is only reported since repairing it would mint funds.
+// a repaired balance is consistent, so repeating the command changes nothing
+#[allow(clippy::too_many_arguments)]
+pub fn reconcile_frozen<'a>(
+    balance_manager: &mut BalanceManager,
+    markets: impl IntoIterator<Item = &'a Market>,
+    update_controller: &mut BalanceUpdateController,
+    persistor: &mut impl PersistExector,
+    user_id: u32,
+    asset: &str,
+    business_id: u64,
+) -> Result<FrozenReconcile> {
+    let mut expected = Decimal::zero();
+    for market in markets {
+        for order_rc in market.users.get(&user_id).into_iter().flat_map(|orders| orders.values()) {
+            let order = order_rc.borrow();
+            let frozen_asset = if order.is_ask() { market.base } else { market.quote };
+            if frozen_asset == asset {
+                expected += order.frozen;
+            }
+        }
+    }
+    let actual = balance_manager.get(user_id, BalanceType::FREEZE, asset);
+    if actual == expected {
+        return Ok(FrozenReconcile::Consistent);
+    }
+    let mismatch = FrozenMismatch {
+        user_id,
+        asset: asset.to_string(),
+        expected,
+        actual,
+        delta: actual - expected,
+    };
+    if actual < expected {
+        log::error!("frozen balance short of the open orders: {:?}", mismatch);
+        if persistor.real_persist() {
+            persistor.put_frozen_deficit(&mismatch);
+        }
+        return Ok(FrozenReconcile::Deficit(mismatch));
+    }
+
+    let surplus = mismatch.delta;
+    let detail = serde_json::json!({
+        "expected": expected.to_string(),
+        "actual": actual.to_string(),
+        "surplus": surplus.to_string(),
+    });
+    let legs = [(BalanceType::FREEZE, -surplus), (BalanceType::AVAILABLE, surplus)]
+        .iter()
+        .map(|(balance_type, change)| BalanceUpdateParams {
+            balance_type: *balance_type,
+            business_type: BusinessType::Adjustment,
+            user_id,
+            business_id,
+            asset: asset.to_string(),
+            business: "frozen_reconcile".to_string(),
+            market_price: Decimal::zero(),
+            change: *change,
+            detail: detail.clone(),
+            signature: vec![],
+        })
+        .collect();
+    update_controller.update_batch(balance_manager, persistor, legs)?;
+    log::warn!("frozen balance surplus released: {:?}", mismatch);
+    Ok(FrozenReconcile::Released(surplus))
+}
+
+// the total of an asset changed by a command differently from what the command should change
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConservationViolation {
+    pub timestamp: f64,
+    pub operation: String,
+    pub asset: String,
+    pub expected_change: Decimal,
+    pub actual_change: Decimal,
+}
+
+// compare the totals of the assets (see `BalanceManager::snapshot_totals`) after a command with the ones before.
+// trades and transfers keep every total, `expected_changes` are the changes the command should make, e.g. by a deposit
+pub fn check_conservation(
+    operation: &str,
+    before: &BTreeMap<String, Decimal>,
+    after: &BTreeMap<String, Decimal>,
+    expected_changes: &[(&str, Decimal)],
+) -> Vec<ConservationViolation> {
+    let mut assets = before
+        .keys()
+        .chain(after.keys())
+        .map(String::as_str)
+        .chain(expected_changes.iter().map(|(asset, _)| *asset))
+        .collect::<Vec<_>>();
+    assets.sort_unstable();
+    assets.dedup();
+    let timestamp = current_timestamp();
+    assets
+        .into_iter()
+        .filter_map(|asset| {
+            let total = |totals: &BTreeMap<String, Decimal>| totals.get(asset).copied().unwrap_or_else(Decimal::zero);
+            let actual_change = total(after) - total(before);
+            let expected_change = expected_changes
+                .iter()
+                .filter(|(changed, _)| *changed == asset)
+                .map(|(_, change)| *change)
+                .sum::<Decimal>();
+            if actual_change == expected_change {
+                return None;
+            }
+            Some(ConservationViolation {
+                timestamp,
+                operation: operation.to_string(),
+                asset: asset.to_string(),
+                expected_change,
+                actual_change,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::BalanceUpdateController;
+    use crate::config::Settings;
+    use crate::fee::FeeManager;
+    use crate::market::OrderSide;
+    use crate::matchengine::mock::*;
+    use crate::sequencer::Sequencer;
+    use fluidex_common::rust_decimal_macros::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_audit_frozen() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(0));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        for user_id in [0, 1] {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, eth, &dec!(1_000_000));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, usdt, &dec!(1_000_000));
+        }
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_integer_prec_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+
+        // a random sequence of orders, many of them trade
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..200 {
+            let order_input = OrderInputBuilder::new(
+                &market_name,
+                rng.gen_range(0..2),
+                if rng.gen::<bool>() { OrderSide::BID } else { OrderSide::ASK },
+                Decimal::from(rng.gen_range(1..10)),
+                Decimal::from(rng.gen_range(120..140)),
+            )
+            .build();
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input,
+                )
+                .unwrap();
+        }
+        assert!(market.trade_count > 0 && !market.orders.is_empty());
+        assert_eq!(audit_frozen(balance_manager, [&market]), vec![]);
+
+        // a frozen balance out of the books is detected
+        balance_manager.frozen(0, usdt, &dec!(3)).unwrap();
+        let expected = market
+            .orders
+            .values()
+            .map(|order| order.borrow())
+            .filter(|order| order.user == 0 && !order.is_ask())
+            .map(|order| order.frozen)
+            .sum::<Decimal>();
+        assert_eq!(
+            audit_frozen(balance_manager, [&market]),
+            vec![FrozenMismatch {
+                user_id: 0,
+                asset: usdt.to_string(),
+                expected,
+                actual: expected + dec!(3),
+                delta: dec!(3),
+            }]
+        );
+
+        // and so are the frozen balances left after the orders are dropped
+        balance_manager.unfrozen(0, usdt, &dec!(3)).unwrap();
+        market.reset();
+        let report = audit_frozen(balance_manager, [&market]);
+        assert!(!report.is_empty());
+        assert!(report
+            .iter()
+            .all(|mismatch| mismatch.expected.is_zero() && mismatch.delta == mismatch.actual));
+    }
+
+    #[test]
+    fn test_reconcile_frozen() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(0));
+        let usdt = &MockAsset::USDT.id();
+        balance_manager.add(0, BalanceType::AVAILABLE, usdt, &dec!(1000));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_integer_prec_market_config(), &Settings::default(), balance_manager).unwrap();
+        let order_input = OrderInputBuilder::new(market.name, 0, OrderSide::BID, dec!(1), dec!(100)).build();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut crate::persist::DummyPersistor::default(),
+                order_input,
+            )
+            .unwrap();
+        let mut reconcile = |balance_manager: &mut BalanceManager, persistor: &mut crate::persist::MemBasedPersistor, business_id| {
+            reconcile_frozen(balance_manager, [&market], &mut update_controller, persistor, 0, usdt, business_id).unwrap()
+        };
+        assert_eq!(reconcile(balance_manager, &mut persistor, 1), FrozenReconcile::Consistent);
+
+        // a surplus is released once
+        balance_manager.frozen(0, usdt, &dec!(7)).unwrap();
+        assert_eq!(reconcile(balance_manager, &mut persistor, 2), FrozenReconcile::Released(dec!(7)));
+        assert_eq!(reconcile(balance_manager, &mut persistor, 3), FrozenReconcile::Consistent);
+        assert_eq!(balance_manager.get(0, BalanceType::FREEZE, usdt), dec!(100));
+        assert_eq!(balance_manager.get(0, BalanceType::AVAILABLE, usdt), dec!(900));
+        assert_eq!(persistor.messages.len(), 2);
+
+        // a deficit is reported without changing anything
+        balance_manager.sub(0, BalanceType::FREEZE, usdt, &dec!(30)).unwrap();
+        let deficit = FrozenMismatch {
+            user_id: 0,
+            asset: usdt.to_string(),
+            expected: dec!(100),
+            actual: dec!(70),
+            delta: dec!(-30),
+        };
+        assert_eq!(
+            reconcile(balance_manager, &mut persistor, 4),
+            FrozenReconcile::Deficit(deficit.clone())
+        );
+        assert_eq!(balance_manager.get(0, BalanceType::FREEZE, usdt), dec!(70));
+        assert_eq!(balance_manager.get(0, BalanceType::AVAILABLE, usdt), dec!(900));
+        assert!(matches!(
+            persistor.messages.last(),
+            Some(crate::message::Message::FrozenDeficitMessage(mismatch)) if **mismatch == deficit
+        ));
+    }
+
+    #[test]
+    fn test_audit_locks() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(0));
+        let usdt = &MockAsset::USDT.id();
+        balance_manager.add(0, BalanceType::AVAILABLE, usdt, &dec!(1000));
+        let sequencer = &mut Sequencer::default();
+        let mut market = Market::new(&get_integer_prec_market_config(), &Settings::default(), balance_manager).unwrap();
+        let order_input = OrderInputBuilder::new(market.name, 0, OrderSide::BID, dec!(1), dec!(100)).build();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut crate::persist::DummyPersistor::default(),
+                order_input,
+            )
+            .unwrap();
+        balance_manager.lock(0, usdt, &dec!(300), 1).unwrap();
+
+        // the lock is neither an order freeze nor released with the orders
+        assert_eq!(audit_frozen(balance_manager, [&market]), vec![]);
+        assert_eq!(audit_locks(balance_manager), vec![]);
+        market
+            .cancel_all_for_user(balance_manager.into(), &mut crate::persist::DummyPersistor::default(), 0)
+            .unwrap();
+        assert_eq!(balance_manager.get(0, BalanceType::FREEZE, usdt), dec!(0));
+        assert_eq!(balance_manager.get(0, BalanceType::LOCK, usdt), dec!(300));
+        assert_eq!(balance_manager.get(0, BalanceType::AVAILABLE, usdt), dec!(700));
+        assert_eq!(audit_frozen(balance_manager, [&market]), vec![]);
+
+        // a LOCK balance out of the locks
+        balance_manager.add(0, BalanceType::LOCK, usdt, &dec!(5));
+        assert_eq!(
+            audit_locks(balance_manager),
+            vec![FrozenMismatch {
+                user_id: 0,
+                asset: usdt.to_string(),
+                expected: dec!(300),
+                actual: dec!(305),
+                delta: dec!(5),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_conservation() {
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(1, BalanceType::AVAILABLE, usdt, &dec!(100));
+        let before = balance_manager.snapshot_totals();
+
+        // a transfer and a freeze keep the totals
+        balance_manager.sub(1, BalanceType::AVAILABLE, usdt, &dec!(30)).unwrap();
+        balance_manager.add(2, BalanceType::AVAILABLE, usdt, &dec!(30));
+        balance_manager.frozen(2, usdt, &dec!(10)).unwrap();
+        let after = balance_manager.snapshot_totals();
+        assert_eq!(after.get(usdt.as_str()), Some(&dec!(100)));
+        assert!(check_conservation("transfer", &before, &after, &[]).is_empty());
+
+        // a deposit changes exactly one asset by exactly the amount
+        balance_manager.add(1, BalanceType::AVAILABLE, eth, &dec!(5));
+        let deposited = balance_manager.snapshot_totals();
+        assert!(check_conservation("balance_update", &after, &deposited, &[(eth, dec!(5))]).is_empty());
+        let violations = check_conservation("balance_update", &after, &deposited, &[(eth, dec!(4))]);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(
+            (
+                violations[0].asset.as_str(),
+                violations[0].expected_change,
+                violations[0].actual_change
+            ),
+            (eth.as_str(), dec!(4), dec!(5))
+        );
+
+        // a balance created out of nothing
+        balance_manager.add(3, BalanceType::FREEZE, usdt, &dec!(1));
+        let violations = check_conservation("order_put", &deposited, &balance_manager.snapshot_totals(), &[]);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].operation, "order_put");
+        assert_eq!((violations[0].expected_change, violations[0].actual_change), (dec!(0), dec!(1)));
+
+        // a withdrawal of an asset that is not held at all
+        let violations = check_conservation("balance_update", &deposited, &deposited, &[("BTC", dec!(-1))]);
+        assert_eq!(violations[0].asset, "BTC");
+    }
+}
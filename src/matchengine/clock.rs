@@ -0,0 +1,93 @@
+use crate::types::TimestampMs;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// a source of the time, the system time unless a test sets it
+pub trait Clock: Send + Sync {
+    fn now(&self) -> TimestampMs;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> TimestampMs {
+        TimestampMs::now()
+    }
+}
+
+// set and advanced by hand, the clones share the time
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(Arc<AtomicU64>);
+
+impl ManualClock {
+    pub fn new(time: TimestampMs) -> Self {
+        ManualClock(Arc::new(AtomicU64::new(time.as_millis())))
+    }
+    pub fn set(&self, time: TimestampMs) {
+        self.0.store(time.as_millis(), Ordering::SeqCst)
+    }
+    pub fn advance(&self, millis: u64) {
+        self.0.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> TimestampMs {
+        TimestampMs(self.0.load(Ordering::SeqCst))
+    }
+}
+
+const NOT_FIXED: u64 = u64::MAX;
+
+// the time of the engine, shared by the controller, the markets and the balance updates.
+// it is fixed to the time of an operation while the operation is replayed, so the replay is deterministic
+#[derive(Clone)]
+pub struct EngineClock {
+    source: Arc<dyn Clock>,
+    fixed: Arc<AtomicU64>,
+}
+
+impl Default for EngineClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl fmt::Debug for EngineClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EngineClock").field("now", &self.now()).finish()
+    }
+}
+
+impl EngineClock {
+    pub fn new(source: impl Clock + 'static) -> Self {
+        EngineClock {
+            source: Arc::new(source),
+            fixed: Arc::new(AtomicU64::new(NOT_FIXED)),
+        }
+    }
+    pub fn fix(&self, time: TimestampMs) {
+        self.fixed.store(time.as_millis(), Ordering::SeqCst)
+    }
+    pub fn release(&self) {
+        self.fixed.store(NOT_FIXED, Ordering::SeqCst)
+    }
+    // fixed to the time now unless it is fixed already, true if it is fixed by this call and to be released
+    // by the caller. a live operation runs at one time this way, the time it is logged with
+    pub fn fix_now(&self) -> bool {
+        self.fixed
+            .compare_exchange(NOT_FIXED, self.source.now().as_millis(), Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
+impl Clock for EngineClock {
+    fn now(&self) -> TimestampMs {
+        match self.fixed.load(Ordering::SeqCst) {
+            NOT_FIXED => self.source.now(),
+            millis => TimestampMs(millis),
+        }
+    }
+}
@@ -1,38 +1,79 @@
-use crate::asset::update_controller::{BalanceUpdateParams, BusinessType};
-use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController};
+use crate::asset::update_controller::{AdjustmentParams, BalanceUpdateParams, BusinessType, TransferParams};
+use crate::asset::{
+    AssetInfo, BalanceManager, BalanceType, BalanceUpdateController, LockRecord, PendingWithdraw, UserBalanceSummary, WithdrawManager,
+};
+use crate::audit::{self, FrozenMismatch, FrozenReconcile};
+use crate::clock::{Clock, EngineClock};
 use crate::config::{self};
 use crate::database::{DatabaseWriterConfig, OperationLogSender};
+use crate::dto;
 use crate::eth_guard::{EthLogGuard, EthLogMetadata};
+use crate::fee::FeeManager;
+use crate::health;
 use crate::history::DatabaseHistoryWriter;
 use crate::market::{self, Order, OrderInput};
-use crate::message::{FullOrderMessageManager, SimpleMessageManager};
+use crate::message::dead_letter::{DeadLetterQueue, DEFAULT_DEAD_LETTERS};
+use crate::message::producer::{FullOrderMessageScheme, SimpleMessageScheme};
+use crate::message::{FullOrderMessageManager, MessageManager, ProducerStats, SimpleMessageManager};
+use crate::metrics;
 use crate::models::{self};
-use crate::persist::{CompositePersistor, DBBasedPersistor, DummyPersistor, FileBasedPersistor, MessengerBasedPersistor, PersistExector};
-use crate::sequencer::Sequencer;
+use crate::persist::{
+    BroadcastFilter, BroadcastPersistor, BroadcastSubscriber, CompositePersistor, DBBasedPersistor, DummyPersistor, EventFilter,
+    FileBasedPersistor, MessengerBasedPersistor, PersistExector, ReplayPersistor, ReplayReport, Rewind, Snapshot, SnapshotHistory,
+    Tombstone, Tombstones, Watermarks,
+};
+use crate::sequencer::{MsgSeq, Sequencer};
 use crate::storage::config::MarketConfigs;
-use crate::types::{ConnectionType, DbType, SimpleResult};
+use crate::types::{ConnectionType, DbType, SimpleResult, TimestampMs};
 use crate::user_manager::{self, UserManager};
 
 use anyhow::{anyhow, bail};
 use fluidex_common::helper::{MergeSortIterator, Order as SortOrder};
-use fluidex_common::rust_decimal::prelude::{RoundingStrategy, Zero};
+use fluidex_common::rust_decimal::prelude::{One, RoundingStrategy, Zero};
 use fluidex_common::rust_decimal::Decimal;
+use fluidex_common::types::BigInt;
 use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
+use itertools::Itertools;
 use orchestra::rpc::exchange::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::Connection;
 use sqlx::Executor;
 use tonic::{self, Status};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::str::FromStr;
+use std::time::Instant;
 
 type MarketName = String;
 type BaseAsset = String;
 type QuoteAsset = String;
 
+// a logged operation, replayed by `Controller::replay_operations`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OperationLogEntry {
+    pub id: u64,
+    pub time: f64,
+    pub method: String,
+    pub params: String,
+    // see `models::OperationLog::failed`
+    #[serde(default)]
+    pub failed: bool,
+}
+
+impl From<models::OperationLog> for OperationLogEntry {
+    fn from(log: models::OperationLog) -> Self {
+        Self {
+            id: log.id as u64,
+            time: FTimestamp::from(&log.time).0,
+            method: log.method,
+            params: log.params,
+            failed: log.failed,
+        }
+    }
+}
+
 pub trait OperationLogConsumer {
     fn is_block(&self) -> bool;
     fn append_operation_log(&mut self, item: models::OperationLog) -> anyhow::Result<(), models::OperationLog>;
@@ -48,39 +89,83 @@ impl OperationLogConsumer for OperationLogSender {
 }
 
 // TODO: reuse pool of two dbs when they are same?
-fn create_persistor(settings: &config::Settings) -> Box<dyn PersistExector> {
+fn create_persistor(settings: &config::Settings, msg_seq: MsgSeq, replica_feed: Option<BroadcastPersistor>) -> Box<dyn PersistExector> {
     let persist_to_mq = true;
     let persist_to_mq_full_order = true;
     let persist_to_db = false;
     let persist_to_file = false;
     let mut persistor = Box::new(CompositePersistor::default());
+    persistor.set_msg_seq(msg_seq.clone());
+    let messenger = |inner: Box<dyn MessageManager>| {
+        let messenger = MessengerBasedPersistor::with_high_watermark(inner, settings.kafka_high_watermark);
+        if settings.message_envelope {
+            messenger.with_envelope(msg_seq.clone())
+        } else {
+            messenger
+        }
+    };
+    let shutdown_timeout = std::time::Duration::from_secs_f64(settings.kafka_shutdown_timeout);
     if !settings.brokers.is_empty() && persist_to_mq {
-        persistor.add_persistor(Box::new(MessengerBasedPersistor::new(Box::new(
-            SimpleMessageManager::new_and_run(&settings.brokers).unwrap(),
-        ))));
+        let dead_letters_file = Some(settings.kafka_dead_letters.clone()).filter(|path| !path.is_empty());
+        let dead_letters = DeadLetterQueue::new(DEFAULT_DEAD_LETTERS, dead_letters_file.map(Into::into));
+        let message_scheme = SimpleMessageScheme::with_dead_letters(dead_letters.shared()).with_queue_limit(settings.kafka_queue_limit);
+        persistor.add_persistor_with_filter(
+            "mq",
+            Box::new(messenger(Box::new(
+                SimpleMessageManager::new_and_run_with(&settings.brokers, &settings.kafka_producer, message_scheme, shutdown_timeout)
+                    .unwrap(),
+            ))),
+            EventFilter::ALL,
+        );
     }
     if !settings.brokers.is_empty() && persist_to_mq_full_order {
-        persistor.add_persistor(Box::new(MessengerBasedPersistor::new(Box::new(
-            FullOrderMessageManager::new_and_run(&settings.brokers).unwrap(),
-        ))));
+        let message_scheme = if settings.kafka_wal.is_empty() {
+            FullOrderMessageScheme::default()
+        } else {
+            FullOrderMessageScheme::with_recovery(&settings.kafka_wal).unwrap()
+        };
+        let message_scheme = message_scheme
+            .with_queue_limit(settings.kafka_queue_limit)
+            .with_format(settings.unify_message_format);
+        let manager =
+            FullOrderMessageManager::new_and_run_with(&settings.brokers, &settings.kafka_producer, message_scheme, shutdown_timeout)
+                .unwrap();
+        persistor.add_persistor_with_filter(
+            "mq_full_order",
+            Box::new(messenger(Box::new(manager)).with_format(settings.unify_message_format)),
+            EventFilter::ALL,
+        );
     }
     if persist_to_db {
         // persisting to db is disabled now
         let pool = sqlx::Pool::<DbType>::connect_lazy(&settings.db_history).unwrap();
-        persistor.add_persistor(Box::new(DBBasedPersistor::new(Box::new(
-            DatabaseHistoryWriter::new(
-                &DatabaseWriterConfig {
-                    spawn_limit: 4,
-                    apply_benchmark: true,
-                    capability_limit: 8192,
-                },
-                &pool,
-            )
-            .unwrap(),
-        ))));
+        persistor.add_persistor_with_filter(
+            "db",
+            Box::new(DBBasedPersistor::new(Box::new(
+                DatabaseHistoryWriter::new(
+                    &DatabaseWriterConfig {
+                        spawn_limit: 4,
+                        apply_benchmark: true,
+                        capability_limit: 8192,
+                    },
+                    &pool,
+                )
+                .unwrap(),
+            ))),
+            EventFilter::ALL,
+        );
     }
     if settings.brokers.is_empty() || persist_to_file {
-        persistor.add_persistor(Box::new(FileBasedPersistor::new("persistor_output.txt")));
+        let file = FileBasedPersistor::with_settings("persistor_output.txt", &settings.file_persist).unwrap();
+        let file = if settings.message_envelope {
+            file.with_envelope(msg_seq)
+        } else {
+            file
+        };
+        persistor.add_persistor_with_filter("file", Box::new(file), EventFilter::ALL);
+    }
+    if let Some(replica_feed) = replica_feed {
+        persistor.add_persistor_with_filter("replica_feed", Box::new(replica_feed), EventFilter::ALL);
     }
     persistor
 }
@@ -91,18 +176,44 @@ pub struct Controller {
     //<LogHandlerType> where LogHandlerType: OperationLogConsumer + Send {
     pub settings: config::Settings,
     pub sequencer: Sequencer,
+    // fixed to the time of an operation while it is replayed
+    pub clock: EngineClock,
+    // the open orders of every user in all markets
+    pub user_orders: market::UserOrderIndex,
+    // the last nonce of every user, shared by the markets
+    pub user_nonces: market::UserNonces,
+    // shared by the markets, see `Settings::trade_audit_path`
+    pub trade_audit: market::TradeAuditLog,
     pub user_manager: UserManager,
     pub balance_manager: BalanceManager,
     pub eth_guard: EthLogGuard,
     //    pub asset_manager: AssetManager,
     pub update_controller: BalanceUpdateController,
+    pub withdraw_manager: WithdrawManager,
+    pub fee_manager: FeeManager,
     pub markets: HashMap<MarketName, market::Market>,
     pub asset_market_names: HashMap<(BaseAsset, QuoteAsset), MarketName>,
+    // alias -> name of the market
+    pub market_aliases: HashMap<MarketName, MarketName>,
+    // the markets closed since they were loaded, kept in the snapshots
+    pub closed_markets: Vec<String>,
+    // the config of every market loaded, a rollback reopens the closed markets with it
+    market_configs: HashMap<MarketName, config::Market>,
     // TODO: is it worth to use generics rather than dynamic pointer?
     pub log_handler: Box<dyn OperationLogConsumer + Send + Sync>,
     pub persistor: Box<dyn PersistExector>,
     // TODO: is this needed?
     pub dummy_persistor: Box<dyn PersistExector>,
+    // the latest snapshots a rollback restarts from
+    pub snapshots: SnapshotHistory,
+    // the logged operations superseded by the rollbacks, skipped by the replays
+    pub tombstones: Tombstones,
+    // no traffic is accepted during a rollback
+    rolling_back: bool,
+    // the last time the persistors took a command
+    last_persisted: Option<Instant>,
+    // the events of every operation in envelopes, none unless `replica_feed_capacity` is set
+    replica_feed: Option<BroadcastPersistor>,
     db_pool: sqlx::Pool<DbType>,
     market_load_cfg: MarketConfigs,
 }
@@ -112,48 +223,321 @@ const OPERATION_REGISTER_USER: &str = "register_user";
 const OPERATION_BALANCE_UPDATE: &str = "balance_update";
 const OPERATION_ORDER_CANCEL: &str = "order_cancel";
 const OPERATION_ORDER_CANCEL_ALL: &str = "order_cancel_all";
+const OPERATION_SIGNED_ORDER_CANCEL: &str = "signed_order_cancel";
 const OPERATION_ORDER_PUT: &str = "order_put";
 const OPERATION_BATCH_ORDER_PUT: &str = "batch_order_put";
 const OPERATION_TRANSFER: &str = "transfer";
+const OPERATION_INTERNAL_TRANSFER: &str = "internal_transfer";
+const OPERATION_MARKET_FEE_UPDATE: &str = "market_fee_update";
+const OPERATION_FEE_DISCOUNT_UPDATE: &str = "fee_discount_update";
+const OPERATION_MARKET_STATE_UPDATE: &str = "market_state_update";
+const OPERATION_MARKET_CLOSE: &str = "market_close";
+const OPERATION_MARKET_PARAMS_UPDATE: &str = "market_params_update";
+const OPERATION_WITHDRAW_REQUEST: &str = "withdraw_request";
+const OPERATION_WITHDRAW_CONFIRM: &str = "withdraw_confirm";
+const OPERATION_WITHDRAW_REJECT: &str = "withdraw_reject";
+const OPERATION_BALANCE_LOCK: &str = "balance_lock";
+const OPERATION_BALANCE_UNLOCK: &str = "balance_unlock";
+const OPERATION_BALANCE_LOCK_CONSUME: &str = "balance_lock_consume";
+const OPERATION_ADMIN_ADJUST_BALANCE: &str = "admin_adjust_balance";
+const OPERATION_FROZEN_RECONCILE: &str = "frozen_reconcile";
+
+// `/market_fee_update` of the admin api, logged as an operation so that the fee changes are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketFeeUpdateRequest {
+    pub market: String,
+    pub maker_fee: Decimal,
+    pub taker_fee: Decimal,
+}
+
+// `/fee_discount_update` of the admin api, logged as an operation so that the opt-ins are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeeDiscountUpdateRequest {
+    pub user_id: u32,
+    // whether the taker fees of the user are paid in the discount asset
+    pub opt_in: bool,
+}
+
+// `/market_state_update` of the admin api, logged as an operation so that halting and resuming are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketStateUpdateRequest {
+    pub market: String,
+    pub state: market::MarketState,
+    pub reason: String,
+}
+
+// `/market_params_update` of the admin api, logged as an operation so that the new params and the cancels are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketParamsUpdateRequest {
+    pub market: String,
+    pub amount_prec: u32,
+    pub price_prec: u32,
+    pub min_amount: Decimal,
+}
+
+// `/market_close` of the admin api, logged as an operation so that the market is closed again on replay
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketCloseRequest {
+    pub market: String,
+    pub reason: String,
+}
+
+// `/withdraw_request` of the admin api, logged as an operation so that the pending withdraws are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WithdrawRequest {
+    pub user_id: u32,
+    pub asset: String,
+    pub business: String,
+    pub business_id: u64,
+    pub amount: Decimal,
+    #[serde(default)]
+    pub nonce: u64,
+    // hex of the eddsa signature of `WithdrawCommitment`, empty if unsigned
+    #[serde(default)]
+    pub signature: String,
+}
+
+// `OrderPutRequest` with the nonce the order is signed with, which is given as the metadata of the rpc.
+// zero is no nonce
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedOrderPutRequest {
+    #[serde(flatten)]
+    pub order: OrderPutRequest,
+    #[serde(default)]
+    pub nonce: u64,
+}
+
+// the cancel of `OrderCancelRequest` signed by the user, whose signature and nonce are given as the metadata of the rpc
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedOrderCancelRequest {
+    pub user_id: u32,
+    pub market: String,
+    pub order_id: u64,
+    pub nonce: u64,
+    // hex of the eddsa signature of `CancelCommitment`
+    pub signature: String,
+}
+
+// `/internal_transfer` of the admin api, logged as an operation. unlike `TransferRequest` the business_id is given by the caller,
+// so a retried transfer is rejected as duplicate
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InternalTransferRequest {
+    pub from: u32,
+    pub to: u32,
+    pub asset: String,
+    pub amount: Decimal,
+    pub business_id: u64,
+    pub memo: String,
+}
+
+// `/withdraw_confirm` and `/withdraw_reject` of the admin api, confirms or rejects a pending withdraw
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WithdrawFinishRequest {
+    pub business_id: u64,
+}
+
+// `/balance_lock` of the admin api, logged as an operation so that the locks are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BalanceLockRequest {
+    pub lock_id: u64,
+    pub user_id: u32,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BalanceUnlockRequest {
+    pub lock_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BalanceLockConsumeRequest {
+    pub lock_id: u64,
+    pub amount: Decimal,
+}
+
+// `/frozen_reconcile` of the admin api, logged as an operation so that the repairs are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FrozenReconcileRequest {
+    pub user_id: u32,
+    pub asset: String,
+}
+
+// the assets and the balances of a user for display
+#[derive(Serialize, Debug)]
+pub struct AccountOverview {
+    pub assets: Vec<AssetInfo>,
+    pub balances: Vec<UserBalanceSummary>,
+}
+
+// `/admin_adjust_balance` of the admin api, logged as an operation so that the adjustments are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdminAdjustBalanceRequest {
+    pub user_id: u32,
+    pub asset: String,
+    pub delta: Decimal,
+    pub reason: String,
+    pub operator_id: u32,
+    pub business_id: u64,
+}
+
+// register the aliases of a market, nothing is added if any of them is already a market name or an alias
+fn add_market_aliases<V>(
+    aliases: &mut HashMap<MarketName, MarketName>,
+    markets: &HashMap<MarketName, V>,
+    entry: &config::Market,
+) -> anyhow::Result<()> {
+    for alias in &entry.aliases {
+        if alias == &entry.name || markets.contains_key(alias) || aliases.contains_key(alias) {
+            bail!("alias {} of market {} collides with an existing market", alias, entry.name);
+        }
+    }
+    if entry.aliases.iter().unique().count() != entry.aliases.len() {
+        bail!("duplicated aliases of market {}", entry.name);
+    }
+    for alias in &entry.aliases {
+        aliases.insert(alias.clone(), entry.name.clone());
+    }
+    Ok(())
+}
+
+// the depth of a market as the rpc response, also served by the replica
+// the messages are defined by orchestra, so the values it has no field for are given in the metadata of the
+// response: the `seq` and the `checksum` of the book, and the order counts and the cumulative totals of the
+// levels of each side, joined by `,` in the order of the levels, e.g. `ask-order-counts: 2,1`
+pub fn order_book_depth_of(
+    market: &market::Market,
+    req: &OrderBookDepthRequest,
+) -> Result<tonic::Response<OrderBookDepthResponse>, Status> {
+    let interval = if req.interval.is_empty() {
+        Decimal::zero()
+    } else {
+        Decimal::from_str(&req.interval).map_err(|_| Status::invalid_argument("invalid interval"))?
+    };
+    let depth = market.depth(req.limit as usize, &interval)?;
+    let convert = |price_info: &Vec<market::PriceInfo>| {
+        price_info
+            .iter()
+            .map(|price_info| order_book_depth_response::PriceInfo {
+                price: price_info.price.to_string(),
+                amount: price_info.amount.to_string(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut response = tonic::Response::new(OrderBookDepthResponse {
+        asks: convert(&depth.asks),
+        bids: convert(&depth.bids),
+    });
+    let metadata = response.metadata_mut();
+    let mut insert = |key: &'static str, value: String| -> Result<(), Status> {
+        metadata.insert(key, value.parse().map_err(|_| Status::internal("invalid metadata"))?);
+        Ok(())
+    };
+    insert("seq", depth.seq.to_string())?;
+    insert("checksum", depth.checksum.to_string())?;
+    for (levels, keys) in [
+        (&depth.asks, ["ask-order-counts", "ask-cumulative-amounts", "ask-cumulative-quotes"]),
+        (&depth.bids, ["bid-order-counts", "bid-cumulative-amounts", "bid-cumulative-quotes"]),
+    ] {
+        insert(keys[0], levels.iter().map(|level| level.order_count.to_string()).join(","))?;
+        insert(keys[1], levels.iter().map(|level| level.cumulative_amount.to_string()).join(","))?;
+        insert(keys[2], levels.iter().map(|level| level.cumulative_quote.to_string()).join(","))?;
+    }
+    Ok(response)
+}
 
 pub fn create_controller(cfgs: (config::Settings, MarketConfigs)) -> Controller {
+    let main_pool = sqlx::Pool::<DbType>::connect_lazy(&cfgs.0.db_log).unwrap();
+    let log_handler = OperationLogSender::new(&DatabaseWriterConfig {
+        spawn_limit: 4,
+        apply_benchmark: true,
+        capability_limit: 8192,
+    })
+    .start_schedule(&main_pool)
+    .unwrap();
+    build_controller(cfgs, main_pool, Box::new(log_handler), EngineClock::default())
+}
+
+fn build_controller(
+    cfgs: (config::Settings, MarketConfigs),
+    main_pool: sqlx::Pool<DbType>,
+    log_handler: Box<dyn OperationLogConsumer + Send + Sync>,
+    clock: EngineClock,
+) -> Controller {
     let settings = cfgs.0;
-    let main_pool = sqlx::Pool::<DbType>::connect_lazy(&settings.db_log).unwrap();
     let user_manager = UserManager::new(); // load from db later
     let balance_manager = BalanceManager::new(&settings.assets).unwrap();
 
-    let update_controller = BalanceUpdateController::new();
+    let mut update_controller = BalanceUpdateController::with_config(&settings.balance_dedup);
+    let mut withdraw_manager = WithdrawManager::new();
+    let fee_manager = FeeManager::new(&settings.fees);
     //        let asset_manager = AssetManager::new(&settings.assets).unwrap();
     let sequencer = Sequencer::default();
+    update_controller.clock = clock.clone();
+    withdraw_manager.clock = clock.clone();
+    let user_orders = market::UserOrderIndex::default();
+    let user_nonces = market::UserNonces::default();
+    let trade_audit = if settings.trade_audit_path.is_empty() {
+        market::TradeAuditLog::default()
+    } else {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&settings.trade_audit_path)
+            .expect("cannot open the trade audit log");
+        market::TradeAuditLog::new(std::io::LineWriter::new(file))
+    };
+    // a clone is kept to subscribe the replica to the events
+    let replica_feed = Some(settings.replica_feed_capacity)
+        .filter(|capacity| *capacity > 0)
+        .map(|capacity| BroadcastPersistor::new(capacity).with_envelope(sequencer.msg_seq()));
+    let mut persistor = create_persistor(&settings, sequencer.msg_seq(), replica_feed.clone());
     let mut markets = HashMap::new();
     let mut asset_market_names = HashMap::new();
     for entry in &settings.markets {
-        let market = market::Market::new(entry, &settings, &balance_manager).unwrap();
+        let mut market = market::Market::new(entry, &settings, &balance_manager).unwrap();
+        market.clock = clock.clone();
+        market.user_orders = user_orders.clone();
+        market.user_nonces = user_nonces.clone();
+        market.trade_audit = trade_audit.clone();
+        // emitted on every start, the consumers should treat it as idempotent
+        persistor.put_market_event(market.created_event());
         markets.insert(entry.name.clone(), market);
         asset_market_names.insert((entry.base.clone(), entry.quote.clone()), entry.name.clone());
     }
+    let mut market_aliases = HashMap::new();
+    for entry in &settings.markets {
+        add_market_aliases(&mut market_aliases, &markets, entry).unwrap();
+    }
+    let market_configs = settings.markets.iter().map(|entry| (entry.name.clone(), entry.clone())).collect();
 
-    let persistor = create_persistor(&settings);
-    let log_handler = OperationLogSender::new(&DatabaseWriterConfig {
-        spawn_limit: 4,
-        apply_benchmark: true,
-        capability_limit: 8192,
-    })
-    .start_schedule(&main_pool)
-    .unwrap();
     Controller {
         settings,
         sequencer,
+        clock,
+        user_orders,
+        user_nonces,
+        trade_audit,
         //            asset_manager,
         user_manager,
         balance_manager,
         eth_guard: EthLogGuard::new(0),
         update_controller,
+        withdraw_manager,
+        fee_manager,
         markets,
         asset_market_names,
-        log_handler: Box::<OperationLogSender>::new(log_handler),
+        market_aliases,
+        closed_markets: Vec::new(),
+        market_configs,
+        log_handler,
         persistor,
         dummy_persistor: DummyPersistor::new_box(),
+        snapshots: SnapshotHistory::new(settings.snapshot_history),
+        tombstones: Tombstones::default(),
+        rolling_back: false,
+        last_persisted: None,
+        replica_feed,
         db_pool: main_pool,
         market_load_cfg: cfgs.1,
     }
@@ -216,7 +600,19 @@ impl Controller {
             .collect();
         Ok(BalanceQueryResponse { balances })
     }
-    pub fn order_query(&self, req: OrderQueryRequest) -> Result<OrderQueryResponse, Status> {
+    // the shown balances are truncated to the display precision of each asset
+    pub fn account_overview(&self, user_id: u32) -> AccountOverview {
+        AccountOverview {
+            assets: self.balance_manager.asset_manager.assets_overview(),
+            balances: self.balance_manager.user_summary(user_id),
+        }
+    }
+    // the open orders of a user in all markets, oldest first
+    pub fn get_all_open_orders(&self, user_id: u32, pagination: &market::OrderPagination) -> market::OpenOrdersPage {
+        self.user_orders.open_orders(user_id, &self.markets, pagination)
+    }
+    pub fn order_query(&self, mut req: OrderQueryRequest) -> Result<OrderQueryResponse, Status> {
+        req.market = self.canonical_market(&req.market);
         if req.market != "all" && !self.markets.contains_key(&req.market) {
             return Err(Status::invalid_argument("invalid market"));
         }
@@ -233,29 +629,32 @@ impl Controller {
         } else {
             req.limit
         };
-        let markets = self
+        let offset = req.offset.max(0) as usize;
+        // the first `offset + limit` of every market are enough for the merged page
+        let query = market::OrderQuery {
+            descending: true,
+            limit: offset + limit as usize,
+            ..Default::default()
+        };
+        let pages = self
             .markets
             .iter()
             .filter(|(key, _market)| req.market == "all" || req.market == **key)
-            .map(|(_key, market)| market);
-        let total_order_count: usize = markets
-            .clone()
-            .map(|m| m.users.get(&req.user_id).map(|order_map| order_map.len()).unwrap_or(0))
-            .sum();
-        let orders_by_market: Vec<Box<dyn Iterator<Item = Order>>> = markets
-            .map(|m| {
-                m.users
-                    .get(&req.user_id)
-                    .map(|order_map| Box::new(order_map.values().rev().map(|order_rc| order_rc.deep())) as Box<dyn Iterator<Item = Order>>)
-                    .unwrap_or_else(|| Box::new(Vec::new().into_iter()) as Box<dyn Iterator<Item = Order>>)
-            })
+            .map(|(_key, market)| market.query_orders(req.user_id, &query))
+            .collect::<Vec<_>>();
+        let total_order_count: usize = pages.iter().map(|page| page.total).sum();
+        let orders_by_market: Vec<Box<dyn Iterator<Item = Order>>> = pages
+            .into_iter()
+            .map(|page| Box::new(page.orders.into_iter()) as Box<dyn Iterator<Item = Order>>)
             .collect();
         // TODO: support ASC in the API
-        let orders = MergeSortIterator::compare_by(orders_by_market, SortOrder::Desc, |a, b| a.id.cmp(&b.id))
-            .skip(req.offset as usize)
-            .take(limit as usize)
-            .map(OrderInfo::from)
-            .collect();
+        let orders = MergeSortIterator::compare_by(orders_by_market, SortOrder::Desc, |a, b| {
+            market::OrderQueryCursor::of(a).compare(&market::OrderQueryCursor::of(b), market::OrderSortBy::CreateTime)
+        })
+        .skip(offset)
+        .take(limit as usize)
+        .map(OrderInfo::from)
+        .collect();
         let result = OrderQueryResponse {
             offset: req.offset,
             limit,
@@ -264,38 +663,44 @@ impl Controller {
         };
         Ok(result)
     }
-    pub fn order_book_depth(&self, req: OrderBookDepthRequest) -> Result<OrderBookDepthResponse, Status> {
+    pub fn order_book_depth(&self, req: OrderBookDepthRequest) -> Result<tonic::Response<OrderBookDepthResponse>, Status> {
         // TODO cache
         let market = self
             .markets
-            .get(&req.market)
+            .get(&self.canonical_market(&req.market))
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
-        // TODO check interval
-        let interval = if req.interval.is_empty() {
-            Decimal::zero()
-        } else {
-            Decimal::from_str(&req.interval).map_err(|_| Status::invalid_argument("invalid interval"))?
-        };
-        let depth = market.depth(req.limit as usize, &interval);
-        let convert = |price_info: &Vec<market::PriceInfo>| {
-            price_info
-                .iter()
-                .map(|price_info| order_book_depth_response::PriceInfo {
-                    price: price_info.price.to_string(),
-                    amount: price_info.amount.to_string(),
-                })
-                .collect::<Vec<_>>()
-        };
-        Ok(OrderBookDepthResponse {
-            asks: convert(&depth.asks),
-            bids: convert(&depth.bids),
-        })
+        order_book_depth_of(market, &req)
+    }
+
+    pub fn market_ticker(&self, market: &str) -> Result<market::MarketTicker, Status> {
+        let market = self
+            .markets
+            .get(&self.canonical_market(market))
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        Ok(market.ticker())
+    }
+
+    pub fn recent_trades(&self, market: &str, limit: usize) -> Result<Vec<market::TradeSummary>, Status> {
+        let market = self
+            .markets
+            .get(&self.canonical_market(market))
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        Ok(market.recent_trades(limit))
+    }
+
+    // with the best prices, the frozen totals, the user count and the 24h stats
+    pub fn market_status(&self, market: &str) -> Result<market::MarketStatus, Status> {
+        let market = self
+            .markets
+            .get(&self.canonical_market(market))
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        Ok(market.status())
     }
 
     pub fn order_detail(&self, req: OrderDetailRequest) -> Result<OrderInfo, Status> {
         let market = self
             .markets
-            .get(&req.market)
+            .get(&self.canonical_market(&req.market))
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
         let order = market
             .get(req.order_id)
@@ -324,17 +729,19 @@ impl Controller {
         let markets: Vec<String> = if req.markets.is_empty() {
             self.markets.keys().cloned().collect()
         } else {
-            for market in &req.markets {
+            let markets: Vec<String> = req.markets.iter().map(|market| self.canonical_market(market)).collect();
+            for market in &markets {
                 if !self.markets.contains_key(market) {
                     return Err(Status::invalid_argument("invalid market"));
                 }
             }
-            req.markets
+            markets
         };
         let market_summaries = markets
             .iter()
             .map(|market| {
                 let status = self.markets.get(market).unwrap().status();
+                // the message is defined by orchestra, the full status is `market_status`
                 market_summary_response::MarketSummary {
                     name: status.name,
                     ask_count: status.ask_count as i32,
@@ -348,12 +755,42 @@ impl Controller {
         Ok(MarketSummaryResponse { market_summaries })
     }
 
-    fn check_service_available(&self) -> bool {
+    // the name of the market an alias is routed to, other names are returned as they are
+    fn canonical_market(&self, market: &str) -> MarketName {
+        self.market_aliases.get(market).cloned().unwrap_or_else(|| market.to_string())
+    }
+
+    // the kafka producers, by the names of the persistors
+    pub fn producer_stats(&self) -> Vec<(String, ProducerStats)> {
+        self.persistor.producer_stats()
+    }
+
+    // served on `health_listen`
+    pub fn health(&self) -> health::Health {
+        let engine = health::EngineHealth {
+            rolling_back: self.rolling_back,
+            log_handler_blocked: self.log_handler.is_block(),
+            last_operation_id: self.sequencer.get_operation_log_id(),
+            secs_since_persist: self.last_persisted.map(|at| at.elapsed().as_secs_f64()),
+        };
+        health::Health::collect(engine, self.markets.values(), self.persistor.as_ref())
+    }
+
+    fn check_service_available(&mut self) -> bool {
+        if self.rolling_back {
+            log::warn!("rollback in progress");
+            return false;
+        }
         if self.log_handler.is_block() {
             log::warn!("log_handler full");
             return false;
         }
-        self.persistor.service_available()
+        let available = self.persistor.service_available();
+        metrics::persistor_probe(available);
+        if available {
+            self.last_persisted = Some(Instant::now());
+        }
+        available
     }
 
     pub fn register_user(&mut self, real: bool, mut req: UserInfo) -> std::result::Result<UserInfo, Status> {
@@ -413,6 +850,7 @@ impl Controller {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        let before = self.conservation_snapshot();
 
         let meta: Option<EthLogMetadata> = req.log_metadata.as_ref().map(|meta| meta.into());
         // ignore processed request
@@ -471,48 +909,136 @@ impl Controller {
 
         self.eth_guard.update_optional(meta);
 
+        self.check_conservation(real, OPERATION_BALANCE_UPDATE, before, &[(asset, change)]);
         Ok(BalanceUpdateResponse::default())
     }
 
+    // the eddsa signature of a commitment, by the l2 key the user registered with, as `check_eddsa_signatue` asks.
+    // true if it is verified. with `OrderSignatrueCheck::LogOnly` an invalid signature is logged and the request goes on
+    fn check_signature(
+        &self,
+        user_id: u32,
+        signature: &str,
+        request: &str,
+        hash: impl FnOnce() -> Result<BigInt, Status>,
+    ) -> Result<bool, Status> {
+        let check = self.settings.check_eddsa_signatue;
+        if !check.verifies(signature) {
+            return Ok(false);
+        }
+        if !signature.is_empty() && self.user_manager.verify_signature(user_id, hash()?, signature) {
+            return Ok(true);
+        }
+        if check == config::OrderSignatrueCheck::LogOnly {
+            log::warn!("invalid signature of {} of user {}, accepted", request, user_id);
+            return Ok(false);
+        }
+        Err(market::MarketError::SignatureInvalid.into())
+    }
+
+    // a request which has no signature, refused when the signatures are needed
+    fn check_unsigned(&self, user_id: u32, request: &str) -> Result<(), Status> {
+        match self.settings.check_eddsa_signatue {
+            config::OrderSignatrueCheck::Needed => Err(market::MarketError::SignatureInvalid.into()),
+            config::OrderSignatrueCheck::LogOnly => {
+                log::warn!("unsigned {} of user {}, accepted", request, user_id);
+                Ok(())
+            }
+            config::OrderSignatrueCheck::None | config::OrderSignatrueCheck::Auto => Ok(()),
+        }
+    }
+
+    // signed cancels and withdrawals share the nonces of the orders, zero is no nonce
+    fn check_nonce(&self, user_id: u32, nonce: u64) -> Result<(), Status> {
+        let last = self.user_nonces.last(user_id);
+        if nonce != 0 && nonce <= last {
+            return Err(market::MarketError::NonceTooLow { nonce, last }.into());
+        }
+        Ok(())
+    }
+
+    // the orders of a batch have no nonce, so they are refused when the signatures are needed
+    pub fn check_order_signature(&self, req: &OrderPutRequest, nonce: u64) -> Result<(), Status> {
+        // an alias is resolved first, the order is signed with the tokens of the market
+        let market = match self.markets.get(&self.canonical_market(&req.market)) {
+            Some(market) => market,
+            None => return Err(Status::invalid_argument("invalid market")),
+        };
+        // without a nonce the same signed order could be put again
+        if self.settings.check_eddsa_signatue == config::OrderSignatrueCheck::Needed && nonce == 0 {
+            return Err(Status::invalid_argument("the nonce of a signed order is needed"));
+        }
+        let request = format!("an order in market {}", market.name);
+        self.check_signature(req.user_id, &req.signature, &request, || {
+            let order = self
+                .balance_manager
+                .asset_manager
+                .commit_order(req, nonce, market)
+                .map_err(|_| Status::invalid_argument("invalid order params"))?;
+            Ok(order.hash())
+        })?;
+        Ok(())
+    }
+
     pub fn order_put(&mut self, real: bool, req: OrderPutRequest) -> Result<OrderInfo, Status> {
+        self.signed_order_put(real, SignedOrderPutRequest { order: req, nonce: 0 })
+    }
+
+    pub fn signed_order_put(&mut self, real: bool, mut req: SignedOrderPutRequest) -> Result<OrderInfo, Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
-        let order = self.put_order(real, &req)?;
-        if real {
-            self.append_operation_log(OPERATION_ORDER_PUT, &req);
+        let before = self.conservation_snapshot();
+        req.order.market = self.canonical_market(&req.order.market);
+        let order_id = self.sequencer.get_order_id();
+        let order = self.put_order(real, &req.order, req.nonce);
+        // an order failing after it was created may have traded already, so the replay has to put it too
+        if real && (order.is_ok() || self.sequencer.get_order_id() != order_id) {
+            self.append_operation_log_with(OPERATION_ORDER_PUT, &req, order.is_err());
         }
-        Ok(OrderInfo::from(order))
+        self.check_conservation(real, OPERATION_ORDER_PUT, before, &[]);
+        Ok(OrderInfo::from(order?))
     }
 
-    pub fn batch_order_put(&mut self, real: bool, req: BatchOrderPutRequest) -> Result<BatchOrderPutResponse, Status> {
+    pub fn batch_order_put(&mut self, real: bool, mut req: BatchOrderPutRequest) -> Result<BatchOrderPutResponse, Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        let before = self.conservation_snapshot();
+        req.market = self.canonical_market(&req.market);
+        for order_req in &mut req.orders {
+            order_req.market = self.canonical_market(&order_req.market);
+        }
         let market_name = &req.market;
         if !self.markets.contains_key(market_name) {
             return Err(Status::invalid_argument("invalid market"));
         }
         let orders = &req.orders;
+        // checked before anything is canceled or put
+        if orders.iter().any(|order_req| market_name != &order_req.market) {
+            return Err(Status::invalid_argument("inconsistent order markets"));
+        }
         if req.reset {
             for order_req in orders {
-                if market_name != &order_req.market {
-                    return Err(Status::invalid_argument("inconsistent order markets"));
-                }
                 let market = self.markets.get_mut(market_name).unwrap();
                 let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-                market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, order_req.user_id);
+                let canceled = market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, order_req.user_id);
+                // the orders are canceled before a violation is returned
+                if matches!(canceled, Err(market::MarketError::InvariantViolated(_))) {
+                    if real {
+                        self.append_operation_log_with(OPERATION_BATCH_ORDER_PUT, &req, true);
+                    }
+                    self.check_conservation(real, OPERATION_BATCH_ORDER_PUT, before, &[]);
+                    return Err(canceled.unwrap_err().into());
+                }
+                canceled.map_err(Status::from)?;
             }
         }
         let mut result_code = ResultCode::Success;
         let mut error_message = "".to_string();
         let mut order_ids = Vec::with_capacity(orders.len());
         for order_req in orders {
-            if market_name != &order_req.market {
-                return Err(Status::invalid_argument("inconsistent order markets"));
-            }
-
-            match self.put_order(real, order_req) {
+            match self.put_order(real, order_req, 0) {
                 Ok(order) => order_ids.push(order.id),
                 Err(error) => {
                     result_code = ResultCode::InternalError;
@@ -524,6 +1050,7 @@ impl Controller {
         if real {
             self.append_operation_log(OPERATION_BATCH_ORDER_PUT, &req);
         }
+        self.check_conservation(real, OPERATION_BATCH_ORDER_PUT, before, &[]);
         Ok(BatchOrderPutResponse {
             result_code: result_code.into(),
             error_message,
@@ -531,122 +1058,721 @@ impl Controller {
         })
     }
 
-    pub fn order_cancel(&mut self, real: bool, req: OrderCancelRequest) -> Result<OrderInfo, tonic::Status> {
+    pub fn order_cancel(&mut self, real: bool, mut req: OrderCancelRequest) -> Result<OrderInfo, tonic::Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        // the signed cancel is `signed_order_cancel`
+        if real {
+            self.check_unsigned(req.user_id, "a cancel")?;
+        }
+        let before = self.conservation_snapshot();
+        req.market = self.canonical_market(&req.market);
         let market = self
             .markets
             .get_mut(&req.market)
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
-        let order = market
-            .get(req.order_id)
-            .ok_or_else(|| Status::invalid_argument("invalid order_id"))?;
-        if order.user != req.user_id {
-            return Err(Status::invalid_argument("invalid user"));
-        }
         let balance_manager = &mut self.balance_manager;
         //let persistor = self.get_persistor(real);
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-        market.cancel(balance_manager.into(), persistor, order.id);
+        let order = market
+            .cancel(balance_manager.into(), persistor, req.order_id, req.user_id)
+            .map_err(Status::from)?;
         if real {
             self.append_operation_log(OPERATION_ORDER_CANCEL, &req);
         }
+        self.check_conservation(real, OPERATION_ORDER_CANCEL, before, &[]);
         Ok(OrderInfo::from(order))
     }
 
-    pub fn order_cancel_all(&mut self, real: bool, req: OrderCancelAllRequest) -> Result<OrderCancelAllResponse, tonic::Status> {
+    pub fn signed_order_cancel(&mut self, real: bool, mut req: SignedOrderCancelRequest) -> Result<OrderInfo, Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        req.market = self.canonical_market(&req.market);
         let market = self
             .markets
-            .get_mut(&req.market)
+            .get(&req.market)
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
-        //let persistor = self.get_persistor(real);
+        // the replay does not verify again, the key of the user may have changed since
+        if real {
+            self.check_signature(req.user_id, &req.signature, "a cancel", || {
+                let cancel = self
+                    .balance_manager
+                    .asset_manager
+                    .commit_cancel(req.user_id, market, req.order_id, req.nonce)
+                    .map_err(|_| Status::invalid_argument("invalid market"))?;
+                Ok(cancel.hash())
+            })?;
+        }
+        self.check_nonce(req.user_id, req.nonce)?;
+        let before = self.conservation_snapshot();
+        let market = self.markets.get_mut(&req.market).unwrap();
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-        let total = market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, req.user_id) as u32;
+        let order = market
+            .cancel((&mut self.balance_manager).into(), persistor, req.order_id, req.user_id)
+            .map_err(Status::from)?;
+        self.user_nonces.accept(req.user_id, req.nonce);
         if real {
-            self.append_operation_log(OPERATION_ORDER_CANCEL_ALL, &req);
+            self.append_operation_log(OPERATION_SIGNED_ORDER_CANCEL, &req);
         }
-        Ok(OrderCancelAllResponse { total })
+        self.check_conservation(real, OPERATION_SIGNED_ORDER_CANCEL, before, &[]);
+        Ok(OrderInfo::from(order))
     }
 
-    pub async fn debug_dump(&self, _req: DebugDumpRequest) -> Result<DebugDumpResponse, Status> {
-        async {
-            let mut connection = ConnectionType::connect(&self.settings.db_log).await?;
-            crate::persist::dump_to_db(&mut connection, current_timestamp() as i64, self).await
+    pub fn order_cancel_all(&mut self, real: bool, mut req: OrderCancelAllRequest) -> Result<OrderCancelAllResponse, tonic::Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
         }
-        .await
-        .map_err(|err| Status::unknown(format!("{}", err)))?;
-        Ok(DebugDumpResponse {})
+        if real {
+            self.check_unsigned(req.user_id, "a cancel of all the orders")?;
+        }
+        let before = self.conservation_snapshot();
+        req.market = self.canonical_market(&req.market);
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        //let persistor = self.get_persistor(real);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let canceled = market.cancel_all_for_user((&mut self.balance_manager).into(), persistor, req.user_id);
+        // the orders are canceled before a violation is returned
+        let violated = matches!(canceled, Err(market::MarketError::InvariantViolated(_)));
+        if real && (canceled.is_ok() || violated) {
+            self.append_operation_log_with(OPERATION_ORDER_CANCEL_ALL, &req, violated);
+        }
+        self.check_conservation(real, OPERATION_ORDER_CANCEL_ALL, before, &[]);
+        let total = canceled.map_err(Status::from)? as u32;
+        Ok(OrderCancelAllResponse { total })
     }
 
-    fn reset_state(&mut self) {
-        self.sequencer.reset();
-        for market in self.markets.values_mut() {
-            market.reset();
+    pub fn market_fee_update(&mut self, real: bool, mut req: MarketFeeUpdateRequest) -> Result<(), Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
         }
-        //self.log_handler.reset();
-        self.update_controller.reset();
-        self.balance_manager.reset();
-        self.user_manager.reset();
-        //Ok(())
+        req.market = self.canonical_market(&req.market);
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        market.update_fees(req.maker_fee, req.taker_fee).map_err(Status::from)?;
+        if real {
+            self.append_operation_log(OPERATION_MARKET_FEE_UPDATE, &req);
+        }
+        Ok(())
     }
 
-    pub async fn market_reload(&mut self, from_scratch: bool) -> Result<(), Status> {
-        if from_scratch {
-            self.market_load_cfg.reset_load_time();
+    pub fn fee_discount_update(&mut self, real: bool, req: FeeDiscountUpdateRequest) -> Result<(), Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
         }
-
-        //assets and markets can be updated respectively, and must be handled one
-        //after another
-        let new_assets = self
-            .market_load_cfg
-            .load_asset_from_db(&self.db_pool)
-            .await
-            .map_err(|e| tonic::Status::internal(e.to_string()))?;
-
-        self.balance_manager.asset_manager.append(&new_assets);
-
-        let new_markets = self
-            .market_load_cfg
-            .load_market_from_db(&self.db_pool)
-            .await
-            .map_err(|e| tonic::Status::internal(e.to_string()))?;
-
-        for entry in new_markets.into_iter() {
-            let handle_ret = if self.markets.get(&entry.name).is_none() {
-                market::Market::new(&entry, &self.settings, &self.balance_manager).map(|mk| {
-                    self.markets.insert(entry.name.clone(), mk);
-                    self.asset_market_names.insert((entry.base, entry.quote), entry.name);
-                })
-            } else {
-                Err(anyhow!("market {} is duplicated", entry.name))
-            };
-
-            if let Err(e) = handle_ret {
-                log::error!("On handle append market fail: {}", e);
-            }
+        if self.fee_manager.discount.is_none() {
+            return Err(Status::failed_precondition("the fee discount is not configured"));
+        }
+        self.fee_manager.set_fee_discount(req.user_id, req.opt_in);
+        if real {
+            self.append_operation_log(OPERATION_FEE_DISCOUNT_UPDATE, &req);
         }
-
         Ok(())
     }
 
-    pub fn transfer(&mut self, real: bool, req: TransferRequest) -> Result<TransferResponse, Status> {
+    pub fn market_state_update(&mut self, real: bool, mut req: MarketStateUpdateRequest) -> Result<(), Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
-
-        let asset = &req.asset;
-        if !self.balance_manager.asset_manager.asset_exist(asset) {
-            return Err(Status::invalid_argument("invalid asset"));
+        req.market = self.canonical_market(&req.market);
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        market.set_state(persistor, req.state, req.reason.clone());
+        if real {
+            self.append_operation_log(OPERATION_MARKET_STATE_UPDATE, &req);
         }
+        Ok(())
+    }
 
-        let from_user_id = req.from;
-        let to_user_id = req.to;
-        if !self.user_manager.users.contains_key(&to_user_id) {
-            return Err(Status::invalid_argument("invalid to_user"));
+    // returns how many resting orders are canceled
+    pub fn market_params_update(&mut self, real: bool, mut req: MarketParamsUpdateRequest) -> Result<usize, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        req.market = self.canonical_market(&req.market);
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let canceled = market.update_params(
+            (&mut self.balance_manager).into(),
+            persistor,
+            req.amount_prec,
+            req.price_prec,
+            req.min_amount,
+        );
+        // the params are applied and every stranded order finished before a violation is returned
+        let violated = matches!(canceled, Err(market::MarketError::InvariantViolated(_)));
+        if real && (canceled.is_ok() || violated) {
+            self.append_operation_log_with(OPERATION_MARKET_PARAMS_UPDATE, &req, violated);
+        }
+        self.check_conservation(real, OPERATION_MARKET_PARAMS_UPDATE, before, &[]);
+        Ok(canceled.map_err(Status::from)?.len())
+    }
+
+    // cancel all the orders of a market and remove it, the orders put to it later are rejected as an invalid market.
+    // returns how many orders are canceled
+    pub fn close_market(&mut self, real: bool, mut req: MarketCloseRequest) -> Result<usize, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        req.market = self.canonical_market(&req.market);
+        let mut market = self
+            .markets
+            .remove(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        self.asset_market_names.retain(|_, name| name != &req.market);
+        self.market_aliases.retain(|_, name| name != &req.market);
+        self.closed_markets.push(req.market.to_string());
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        market.set_state(persistor, market::MarketState::CancelOnly, req.reason.clone());
+        let total = market.drain_all_orders((&mut self.balance_manager).into(), persistor);
+        if real {
+            self.append_operation_log(OPERATION_MARKET_CLOSE, &req);
+        }
+        self.check_conservation(real, OPERATION_MARKET_CLOSE, before, &[]);
+        Ok(total)
+    }
+
+    // move the amount from AVAILABLE to WITHDRAWING until the withdrawal is confirmed or rejected
+    pub fn withdraw_request(&mut self, real: bool, mut req: WithdrawRequest) -> Result<(), Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        if !self.balance_manager.asset_manager.asset_exist(&req.asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        // the amount is signed as requested, before the rounding
+        let verified = real
+            && self.check_signature(req.user_id, &req.signature, "a withdrawal", || {
+                let withdraw = self
+                    .balance_manager
+                    .asset_manager
+                    .commit_withdraw(req.user_id, &req.asset, &req.amount, req.business_id, req.nonce)
+                    .map_err(|_| Status::invalid_argument("invalid amount"))?;
+                Ok(withdraw.hash())
+            })?;
+        self.check_nonce(req.user_id, req.nonce)?;
+        // the verified signature goes into the history
+        let signature = if verified {
+            dto::decode_signature(&req.signature)
+                .map_err(|_| Status::invalid_argument("invalid signature"))?
+                .to_vec()
+        } else {
+            Vec::new()
+        };
+        let prec = self.balance_manager.asset_manager.asset_prec_show(&req.asset);
+        req.amount = req.amount.round_dp_with_strategy(prec, RoundingStrategy::ToNegativeInfinity);
+        let market_price = self.usdt_price(&req.asset);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.withdraw_manager
+            .request(
+                &mut self.balance_manager,
+                persistor,
+                market_price,
+                PendingWithdraw {
+                    business_id: req.business_id,
+                    user_id: req.user_id,
+                    asset: req.asset.clone(),
+                    business: req.business.clone(),
+                    amount: req.amount,
+                },
+                signature,
+            )
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        self.user_nonces.accept(req.user_id, req.nonce);
+        if real {
+            self.append_operation_log(OPERATION_WITHDRAW_REQUEST, &req);
+        }
+        self.check_conservation(real, OPERATION_WITHDRAW_REQUEST, before, &[]);
+        Ok(())
+    }
+
+    // the withdrawal succeeded on chain, the pending amount is burnt and published as a withdraw
+    pub fn withdraw_confirm(&mut self, real: bool, req: WithdrawFinishRequest) -> Result<PendingWithdraw, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        let market_price = match self.withdraw_manager.get(req.business_id) {
+            Some(withdraw) => self.usdt_price(&withdraw.asset),
+            None => return Err(Status::invalid_argument("withdraw not found")),
+        };
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let withdraw = self
+            .withdraw_manager
+            .confirm(&mut self.balance_manager, persistor, market_price, req.business_id)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_WITHDRAW_CONFIRM, &req);
+        }
+        self.check_conservation(real, OPERATION_WITHDRAW_CONFIRM, before, &[(&withdraw.asset, -withdraw.amount)]);
+        Ok(withdraw)
+    }
+
+    // the withdrawal failed on chain, the pending amount is returned to AVAILABLE
+    pub fn withdraw_reject(&mut self, real: bool, req: WithdrawFinishRequest) -> Result<PendingWithdraw, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        let market_price = match self.withdraw_manager.get(req.business_id) {
+            Some(withdraw) => self.usdt_price(&withdraw.asset),
+            None => return Err(Status::invalid_argument("withdraw not found")),
+        };
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let withdraw = self
+            .withdraw_manager
+            .reject(&mut self.balance_manager, persistor, market_price, req.business_id)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_WITHDRAW_REJECT, &req);
+        }
+        self.check_conservation(real, OPERATION_WITHDRAW_REJECT, before, &[]);
+        Ok(withdraw)
+    }
+
+    // lock a part of the AVAILABLE balance for an operation out of the engine, e.g. an otc settlement
+    pub fn balance_lock(&mut self, real: bool, req: BalanceLockRequest) -> Result<(), Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        self.balance_manager
+            .lock(req.user_id, &req.asset, &req.amount, req.lock_id)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_BALANCE_LOCK, &req);
+        }
+        self.check_conservation(real, OPERATION_BALANCE_LOCK, before, &[]);
+        Ok(())
+    }
+
+    // the operation is cancelled, the remaining locked amount is returned to AVAILABLE
+    pub fn balance_unlock(&mut self, real: bool, req: BalanceUnlockRequest) -> Result<LockRecord, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        let lock = self
+            .balance_manager
+            .unlock(req.lock_id)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_BALANCE_UNLOCK, &req);
+        }
+        self.check_conservation(real, OPERATION_BALANCE_UNLOCK, before, &[]);
+        Ok(lock)
+    }
+
+    // the operation is settled, the amount leaves the LOCK balance and is published as a balance history
+    pub fn balance_lock_consume(&mut self, real: bool, req: BalanceLockConsumeRequest) -> Result<LockRecord, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        let locked = match self.balance_manager.locks.get(&req.lock_id) {
+            Some(lock) => lock.amount,
+            None => return Err(Status::invalid_argument("lock not found")),
+        };
+        let lock = self
+            .balance_manager
+            .consume(req.lock_id, &req.amount)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        let consumed = locked - lock.amount;
+        if real {
+            let balance_available = self.balance_manager.get(lock.user_id, BalanceType::AVAILABLE, &lock.asset);
+            let balance_frozen = self.balance_manager.get(lock.user_id, BalanceType::FREEZE, &lock.asset);
+            let history = models::BalanceHistory {
+                time: self.clock.now().into(),
+                user_id: lock.user_id as i32,
+                business_id: lock.lock_id as i64,
+                asset: lock.asset.clone(),
+                business: "lock_consume".to_string(),
+                market_price: self.usdt_price(&lock.asset),
+                change: -consumed,
+                balance: balance_available + balance_frozen,
+                balance_available,
+                balance_frozen,
+                detail: json!({"id": lock.lock_id, "remaining": lock.amount}).to_string(),
+                signature: vec![],
+            };
+            self.persistor.put_balance(&history);
+            self.append_operation_log(OPERATION_BALANCE_LOCK_CONSUME, &req);
+        }
+        self.check_conservation(real, OPERATION_BALANCE_LOCK_CONSUME, before, &[(&lock.asset, -consumed)]);
+        Ok(lock)
+    }
+
+    // correct the AVAILABLE balance of a user, e.g. for a chargeback. the operator and the reason are kept in the history
+    pub fn admin_adjust_balance(&mut self, real: bool, req: AdminAdjustBalanceRequest) -> Result<(), Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        let market_price = self.usdt_price(&req.asset);
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        self.update_controller
+            .adjust(
+                &mut self.balance_manager,
+                persistor,
+                AdjustmentParams {
+                    user_id: req.user_id,
+                    business_id: req.business_id,
+                    asset: req.asset.clone(),
+                    market_price,
+                    delta: req.delta,
+                    reason: req.reason.clone(),
+                    operator_id: req.operator_id,
+                },
+            )
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            log::info!(
+                "balance of user {} adjusted by {} {} by operator {}: {}",
+                req.user_id,
+                req.delta,
+                req.asset,
+                req.operator_id,
+                req.reason
+            );
+            self.append_operation_log(OPERATION_ADMIN_ADJUST_BALANCE, &req);
+        }
+        self.check_conservation(real, OPERATION_ADMIN_ADJUST_BALANCE, before, &[(&req.asset, req.delta)]);
+        Ok(())
+    }
+
+    // the locks of a user, the locked amounts are also in the LOCK balances
+    pub fn balance_lock_query(&self, user_id: u32) -> Vec<LockRecord> {
+        self.balance_manager.get_locks(user_id)
+    }
+
+    // whether a deposit or a withdraw with the business id is processed recently,
+    // so that the caller can tell if a retry is needed
+    pub fn balance_update_processed(&self, user_id: u32, business: &str, business_id: u64) -> bool {
+        [BusinessType::Deposit, BusinessType::Withdraw]
+            .iter()
+            .any(|business_type| self.update_controller.contains(*business_type, business, business_id, user_id))
+    }
+
+    // the last price of the market of the asset and USDT, zero if there is no such market
+    fn usdt_price(&self, asset: &str) -> Decimal {
+        self.asset_market_names
+            .get(&(asset.to_owned(), "USDT".to_owned()))
+            .map_or(Decimal::zero(), |market_name| self.markets.get(market_name).unwrap().price)
+    }
+
+    // the totals of the assets before a command, only taken when `verify_conservation` is on
+    fn conservation_snapshot(&self) -> Option<BTreeMap<String, Decimal>> {
+        if self.settings.verify_conservation {
+            Some(self.balance_manager.snapshot_totals())
+        } else {
+            None
+        }
+    }
+
+    // the violations are logged and emitted, the command is not reverted
+    fn check_conservation(
+        &mut self,
+        real: bool,
+        operation: &str,
+        before: Option<BTreeMap<String, Decimal>>,
+        expected_changes: &[(&str, Decimal)],
+    ) {
+        let before = match before {
+            Some(before) => before,
+            None => return,
+        };
+        let after = self.balance_manager.snapshot_totals();
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        for violation in audit::check_conservation(operation, &before, &after, expected_changes) {
+            log::error!("asset conservation violated: {:?}", violation);
+            persistor.put_conservation_violation(&violation);
+        }
+    }
+
+    // compares the frozen balances with the open orders, the mismatches are logged.
+    // with `fail_on_mismatch` an error is returned if there is any, otherwise the report is returned
+    pub fn audit_frozen(&self, fail_on_mismatch: bool) -> Result<Vec<FrozenMismatch>, Status> {
+        let report = audit::audit_frozen(&self.balance_manager, self.markets.values());
+        for mismatch in &report {
+            log::error!("frozen balance mismatch: {:?}", mismatch);
+        }
+        if fail_on_mismatch && !report.is_empty() {
+            return Err(Status::internal(format!("{} frozen balance mismatches", report.len())));
+        }
+        Ok(report)
+    }
+
+    // repair the FREEZE balance of a user found by `audit_frozen`, see `audit::reconcile_frozen`
+    pub fn reconcile_frozen(&mut self, real: bool, req: FrozenReconcileRequest) -> Result<FrozenReconcile, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        if !self.balance_manager.asset_manager.asset_exist(&req.asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        let before = self.conservation_snapshot();
+        let business_id = self.clock.now().as_millis();
+        let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
+        let result = audit::reconcile_frozen(
+            &mut self.balance_manager,
+            self.markets.values(),
+            &mut self.update_controller,
+            persistor,
+            req.user_id,
+            &req.asset,
+            business_id,
+        )
+        .map_err(|e| Status::internal(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_FROZEN_RECONCILE, &req);
+        }
+        self.check_conservation(real, OPERATION_FROZEN_RECONCILE, before, &[]);
+        Ok(result)
+    }
+
+    // wait until the persistors have written what the commands so far emitted, before a slice is made or on shutdown
+    pub fn flush_persistors(&mut self) -> anyhow::Result<()> {
+        self.persistor.flush()
+    }
+
+    // the partial batches buffered for longer than their window, when no command came to write them
+    pub fn flush_elapsed_batches(&mut self) {
+        self.persistor.flush_elapsed();
+    }
+
+    // after the last flush on shutdown, the producers get `kafka_shutdown_timeout` to deliver the rest
+    pub fn shutdown_persistors(&mut self) {
+        self.persistor.shutdown()
+    }
+
+    pub async fn debug_dump(&self, _req: DebugDumpRequest) -> Result<DebugDumpResponse, Status> {
+        async {
+            let mut connection = ConnectionType::connect(&self.settings.db_log).await?;
+            crate::persist::dump_to_db(&mut connection, current_timestamp() as i64, self).await?;
+            if !self.settings.state_export_path.is_empty() {
+                let file = std::fs::File::create(&self.settings.state_export_path)?;
+                self.export_state(std::io::BufWriter::new(file))?;
+            }
+            SimpleResult::Ok(())
+        }
+        .await
+        .map_err(|err| Status::unknown(format!("{}", err)))?;
+        Ok(DebugDumpResponse {})
+    }
+
+    // the events after the snapshot are received by the subscriber, none if there is no replica feed
+    pub fn subscribe_replica(&self) -> Option<(BroadcastSubscriber, Snapshot)> {
+        let replica_feed = self.replica_feed.as_ref()?;
+        Some((replica_feed.subscribe(BroadcastFilter::all()), self.make_snapshot()))
+    }
+
+    pub fn export_state(&self, writer: impl std::io::Write) -> SimpleResult {
+        crate::persist::export_state(writer, &self.sequencer, &self.balance_manager, self.markets.values())
+    }
+
+    pub fn make_snapshot(&self) -> Snapshot {
+        Snapshot::take(
+            self.clock.now().as_secs_f64(),
+            &self.sequencer,
+            &self.balance_manager,
+            &self.withdraw_manager,
+            &self.user_nonces,
+            &self.user_manager,
+            &self.fee_manager,
+            self.markets.values(),
+            &self.closed_markets,
+        )
+    }
+
+    // into the state after `reset_state`, the operations after the snapshot are replayed then
+    pub fn load_snapshot(&mut self, snapshot: Snapshot) -> SimpleResult {
+        let closed_markets = snapshot.closed_markets.clone();
+        snapshot.restore(
+            &mut self.sequencer,
+            &mut self.balance_manager,
+            &mut self.withdraw_manager,
+            &self.user_nonces,
+            &mut self.user_manager,
+            &mut self.fee_manager,
+            &mut self.markets,
+        )?;
+        // the closed markets are removed from the map by the restore
+        let markets = &self.markets;
+        self.asset_market_names.retain(|_, name| markets.contains_key(name));
+        self.market_aliases.retain(|_, name| markets.contains_key(name));
+        self.closed_markets = closed_markets;
+        self.refresh_fee_discount_prices();
+        Ok(())
+    }
+
+    // taken before a slice is made, kept for the rollbacks
+    pub fn keep_snapshot(&mut self) {
+        if self.settings.snapshot_history > 0 {
+            let snapshot = self.make_snapshot();
+            self.snapshots.push(snapshot);
+        }
+    }
+
+    // rewinds to right after the logged operation `target` for an incident, see `persist::rollback_to`.
+    // `ops` are the logged operations up to `target` at least. the tombstone of the operations after it is
+    // persisted, so the later replays skip them too
+    pub fn rollback_to(&mut self, target: u64, ops: impl IntoIterator<Item = OperationLogEntry>) -> anyhow::Result<Tombstone> {
+        self.rolling_back = true;
+        let result = self.rewind_to(target, ops);
+        self.rolling_back = false;
+        result
+    }
+
+    fn rewind_to(&mut self, target: u64, ops: impl IntoIterator<Item = OperationLogEntry>) -> anyhow::Result<Tombstone> {
+        // the events before the rollback are not mixed with the ones of the replay
+        self.persistor.flush()?;
+        let snapshots = std::mem::take(&mut self.snapshots);
+        let time = self.clock.now().as_secs_f64();
+        let result = crate::persist::rollback_to(self, target, &snapshots, ops, time);
+        self.snapshots = snapshots;
+        let tombstone = result.map_err(|e| {
+            log::error!("rollback to operation {} failed, the state is undefined: {}", target, e);
+            e
+        })?;
+        self.snapshots.truncate_after(target);
+        self.tombstones.push(tombstone);
+        self.persistor.put_tombstone(&tombstone);
+        Ok(tombstone)
+    }
+
+    // as they were loaded, the closes are replayed after
+    fn reopen_closed_markets(&mut self) {
+        for name in std::mem::take(&mut self.closed_markets) {
+            let entry = match self.market_configs.get(&name) {
+                Some(entry) => entry.clone(),
+                None => {
+                    log::error!("the config of the closed market {} is not found", name);
+                    continue;
+                }
+            };
+            let reopened = market::Market::new(&entry, &self.settings, &self.balance_manager).and_then(|mut market| {
+                market.clock = self.clock.clone();
+                market.user_orders = self.user_orders.clone();
+                market.user_nonces = self.user_nonces.clone();
+                market.trade_audit = self.trade_audit.clone();
+                add_market_aliases(&mut self.market_aliases, &self.markets, &entry)?;
+                self.markets.insert(entry.name.clone(), market);
+                self.asset_market_names.insert((entry.base, entry.quote), entry.name);
+                Ok(())
+            });
+            if let Err(e) = reopened {
+                log::error!("reopen the closed market {} failed: {}", name, e);
+            }
+        }
+    }
+
+    // `rollback_to` with the operations in the log
+    pub async fn rollback_to_logged(&mut self, target: u64) -> anyhow::Result<Tombstone> {
+        let start = self
+            .snapshots
+            .at_or_before(target)
+            .map_or(0, |snapshot| snapshot.sequencer.operation_log_id);
+        let mut conn = ConnectionType::connect(&self.settings.db_log).await?;
+        let ops = crate::persist::load_operations(&mut conn, start, target).await?;
+        self.rollback_to(target, ops)
+    }
+
+    fn reset_state(&mut self) {
+        self.sequencer.reset();
+        for market in self.markets.values_mut() {
+            market.reset();
+        }
+        //self.log_handler.reset();
+        self.update_controller.reset();
+        self.withdraw_manager.reset();
+        self.balance_manager.reset();
+        self.user_nonces.clear();
+        self.user_manager.reset();
+        self.fee_manager.discount_users.clear();
+        //Ok(())
+    }
+
+    pub async fn market_reload(&mut self, from_scratch: bool) -> Result<(), Status> {
+        if from_scratch {
+            self.market_load_cfg.reset_load_time();
+        }
+
+        //assets and markets can be updated respectively, and must be handled one
+        //after another
+        let new_assets = self
+            .market_load_cfg
+            .load_asset_from_db(&self.db_pool)
+            .await
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+
+        self.balance_manager
+            .asset_manager
+            .append(&new_assets)
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+
+        let new_markets = self
+            .market_load_cfg
+            .load_market_from_db(&self.db_pool)
+            .await
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+
+        for entry in new_markets.into_iter() {
+            let handle_ret = if self.markets.get(&entry.name).is_none() && !self.market_aliases.contains_key(&entry.name) {
+                market::Market::new(&entry, &self.settings, &self.balance_manager).and_then(|mut mk| {
+                    mk.clock = self.clock.clone();
+                    mk.user_orders = self.user_orders.clone();
+                    mk.user_nonces = self.user_nonces.clone();
+                    mk.trade_audit = self.trade_audit.clone();
+                    add_market_aliases(&mut self.market_aliases, &self.markets, &entry)?;
+                    self.persistor.put_market_event(mk.created_event());
+                    self.closed_markets.retain(|name| name != &entry.name);
+                    self.market_configs.insert(entry.name.clone(), entry.clone());
+                    self.markets.insert(entry.name.clone(), mk);
+                    self.asset_market_names.insert((entry.base, entry.quote), entry.name);
+                    Ok(())
+                })
+            } else {
+                Err(anyhow!("market {} is duplicated", entry.name))
+            };
+
+            if let Err(e) = handle_ret {
+                log::error!("On handle append market fail: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn transfer(&mut self, real: bool, req: TransferRequest) -> Result<TransferResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+
+        let asset = &req.asset;
+        if !self.balance_manager.asset_manager.asset_exist(asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+
+        let from_user_id = req.from;
+        let to_user_id = req.to;
+        if !self.user_manager.users.contains_key(&to_user_id) {
+            return Err(Status::invalid_argument("invalid to_user"));
         }
 
         let balance_manager = &self.balance_manager;
@@ -666,78 +1792,84 @@ impl Controller {
         let prec = self.balance_manager.asset_manager.asset_prec_show(asset);
         let change = delta.round_dp_with_strategy(prec, RoundingStrategy::ToNegativeInfinity);
 
-        let business = "transfer";
-        let timestamp = FTimestamp(current_timestamp());
-        let business_id = (timestamp.0 * 1_000_f64) as u64; // milli-seconds
+        let now = self.clock.now();
+        let timestamp = FTimestamp::from(now);
+        let business_id = now.as_millis();
         let detail_json: serde_json::Value = if req.memo.is_empty() {
             json!({})
         } else {
             serde_json::from_str(req.memo.as_str()).map_err(|_| Status::invalid_argument("invalid memo"))?
         };
 
-        // Get market price of requested base asset and quote asset of USDT.
-        let market_price = self
-            .asset_market_names
-            .get(&(asset.to_owned(), "USDT".to_owned()))
-            .map_or(Decimal::zero(), |market_name| self.markets.get(market_name).unwrap().price);
+        let market_price = self.usdt_price(asset);
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
         self.update_controller
-            .update_user_balance(
+            .transfer(
                 &mut self.balance_manager,
                 persistor,
-                BalanceUpdateParams {
-                    balance_type: BalanceType::AVAILABLE,
-                    business_type: BusinessType::Transfer,
-                    user_id: from_user_id,
-                    asset: asset.to_owned(),
-                    business: business.to_owned(),
+                TransferParams {
+                    from_user_id,
+                    to_user_id,
                     business_id,
+                    asset: asset.to_owned(),
+                    business: "transfer".to_owned(),
                     market_price,
-                    change: -change,
-                    detail: detail_json.clone(),
-                    signature: vec![],
+                    amount: change,
+                    detail: detail_json,
+                    signature: req.signature.as_bytes().to_vec(),
                 },
             )
             .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
 
+        if real {
+            self.append_operation_log(OPERATION_TRANSFER, &req);
+        }
+
+        self.check_conservation(real, OPERATION_TRANSFER, before, &[]);
+        Ok(TransferResponse {
+            success: true,
+            asset: asset.to_owned(),
+            balance_from: (balance_from - change).to_string(),
+        })
+    }
+
+    pub fn internal_transfer(&mut self, real: bool, req: InternalTransferRequest) -> Result<(), Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let before = self.conservation_snapshot();
+        if !self.user_manager.users.contains_key(&req.to) {
+            return Err(Status::invalid_argument("invalid to_user"));
+        }
+        let detail_json: serde_json::Value = if req.memo.is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(req.memo.as_str()).map_err(|_| Status::invalid_argument("invalid memo"))?
+        };
+        let market_price = self.usdt_price(&req.asset);
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
         self.update_controller
-            .update_user_balance(
+            .transfer(
                 &mut self.balance_manager,
                 persistor,
-                BalanceUpdateParams {
-                    balance_type: BalanceType::AVAILABLE,
-                    business_type: BusinessType::Transfer,
-                    user_id: to_user_id,
-                    asset: asset.to_owned(),
-                    business: business.to_owned(),
-                    business_id,
-                    market_price: Decimal::zero(),
-                    change,
+                TransferParams {
+                    from_user_id: req.from,
+                    to_user_id: req.to,
+                    business_id: req.business_id,
+                    asset: req.asset.clone(),
+                    business: "transfer".to_owned(),
+                    market_price,
+                    amount: req.amount,
                     detail: detail_json,
                     signature: vec![],
                 },
             )
             .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
-
         if real {
-            self.persistor.put_transfer(models::InternalTx {
-                time: timestamp.into(),
-                user_from: from_user_id as i32, // TODO: will this overflow?
-                user_to: to_user_id as i32,     // TODO: will this overflow?
-                asset: asset.to_owned(),
-                amount: change,
-                signature: req.signature.as_bytes().to_vec(),
-            });
-
-            self.append_operation_log(OPERATION_TRANSFER, &req);
+            self.append_operation_log(OPERATION_INTERNAL_TRANSFER, &req);
         }
-
-        Ok(TransferResponse {
-            success: true,
-            asset: asset.to_owned(),
-            balance_from: (balance_from - change).to_string(),
-        })
+        self.check_conservation(real, OPERATION_INTERNAL_TRANSFER, before, &[]);
+        Ok(())
     }
 
     pub async fn debug_reset(&mut self, _req: DebugResetRequest) -> Result<DebugResetResponse, Status> {
@@ -819,6 +1951,64 @@ impl Controller {
         Ok(DebugReloadResponse {})
     }
 
+    // replay the logged operations with the persistence disabled, each at the time it was logged,
+    // so they make the same state as they made
+    pub fn replay_operations(&mut self, ops: impl IntoIterator<Item = OperationLogEntry>) -> SimpleResult {
+        for op in ops {
+            self.replay_operation(&op)?;
+        }
+        Ok(())
+    }
+
+    fn replay_operation(&mut self, op: &OperationLogEntry) -> SimpleResult {
+        if let Some(tombstone) = self.tombstones.find(op.id).copied() {
+            tombstone.skip(op.id, &mut self.sequencer);
+            return Ok(());
+        }
+        log::info!("replay {} {}", &op.method, &op.params);
+        self.clock.fix(TimestampMs::from_secs_f64(op.time));
+        let result = self.replay(&op.method, &op.params);
+        self.clock.release();
+        match result {
+            Err(e) if !op.failed => bail!("replay operation {} failed: {}", op.id, e),
+            Err(e) => log::info!("replay operation {} failed as it was logged: {}", op.id, e),
+            Ok(()) if op.failed => bail!("replay operation {} succeeded, it was logged as failed", op.id),
+            Ok(()) => {}
+        }
+        self.sequencer.set_operation_log_id(op.id);
+        Ok(())
+    }
+
+    // replay the operations strictly beyond the restored one after a crash, emitting the events the sinks
+    // have not got by the watermarks again, see `ReplayPersistor`.
+    // the events only made for the operations from the clients, e.g. registering a user, are not emitted again
+    pub fn replay_reconciled(
+        &mut self,
+        ops: impl IntoIterator<Item = OperationLogEntry>,
+        watermarks: &Watermarks,
+    ) -> anyhow::Result<ReplayReport> {
+        let restored_op_id = self.sequencer.get_operation_log_id();
+        let persistor = std::mem::replace(&mut self.persistor, DummyPersistor::new_box());
+        let replay = ReplayPersistor::new(persistor, self.sequencer.msg_seq(), *watermarks);
+        let dummy = std::mem::replace(&mut self.dummy_persistor, Box::new(replay.clone()));
+        let mut result = Ok(());
+        for op in ops {
+            if op.id <= restored_op_id {
+                replay.skip();
+                continue;
+            }
+            replay.begin(&self.sequencer.state());
+            result = self.replay_operation(&op);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.dummy_persistor = dummy;
+        let (persistor, report) = replay.into_inner();
+        self.persistor = persistor;
+        result.map(|_| report)
+    }
+
     // reload 1000 in batch and replay
     pub fn replay(&mut self, method: &str, params: &str) -> SimpleResult {
         match method {
@@ -831,8 +2021,11 @@ impl Controller {
             OPERATION_ORDER_CANCEL_ALL => {
                 self.order_cancel_all(false, serde_json::from_str(params)?)?;
             }
+            OPERATION_SIGNED_ORDER_CANCEL => {
+                self.signed_order_cancel(false, serde_json::from_str(params)?)?;
+            }
             OPERATION_ORDER_PUT => {
-                self.order_put(false, serde_json::from_str(params)?)?;
+                self.signed_order_put(false, serde_json::from_str(params)?)?;
             }
             OPERATION_BATCH_ORDER_PUT => {
                 self.batch_order_put(false, serde_json::from_str(params)?)?;
@@ -840,14 +2033,56 @@ impl Controller {
             OPERATION_TRANSFER => {
                 self.transfer(false, serde_json::from_str(params)?)?;
             }
+            OPERATION_INTERNAL_TRANSFER => {
+                self.internal_transfer(false, serde_json::from_str(params)?)?;
+            }
             OPERATION_REGISTER_USER => {
                 self.register_user(false, serde_json::from_str(params)?)?;
             }
+            OPERATION_FEE_DISCOUNT_UPDATE => {
+                self.fee_discount_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_MARKET_FEE_UPDATE => {
+                self.market_fee_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_MARKET_STATE_UPDATE => {
+                self.market_state_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_MARKET_PARAMS_UPDATE => {
+                self.market_params_update(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_MARKET_CLOSE => {
+                self.close_market(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_WITHDRAW_REQUEST => {
+                self.withdraw_request(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_WITHDRAW_CONFIRM => {
+                self.withdraw_confirm(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_WITHDRAW_REJECT => {
+                self.withdraw_reject(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_BALANCE_LOCK => {
+                self.balance_lock(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_BALANCE_UNLOCK => {
+                self.balance_unlock(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_BALANCE_LOCK_CONSUME => {
+                self.balance_lock_consume(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_ADMIN_ADJUST_BALANCE => {
+                self.admin_adjust_balance(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_FROZEN_RECONCILE => {
+                self.reconcile_frozen(false, serde_json::from_str(params)?)?;
+            }
             _ => bail!("invalid operation {}", method),
         }
         Ok(())
     }
-    fn put_order(&mut self, real: bool, req: &OrderPutRequest) -> Result<Order, Status> {
+    fn put_order(&mut self, real: bool, req: &OrderPutRequest, nonce: u64) -> Result<Order, Status> {
         if !self.markets.contains_key(&req.market) {
             return Err(Status::invalid_argument("invalid market"));
         }
@@ -861,36 +2096,647 @@ impl Controller {
             return Err(Status::unavailable("too many active orders for user"));
         }
         let market = self.markets.get_mut(&req.market).unwrap();
+        let price_before = market.price;
         let balance_manager = &mut self.balance_manager;
         let update_controller = &mut self.update_controller;
         let persistor = if real { &mut self.persistor } else { &mut self.dummy_persistor };
-        let order_input = OrderInput::try_from(req.clone()).map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
-        market
+        let mut order_input = OrderInput::try_from(req.clone()).map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
+        order_input.nonce = nonce;
+        let result = market
             .put_order(
                 &mut self.sequencer,
                 balance_manager.into(),
                 update_controller,
+                &self.fee_manager,
                 persistor,
                 order_input,
             )
-            .map_err(|e| Status::unknown(format!("{}", e)))
+            .map_err(Status::from);
+        let (base, quote, price) = (market.base, market.quote, market.price);
+        if price != price_before {
+            self.update_fee_discount_price(base, quote, price);
+        }
+        result
+    }
+    // the quote asset of a market of the discount asset is priced by its last price
+    fn update_fee_discount_price(&mut self, base: &str, quote: &str, price: Decimal) {
+        let is_discount_market = matches!(&self.fee_manager.discount, Some(discount) if discount.asset == base);
+        if is_discount_market && price.gt(&Decimal::zero()) {
+            self.fee_manager.set_discount_price(quote, Decimal::one() / price);
+        }
+    }
+    // from the prices of all the markets, after they are restored
+    pub fn refresh_fee_discount_prices(&mut self) {
+        let prices = self
+            .markets
+            .values()
+            .map(|market| (market.base, market.quote, market.price))
+            .collect::<Vec<_>>();
+        for (base, quote, price) in prices {
+            self.update_fee_discount_price(base, quote, price);
+        }
     }
     fn append_operation_log<Operation>(&mut self, method: &str, req: &Operation)
+    where
+        Operation: Serialize,
+    {
+        self.append_operation_log_with(method, req, false)
+    }
+
+    // `failed` for an operation which changed the state before it failed, its replay is expected to fail the same way
+    fn append_operation_log_with<Operation>(&mut self, method: &str, req: &Operation, failed: bool)
     where
         Operation: Serialize,
     {
         let params = serde_json::to_string(req).unwrap();
         let operation_log = models::OperationLog {
             id: self.sequencer.next_operation_log_id() as i64,
-            time: FTimestamp(current_timestamp()).into(),
+            time: self.clock.now().into(),
             method: method.to_owned(),
             params,
+            failed,
         };
+        self.persistor.put_operation_log(&OperationLogEntry::from(operation_log.clone()));
         (*self.log_handler).append_operation_log(operation_log).ok();
     }
 }
 
+// the users and the closed markets are reset too, the replay registers and closes them again
+impl Rewind for Controller {
+    fn reset(&mut self) {
+        self.sequencer.reset();
+        for market in self.markets.values_mut() {
+            market.reset();
+        }
+        self.update_controller.reset();
+        self.withdraw_manager.reset();
+        self.balance_manager.reset();
+        self.user_nonces.clear();
+        self.user_manager.reset();
+        self.fee_manager.discount_users.clear();
+        self.reopen_closed_markets();
+        self.eth_guard = EthLogGuard::new(0);
+    }
+    fn load_snapshot(&mut self, snapshot: Snapshot) -> SimpleResult {
+        Controller::load_snapshot(self, snapshot)
+    }
+    fn replay_logged(&mut self, op: &OperationLogEntry) -> SimpleResult {
+        // only the traffic is refused during the rollback, not the replay
+        self.rolling_back = false;
+        let result = self.replay_operation(op);
+        self.rolling_back = true;
+        result
+    }
+    fn sequencer(&mut self) -> &mut Sequencer {
+        &mut self.sequencer
+    }
+}
+
 #[cfg(sqlxverf)]
 fn sqlverf_clear_slice() -> impl std::any::Any {
     sqlx::query!("drop table if exists balance_history, balance_slice")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matchengine::mock::*;
+    use fluidex_common::rust_decimal_macros::*;
+    use std::sync::{Arc, Mutex};
+
+    // the operation log kept in memory in place of the database
+    #[derive(Clone, Default)]
+    struct LoggedOperations(Arc<Mutex<Vec<models::OperationLog>>>);
+
+    impl OperationLogConsumer for LoggedOperations {
+        fn is_block(&self) -> bool {
+            false
+        }
+        fn append_operation_log(&mut self, item: models::OperationLog) -> anyhow::Result<(), models::OperationLog> {
+            self.0.lock().unwrap().push(item);
+            Ok(())
+        }
+    }
+
+    impl LoggedOperations {
+        fn entries(&self) -> Vec<OperationLogEntry> {
+            self.0.lock().unwrap().iter().cloned().map(OperationLogEntry::from).collect()
+        }
+    }
+
+    // a controller with the simple market and no kafka, the pool is never connected
+    fn test_controller() -> (Controller, LoggedOperations) {
+        test_controller_with_clock(EngineClock::default())
+    }
+
+    fn test_controller_with_clock(clock: EngineClock) -> (Controller, LoggedOperations) {
+        let settings = config::Settings {
+            brokers: String::new(),
+            db_log: "postgres://localhost/test".to_string(),
+            assets: get_simple_asset_config(8),
+            markets: vec![get_simple_market_config()],
+            ..Default::default()
+        };
+        let main_pool = sqlx::Pool::<DbType>::connect_lazy(&settings.db_log).unwrap();
+        let log = LoggedOperations::default();
+        let controller = build_controller((settings, MarketConfigs::new()), main_pool, Box::new(log.clone()), clock);
+        (controller, log)
+    }
+
+    fn deposit(user_id: u32, asset: MockAsset, delta: &str) -> BalanceUpdateRequest {
+        BalanceUpdateRequest {
+            user_id,
+            asset: asset.id(),
+            business: "deposit".to_string(),
+            business_id: user_id as u64,
+            delta: delta.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn limit_order(user_id: u32, side: OrderSide, amount: &str, price: &str) -> OrderPutRequest {
+        OrderPutRequest {
+            user_id,
+            market: get_simple_market_config().name,
+            order_side: side as i32,
+            order_type: OrderType::Limit as i32,
+            amount: amount.to_string(),
+            price: price.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // a maker frozen less than it trades fails the next taker after its id is taken
+    fn lose_frozen(controller: &mut Controller, order_id: u64) {
+        let market = controller.markets.get(&get_simple_market_config().name).unwrap();
+        market.orders.get(&order_id).unwrap().borrow_mut().frozen = dec!(0.5);
+    }
+
+    #[tokio::test]
+    async fn test_replay_session() {
+        use crate::clock::ManualClock;
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        // the original session, every operation runs at the time fixed at its start as the server runs it
+        let source = ManualClock::new(TimestampMs(1_600_000_000_000));
+        let (mut controller, log) = test_controller_with_clock(EngineClock::new(source.clone()));
+        let market_name = get_simple_market_config().name;
+        let mut rng = StdRng::seed_from_u64(4341);
+        for user_id in [0, 1, 2] {
+            for (n, (asset, delta)) in [(MockAsset::USDT, "100000"), (MockAsset::ETH, "1000")].into_iter().enumerate() {
+                let req = BalanceUpdateRequest {
+                    business_id: user_id as u64 * 2 + n as u64,
+                    ..deposit(user_id, asset, delta)
+                };
+                controller.update_balance(true, req).unwrap();
+            }
+        }
+        for _ in 0..300 {
+            source.advance(rng.gen_range(1..1000));
+            let fixed = controller.clock.fix_now();
+            assert!(fixed);
+            let open_orders = controller.markets[&market_name]
+                .orders
+                .values()
+                .map(|order| *order.borrow())
+                .collect::<Vec<_>>();
+            if !open_orders.is_empty() && rng.gen_range(0..4) == 0 {
+                let order = &open_orders[rng.gen_range(0..open_orders.len())];
+                let req = OrderCancelRequest {
+                    user_id: order.user,
+                    market: market_name.clone(),
+                    order_id: order.id,
+                };
+                controller.order_cancel(true, req).unwrap();
+            } else {
+                let side = if rng.gen::<bool>() { OrderSide::Bid } else { OrderSide::Ask };
+                let amount = rng.gen_range(1..20).to_string();
+                let price = rng.gen_range(120..140).to_string();
+                let req = OrderPutRequest {
+                    taker_fee: "0.002".to_string(),
+                    maker_fee: "0.001".to_string(),
+                    ..limit_order(rng.gen_range(0..3), side, &amount, &price)
+                };
+                // rejected the same way when replayed
+                if let Ok(order) = controller.order_put(true, req) {
+                    let logged = log.entries().pop().unwrap();
+                    assert_eq!(TimestampMs::from_secs_f64(logged.time).as_millis() as i64, order.create_time);
+                }
+            }
+            controller.clock.release();
+        }
+        assert!(controller.markets[&market_name].trade_count > 0);
+
+        // replayed by a fresh controller at the logged times
+        let (mut replayed, _) = test_controller();
+        replayed.replay_operations(log.entries()).unwrap();
+        let snapshot = |controller: &Controller| {
+            controller.clock.fix(TimestampMs(1_600_001_000_000));
+            let mut snapshot = controller.make_snapshot();
+            // the replay emits no messages
+            snapshot.sequencer.msg_id = 0;
+            let snapshot = serde_json::to_value(snapshot).unwrap();
+            let status = controller.markets[&market_name].status();
+            controller.clock.release();
+            (snapshot, status)
+        };
+        assert_eq!(snapshot(&replayed), snapshot(&controller));
+    }
+
+    #[tokio::test]
+    async fn test_replay_failed_order_put() {
+        let (mut controller, log) = test_controller();
+        controller.update_balance(true, deposit(101, MockAsset::ETH, "10")).unwrap();
+        controller.update_balance(true, deposit(102, MockAsset::USDT, "1000")).unwrap();
+        let ask = controller.order_put(true, limit_order(101, OrderSide::Ask, "1", "100")).unwrap();
+        lose_frozen(&mut controller, ask.id);
+        let status = controller
+            .order_put(true, limit_order(102, OrderSide::Bid, "1", "100"))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+
+        // the failed order is logged with its outcome
+        let ops = log.entries();
+        assert_eq!(ops.len(), 4);
+        assert_eq!(ops.iter().map(|op| op.failed).collect::<Vec<_>>(), vec![false, false, false, true]);
+
+        // the replay fails the order the same way and goes on
+        let (mut replayed, _) = test_controller();
+        replayed.replay_operations(ops[..3].iter().cloned()).unwrap();
+        lose_frozen(&mut replayed, ask.id);
+        replayed.replay_operations(ops[3..].iter().cloned()).unwrap();
+        assert_eq!(replayed.sequencer.get_order_id(), controller.sequencer.get_order_id());
+        assert_eq!(
+            replayed.sequencer.get_operation_log_id(),
+            controller.sequencer.get_operation_log_id()
+        );
+        let market_name = get_simple_market_config().name;
+        assert_eq!(replayed.markets[&market_name].state, controller.markets[&market_name].state);
+
+        // an operation logged as failed which succeeds on the replay is a divergence
+        let (mut diverged, _) = test_controller();
+        assert!(diverged.replay_operations(ops).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_signature_needed() {
+        let (mut controller, _) = test_controller();
+        controller.settings.check_eddsa_signatue = config::OrderSignatrueCheck::Needed;
+        let key = fluidex_common::babyjubjub_rs::new_key();
+        controller.user_manager.users.insert(
+            101,
+            user_manager::UserInfo {
+                l1_address: String::new(),
+                l2_pubkey: hex::encode(key.public().compress()),
+            },
+        );
+        controller.update_balance(true, deposit(101, MockAsset::ETH, "10")).unwrap();
+        let order = controller.order_put(true, limit_order(101, OrderSide::Ask, "1", "100")).unwrap();
+        let market_name = get_simple_market_config().name;
+
+        // the unsigned cancels are refused
+        let cancel = OrderCancelRequest {
+            user_id: 101,
+            market: market_name.clone(),
+            order_id: order.id,
+        };
+        assert!(controller.order_cancel(true, cancel).is_err());
+        let cancel_all = OrderCancelAllRequest {
+            user_id: 101,
+            market: market_name.clone(),
+        };
+        assert!(controller.order_cancel_all(true, cancel_all).is_err());
+
+        let commitment = controller
+            .balance_manager
+            .asset_manager
+            .commit_cancel(101, &controller.markets[&market_name], order.id, 1)
+            .unwrap();
+        let signed = SignedOrderCancelRequest {
+            user_id: 101,
+            market: market_name.clone(),
+            order_id: order.id,
+            nonce: 1,
+            signature: hex::encode(key.sign(commitment.hash()).unwrap().compress()),
+        };
+        controller.signed_order_cancel(true, signed).unwrap();
+        assert!(controller.markets[&market_name].get(order.id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_signed_order_put_twice() {
+        let (mut controller, log) = test_controller();
+        controller.settings.check_eddsa_signatue = config::OrderSignatrueCheck::Needed;
+        let key = fluidex_common::babyjubjub_rs::new_key();
+        controller.user_manager.users.insert(
+            101,
+            user_manager::UserInfo {
+                l1_address: String::new(),
+                l2_pubkey: hex::encode(key.public().compress()),
+            },
+        );
+        controller.update_balance(true, deposit(101, MockAsset::ETH, "10")).unwrap();
+        let market_name = get_simple_market_config().name;
+        let mut order = limit_order(101, OrderSide::Ask, "1", "100");
+        let commitment = controller
+            .balance_manager
+            .asset_manager
+            .commit_order(&order, 1, &controller.markets[&market_name])
+            .unwrap();
+        order.signature = hex::encode(key.sign(commitment.hash()).unwrap().compress());
+
+        // an order signed without a nonce is refused
+        assert!(controller.check_order_signature(&order, 0).is_err());
+        controller.check_order_signature(&order, 1).unwrap();
+        // the same signature for an alias of the market
+        controller.market_aliases.insert("ETHUSDT".to_string(), market_name.clone());
+        let aliased = OrderPutRequest {
+            market: "ETHUSDT".to_string(),
+            ..order.clone()
+        };
+        controller.check_order_signature(&aliased, 1).unwrap();
+        let req = SignedOrderPutRequest { order, nonce: 1 };
+        controller.signed_order_put(true, req.clone()).unwrap();
+
+        // the signature is still valid, the nonce is not
+        controller.check_order_signature(&req.order, 1).unwrap();
+        let status = controller.signed_order_put(true, req).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(controller.markets[&market_name].get_order_num_of_user(101), 1);
+
+        // the nonce is logged and replayed
+        let (mut replayed, _) = test_controller();
+        replayed.replay_operations(log.entries()).unwrap();
+        assert_eq!(replayed.user_nonces.last(101), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replay_failed_cancel_all() {
+        let (mut controller, log) = test_controller();
+        controller.update_balance(true, deposit(101, MockAsset::ETH, "10")).unwrap();
+        controller.order_put(true, limit_order(101, OrderSide::Ask, "2", "100")).unwrap();
+        controller.order_put(true, limit_order(101, OrderSide::Ask, "1", "100")).unwrap();
+        let lose_freeze = |controller: &mut Controller| {
+            controller
+                .balance_manager
+                .sub(101, BalanceType::FREEZE, &MockAsset::ETH.id(), &dec!(1.5))
+                .unwrap();
+        };
+        lose_freeze(&mut controller);
+        let req = OrderCancelAllRequest {
+            user_id: 101,
+            market: get_simple_market_config().name,
+        };
+        let status = controller.order_cancel_all(true, req).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+
+        // both orders are canceled, so the cancel is logged
+        let ops = log.entries();
+        assert_eq!(ops.len(), 4);
+        assert!(ops[3].failed);
+        let (mut replayed, _) = test_controller();
+        replayed.replay_operations(ops[..3].iter().cloned()).unwrap();
+        lose_freeze(&mut replayed);
+        replayed.replay_operations(ops[3..].iter().cloned()).unwrap();
+        let market_name = get_simple_market_config().name;
+        assert_eq!(replayed.markets[&market_name].get_order_num_of_user(101), 0);
+        assert_eq!(
+            replayed.balance_manager.get(101, BalanceType::FREEZE, &MockAsset::ETH.id()),
+            dec!(0.5)
+        );
+    }
+
+    #[test]
+    fn test_market_aliases() {
+        let mut aliases = HashMap::new();
+        let markets: HashMap<MarketName, ()> = ["ETH_USDT", "BTC_USDT"].iter().map(|name| (name.to_string(), ())).collect();
+        let entry = |name: &str, aliases: &[&str]| config::Market {
+            name: name.to_string(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            ..Default::default()
+        };
+
+        add_market_aliases(&mut aliases, &markets, &entry("ETH_USDT", &["ETH_USD", "ETHUSD"])).unwrap();
+        assert_eq!(aliases.get("ETH_USD").unwrap(), "ETH_USDT");
+        assert_eq!(aliases.get("ETHUSD").unwrap(), "ETH_USDT");
+
+        // an alias can not shadow a market or another alias, nothing is added then
+        assert!(add_market_aliases(&mut aliases, &markets, &entry("BTC_USDT", &["BTC_USD", "ETH_USDT"])).is_err());
+        assert!(add_market_aliases(&mut aliases, &markets, &entry("BTC_USDT", &["BTC_USD", "ETH_USD"])).is_err());
+        assert!(add_market_aliases(&mut aliases, &markets, &entry("BTC_USDT", &["BTC_USD", "BTC_USD"])).is_err());
+        assert!(add_market_aliases(&mut aliases, &markets, &entry("BTC_USDT", &["BTC_USDT"])).is_err());
+        assert!(!aliases.contains_key("BTC_USD"));
+
+        add_market_aliases(&mut aliases, &markets, &entry("BTC_USDT", &["BTC_USD"])).unwrap();
+        assert_eq!(aliases.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_admin_operations() {
+        use crate::admin;
+        use tokio::sync::{mpsc, RwLock};
+
+        let (mut controller, log) = test_controller();
+        controller.update_balance(true, deposit(101, MockAsset::ETH, "10")).unwrap();
+        let stub = Arc::new(RwLock::new(controller));
+        // the scheduler of the server
+        let (tx, mut rx) = mpsc::channel::<crate::server::ControllerAction>(16);
+        let scheduler_stub = stub.clone();
+        tokio::spawn(async move {
+            while let Some(task) = rx.recv().await {
+                task(scheduler_stub.clone()).await;
+            }
+        });
+        let lock = serde_json::json!({"lock_id": 7, "user_id": 101, "asset": MockAsset::ETH.id(), "amount": "4"});
+        admin::handle("/balance_lock", lock.to_string().as_bytes(), &stub, &tx)
+            .await
+            .unwrap();
+        assert_eq!(log.entries().last().unwrap().method, OPERATION_BALANCE_LOCK);
+
+        let locks = admin::handle("/balance_locks", br#"{"user_id": 101}"#, &stub, &tx).await.unwrap();
+        assert_eq!(locks[0]["lock_id"], 7);
+        let status = admin::handle("/market_status", br#"{"market": "ETH_USDT"}"#, &stub, &tx)
+            .await
+            .unwrap();
+        assert_eq!(status["state"], "open");
+        let report = admin::handle("/audit_frozen", br#"{"fail_on_mismatch": true}"#, &stub, &tx)
+            .await
+            .unwrap();
+        assert_eq!(report, serde_json::json!([]));
+
+        let err = admin::handle("/balance_lock", br#"{"lock_id": 8}"#, &stub, &tx).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = admin::handle("/market_ticker", br#"{"market": "BTC_USDT"}"#, &stub, &tx)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = admin::handle("/unknown", b"{}", &stub, &tx).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_rollback_registrations_and_close() {
+        let (mut controller, log) = test_controller();
+        let market_name = get_simple_market_config().name;
+        let user = |n: u32| UserInfo {
+            l1_address: format!("0x{:040x}", n),
+            l2_pubkey: format!("0x{:064x}", n),
+            ..Default::default()
+        };
+        controller.register_user(true, user(1)).unwrap();
+        controller.register_user(true, user(2)).unwrap();
+        // the rollback restarts from here
+        controller.keep_snapshot();
+        controller.update_balance(true, deposit(1, MockAsset::ETH, "10")).unwrap();
+        controller.order_put(true, limit_order(1, OrderSide::Ask, "1", "100")).unwrap();
+        let close = MarketCloseRequest {
+            market: market_name.clone(),
+            reason: "delisted".to_string(),
+        };
+        controller.close_market(true, close).unwrap();
+        assert_eq!(controller.register_user(true, user(3)).unwrap().user_id, 3);
+        let target = controller.sequencer.get_operation_log_id();
+        controller.update_balance(true, deposit(2, MockAsset::USDT, "100")).unwrap();
+
+        controller.rollback_to(target, log.entries()).unwrap();
+        assert!(!controller.rolling_back);
+        let mut users = controller
+            .user_manager
+            .users
+            .iter()
+            .map(|(id, user)| (*id, user.l1_address.clone()))
+            .collect::<Vec<_>>();
+        users.sort();
+        assert_eq!(users, (1..=3).map(|n| (n, user(n).l1_address)).collect::<Vec<_>>());
+        assert!(!controller.markets.contains_key(&market_name));
+        assert_eq!(controller.closed_markets, vec![market_name.clone()]);
+        // the canceled order is back to AVAILABLE, the deposit after the target is gone
+        let eth = controller.balance_manager.get(1, BalanceType::AVAILABLE, &MockAsset::ETH.id());
+        assert_eq!(eth, dec!(10));
+        assert!(controller
+            .balance_manager
+            .get(2, BalanceType::AVAILABLE, &MockAsset::USDT.id())
+            .is_zero());
+
+        // without a snapshot the closed market is reopened from its config before the replay
+        controller.snapshots = SnapshotHistory::new(0);
+        controller.rollback_to(target - 1, log.entries()).unwrap();
+        assert!(!controller.markets.contains_key(&market_name));
+        assert_eq!(controller.user_manager.users.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_events() {
+        let (mut controller, _) = test_controller();
+        let broadcast = BroadcastPersistor::new(64);
+        let mut subscriber = broadcast.subscribe(BroadcastFilter::all());
+        controller.persistor = Box::new(broadcast);
+        let market_name = get_simple_market_config().name;
+        controller.update_balance(true, deposit(101, MockAsset::ETH, "10")).unwrap();
+        controller.update_balance(true, deposit(102, MockAsset::USDT, "1000")).unwrap();
+
+        let filled = controller.order_put(true, limit_order(101, OrderSide::Ask, "1", "100")).unwrap();
+        let canceled = controller.order_put(true, limit_order(101, OrderSide::Ask, "1", "110")).unwrap();
+        let cancel = OrderCancelRequest {
+            user_id: 101,
+            market: market_name.clone(),
+            order_id: canceled.id,
+        };
+        controller.order_cancel(true, cancel).unwrap();
+        let post_only = OrderPutRequest {
+            post_only: true,
+            ..limit_order(102, OrderSide::Bid, "1", "100")
+        };
+        let post_only = controller.order_put(true, post_only).unwrap();
+        let taker = controller.order_put(true, limit_order(102, OrderSide::Bid, "1", "100")).unwrap();
+        let closed = controller.order_put(true, limit_order(101, OrderSide::Ask, "1", "120")).unwrap();
+        let close = MarketCloseRequest {
+            market: market_name,
+            reason: "delisted".to_string(),
+        };
+        controller.close_market(true, close).unwrap();
+
+        // every order leaves with FINISH, the consumers tell a cancellation by the finish reason
+        let finished = std::iter::from_fn(|| subscriber.try_recv())
+            .filter_map(|event| {
+                let message: serde_json::Value = serde_json::from_str(&event.json).unwrap();
+                let value = &message["value"];
+                if message["type"] != "OrderMessage" || value["finish_reason"].is_null() {
+                    return None;
+                }
+                Some((
+                    value["order"]["id"].as_u64().unwrap(),
+                    value["event"].as_str().unwrap().to_string(),
+                    value["finish_reason"].as_str().unwrap().to_string(),
+                    value["finish_actor"].as_str().unwrap().to_string(),
+                    value["cancel_reason"].as_str().map(str::to_string),
+                ))
+            })
+            .collect::<Vec<_>>();
+        let expected = |id: u64, reason: &str, actor: &str, cancel_reason: Option<&str>| {
+            (
+                id,
+                "FINISH".to_string(),
+                reason.to_string(),
+                actor.to_string(),
+                cancel_reason.map(str::to_string),
+            )
+        };
+        assert_eq!(
+            finished,
+            vec![
+                expected(canceled.id, "canceled", "user", None),
+                expected(post_only.id, "post_only_cross", "system", Some("post_only_cross")),
+                expected(filled.id, "filled", "system", None),
+                expected(taker.id, "filled", "system", None),
+                expected(closed.id, "market_closed", "admin", None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fee_discount_update() {
+        use crate::fee::FeeDiscount;
+
+        let discount = config::FeeDiscountSettings {
+            asset: MockAsset::ETH.id(),
+            discount: dec!(0.25),
+            prices: Default::default(),
+        };
+        let (mut controller, log) = test_controller();
+        let opt_in = FeeDiscountUpdateRequest {
+            user_id: 102,
+            opt_in: true,
+        };
+        let err = controller.fee_discount_update(true, opt_in.clone()).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        controller.fee_manager.discount = Some(discount.clone());
+        controller.fee_discount_update(true, opt_in).unwrap();
+        assert_eq!(log.entries().last().unwrap().method, OPERATION_FEE_DISCOUNT_UPDATE);
+
+        // USDT is priced in ETH by the trade
+        let usdt = MockAsset::USDT.id();
+        assert_eq!(controller.fee_manager.get_discount(102, &usdt), None);
+        controller.update_balance(true, deposit(101, MockAsset::ETH, "10")).unwrap();
+        controller.update_balance(true, deposit(102, MockAsset::USDT, "1000")).unwrap();
+        controller.order_put(true, limit_order(101, OrderSide::Ask, "1", "100")).unwrap();
+        controller.order_put(true, limit_order(102, OrderSide::Bid, "1", "100")).unwrap();
+        let expected = Some(FeeDiscount {
+            asset: MockAsset::ETH.id(),
+            price: dec!(0.0075),
+        });
+        assert_eq!(controller.fee_manager.get_discount(102, &usdt), expected);
+        assert_eq!(controller.make_snapshot().discount_users, vec![102]);
+
+        // replayed, and restored from a snapshot
+        let (mut replayed, _) = test_controller();
+        replayed.fee_manager.discount = Some(discount.clone());
+        replayed.replay_operations(log.entries()).unwrap();
+        assert_eq!(replayed.fee_manager.get_discount(102, &usdt), expected);
+        let (mut restored, _) = test_controller();
+        restored.fee_manager.discount = Some(discount);
+        restored.load_snapshot(controller.make_snapshot()).unwrap();
+        assert_eq!(restored.fee_manager.get_discount(102, &usdt), expected);
+    }
+}
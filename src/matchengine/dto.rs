@@ -3,7 +3,6 @@ use crate::market;
 use anyhow::{anyhow, bail, Result};
 use arrayref::array_ref;
 use fluidex_common::rust_decimal::{self, prelude::Zero, Decimal};
-use fluidex_common::utils::timeutil::FTimestamp;
 use orchestra::rpc::exchange::*;
 
 use std::convert::TryFrom;
@@ -17,6 +16,15 @@ pub fn str_to_decimal(s: &str, allow_empty: bool) -> Result<Decimal, rust_decima
     }
 }
 
+// the 64 bytes of a hex eddsa signature, with or without 0x
+pub fn decode_signature(signature: &str) -> Result<[u8; 64]> {
+    let v: Vec<u8> = hex::decode(signature.trim_start_matches("0x"))?;
+    if v.len() != 64 {
+        bail!("invalid signature length");
+    }
+    Ok(*array_ref!(v[..64], 0, 64))
+}
+
 impl From<market::Order> for OrderInfo {
     fn from(o: market::Order) -> Self {
         OrderInfo {
@@ -33,8 +41,8 @@ impl From<market::Order> for OrderInfo {
                 OrderSide::Bid as i32
             },
             user_id: o.user,
-            create_time: FTimestamp::from(&o.create_time).as_milliseconds(),
-            update_time: FTimestamp::from(&o.update_time).as_milliseconds(),
+            create_time: o.create_time.as_millis() as i64,
+            update_time: o.update_time.as_millis() as i64,
             price: o.price.to_string(),
             amount: o.amount.to_string(),
             taker_fee: o.taker_fee.to_string(),
@@ -67,6 +75,9 @@ impl TryFrom<OrderPutRequest> for market::OrderInput {
             amount: str_to_decimal(&req.amount, false).map_err(|_| anyhow!("invalid amount"))?,
             price: str_to_decimal(&req.price, req.order_type == OrderType::Market as i32).map_err(|_| anyhow!("invalid price"))?,
             quote_limit: str_to_decimal(&req.quote_limit, true).map_err(|_| anyhow!("invalid quote limit"))?,
+            amount_is_quote: false,
+            max_slippage: None,
+            client_order_id: None,
             taker_fee: str_to_decimal(&req.taker_fee, true).map_err(|_| anyhow!("invalid taker fee"))?,
             maker_fee: str_to_decimal(&req.maker_fee, true).map_err(|_| anyhow!("invalid maker fee"))?,
             market: req.market.clone(),
@@ -75,13 +86,10 @@ impl TryFrom<OrderPutRequest> for market::OrderInput {
                 log::warn!("empty signature. should only happen in tests");
                 [0; 64]
             } else {
-                let sig = req.signature.trim_start_matches("0x");
-                let v: Vec<u8> = hex::decode(sig)?;
-                if v.len() != 64 {
-                    bail!("invalid signature length");
-                }
-                *array_ref!(v[..64], 0, 64)
+                decode_signature(&req.signature)?
             },
+            // the nonce is not in the request, see `SignedOrderPutRequest`
+            nonce: 0,
         })
     }
 }
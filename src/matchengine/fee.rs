@@ -0,0 +1,95 @@
+use crate::config::{FeeDiscountSettings, FeeSettings, FeeTier};
+use crate::market::OrderInput;
+use fluidex_common::rust_decimal::prelude::{One, Zero};
+use fluidex_common::rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+
+// the taker fee of an order paid in the discount asset,
+// `price` is the amount of the discount asset charged per unit of the fee, the discount included
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeDiscount {
+    pub asset: String,
+    pub price: Decimal,
+}
+
+// per-user fee rates, used instead of the fees of the order input when enabled
+#[derive(Default)]
+pub struct FeeManager {
+    pub enabled: bool,
+    pub default_tier: FeeTier,
+    pub user_tiers: HashMap<u32, FeeTier>,
+    pub discount: Option<FeeDiscountSettings>,
+    // the users paying their taker fees in the discount asset
+    pub discount_users: HashSet<u32>,
+}
+
+impl FeeManager {
+    pub fn new(settings: &FeeSettings) -> Self {
+        Self {
+            enabled: settings.enabled,
+            default_tier: settings.default_tier.clone(),
+            user_tiers: settings
+                .user_tiers
+                .iter()
+                .map(|tier| {
+                    (
+                        tier.user_id,
+                        FeeTier {
+                            maker_fee: tier.maker_fee,
+                            taker_fee: tier.taker_fee,
+                        },
+                    )
+                })
+                .collect(),
+            discount: settings.discount.clone(),
+            discount_users: HashSet::new(),
+        }
+    }
+    pub fn get_tier(&self, user_id: u32) -> &FeeTier {
+        self.user_tiers.get(&user_id).unwrap_or(&self.default_tier)
+    }
+    pub fn set_user_tier(&mut self, user_id: u32, tier: FeeTier) {
+        self.user_tiers.insert(user_id, tier);
+    }
+    pub fn remove_user_tier(&mut self, user_id: u32) -> Option<FeeTier> {
+        self.user_tiers.remove(&user_id)
+    }
+    // override the client supplied fees with the tier of the user,
+    // the input is left as is in compatibility mode
+    pub fn apply(&self, order_input: &mut OrderInput) {
+        if self.enabled {
+            let tier = self.get_tier(order_input.user_id);
+            order_input.maker_fee = tier.maker_fee;
+            order_input.taker_fee = tier.taker_fee;
+        }
+    }
+    pub fn set_fee_discount(&mut self, user_id: u32, opt_in: bool) {
+        if opt_in {
+            self.discount_users.insert(user_id);
+        } else {
+            self.discount_users.remove(&user_id);
+        }
+    }
+    // update the price of a fee asset in the discount asset, e.g. from the last price of a market
+    pub fn set_discount_price(&mut self, fee_asset: &str, price: Decimal) {
+        if let Some(discount) = self.discount.as_mut() {
+            discount.prices.insert(fee_asset.to_string(), price);
+        }
+    }
+    // how the taker fees of the user in `fee_asset` are paid in the discount asset,
+    // None if the user has not opted in or the fee asset has no price
+    pub fn get_discount(&self, user_id: u32, fee_asset: &str) -> Option<FeeDiscount> {
+        let discount = self.discount.as_ref()?;
+        if !self.discount_users.contains(&user_id) {
+            return None;
+        }
+        let price = discount.prices.get(fee_asset)?;
+        if !price.gt(&Decimal::zero()) {
+            return None;
+        }
+        Some(FeeDiscount {
+            asset: discount.asset.clone(),
+            price: price * (Decimal::one() - discount.discount),
+        })
+    }
+}
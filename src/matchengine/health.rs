@@ -0,0 +1,210 @@
+// a single probe of the engine for the operators, served as json on `/health`
+use crate::controller::Controller;
+use crate::market::{Market, MarketState};
+use crate::message::ProducerStats;
+use crate::persist::{PersistExector, SinkStatus};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HealthVerdict {
+    Ok,
+    // a non critical persistor is blocked, the traffic is still accepted
+    Degraded,
+    // the traffic is refused
+    Unavailable,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MarketHealth {
+    pub name: String,
+    pub state: MarketState,
+    pub ask_count: usize,
+    pub bid_count: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProducerHealth {
+    pub name: String,
+    #[serde(flatten)]
+    pub stats: ProducerStats,
+}
+
+// the engine apart from the markets and the persistors
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct EngineHealth {
+    pub rolling_back: bool,
+    pub log_handler_blocked: bool,
+    pub last_operation_id: u64,
+    // since the persistors last took a command, none if they never did
+    pub secs_since_persist: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub verdict: HealthVerdict,
+    #[serde(flatten)]
+    pub engine: EngineHealth,
+    pub markets: Vec<MarketHealth>,
+    // the children of the composite, or the persistor itself
+    pub sinks: Vec<SinkStatus>,
+    pub producers: Vec<ProducerHealth>,
+}
+
+impl Health {
+    pub fn collect<'a>(engine: EngineHealth, markets: impl IntoIterator<Item = &'a Market>, persistor: &dyn PersistExector) -> Self {
+        let mut markets = markets
+            .into_iter()
+            .map(|market| MarketHealth {
+                name: market.name.to_string(),
+                state: market.state,
+                ask_count: market.asks.len(),
+                bid_count: market.bids.len(),
+            })
+            .collect::<Vec<_>>();
+        markets.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut sinks = persistor.sink_status();
+        if sinks.is_empty() {
+            sinks.push(SinkStatus {
+                name: "persistor".to_string(),
+                critical: true,
+                available: persistor.service_available(),
+            });
+        }
+        let producers = persistor
+            .producer_stats()
+            .into_iter()
+            .map(|(name, stats)| ProducerHealth { name, stats })
+            .collect();
+        let verdict = if engine.rolling_back || engine.log_handler_blocked || sinks.iter().any(|sink| sink.critical && !sink.available) {
+            HealthVerdict::Unavailable
+        } else if sinks.iter().any(|sink| !sink.available) {
+            HealthVerdict::Degraded
+        } else {
+            HealthVerdict::Ok
+        };
+        Health {
+            verdict,
+            engine,
+            markets,
+            sinks,
+            producers,
+        }
+    }
+}
+
+// `/health` answers 503 when the engine is unavailable, so a load balancer can take it out
+pub async fn serve(addr: std::net::SocketAddr, stub: Arc<RwLock<Controller>>) -> anyhow::Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+
+    let make_service = make_service_fn(move |_| {
+        let stub = stub.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let stub = stub.clone();
+                async move {
+                    if req.uri().path() != "/health" {
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        return Ok::<_, hyper::Error>(response);
+                    }
+                    let health = stub.read().await.health();
+                    let mut response = Response::new(Body::from(serde_json::to_string(&health).unwrap()));
+                    if health.verdict == HealthVerdict::Unavailable {
+                        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    response.headers_mut().insert(
+                        hyper::header::CONTENT_TYPE,
+                        hyper::header::HeaderValue::from_static("application/json"),
+                    );
+                    Ok(response)
+                }
+            }))
+        }
+    });
+    log::info!("serving health on {}", addr);
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::market::{MarketEvent, Trade, TradeFeeRecord};
+    use crate::matchengine::mock::*;
+    use crate::message::{self, BalanceMessage, MessageManager, OrderMessage};
+    use crate::persist::{CompositePersistor, DummyPersistor, EventFilter, MessengerBasedPersistor};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // a kafka producer whose queue is full while the flag is set
+    #[derive(Clone, Default)]
+    struct Messenger(Arc<AtomicBool>);
+    impl MessageManager for Messenger {
+        fn is_block(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+        fn push_order_message(&mut self, _order: &OrderMessage) {}
+        fn push_trade_message(&mut self, _trade: &Trade) {}
+        fn push_fee_message(&mut self, _fee: &TradeFeeRecord) {}
+        fn push_market_event_message(&mut self, _event: &MarketEvent) {}
+        fn push_balance_message(&mut self, _balance: &BalanceMessage) {}
+        fn push_deposit_message(&mut self, _balance: &message::DepositMessage) {}
+        fn push_withdraw_message(&mut self, _balance: &message::WithdrawMessage) {}
+        fn push_transfer_message(&mut self, _tx: &message::TransferMessage) {}
+        fn push_user_message(&mut self, _user: &message::UserMessage) {}
+        fn push_json(&mut self, _topic: &'static str, _json: String) {}
+        fn push_bytes(&mut self, _topic: &'static str, _bytes: Vec<u8>) {}
+    }
+
+    #[test]
+    fn test_blocked_messenger_degrades() {
+        let balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let market = Market::new(&get_simple_market_config(), &Settings::default(), &balance_manager).unwrap();
+        let (db, mq) = (Messenger::default(), Messenger::default());
+        let mut composite = CompositePersistor::default();
+        composite.add_persistor_with_filter("db", Box::new(MessengerBasedPersistor::new(Box::new(db.clone()))), EventFilter::ALL);
+        composite.add_persistor_with_filter("mq", Box::new(MessengerBasedPersistor::new(Box::new(mq.clone()))), EventFilter::ALL);
+        assert!(composite.set_critical("mq", false));
+        let engine = EngineHealth {
+            last_operation_id: 42,
+            ..Default::default()
+        };
+
+        let health = Health::collect(engine.clone(), [&market], &composite);
+        assert_eq!(health.verdict, HealthVerdict::Ok);
+        assert_eq!(health.markets.len(), 1);
+        assert_eq!(health.markets[0].state, MarketState::Open);
+
+        mq.0.store(true, Ordering::SeqCst);
+        let health = Health::collect(engine.clone(), [&market], &composite);
+        assert_eq!(health.verdict, HealthVerdict::Degraded);
+        let flagged = health.sinks.iter().filter(|sink| !sink.available).collect::<Vec<_>>();
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].name, "mq");
+        assert!(!flagged[0].critical);
+        let json = serde_json::to_value(&health).unwrap();
+        assert_eq!(json["verdict"], "DEGRADED");
+        assert_eq!(json["last_operation_id"], 42);
+        assert_eq!(json["sinks"][1]["available"], false);
+
+        // a critical sink blocked too
+        db.0.store(true, Ordering::SeqCst);
+        let health = Health::collect(engine.clone(), [&market], &composite);
+        assert_eq!(health.verdict, HealthVerdict::Unavailable);
+
+        // a single persistor, and the engine itself
+        let health = Health::collect(engine, [&market], &DummyPersistor::default());
+        assert_eq!(health.verdict, HealthVerdict::Ok);
+        assert_eq!(health.sinks.len(), 1);
+        let rolling_back = EngineHealth {
+            rolling_back: true,
+            ..Default::default()
+        };
+        let health = Health::collect(rolling_back, [&market], &DummyPersistor::default());
+        assert_eq!(health.verdict, HealthVerdict::Unavailable);
+    }
+}
@@ -1,26 +1,81 @@
+use crate::controller::OperationLogEntry;
 use crate::database::{DatabaseWriter, DatabaseWriterConfig};
 use crate::market;
 use crate::models;
-use market::Trade;
+use crate::persist::Tombstone;
+use crate::sqlxextend::{InsertTable, InsertTableBatch, SqlxAction, TableSchemas};
+use market::{MarketEvent, Trade, TradeFeeRecord};
 
+use crate::types::{ConnectionType, DbType, FinishReason, OrderFinish};
 use anyhow::Result;
 use fluidex_common::utils::timeutil::FTimestamp;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 
 type BalanceWriter = DatabaseWriter<models::BalanceHistory>;
 type TransferWriter = DatabaseWriter<models::InternalTx>;
 type UserWriter = DatabaseWriter<models::AccountDesc>;
 type OrderWriter = DatabaseWriter<models::OrderHistory>;
 type TradeWriter = DatabaseWriter<models::UserTrade>;
+type FeeWriter = DatabaseWriter<models::TradeFee>;
+type MarketEventWriter = DatabaseWriter<models::MarketEventHistory>;
+type TombstoneWriter = DatabaseWriter<models::OperationLogTombstone>;
+type OperationLogWriter = DatabaseWriter<models::OperationLog>;
 
 pub trait HistoryWriter: Sync + Send {
     fn is_block(&self) -> bool;
+    // the items not written to the db yet
+    fn pending(&self) -> usize {
+        0
+    }
     //TODO: don't take the ownership?
     fn append_balance_history(&mut self, data: models::BalanceHistory);
     fn append_internal_transfer(&mut self, data: models::InternalTx);
     fn append_user(&mut self, user: models::AccountDesc);
-    fn append_order_history(&mut self, order: &market::Order);
+    // `finish` is none for the orders finished without a known reason
+    fn append_order_history(&mut self, order: &market::Order, finish: Option<OrderFinish>);
     fn append_expired_order_history(&mut self, _order: &market::Order);
     fn append_pair_user_trade(&mut self, trade: &Trade);
+    fn append_trade_fee(&mut self, fee: &TradeFeeRecord);
+    fn append_market_event(&mut self, event: &MarketEvent);
+    fn append_tombstone(&mut self, _tombstone: &Tombstone) {}
+    fn append_operation_log(&mut self, _operation: &OperationLogEntry) {}
+    // the items are written in a single transaction when the writer supports it, i.e. all or none of them,
+    // otherwise one by one in order
+    fn append_batch(&mut self, batch: Vec<HistoryItem>) {
+        for item in batch {
+            match item {
+                HistoryItem::Balance(data) => self.append_balance_history(data),
+                HistoryItem::Transfer(data) => self.append_internal_transfer(data),
+                HistoryItem::User(user) => self.append_user(user),
+                HistoryItem::Order(order, finish) => self.append_order_history(&order, finish),
+                HistoryItem::ExpiredOrder(order) => self.append_expired_order_history(&order),
+                HistoryItem::Trade(trade) => self.append_pair_user_trade(&trade),
+                HistoryItem::Fee(fee) => self.append_trade_fee(&fee),
+                HistoryItem::MarketEvent(event) => self.append_market_event(&event),
+                HistoryItem::Tombstone(tombstone) => self.append_tombstone(&tombstone),
+                HistoryItem::OperationLog(operation) => self.append_operation_log(&operation),
+            }
+        }
+    }
+}
+
+// a history of a batch, the batch keeps them in the order they came
+#[derive(Debug, Clone)]
+pub enum HistoryItem {
+    Balance(models::BalanceHistory),
+    Transfer(models::InternalTx),
+    User(models::AccountDesc),
+    Order(market::Order, Option<OrderFinish>),
+    ExpiredOrder(market::Order),
+    Trade(Trade),
+    Fee(TradeFeeRecord),
+    MarketEvent(MarketEvent),
+    Tombstone(Tombstone),
+    OperationLog(OperationLogEntry),
 }
 
 pub struct DummyHistoryWriter;
@@ -28,9 +83,11 @@ impl HistoryWriter for DummyHistoryWriter {
     fn append_balance_history(&mut self, _data: models::BalanceHistory) {}
     fn append_internal_transfer(&mut self, _data: models::InternalTx) {}
     fn append_user(&mut self, _user: models::AccountDesc) {}
-    fn append_order_history(&mut self, _order: &market::Order) {}
+    fn append_order_history(&mut self, _order: &market::Order, _finish: Option<OrderFinish>) {}
     fn append_expired_order_history(&mut self, _order: &market::Order) {}
     fn append_pair_user_trade(&mut self, _trade: &Trade) {}
+    fn append_trade_fee(&mut self, _fee: &TradeFeeRecord) {}
+    fn append_market_event(&mut self, _event: &MarketEvent) {}
     fn is_block(&self) -> bool {
         false
     }
@@ -42,6 +99,11 @@ pub struct DatabaseHistoryWriter {
     pub user_writer: UserWriter,
     pub trade_writer: TradeWriter,
     pub order_writer: OrderWriter,
+    pub fee_writer: FeeWriter,
+    pub market_event_writer: MarketEventWriter,
+    pub tombstone_writer: TombstoneWriter,
+    pub operation_log_writer: OperationLogWriter,
+    pub batch_writer: HistoryBatchWriter,
 }
 
 impl DatabaseHistoryWriter {
@@ -52,6 +114,11 @@ impl DatabaseHistoryWriter {
             user_writer: UserWriter::new(config).start_schedule(pool)?,
             trade_writer: TradeWriter::new(config).start_schedule(pool)?,
             order_writer: OrderWriter::new(config).start_schedule(pool)?,
+            fee_writer: FeeWriter::new(config).start_schedule(pool)?,
+            market_event_writer: MarketEventWriter::new(config).start_schedule(pool)?,
+            tombstone_writer: TombstoneWriter::new(config).start_schedule(pool)?,
+            operation_log_writer: OperationLogWriter::new(config).start_schedule(pool)?,
+            batch_writer: HistoryBatchWriter::new(config).start_schedule(pool),
         })
     }
 }
@@ -66,8 +133,8 @@ impl<'r> From<&'r market::Order> for models::OrderHistory {
 
         models::OrderHistory {
             id: order.id as i64,
-            create_time: FTimestamp(order.create_time).into(),
-            finish_time: FTimestamp(order.update_time).into(),
+            create_time: order.create_time.into(),
+            finish_time: order.update_time.into(),
             status,
             user_id: order.user as i32,
             market: order.market.to_string(),
@@ -82,13 +149,108 @@ impl<'r> From<&'r market::Order> for models::OrderHistory {
             finished_fee: order.finished_fee,
             post_only: order.post_only,
             signature: order.signature.to_vec(),
+            client_order_id: order.client_order_id.map(|id| id as i64),
+            finish_reason: None,
+            finish_actor: None,
+        }
+    }
+}
+
+fn order_history(order: &market::Order, finish: Option<OrderFinish>) -> models::OrderHistory {
+    let history: models::OrderHistory = order.into();
+    models::OrderHistory {
+        // a market order which spent its quote limit is filled with a remain
+        status: match finish {
+            Some(finish) if finish.reason == FinishReason::Filled => models::OrderStatus::Filled,
+            _ => history.status,
+        },
+        finish_reason: finish.map(|finish| finish.reason),
+        finish_actor: finish.map(|finish| finish.actor),
+        ..history
+    }
+}
+
+fn expired_order_history(order: &market::Order) -> models::OrderHistory {
+    models::OrderHistory {
+        status: models::OrderStatus::Expired,
+        ..order_history(order, Some(OrderFinish::system(FinishReason::Expired)))
+    }
+}
+
+impl<'r> From<&'r TradeFeeRecord> for models::TradeFee {
+    fn from(fee: &'r TradeFeeRecord) -> Self {
+        models::TradeFee {
+            time: fee.timestamp.into(),
+            trade_id: fee.trade_id as i64,
+            market: fee.market.clone(),
+            user_id: fee.user_id as i32,
+            order_id: fee.order_id as i64,
+            role: fee.role as i16,
+            asset: fee.asset.clone(),
+            amount: fee.amount,
+            rate: fee.rate,
+        }
+    }
+}
+
+impl<'r> From<&'r MarketEvent> for models::MarketEventHistory {
+    fn from(event: &'r MarketEvent) -> Self {
+        models::MarketEventHistory {
+            time: event.timestamp.into(),
+            market: event.market.clone(),
+            event: event.kind.name().to_string(),
+            detail: serde_json::to_string(&event.kind).unwrap(),
+        }
+    }
+}
+
+impl<'r> From<&'r Tombstone> for models::OperationLogTombstone {
+    fn from(tombstone: &'r Tombstone) -> Self {
+        models::OperationLogTombstone {
+            time: FTimestamp(tombstone.time).into(),
+            from_id: tombstone.from as i64,
+            to_id: tombstone.to as i64,
+            end_order_id: tombstone.order_id as i64,
+            end_trade_id: tombstone.trade_id as i64,
+        }
+    }
+}
+
+// the same row as the operation log of the controller, an insert of both is kept once
+impl<'r> From<&'r OperationLogEntry> for models::OperationLog {
+    fn from(operation: &'r OperationLogEntry) -> Self {
+        models::OperationLog {
+            id: operation.id as i64,
+            time: FTimestamp(operation.time).into(),
+            method: operation.method.clone(),
+            params: operation.params.clone(),
+            failed: operation.failed,
         }
     }
 }
 
 impl HistoryWriter for DatabaseHistoryWriter {
     fn is_block(&self) -> bool {
-        self.balance_writer.is_block() || self.trade_writer.is_block() || self.order_writer.is_block()
+        self.balance_writer.is_block()
+            || self.trade_writer.is_block()
+            || self.order_writer.is_block()
+            || self.fee_writer.is_block()
+            || self.market_event_writer.is_block()
+            || self.tombstone_writer.is_block()
+            || self.operation_log_writer.is_block()
+            || self.batch_writer.is_block()
+    }
+    fn pending(&self) -> usize {
+        self.balance_writer.status().pending_count
+            + self.transfer_writer.status().pending_count
+            + self.user_writer.status().pending_count
+            + self.trade_writer.status().pending_count
+            + self.order_writer.status().pending_count
+            + self.fee_writer.status().pending_count
+            + self.market_event_writer.status().pending_count
+            + self.tombstone_writer.status().pending_count
+            + self.operation_log_writer.status().pending_count
+            + self.batch_writer.pending()
     }
     fn append_balance_history(&mut self, data: models::BalanceHistory) {
         self.balance_writer.append(data).ok();
@@ -99,47 +261,208 @@ impl HistoryWriter for DatabaseHistoryWriter {
     fn append_user(&mut self, user: models::AccountDesc) {
         self.user_writer.append(user).ok();
     }
-    fn append_order_history(&mut self, order: &market::Order) {
-        self.order_writer.append(order.into()).ok();
+    fn append_order_history(&mut self, order: &market::Order, finish: Option<OrderFinish>) {
+        self.order_writer.append(order_history(order, finish)).ok();
     }
     fn append_expired_order_history(&mut self, order: &market::Order) {
-        let mut order_for_db: models::OrderHistory = From::from(order);
-        order_for_db.status = models::OrderStatus::Expired;
-        self.order_writer.append(order_for_db).ok();
+        self.order_writer.append(expired_order_history(order)).ok();
     }
 
     fn append_pair_user_trade(&mut self, trade: &Trade) {
-        let ask_trade = models::UserTrade {
-            time: FTimestamp(trade.timestamp).into(),
-            user_id: trade.ask_user_id as i32,
-            market: trade.market.clone(),
-            trade_id: trade.id as i64,
-            order_id: trade.ask_order_id as i64,
-            counter_order_id: trade.bid_order_id as i64, // counter order
-            side: market::OrderSide::ASK as i16,
-            role: trade.ask_role as i16,
-            price: trade.price,
-            amount: trade.amount,
-            quote_amount: trade.quote_amount,
-            fee: trade.ask_fee,
-            counter_order_fee: trade.bid_fee, // counter order
-        };
-        let bid_trade = models::UserTrade {
-            time: FTimestamp(trade.timestamp).into(),
-            user_id: trade.bid_user_id as i32,
-            market: trade.market.clone(),
-            trade_id: trade.id as i64,
-            order_id: trade.bid_order_id as i64,
-            counter_order_id: trade.ask_order_id as i64, // counter order
-            side: market::OrderSide::BID as i16,
-            role: trade.bid_role as i16,
-            price: trade.price,
-            amount: trade.amount,
-            quote_amount: trade.quote_amount,
-            fee: trade.bid_fee,
-            counter_order_fee: trade.ask_fee, // counter order
-        };
+        let [ask_trade, bid_trade] = user_trades(trade);
         self.trade_writer.append(ask_trade).ok();
         self.trade_writer.append(bid_trade).ok();
     }
+    fn append_trade_fee(&mut self, fee: &TradeFeeRecord) {
+        self.fee_writer.append(fee.into()).ok();
+    }
+    fn append_market_event(&mut self, event: &MarketEvent) {
+        self.market_event_writer.append(event.into()).ok();
+    }
+    fn append_tombstone(&mut self, tombstone: &Tombstone) {
+        self.tombstone_writer.append(tombstone.into()).ok();
+    }
+    fn append_operation_log(&mut self, operation: &OperationLogEntry) {
+        self.operation_log_writer.append(operation.into()).ok();
+    }
+    fn append_batch(&mut self, batch: Vec<HistoryItem>) {
+        let mut rows = HistoryRows::default();
+        for item in batch {
+            rows.push(item);
+        }
+        if let Err(rows) = self.batch_writer.append(rows) {
+            log::error!("{} histories lost", rows.len());
+        }
+    }
+}
+
+// the trade as seen by each side
+fn user_trades(trade: &Trade) -> [models::UserTrade; 2] {
+    let ask_trade = models::UserTrade {
+        time: trade.timestamp.into(),
+        user_id: trade.ask_user_id as i32,
+        market: trade.market.clone(),
+        trade_id: trade.id as i64,
+        order_id: trade.ask_order_id as i64,
+        counter_order_id: trade.bid_order_id as i64, // counter order
+        side: market::OrderSide::ASK as i16,
+        role: trade.ask_role as i16,
+        price: trade.price,
+        amount: trade.amount,
+        quote_amount: trade.quote_amount,
+        fee: trade.ask_fee,
+        counter_order_fee: trade.bid_fee, // counter order
+    };
+    let bid_trade = models::UserTrade {
+        time: trade.timestamp.into(),
+        user_id: trade.bid_user_id as i32,
+        market: trade.market.clone(),
+        trade_id: trade.id as i64,
+        order_id: trade.bid_order_id as i64,
+        counter_order_id: trade.ask_order_id as i64, // counter order
+        side: market::OrderSide::BID as i16,
+        role: trade.bid_role as i16,
+        price: trade.price,
+        amount: trade.amount,
+        quote_amount: trade.quote_amount,
+        fee: trade.bid_fee,
+        counter_order_fee: trade.ask_fee, // counter order
+    };
+    [ask_trade, bid_trade]
+}
+
+// the rows of a batch by their tables
+#[derive(Debug, Default)]
+pub struct HistoryRows {
+    balances: Vec<models::BalanceHistory>,
+    transfers: Vec<models::InternalTx>,
+    users: Vec<models::AccountDesc>,
+    orders: Vec<models::OrderHistory>,
+    trades: Vec<models::UserTrade>,
+    fees: Vec<models::TradeFee>,
+    market_events: Vec<models::MarketEventHistory>,
+    tombstones: Vec<models::OperationLogTombstone>,
+    operation_logs: Vec<models::OperationLog>,
+}
+
+impl HistoryRows {
+    fn push(&mut self, item: HistoryItem) {
+        match item {
+            HistoryItem::Balance(data) => self.balances.push(data),
+            HistoryItem::Transfer(data) => self.transfers.push(data),
+            HistoryItem::User(user) => self.users.push(user),
+            HistoryItem::Order(order, finish) => self.orders.push(order_history(&order, finish)),
+            HistoryItem::ExpiredOrder(order) => self.orders.push(expired_order_history(&order)),
+            HistoryItem::Trade(trade) => self.trades.extend(user_trades(&trade)),
+            HistoryItem::Fee(fee) => self.fees.push((&fee).into()),
+            HistoryItem::MarketEvent(event) => self.market_events.push((&event).into()),
+            HistoryItem::Tombstone(tombstone) => self.tombstones.push((&tombstone).into()),
+            HistoryItem::OperationLog(operation) => self.operation_logs.push((&operation).into()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.balances.len()
+            + self.transfers.len()
+            + self.users.len()
+            + self.orders.len()
+            + self.trades.len()
+            + self.fees.len()
+            + self.market_events.len()
+            + self.tombstones.len()
+            + self.operation_logs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // a failed insert rolls back the whole transaction
+    async fn insert(&self, pool: &sqlx::Pool<DbType>) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        insert_rows(&self.balances, &mut tx).await?;
+        insert_rows(&self.transfers, &mut tx).await?;
+        insert_rows(&self.users, &mut tx).await?;
+        insert_rows(&self.orders, &mut tx).await?;
+        insert_rows(&self.trades, &mut tx).await?;
+        insert_rows(&self.fees, &mut tx).await?;
+        insert_rows(&self.market_events, &mut tx).await?;
+        insert_rows(&self.tombstones, &mut tx).await?;
+        insert_rows(&self.operation_logs, &mut tx).await?;
+        tx.commit().await
+    }
+}
+
+async fn insert_rows<Q>(rows: &[Q], conn: &mut ConnectionType) -> Result<(), sqlx::Error>
+where
+    Q: Send + Sync + Clone + TableSchemas,
+    Q: for<'r> SqlxAction<'r, InsertTable, DbType>,
+{
+    if rows.is_empty() {
+        return Ok(());
+    }
+    InsertTableBatch::sql_query_fine(rows, conn).await.map(|_| ()).map_err(|(_, e)| e)
+}
+
+// by designation a batch is written before the next one is made, so the channel never piles up
+const BATCH_CHANNEL_LIMIT: usize = 1000;
+
+// writes each batch in one transaction covering every table, the batches one after another
+pub struct HistoryBatchWriter {
+    sender: Option<mpsc::Sender<HistoryRows>>,
+    // the rows sent and not committed yet
+    pending: Arc<AtomicUsize>,
+    config: DatabaseWriterConfig,
+}
+
+impl HistoryBatchWriter {
+    pub fn new(config: &DatabaseWriterConfig) -> Self {
+        Self {
+            sender: None,
+            pending: Arc::new(AtomicUsize::new(0)),
+            config: config.clone(),
+        }
+    }
+
+    pub fn start_schedule(mut self, pool: &sqlx::Pool<DbType>) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<HistoryRows>(BATCH_CHANNEL_LIMIT);
+        self.sender = Some(sender);
+        let pending = self.pending.clone();
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            while let Some(rows) = receiver.recv().await {
+                while let Err(e) = rows.insert(&pool).await {
+                    log::error!("write a batch of {} histories fail: {}. retry", rows.len(), e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                pending.fetch_sub(rows.len(), Ordering::SeqCst);
+            }
+            log::info!("db batch writer for the histories exit");
+        });
+        self
+    }
+
+    pub fn append(&mut self, rows: HistoryRows) -> Result<(), HistoryRows> {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Err(rows),
+        };
+        let len = rows.len();
+        // counted before the writer may take it
+        self.pending.fetch_add(len, Ordering::SeqCst);
+        sender.try_send(rows).map_err(|e| {
+            self.pending.fetch_sub(len, Ordering::SeqCst);
+            match e {
+                TrySendError::Full(rows) | TrySendError::Closed(rows) => rows,
+            }
+        })
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    pub fn is_block(&self) -> bool {
+        self.sender.is_none() || ((self.config.capability_limit as f64 * 0.9) as usize) < self.pending()
+    }
 }
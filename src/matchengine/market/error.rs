@@ -0,0 +1,161 @@
+use fluidex_common::rust_decimal::Decimal;
+use thiserror::Error;
+use tonic::Status;
+
+// errors of the order operations of a market.
+// the messages are part of the api, keep them stable
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum MarketError {
+    #[error("order market {actual} does not match market {expected}")]
+    MarketMismatch { expected: String, actual: String },
+    #[error("invalid fee rate")]
+    InvalidFee,
+    #[error("market orders disabled")]
+    MarketOrdersDisabled,
+    #[error("quote amount is only supported for market bid order")]
+    QuoteAmountNotSupported,
+    #[error("max slippage is only supported for market order")]
+    MaxSlippageNotSupported,
+    #[error("only 0 fee is supported now")]
+    FeeNotSupported,
+    #[error("invalid amount precision")]
+    InvalidAmountPrecision,
+    #[error("invalid price precision")]
+    InvalidPricePrecision,
+    #[error("amount too small")]
+    BelowMinAmount,
+    #[error("amount too large")]
+    AboveMaxAmount,
+    #[error("quote amount too small")]
+    BelowMinQuoteAmount,
+    #[error("quote amount too large")]
+    AboveMaxQuoteAmount,
+    #[error("quote amount overflows")]
+    QuoteAmountOverflow,
+    #[error("notional too small")]
+    BelowMinNotional,
+    #[error("market order should not have a price")]
+    MarketOrderWithPrice,
+    #[error("market order cannot be post only")]
+    MarketOrderPostOnly,
+    #[error("invalid price for limit order")]
+    InvalidPrice,
+    #[error("invalid quote limit")]
+    InvalidQuoteLimit,
+    #[error("invalid max slippage")]
+    InvalidMaxSlippage,
+    #[error("no counter orders")]
+    NoCounterOrders,
+    #[error("price deviates too much from the last price")]
+    PriceDeviation,
+    #[error("duplicate client order id {0}")]
+    DuplicateClientOrderId(u64),
+    #[error("too many open orders")]
+    TooManyOpenOrders,
+    #[error("balance not enough: {asset} available({available}) < required({required})")]
+    BalanceNotEnough {
+        asset: String,
+        required: Decimal,
+        available: Decimal,
+    },
+    #[error("invalid order_id {0}: order not found")]
+    OrderNotFound(u64),
+    #[error("invalid client_order_id {0}: order not found")]
+    ClientOrderNotFound(u64),
+    #[error("invalid user {user_id}: order {order_id} belongs to another user")]
+    NotOrderOwner { user_id: u32, order_id: u64 },
+    #[error("only limit orders can be placed when replacing orders")]
+    ReplaceWithMarketOrder,
+    #[error("nothing to amend")]
+    NothingToAmend,
+    #[error("amount should be greater than the finished amount")]
+    AmountNotAboveFinished,
+    #[error("invalid reduce amount")]
+    InvalidReduceAmount,
+    #[error("reduce amount exceeds the remain")]
+    ReduceAmountExceedsRemain,
+    #[error("invalid depth interval")]
+    InvalidDepthInterval,
+    #[error("market is not open for new orders")]
+    MarketNotOpen,
+    #[error("market is halted")]
+    MarketHalted,
+    #[error("invalid precision")]
+    InvalidPrecision,
+    #[error("invalid signature")]
+    SignatureInvalid,
+    #[error("nonce {nonce} is not above the last nonce {last} of the user")]
+    NonceTooLow { nonce: u64, last: u64 },
+    #[error("invalid min amount")]
+    InvalidMinAmount,
+    // the market is halted, see `MarketEventKind::InvariantViolated`
+    #[error("invariant violated: {0}")]
+    InvariantViolated(String),
+}
+
+impl MarketError {
+    // a short label of the kind of the error, e.g. for the metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            MarketError::MarketMismatch { .. } => "market_mismatch",
+            MarketError::InvalidFee => "invalid_fee",
+            MarketError::MarketOrdersDisabled => "market_orders_disabled",
+            MarketError::QuoteAmountNotSupported => "quote_amount_not_supported",
+            MarketError::MaxSlippageNotSupported => "max_slippage_not_supported",
+            MarketError::FeeNotSupported => "fee_not_supported",
+            MarketError::InvalidAmountPrecision => "invalid_amount_precision",
+            MarketError::InvalidPricePrecision => "invalid_price_precision",
+            MarketError::BelowMinAmount => "below_min_amount",
+            MarketError::AboveMaxAmount => "above_max_amount",
+            MarketError::BelowMinQuoteAmount => "below_min_quote_amount",
+            MarketError::AboveMaxQuoteAmount => "above_max_quote_amount",
+            MarketError::QuoteAmountOverflow => "quote_amount_overflow",
+            MarketError::BelowMinNotional => "below_min_notional",
+            MarketError::MarketOrderWithPrice => "market_order_with_price",
+            MarketError::MarketOrderPostOnly => "market_order_post_only",
+            MarketError::InvalidPrice => "invalid_price",
+            MarketError::InvalidQuoteLimit => "invalid_quote_limit",
+            MarketError::InvalidMaxSlippage => "invalid_max_slippage",
+            MarketError::NoCounterOrders => "no_counter_orders",
+            MarketError::PriceDeviation => "price_deviation",
+            MarketError::DuplicateClientOrderId(_) => "duplicate_client_order_id",
+            MarketError::TooManyOpenOrders => "too_many_open_orders",
+            MarketError::BalanceNotEnough { .. } => "balance_not_enough",
+            MarketError::OrderNotFound(_) => "order_not_found",
+            MarketError::ClientOrderNotFound(_) => "client_order_not_found",
+            MarketError::NotOrderOwner { .. } => "not_order_owner",
+            MarketError::ReplaceWithMarketOrder => "replace_with_market_order",
+            MarketError::NothingToAmend => "nothing_to_amend",
+            MarketError::AmountNotAboveFinished => "amount_not_above_finished",
+            MarketError::InvalidReduceAmount => "invalid_reduce_amount",
+            MarketError::ReduceAmountExceedsRemain => "reduce_amount_exceeds_remain",
+            MarketError::InvalidDepthInterval => "invalid_depth_interval",
+            MarketError::MarketNotOpen => "market_not_open",
+            MarketError::MarketHalted => "market_halted",
+            MarketError::InvalidPrecision => "invalid_precision",
+            MarketError::SignatureInvalid => "signature_invalid",
+            MarketError::NonceTooLow { .. } => "nonce_too_low",
+            MarketError::InvalidMinAmount => "invalid_min_amount",
+            MarketError::InvariantViolated(_) => "invariant_violated",
+        }
+    }
+}
+
+impl From<MarketError> for Status {
+    fn from(error: MarketError) -> Self {
+        let message = error.to_string();
+        match error {
+            MarketError::OrderNotFound(_) | MarketError::ClientOrderNotFound(_) => Status::not_found(message),
+            MarketError::NotOrderOwner { .. } => Status::permission_denied(message),
+            MarketError::TooManyOpenOrders => Status::resource_exhausted(message),
+            MarketError::PriceDeviation => Status::out_of_range(message),
+            MarketError::MarketOrdersDisabled
+            | MarketError::NoCounterOrders
+            | MarketError::BalanceNotEnough { .. }
+            | MarketError::MarketNotOpen
+            | MarketError::MarketHalted => Status::failed_precondition(message),
+            MarketError::InvariantViolated(_) => Status::internal(message),
+            _ => Status::invalid_argument(message),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use super::MarketState;
+use crate::types::TimestampMs;
+use fluidex_common::rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+// the lifecycle and the last price changes of a market
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MarketEvent {
+    pub timestamp: TimestampMs,
+    pub market: String,
+    #[serde(flatten)]
+    pub kind: MarketEventKind,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MarketEventKind {
+    Created {
+        base: String,
+        quote: String,
+        amount_prec: u32,
+        price_prec: u32,
+        fee_prec: u32,
+    },
+    PriceUpdated {
+        price: Decimal,
+        trade_id: u64,
+    },
+    // the market is not open any more, `state` tells whether cancels are accepted
+    Halted {
+        state: MarketState,
+        reason: String,
+    },
+    Resumed {
+        reason: String,
+    },
+    // followed by the halt of the market
+    InvariantViolated {
+        order_id: u64,
+        invariant: String,
+    },
+}
+
+impl MarketEventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MarketEventKind::Created { .. } => "created",
+            MarketEventKind::PriceUpdated { .. } => "price_updated",
+            MarketEventKind::Halted { .. } => "halted",
+            MarketEventKind::Resumed { .. } => "resumed",
+            MarketEventKind::InvariantViolated { .. } => "invariant_violated",
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::config::KlineInterval;
+use crate::types::TimestampMs;
+use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, VecDeque};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Kline {
+    pub market: String,
+    pub interval: KlineInterval,
+    // the start of the period, in seconds
+    pub start: u64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    pub quote_volume: Decimal,
+    pub trade_count: u64,
+}
+
+impl Kline {
+    // a period without trades, carrying forward the previous close
+    fn empty(market: &str, interval: KlineInterval, start: u64, close: Decimal) -> Self {
+        Self {
+            market: market.to_string(),
+            interval,
+            start,
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: Decimal::zero(),
+            quote_volume: Decimal::zero(),
+            trade_count: 0,
+        }
+    }
+    fn update(&mut self, price: Decimal, amount: Decimal, quote_amount: Decimal) {
+        self.high = max(self.high, price);
+        self.low = min(self.low, price);
+        self.close = price;
+        // saturates rather than overflows, like the market statistics
+        self.volume = self.volume.checked_add(amount).unwrap_or(Decimal::MAX);
+        self.quote_volume = self.quote_volume.checked_add(quote_amount).unwrap_or(Decimal::MAX);
+        self.trade_count += 1;
+    }
+}
+
+// builds the candles of a market from its trades, keeping the latest `history` candles of each interval.
+// a candle is closed by the first trade after its period, the closed candles are returned to be persisted
+pub struct KlineAggregator {
+    market: &'static str,
+    history: usize,
+    candles: BTreeMap<KlineInterval, VecDeque<Kline>>,
+}
+
+impl KlineAggregator {
+    pub fn new(market: &'static str, intervals: &[KlineInterval], history: usize) -> Self {
+        Self {
+            market,
+            history: max(history, 1),
+            candles: intervals.iter().map(|interval| (*interval, VecDeque::new())).collect(),
+        }
+    }
+
+    pub fn record(&mut self, timestamp: TimestampMs, price: Decimal, amount: Decimal, quote_amount: Decimal) -> Vec<Kline> {
+        let mut closed = Vec::new();
+        for (interval, candles) in self.candles.iter_mut() {
+            let seconds = interval.seconds();
+            let start = timestamp.as_secs() / seconds * seconds;
+            let (live_start, live_close) = match candles.back_mut() {
+                // a trade with an earlier timestamp is folded into the live candle
+                Some(live) if live.start >= start => {
+                    live.update(price, amount, quote_amount);
+                    continue;
+                }
+                Some(live) => {
+                    closed.push(live.clone());
+                    (live.start, live.close)
+                }
+                None => (start, price),
+            };
+            if live_start < start {
+                // only the latest empty candles within the history are built for a long gap
+                let missing = (start - live_start) / seconds - 1;
+                let mut gap_start = live_start + (1 + missing.saturating_sub(self.history as u64)) * seconds;
+                while gap_start < start {
+                    let kline = Kline::empty(self.market, *interval, gap_start, live_close);
+                    closed.push(kline.clone());
+                    candles.push_back(kline);
+                    gap_start += seconds;
+                }
+            }
+            let mut kline = Kline::empty(self.market, *interval, start, price);
+            kline.update(price, amount, quote_amount);
+            candles.push_back(kline);
+            while candles.len() > self.history {
+                candles.pop_front();
+            }
+        }
+        closed
+    }
+
+    // the latest candles, oldest first, the last one is the live candle
+    pub fn klines(&self, interval: KlineInterval, limit: usize) -> Vec<Kline> {
+        match self.candles.get(&interval) {
+            Some(candles) => candles.iter().skip(candles.len().saturating_sub(limit)).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_kline_aggregation() {
+        let mut aggregator = KlineAggregator::new("ETH_USDT", &[KlineInterval::OneMinute, KlineInterval::FiveMinutes], 10);
+        let start = 1_600_000_200;
+        let candle = |interval, offset, prices: (Decimal, Decimal, Decimal, Decimal), volume, quote_volume, trade_count| Kline {
+            market: "ETH_USDT".to_string(),
+            interval,
+            start: start + offset,
+            open: prices.0,
+            high: prices.1,
+            low: prices.2,
+            close: prices.3,
+            volume,
+            quote_volume,
+            trade_count,
+        };
+        let at = |offset: f64| TimestampMs::from_secs_f64(start as f64 + offset);
+
+        assert!(aggregator.record(at(0.0), dec!(10), dec!(1), dec!(10)).is_empty());
+        assert!(aggregator.record(at(30.0), dec!(12), dec!(1), dec!(12)).is_empty());
+        assert!(aggregator.record(at(59.9), dec!(9), dec!(2), dec!(18)).is_empty());
+        let first = candle(
+            KlineInterval::OneMinute,
+            0,
+            (dec!(10), dec!(12), dec!(9), dec!(9)),
+            dec!(4),
+            dec!(40),
+            3,
+        );
+        assert_eq!(aggregator.record(at(60.0), dec!(11), dec!(1), dec!(11)), vec![first.clone()]);
+
+        // two minutes without trades carry forward the close
+        let second = candle(
+            KlineInterval::OneMinute,
+            60,
+            (dec!(11), dec!(11), dec!(11), dec!(11)),
+            dec!(1),
+            dec!(11),
+            1,
+        );
+        let gap = |offset| {
+            candle(
+                KlineInterval::OneMinute,
+                offset,
+                (dec!(11), dec!(11), dec!(11), dec!(11)),
+                dec!(0),
+                dec!(0),
+                0,
+            )
+        };
+        assert_eq!(
+            aggregator.record(at(250.0), dec!(13), dec!(1), dec!(13)),
+            vec![second.clone(), gap(120), gap(180)]
+        );
+        let live = candle(
+            KlineInterval::OneMinute,
+            240,
+            (dec!(13), dec!(13), dec!(13), dec!(13)),
+            dec!(1),
+            dec!(13),
+            1,
+        );
+        assert_eq!(
+            aggregator.klines(KlineInterval::OneMinute, 100),
+            vec![first, second, gap(120), gap(180), live.clone()]
+        );
+        let five_minutes = candle(
+            KlineInterval::FiveMinutes,
+            0,
+            (dec!(10), dec!(13), dec!(9), dec!(13)),
+            dec!(6),
+            dec!(64),
+            5,
+        );
+        assert_eq!(aggregator.klines(KlineInterval::FiveMinutes, 1), vec![five_minutes.clone()]);
+
+        // both intervals close at the boundary of 5 minutes
+        assert_eq!(aggregator.record(at(300.0), dec!(14), dec!(1), dec!(14)), vec![live, five_minutes]);
+        assert_eq!(aggregator.klines(KlineInterval::OneMinute, 2)[1].start, start + 300);
+        assert!(aggregator.klines(KlineInterval::OneHour, 2).is_empty());
+    }
+
+    #[test]
+    fn test_kline_history() {
+        let mut aggregator = KlineAggregator::new("ETH_USDT", &[KlineInterval::OneMinute], 3);
+        let start = 1_600_000_200;
+        let at = |offset: f64| TimestampMs::from_secs_f64(start as f64 + offset);
+        aggregator.record(at(0.0), dec!(10), dec!(1), dec!(10));
+        // the gap is longer than the history
+        let closed = aggregator.record(at(6000.0), dec!(11), dec!(1), dec!(11));
+        assert_eq!(closed.len(), 4);
+        assert_eq!(closed[0].start, start);
+        assert_eq!(closed[1].start, start + 5820);
+        assert!(closed[1..].iter().all(|kline| kline.trade_count == 0 && kline.close == dec!(10)));
+
+        let klines = aggregator.klines(KlineInterval::OneMinute, 100);
+        assert_eq!(
+            klines.iter().map(|kline| kline.start).collect::<Vec<_>>(),
+            vec![start + 5880, start + 5940, start + 6000]
+        );
+        assert_eq!(klines[2].close, dec!(11));
+    }
+}
@@ -1,27 +1,43 @@
 #![allow(clippy::if_same_then_else)]
 use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController, BalanceUpdateParams, BusinessType};
+use crate::clock::{Clock, EngineClock};
 use crate::config::{self, OrderSignatrueCheck};
-use crate::persist::PersistExector;
-use crate::sequencer::Sequencer;
-use crate::types::{self, MarketRole, OrderEventType};
+use crate::fee::{FeeDiscount, FeeManager};
+use crate::metrics;
+use crate::persist::{BufferedPersistor, PersistExector, SharedTrade};
+use crate::sequencer::IdAllocator;
+use crate::types::{self, FinishReason, MarketRole, OrderActor, OrderCancelReason, OrderEventType, OrderFinish, TimestampMs};
 
-use std::cmp::min;
-use std::collections::BTreeMap;
+use std::cmp::{min, Ordering};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::iter::Iterator;
 
 use anyhow::{bail, Result};
-use fluidex_common::rust_decimal::prelude::Zero;
+use fluidex_common::rust_decimal::prelude::{One, Zero};
 use fluidex_common::rust_decimal::{Decimal, RoundingStrategy};
-use fluidex_common::utils::timeutil::current_timestamp;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
 pub use types::{OrderSide, OrderType};
 
+mod error;
+pub use error::*;
 mod order;
 pub use order::*;
 mod trade;
 pub use trade::*;
+mod stats;
+pub use stats::*;
+mod kline;
+pub use kline::*;
+mod event;
+pub use event::*;
+mod user_index;
+pub use user_index::*;
+mod nonce;
+pub use nonce::*;
+mod trade_audit;
+pub use trade_audit::*;
 
 pub struct Market {
     pub name: &'static str,
@@ -32,20 +48,65 @@ pub struct Market {
     pub base_prec: u32,
     pub quote_prec: u32,
     pub fee_prec: u32,
+    pub default_maker_fee: Decimal,
+    pub default_taker_fee: Decimal,
+    pub use_default_fees: bool,
+    pub fee_rounding: RoundingStrategy,
+    pub min_base_fee: Option<Decimal>,
+    pub min_quote_fee: Option<Decimal>,
     pub min_amount: Decimal,
+    pub max_amount: Option<Decimal>,
+    pub max_quote_amount: Option<Decimal>,
+    pub min_notional: Decimal,
     pub price: Decimal,
+    pub state: MarketState,
+    pub cancel_when_halted: bool,
 
     pub orders: BTreeMap<u64, OrderRc>,
     pub users: BTreeMap<u32, BTreeMap<u64, OrderRc>>,
+    // user_id -> client_order_id -> order_id, only for the open orders with a client order id
+    pub client_ids: BTreeMap<u32, BTreeMap<u64, u64>>,
 
     pub asks: BTreeMap<MarketKeyAsk, OrderRc>,
     pub bids: BTreeMap<MarketKeyBid, OrderRc>,
+    // price -> (total remain, order count) of the resting orders, kept along with the book
+    // so the depth does not need to scan the orders
+    pub ask_levels: BTreeMap<Decimal, (Decimal, usize)>,
+    pub bid_levels: BTreeMap<Decimal, (Decimal, usize)>,
+    pub ask_totals: BookTotals,
+    pub bid_totals: BookTotals,
+    // the frozen amounts and the order count of every user with resting orders, kept along with the book
+    pub user_exposures: BTreeMap<u32, UserExposure>,
 
     pub trade_count: u64,
+    // sequence of the depth updates, increased for every change of a price level
+    pub depth_seq: u64,
+    // the levels changed while an order is matched, their updates are emitted together once it is done
+    depth_batch: Option<Vec<(OrderSide, Decimal)>>,
+    // rolling 24h statistics of the trades, not kept in the state dump
+    pub trade_stats: MarketStats,
+    pub kline_aggregator: KlineAggregator,
+    // the latest trades, newest at the front
+    pub trade_history: VecDeque<TradeSummary>,
+    pub trade_history_size: usize,
+    pub checksum_levels: usize,
 
     pub disable_self_trade: bool,
     pub disable_market_order: bool,
+    pub post_only_reprice: bool,
+    pub finish_dust_orders: bool,
+    pub max_price_deviation: Option<Decimal>,
+    pub max_open_orders_per_user: usize,
+    pub fee_account_id: u32,
     pub check_eddsa_signatue: OrderSignatrueCheck,
+    // the controller shares its clock, to fix the time of the replayed operations
+    pub clock: EngineClock,
+    // shared by the markets of the controller, see `UserOrderIndex`
+    pub user_orders: UserOrderIndex,
+    // shared by the markets of the controller, see `UserNonces`
+    pub user_nonces: UserNonces,
+    // the matching decisions, off by default
+    pub trade_audit: TradeAuditLog,
 }
 
 pub struct BalanceManagerWrapper<'a> {
@@ -68,13 +129,13 @@ impl BalanceManagerWrapper<'_> {
     pub fn balance_total(&mut self, user_id: u32, asset: &str) -> Decimal {
         self.inner.get(user_id, BalanceType::FREEZE, asset) + self.inner.get(user_id, BalanceType::AVAILABLE, asset)
     }
-    pub fn balance_sub(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) {
-        self.inner.sub(user_id, balance_type, asset, amount);
+    pub fn balance_sub(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> Result<()> {
+        self.inner.sub(user_id, balance_type, asset, amount).map(|_| ())
     }
-    pub fn balance_frozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+    pub fn balance_frozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) -> Result<()> {
         self.inner.frozen(user_id, asset, amount)
     }
-    pub fn balance_unfrozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+    pub fn balance_unfrozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) -> Result<()> {
         self.inner.unfrozen(user_id, asset, amount)
     }
     pub fn asset_prec(&mut self, asset: &str) -> u32 {
@@ -106,9 +167,25 @@ impl Market {
                 bail!("invalid fee precision");
             }
         }
+        if let Some(max_amount) = market_conf.max_amount {
+            if max_amount.lt(&market_conf.min_amount) {
+                bail!("invalid max amount");
+            }
+        }
+        if let Some(max_quote_amount) = market_conf.max_quote_amount {
+            if max_quote_amount.is_sign_negative() || max_quote_amount.is_zero() {
+                bail!("invalid max quote amount");
+            }
+        }
+        if let Some(deviation) = market_conf.max_price_deviation {
+            if deviation.is_sign_negative() || deviation.is_zero() {
+                bail!("invalid max price deviation");
+            }
+        }
         let leak_fn = |x: &str| -> &'static str { Box::leak(x.to_string().into_boxed_str()) };
+        let name = leak_fn(&market_conf.name);
         let market = Market {
-            name: leak_fn(&market_conf.name),
+            name,
             base: leak_fn(&market_conf.base),
             quote: leak_fn(&market_conf.quote),
             amount_prec: market_conf.amount_prec,
@@ -116,16 +193,51 @@ impl Market {
             base_prec,
             quote_prec,
             fee_prec: market_conf.fee_prec,
+            default_maker_fee: market_conf.default_maker_fee,
+            default_taker_fee: market_conf.default_taker_fee,
+            use_default_fees: global_settings.use_market_default_fees,
+            fee_rounding: market_conf.fee_rounding.into(),
+            min_base_fee: market_conf.min_base_fee,
+            min_quote_fee: market_conf.min_quote_fee,
             min_amount: market_conf.min_amount,
+            max_amount: market_conf.max_amount,
+            max_quote_amount: market_conf.max_quote_amount,
+            min_notional: market_conf.min_notional,
             price: Decimal::zero(),
+            state: MarketState::Open,
+            cancel_when_halted: market_conf.cancel_when_halted,
             orders: BTreeMap::new(),
             users: BTreeMap::new(),
+            client_ids: BTreeMap::new(),
             asks: BTreeMap::new(),
             bids: BTreeMap::new(),
+            ask_levels: BTreeMap::new(),
+            bid_levels: BTreeMap::new(),
+            ask_totals: BookTotals::default(),
+            bid_totals: BookTotals::default(),
+            user_exposures: BTreeMap::new(),
             trade_count: 0,
+            depth_seq: 0,
+            depth_batch: None,
+            trade_stats: MarketStats::default(),
+            trade_history: VecDeque::new(),
+            trade_history_size: market_conf.recent_trades,
+            checksum_levels: market_conf.checksum_levels,
+            kline_aggregator: KlineAggregator::new(name, &market_conf.kline_intervals, market_conf.kline_history),
             disable_self_trade: global_settings.disable_self_trade,
             disable_market_order: global_settings.disable_market_order,
+            post_only_reprice: market_conf.post_only_reprice,
+            finish_dust_orders: market_conf.finish_dust_orders,
+            max_price_deviation: market_conf.max_price_deviation,
+            max_open_orders_per_user: market_conf
+                .max_open_orders_per_user
+                .unwrap_or(global_settings.max_open_orders_per_user),
+            fee_account_id: global_settings.fee_account_id,
             check_eddsa_signatue: global_settings.check_eddsa_signatue,
+            clock: EngineClock::default(),
+            user_orders: UserOrderIndex::default(),
+            user_nonces: UserNonces::default(),
+            trade_audit: TradeAuditLog::default(),
         };
         Ok(market)
     }
@@ -134,83 +246,319 @@ impl Market {
         log::debug!("market {} reset", self.name);
         self.bids.clear();
         self.asks.clear();
+        self.bid_levels.clear();
+        self.ask_levels.clear();
+        self.bid_totals = BookTotals::default();
+        self.ask_totals = BookTotals::default();
+        self.user_exposures.clear();
+        self.book_size_changed(OrderSide::ASK);
+        self.book_size_changed(OrderSide::BID);
         self.users.clear();
+        self.user_orders.remove_market(self.name);
+        self.client_ids.clear();
         self.orders.clear();
+        self.state = MarketState::Open;
+        self.price = Decimal::zero();
+        self.trade_count = 0;
+        self.trade_stats = MarketStats::default();
+        self.trade_history.clear();
     }
-    pub fn frozen_balance(&self, balance_manager: &mut BalanceManagerWrapper<'_>, order: &Order) {
+    pub fn frozen_balance(&self, balance_manager: &mut BalanceManagerWrapper<'_>, order: &Order) -> Result<()> {
         let asset = if order.is_ask() { &self.base } else { &self.quote };
 
-        balance_manager.balance_frozen(order.user, asset, &order.frozen);
+        balance_manager.balance_frozen(order.user, asset, &order.frozen)
     }
-    pub fn unfrozen_balance(&self, balance_manager: &mut BalanceManagerWrapper<'_>, order: &Order) {
-        debug_assert!(order.remain.is_sign_positive());
-        if order.remain.is_zero() {
-            return;
+    pub fn unfrozen_balance(&self, balance_manager: &mut BalanceManagerWrapper<'_>, order: &Order) -> Result<()> {
+        if order.remain.is_sign_negative() {
+            bail!("order {} has a negative remain {}", order.id, order.remain);
+        }
+        // whatever is still frozen is released, a filled order has nothing left
+        if order.frozen.is_zero() {
+            return Ok(());
         }
         let asset = if order.is_ask() { &self.base } else { &self.quote };
-        balance_manager.balance_unfrozen(order.user, asset, &order.frozen);
+        balance_manager.balance_unfrozen(order.user, asset, &order.frozen)
     }
 
-    pub fn put_order(
+    // a nonce has to be above the last one of the user, so a signed order can not be put twice.
+    // zero is no nonce, the order is not checked
+    fn check_nonce(order_input: &OrderInput, last: u64) -> Result<(), MarketError> {
+        if order_input.nonce != 0 && order_input.nonce <= last {
+            return Err(MarketError::NonceTooLow {
+                nonce: order_input.nonce,
+                last,
+            });
+        }
+        Ok(())
+    }
+
+    // the price * amount of an order, and the notional of its side of the book with it, are summed up all along
+    // the matching. an order for which they overflow is refused
+    fn check_notional(&self, side: OrderSide, amount: Decimal, price: Decimal) -> Result<(), MarketError> {
+        let totals = if side == OrderSide::ASK {
+            &self.ask_totals
+        } else {
+            &self.bid_totals
+        };
+        match amount.checked_mul(price).and_then(|notional| notional.checked_add(totals.notional)) {
+            Some(_) => Ok(()),
+            None => Err(MarketError::QuoteAmountOverflow),
+        }
+    }
+
+    // a broken invariant of the book or of the balances. it is emitted as a market event and the market
+    // takes only cancels afterwards, so the state is not corrupted any further
+    fn invariant_violated(&mut self, persistor: &mut impl PersistExector, order_id: u64, invariant: String) -> MarketError {
+        log::error!("market {} order {}: invariant violated: {}", self.name, order_id, invariant);
+        persistor.put_market_event(self.event(MarketEventKind::InvariantViolated {
+            order_id,
+            invariant: invariant.clone(),
+        }));
+        if self.state == MarketState::Open {
+            self.set_state(
+                persistor,
+                MarketState::CancelOnly,
+                format!("invariant violated by order {}", order_id),
+            );
+        }
+        MarketError::InvariantViolated(invariant)
+    }
+
+    // allowed (low, high) price range around the last trade price,
+    // none when no band is configured or no trade has happened yet
+    fn price_band(&self) -> Option<(Decimal, Decimal)> {
+        match self.max_price_deviation {
+            Some(deviation) if !self.price.is_zero() => {
+                Some((self.price * (Decimal::one() - deviation), self.price * (Decimal::one() + deviation)))
+            }
+            _ => None,
+        }
+    }
+
+    // an order whose remain cannot be traded anymore
+    fn is_dust(amount_prec: u32, min_amount: &Decimal, remain: &Decimal) -> bool {
+        let remain = remain.round_dp_with_strategy(amount_prec, RoundingStrategy::ToZero);
+        remain.is_zero() || remain.lt(min_amount)
+    }
+
+    // the fee of trading `amount` at `rate`, rounded to `prec`. a positive fee is raised
+    // to `min_fee` if below it, but never above the amount. rebates are always rounded down
+    fn trade_fee(amount: Decimal, rate: Decimal, prec: u32, rounding: RoundingStrategy, min_fee: Option<Decimal>) -> Decimal {
+        let fee = amount * rate;
+        if !fee.gt(&Decimal::zero()) {
+            return fee.round_dp_with_strategy(prec, RoundingStrategy::ToZero);
+        }
+        let fee = fee.round_dp_with_strategy(prec, rounding);
+        match min_fee {
+            Some(min_fee) if fee.lt(&min_fee) => min(min_fee, amount),
+            _ => fee,
+        }
+    }
+
+    // the taker fee rate is in [0, 1), and a maker never pays more than a taker.
+    // a negative maker fee is a rebate, which cannot exceed the taker fee either
+    fn valid_fee_rates(maker_fee: &Decimal, taker_fee: &Decimal) -> bool {
+        !taker_fee.is_sign_negative() && taker_fee.lt(&Decimal::one()) && !maker_fee.abs().gt(taker_fee)
+    }
+
+    // change the default fees of the market. only the orders placed later are affected,
+    // the resting orders keep the fees they were placed with
+    pub fn update_fees(&mut self, maker_fee: Decimal, taker_fee: Decimal) -> Result<(), MarketError> {
+        if !Self::valid_fee_rates(&maker_fee, &taker_fee) {
+            return Err(MarketError::InvalidFee);
+        }
+        if self.fee_prec == 0 && (!taker_fee.is_zero() || !maker_fee.is_zero()) {
+            return Err(MarketError::FeeNotSupported);
+        }
+        self.default_maker_fee = maker_fee;
+        self.default_taker_fee = taker_fee;
+        Ok(())
+    }
+
+    // change the precisions and the min amount for the orders placed later. the resting orders
+    // that do not fit the new values are canceled, returns them
+    pub fn update_params(
         &mut self,
-        sequencer: &mut Sequencer,
         mut balance_manager: BalanceManagerWrapper<'_>,
-        balance_update_controller: &mut BalanceUpdateController,
         persistor: &mut impl PersistExector,
-        order_input: OrderInput,
-    ) -> Result<Order> {
+        amount_prec: u32,
+        price_prec: u32,
+        min_amount: Decimal,
+    ) -> Result<Vec<Order>, MarketError> {
+        // the same invariants as `Market::new`
+        if amount_prec > self.base_prec || amount_prec + price_prec > self.quote_prec {
+            return Err(MarketError::InvalidPrecision);
+        }
+        if min_amount.is_sign_negative() || self.max_amount.map_or(false, |max_amount| max_amount.lt(&min_amount)) {
+            return Err(MarketError::InvalidMinAmount);
+        }
+        self.amount_prec = amount_prec;
+        self.price_prec = price_prec;
+        self.min_amount = min_amount;
+
+        let stranded: Vec<Order> = self
+            .orders
+            .values()
+            .map(OrderRc::deep)
+            .filter(|order| {
+                order.price.round_dp(price_prec) != order.price
+                    || order.remain.round_dp_with_strategy(amount_prec, RoundingStrategy::ToZero) != order.remain
+                    || order.remain.lt(&min_amount)
+            })
+            .collect();
+        // every stranded order leaves the book, the first violation is returned after
+        let mut violation = None;
+        for order in &stranded {
+            let finished = self.order_finish(
+                &mut balance_manager,
+                persistor,
+                order,
+                OrderFinish::new(FinishReason::MarketParamsChanged, OrderActor::Admin),
+            );
+            if let Err(e) = finished {
+                violation.get_or_insert(e);
+            }
+        }
+        match violation {
+            Some(e) => Err(e),
+            None => Ok(stranded),
+        }
+    }
+
+    // the fees of the tier of the user when the tiers are enabled,
+    // otherwise the defaults of the market for an order placed without fees
+    fn apply_fees(&self, fee_manager: &FeeManager, order_input: &mut OrderInput) {
+        if fee_manager.enabled {
+            fee_manager.apply(order_input);
+        } else if self.use_default_fees && order_input.maker_fee.is_zero() && order_input.taker_fee.is_zero() {
+            order_input.maker_fee = self.default_maker_fee;
+            order_input.taker_fee = self.default_taker_fee;
+        }
+    }
+
+    // validate the order input against the available balance of the asset to be frozen
+    // and the number of open orders of the user, returns (amount, quote_limit, slippage_price)
+    fn check_order_input(
+        &self,
+        order_input: &OrderInput,
+        available: &Decimal,
+        open_orders: usize,
+        band: Option<(Decimal, Decimal)>,
+    ) -> Result<(Decimal, Decimal, Option<Decimal>), MarketError> {
+        // a routing bug should not book the order with the precision rules of another market
+        if order_input.market != self.name {
+            return Err(MarketError::MarketMismatch {
+                expected: self.name.to_string(),
+                actual: order_input.market.clone(),
+            });
+        }
+        if !Self::valid_fee_rates(&order_input.maker_fee, &order_input.taker_fee) {
+            return Err(MarketError::InvalidFee);
+        }
         if order_input.type_ == OrderType::MARKET && self.disable_market_order {
-            bail!("market orders disabled");
+            return Err(MarketError::MarketOrdersDisabled);
+        }
+        let is_market_bid = order_input.type_ == OrderType::MARKET && order_input.side == OrderSide::BID;
+        if order_input.amount_is_quote && !is_market_bid {
+            return Err(MarketError::QuoteAmountNotSupported);
         }
-        if order_input.amount.lt(&self.min_amount) {
-            bail!("invalid amount");
+        // for quote amount, the minimum is checked against the counter orderbook later
+        if !order_input.amount_is_quote && order_input.amount.lt(&self.min_amount) {
+            return Err(MarketError::BelowMinAmount);
         }
         // fee_prec == 0 means no fee allowed
         if self.fee_prec == 0 && (!order_input.taker_fee.is_zero() || !order_input.maker_fee.is_zero()) {
-            bail!("only 0 fee is supported now");
+            return Err(MarketError::FeeNotSupported);
         }
-        let amount = order_input
-            .amount
-            .round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero);
+        let amount_prec = if order_input.amount_is_quote {
+            self.amount_prec + self.price_prec
+        } else {
+            self.amount_prec
+        };
+        let amount = order_input.amount.round_dp_with_strategy(amount_prec, RoundingStrategy::ToZero);
         if amount != order_input.amount {
-            bail!("invalid amount precision");
+            return Err(MarketError::InvalidAmountPrecision);
         }
         let price = order_input.price.round_dp(self.price_prec);
         if price != order_input.price {
-            bail!("invalid price precision");
+            return Err(MarketError::InvalidPricePrecision);
+        }
+        if order_input.type_ == OrderType::LIMIT {
+            self.check_notional(order_input.side, order_input.amount, order_input.price)?;
+        }
+        // caps are checked after the precision checks so the errors are deterministic
+        if let Some(max_amount) = self.max_amount {
+            if !order_input.amount_is_quote && order_input.amount.gt(&max_amount) {
+                return Err(MarketError::AboveMaxAmount);
+            }
+        }
+        if let Some(max_quote_amount) = self.max_quote_amount {
+            // the market bid case is checked against quote_limit below
+            if order_input.type_ == OrderType::LIMIT && (order_input.amount * order_input.price).gt(&max_quote_amount) {
+                return Err(MarketError::AboveMaxQuoteAmount);
+            }
         }
         if order_input.type_ == OrderType::MARKET {
             if !order_input.price.is_zero() {
-                bail!("market order should not have a price");
+                return Err(MarketError::MarketOrderWithPrice);
             }
             if order_input.post_only {
-                bail!("market order cannot be post only");
+                return Err(MarketError::MarketOrderPostOnly);
             }
             if order_input.side == OrderSide::ASK && self.bids.is_empty() || order_input.side == OrderSide::BID && self.asks.is_empty() {
-                bail!("no counter orders");
+                return Err(MarketError::NoCounterOrders);
+            }
+            if order_input.side == OrderSide::ASK {
+                let best_bid_price = self.bids.values().next().unwrap().borrow().price;
+                if order_input.amount.checked_mul(best_bid_price).is_none() {
+                    return Err(MarketError::QuoteAmountOverflow);
+                }
+            }
+            if order_input.amount_is_quote {
+                // quote to spend should be able to buy at least `min_amount` at the best ask price
+                let best_ask_price = self.asks.values().next().unwrap().borrow().price;
+                if order_input.amount.lt(&(self.min_amount * best_ask_price)) {
+                    return Err(MarketError::BelowMinAmount);
+                }
             }
+        } else if order_input.max_slippage.is_some() {
+            return Err(MarketError::MaxSlippageNotSupported);
         } else if order_input.price.is_zero() {
-            bail!("invalid price for limit order");
+            return Err(MarketError::InvalidPrice);
+        } else if let Some((low, high)) = band {
+            if order_input.price.lt(&low) || order_input.price.gt(&high) {
+                return Err(MarketError::PriceDeviation);
+            }
+        }
+
+        if let Some(client_order_id) = order_input.client_order_id {
+            if self.get_by_client_id(order_input.user_id, client_order_id).is_some() {
+                return Err(MarketError::DuplicateClientOrderId(client_order_id));
+            }
+        }
+        // market orders never rest in the orderbook, so they don't take the quota
+        if order_input.type_ == OrderType::LIMIT && self.max_open_orders_per_user != 0 && open_orders >= self.max_open_orders_per_user {
+            return Err(MarketError::TooManyOpenOrders);
         }
 
         if order_input.side == OrderSide::ASK {
-            if balance_manager
-                .balance_get(order_input.user_id, BalanceType::AVAILABLE, self.base)
-                .lt(&order_input.amount)
-            {
-                bail!("balance not enough");
+            if available.lt(&order_input.amount) {
+                return Err(MarketError::BalanceNotEnough {
+                    asset: self.base.to_string(),
+                    required: order_input.amount,
+                    available: *available,
+                });
             }
         } else {
-            let balance = balance_manager.balance_get(order_input.user_id, BalanceType::AVAILABLE, self.quote);
+            let balance = *available;
 
             if order_input.type_ == OrderType::LIMIT {
                 if balance.lt(&(order_input.amount * order_input.price)) {
-                    bail!(
-                        "balance not enough: balance({}) < amount({}) * price({})",
-                        &balance,
-                        &order_input.amount,
-                        &order_input.price
-                    );
+                    return Err(MarketError::BalanceNotEnough {
+                        asset: self.quote.to_string(),
+                        required: order_input.amount * order_input.price,
+                        available: balance,
+                    });
                 }
             } else {
                 // We have already checked that counter order book is not empty,
@@ -227,9 +575,9 @@ impl Market {
                 //}
             }
         }
-        let quote_limit = if order_input.type_ == OrderType::MARKET && order_input.side == OrderSide::BID {
-            let balance = balance_manager.balance_get(order_input.user_id, BalanceType::AVAILABLE, self.quote);
-            if order_input.quote_limit.is_zero() {
+        let quote_limit = if is_market_bid {
+            let balance = *available;
+            let quote_limit = if order_input.quote_limit.is_zero() {
                 // quote_limit == 0 means no extra limit
                 balance
             } else {
@@ -237,17 +585,175 @@ impl Market {
                     balance,
                     order_input
                         .quote_limit
-                        .round_dp_with_strategy(balance_manager.asset_prec(self.quote), RoundingStrategy::ToZero),
+                        .round_dp_with_strategy(self.quote_prec, RoundingStrategy::ToZero),
                 )
+            };
+            let quote_limit = if order_input.amount_is_quote {
+                // the budget is clamped by the available balance
+                std::cmp::min(quote_limit, order_input.amount)
+            } else {
+                quote_limit
+            };
+            match self.max_quote_amount {
+                // `amount` of a market bid is only a base-denominated wish,
+                // the quote actually spent is bounded by quote_limit
+                Some(max_quote_amount) if order_input.amount_is_quote || !order_input.quote_limit.is_zero() => {
+                    if quote_limit.gt(&max_quote_amount) {
+                        return Err(MarketError::AboveMaxQuoteAmount);
+                    }
+                    quote_limit
+                }
+                // no explicit limit from the user, spend at most the cap
+                Some(max_quote_amount) => std::cmp::min(quote_limit, max_quote_amount),
+                None => quote_limit,
             }
+        } else if order_input.type_ == OrderType::MARKET && !order_input.quote_limit.is_zero() {
+            // for market ask, quote_limit is the max quote the user wants to receive
+            let quote_limit = order_input
+                .quote_limit
+                .round_dp_with_strategy(self.quote_prec, RoundingStrategy::ToZero);
+            if quote_limit.is_sign_negative() || quote_limit.is_zero() {
+                return Err(MarketError::InvalidQuoteLimit);
+            }
+            quote_limit
         } else {
             // not used
             Decimal::zero()
         };
 
-        let t = current_timestamp();
+        // the worst price the market order accepts
+        let slippage_price = match order_input.max_slippage {
+            Some(max_slippage) => {
+                if max_slippage.is_sign_negative() {
+                    return Err(MarketError::InvalidMaxSlippage);
+                }
+                if order_input.side == OrderSide::BID {
+                    let best_ask_price = self.asks.values().next().unwrap().borrow().price;
+                    Some(
+                        (best_ask_price * (Decimal::one() + max_slippage))
+                            .round_dp_with_strategy(self.price_prec, RoundingStrategy::ToZero),
+                    )
+                } else {
+                    let best_bid_price = self.bids.values().next().unwrap().borrow().price;
+                    Some(
+                        (best_bid_price * (Decimal::one() - max_slippage))
+                            .round_dp_with_strategy(self.price_prec, RoundingStrategy::AwayFromZero),
+                    )
+                }
+            }
+            None => None,
+        };
+
+        if !self.min_notional.is_zero() {
+            let notional = match (order_input.type_, order_input.side) {
+                (OrderType::LIMIT, _) => order_input.amount * order_input.price,
+                // quote_limit is already clamped by the balance and the quote amount
+                (OrderType::MARKET, OrderSide::BID) => quote_limit,
+                (OrderType::MARKET, OrderSide::ASK) => order_input.amount * self.bids.values().next().unwrap().borrow().price,
+            };
+            if notional.lt(&self.min_notional) {
+                return Err(MarketError::BelowMinNotional);
+            }
+        }
+
+        let amount = if order_input.amount_is_quote {
+            // asks are sorted by price, so the budget divided by the best ask price
+            // is an upper bound of the base amount that can be bought
+            let best_ask_price = self.asks.values().next().unwrap().borrow().price;
+            let amount = (quote_limit / best_ask_price).round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero);
+            if amount.is_zero() {
+                return Err(MarketError::BelowMinQuoteAmount);
+            }
+            amount
+        } else {
+            order_input.amount
+        };
+        Ok((amount, quote_limit, slippage_price))
+    }
+
+    pub fn put_order(
+        &mut self,
+        sequencer: &mut impl IdAllocator,
+        balance_manager: BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        fee_manager: &FeeManager,
+        persistor: &mut impl PersistExector,
+        order_input: OrderInput,
+    ) -> Result<Order, MarketError> {
+        let band = self.price_band();
+        self.put_order_in_band(
+            sequencer,
+            balance_manager,
+            balance_update_controller,
+            fee_manager,
+            persistor,
+            order_input,
+            band,
+        )
+    }
+    // the price of a limit order is checked against `band` rather than the band around the last price
+    fn put_order_in_band(
+        &mut self,
+        sequencer: &mut impl IdAllocator,
+        balance_manager: BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        fee_manager: &FeeManager,
+        persistor: &mut impl PersistExector,
+        order_input: OrderInput,
+        band: Option<(Decimal, Decimal)>,
+    ) -> Result<Order, MarketError> {
+        let timer = metrics::MatchTimer::start();
+        let result = self.put_order_unmetered(
+            sequencer,
+            balance_manager,
+            balance_update_controller,
+            fee_manager,
+            persistor,
+            order_input,
+            band,
+        );
+        metrics::order_put(self.name, &result, timer);
+        result
+    }
+    fn put_order_unmetered(
+        &mut self,
+        sequencer: &mut impl IdAllocator,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        fee_manager: &FeeManager,
+        persistor: &mut impl PersistExector,
+        mut order_input: OrderInput,
+        band: Option<(Decimal, Decimal)>,
+    ) -> Result<Order, MarketError> {
+        self.check_open()?;
+        self.apply_fees(fee_manager, &mut order_input);
+        let asset = if order_input.side == OrderSide::ASK {
+            self.base
+        } else {
+            self.quote
+        };
+        let available = balance_manager.balance_get(order_input.user_id, BalanceType::AVAILABLE, asset);
+        let open_orders = self.get_order_num_of_user(order_input.user_id);
+        let (amount, quote_limit, slippage_price) = self.check_order_input(&order_input, &available, open_orders, band)?;
+        Self::check_nonce(&order_input, self.user_nonces.last(order_input.user_id))?;
+        // the taker fee is charged in the asset received. paying it in the discount asset
+        // is not supported when trading the discount asset itself
+        let fee_asset = if order_input.side == OrderSide::ASK {
+            self.quote
+        } else {
+            self.base
+        };
+        let fee_discount = fee_manager
+            .get_discount(order_input.user_id, fee_asset)
+            .filter(|discount| discount.asset != self.base && discount.asset != self.quote);
+
+        let t = self.clock.now();
+        let id = sequencer.next_order_id();
+        // taken by the order from now on, even if it fails later on
+        self.user_nonces.accept(order_input.user_id, order_input.nonce);
         let order = Order {
-            id: sequencer.next_order_id(),
+            id,
+            priority: id,
             type_: order_input.type_,
             side: order_input.side,
             create_time: t,
@@ -257,47 +763,374 @@ impl Market {
             quote: self.quote.into(),
             user: order_input.user_id,
             price: order_input.price,
-            amount: order_input.amount,
+            amount,
             taker_fee: order_input.taker_fee,
             maker_fee: order_input.maker_fee,
-            remain: order_input.amount,
+            remain: amount,
             frozen: Decimal::zero(),
             finished_base: Decimal::zero(),
             finished_quote: Decimal::zero(),
             finished_fee: Decimal::zero(),
             post_only: order_input.post_only,
             signature: order_input.signature,
+            client_order_id: order_input.client_order_id,
+            fill_outcome: None,
         };
-        let order = self.execute_order(
+        self.execute_order(
             sequencer,
             &mut balance_manager,
             balance_update_controller,
             persistor,
             order,
             &quote_limit,
+            slippage_price,
+            fee_discount.as_ref(),
+            true,
+        )
+    }
+
+    // cancel `cancel_ids` then place `new_orders` in one call. all the new orders are validated
+    // first, counting the balance unfrozen by the cancels, so nothing is applied if any of them is invalid.
+    // the prices are checked against the band before the replacement, which a trade of an earlier new order
+    // does not move. the events are emitted as one batch after the replacement.
+    // returns the canceled orders and the placed orders
+    pub fn replace_orders(
+        &mut self,
+        sequencer: &mut impl IdAllocator,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        fee_manager: &FeeManager,
+        persistor: &mut impl PersistExector,
+        cancel_ids: Vec<u64>,
+        mut new_orders: Vec<OrderInput>,
+    ) -> Result<(Vec<Order>, Vec<Order>), MarketError> {
+        if !new_orders.is_empty() {
+            self.check_open()?;
+        }
+        self.check_cancel()?;
+        let mut canceled = Vec::with_capacity(cancel_ids.len());
+        for order_id in cancel_ids.iter().unique() {
+            match self.orders.get(order_id) {
+                Some(order) => canceled.push(order.deep()),
+                None => return Err(MarketError::OrderNotFound(*order_id)),
+            }
+        }
+
+        // (user, asset) -> balance to be released by the cancels minus balance taken by the new orders
+        let mut balance_deltas: BTreeMap<(u32, &'static str), Decimal> = BTreeMap::new();
+        // user -> change of open orders
+        let mut open_order_deltas: BTreeMap<u32, isize> = BTreeMap::new();
+        // user -> the last nonce, counting the new orders before
+        let mut last_nonces: BTreeMap<u32, u64> = BTreeMap::new();
+        // the client order ids of the new orders, unique among them too
+        let mut client_ids: BTreeSet<(u32, u64)> = BTreeSet::new();
+        // the notional of each side as if all the new orders rest, it only shrinks by the trades
+        let (mut ask_notional, mut bid_notional) = (self.ask_totals.notional, self.bid_totals.notional);
+        let band = self.price_band();
+        for order in &canceled {
+            let asset = if order.is_ask() { self.base } else { self.quote };
+            *balance_deltas.entry((order.user, asset)).or_insert_with(Decimal::zero) += order.frozen;
+            *open_order_deltas.entry(order.user).or_insert(0) -= 1;
+        }
+        for order_input in &mut new_orders {
+            self.apply_fees(fee_manager, order_input);
+            // market orders take whatever balance is available, so the frozen amount of
+            // the following orders could not be known before placing them
+            if order_input.type_ != OrderType::LIMIT {
+                return Err(MarketError::ReplaceWithMarketOrder);
+            }
+            let asset = if order_input.side == OrderSide::ASK {
+                self.base
+            } else {
+                self.quote
+            };
+            let balance_delta = balance_deltas.entry((order_input.user_id, asset)).or_insert_with(Decimal::zero);
+            let available = balance_manager.balance_get(order_input.user_id, BalanceType::AVAILABLE, asset) + *balance_delta;
+            let open_order_delta = open_order_deltas.entry(order_input.user_id).or_insert(0);
+            let open_orders = (self.get_order_num_of_user(order_input.user_id) as isize + *open_order_delta) as usize;
+            self.check_order_input(order_input, &available, open_orders, band)?;
+            if let Some(client_order_id) = order_input.client_order_id {
+                if !client_ids.insert((order_input.user_id, client_order_id)) {
+                    return Err(MarketError::DuplicateClientOrderId(client_order_id));
+                }
+            }
+            let notional = if order_input.side == OrderSide::ASK {
+                &mut ask_notional
+            } else {
+                &mut bid_notional
+            };
+            *notional = (order_input.amount * order_input.price)
+                .checked_add(*notional)
+                .ok_or(MarketError::QuoteAmountOverflow)?;
+            let last_nonce = last_nonces
+                .entry(order_input.user_id)
+                .or_insert_with(|| self.user_nonces.last(order_input.user_id));
+            Self::check_nonce(order_input, *last_nonce)?;
+            *last_nonce = order_input.nonce.max(*last_nonce);
+            // the most the order can take, when it rests in the orderbook entirely
+            *balance_delta -= if order_input.side == OrderSide::ASK {
+                order_input.amount
+            } else {
+                order_input.amount * order_input.price
+            };
+            *open_order_delta += 1;
+        }
+
+        let mut buffered = BufferedPersistor::new(persistor.real_persist());
+        let placed = self.apply_replacement(
+            sequencer,
+            balance_manager,
+            balance_update_controller,
+            fee_manager,
+            &mut buffered,
+            &canceled,
+            new_orders,
+            band,
         );
+        // the state is changed even if a violation stopped the replacement
+        buffered.flush_into(persistor);
+        Ok((canceled, placed?))
+    }
+    // the validation of `replace_orders` ensures it succeeds, unless an invariant is found violated
+    fn apply_replacement(
+        &mut self,
+        sequencer: &mut impl IdAllocator,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        fee_manager: &FeeManager,
+        persistor: &mut BufferedPersistor,
+        canceled: &[Order],
+        new_orders: Vec<OrderInput>,
+        band: Option<(Decimal, Decimal)>,
+    ) -> Result<Vec<Order>, MarketError> {
+        for order in canceled {
+            self.order_finish(
+                &mut balance_manager,
+                persistor,
+                order,
+                OrderFinish::new(FinishReason::Canceled, OrderActor::User),
+            )?;
+        }
+        let mut placed = Vec::with_capacity(new_orders.len());
+        for order_input in new_orders {
+            let order = self.put_order_in_band(
+                sequencer,
+                (&mut *balance_manager.inner).into(),
+                balance_update_controller,
+                fee_manager,
+                persistor,
+                order_input,
+                band,
+            )?;
+            placed.push(order);
+        }
+        Ok(placed)
+    }
+
+    // shrink a resting order by `reduce_by` in place, keeping its time priority.
+    // the order is finished if the rest falls below `min_amount`
+    pub fn reduce_order(
+        &mut self,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        order_id: u64,
+        reduce_by: Decimal,
+    ) -> Result<Order, MarketError> {
+        self.check_cancel()?;
+        let mut order = match self.orders.get(&order_id) {
+            Some(order) => order.deep(),
+            None => return Err(MarketError::OrderNotFound(order_id)),
+        };
+        if reduce_by.is_sign_negative() || reduce_by.is_zero() {
+            return Err(MarketError::InvalidReduceAmount);
+        }
+        if reduce_by.round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero) != reduce_by {
+            return Err(MarketError::InvalidAmountPrecision);
+        }
+        if reduce_by.gt(&order.remain) {
+            return Err(MarketError::ReduceAmountExceedsRemain);
+        }
+
+        let unfrozen = if order.is_ask() { reduce_by } else { reduce_by * order.price };
+        let asset = if order.is_ask() { self.base } else { self.quote };
+        balance_manager
+            .balance_unfrozen(order.user, asset, &unfrozen)
+            .map_err(|e| self.invariant_violated(persistor, order_id, e.to_string()))?;
+        order.amount -= reduce_by;
+        order.remain -= reduce_by;
+        order.frozen -= unfrozen;
+        order.update_time = self.clock.now();
+        *self.orders.get_mut(&order_id).unwrap().borrow_mut() = order;
+        Self::level_sub(self.levels_mut(order.side), order.price, reduce_by, 0);
+        let totals = self.totals_mut(order.side);
+        totals.amount -= reduce_by;
+        totals.frozen -= unfrozen;
+        totals.notional -= reduce_by * order.price;
+        Self::exposure_add(&mut self.user_exposures, order.user, order.side, -unfrozen, 0);
+
+        if order.remain.lt(&self.min_amount) {
+            // reduced by the user below the min amount
+            self.order_finish(
+                &mut balance_manager,
+                persistor,
+                &order,
+                OrderFinish::new(FinishReason::Dust, OrderActor::User),
+            )?;
+        } else {
+            persistor.put_order(&order, OrderEventType::UPDATE);
+            self.put_depth_update(persistor, order.side, order.price);
+        }
         Ok(order)
     }
 
-    // the last parameter `quote_limit`, is only used for market bid order,
-    // it indicates the `quote` balance of the user,
-    // so the sum of all the trades' quote amount cannot exceed this value
+    // change the price and/or the total amount of a resting limit order, the order id is kept.
+    // decreasing the amount keeps the time priority of the order. otherwise the order
+    // goes to the back of its (new) price level, and is matched again if it crosses the book
+    pub fn amend_order(
+        &mut self,
+        sequencer: &mut impl IdAllocator,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        balance_update_controller: &mut BalanceUpdateController,
+        persistor: &mut impl PersistExector,
+        order_id: u64,
+        new_price: Option<Decimal>,
+        new_amount: Option<Decimal>,
+    ) -> Result<Order, MarketError> {
+        // an amended order may be matched again
+        self.check_open()?;
+        let before = match self.orders.get(&order_id) {
+            Some(order) => order.deep(),
+            None => return Err(MarketError::OrderNotFound(order_id)),
+        };
+        let price = new_price.unwrap_or(before.price);
+        let amount = new_amount.unwrap_or(before.amount);
+        if price == before.price && amount == before.amount {
+            return Err(MarketError::NothingToAmend);
+        }
+        if amount.round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero) != amount {
+            return Err(MarketError::InvalidAmountPrecision);
+        }
+        if price.round_dp(self.price_prec) != price {
+            return Err(MarketError::InvalidPricePrecision);
+        }
+        if price.is_sign_negative() || price.is_zero() {
+            return Err(MarketError::InvalidPrice);
+        }
+        if amount.lt(&self.min_amount) {
+            return Err(MarketError::BelowMinAmount);
+        }
+        if let Some(max_amount) = self.max_amount {
+            if amount.gt(&max_amount) {
+                return Err(MarketError::AboveMaxAmount);
+            }
+        }
+        self.check_notional(before.side, amount, price)?;
+        if let Some(max_quote_amount) = self.max_quote_amount {
+            if (amount * price).gt(&max_quote_amount) {
+                return Err(MarketError::AboveMaxQuoteAmount);
+            }
+        }
+        if price != before.price {
+            if let Some((low, high)) = self.price_band() {
+                if price.lt(&low) || price.gt(&high) {
+                    return Err(MarketError::PriceDeviation);
+                }
+            }
+        }
+        if !amount.gt(&before.finished_base) {
+            return Err(MarketError::AmountNotAboveFinished);
+        }
+
+        let remain = amount - before.finished_base;
+        let (asset, frozen) = if before.is_ask() {
+            (self.base, remain)
+        } else {
+            (self.quote, remain * price)
+        };
+        if frozen.gt(&before.frozen) {
+            let available = balance_manager.balance_get(before.user, BalanceType::AVAILABLE, asset);
+            if available.lt(&(frozen - before.frozen)) {
+                return Err(MarketError::BalanceNotEnough {
+                    asset: asset.to_string(),
+                    required: frozen - before.frozen,
+                    available,
+                });
+            }
+        }
+
+        let mut after = before;
+        after.price = price;
+        after.amount = amount;
+        after.remain = remain;
+        after.update_time = self.clock.now();
+
+        if price == before.price && amount.lt(&before.amount) {
+            // same book key, update the order in place to keep the time priority
+            after.frozen = frozen;
+            balance_manager
+                .balance_unfrozen(before.user, asset, &(before.frozen - frozen))
+                .map_err(|e| self.invariant_violated(persistor, order_id, e.to_string()))?;
+            *self.orders.get_mut(&order_id).unwrap().borrow_mut() = after;
+            Self::level_sub(self.levels_mut(after.side), after.price, before.remain - after.remain, 0);
+            let totals = self.totals_mut(after.side);
+            totals.amount -= before.remain - after.remain;
+            totals.frozen -= before.frozen - frozen;
+            totals.notional -= (before.remain - after.remain) * after.price;
+            Self::exposure_add(&mut self.user_exposures, after.user, after.side, frozen - before.frozen, 0);
+            persistor.put_amended_order(&before, &after);
+            self.put_depth_update(persistor, after.side, after.price);
+            return Ok(after);
+        }
+
+        self.unfrozen_balance(&mut balance_manager, &before)
+            .map_err(|e| self.invariant_violated(persistor, order_id, e.to_string()))?;
+        self.remove_order_from_orderbook(&before);
+        self.put_depth_update(persistor, before.side, before.price);
+        after.frozen = Decimal::zero();
+        // priorities share the sequence with order ids, so book keys never collide
+        after.priority = sequencer.next_order_id();
+        persistor.put_amended_order(&before, &after);
+        self.execute_order(
+            sequencer,
+            &mut balance_manager,
+            balance_update_controller,
+            persistor,
+            after,
+            &Decimal::zero(),
+            None,
+            None,
+            false,
+        )
+    }
+
+    // the last parameter `quote_limit`, is only used for market orders.
+    // for market bid order, it indicates the `quote` balance of the user,
+    // for market ask order, it is the max quote proceeds the user wants (zero means no limit),
+    // so the sum of all the trades' quote amount cannot exceed this value.
+    // `slippage_price` is the worst maker price a market order can be matched with.
+    // `fee_discount` is set when the taker fees are paid in the discount asset.
+    // `is_new_order` is false when an existing order is matched again, no PUT event is emitted then
     fn execute_order(
         &mut self,
-        sequencer: &mut Sequencer,
+        sequencer: &mut impl IdAllocator,
         balance_manager: &mut BalanceManagerWrapper<'_>,
         balance_update_controller: &mut BalanceUpdateController,
         persistor: &mut impl PersistExector,
         mut taker: Order,
         quote_limit: &Decimal,
-    ) -> Order {
+        slippage_price: Option<Decimal>,
+        fee_discount: Option<&FeeDiscount>,
+        is_new_order: bool,
+    ) -> Result<Order, MarketError> {
         log::debug!("execute_order {:?}", taker);
+        debug_assert_eq!(self.state, MarketState::Open);
 
         // the the older version, PUT means being inserted into orderbook
         // so if an order is matched instantly, only 'FINISH' event will occur, no 'PUT' event
-        // now PUT means being created
-        // we can revisit this decision later
-        persistor.put_order(&taker, OrderEventType::PUT);
+        // now PUT means being created, it is emitted before the first trade or before resting,
+        // so an order canceled before any trade (post only crossing, self trade...) only gets a FINISH event with the reason
+        let mut put_pending = is_new_order;
+        self.depth_batch = Some(Vec::new());
 
         let taker_is_ask = taker.side == OrderSide::ASK;
         let taker_is_bid = !taker_is_ask;
@@ -306,8 +1139,17 @@ impl Market {
         let is_limit_order = taker.type_ == OrderType::LIMIT;
         let is_market_order = !is_limit_order;
         let is_post_only_order = taker.post_only;
+        let is_quote_limited = is_market_order && (taker_is_bid || !quote_limit.is_zero());
 
         let mut quote_sum = Decimal::zero();
+        let trade_count_before = self.trade_count;
+        let mut stop_reason = None;
+        // a clamped trade smaller than this is not made
+        let min_trade_amount = Decimal::new(1, self.amount_prec).max(self.min_amount);
+        // the rest of the quote limit did not buy the whole of a counter order
+        let mut budget_short = false;
+        // the band is fixed by the last price before this order
+        let price_band = self.price_band();
 
         let mut finished_orders = Vec::new();
 
@@ -317,12 +1159,18 @@ impl Market {
             Box::new(self.asks.values_mut())
         };
 
+        // the price of the maker left partially filled, whose level is changed
+        let mut partially_filled_price = None;
         // TODO: find a more elegant way to handle this
-        let mut need_cancel = false;
+        let mut cancel_reason = None;
+        let mut post_only_adjusted_price = None;
+        // the broken invariant which stopped the matching
+        let mut broken = None;
         for maker_ref in counter_orders {
             // Step1: get ask and bid
             let mut maker = maker_ref.borrow_mut();
             if taker.remain.is_zero() {
+                stop_reason = Some(MatchStopReason::Filled);
                 break;
             }
             let (ask_fee_rate, bid_fee_rate) = if taker_is_ask {
@@ -332,76 +1180,388 @@ impl Market {
             };
             // of course, price should be counter order price
             let price = maker.price;
-            let (ask_order, bid_order) = if taker_is_ask {
-                (&mut taker, &mut *maker)
-            } else {
-                (&mut *maker, &mut taker)
-            };
-            //let ask_order_id: u64 = ask_order.id;
-            //let bid_order_id: u64 = bid_order.id;
 
             // Step2: abort if needed
-            if is_limit_order && ask_order.price.gt(&bid_order.price) {
+            let (ask_price, bid_price) = if taker_is_ask { (taker.price, price) } else { (price, taker.price) };
+            if is_limit_order && ask_price.gt(&bid_price) {
+                stop_reason = Some(MatchStopReason::PriceCross);
                 break;
             }
+            if let Some(slippage_price) = slippage_price {
+                // the rest of the order is finished
+                if taker_is_bid && price.gt(&slippage_price) || taker_is_ask && price.lt(&slippage_price) {
+                    stop_reason = Some(MatchStopReason::Slippage);
+                    break;
+                }
+            }
+            if let Some((low, high)) = price_band {
+                if is_market_order && (price.lt(&low) || price.gt(&high)) {
+                    cancel_reason = Some(OrderCancelReason::PriceDeviation);
+                    stop_reason = Some(MatchStopReason::PriceDeviation);
+                    break;
+                }
+            }
             // new trade will be generated
             if is_post_only_order {
-                need_cancel = true;
+                stop_reason = Some(MatchStopReason::PostOnly);
+                if self.post_only_reprice {
+                    // move the order to one tick away from the best counter price rather than cancel it
+                    let tick = Decimal::new(1, self.price_prec);
+                    let adjusted_price = if taker_is_bid { price - tick } else { price + tick };
+                    debug_assert_eq!(adjusted_price.round_dp(self.price_prec), adjusted_price);
+                    if adjusted_price.is_sign_positive() && !adjusted_price.is_zero() {
+                        post_only_adjusted_price = Some(adjusted_price);
+                        break;
+                    }
+                }
+                cancel_reason = Some(OrderCancelReason::PostOnlyCross);
                 break;
             }
-            if ask_order.user == bid_order.user && self.disable_self_trade {
-                need_cancel = true;
+            if taker.user == maker.user && self.disable_self_trade {
+                cancel_reason = Some(OrderCancelReason::SelfTrade);
+                stop_reason = Some(MatchStopReason::SelfTrade);
                 break;
             }
+            if put_pending {
+                persistor.put_order(&taker, OrderEventType::PUT);
+                put_pending = false;
+            }
+
+            let (ask_order, bid_order) = if taker_is_ask {
+                (&mut taker, &mut *maker)
+            } else {
+                (&mut *maker, &mut taker)
+            };
+            //let ask_order_id: u64 = ask_order.id;
+            //let bid_order_id: u64 = bid_order.id;
 
             // Step3: get trade amount
             let mut traded_base_amount = min(ask_order.remain, bid_order.remain);
-            if taker_is_bid && is_market_order {
-                if (quote_sum + price * traded_base_amount).gt(quote_limit) {
+            if is_quote_limited {
+                // an overflow is over any limit
+                let quote_after = price.checked_mul(traded_base_amount).and_then(|quote| quote.checked_add(quote_sum));
+                if quote_after.map_or(true, |quote_after| quote_after.gt(quote_limit)) {
                     // divide remain quote by price to get a base amount to be traded,
                     // so quote_limit will be `almost` fulfilled
                     let remain_quote_limit = quote_limit - quote_sum;
                     traded_base_amount = (remain_quote_limit / price).round_dp_with_strategy(self.amount_prec, RoundingStrategy::ToZero);
-                    if traded_base_amount.is_zero() {
+                    budget_short = true;
+                    if traded_base_amount < min_trade_amount {
+                        // the prices only get worse for a bid. an ask gets more base for the rest of its limit
+                        // at the lower bids deeper in the book
+                        if taker_is_ask {
+                            continue;
+                        }
+                        stop_reason = Some(MatchStopReason::QuoteLimit);
                         break;
                     }
                 }
             }
-            let traded_quote_amount = price * traded_base_amount;
-            debug_assert!(!traded_base_amount.is_zero());
-            debug_assert!(!traded_quote_amount.is_zero());
-            quote_sum += traded_quote_amount;
-            if taker_is_bid && is_market_order {
-                debug_assert!(quote_sum <= *quote_limit);
+            // rounded once toward zero to the quote precision, which is a no-op unless the quote asset lost digits
+            // since the market was created. the legs of both sides, the trade and the finished quotes all take this value
+            let traded_quote_amount = price
+                .checked_mul(traded_base_amount)
+                .map(|quote| quote.round_dp_with_strategy(self.quote_prec, RoundingStrategy::ToZero));
+            // the sums the trade adds to. an overflow cancels the taker before anything of the trade is applied
+            let traded_quote_amount = match traded_quote_amount {
+                Some(quote)
+                    if quote_sum.checked_add(quote).is_some()
+                        && ask_order.finished_quote.checked_add(quote).is_some()
+                        && bid_order.finished_quote.checked_add(quote).is_some() =>
+                {
+                    quote
+                }
+                _ => {
+                    cancel_reason = Some(OrderCancelReason::AmountOverflow);
+                    stop_reason = Some(MatchStopReason::AmountOverflow);
+                    break;
+                }
+            };
+            // the frozen of the maker is recomputed from its remain after the trade rather than decreased by the
+            // traded amount, so a residual of a rounding is released with the trade instead of stranded
+            let maker_order = if maker_is_bid { &*bid_order } else { &*ask_order };
+            let maker_traded = if maker_is_bid { traded_quote_amount } else { traded_base_amount };
+            let maker_frozen = maker_order.frozen;
+            let maker_frozen_after = maker_order.frozen_for(maker_order.remain - traded_base_amount);
+            let maker_unfrozen = maker_frozen - maker_frozen_after;
+            let maker_residual = maker_unfrozen - maker_traded;
+            // checked before anything of the trade is applied
+            let invariant = if traded_base_amount <= Decimal::zero() || traded_quote_amount <= Decimal::zero() {
+                Some(format!("trade amount {} at price {} is not positive", traded_base_amount, price))
+            } else if traded_base_amount > ask_order.remain || traded_base_amount > bid_order.remain {
+                Some(format!(
+                    "trade amount {} exceeds the remain of ask {} or bid {}",
+                    traded_base_amount, ask_order.remain, bid_order.remain
+                ))
+            } else if is_quote_limited && quote_sum + traded_quote_amount > *quote_limit {
+                Some(format!(
+                    "quote {} exceeds the quote limit {}",
+                    quote_sum + traded_quote_amount,
+                    quote_limit
+                ))
+            } else if maker_residual.is_sign_negative() && !maker_residual.is_zero() {
+                Some(format!(
+                    "maker frozen {} is below the traded {} and the frozen after {}",
+                    maker_frozen, maker_traded, maker_frozen_after
+                ))
+            } else {
+                None
+            };
+            if invariant.is_some() {
+                broken = invariant;
+                cancel_reason = Some(OrderCancelReason::InvariantViolated);
+                stop_reason = Some(MatchStopReason::InvariantViolated);
+                break;
             }
+            quote_sum += traded_quote_amount;
 
             // Step4: create the trade
-            let bid_fee = (traded_base_amount * bid_fee_rate).round_dp_with_strategy(self.base_prec, RoundingStrategy::ToZero);
-            let ask_fee = (traded_quote_amount * ask_fee_rate).round_dp_with_strategy(self.quote_prec, RoundingStrategy::ToZero);
-
-            let timestamp = current_timestamp();
-            ask_order.update_time = timestamp;
-            bid_order.update_time = timestamp;
+            let mut bid_fee = Self::trade_fee(
+                traded_base_amount,
+                bid_fee_rate,
+                self.base_prec,
+                self.fee_rounding,
+                self.min_base_fee,
+            );
+            let mut ask_fee = Self::trade_fee(
+                traded_quote_amount,
+                ask_fee_rate,
+                self.quote_prec,
+                self.fee_rounding,
+                self.min_quote_fee,
+            );
+            // a rebate is paid out of the fee account, so it is capped by the balance of the account.
+            // the fee account gets no rebate when trading itself
+            let (maker_fee, rebate_asset) = if maker_is_ask {
+                (&mut ask_fee, self.quote)
+            } else {
+                (&mut bid_fee, self.base)
+            };
+            if maker_fee.is_sign_negative() {
+                if ask_order.user == self.fee_account_id || bid_order.user == self.fee_account_id {
+                    *maker_fee = Decimal::zero();
+                } else {
+                    let pool = balance_manager.inner.get(self.fee_account_id, BalanceType::AVAILABLE, rebate_asset);
+                    *maker_fee = -min(-*maker_fee, pool);
+                }
+            }
+            let rebate = if maker_fee.is_sign_negative() {
+                -*maker_fee
+            } else {
+                Decimal::zero()
+            };
+            // the taker fee is paid in the discount asset if the taker holds enough of it for this trade,
+            // otherwise in the asset received. a fee rounded to zero in the discount asset is not discounted
+            let (taker_fee, taker_user) = if taker_is_ask {
+                (&mut ask_fee, ask_order.user)
+            } else {
+                (&mut bid_fee, bid_order.user)
+            };
+            let discount_fee = match fee_discount {
+                Some(discount) if taker_fee.gt(&Decimal::zero()) && taker_user != self.fee_account_id => {
+                    let prec = balance_manager.inner.asset_manager.asset_prec(&discount.asset);
+                    let amount = (*taker_fee * discount.price).round_dp_with_strategy(prec, RoundingStrategy::ToZero);
+                    let available = balance_manager.inner.get(taker_user, BalanceType::AVAILABLE, &discount.asset);
+                    if amount.is_zero() || amount.gt(&available) {
+                        None
+                    } else {
+                        Some(DiscountFee {
+                            asset: discount.asset.clone(),
+                            amount,
+                        })
+                    }
+                }
+                _ => None,
+            };
+            if discount_fee.is_some() {
+                *taker_fee = Decimal::zero();
+            }
 
-            // emit the trade
+            // Step4.5: settle the trade. the trade id is taken before the settlement and given back by `cancel_trade_id` if it fails
             let trade_id = sequencer.next_trade_id();
-            let trade = Trade {
-                id: trade_id,
-                timestamp: current_timestamp(),
-                market: self.name.to_string(),
-                base: self.base.into(),
-                quote: self.quote.into(),
-                price,
-                amount: traded_base_amount,
-                quote_amount: traded_quote_amount,
-                ask_user_id: ask_order.user,
-                ask_order_id: ask_order.id,
+            #[cfg(feature = "emit_state_diff")]
+            let state_before = Self::get_trade_state(ask_order, bid_order, balance_manager, self.base, self.quote);
+            let mut legs = vec![
+                BalanceUpdateParams {
+                    balance_type: BalanceType::AVAILABLE,
+                    business_type: BusinessType::Trade,
+                    user_id: bid_order.user,
+                    asset: self.base.to_string(),
+                    business: "trade".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: if bid_fee.is_sign_positive() {
+                        traded_base_amount - bid_fee
+                    } else {
+                        traded_base_amount
+                    },
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+                BalanceUpdateParams {
+                    balance_type: if maker_is_ask {
+                        BalanceType::FREEZE
+                    } else {
+                        BalanceType::AVAILABLE
+                    },
+                    business_type: BusinessType::Trade,
+                    user_id: ask_order.user,
+                    asset: self.base.to_string(),
+                    business: "trade".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: if maker_is_ask { -maker_unfrozen } else { -traded_base_amount },
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+                BalanceUpdateParams {
+                    balance_type: BalanceType::AVAILABLE,
+                    business_type: BusinessType::Trade,
+                    user_id: ask_order.user,
+                    asset: self.quote.to_string(),
+                    business: "trade".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: if ask_fee.is_sign_positive() {
+                        traded_quote_amount - ask_fee
+                    } else {
+                        traded_quote_amount
+                    },
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+                BalanceUpdateParams {
+                    balance_type: if maker_is_bid {
+                        BalanceType::FREEZE
+                    } else {
+                        BalanceType::AVAILABLE
+                    },
+                    business_type: BusinessType::Trade,
+                    user_id: bid_order.user,
+                    asset: self.quote.to_string(),
+                    business: "trade".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: if maker_is_bid { -maker_unfrozen } else { -traded_quote_amount },
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+            ];
+            // the residual taken from the frozen of the maker beyond the traded amount goes back to its available
+            if maker_residual.is_sign_positive() && !maker_residual.is_zero() {
+                let (user_id, asset) = if maker_is_ask {
+                    (ask_order.user, self.base)
+                } else {
+                    (bid_order.user, self.quote)
+                };
+                legs.push(BalanceUpdateParams {
+                    balance_type: BalanceType::AVAILABLE,
+                    business_type: BusinessType::Trade,
+                    user_id,
+                    asset: asset.to_string(),
+                    business: "frozen_residual".to_string(),
+                    business_id: trade_id,
+                    market_price: self.price,
+                    change: maker_residual,
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                });
+            }
+            // the fees go to the fee account, so the totals of the assets are conserved by trades
+            for (asset, fee) in [(self.base, bid_fee), (self.quote, ask_fee)] {
+                if fee.gt(&Decimal::zero()) {
+                    legs.push(BalanceUpdateParams {
+                        balance_type: BalanceType::AVAILABLE,
+                        business_type: BusinessType::Trade,
+                        user_id: self.fee_account_id,
+                        asset: asset.to_string(),
+                        business: "fee".to_string(),
+                        business_id: trade_id,
+                        market_price: self.price,
+                        change: fee,
+                        detail: serde_json::Value::default(),
+                        signature: vec![],
+                    });
+                }
+            }
+            if let Some(discount_fee) = &discount_fee {
+                for (user_id, change) in [(taker_user, -discount_fee.amount), (self.fee_account_id, discount_fee.amount)] {
+                    legs.push(BalanceUpdateParams {
+                        balance_type: BalanceType::AVAILABLE,
+                        business_type: BusinessType::Trade,
+                        user_id,
+                        asset: discount_fee.asset.clone(),
+                        business: "fee_discount".to_string(),
+                        business_id: trade_id,
+                        market_price: self.price,
+                        change,
+                        detail: serde_json::Value::default(),
+                        signature: vec![],
+                    });
+                }
+            }
+            if !rebate.is_zero() {
+                let maker_user = if maker_is_ask { ask_order.user } else { bid_order.user };
+                for (user_id, change) in [(self.fee_account_id, -rebate), (maker_user, rebate)] {
+                    legs.push(BalanceUpdateParams {
+                        balance_type: BalanceType::AVAILABLE,
+                        business_type: BusinessType::Trade,
+                        user_id,
+                        asset: rebate_asset.to_string(),
+                        business: "maker_rebate".to_string(),
+                        business_id: trade_id,
+                        market_price: self.price,
+                        change,
+                        detail: serde_json::Value::default(),
+                        signature: vec![],
+                    });
+                }
+            }
+            // nothing of the trade is applied if any leg fails, the rest of the taker is canceled
+            if let Err(e) = balance_update_controller.update_batch(balance_manager.inner, persistor, legs) {
+                log::error!(
+                    "settle trade {} of orders {} {} failed: {}",
+                    trade_id,
+                    ask_order.id,
+                    bid_order.id,
+                    e
+                );
+                cancel_reason = Some(OrderCancelReason::SettlementFailed);
+                stop_reason = Some(MatchStopReason::SettlementFailed);
+                sequencer.cancel_trade_id(trade_id);
+                break;
+            }
+
+            let timestamp = self.clock.now();
+            ask_order.update_time = timestamp;
+            bid_order.update_time = timestamp;
+            self.trade_stats.record(timestamp, price, traded_base_amount, traded_quote_amount);
+            for kline in self
+                .kline_aggregator
+                .record(timestamp, price, traded_base_amount, traded_quote_amount)
+            {
+                persistor.put_kline(&kline);
+            }
+
+            // emit the trade
+            let trade = Trade {
+                id: trade_id,
+                timestamp,
+                market: self.name.to_string(),
+                base: self.base.into(),
+                quote: self.quote.into(),
+                price,
+                amount: traded_base_amount,
+                quote_amount: traded_quote_amount,
+                ask_user_id: ask_order.user,
+                ask_order_id: ask_order.id,
                 ask_role: if taker_is_ask { MarketRole::TAKER } else { MarketRole::MAKER },
                 ask_fee,
                 bid_user_id: bid_order.user,
                 bid_order_id: bid_order.id,
                 bid_role: if taker_is_ask { MarketRole::MAKER } else { MarketRole::TAKER },
                 bid_fee,
+                discount_fee: discount_fee.clone(),
 
                 ask_order: None,
                 bid_order: None,
@@ -410,9 +1570,8 @@ impl Market {
                 #[cfg(feature = "emit_state_diff")]
                 state_after: Default::default(),
             };
-            #[cfg(feature = "emit_state_diff")]
-            let state_before = Self::get_trade_state(ask_order, bid_order, balance_manager, self.base, self.quote);
             self.trade_count += 1;
+            metrics::trade_executed(self.name);
             if self.disable_self_trade {
                 debug_assert_ne!(trade.ask_user_id, trade.bid_user_id);
             }
@@ -423,9 +1582,7 @@ impl Market {
             let bid_order_is_new = bid_order.finished_base.is_zero();
             let bid_order_before = *bid_order;
             ask_order.remain -= traded_base_amount;
-            debug_assert!(ask_order.remain.is_sign_positive());
             bid_order.remain -= traded_base_amount;
-            debug_assert!(bid_order.remain.is_sign_positive());
             ask_order.finished_base += traded_base_amount;
             bid_order.finished_base += traded_base_amount;
             ask_order.finished_quote += traded_quote_amount;
@@ -433,95 +1590,6 @@ impl Market {
             ask_order.finished_fee += ask_fee;
             bid_order.finished_fee += bid_fee;
 
-            // Step6: update balances
-            balance_update_controller
-                .update_user_balance(
-                    balance_manager.inner,
-                    persistor,
-                    BalanceUpdateParams {
-                        balance_type: BalanceType::AVAILABLE,
-                        business_type: BusinessType::Trade,
-                        user_id: bid_order.user,
-                        asset: self.base.to_string(),
-                        business: "trade".to_string(),
-                        business_id: trade_id,
-                        market_price: self.price,
-                        change: if bid_fee.is_sign_positive() {
-                            traded_base_amount - bid_fee
-                        } else {
-                            traded_base_amount
-                        },
-                        detail: serde_json::Value::default(),
-                        signature: vec![],
-                    },
-                )
-                .unwrap();
-            balance_update_controller
-                .update_user_balance(
-                    balance_manager.inner,
-                    persistor,
-                    BalanceUpdateParams {
-                        balance_type: if maker_is_ask {
-                            BalanceType::FREEZE
-                        } else {
-                            BalanceType::AVAILABLE
-                        },
-                        business_type: BusinessType::Trade,
-                        user_id: ask_order.user,
-                        asset: self.base.to_string(),
-                        business: "trade".to_string(),
-                        business_id: trade_id,
-                        market_price: self.price,
-                        change: -traded_base_amount,
-                        detail: serde_json::Value::default(),
-                        signature: vec![],
-                    },
-                )
-                .unwrap();
-            balance_update_controller
-                .update_user_balance(
-                    balance_manager.inner,
-                    persistor,
-                    BalanceUpdateParams {
-                        balance_type: BalanceType::AVAILABLE,
-                        business_type: BusinessType::Trade,
-                        user_id: ask_order.user,
-                        asset: self.quote.to_string(),
-                        business: "trade".to_string(),
-                        business_id: trade_id,
-                        market_price: self.price,
-                        change: if ask_fee.is_sign_positive() {
-                            traded_quote_amount - ask_fee
-                        } else {
-                            traded_quote_amount
-                        },
-                        detail: serde_json::Value::default(),
-                        signature: vec![],
-                    },
-                )
-                .unwrap();
-            balance_update_controller
-                .update_user_balance(
-                    balance_manager.inner,
-                    persistor,
-                    BalanceUpdateParams {
-                        balance_type: if maker_is_bid {
-                            BalanceType::FREEZE
-                        } else {
-                            BalanceType::AVAILABLE
-                        },
-                        business_type: BusinessType::Trade,
-                        user_id: bid_order.user,
-                        asset: self.quote.to_string(),
-                        business: "trade".to_string(),
-                        business_id: trade_id,
-                        market_price: self.price,
-                        change: -traded_quote_amount,
-                        detail: serde_json::Value::default(),
-                        signature: vec![],
-                    },
-                )
-                .unwrap();
             #[cfg(feature = "emit_state_diff")]
             let state_after = Self::get_trade_state(ask_order, bid_order, balance_manager, self.base, self.quote);
 
@@ -537,58 +1605,192 @@ impl Market {
                 bid_order: if bid_order_is_new { Some(bid_order_before) } else { None },
                 ..trade
             };
-            persistor.put_trade(&trade);
+            // shared by the persistors, so the trade is neither cloned nor serialized for each of them
+            let shared = SharedTrade::new(trade);
+            persistor.put_trade_shared(&shared);
+            let trade = shared.trade();
+            if self.trade_audit.is_enabled() {
+                let state_after = Self::get_trade_state(ask_order, bid_order, balance_manager, self.base, self.quote);
+                let (taker_order, maker_order) = if taker_is_ask {
+                    (&*ask_order, &*bid_order)
+                } else {
+                    (&*bid_order, &*ask_order)
+                };
+                self.trade_audit.fill(self.name, trade, taker_order, maker_order, state_after);
+            }
+            self.trade_history.push_front(TradeSummary::from(trade));
+            self.trade_history.truncate(self.trade_history_size);
+            // one fee record for each side, the taker fee may have been paid in the discount asset
+            for (user_id, order_id, role, asset, amount, rate) in [
+                (
+                    trade.ask_user_id,
+                    trade.ask_order_id,
+                    trade.ask_role,
+                    self.quote,
+                    trade.ask_fee,
+                    ask_fee_rate,
+                ),
+                (
+                    trade.bid_user_id,
+                    trade.bid_order_id,
+                    trade.bid_role,
+                    self.base,
+                    trade.bid_fee,
+                    bid_fee_rate,
+                ),
+            ] {
+                let (asset, amount) = match &trade.discount_fee {
+                    Some(discount_fee) if role == MarketRole::TAKER => (discount_fee.asset.clone(), discount_fee.amount),
+                    _ => (asset.to_string(), amount),
+                };
+                persistor.put_fee(&TradeFeeRecord {
+                    trade_id: trade.id,
+                    timestamp: trade.timestamp,
+                    market: trade.market.clone(),
+                    user_id,
+                    order_id,
+                    role,
+                    asset,
+                    amount,
+                    rate,
+                });
+            }
             //}
-            maker.frozen -= if maker_is_bid { traded_quote_amount } else { traded_base_amount };
+            maker.frozen = maker_frozen_after;
+            let maker_levels = if maker_is_bid { &mut self.bid_levels } else { &mut self.ask_levels };
+            Self::level_sub(maker_levels, maker.price, traded_base_amount, 0);
+            let maker_totals = if maker_is_bid { &mut self.bid_totals } else { &mut self.ask_totals };
+            maker_totals.amount -= traded_base_amount;
+            maker_totals.frozen -= maker_unfrozen;
+            maker_totals.notional -= traded_base_amount * maker.price;
+            Self::exposure_add(&mut self.user_exposures, maker.user, maker.side, -maker_unfrozen, 0);
 
-            let maker_finished = maker.remain.is_zero();
+            let maker_finished =
+                maker.remain.is_zero() || self.finish_dust_orders && Self::is_dust(self.amount_prec, &self.min_amount, &maker.remain);
             if maker_finished {
                 finished_orders.push(*maker);
             } else {
                 // When maker_finished, `order_finish` will send message.
                 // So we don't need to send the finish message here.
                 persistor.put_order(&maker, OrderEventType::UPDATE);
+                partially_filled_price = Some(maker.price);
             }
 
             // Save this trade price to market.
+            if price != self.price {
+                persistor.put_market_event(MarketEvent {
+                    timestamp,
+                    market: self.name.to_string(),
+                    kind: MarketEventKind::PriceUpdated { price, trade_id },
+                });
+            }
             self.price = price;
         }
 
+        let stop_reason = stop_reason.unwrap_or(if taker.remain.is_zero() {
+            MatchStopReason::Filled
+        } else if budget_short {
+            MatchStopReason::QuoteLimit
+        } else {
+            MatchStopReason::BookExhausted
+        });
+        taker.fill_outcome = Some(stop_reason.into());
+        if self.trade_audit.is_enabled() {
+            self.trade_audit
+                .stop(self.name, &taker, stop_reason, self.trade_count - trade_count_before);
+        }
+
+        let mut error = broken.map(|invariant| self.invariant_violated(persistor, taker.id, invariant));
         for item in finished_orders.iter() {
-            self.order_finish(&mut *balance_manager, persistor, item);
+            let finish = OrderFinish::system(Self::fill_reason(item));
+            if let Err(e) = self.order_finish(&mut *balance_manager, persistor, item, finish) {
+                error.get_or_insert(e);
+            }
+        }
+        if let Some(price) = partially_filled_price {
+            self.put_depth_update(persistor, if maker_is_ask { OrderSide::ASK } else { OrderSide::BID }, price);
+        }
+
+        if let Some(price) = post_only_adjusted_price {
+            taker.fill_outcome = Some(FillOutcome::Repriced { from: taker.price });
+            taker.price = price;
+            taker.update_time = self.clock.now();
+            // a new order is created at the adjusted price, only the one put before is updated
+            if !put_pending {
+                persistor.put_order(&taker, OrderEventType::UPDATE);
+            }
+        }
+
+        if put_pending && cancel_reason.is_none() {
+            persistor.put_order(&taker, OrderEventType::PUT);
         }
 
-        if need_cancel {
+        if let Some(reason) = cancel_reason {
             // Now both self trade orders and immediately triggered post_only
             // limit orders will be cancelled here.
-            // TODO: use CANCEL event here
-            persistor.put_order(&taker, OrderEventType::FINISH);
+            persistor.put_finished_order(&taker, OrderFinish::system(reason.into()));
         } else if taker.type_ == OrderType::MARKET {
-            // market order can either filled or not
-            // if it is filled, `FINISH` is ok
-            // if it is not filled, `CANCELED` may be a better choice?
-            persistor.put_order(&taker, OrderEventType::FINISH);
+            // a market order never rests. it is FINISH when filled or when its quote limit is spent, otherwise the rest
+            // is canceled as unfilled and the finished base and quote of the order tell how much of it was traded
+            let reason = if taker.remain.is_zero() || taker.fill_outcome == Some(FillOutcome::BudgetExhausted) {
+                FinishReason::Filled
+            } else {
+                FinishReason::Unmatched
+            };
+            persistor.put_finished_order(&taker, OrderFinish::system(reason));
         } else {
             // now the order type is limit
-            if taker.remain.is_zero() {
-                persistor.put_order(&taker, OrderEventType::FINISH);
+            if taker.remain.is_zero() || self.finish_dust_orders && Self::is_dust(self.amount_prec, &self.min_amount, &taker.remain) {
+                persistor.put_finished_order(&taker, OrderFinish::system(Self::fill_reason(&taker)));
             } else {
                 // `insert_order` will update the order info
+                let fill_outcome = taker.fill_outcome;
                 taker = self.insert_order_into_orderbook(taker);
-                self.frozen_balance(balance_manager, &taker);
+                taker.fill_outcome = fill_outcome;
+                let asset = if taker.is_ask() { self.base } else { self.quote };
+                let available = balance_manager.balance_get(taker.user, BalanceType::AVAILABLE, asset);
+                let required = taker.frozen.round_dp(balance_manager.asset_prec(asset));
+                if available < required {
+                    // the balance checked before the matching is taken by now. the order is finished with nothing
+                    // frozen rather than left resting unfunded, the trades made are kept
+                    let unfunded = Order {
+                        frozen: Decimal::zero(),
+                        ..taker
+                    };
+                    let finish = OrderFinish::system(FinishReason::BalanceNotEnough);
+                    if let Err(e) = self.order_finish(balance_manager, persistor, &unfunded, finish) {
+                        error.get_or_insert(e);
+                    }
+                    error.get_or_insert(MarketError::BalanceNotEnough {
+                        asset: asset.to_string(),
+                        required,
+                        available,
+                    });
+                } else if let Err(e) = self.frozen_balance(balance_manager, &taker) {
+                    // never rests without its balance frozen
+                    self.remove_order_from_orderbook(&taker);
+                    persistor.put_finished_order(&taker, OrderFinish::system(FinishReason::InvariantViolated));
+                    let e = self.invariant_violated(persistor, taker.id, e.to_string());
+                    error.get_or_insert(e);
+                } else {
+                    self.put_depth_update(persistor, taker.side, taker.price);
+                }
             }
         }
 
+        if let Some(levels) = self.depth_batch.take() {
+            self.put_depth_updates(persistor, &levels);
+        }
         log::debug!("execute_order done {:?}", taker);
-        taker
+        match error {
+            Some(e) => Err(e),
+            None => Ok(taker),
+        }
     }
 
     pub fn insert_order_into_orderbook(&mut self, mut order: Order) -> Order {
-        if order.side == OrderSide::ASK {
-            order.frozen = order.remain;
-        } else {
-            order.frozen = order.remain * order.price;
-        }
+        order.frozen = order.frozen_for(order.remain);
+        order.fill_outcome = None;
         debug_assert_eq!(order.type_, OrderType::LIMIT);
         debug_assert!(!self.orders.contains_key(&order.id));
         // log::debug!("order insert {}", &order.id);
@@ -598,6 +1800,12 @@ impl Market {
         let user_map = self.users.entry(order.user).or_insert_with(BTreeMap::new);
         debug_assert!(!user_map.contains_key(&order.id));
         user_map.insert(order.id, order_rc.clone());
+        self.user_orders.insert(order.user, self.name, order.id);
+        if let Some(client_order_id) = order.client_order_id {
+            let client_map = self.client_ids.entry(order.user).or_insert_with(BTreeMap::new);
+            debug_assert!(!client_map.contains_key(&client_order_id));
+            client_map.insert(client_order_id, order.id);
+        }
         if order.side == OrderSide::ASK {
             let key = order.get_ask_key();
             debug_assert!(!self.asks.contains_key(&key));
@@ -607,28 +1815,169 @@ impl Market {
             debug_assert!(!self.bids.contains_key(&key));
             self.bids.insert(key, order_rc.clone());
         }
+        let level = self.levels_mut(order.side).entry(order.price).or_insert((Decimal::zero(), 0));
+        level.0 += order.remain;
+        level.1 += 1;
+        let totals = self.totals_mut(order.side);
+        totals.amount += order.remain;
+        totals.frozen += order.frozen;
+        totals.notional += order.remain * order.price;
+        Self::exposure_add(&mut self.user_exposures, order.user, order.side, order.frozen, 1);
+        self.book_size_changed(order.side);
         order_rc.deep()
     }
 
-    fn order_finish(&mut self, balance_manager: &mut BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector, order: &Order) {
-        if order.side == OrderSide::ASK {
+    // the order leaves the book even if its balance cannot be unfrozen, the violation is returned then
+    fn order_finish(
+        &mut self,
+        balance_manager: &mut BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        order: &Order,
+        finish: OrderFinish,
+    ) -> Result<(), MarketError> {
+        self.remove_order_from_orderbook(order);
+        let unfrozen = self.unfrozen_balance(balance_manager, order);
+        persistor.put_finished_order(order, finish);
+        self.put_depth_update(persistor, order.side, order.price);
+        unfrozen.map_err(|e| self.invariant_violated(persistor, order.id, e.to_string()))
+    }
+
+    // a finished order is either filled or left with the dust
+    fn fill_reason(order: &Order) -> FinishReason {
+        if order.remain.is_zero() {
+            FinishReason::Filled
+        } else {
+            FinishReason::Dust
+        }
+    }
+
+    fn levels_mut(&mut self, side: OrderSide) -> &mut BTreeMap<Decimal, (Decimal, usize)> {
+        if side == OrderSide::ASK {
+            &mut self.ask_levels
+        } else {
+            &mut self.bid_levels
+        }
+    }
+    fn totals_mut(&mut self, side: OrderSide) -> &mut BookTotals {
+        if side == OrderSide::ASK {
+            &mut self.ask_totals
+        } else {
+            &mut self.bid_totals
+        }
+    }
+    fn book_size_changed(&self, side: OrderSide) {
+        let orders = if side == OrderSide::ASK { self.asks.len() } else { self.bids.len() };
+        metrics::book_size(self.name, side, orders);
+    }
+    // `frozen` and `order_count` are the changes. only the users with resting orders are kept
+    fn exposure_add(exposures: &mut BTreeMap<u32, UserExposure>, user_id: u32, side: OrderSide, frozen: Decimal, order_count: i64) {
+        let exposure = exposures.entry(user_id).or_default();
+        if side == OrderSide::ASK {
+            exposure.frozen_base += frozen;
+        } else {
+            exposure.frozen_quote += frozen;
+        }
+        exposure.order_count = (exposure.order_count as i64 + order_count) as usize;
+        if exposure.order_count == 0 {
+            debug_assert!(exposure.frozen_base.is_zero() && exposure.frozen_quote.is_zero());
+            exposures.remove(&user_id);
+        }
+    }
+    // take the amount and the orders off a level, the level is dropped with its last order
+    fn level_sub(levels: &mut BTreeMap<Decimal, (Decimal, usize)>, price: Decimal, amount: Decimal, order_count: usize) {
+        let level = levels.get_mut(&price).unwrap();
+        level.0 -= amount;
+        level.1 -= order_count;
+        if level.1 == 0 {
+            debug_assert!(level.0.is_zero());
+            levels.remove(&price);
+        }
+    }
+
+    // the total remain of the resting orders at a price
+    fn level_amount(&self, side: OrderSide, price: Decimal) -> Decimal {
+        let levels = if side == OrderSide::ASK {
+            &self.ask_levels
+        } else {
+            &self.bid_levels
+        };
+        levels.get(&price).map(|level| level.0).unwrap_or_else(Decimal::zero)
+    }
+
+    // emit the new amount of a price level after the book is changed, or after the matching when it is batched
+    fn put_depth_update(&mut self, persistor: &mut impl PersistExector, side: OrderSide, price: Decimal) {
+        if let Some(batch) = &mut self.depth_batch {
+            if !batch.contains(&(side, price)) {
+                batch.push((side, price));
+            }
+            return;
+        }
+        self.put_depth_updates(persistor, &[(side, price)]);
+    }
+
+    // the checksum is computed once, it is only on the last update as the book is verified after all of them
+    fn put_depth_updates(&mut self, persistor: &mut impl PersistExector, levels: &[(OrderSide, Decimal)]) {
+        if levels.is_empty() {
+            return;
+        }
+        let checksum = self.orderbook_checksum();
+        for (n, (side, price)) in levels.iter().enumerate() {
+            self.depth_seq += 1;
+            persistor.put_depth_update(&DepthUpdate {
+                market: self.name.to_string(),
+                seq: self.depth_seq,
+                side: *side,
+                price: *price,
+                new_amount: self.level_amount(*side, *price),
+                checksum: if n + 1 == levels.len() { Some(checksum) } else { None },
+            });
+        }
+    }
+
+    pub fn remove_order_from_orderbook(&mut self, order: &Order) {
+        // the remain in the book is the one counted in the level
+        let removed = if order.side == OrderSide::ASK {
             let key = &order.get_ask_key();
             debug_assert!(self.asks.contains_key(key));
-            self.asks.remove(key);
+            self.asks.remove(key)
         } else {
             let key = &order.get_bid_key();
             debug_assert!(self.bids.contains_key(key));
-            self.bids.remove(key);
+            self.bids.remove(key)
+        };
+        if let Some(removed) = removed {
+            let (remain, frozen) = {
+                let removed = removed.borrow();
+                (removed.remain, removed.frozen)
+            };
+            Self::level_sub(self.levels_mut(order.side), order.price, remain, 1);
+            let totals = self.totals_mut(order.side);
+            totals.amount -= remain;
+            totals.frozen -= frozen;
+            totals.notional -= remain * order.price;
+            Self::exposure_add(&mut self.user_exposures, order.user, order.side, -frozen, -1);
+            self.book_size_changed(order.side);
         }
-        self.unfrozen_balance(balance_manager, order);
         debug_assert!(self.orders.contains_key(&order.id));
         // log::debug!("order finish {}", &order.id);
         self.orders.remove(&order.id);
         let user_map = self.users.get_mut(&order.user).unwrap();
         debug_assert!(user_map.contains_key(&order.id));
         user_map.remove(&order.id);
-
-        persistor.put_order(order, OrderEventType::FINISH);
+        // only the users with open orders are kept, see `MarketStatus::user_count`
+        if user_map.is_empty() {
+            self.users.remove(&order.user);
+        }
+        self.user_orders.remove(order.user, self.name, order.id);
+        if let Some(client_order_id) = order.client_order_id {
+            let client_map = self.client_ids.get_mut(&order.user).unwrap();
+            debug_assert_eq!(client_map.get(&client_order_id), Some(&order.id));
+            client_map.remove(&client_order_id);
+            // drop the empty map so the index does not grow with inactive users
+            if client_map.is_empty() {
+                self.client_ids.remove(&order.user);
+            }
+        }
     }
 
     // for debugging
@@ -685,31 +2034,143 @@ impl Market {
             ],
         }
     }
-    pub fn cancel(&mut self, mut balance_manager: BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector, order_id: u64) -> Order {
-        let order = self.orders.get(&order_id).unwrap();
-        let order_struct = order.deep();
-        self.order_finish(&mut balance_manager, persistor, &order_struct);
-        order_struct
+    pub fn cancel(
+        &mut self,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        order_id: u64,
+        user_id: u32,
+    ) -> Result<Order, MarketError> {
+        self.check_cancel()?;
+        let order = match self.orders.get(&order_id) {
+            Some(order) => order.deep(),
+            // the order may have been finished already
+            None => return Err(MarketError::OrderNotFound(order_id)),
+        };
+        if order.user != user_id {
+            return Err(MarketError::NotOrderOwner { user_id, order_id });
+        }
+        self.order_finish(
+            &mut balance_manager,
+            persistor,
+            &order,
+            OrderFinish::new(FinishReason::Canceled, OrderActor::User),
+        )?;
+        Ok(order)
     }
     pub fn cancel_all_for_user(
         &mut self,
         mut balance_manager: BalanceManagerWrapper<'_>,
         persistor: &mut impl PersistExector,
         user_id: u32,
-    ) -> usize {
-        // TODO: can we mutate while iterate?
-        let order_ids: Vec<u64> = self.users.get(&user_id).unwrap_or(&BTreeMap::new()).keys().copied().collect();
-        let total = order_ids.len();
-        for order_id in order_ids {
-            let order = self.orders.get(&order_id).unwrap();
-            let order_struct = order.deep();
-            self.order_finish(&mut balance_manager, persistor, &order_struct);
+    ) -> Result<usize, MarketError> {
+        self.check_cancel()?;
+        // take the orders one by one from the user's map rather than collecting the ids first,
+        // so an entry removed in between is never looked up
+        let mut total = 0;
+        // every order leaves the book, the first violation is returned after
+        let mut violation = None;
+        while let Some(order) = self.users.get(&user_id).and_then(|m| m.values().next()).map(OrderRc::deep) {
+            let finished = self.order_finish(
+                &mut balance_manager,
+                persistor,
+                &order,
+                OrderFinish::new(FinishReason::Canceled, OrderActor::User),
+            );
+            if let Err(e) = finished {
+                violation.get_or_insert(e);
+            }
+            total += 1;
+        }
+        match violation {
+            Some(e) => Err(e),
+            None => Ok(total),
+        }
+    }
+    // cancel the orders of a user on `side` (both sides if none), and with `price.cmp(bound) == ordering`
+    // if `price_bound` is given, e.g. `(x, Ordering::Less)` cancels the orders priced under x
+    pub fn cancel_for_user_filtered(
+        &mut self,
+        mut balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        user_id: u32,
+        side: Option<OrderSide>,
+        price_bound: Option<(Decimal, Ordering)>,
+    ) -> Result<Vec<Order>, MarketError> {
+        self.check_cancel()?;
+        // copy the matched orders out in one pass, finishing them mutates the user's map
+        let orders: Vec<Order> = match self.users.get(&user_id) {
+            Some(user_orders) => user_orders
+                .values()
+                .map(OrderRc::deep)
+                .filter(|order| side.map_or(true, |side| order.side == side))
+                .filter(|order| price_bound.map_or(true, |(bound, ordering)| order.price.cmp(&bound) == ordering))
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut violation = None;
+        for order in &orders {
+            let finished = self.order_finish(
+                &mut balance_manager,
+                persistor,
+                order,
+                OrderFinish::new(FinishReason::Canceled, OrderActor::User),
+            );
+            if let Err(e) = finished {
+                violation.get_or_insert(e);
+            }
+        }
+        match violation {
+            Some(e) => Err(e),
+            None => Ok(orders),
+        }
+    }
+    // finish every resting order, unfreezing the balances. unlike `reset`, the events are emitted.
+    // used to close a market, so the state of the market is not checked
+    pub fn drain_all_orders(&mut self, mut balance_manager: BalanceManagerWrapper<'_>, persistor: &mut impl PersistExector) -> usize {
+        let mut total = 0;
+        while let Some(order) = self.orders.values().next().map(OrderRc::deep) {
+            // the order leaves the book even if a violation is found, the market is closed anyway
+            let _ = self.order_finish(
+                &mut balance_manager,
+                persistor,
+                &order,
+                OrderFinish::new(FinishReason::MarketClosed, OrderActor::Admin),
+            );
+            total += 1;
         }
         total
     }
     pub fn get(&self, order_id: u64) -> Option<Order> {
         self.orders.get(&order_id).map(OrderRc::deep)
     }
+    pub fn get_by_client_id(&self, user_id: u32, client_order_id: u64) -> Option<Order> {
+        let order_id = self.client_ids.get(&user_id)?.get(&client_order_id)?;
+        self.get(*order_id)
+    }
+    pub fn cancel_by_client_id(
+        &mut self,
+        balance_manager: BalanceManagerWrapper<'_>,
+        persistor: &mut impl PersistExector,
+        user_id: u32,
+        client_order_id: u64,
+    ) -> Result<Order, MarketError> {
+        let order_id = match self.client_ids.get(&user_id).and_then(|m| m.get(&client_order_id)) {
+            Some(order_id) => *order_id,
+            None => return Err(MarketError::ClientOrderNotFound(client_order_id)),
+        };
+        self.cancel(balance_manager, persistor, order_id, user_id)
+    }
+    // the resting orders of the user in this market, for the risk monitoring
+    pub fn user_exposure(&self, user_id: u32) -> UserExposure {
+        self.user_exposures.get(&user_id).copied().unwrap_or_default()
+    }
+    pub fn open_interest(&self) -> OpenInterest {
+        OpenInterest {
+            ask_notional: self.ask_totals.notional,
+            bid_notional: self.bid_totals.notional,
+        }
+    }
     pub fn get_order_num_of_user(&self, user_id: u32) -> usize {
         self.users.get(&user_id).map(|m| m.len()).unwrap_or(0)
     }
@@ -721,73 +2182,389 @@ impl Market {
             .map(OrderRc::deep)
             .collect()
     }
+    // a page of the orders of a user. the filters are checked on the orders in place,
+    // only the orders of the page are copied
+    pub fn query_orders(&self, user_id: u32, query: &OrderQuery) -> OrderPage {
+        let user_orders = match self.users.get(&user_id) {
+            Some(user_orders) => user_orders,
+            None => return OrderPage::default(),
+        };
+        let mut matched = user_orders
+            .values()
+            .filter_map(|order_rc| {
+                let order = order_rc.borrow();
+                let matched = query.side.map_or(true, |side| order.side == side)
+                    && query
+                        .price_range
+                        .map_or(true, |(low, high)| low <= order.price && order.price <= high);
+                matched.then(|| (OrderQueryCursor::of(&order), order_rc))
+            })
+            .collect::<Vec<_>>();
+        let total = matched.len();
+        let compare = |a: &OrderQueryCursor, b: &OrderQueryCursor| {
+            let ordering = a.compare(b, query.sort_by);
+            if query.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        };
+        matched.sort_by(|(a, _), (b, _)| compare(a, b));
+        let start = match &query.after {
+            Some(after) => matched.partition_point(|(position, _)| compare(position, after) != Ordering::Greater),
+            None => 0,
+        } + query.offset;
+        let limit = if query.limit == 0 { usize::MAX } else { query.limit };
+        let orders = matched
+            .iter()
+            .skip(start)
+            .take(limit)
+            .map(|(_, order_rc)| order_rc.deep())
+            .collect::<Vec<_>>();
+        let next = if start + orders.len() < total {
+            orders.last().map(OrderQueryCursor::of)
+        } else {
+            None
+        };
+        OrderPage { orders, total, next }
+    }
     pub fn print(&self) {
         log::info!("orders:");
         for (k, v) in self.orders.iter() {
             log::info!("{}, {:?}", k, v.borrow())
         }
     }
+    fn check_open(&self) -> Result<(), MarketError> {
+        match self.state {
+            MarketState::Open => Ok(()),
+            _ => Err(MarketError::MarketNotOpen),
+        }
+    }
+    fn check_cancel(&self) -> Result<(), MarketError> {
+        match self.state {
+            MarketState::Halted if !self.cancel_when_halted => Err(MarketError::MarketHalted),
+            _ => Ok(()),
+        }
+    }
+    // resting orders are kept whatever the state is. the change is emitted as a market event
+    pub fn set_state(&mut self, persistor: &mut impl PersistExector, state: MarketState, reason: String) {
+        if state == self.state {
+            return;
+        }
+        log::info!("market {} state {:?} -> {:?}: {}", self.name, self.state, state, reason);
+        self.state = state;
+        let kind = match state {
+            MarketState::Open => MarketEventKind::Resumed { reason },
+            _ => MarketEventKind::Halted { state, reason },
+        };
+        persistor.put_market_event(self.event(kind));
+    }
+    pub fn event(&self, kind: MarketEventKind) -> MarketEvent {
+        MarketEvent {
+            timestamp: self.clock.now(),
+            market: self.name.to_string(),
+            kind,
+        }
+    }
+    pub fn created_event(&self) -> MarketEvent {
+        self.event(MarketEventKind::Created {
+            base: self.base.to_string(),
+            quote: self.quote.to_string(),
+            amount_prec: self.amount_prec,
+            price_prec: self.price_prec,
+            fee_prec: self.fee_prec,
+        })
+    }
     pub fn status(&self) -> MarketStatus {
         MarketStatus {
             name: self.name.to_string(),
+            state: self.state,
             ask_count: self.asks.len(),
-            ask_amount: self.asks.values().map(|item| item.borrow().remain).sum(),
+            ask_amount: self.ask_totals.amount,
+            ask_frozen: self.ask_totals.frozen,
             bid_count: self.bids.len(),
-            bid_amount: self.bids.values().map(|item| item.borrow().remain).sum(),
+            bid_amount: self.bid_totals.amount,
+            bid_frozen: self.bid_totals.frozen,
+            best_ask: self.ask_levels.keys().next().copied(),
+            best_bid: self.bid_levels.keys().next_back().copied(),
+            last_price: if self.price.is_zero() { None } else { Some(self.price) },
+            user_count: self.users.len(),
             trade_count: self.trade_count,
+            stats: self.stats(),
+        }
+    }
+    pub fn stats(&self) -> MarketStatsInfo {
+        self.trade_stats.info(self.clock.now())
+    }
+    pub fn klines(&self, interval: config::KlineInterval, limit: usize) -> Vec<Kline> {
+        self.kline_aggregator.klines(interval, limit)
+    }
+    // newest first
+    pub fn recent_trades(&self, limit: usize) -> Vec<TradeSummary> {
+        self.trade_history.iter().take(limit).cloned().collect()
+    }
+    // the top of the book, the amounts are the totals of the best levels
+    pub fn ticker(&self) -> MarketTicker {
+        let best_ask = self.asks.values().next().map(|order| order.borrow().price);
+        let best_bid = self.bids.values().next().map(|order| order.borrow().price);
+        let (mid_price, spread) = match (best_ask, best_bid) {
+            (Some(ask), Some(bid)) => (Some((ask + bid) / Decimal::from(2)), Some(ask - bid)),
+            _ => (None, None),
+        };
+        MarketTicker {
+            name: self.name.to_string(),
+            best_ask,
+            best_ask_amount: best_ask.map(|price| self.level_amount(OrderSide::ASK, price)),
+            best_bid,
+            best_bid_amount: best_bid.map(|price| self.level_amount(OrderSide::BID, price)),
+            // the price is zero before the first trade
+            last_price: if self.price.is_zero() { None } else { Some(self.price) },
+            mid_price,
+            spread,
         }
     }
-    pub fn depth(&self, limit: usize, interval: &Decimal) -> MarketDepth {
-        if interval.is_zero() {
-            let id_fn = |order: &Order| -> Decimal { order.price };
+    // a non zero interval merges the levels into buckets of the interval, it cannot be smaller than a price tick.
+    // at most `limit` levels or buckets are built for each side
+    pub fn depth(&self, limit: usize, interval: &Decimal) -> Result<MarketDepth, MarketError> {
+        if !interval.is_zero() && (interval.is_sign_negative() || interval.lt(&Decimal::new(1, self.price_prec))) {
+            return Err(MarketError::InvalidDepthInterval);
+        }
+        Ok(if interval.is_zero() {
+            let id_fn = |price: Decimal| -> Decimal { price };
             MarketDepth {
-                asks: Self::group_ordebook_by_fn(&self.asks, limit, id_fn),
-                bids: Self::group_ordebook_by_fn(&self.bids, limit, id_fn),
+                asks: Self::group_levels_by_fn(self.ask_levels.iter(), limit, id_fn),
+                bids: Self::group_levels_by_fn(self.bid_levels.iter().rev(), limit, id_fn),
+                seq: self.depth_seq,
+                checksum: self.orderbook_checksum(),
             }
         } else {
-            let ask_group_fn = |order: &Order| -> Decimal { (order.price / interval).ceil() * interval };
-            let bid_group_fn = |order: &Order| -> Decimal { (order.price / interval).floor() * interval };
+            let ask_group_fn = |price: Decimal| -> Decimal { (price / interval).ceil() * interval };
+            let bid_group_fn = |price: Decimal| -> Decimal { (price / interval).floor() * interval };
             MarketDepth {
-                asks: Self::group_ordebook_by_fn(&self.asks, limit, ask_group_fn),
-                bids: Self::group_ordebook_by_fn(&self.bids, limit, bid_group_fn),
+                asks: Self::group_levels_by_fn(self.ask_levels.iter(), limit, ask_group_fn),
+                bids: Self::group_levels_by_fn(self.bid_levels.iter().rev(), limit, bid_group_fn),
+                seq: self.depth_seq,
+                checksum: self.orderbook_checksum(),
             }
-        }
+        })
+    }
+
+    // the top `checksum_levels` levels of the asks, best first, then the ones of the bids.
+    // each level is `price:amount` with the trailing zeros stripped, e.g. `1.5:2`,
+    // the levels are joined by `,` and the two sides by `;`, an empty book is `;`
+    fn checksum_payload(&self) -> String {
+        let format_side = |levels: Vec<PriceInfo>| {
+            levels
+                .iter()
+                .map(|level| format!("{}:{}", level.price.normalize(), level.amount.normalize()))
+                .join(",")
+        };
+        let id_fn = |price: Decimal| -> Decimal { price };
+        let asks = format_side(Self::group_levels_by_fn(self.ask_levels.iter(), self.checksum_levels, id_fn));
+        let bids = format_side(Self::group_levels_by_fn(self.bid_levels.iter().rev(), self.checksum_levels, id_fn));
+        format!("{};{}", asks, bids)
+    }
+    // the CRC32 of the canonical top levels, for the clients to verify the book they rebuilt
+    pub fn orderbook_checksum(&self) -> u32 {
+        crc32fast::hash(self.checksum_payload().as_bytes())
     }
 
-    fn group_ordebook_by_fn<K, F>(orderbook: &BTreeMap<K, OrderRc>, limit: usize, f: F) -> Vec<PriceInfo>
+    // merge the levels, best first, into at most `limit` groups and stop there.
+    // the cumulative quote is summed with the prices of the levels rather than the grouped prices
+    fn group_levels_by_fn<'a, I, F>(levels: I, limit: usize, f: F) -> Vec<PriceInfo>
     where
-        F: Fn(&Order) -> Decimal,
+        I: Iterator<Item = (&'a Decimal, &'a (Decimal, usize))>,
+        F: Fn(Decimal) -> Decimal,
     {
-        orderbook
-            .values()
-            .group_by(|order_rc| -> Decimal { f(&order_rc.borrow()) })
-            .into_iter()
-            .take(limit)
-            .map(|(price, group)| PriceInfo {
-                price,
-                amount: group.map(|order_rc| order_rc.borrow().remain).sum(),
-            })
-            .collect::<Vec<PriceInfo>>()
+        let mut groups: Vec<PriceInfo> = Vec::new();
+        let mut cumulative_amount = Decimal::zero();
+        let mut cumulative_quote = Decimal::zero();
+        for (price, (amount, order_count)) in levels {
+            let group_price = f(*price);
+            let is_new_group = groups.last().map(|group| group.price != group_price).unwrap_or(true);
+            if is_new_group && groups.len() == limit {
+                break;
+            }
+            cumulative_amount += amount;
+            cumulative_quote += price * amount;
+            if is_new_group {
+                groups.push(PriceInfo {
+                    price: group_price,
+                    amount: Decimal::zero(),
+                    order_count: 0,
+                    cumulative_amount,
+                    cumulative_quote,
+                });
+            }
+            let group = groups.last_mut().unwrap();
+            group.amount += amount;
+            group.order_count += order_count;
+            group.cumulative_amount = cumulative_amount;
+            group.cumulative_quote = cumulative_quote;
+        }
+        groups
     }
 }
 
-pub struct MarketStatus {
-    pub name: String,
-    pub ask_count: usize,
-    pub ask_amount: Decimal,
+// the running totals of the resting orders of one side,
+// the frozen is in the base asset for the asks and in the quote asset for the bids
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BookTotals {
+    pub amount: Decimal,
+    pub frozen: Decimal,
+    // the remain * price of the orders, in the quote asset
+    pub notional: Decimal,
+}
+
+// set by the operators, e.g. to pause a market during an incident
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarketState {
+    Open,
+    // only cancels are accepted
+    CancelOnly,
+    // nothing is accepted, unless `cancel_when_halted` allows cancels
+    Halted,
+}
+
+// the frozen base is of the asks, the frozen quote of the bids
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct UserExposure {
+    pub frozen_base: Decimal,
+    pub frozen_quote: Decimal,
+    pub order_count: usize,
+}
+
+// the quote value resting on each side of the book
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpenInterest {
+    pub ask_notional: Decimal,
+    pub bid_notional: Decimal,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MarketStatus {
+    pub name: String,
+    pub state: MarketState,
+    pub ask_count: usize,
+    pub ask_amount: Decimal,
+    pub ask_frozen: Decimal,
     pub bid_count: usize,
     pub bid_amount: Decimal,
+    pub bid_frozen: Decimal,
+    pub best_ask: Option<Decimal>,
+    pub best_bid: Option<Decimal>,
+    pub last_price: Option<Decimal>,
+    // the users with open orders
+    pub user_count: usize,
     pub trade_count: u64,
+    pub stats: MarketStatsInfo,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MarketTicker {
+    pub name: String,
+    pub best_ask: Option<Decimal>,
+    pub best_ask_amount: Option<Decimal>,
+    pub best_bid: Option<Decimal>,
+    pub best_bid_amount: Option<Decimal>,
+    pub last_price: Option<Decimal>,
+    pub mid_price: Option<Decimal>,
+    pub spread: Option<Decimal>,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct PriceInfo {
     pub price: Decimal,
     pub amount: Decimal,
+    pub order_count: usize,
+    // the totals of this level and all the better levels
+    pub cumulative_amount: Decimal,
+    pub cumulative_quote: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderSortBy {
+    // the same as by id, but for the orders put at the same time
+    CreateTime,
+    Price,
+}
+
+impl Default for OrderSortBy {
+    fn default() -> Self {
+        OrderSortBy::CreateTime
+    }
+}
+
+// the position of an order in a query, the next page starts after it. it is kept by value
+// so the next page does not shift when the orders of the previous pages are canceled
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OrderQueryCursor {
+    pub create_time: TimestampMs,
+    pub price: Decimal,
+    pub id: u64,
+}
+
+impl OrderQueryCursor {
+    pub fn of(order: &Order) -> Self {
+        OrderQueryCursor {
+            create_time: order.create_time,
+            price: order.price,
+            id: order.id,
+        }
+    }
+    // ties are broken by id
+    pub fn compare(&self, other: &Self, sort_by: OrderSortBy) -> Ordering {
+        let ordering = match sort_by {
+            OrderSortBy::CreateTime => self.create_time.cmp(&other.create_time),
+            OrderSortBy::Price => self.price.cmp(&other.price),
+        };
+        ordering.then(self.id.cmp(&other.id))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OrderQuery {
+    pub side: Option<OrderSide>,
+    // both ends included
+    pub price_range: Option<(Decimal, Decimal)>,
+    pub sort_by: OrderSortBy,
+    pub descending: bool,
+    // counted from the cursor if both are given
+    pub offset: usize,
+    // zero means no limit
+    pub limit: usize,
+    pub after: Option<OrderQueryCursor>,
+}
+
+#[derive(Debug, Default)]
+pub struct OrderPage {
+    pub orders: Vec<Order>,
+    // the matched orders in all pages
+    pub total: usize,
+    // `after` of the next page, none on the last page
+    pub next: Option<OrderQueryCursor>,
 }
 
 pub struct MarketDepth {
     pub asks: Vec<PriceInfo>,
     pub bids: Vec<PriceInfo>,
+    // the depth update sequence the snapshot is taken at
+    pub seq: u64,
+    pub checksum: u32,
+}
+
+// a price level of the book is changed, a zero amount means the level is removed
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DepthUpdate {
+    pub market: String,
+    pub seq: u64,
+    pub side: OrderSide,
+    pub price: Decimal,
+    pub new_amount: Decimal,
+    // the orderbook checksum after the change, only on the last update of an operation
+    pub checksum: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -811,18 +2588,21 @@ struct BalanceHistoryFromFee {
 mod tests {
     use super::*;
     use crate::asset::update_controller::{BalanceUpdateParams, BusinessType};
+    use crate::clock::ManualClock;
     use crate::config::Settings;
     use crate::matchengine::mock;
     use crate::message::{Message, OrderMessage};
+    use crate::sequencer::Sequencer;
     use fluidex_common::rust_decimal_macros::*;
     use mock::*;
+    use tonic::Status;
 
     //#[cfg(feature = "emit_state_diff")]
     #[test]
     fn test_multi_orders() {
         use crate::asset::BalanceUpdateController;
-        use crate::matchengine::market::{Market, OrderInput};
-        use crate::types::{OrderSide, OrderType};
+        use crate::matchengine::market::Market;
+        use crate::types::OrderSide;
         use fluidex_common::rust_decimal::prelude::FromPrimitive;
         use rand::Rng;
 
@@ -832,7 +2612,7 @@ mod tests {
             Ok(b) => Box::new(crate::persist::MessengerBasedPersistor::new(Box::new(
                 crate::message::FullOrderMessageManager::new_and_run(&b).unwrap(),
             ))),
-            Err(_) => Box::new(crate::persist::FileBasedPersistor::new("market_test_output.txt")),
+            Err(_) => Box::new(crate::persist::FileBasedPersistor::new("market_test_output.txt").unwrap()),
         };
         //let persistor = &mut persistor;
         let mut update_controller = BalanceUpdateController::new();
@@ -865,6 +2645,8 @@ mod tests {
         update_balance_fn(3, uid1, &MockAsset::ETH.id(), dec!(1_000_000));
 
         let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
         let market_conf = if only_int {
             mock::get_integer_prec_market_config()
         } else {
@@ -885,24 +2667,23 @@ mod tests {
             } else {
                 Decimal::from_f64(rng.gen_range(120.0..140.0)).unwrap()
             };
-            let order = OrderInput {
-                user_id,
-                side,
-                type_: OrderType::LIMIT,
-                // the matchengine will truncate precision
-                // but later we'd better truncate precision outside
-                amount,
-                price,
-                quote_limit: dec!(0),
-                taker_fee: dec!(0),
-                maker_fee: dec!(0),
-                market: market.name.to_string(),
-                post_only: false,
-                signature: [0; 64],
-            };
+            // the matchengine will truncate precision
+            // but later we'd better truncate precision outside
+            let order = OrderInputBuilder::new(market.name, user_id, side, amount, price).build();
+            let before = balance_manager.snapshot_totals();
             market
-                .put_order(sequencer, balance_manager.into(), &mut update_controller, &mut persistor, order)
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order,
+                )
                 .unwrap();
+            // trades keep the totals of the assets
+            let after = balance_manager.snapshot_totals();
+            assert_eq!(crate::audit::check_conservation("order_put", &before, &after, &[]), vec![]);
         }
     }
     #[test]
@@ -916,27 +2697,20 @@ mod tests {
         balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
 
         let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
         let mut persistor = crate::persist::DummyPersistor::default();
         let ask_user_id = 101;
         let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
-        let ask_order_input = OrderInput {
-            user_id: ask_user_id,
-            side: OrderSide::ASK,
-            type_: OrderType::LIMIT,
-            amount: dec!(20.0),
-            price: dec!(0.1),
-            quote_limit: dec!(0),
-            taker_fee: dec!(0.001),
-            maker_fee: dec!(0.001),
-            market: market.name.to_string(),
-            post_only: false,
-            signature: [0; 64],
-        };
+        let ask_order_input = OrderInputBuilder::new(market.name, ask_user_id, OrderSide::ASK, dec!(20.0), dec!(0.1))
+            .fees(dec!(0.001), dec!(0.001))
+            .build();
         let ask_order = market
             .put_order(
                 sequencer,
                 balance_manager.into(),
                 &mut update_controller,
+                &fee_manager,
                 &mut persistor,
                 ask_order_input,
             )
@@ -945,24 +2719,16 @@ mod tests {
         assert_eq!(ask_order.remain, dec!(20.0));
 
         let bid_user_id = 102;
-        let bid_order_input = OrderInput {
-            user_id: bid_user_id,
-            side: OrderSide::BID,
-            type_: OrderType::MARKET,
-            amount: dec!(10.0),
-            price: dec!(0),
-            quote_limit: dec!(0),
-            taker_fee: dec!(0.001),
-            maker_fee: dec!(0.001),
-            market: market.name.to_string(),
-            post_only: false,
-            signature: [0; 64],
-        };
+        let bid_order_input = OrderInputBuilder::new(market.name, bid_user_id, OrderSide::BID, dec!(10.0), dec!(0))
+            .type_(OrderType::MARKET)
+            .fees(dec!(0.001), dec!(0.001))
+            .build();
         let bid_order = market
             .put_order(
                 sequencer,
                 balance_manager.into(),
                 &mut update_controller,
+                &fee_manager,
                 &mut persistor,
                 bid_order_input,
             )
@@ -1020,6 +2786,352 @@ mod tests {
         //assert_eq!(persistor.trades.len(), 1);
     }
 
+    #[test]
+    fn test_times_from_the_clock() {
+        let clock = ManualClock::new(TimestampMs(1_600_000_000_123));
+        let mut update_controller = BalanceUpdateController::new();
+        update_controller.clock = EngineClock::new(clock.clone());
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        market.clock = update_controller.clock.clone();
+        let order_input = |user_id, side| {
+            OrderInputBuilder::new(market.name, user_id, side, dec!(1), dec!(100))
+                .fees(dec!(0.001), dec!(0.001))
+                .build()
+        };
+        let (ask, bid) = (order_input(101, OrderSide::ASK), order_input(102, OrderSide::BID));
+
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                ask,
+            )
+            .unwrap();
+        assert_eq!(ask.create_time, TimestampMs(1_600_000_000_123));
+        assert_eq!(ask.update_time, TimestampMs(1_600_000_000_123));
+
+        // the maker is updated at the time of the taker
+        clock.advance(2_500);
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                bid,
+            )
+            .unwrap();
+        assert_eq!(bid.create_time, TimestampMs(1_600_000_002_623));
+        assert_eq!(bid.update_time, TimestampMs(1_600_000_002_623));
+        let maker = &persistor
+            .orders()
+            .into_iter()
+            .filter(|message| message.order.id == ask.id)
+            .last()
+            .unwrap()
+            .order;
+        assert_eq!(maker.create_time, TimestampMs(1_600_000_000_123));
+        assert_eq!(maker.update_time, TimestampMs(1_600_000_002_623));
+        assert_eq!(persistor.trades().len(), 1);
+        assert_eq!(persistor.trades()[0].timestamp, TimestampMs(1_600_000_002_623));
+        assert!(!persistor.balances().is_empty());
+        assert!(persistor
+            .balances()
+            .iter()
+            .all(|balance| balance.timestamp == TimestampMs(1_600_000_002_623)));
+
+        // a replayed operation is at its logged time, whatever the source says
+        market.clock.fix(TimestampMs(1_600_000_001_000));
+        clock.advance(60_000);
+        assert_eq!(market.clock.now(), TimestampMs(1_600_000_001_000));
+        market.clock.release();
+        assert_eq!(market.clock.now(), TimestampMs(1_600_000_062_623));
+    }
+
+    #[test]
+    fn test_invariant_violation_halts() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(10));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(1000));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let order_input = |user_id, side, amount| OrderInputBuilder::new(market.name, user_id, side, amount, dec!(100)).build();
+        let (ask1, ask2) = (order_input(101, OrderSide::ASK, dec!(1)), order_input(101, OrderSide::ASK, dec!(2)));
+        let bid = order_input(102, OrderSide::BID, dec!(1));
+        let ask1 = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                ask1,
+            )
+            .unwrap();
+        let ask2 = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                ask2,
+            )
+            .unwrap();
+
+        // an underflow fails and changes nothing
+        assert!(balance_manager.sub(101, BalanceType::FREEZE, eth, &dec!(4)).is_err());
+        assert!(balance_manager.unfrozen(101, eth, &dec!(4)).is_err());
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(3));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, eth), dec!(7));
+
+        // a maker frozen less than it trades stops the matching before the trade
+        market.orders.get(&ask1.id).unwrap().borrow_mut().frozen = dec!(0.5);
+        let error = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                bid,
+            )
+            .unwrap_err();
+        assert!(matches!(error, MarketError::InvariantViolated(_)), "{:?}", error);
+        assert_eq!(market.state, MarketState::CancelOnly);
+        assert_eq!(market.trade_count, 0);
+        assert!(persistor.trades().is_empty());
+        assert_eq!(market.get(ask1.id).unwrap().remain, dec!(1));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(1000));
+        let events = persistor
+            .messages
+            .iter()
+            .filter_map(|message| match message {
+                Message::MarketEventMessage(event) => Some(event.kind.name()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec!["invariant_violated", "halted"]);
+        assert!(matches!(
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                order_input(101, OrderSide::ASK, dec!(1))
+            ),
+            Err(MarketError::MarketNotOpen)
+        ));
+
+        // the frozen balance lost under an order fails its cancel, the order leaves the book
+        balance_manager.sub(101, BalanceType::FREEZE, eth, &dec!(2.5)).unwrap();
+        let error = market.cancel(balance_manager.into(), &mut persistor, ask2.id, 101).unwrap_err();
+        assert!(matches!(error, MarketError::InvariantViolated(_)), "{:?}", error);
+        assert!(market.get(ask2.id).is_none());
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(0.5));
+        assert_eq!(market.state, MarketState::CancelOnly);
+    }
+
+    #[test]
+    fn test_cancel_all_after_violation() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(10));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        for amount in [dec!(2), dec!(1)] {
+            let order_input = OrderInputBuilder::new(market.name, 101, OrderSide::ASK, amount, dec!(100)).build();
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &FeeManager::default(),
+                    &mut persistor,
+                    order_input,
+                )
+                .unwrap();
+        }
+
+        // the first order can not be unfrozen, the cancel goes on to the next one and returns the violation after
+        balance_manager.sub(101, BalanceType::FREEZE, eth, &dec!(1.5)).unwrap();
+        let error = market.cancel_all_for_user(balance_manager.into(), &mut persistor, 101).unwrap_err();
+        assert!(matches!(error, MarketError::InvariantViolated(_)), "{:?}", error);
+        assert!(market.orders.is_empty());
+        assert_eq!(market.get_order_num_of_user(101), 0);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(0.5));
+        assert_eq!(market.state, MarketState::CancelOnly);
+    }
+
+    #[test]
+    fn test_market_taker_is_ask_with_quote_limit() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let ask_user_id = 101;
+        let bid_user_id = 102;
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let bid_order_input = OrderInputBuilder::new(market.name, bid_user_id, OrderSide::BID, dec!(10.0), dec!(3)).build();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                bid_order_input,
+            )
+            .unwrap();
+
+        let ask_order_input = OrderInputBuilder::new(market.name, ask_user_id, OrderSide::ASK, dec!(10.0), dec!(0))
+            .type_(OrderType::MARKET)
+            .quote_limit(dec!(10))
+            .build();
+        let ask_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                ask_order_input,
+            )
+            .unwrap();
+        // trade: price: 3 amount: 10 / 3 rounded down to amount_prec
+        assert!(ask_order.finished_quote <= dec!(10));
+        assert_eq!(ask_order.finished_base, dec!(3.3333));
+        assert_eq!(ask_order.finished_quote, dec!(9.9999));
+        assert_eq!(ask_order.remain, dec!(6.6667));
+        // the remain part is finished rather than inserted into the orderbook
+        assert!(market.get(ask_order.id).is_none());
+        assert_eq!(market.asks.len(), 0);
+
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(996.6667)
+        );
+        assert_eq!(balance_manager.get(ask_user_id, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+        assert_eq!(
+            balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(309.9999)
+        );
+    }
+
+    #[test]
+    fn test_market_bid_with_quote_amount() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(20));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let ask_user_id = 101;
+        let bid_user_id = 102;
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        for price in [dec!(2), dec!(4)] {
+            let ask_order_input = OrderInputBuilder::new(market.name, ask_user_id, OrderSide::ASK, dec!(5), price).build();
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    ask_order_input,
+                )
+                .unwrap();
+        }
+
+        let market_name = market.name.to_string();
+        let bid_order_input = |amount| {
+            OrderInputBuilder::new(&market_name, bid_user_id, OrderSide::BID, amount, dec!(0))
+                .type_(OrderType::MARKET)
+                .amount_is_quote()
+                .build()
+        };
+        // budget smaller than min_amount at the best ask price
+        let small_order_input = bid_order_input(dec!(0.01));
+        assert!(market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                small_order_input,
+            )
+            .is_err());
+
+        // spend 12: 5 @ 2, then 0.5 @ 4
+        let bid_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                bid_order_input(dec!(12)),
+            )
+            .unwrap();
+        assert_eq!(bid_order.finished_base, dec!(5.5));
+        assert_eq!(bid_order.finished_quote, dec!(12));
+        assert!(market.get(bid_order.id).is_none());
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(8)
+        );
+
+        // budget larger than the available balance is clamped by the balance
+        let bid_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                bid_order_input(dec!(100)),
+            )
+            .unwrap();
+        assert_eq!(bid_order.finished_base, dec!(2));
+        assert_eq!(bid_order.finished_quote, dec!(8));
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id()),
+            dec!(0)
+        );
+        assert_eq!(
+            balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(7.5)
+        );
+    }
+
     #[test]
     fn test_limit_post_only_orders() {
         let mut update_controller = BalanceUpdateController::new();
@@ -1031,27 +3143,21 @@ mod tests {
         balance_manager.add(202, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
 
         let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
         let mut persistor = crate::persist::MemBasedPersistor::default();
         let ask_user_id = 201;
         let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
-        let ask_order_input = OrderInput {
-            user_id: ask_user_id,
-            side: OrderSide::ASK,
-            type_: OrderType::LIMIT,
-            amount: dec!(20.0),
-            price: dec!(0.1),
-            quote_limit: dec!(0),
-            taker_fee: dec!(0.001),
-            maker_fee: dec!(0.001),
-            market: market.name.to_string(),
-            post_only: true,
-            signature: [0; 64],
-        };
+        let ask_order_input = OrderInputBuilder::new(market.name, ask_user_id, OrderSide::ASK, dec!(20.0), dec!(0.1))
+            .fees(dec!(0.001), dec!(0.001))
+            .post_only(true)
+            .build();
         let ask_order = market
             .put_order(
                 sequencer,
                 balance_manager.into(),
                 &mut update_controller,
+                &fee_manager,
                 &mut persistor,
                 ask_order_input,
             )
@@ -1061,30 +3167,22 @@ mod tests {
         assert_eq!(ask_order.remain, dec!(20));
 
         let bid_user_id = 202;
-        let bid_order_input = OrderInput {
-            user_id: bid_user_id,
-            side: OrderSide::BID,
-            type_: OrderType::LIMIT,
-            amount: dec!(10.0),
-            price: dec!(0.1),
-            quote_limit: dec!(0),
-            taker_fee: dec!(0.001),
-            maker_fee: dec!(0.001),
-            market: market.name.to_string(),
-            post_only: true,
-            signature: [0; 64],
-        };
+        let bid_order_input = OrderInputBuilder::new(market.name, bid_user_id, OrderSide::BID, dec!(10.0), dec!(0.1))
+            .fees(dec!(0.001), dec!(0.001))
+            .post_only(true)
+            .build();
         let bid_order = market
             .put_order(
                 sequencer,
                 balance_manager.into(),
                 &mut update_controller,
+                &fee_manager,
                 &mut persistor,
                 bid_order_input,
             )
             .unwrap();
 
-        // No trade occurred since limit and post only. This BID order should be finished.
+        // No trade occurred since limit and post only. This BID order should be canceled.
         assert_eq!(bid_order.id, 2);
         assert_eq!(bid_order.remain, dec!(10));
         assert_eq!(bid_order.finished_quote, dec!(0));
@@ -1097,20 +3195,15 @@ mod tests {
         assert_eq!(ask_order.finished_base, dec!(0));
         assert_eq!(ask_order.finished_fee, dec!(0));
 
-        let bid_order_message = persistor.messages.last().unwrap();
-        match bid_order_message {
-            Message::OrderMessage(msg) => {
-                assert!(matches!(
-                    **msg,
-                    OrderMessage {
-                        event: OrderEventType::FINISH,
-                        order: Order { id: 2, user: 202, .. },
-                        ..
-                    }
-                ));
+        assert!(matches!(
+            persistor.orders().last().unwrap(),
+            OrderMessage {
+                event: OrderEventType::FINISH,
+                order: Order { id: 2, user: 202, .. },
+                cancel_reason: Some(OrderCancelReason::PostOnlyCross),
+                ..
             }
-            _ => panic!("expect OrderMessage only"),
-        }
+        ));
 
         assert_eq!(
             balance_manager.get(ask_user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
@@ -1143,4 +3236,4010 @@ mod tests {
             dec!(0)
         );
     }
+
+    #[test]
+    fn test_limit_post_only_orders_reprice() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(201, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(202, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let market_conf = config::Market {
+            post_only_reprice: true,
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let ask_order_input = OrderInputBuilder::new(market.name, 201, OrderSide::ASK, dec!(20.0), dec!(0.1))
+            .fees(dec!(0.001), dec!(0.001))
+            .post_only(true)
+            .build();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                ask_order_input,
+            )
+            .unwrap();
+
+        let bid_order_input = OrderInputBuilder::new(market.name, 202, OrderSide::BID, dec!(10.0), dec!(0.2))
+            .fees(dec!(0.001), dec!(0.001))
+            .post_only(true)
+            .build();
+        let bid_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                bid_order_input,
+            )
+            .unwrap();
+
+        // the crossing BID order is repriced to best ask - 1 tick and inserted into the orderbook
+        assert_eq!(bid_order.price, dec!(0.09));
+        assert_eq!(bid_order.remain, dec!(10));
+        assert_eq!(market.get(bid_order.id).unwrap().price, dec!(0.09));
+        assert_eq!(market.bids.len(), 1);
+        // a single PUT at the adjusted price, the crossing price is never emitted. the reprice is told by the outcome
+        let repriced = Some(FillOutcome::Repriced { from: dec!(0.2) });
+        assert_eq!(bid_order.fill_outcome, repriced);
+        let events = persistor
+            .messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::OrderMessage(msg) if msg.order.id == bid_order.id => Some((msg.event, msg.order.price, msg.order.fill_outcome)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![(OrderEventType::PUT, dec!(0.09), repriced)]);
+        let json = persistor
+            .messages
+            .iter()
+            .find_map(|msg| match msg {
+                Message::OrderMessage(msg) if msg.order.id == bid_order.id => Some(serde_json::to_value(msg).unwrap()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(json["order"]["fill_outcome"]["outcome"], "repriced");
+        assert_eq!(balance_manager.get(202, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(0.9));
+        assert_eq!(balance_manager.get(202, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(299.1));
+    }
+
+    #[test]
+    fn test_finish_dust_orders() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let market_conf = config::Market {
+            finish_dust_orders: true,
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let ask_order_input = OrderInputBuilder::new(market.name, 101, OrderSide::ASK, dec!(10), dec!(1)).build();
+        let ask_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                ask_order_input,
+            )
+            .unwrap();
+
+        let bid_order_input = OrderInputBuilder::new(market.name, 102, OrderSide::BID, dec!(9.995), dec!(1)).build();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                bid_order_input,
+            )
+            .unwrap();
+
+        // remain 0.005 is less than min_amount 0.01, so the maker is finished and the dust is unfrozen
+        assert!(market.get(ask_order.id).is_none());
+        assert!(market.asks.is_empty());
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(0));
+        assert_eq!(
+            balance_manager.get(101, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            dec!(990.005)
+        );
+        assert!(persistor.messages.iter().any(|msg| matches!(
+            msg,
+            Message::OrderMessage(msg) if msg.event == OrderEventType::FINISH && msg.order.id == ask_order.id && msg.order.remain == dec!(0.005)
+        )));
+    }
+
+    #[test]
+    fn test_max_price_deviation() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let market_conf = config::Market {
+            max_price_deviation: Some(dec!(0.2)),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .build()
+        };
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                input,
+            )
+        };
+
+        // cold start: no trade yet, so the band is skipped
+        put(&mut market, order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(5), dec!(1))).unwrap();
+        put(&mut market, order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(5), dec!(1.5))).unwrap();
+        put(&mut market, order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(2), dec!(0.5))).unwrap();
+        put(&mut market, order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1))).unwrap();
+        assert_eq!(market.price, dec!(1));
+
+        // limit orders out of the band are rejected
+        assert!(put(&mut market, order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1.3))).is_err());
+        assert!(put(&mut market, order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(1), dec!(0.7))).is_err());
+        put(&mut market, order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(2), dec!(0.9))).unwrap();
+
+        // market bid stops before the ask at 1.5
+        let order = put(&mut market, order_input(102, OrderSide::BID, OrderType::MARKET, dec!(10), dec!(0))).unwrap();
+        assert_eq!(order.finished_base, dec!(4));
+        assert_eq!(market.asks.len(), 1);
+
+        // market ask stops before the bid at 0.5
+        let order = put(&mut market, order_input(101, OrderSide::ASK, OrderType::MARKET, dec!(10), dec!(0))).unwrap();
+        assert_eq!(order.finished_base, dec!(2));
+        assert_eq!(market.bids.len(), 1);
+        match persistor.messages.last().unwrap() {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.event, OrderEventType::FINISH);
+                assert_eq!(msg.cancel_reason, Some(OrderCancelReason::PriceDeviation));
+            }
+            _ => panic!("expect OrderMessage only"),
+        }
+    }
+
+    #[test]
+    fn test_market_order_max_slippage() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        for price in [dec!(2), dec!(3)] {
+            let ask_order_input = OrderInputBuilder::new(market.name, 101, OrderSide::ASK, dec!(5), price).build();
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    ask_order_input,
+                )
+                .unwrap();
+        }
+
+        let bid_order_input = OrderInputBuilder::new(market.name, 102, OrderSide::BID, dec!(10), dec!(0))
+            .type_(OrderType::MARKET)
+            .max_slippage(dec!(0.1))
+            .build();
+        let bid_order = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                bid_order_input,
+            )
+            .unwrap();
+
+        // the first level at 2 is filled, the second level at 3 breaches 2 * 1.1
+        assert_eq!(bid_order.finished_base, dec!(5));
+        assert_eq!(bid_order.finished_quote, dec!(10));
+        assert_eq!(bid_order.remain, dec!(5));
+        assert!(market.get(bid_order.id).is_none());
+        assert_eq!(market.asks.len(), 1);
+
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(290));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, &MockAsset::USDT.id()), dec!(0));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(5));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &MockAsset::ETH.id()), dec!(990));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(5));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &MockAsset::USDT.id()), dec!(10));
+    }
+
+    #[test]
+    fn test_min_notional() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(300));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let market_conf = config::Market {
+            min_notional: dec!(1),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .build()
+        };
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                input,
+            )
+        };
+
+        let err = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(0.001), dec!(2)),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "amount too small");
+        let err = put(&mut market, order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(0.4), dec!(2))).unwrap_err();
+        assert_eq!(err.to_string(), "notional too small");
+        put(&mut market, order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(0.5), dec!(2))).unwrap();
+
+        // market ask is validated against the best bid
+        let err = put(&mut market, order_input(101, OrderSide::ASK, OrderType::MARKET, dec!(0.4), dec!(0))).unwrap_err();
+        assert_eq!(err.to_string(), "notional too small");
+        put(&mut market, order_input(101, OrderSide::ASK, OrderType::MARKET, dec!(0.5), dec!(0))).unwrap();
+    }
+
+    #[test]
+    fn test_max_open_orders_per_user() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let settings = Settings {
+            max_open_orders_per_user: 1,
+            ..Settings::default()
+        };
+        // the market override takes precedence over the global setting
+        let market_conf = config::Market {
+            max_open_orders_per_user: Some(2),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &settings, balance_manager).unwrap();
+        assert_eq!(market.max_open_orders_per_user, 2);
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .build()
+        };
+
+        let bid1 = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1)),
+            )
+            .unwrap();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1.1)),
+            )
+            .unwrap();
+        assert_eq!(market.get_order_num_of_user(102), 2);
+
+        let err = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1.2)),
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "too many open orders");
+
+        // market orders are not counted
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(1), dec!(2)),
+            )
+            .unwrap();
+        let market_bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, OrderType::MARKET, dec!(1), dec!(0)),
+            )
+            .unwrap();
+        assert!(market_bid.remain.is_zero());
+        assert_eq!(market.get_order_num_of_user(102), 2);
+
+        market.cancel(balance_manager.into(), &mut persistor, bid1.id, 102).unwrap();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1.2)),
+            )
+            .unwrap();
+        assert_eq!(market.get_order_num_of_user(102), 2);
+    }
+
+    #[test]
+    fn test_max_order_size() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let market_conf = config::Market {
+            max_amount: Some(dec!(10)),
+            max_quote_amount: Some(dec!(20)),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price, quote_limit| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .quote_limit(quote_limit)
+                .build()
+        };
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                input,
+            )
+        };
+
+        // precision is checked before the caps
+        let err = put(
+            &mut market,
+            order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(10.00001), dec!(1), dec!(0)),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "invalid amount precision");
+        let err = put(
+            &mut market,
+            order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(10.0001), dec!(1), dec!(0)),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "amount too large");
+        put(
+            &mut market,
+            order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(10), dec!(2), dec!(0)),
+        )
+        .unwrap();
+
+        let err = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(10), dec!(1.01), dec!(0)),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "quote amount too large");
+        put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(10), dec!(1), dec!(0)),
+        )
+        .unwrap();
+
+        // market bid is capped by its quote_limit rather than the amount
+        let err = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::MARKET, dec!(1), dec!(0), dec!(20.01)),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "quote amount too large");
+        let order = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::MARKET, dec!(10), dec!(0), dec!(20)),
+        )
+        .unwrap();
+        assert_eq!(order.finished_quote, dec!(20));
+
+        // without an explicit quote_limit, the spending is clamped to the cap
+        put(
+            &mut market,
+            order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(10), dec!(4), dec!(0)),
+        )
+        .unwrap();
+        let order = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::MARKET, dec!(10), dec!(0), dec!(0)),
+        )
+        .unwrap();
+        assert_eq!(order.finished_quote, dec!(20));
+    }
+
+    #[test]
+    fn test_amend_ask_order() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |price| OrderInputBuilder::new(&market_name, 101, OrderSide::ASK, dec!(10), price).build();
+        let ask1 = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(dec!(2)),
+            )
+            .unwrap();
+        let ask2 = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(dec!(2)),
+            )
+            .unwrap();
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(20));
+
+        // decreasing the amount keeps the priority
+        let order = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                ask1.id,
+                None,
+                Some(dec!(6)),
+            )
+            .unwrap();
+        assert_eq!(order.remain, dec!(6));
+        assert_eq!(order.frozen, dec!(6));
+        assert_eq!(order.priority, ask1.id);
+        assert_eq!(market.asks.values().next().unwrap().borrow().id, ask1.id);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(16));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, eth), dec!(84));
+        match persistor.messages.last().unwrap() {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.event, OrderEventType::UPDATE);
+                assert_eq!(msg.order.amount, dec!(6));
+                assert_eq!(msg.order_before.unwrap().amount, dec!(10));
+            }
+            _ => panic!("expect OrderMessage only"),
+        }
+
+        // increasing the amount loses the priority
+        let order = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                ask1.id,
+                None,
+                Some(dec!(12)),
+            )
+            .unwrap();
+        assert_eq!(order.frozen, dec!(12));
+        assert!(order.priority > ask2.id);
+        assert_eq!(market.asks.values().next().unwrap().borrow().id, ask2.id);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(22));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, eth), dec!(78));
+
+        let err = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                ask1.id,
+                None,
+                Some(dec!(100)),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err,
+            MarketError::BalanceNotEnough {
+                asset: eth.to_string(),
+                required: dec!(88),
+                available: dec!(78),
+            }
+        );
+        let err = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                ask1.id,
+                Some(dec!(1.001)),
+                None,
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid price precision");
+        let err = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                ask1.id,
+                None,
+                Some(dec!(0.001)),
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "amount too small");
+
+        // a price change moves the order to the new price level
+        let order = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                ask1.id,
+                Some(dec!(1.5)),
+                None,
+            )
+            .unwrap();
+        assert_eq!(order.price, dec!(1.5));
+        assert_eq!(order.frozen, dec!(12));
+        assert_eq!(market.asks.values().next().unwrap().borrow().id, ask1.id);
+        assert_eq!(market.asks.len(), 2);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(22));
+    }
+
+    #[test]
+    fn test_amend_bid_order() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, amount, price| OrderInputBuilder::new(&market_name, user_id, side, amount, price).build();
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(10), dec!(2)),
+            )
+            .unwrap();
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(20));
+
+        let order = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                bid.id,
+                Some(dec!(1.5)),
+                None,
+            )
+            .unwrap();
+        assert_eq!(order.frozen, dec!(15));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(15));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(85));
+
+        let order = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                bid.id,
+                None,
+                Some(dec!(5)),
+            )
+            .unwrap();
+        assert_eq!(order.frozen, dec!(7.5));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(7.5));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(92.5));
+
+        // the amended order crosses the book and is matched again
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, dec!(3), dec!(1.6)),
+            )
+            .unwrap();
+        let order = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                bid.id,
+                Some(dec!(1.6)),
+                None,
+            )
+            .unwrap();
+        assert_eq!(order.finished_base, dec!(3));
+        assert_eq!(order.remain, dec!(2));
+        assert_eq!(order.frozen, dec!(3.2));
+        assert!(market.asks.is_empty());
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, eth), dec!(3));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(3.2));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(92));
+
+        // the amount cannot be reduced below the finished part
+        let err = market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                bid.id,
+                None,
+                Some(dec!(3)),
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "amount should be greater than the finished amount");
+    }
+
+    #[test]
+    fn test_replace_orders() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let usdt = &MockAsset::USDT.id();
+
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |price| OrderInputBuilder::new(&market_name, 102, OrderSide::BID, dec!(10), price).build();
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(dec!(9)),
+            )
+            .unwrap();
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(10));
+
+        // the quote unfrozen from the canceled bid funds the new bid
+        let (canceled, placed) = market
+            .replace_orders(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                vec![bid.id],
+                vec![order_input(dec!(9.5))],
+            )
+            .unwrap();
+        assert_eq!(canceled.len(), 1);
+        assert_eq!(canceled[0].id, bid.id);
+        assert_eq!(placed.len(), 1);
+        assert!(market.get(bid.id).is_none());
+        assert_eq!(market.get(placed[0].id).unwrap().frozen, dec!(95));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(95));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(5));
+        let events: Vec<(u64, OrderEventType)> = persistor
+            .messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::OrderMessage(msg) => Some((msg.order.id, msg.event)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            events[events.len() - 2..],
+            [(bid.id, OrderEventType::FINISH), (placed[0].id, OrderEventType::PUT)]
+        );
+
+        // nothing is applied when the new orders are invalid
+        let err = market
+            .replace_orders(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                vec![placed[0].id],
+                vec![order_input(dec!(5)), order_input(dec!(5.1))],
+            )
+            .unwrap_err();
+        assert!(err.to_string().starts_with("balance not enough"));
+        assert!(market.get(placed[0].id).is_some());
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(95));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(5));
+
+        // a client order id can not be taken twice by the new orders
+        let with_client_id = |price| {
+            OrderInputBuilder::new(&market_name, 102, OrderSide::BID, dec!(1), price)
+                .client_order_id(Some(7))
+                .build()
+        };
+        let err = market
+            .replace_orders(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                vec![placed[0].id],
+                vec![with_client_id(dec!(5)), with_client_id(dec!(6))],
+            )
+            .unwrap_err();
+        assert_eq!(err, MarketError::DuplicateClientOrderId(7));
+        assert!(market.get(placed[0].id).is_some());
+    }
+
+    #[test]
+    fn test_replace_orders_in_band() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(10));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(1000));
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let market_conf = config::Market {
+            max_price_deviation: Some(dec!(0.2)),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, price| OrderInputBuilder::new(&market_name, user_id, side, dec!(1), price).build();
+        // the last price is 100, the band is 80 - 120
+        let mut ids = Vec::new();
+        for (user_id, side, price) in [
+            (101, OrderSide::ASK, dec!(100)),
+            (102, OrderSide::BID, dec!(100)),
+            (101, OrderSide::ASK, dec!(119)),
+            (102, OrderSide::BID, dec!(90)),
+        ] {
+            let order = market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input(user_id, side, price),
+                )
+                .unwrap();
+            ids.push(order.id);
+        }
+        assert_eq!(market.price, dec!(100));
+        let messages = persistor.messages.len();
+
+        // the first new bid trades at 119, the second is still in the band taken before the replacement
+        let (canceled, placed) = market
+            .replace_orders(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                vec![ids[3]],
+                vec![
+                    order_input(102, OrderSide::BID, dec!(119)),
+                    order_input(102, OrderSide::BID, dec!(85)),
+                ],
+            )
+            .unwrap();
+        assert_eq!(canceled[0].id, ids[3]);
+        assert_eq!(placed.len(), 2);
+        assert_eq!(market.price, dec!(119));
+        assert_eq!(market.get(placed[1].id).unwrap().price, dec!(85));
+        assert!(persistor.messages.len() > messages);
+    }
+
+    #[test]
+    fn test_cancel_invalid_order() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side| OrderInputBuilder::new(&market_name, user_id, side, dec!(10), dec!(2)).build();
+
+        let err = market.cancel(balance_manager.into(), &mut persistor, 100, 101).unwrap_err();
+        assert_eq!(err.to_string(), "invalid order_id 100: order not found");
+
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK),
+            )
+            .unwrap();
+        let err = market.cancel(balance_manager.into(), &mut persistor, ask.id, 102).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("invalid user 102: order {} belongs to another user", ask.id)
+        );
+        assert!(market.get(ask.id).is_some());
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(10));
+
+        // fully filled
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID),
+            )
+            .unwrap();
+        let err = market.cancel(balance_manager.into(), &mut persistor, ask.id, 101).unwrap_err();
+        assert_eq!(err.to_string(), format!("invalid order_id {}: order not found", ask.id));
+    }
+
+    #[test]
+    fn test_cancel_for_user_filtered() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(101, BalanceType::AVAILABLE, usdt, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |side, price| OrderInputBuilder::new(&market_name, 101, side, dec!(1), price).build();
+        for (side, price) in [
+            (OrderSide::BID, dec!(1)),
+            (OrderSide::BID, dec!(2)),
+            (OrderSide::ASK, dec!(3)),
+            (OrderSide::ASK, dec!(4)),
+            (OrderSide::ASK, dec!(5)),
+        ] {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input(side, price),
+                )
+                .unwrap();
+        }
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(3));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, usdt), dec!(3));
+
+        // asks priced under 5
+        let canceled = market
+            .cancel_for_user_filtered(
+                balance_manager.into(),
+                &mut persistor,
+                101,
+                Some(OrderSide::ASK),
+                Some((dec!(5), Ordering::Less)),
+            )
+            .unwrap();
+        assert_eq!(canceled.len(), 2);
+        assert!(canceled.iter().all(|order| order.side == OrderSide::ASK && order.price < dec!(5)));
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(market.bids.len(), 2);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(1));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, usdt), dec!(3));
+
+        // both sides priced above 1
+        let canceled = market
+            .cancel_for_user_filtered(
+                balance_manager.into(),
+                &mut persistor,
+                101,
+                None,
+                Some((dec!(1), Ordering::Greater)),
+            )
+            .unwrap();
+        assert_eq!(canceled.len(), 2);
+        assert_eq!(market.asks.len(), 0);
+        assert_eq!(market.bids.len(), 1);
+        assert_eq!(market.bids.values().next().unwrap().borrow().price, dec!(1));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(0));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, usdt), dec!(1));
+
+        let canceled = market
+            .cancel_for_user_filtered(balance_manager.into(), &mut persistor, 102, None, None)
+            .unwrap();
+        assert!(canceled.is_empty());
+        assert_eq!(market.get_order_num_of_user(101), 1);
+    }
+
+    #[test]
+    fn test_client_order_id() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, client_order_id| {
+            OrderInputBuilder::new(&market_name, user_id, side, dec!(1), dec!(2))
+                .client_order_id(client_order_id)
+                .build()
+        };
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                input,
+            )
+        };
+
+        let ask = put(&mut market, order_input(101, OrderSide::ASK, Some(7))).unwrap();
+        assert_eq!(market.get_by_client_id(101, 7).unwrap().id, ask.id);
+        assert!(market.get_by_client_id(102, 7).is_none());
+        let err = put(&mut market, order_input(101, OrderSide::ASK, Some(7))).unwrap_err();
+        assert_eq!(err.to_string(), "duplicate client order id 7");
+
+        // the client id is released once the order is finished
+        let bid = put(&mut market, order_input(102, OrderSide::BID, Some(7))).unwrap();
+        assert!(bid.remain.is_zero());
+        assert!(market.get_by_client_id(101, 7).is_none());
+        assert!(market.client_ids.is_empty());
+
+        let ask = put(&mut market, order_input(101, OrderSide::ASK, Some(7))).unwrap();
+        let err = market
+            .cancel_by_client_id(balance_manager.into(), &mut persistor, 101, 8)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid client_order_id 8: order not found");
+        let order = market.cancel_by_client_id(balance_manager.into(), &mut persistor, 101, 7).unwrap();
+        assert_eq!(order.id, ask.id);
+        assert!(market.get(ask.id).is_none());
+        assert!(market.client_ids.is_empty());
+        match persistor.messages.last().unwrap() {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.event, OrderEventType::FINISH);
+                assert_eq!(msg.order.client_order_id, Some(7));
+            }
+            _ => panic!("expect OrderMessage only"),
+        }
+    }
+
+    #[test]
+    fn test_reduce_order() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, price| OrderInputBuilder::new(&market_name, user_id, side, dec!(10), price).build();
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                input,
+            )
+        };
+        let ask1 = put(&mut market, order_input(101, OrderSide::ASK, dec!(3))).unwrap();
+        let ask2 = put(&mut market, order_input(101, OrderSide::ASK, dec!(3))).unwrap();
+        let bid = put(&mut market, order_input(102, OrderSide::BID, dec!(2))).unwrap();
+
+        let err = market
+            .reduce_order(balance_manager.into(), &mut persistor, ask1.id, dec!(0.00001))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid amount precision");
+        let err = market
+            .reduce_order(balance_manager.into(), &mut persistor, ask1.id, dec!(11))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "reduce amount exceeds the remain");
+
+        let order = market
+            .reduce_order(balance_manager.into(), &mut persistor, ask1.id, dec!(4))
+            .unwrap();
+        assert_eq!(order.amount, dec!(6));
+        assert_eq!(order.remain, dec!(6));
+        assert_eq!(order.frozen, dec!(6));
+        assert_eq!(market.get(ask1.id).unwrap().remain, dec!(6));
+        // the priority is kept
+        assert_eq!(market.asks.values().next().unwrap().borrow().id, ask1.id);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(16));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, eth), dec!(84));
+        match persistor.messages.last().unwrap() {
+            Message::OrderMessage(msg) => {
+                assert_eq!(msg.event, OrderEventType::UPDATE);
+                assert_eq!(msg.order.remain, dec!(6));
+            }
+            _ => panic!("expect OrderMessage only"),
+        }
+
+        let order = market
+            .reduce_order(balance_manager.into(), &mut persistor, bid.id, dec!(2.5))
+            .unwrap();
+        assert_eq!(order.remain, dec!(7.5));
+        assert_eq!(order.frozen, dec!(15));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(15));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(85));
+
+        // the rest below min_amount finishes the order
+        let order = market
+            .reduce_order(balance_manager.into(), &mut persistor, ask2.id, dec!(9.995))
+            .unwrap();
+        assert_eq!(order.remain, dec!(0.005));
+        assert!(market.get(ask2.id).is_none());
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(6));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, eth), dec!(94));
+        match persistor.messages.last().unwrap() {
+            Message::OrderMessage(msg) => assert_eq!(msg.event, OrderEventType::FINISH),
+            _ => panic!("expect OrderMessage only"),
+        }
+    }
+
+    #[test]
+    fn test_market_error_variants() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let usdt = &MockAsset::USDT.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(10));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let settings = Settings {
+            disable_market_order: true,
+            ..Settings::default()
+        };
+        let mut market = Market::new(&get_simple_market_config(), &settings, balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .build()
+        };
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                input,
+            )
+        };
+
+        let err = put(&mut market, order_input(102, OrderSide::BID, OrderType::MARKET, dec!(1), dec!(0))).unwrap_err();
+        assert_eq!(err, MarketError::MarketOrdersDisabled);
+        assert_eq!(Status::from(err).code(), tonic::Code::FailedPrecondition);
+        let err = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(0.00001), dec!(1)),
+        )
+        .unwrap_err();
+        assert_eq!(err, MarketError::BelowMinAmount);
+        let err = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1.00001), dec!(1)),
+        )
+        .unwrap_err();
+        assert_eq!(err, MarketError::InvalidAmountPrecision);
+        let err = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1), dec!(1.001)),
+        )
+        .unwrap_err();
+        assert_eq!(err, MarketError::InvalidPricePrecision);
+        assert_eq!(Status::from(err).code(), tonic::Code::InvalidArgument);
+        let err = put(&mut market, order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(6), dec!(2))).unwrap_err();
+        assert_eq!(
+            err,
+            MarketError::BalanceNotEnough {
+                asset: usdt.to_string(),
+                required: dec!(12),
+                available: dec!(10),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            format!("balance not enough: {} available(10) < required(12)", usdt)
+        );
+
+        let ask = put(&mut market, order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(1), dec!(2))).unwrap();
+        let err = market.cancel(balance_manager.into(), &mut persistor, ask.id, 102).unwrap_err();
+        assert_eq!(
+            err,
+            MarketError::NotOrderOwner {
+                user_id: 102,
+                order_id: ask.id
+            }
+        );
+        assert_eq!(Status::from(err).code(), tonic::Code::PermissionDenied);
+        let err = market.cancel(balance_manager.into(), &mut persistor, ask.id + 1, 101).unwrap_err();
+        assert_eq!(err, MarketError::OrderNotFound(ask.id + 1));
+        assert_eq!(Status::from(err).code(), tonic::Code::NotFound);
+    }
+
+    #[test]
+    fn test_order_input_sanity() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let order_input = |market: &str, taker_fee, maker_fee| {
+            OrderInputBuilder::new(market, 101, OrderSide::ASK, dec!(1), dec!(2))
+                .fees(taker_fee, maker_fee)
+                .build()
+        };
+        let market_name = market.name.to_string();
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                input,
+            )
+        };
+
+        let err = put(&mut market, order_input("BTC_USDT", dec!(0), dec!(0))).unwrap_err();
+        assert_eq!(
+            err,
+            MarketError::MarketMismatch {
+                expected: market_name.clone(),
+                actual: "BTC_USDT".to_string(),
+            }
+        );
+        let err = put(&mut market, order_input(&market_name, dec!(1), dec!(0))).unwrap_err();
+        assert_eq!(err, MarketError::InvalidFee);
+        let err = put(&mut market, order_input(&market_name, dec!(0.01), dec!(-0.02))).unwrap_err();
+        assert_eq!(err, MarketError::InvalidFee);
+        let err = put(&mut market, order_input(&market_name, dec!(0.01), dec!(0.02))).unwrap_err();
+        assert_eq!(err, MarketError::InvalidFee);
+        // nothing is emitted for the rejected orders
+        assert!(persistor.messages.is_empty());
+
+        put(&mut market, order_input(&market_name, dec!(0.02), dec!(0.01))).unwrap();
+    }
+
+    #[test]
+    fn test_put_event_ordering() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, amount, post_only| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, dec!(2))
+                .post_only(post_only)
+                .build()
+        };
+        // (order id, event) for order messages, (trade id, None) for trade messages
+        let events = |persistor: &mut crate::persist::MemBasedPersistor| -> Vec<(u64, Option<OrderEventType>)> {
+            persistor
+                .messages
+                .drain(..)
+                .filter_map(|msg| match msg {
+                    Message::OrderMessage(msg) => Some((msg.order.id, Some(msg.event))),
+                    Message::TradeMessage(trade) => Some((trade.id, None)),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, dec!(1), false),
+            )
+            .unwrap();
+        assert_eq!(events(&mut persistor), vec![(ask.id, Some(OrderEventType::PUT))]);
+
+        // orders canceled before any trade never get a PUT event
+        let post_only_bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(1), true),
+            )
+            .unwrap();
+        assert_eq!(events(&mut persistor), vec![(post_only_bid.id, Some(OrderEventType::FINISH))]);
+        let self_trade_bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::BID, dec!(1), false),
+            )
+            .unwrap();
+        assert_eq!(events(&mut persistor), vec![(self_trade_bid.id, Some(OrderEventType::FINISH))]);
+
+        // PUT, then the trades, then the maker is finished, and the rest of the taker rests
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(2), false),
+            )
+            .unwrap();
+        assert_eq!(
+            events(&mut persistor),
+            vec![
+                (bid.id, Some(OrderEventType::PUT)),
+                (1, None),
+                (ask.id, Some(OrderEventType::FINISH))
+            ]
+        );
+        assert_eq!(market.get(bid.id).unwrap().remain, dec!(1));
+    }
+
+    #[test]
+    fn test_fee_tiers() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let fee_manager = FeeManager::new(&config::FeeSettings {
+            enabled: true,
+            default_tier: config::FeeTier {
+                maker_fee: dec!(0.01),
+                taker_fee: dec!(0.02),
+            },
+            user_tiers: vec![config::UserFeeTier {
+                user_id: 102,
+                maker_fee: dec!(0),
+                taker_fee: dec!(0.01),
+            }],
+        });
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        // the client supplied fees are ignored
+        let order_input = |user_id, side| {
+            OrderInputBuilder::new(&market_name, user_id, side, dec!(10), dec!(1))
+                .fees(dec!(0.5), dec!(0.5))
+                .build()
+        };
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK),
+            )
+            .unwrap();
+        assert_eq!((ask.maker_fee, ask.taker_fee), (dec!(0.01), dec!(0.02)));
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID),
+            )
+            .unwrap();
+        assert_eq!((bid.maker_fee, bid.taker_fee), (dec!(0), dec!(0.01)));
+
+        // the maker pays its default maker rate, the taker its own taker rate
+        assert_eq!(bid.finished_fee, dec!(0.1));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, eth), dec!(9.9));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, usdt), dec!(9.9));
+    }
+
+    #[test]
+    fn test_maker_rebate() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+        let fee_account = 1;
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+        balance_manager.add(fee_account, BalanceType::AVAILABLE, usdt, &dec!(0.08));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let settings = Settings {
+            fee_account_id: fee_account,
+            ..Default::default()
+        };
+        let mut market = Market::new(&get_simple_market_config(), &settings, balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, maker_fee| {
+            OrderInputBuilder::new(&market_name, user_id, side, dec!(10), dec!(1))
+                .fees(dec!(0.01), maker_fee)
+                .build()
+        };
+        let quote_total = balance_manager.status(usdt).total;
+
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, dec!(-0.01)),
+            )
+            .unwrap();
+        let mut put_bid = |market: &mut Market, balance_manager: &mut BalanceManager| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    OrderInput {
+                        amount: dec!(5),
+                        ..order_input(102, OrderSide::BID, dec!(0))
+                    },
+                )
+                .unwrap()
+        };
+        put_bid(&mut market, balance_manager);
+
+        // the maker receives the quote of the trade plus a rebate of 5 * 0.01
+        assert_eq!(market.get(ask.id).unwrap().finished_fee, dec!(-0.05));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, usdt), dec!(5.05));
+        assert_eq!(balance_manager.get(fee_account, BalanceType::AVAILABLE, usdt), dec!(0.03));
+        assert_eq!(balance_manager.status(usdt).total, quote_total);
+
+        // the second rebate is capped by the balance of the fee account
+        put_bid(&mut market, balance_manager);
+        assert!(market.get(ask.id).is_none());
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, usdt), dec!(10.08));
+        assert_eq!(balance_manager.get(fee_account, BalanceType::AVAILABLE, usdt), dec!(0));
+        assert_eq!(balance_manager.status(usdt).total, quote_total);
+    }
+
+    #[test]
+    fn test_fee_account() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+        let fee_account = 1;
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let settings = Settings {
+            fee_account_id: fee_account,
+            ..Default::default()
+        };
+        let mut market = Market::new(&get_simple_market_config(), &settings, balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .fees(dec!(0.02), dec!(0.01))
+                .build()
+        };
+        let base_total = balance_manager.status(eth).total;
+        let quote_total = balance_manager.status(usdt).total;
+
+        let mut put = |market: &mut Market, balance_manager: &mut BalanceManager, input| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    input,
+                )
+                .unwrap()
+        };
+        for price in [dec!(1), dec!(1.5), dec!(2)] {
+            put(
+                &mut market,
+                balance_manager,
+                order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(10), price),
+            );
+        }
+        // a bid sweeping the asks, three trades of 10 ETH
+        let bid = put(
+            &mut market,
+            balance_manager,
+            order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(30), dec!(2)),
+        );
+        assert_eq!(bid.finished_base, dec!(30));
+        assert_eq!(bid.finished_fee, dec!(0.6));
+
+        // the taker pays 0.02 of 30 ETH, the maker 0.01 of 45 USDT
+        assert_eq!(balance_manager.get(fee_account, BalanceType::AVAILABLE, eth), dec!(0.6));
+        assert_eq!(balance_manager.get(fee_account, BalanceType::AVAILABLE, usdt), dec!(0.45));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, eth), dec!(29.4));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, usdt), dec!(44.55));
+        assert_eq!(balance_manager.status(eth).total, base_total);
+        assert_eq!(balance_manager.status(usdt).total, quote_total);
+    }
+
+    #[test]
+    fn test_fee_discount() {
+        let mut update_controller = BalanceUpdateController::new();
+        let mut assets = get_simple_asset_config(8);
+        assets.push(config::Asset {
+            id: "DIF".to_string(),
+            symbol: "DIF".to_string(),
+            rollup_token_id: 2,
+            prec_save: 2,
+            prec_show: 2,
+            ..Default::default()
+        });
+        let balance_manager = &mut get_simple_balance_manager(assets);
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+        let dif = "DIF";
+        let fee_account = 1;
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, dif, &dec!(10));
+
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        // 1 ETH of fee is paid with 100 DIF, 25% off
+        let mut fee_manager = FeeManager::new(&config::FeeSettings {
+            discount: Some(config::FeeDiscountSettings {
+                asset: dif.to_string(),
+                discount: dec!(0.25),
+                prices: vec![(eth.clone(), dec!(100))].into_iter().collect(),
+            }),
+            ..Default::default()
+        });
+        fee_manager.set_fee_discount(102, true);
+        let settings = Settings {
+            fee_account_id: fee_account,
+            ..Default::default()
+        };
+        let mut market = Market::new(&get_simple_market_config(), &settings, balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, amount| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, dec!(1))
+                .fees(dec!(0.01), dec!(0))
+                .build()
+        };
+        let mut put =
+            |market: &mut Market, balance_manager: &mut BalanceManager, persistor: &mut crate::persist::MemBasedPersistor, input| {
+                market
+                    .put_order(
+                        sequencer,
+                        balance_manager.into(),
+                        &mut update_controller,
+                        &fee_manager,
+                        persistor,
+                        input,
+                    )
+                    .unwrap()
+            };
+
+        // enough discount asset, the fee of 0.01 ETH is paid with 0.75 DIF
+        put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(101, OrderSide::ASK, dec!(1)),
+        );
+        let bid = put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(102, OrderSide::BID, dec!(1)),
+        );
+        assert_eq!(bid.finished_fee, dec!(0));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, eth), dec!(1));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, dif), dec!(9.25));
+        assert_eq!(balance_manager.get(fee_account, BalanceType::AVAILABLE, dif), dec!(0.75));
+        let trade = persistor
+            .messages
+            .iter()
+            .find_map(|msg| match msg {
+                Message::TradeMessage(trade) => Some(trade.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(trade.bid_fee, dec!(0));
+        assert_eq!(
+            trade.discount_fee,
+            Some(DiscountFee {
+                asset: dif.to_string(),
+                amount: dec!(0.75),
+            })
+        );
+
+        // the discount asset runs out on the third trade, whose fee is paid in ETH
+        for _ in 0..3 {
+            put(
+                &mut market,
+                balance_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, dec!(5)),
+            );
+        }
+        let bid = put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(102, OrderSide::BID, dec!(15)),
+        );
+        assert_eq!(bid.finished_fee, dec!(0.05));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, eth), dec!(15.95));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, dif), dec!(1.75));
+        assert_eq!(balance_manager.get(fee_account, BalanceType::AVAILABLE, dif), dec!(8.25));
+        assert_eq!(balance_manager.get(fee_account, BalanceType::AVAILABLE, eth), dec!(0.05));
+
+        // 0.0001 ETH of fee is 0.0075 DIF, which rounds to zero, so it is paid in ETH
+        put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(101, OrderSide::ASK, dec!(0.01)),
+        );
+        let bid = put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(102, OrderSide::BID, dec!(0.01)),
+        );
+        assert_eq!(bid.finished_fee, dec!(0.0001));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, dif), dec!(1.75));
+        assert_eq!(balance_manager.get(fee_account, BalanceType::AVAILABLE, eth), dec!(0.0501));
+    }
+
+    #[test]
+    fn test_fee_rounding() {
+        // returns the (bid_fee, ask_fee) of trading 0.01 ETH at `price` with a fee rate of 0.001,
+        // the min fees are in ETH and in USDT
+        let trade_fees = |fee_rounding, price, (min_base_fee, min_quote_fee)| {
+            let mut update_controller = BalanceUpdateController::new();
+            let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(4));
+            balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+            balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100));
+
+            let sequencer = &mut Sequencer::default();
+            let fee_manager = FeeManager::default();
+            let mut persistor = crate::persist::DummyPersistor::default();
+            let market_conf = config::Market {
+                amount_prec: 2,
+                price_prec: 0,
+                fee_rounding,
+                min_base_fee,
+                min_quote_fee,
+                ..get_simple_market_config()
+            };
+            let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+            let order_input = |user_id, side| {
+                OrderInputBuilder::new(market.name, user_id, side, dec!(0.01), price)
+                    .fees(dec!(0.001), dec!(0.001))
+                    .build()
+            };
+            let (ask, bid) = (order_input(101, OrderSide::ASK), order_input(102, OrderSide::BID));
+            let mut put = |market: &mut Market, input| {
+                market
+                    .put_order(
+                        sequencer,
+                        balance_manager.into(),
+                        &mut update_controller,
+                        &fee_manager,
+                        &mut persistor,
+                        input,
+                    )
+                    .unwrap()
+            };
+            let ask = put(&mut market, ask);
+            let bid = put(&mut market, bid);
+            assert_eq!(bid.finished_base, dec!(0.01));
+            assert!(market.get(ask.id).is_none());
+            let ask_fee = dec!(0.01) * price - balance_manager.get(101, BalanceType::AVAILABLE, &MockAsset::USDT.id());
+            (bid.finished_fee, ask_fee)
+        };
+
+        // 0.00001 of fee is rounded down to zero at the precision of 4
+        let (one, no_min) = (dec!(1), (None, None));
+        assert_eq!(trade_fees(config::FeeRounding::ToZero, one, no_min), (dec!(0), dec!(0)));
+        assert_eq!(
+            trade_fees(config::FeeRounding::MidpointNearestEven, one, no_min),
+            (dec!(0), dec!(0))
+        );
+        assert_eq!(
+            trade_fees(config::FeeRounding::AwayFromZero, one, no_min),
+            (dec!(0.0001), dec!(0.0001))
+        );
+        assert_eq!(
+            trade_fees(config::FeeRounding::AwayFromZero, one, (Some(dec!(0.001)), Some(dec!(0.001)))),
+            (dec!(0.001), dec!(0.001))
+        );
+        // the min fee is capped at the traded amount
+        assert_eq!(
+            trade_fees(config::FeeRounding::AwayFromZero, one, (Some(dec!(1)), Some(dec!(1)))),
+            (dec!(0.01), dec!(0.01))
+        );
+        // at 1000 USDT the bid pays 0.00001 ETH and the ask 0.01 USDT, each raised to the min fee of its asset
+        assert_eq!(
+            trade_fees(config::FeeRounding::AwayFromZero, dec!(1000), no_min),
+            (dec!(0.0001), dec!(0.01))
+        );
+        assert_eq!(
+            trade_fees(config::FeeRounding::AwayFromZero, dec!(1000), (Some(dec!(0.001)), Some(dec!(0.5)))),
+            (dec!(0.001), dec!(0.5))
+        );
+        // only the side below its min fee is raised
+        assert_eq!(
+            trade_fees(
+                config::FeeRounding::AwayFromZero,
+                dec!(1000),
+                (Some(dec!(0.001)), Some(dec!(0.001)))
+            ),
+            (dec!(0.001), dec!(0.01))
+        );
+    }
+
+    #[test]
+    fn test_update_fees() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let market_conf = config::Market {
+            default_maker_fee: dec!(0.01),
+            default_taker_fee: dec!(0.02),
+            ..get_simple_market_config()
+        };
+        let settings = Settings {
+            use_market_default_fees: true,
+            ..Default::default()
+        };
+        let mut market = Market::new(&market_conf, &settings, balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        // placed without fees
+        let order_input = |user_id, side| OrderInputBuilder::new(&market_name, user_id, side, dec!(10), dec!(1)).build();
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK),
+            )
+            .unwrap();
+        assert_eq!((ask.maker_fee, ask.taker_fee), (dec!(0.01), dec!(0.02)));
+
+        assert_eq!(market.update_fees(dec!(0.03), dec!(0.02)), Err(MarketError::InvalidFee));
+        market.update_fees(dec!(0), dec!(0.05)).unwrap();
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID),
+            )
+            .unwrap();
+        assert_eq!((bid.maker_fee, bid.taker_fee), (dec!(0), dec!(0.05)));
+
+        // the resting maker still pays the fee it was placed with, the taker pays the new one
+        assert_eq!(bid.finished_fee, dec!(0.5));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, usdt), dec!(9.9));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, eth), dec!(9.5));
+    }
+
+    #[test]
+    fn test_trade_fee_records() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let eth = &MockAsset::ETH.id();
+        let usdt = &MockAsset::USDT.id();
+
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side| {
+            OrderInputBuilder::new(&market_name, user_id, side, dec!(10), dec!(1))
+                .fees(dec!(0.02), dec!(0.01))
+                .build()
+        };
+        let mut put = |market: &mut Market, persistor: &mut crate::persist::MemBasedPersistor, input| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    persistor,
+                    input,
+                )
+                .unwrap()
+        };
+        let fees = |persistor: &crate::persist::MemBasedPersistor| -> Vec<TradeFeeRecord> {
+            persistor
+                .messages
+                .iter()
+                .filter_map(|msg| match msg {
+                    Message::FeeMessage(fee) => Some(*fee.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // the taker is bid
+        let ask = put(&mut market, &mut persistor, order_input(101, OrderSide::ASK));
+        let bid = put(&mut market, &mut persistor, order_input(102, OrderSide::BID));
+        let records = fees(&persistor);
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].user_id, records[0].order_id, records[0].role, records[0].asset.as_str()),
+            (101, ask.id, MarketRole::MAKER, usdt.as_str())
+        );
+        assert_eq!((records[0].amount, records[0].rate), (dec!(0.1), dec!(0.01)));
+        assert_eq!(
+            (records[1].user_id, records[1].order_id, records[1].role, records[1].asset.as_str()),
+            (102, bid.id, MarketRole::TAKER, eth.as_str())
+        );
+        assert_eq!((records[1].amount, records[1].rate), (dec!(0.2), dec!(0.02)));
+        assert!(records.iter().all(|fee| fee.trade_id == records[0].trade_id));
+
+        // the taker is ask
+        persistor.messages.clear();
+        let bid = put(&mut market, &mut persistor, order_input(102, OrderSide::BID));
+        let ask = put(&mut market, &mut persistor, order_input(101, OrderSide::ASK));
+        let records = fees(&persistor);
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].user_id, records[0].order_id, records[0].role, records[0].asset.as_str()),
+            (101, ask.id, MarketRole::TAKER, usdt.as_str())
+        );
+        assert_eq!((records[0].amount, records[0].rate), (dec!(0.2), dec!(0.02)));
+        assert_eq!(
+            (records[1].user_id, records[1].order_id, records[1].role, records[1].asset.as_str()),
+            (102, bid.id, MarketRole::MAKER, eth.as_str())
+        );
+        assert_eq!((records[1].amount, records[1].rate), (dec!(0.1), dec!(0.01)));
+    }
+
+    #[test]
+    fn test_depth_levels() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        for (amount, price) in [(dec!(1), dec!(11)), (dec!(2), dec!(11)), (dec!(3), dec!(12))] {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    OrderInputBuilder::new(&market_name, 101, OrderSide::ASK, amount, price).build(),
+                )
+                .unwrap();
+        }
+
+        let depth = market.depth(usize::MAX, &dec!(0)).unwrap();
+        assert!(depth.bids.is_empty());
+        assert_eq!(
+            depth.asks,
+            vec![
+                PriceInfo {
+                    price: dec!(11),
+                    amount: dec!(3),
+                    order_count: 2,
+                    cumulative_amount: dec!(3),
+                    cumulative_quote: dec!(33),
+                },
+                PriceInfo {
+                    price: dec!(12),
+                    amount: dec!(3),
+                    order_count: 1,
+                    cumulative_amount: dec!(6),
+                    cumulative_quote: dec!(69),
+                },
+            ]
+        );
+        // both levels are merged into the level of 12
+        let depth = market.depth(usize::MAX, &dec!(2)).unwrap();
+        assert_eq!(
+            depth.asks,
+            vec![PriceInfo {
+                price: dec!(12),
+                amount: dec!(6),
+                order_count: 3,
+                cumulative_amount: dec!(6),
+                cumulative_quote: dec!(69),
+            }]
+        );
+        let depth = market.depth(1, &dec!(0)).unwrap();
+        assert_eq!(depth.asks.len(), 1);
+        assert_eq!(depth.asks[0].order_count, 2);
+    }
+
+    #[test]
+    fn test_depth_interval() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        for (user_id, side, price) in [
+            (101, OrderSide::ASK, dec!(10.01)),
+            (101, OrderSide::ASK, dec!(10.02)),
+            (101, OrderSide::ASK, dec!(10.15)),
+            (102, OrderSide::BID, dec!(9.99)),
+            (102, OrderSide::BID, dec!(9.91)),
+            (102, OrderSide::BID, dec!(9.85)),
+        ] {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    OrderInputBuilder::new(&market_name, user_id, side, dec!(1), price).build(),
+                )
+                .unwrap();
+        }
+        let prices = |levels: &Vec<PriceInfo>| levels.iter().map(|level| level.price).collect::<Vec<_>>();
+
+        // one tick keeps the levels as they are
+        let depth = market.depth(usize::MAX, &dec!(0.01)).unwrap();
+        assert_eq!(prices(&depth.asks), vec![dec!(10.01), dec!(10.02), dec!(10.15)]);
+        assert_eq!(prices(&depth.bids), vec![dec!(9.99), dec!(9.91), dec!(9.85)]);
+
+        // ten ticks, asks round up and bids round down
+        let depth = market.depth(usize::MAX, &dec!(0.1)).unwrap();
+        assert_eq!(prices(&depth.asks), vec![dec!(10.1), dec!(10.2)]);
+        assert_eq!(depth.asks[0].amount, dec!(2));
+        assert_eq!(prices(&depth.bids), vec![dec!(9.9), dec!(9.8)]);
+        assert_eq!(depth.bids[0].amount, dec!(2));
+        let depth = market.depth(1, &dec!(0.1)).unwrap();
+        assert_eq!((depth.asks.len(), depth.bids.len()), (1, 1));
+
+        // a huge interval merges a whole side
+        let depth = market.depth(usize::MAX, &dec!(1000000)).unwrap();
+        assert_eq!((depth.asks.len(), depth.asks[0].amount, depth.asks[0].order_count), (1, dec!(3), 3));
+        assert_eq!((depth.bids.len(), depth.bids[0].amount, depth.bids[0].order_count), (1, dec!(3), 3));
+
+        for interval in [dec!(-1), dec!(0.001)] {
+            assert_eq!(market.depth(usize::MAX, &interval).err(), Some(MarketError::InvalidDepthInterval));
+        }
+    }
+
+    #[test]
+    fn test_depth_updates() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, amount, price| OrderInputBuilder::new(&market_name, user_id, side, amount, price).build();
+        let levels = |depth: &MarketDepth| -> BTreeMap<(bool, Decimal), Decimal> {
+            let asks = depth.asks.iter().map(|info| ((true, info.price), info.amount));
+            let bids = depth.bids.iter().map(|info| ((false, info.price), info.amount));
+            asks.chain(bids).collect()
+        };
+
+        for (amount, price) in [(dec!(1), dec!(11)), (dec!(2), dec!(11))] {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input(101, OrderSide::ASK, amount, price),
+                )
+                .unwrap();
+        }
+        let snapshot = market.depth(usize::MAX, &dec!(0)).unwrap();
+        assert_eq!(snapshot.seq, 2);
+        persistor.messages.clear();
+
+        let ask = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, dec!(3), dec!(12)),
+            )
+            .unwrap();
+        let bid = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(5), dec!(10)),
+            )
+            .unwrap();
+        // fills the first ask and a part of the second one
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(2), dec!(11)),
+            )
+            .unwrap();
+        // sweeps the level of 11 and rests at 11
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(3), dec!(11)),
+            )
+            .unwrap();
+        market
+            .reduce_order(balance_manager.into(), &mut persistor, bid.id, dec!(2))
+            .unwrap();
+        market.cancel(balance_manager.into(), &mut persistor, ask.id, 101).unwrap();
+
+        let mut book = levels(&snapshot);
+        let mut seq = snapshot.seq;
+        let mut checksums = Vec::new();
+        for msg in persistor.messages.iter() {
+            if let Message::DepthUpdateMessage(update) = msg {
+                checksums.extend(update.checksum);
+                seq += 1;
+                assert_eq!(update.seq, seq);
+                assert_eq!(update.market, market_name);
+                let key = (update.side == OrderSide::ASK, update.price);
+                if update.new_amount.is_zero() {
+                    book.remove(&key);
+                } else {
+                    book.insert(key, update.new_amount);
+                }
+            }
+        }
+        let depth = market.depth(usize::MAX, &dec!(0)).unwrap();
+        assert_eq!(depth.seq, seq);
+        assert_eq!(book, levels(&depth));
+        // one checksum for each of the six operations, the matching ones included
+        assert_eq!(checksums.len(), 6);
+        assert_eq!(checksums.last(), Some(&depth.checksum));
+        assert_eq!(
+            book.into_iter().collect::<Vec<_>>(),
+            vec![((false, dec!(10)), dec!(3)), ((false, dec!(11)), dec!(2))]
+        );
+    }
+
+    #[test]
+    fn test_ticker() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let mut put = |market: &mut Market, user_id, side, amount, price| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    OrderInputBuilder::new(&market_name, user_id, side, amount, price).build(),
+                )
+                .unwrap()
+        };
+
+        let ticker = market.ticker();
+        assert_eq!(ticker.name, market.name);
+        assert_eq!((ticker.best_ask, ticker.best_ask_amount), (None, None));
+        assert_eq!((ticker.best_bid, ticker.best_bid_amount), (None, None));
+        assert_eq!((ticker.last_price, ticker.mid_price, ticker.spread), (None, None, None));
+
+        put(&mut market, 101, OrderSide::ASK, dec!(1), dec!(12));
+        put(&mut market, 101, OrderSide::ASK, dec!(2), dec!(12));
+        put(&mut market, 101, OrderSide::ASK, dec!(3), dec!(13));
+        let ticker = market.ticker();
+        assert_eq!((ticker.best_ask, ticker.best_ask_amount), (Some(dec!(12)), Some(dec!(3))));
+        assert_eq!((ticker.best_bid, ticker.best_bid_amount), (None, None));
+        assert_eq!((ticker.mid_price, ticker.spread), (None, None));
+
+        put(&mut market, 102, OrderSide::BID, dec!(4), dec!(10));
+        let ticker = market.ticker();
+        assert_eq!((ticker.best_bid, ticker.best_bid_amount), (Some(dec!(10)), Some(dec!(4))));
+        assert_eq!((ticker.mid_price, ticker.spread), (Some(dec!(11)), Some(dec!(2))));
+        assert_eq!(ticker.last_price, None);
+
+        // takes the level of 12
+        put(&mut market, 102, OrderSide::BID, dec!(3), dec!(12));
+        let ticker = market.ticker();
+        assert_eq!(ticker.last_price, Some(dec!(12)));
+        assert_eq!((ticker.best_ask, ticker.best_ask_amount), (Some(dec!(13)), Some(dec!(3))));
+        assert_eq!((ticker.mid_price, ticker.spread), (Some(dec!(11.5)), Some(dec!(3))));
+    }
+
+    #[test]
+    fn test_recent_trades() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let market_conf = config::Market {
+            recent_trades: 3,
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let mut put = |market: &mut Market, user_id, side, amount, price| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    OrderInputBuilder::new(&market_name, user_id, side, amount, price).build(),
+                )
+                .unwrap()
+        };
+
+        assert!(market.recent_trades(10).is_empty());
+        put(&mut market, 101, OrderSide::ASK, dec!(5), dec!(10));
+        for amount in [dec!(1), dec!(2), dec!(1)] {
+            put(&mut market, 102, OrderSide::BID, amount, dec!(10));
+        }
+        let trades = market.recent_trades(10);
+        assert_eq!(
+            trades.iter().map(|trade| trade.amount).collect::<Vec<_>>(),
+            vec![dec!(1), dec!(2), dec!(1)]
+        );
+        assert!(trades
+            .iter()
+            .all(|trade| trade.taker_side == OrderSide::BID && trade.price == dec!(10)));
+        assert!(trades[0].id > trades[1].id && trades[1].id > trades[2].id);
+
+        // the oldest trade is evicted
+        put(&mut market, 102, OrderSide::BID, dec!(3), dec!(9));
+        put(&mut market, 101, OrderSide::ASK, dec!(3), dec!(9));
+        let trades = market.recent_trades(10);
+        assert_eq!(trades.len(), 3);
+        assert_eq!(
+            (trades[0].taker_side, trades[0].price, trades[0].amount, trades[0].quote_amount),
+            (OrderSide::ASK, dec!(9), dec!(3), dec!(27))
+        );
+        assert_eq!((trades[1].amount, trades[2].amount), (dec!(1), dec!(2)));
+        assert_eq!(market.recent_trades(1), trades[..1].to_vec());
+    }
+
+    #[test]
+    fn test_orderbook_checksum() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let mut other = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let mut put = |market: &mut Market, user_id, side, amount, price| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    OrderInputBuilder::new(&market_name, user_id, side, amount, price).build(),
+                )
+                .unwrap()
+        };
+
+        assert_eq!(market.checksum_payload(), ";");
+        assert_eq!(market.orderbook_checksum(), crc32fast::hash(b";"));
+
+        // the same book, with and without trailing zeros
+        put(&mut market, 101, OrderSide::ASK, dec!(1.0), dec!(11.50));
+        put(&mut market, 101, OrderSide::ASK, dec!(2), dec!(11.5));
+        put(&mut market, 101, OrderSide::ASK, dec!(1.000), dec!(12.00));
+        put(&mut other, 101, OrderSide::ASK, dec!(3), dec!(11.5));
+        put(&mut other, 101, OrderSide::ASK, dec!(1), dec!(12));
+        assert_eq!(market.checksum_payload(), "11.5:3,12:1;");
+        assert_eq!(market.orderbook_checksum(), other.orderbook_checksum());
+
+        put(&mut market, 102, OrderSide::BID, dec!(0.50), dec!(10.10));
+        assert_eq!(market.checksum_payload(), "11.5:3,12:1;10.1:0.5");
+        assert_eq!(market.orderbook_checksum(), crc32fast::hash(b"11.5:3,12:1;10.1:0.5"));
+        assert_ne!(market.orderbook_checksum(), other.orderbook_checksum());
+        assert_eq!(market.depth(1, &dec!(0)).unwrap().checksum, market.orderbook_checksum());
+
+        // only the top levels are covered
+        market.checksum_levels = 1;
+        assert_eq!(market.checksum_payload(), "11.5:3;10.1:0.5");
+    }
+
+    // the levels built by scanning the resting orders, which the maintained levels are checked against
+    fn scan_levels<'a>(
+        orders: impl Iterator<Item = &'a OrderRc>,
+        limit: usize,
+        group_fn: impl Fn(Decimal) -> Decimal,
+    ) -> Vec<(Decimal, Decimal, usize)> {
+        let mut levels: Vec<(Decimal, Decimal, usize)> = Vec::new();
+        for order_rc in orders {
+            let order = order_rc.borrow();
+            let price = group_fn(order.price);
+            match levels.last_mut() {
+                Some(level) if level.0 == price => {
+                    level.1 += order.remain;
+                    level.2 += 1;
+                }
+                _ if levels.len() == limit => break,
+                _ => levels.push((price, order.remain, 1)),
+            }
+        }
+        levels
+    }
+
+    fn depth_levels(levels: &[PriceInfo]) -> Vec<(Decimal, Decimal, usize)> {
+        levels.iter().map(|level| (level.price, level.amount, level.order_count)).collect()
+    }
+
+    #[test]
+    fn test_maintained_levels() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, amount, price| OrderInputBuilder::new(&market_name, user_id, side, amount, price).build();
+        let check = |market: &Market| {
+            for (limit, interval) in [(usize::MAX, dec!(0)), (2, dec!(0)), (usize::MAX, dec!(1)), (1, dec!(5))] {
+                let depth = market.depth(limit, &interval).unwrap();
+                let asks = scan_levels(market.asks.values(), limit, |price| {
+                    if interval.is_zero() {
+                        price
+                    } else {
+                        (price / interval).ceil() * interval
+                    }
+                });
+                let bids = scan_levels(market.bids.values(), limit, |price| {
+                    if interval.is_zero() {
+                        price
+                    } else {
+                        (price / interval).floor() * interval
+                    }
+                });
+                assert_eq!(depth_levels(&depth.asks), asks);
+                assert_eq!(depth_levels(&depth.bids), bids);
+            }
+        };
+
+        let mut orders = Vec::new();
+        for (side, amount, price) in [
+            (OrderSide::ASK, dec!(1), dec!(11)),
+            (OrderSide::ASK, dec!(2), dec!(11)),
+            (OrderSide::ASK, dec!(3), dec!(12.5)),
+            (OrderSide::ASK, dec!(4), dec!(13)),
+            (OrderSide::BID, dec!(5), dec!(10)),
+            (OrderSide::BID, dec!(1), dec!(9.5)),
+            (OrderSide::BID, dec!(2), dec!(9)),
+        ] {
+            let user_id = if side == OrderSide::ASK { 101 } else { 102 };
+            let order = market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input(user_id, side, amount, price),
+                )
+                .unwrap();
+            orders.push(order);
+        }
+        check(&market);
+        assert_eq!(market.ask_levels.get(&dec!(11)), Some(&(dec!(3), 2)));
+
+        // fills a level and a part of the next one
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(4), dec!(12.5)),
+            )
+            .unwrap();
+        check(&market);
+        assert_eq!(market.ask_levels.get(&dec!(11)), None);
+        assert_eq!(market.ask_levels.get(&dec!(12.5)), Some(&(dec!(2), 1)));
+
+        market
+            .reduce_order(balance_manager.into(), &mut persistor, orders[4].id, dec!(1))
+            .unwrap();
+        check(&market);
+        market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                orders[3].id,
+                None,
+                Some(dec!(3)),
+            )
+            .unwrap();
+        check(&market);
+        market
+            .amend_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                orders[6].id,
+                Some(dec!(9.5)),
+                None,
+            )
+            .unwrap();
+        check(&market);
+        assert_eq!(market.bid_levels.get(&dec!(9.5)), Some(&(dec!(3), 2)));
+        market.cancel(balance_manager.into(), &mut persistor, orders[5].id, 102).unwrap();
+        check(&market);
+        assert_eq!(market.level_amount(OrderSide::BID, dec!(9.5)), dec!(2));
+
+        market.cancel_all_for_user(balance_manager.into(), &mut persistor, 101).unwrap();
+        market.cancel_all_for_user(balance_manager.into(), &mut persistor, 102).unwrap();
+        assert!(market.ask_levels.is_empty() && market.bid_levels.is_empty());
+    }
+
+    #[test]
+    fn test_status_totals() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        for user_id in 101..105 {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000000));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000000));
+        }
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let check = |market: &Market| {
+            let status = market.status();
+            let sum = |orders: Vec<&OrderRc>| -> (Decimal, Decimal) {
+                orders
+                    .iter()
+                    .map(|order| (order.borrow().remain, order.borrow().frozen))
+                    .fold((Decimal::zero(), Decimal::zero()), |acc, item| (acc.0 + item.0, acc.1 + item.1))
+            };
+            assert_eq!((status.ask_amount, status.ask_frozen), sum(market.asks.values().collect()));
+            assert_eq!((status.bid_amount, status.bid_frozen), sum(market.bids.values().collect()));
+            assert_eq!(status.best_ask, market.asks.values().next().map(|order| order.borrow().price));
+            assert_eq!(status.best_bid, market.bids.values().next().map(|order| order.borrow().price));
+            let users = market.orders.values().map(|order| order.borrow().user).unique().count();
+            assert_eq!(status.user_count, users);
+        };
+
+        let status = market.status();
+        assert_eq!(
+            (status.best_ask, status.best_bid, status.last_price, status.user_count),
+            (None, None, None, 0)
+        );
+
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..500 {
+            let order_ids = market.orders.keys().copied().collect::<Vec<_>>();
+            match rng.gen_range(0..10) {
+                0..=1 if !order_ids.is_empty() => {
+                    let order = market.get(order_ids[rng.gen_range(0..order_ids.len())]).unwrap();
+                    market.cancel(balance_manager.into(), &mut persistor, order.id, order.user).unwrap();
+                }
+                2 if !order_ids.is_empty() => {
+                    let order = market.get(order_ids[rng.gen_range(0..order_ids.len())]).unwrap();
+                    let reduce_by = min(order.remain, dec!(0.5));
+                    market
+                        .reduce_order(balance_manager.into(), &mut persistor, order.id, reduce_by)
+                        .unwrap();
+                }
+                _ => {
+                    let side = if rng.gen::<bool>() { OrderSide::ASK } else { OrderSide::BID };
+                    market
+                        .put_order(
+                            sequencer,
+                            balance_manager.into(),
+                            &mut update_controller,
+                            &fee_manager,
+                            &mut persistor,
+                            OrderInputBuilder::new(
+                                &market_name,
+                                rng.gen_range(101..105),
+                                side,
+                                Decimal::new(rng.gen_range(1..50), 1),
+                                Decimal::new(rng.gen_range(95..106), 0),
+                            )
+                            .build(),
+                        )
+                        .unwrap();
+                }
+            }
+            check(&market);
+        }
+        assert!(market.trade_count > 0);
+        assert_eq!(market.status().last_price, Some(market.price));
+    }
+
+    #[test]
+    fn test_depth_large_book() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        let sequencer = &mut Sequencer::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let template = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut crate::persist::DummyPersistor::default(),
+                OrderInputBuilder::new(market.name, 101, OrderSide::ASK, dec!(1), dec!(100)).build(),
+            )
+            .unwrap();
+        // 20k orders on 500 levels of each side
+        for i in 0..20_000u64 {
+            let mut order = template;
+            order.id = 1_000_000 + i;
+            order.priority = order.id;
+            if i % 2 == 0 {
+                order.price = dec!(100) + Decimal::new((i % 1000) as i64, 2);
+            } else {
+                order.side = OrderSide::BID;
+                order.price = dec!(99) - Decimal::new((i % 1000) as i64, 2);
+            }
+            market.insert_order_into_orderbook(order);
+        }
+
+        let id_fn = |price: Decimal| price;
+        let depth = market.depth(20, &dec!(0)).unwrap();
+        assert_eq!(depth_levels(&depth.asks), scan_levels(market.asks.values(), 20, id_fn));
+        assert_eq!(depth_levels(&depth.bids), scan_levels(market.bids.values(), 20, id_fn));
+        assert_eq!(depth.asks.len(), 20);
+        assert_eq!(depth.asks[19].order_count, 20);
+    }
+
+    #[test]
+    fn test_many_trades() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000000));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100000000));
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side| OrderInputBuilder::new(&market_name, user_id, side, dec!(1), dec!(100)).build();
+
+        let rounds = 1000;
+        let mut entries = None;
+        for _ in 0..rounds {
+            for (user_id, side) in [(101, OrderSide::ASK), (102, OrderSide::BID)] {
+                market
+                    .put_order(
+                        sequencer,
+                        balance_manager.into(),
+                        &mut update_controller,
+                        &fee_manager,
+                        &mut persistor,
+                        order_input(user_id, side),
+                    )
+                    .unwrap();
+            }
+            entries.get_or_insert(balance_manager.balances.len());
+        }
+        assert_eq!(market.trade_count, rounds);
+        assert!(market.orders.is_empty());
+        // the balances of the users are kept in the same entries, however many trades are made
+        assert_eq!(Some(balance_manager.balances.len()), entries);
+        assert_eq!(
+            balance_manager.get(102, BalanceType::AVAILABLE, &MockAsset::ETH.id()),
+            Decimal::from(rounds)
+        );
+    }
+
+    #[test]
+    fn test_market_events() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+
+        let created = market.created_event();
+        assert_eq!(created.market, market_name);
+        assert_eq!(
+            created.kind,
+            MarketEventKind::Created {
+                base: MockAsset::ETH.id(),
+                quote: MockAsset::USDT.id(),
+                amount_prec: 4,
+                price_prec: 2,
+                fee_prec: 2,
+            }
+        );
+
+        let order_input = |user_id, side, amount, price| OrderInputBuilder::new(&market_name, user_id, side, amount, price).build();
+        for price in [dec!(10), dec!(10), dec!(11)] {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input(101, OrderSide::ASK, dec!(1), price),
+                )
+                .unwrap();
+        }
+        // the second trade at the same price emits nothing
+        for price in [dec!(10), dec!(10), dec!(11)] {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input(102, OrderSide::BID, dec!(1), price),
+                )
+                .unwrap();
+        }
+
+        let trade_ids = persistor
+            .messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::TradeMessage(trade) => Some(trade.id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let events = persistor
+            .messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::MarketEventMessage(event) => Some(event.kind.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(trade_ids.len(), 3);
+        assert_eq!(
+            events,
+            vec![
+                MarketEventKind::PriceUpdated {
+                    price: dec!(10),
+                    trade_id: trade_ids[0],
+                },
+                MarketEventKind::PriceUpdated {
+                    price: dec!(11),
+                    trade_id: trade_ids[2],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_market_state() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, price| OrderInputBuilder::new(&market_name, user_id, side, dec!(1), price).build();
+        let mut orders = Vec::new();
+        for price in [dec!(10), dec!(11), dec!(12)] {
+            let order = market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input(101, OrderSide::ASK, price),
+                )
+                .unwrap();
+            orders.push(order);
+        }
+        persistor.messages.clear();
+
+        // cancel only: new orders and amends are rejected, cancels are accepted
+        market.set_state(&mut persistor, MarketState::CancelOnly, "maintenance".to_string());
+        assert_eq!(market.status().state, MarketState::CancelOnly);
+        let err = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(10)),
+            )
+            .unwrap_err();
+        assert_eq!(err, MarketError::MarketNotOpen);
+        assert_eq!(
+            market
+                .amend_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &mut persistor,
+                    orders[0].id,
+                    Some(dec!(9)),
+                    None,
+                )
+                .unwrap_err(),
+            MarketError::MarketNotOpen
+        );
+        market.cancel(balance_manager.into(), &mut persistor, orders[0].id, 101).unwrap();
+        assert_eq!(market.asks.len(), 2);
+
+        // halted: the resting orders are kept but nothing is accepted
+        market.set_state(&mut persistor, MarketState::Halted, "incident".to_string());
+        assert_eq!(
+            market
+                .cancel(balance_manager.into(), &mut persistor, orders[1].id, 101)
+                .unwrap_err(),
+            MarketError::MarketHalted
+        );
+        assert_eq!(
+            market.cancel_all_for_user(balance_manager.into(), &mut persistor, 101).unwrap_err(),
+            MarketError::MarketHalted
+        );
+        assert_eq!(
+            market
+                .reduce_order(balance_manager.into(), &mut persistor, orders[1].id, dec!(0.5))
+                .unwrap_err(),
+            MarketError::MarketHalted
+        );
+        assert_eq!(market.asks.len(), 2);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &MockAsset::ETH.id()), dec!(2));
+
+        // cancels can be allowed while halted
+        market.cancel_when_halted = true;
+        market.cancel(balance_manager.into(), &mut persistor, orders[1].id, 101).unwrap();
+        assert_eq!(market.asks.len(), 1);
+
+        // the same state emits nothing
+        market.set_state(&mut persistor, MarketState::Halted, "incident".to_string());
+        market.set_state(&mut persistor, MarketState::Open, "resolved".to_string());
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID, dec!(12)),
+            )
+            .unwrap();
+        assert!(market.asks.is_empty());
+        assert_eq!(market.price, dec!(12));
+
+        let events = persistor
+            .messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::MarketEventMessage(event) => Some(event.kind.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                MarketEventKind::Halted {
+                    state: MarketState::CancelOnly,
+                    reason: "maintenance".to_string(),
+                },
+                MarketEventKind::Halted {
+                    state: MarketState::Halted,
+                    reason: "incident".to_string(),
+                },
+                MarketEventKind::Resumed {
+                    reason: "resolved".to_string(),
+                },
+                MarketEventKind::PriceUpdated {
+                    price: dec!(12),
+                    trade_id: market.trade_history[0].id,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drain_all_orders() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        let users = [101, 102, 103];
+        for user_id in users {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, eth, &dec!(100));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, usdt, &dec!(1000));
+        }
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        for (i, user_id) in users.iter().enumerate() {
+            for (side, price) in [(OrderSide::ASK, dec!(12)), (OrderSide::BID, dec!(9))] {
+                market
+                    .put_order(
+                        sequencer,
+                        balance_manager.into(),
+                        &mut update_controller,
+                        &fee_manager,
+                        &mut persistor,
+                        OrderInputBuilder::new(&market_name, *user_id, side, dec!(1) + Decimal::from(i), price + Decimal::from(i))
+                            .client_order_id(Some(i as u64 + 1))
+                            .build(),
+                    )
+                    .unwrap();
+            }
+        }
+        assert_eq!(market.orders.len(), 6);
+        persistor.messages.clear();
+
+        // the drain does not depend on the state
+        market.set_state(&mut persistor, MarketState::Halted, "closing".to_string());
+        assert_eq!(market.drain_all_orders(balance_manager.into(), &mut persistor), 6);
+        for user_id in users {
+            assert_eq!(balance_manager.get(user_id, BalanceType::FREEZE, eth), dec!(0));
+            assert_eq!(balance_manager.get(user_id, BalanceType::FREEZE, usdt), dec!(0));
+            assert_eq!(balance_manager.get(user_id, BalanceType::AVAILABLE, eth), dec!(100));
+            assert_eq!(balance_manager.get(user_id, BalanceType::AVAILABLE, usdt), dec!(1000));
+        }
+        assert!(market.orders.is_empty() && market.users.is_empty() && market.client_ids.is_empty());
+        assert!(market.ask_levels.is_empty() && market.bid_levels.is_empty());
+        assert_eq!(
+            (market.ask_totals, market.bid_totals),
+            (BookTotals::default(), BookTotals::default())
+        );
+        let finished = persistor
+            .messages
+            .iter()
+            .filter(|msg| matches!(msg, Message::OrderMessage(msg) if msg.event == OrderEventType::FINISH))
+            .count();
+        assert_eq!(finished, 6);
+        assert_eq!(market.drain_all_orders(balance_manager.into(), &mut persistor), 0);
+    }
+
+    #[test]
+    fn test_update_params() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(100));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(1000));
+
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, amount, price| OrderInputBuilder::new(&market_name, user_id, side, amount, price).build();
+        let mut ids = Vec::new();
+        for (user_id, side, amount, price) in [
+            (101, OrderSide::ASK, dec!(1), dec!(10.25)),
+            (101, OrderSide::ASK, dec!(1), dec!(11)),
+            (102, OrderSide::BID, dec!(0.25), dec!(9.5)),
+            (102, OrderSide::BID, dec!(0.4), dec!(9)),
+            (102, OrderSide::BID, dec!(1), dec!(9.5)),
+        ] {
+            let order = market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input(user_id, side, amount, price),
+                )
+                .unwrap();
+            ids.push(order.id);
+        }
+        persistor.messages.clear();
+
+        // the same invariants as a new market
+        assert_eq!(
+            market
+                .update_params(balance_manager.into(), &mut persistor, 9, 0, dec!(0.01))
+                .unwrap_err(),
+            MarketError::InvalidPrecision
+        );
+        assert_eq!(
+            market
+                .update_params(balance_manager.into(), &mut persistor, 4, 2, dec!(-1))
+                .unwrap_err(),
+            MarketError::InvalidMinAmount
+        );
+
+        // loosening keeps every order
+        assert!(market
+            .update_params(balance_manager.into(), &mut persistor, 6, 2, dec!(0.001))
+            .unwrap()
+            .is_empty());
+        assert_eq!(market.orders.len(), 5);
+        assert!(persistor.messages.is_empty());
+
+        // tightening cancels the orders with a price or an amount out of the precision, or below the min amount
+        let canceled = market
+            .update_params(balance_manager.into(), &mut persistor, 1, 1, dec!(0.5))
+            .unwrap();
+        assert_eq!(
+            canceled.iter().map(|order| order.id).collect::<Vec<_>>(),
+            vec![ids[0], ids[2], ids[3]]
+        );
+        assert_eq!(market.orders.keys().copied().collect::<Vec<_>>(), vec![ids[1], ids[4]]);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(1));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(9.5));
+        assert_eq!(market.bid_totals.frozen, dec!(9.5));
+        let cancel_events = persistor
+            .messages
+            .iter()
+            .filter(|msg| {
+                matches!(msg, Message::OrderMessage(msg)
+                    if msg.event == OrderEventType::FINISH && msg.cancel_reason == Some(OrderCancelReason::MarketParamsChanged))
+            })
+            .count();
+        assert_eq!(cancel_events, 3);
+
+        // the new precision applies to the new orders
+        let err = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, dec!(1), dec!(12.25)),
+            )
+            .unwrap_err();
+        assert_eq!(err, MarketError::InvalidPricePrecision);
+
+        // a partially filled order whose remain is below the min amount is canceled too
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, dec!(0.8), dec!(9.5)),
+            )
+            .unwrap();
+        assert_eq!(market.get(ids[4]).unwrap().remain, dec!(0.2));
+        let canceled = market
+            .update_params(balance_manager.into(), &mut persistor, 1, 1, dec!(0.5))
+            .unwrap();
+        assert_eq!(canceled.iter().map(|order| order.id).collect::<Vec<_>>(), vec![ids[4]]);
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(0));
+    }
+
+    #[test]
+    fn test_failed_settlement() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        for user_id in [101, 102] {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, eth, &dec!(100));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, usdt, &dec!(1000));
+        }
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side| {
+            OrderInputBuilder::new(&market_name, user_id, side, dec!(2), dec!(10))
+                .fees(dec!(0.001), dec!(0.001))
+                .build()
+        };
+        let maker = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK),
+            )
+            .unwrap();
+
+        // a leg of the next trade is flagged as duplicate, as if it had been processed
+        update_controller
+            .update_user_balance(
+                balance_manager,
+                &mut crate::persist::DummyPersistor::default(),
+                BalanceUpdateParams {
+                    balance_type: BalanceType::AVAILABLE,
+                    business_type: BusinessType::Trade,
+                    user_id: 101,
+                    asset: usdt.to_string(),
+                    business: "trade".to_string(),
+                    business_id: sequencer.get_trade_id() + 1,
+                    market_price: dec!(0),
+                    change: dec!(0),
+                    detail: serde_json::Value::default(),
+                    signature: vec![],
+                },
+            )
+            .unwrap();
+        let balances_before = balance_manager.balances.clone();
+        persistor.messages.clear();
+
+        let taker = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &fee_manager,
+                &mut persistor,
+                order_input(102, OrderSide::BID),
+            )
+            .unwrap();
+        // no balance moved and no history, trade or fee was persisted
+        assert_eq!(balance_manager.balances, balances_before);
+        assert!(persistor.messages.iter().all(|msg| matches!(msg, Message::OrderMessage(_))));
+        let canceled = persistor
+            .messages
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::OrderMessage(msg) if msg.cancel_reason.is_some() => Some((msg.order.id, msg.cancel_reason)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(canceled, vec![(taker.id, Some(OrderCancelReason::SettlementFailed))]);
+        assert_eq!((market.trade_count, sequencer.get_trade_id()), (0, 0));
+        assert_eq!(market.get(maker.id).unwrap().remain, dec!(2));
+        assert_eq!(market.orders.len(), 1);
+    }
+
+    #[test]
+    fn test_finish_reasons() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        for user_id in [101, 102] {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, eth, &dec!(100));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, usdt, &dec!(1000));
+        }
+        let sequencer = &mut Sequencer::default();
+        let fee_manager = FeeManager::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let input = |user_id, side, price, post_only| {
+            OrderInputBuilder::new(&market_name, user_id, side, dec!(1), price)
+                .post_only(post_only)
+                .build()
+        };
+        let inputs = [
+            // filled, both the maker and the taker
+            input(101, OrderSide::ASK, dec!(10), false),
+            input(102, OrderSide::BID, dec!(10), false),
+            // the taker would trade with its own maker
+            input(101, OrderSide::ASK, dec!(10), false),
+            input(101, OrderSide::BID, dec!(10), false),
+            input(102, OrderSide::BID, dec!(10), true),
+            input(102, OrderSide::ASK, dec!(11), false),
+        ];
+        for order_input in inputs {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &fee_manager,
+                    &mut persistor,
+                    order_input,
+                )
+                .unwrap();
+        }
+        market.cancel(balance_manager.into(), &mut persistor, 6, 102).unwrap();
+        market.drain_all_orders(balance_manager.into(), &mut persistor);
+
+        let finished: Vec<_> = persistor
+            .orders()
+            .iter()
+            .filter_map(|msg| Some((msg.order.id, msg.event, msg.finish_reason?, msg.finish_actor?)))
+            .collect();
+        assert_eq!(
+            finished,
+            vec![
+                (1, OrderEventType::FINISH, FinishReason::Filled, OrderActor::System),
+                (2, OrderEventType::FINISH, FinishReason::Filled, OrderActor::System),
+                (4, OrderEventType::FINISH, FinishReason::SelfTrade, OrderActor::System),
+                (5, OrderEventType::FINISH, FinishReason::PostOnlyCross, OrderActor::System),
+                (6, OrderEventType::FINISH, FinishReason::Canceled, OrderActor::User),
+                (3, OrderEventType::FINISH, FinishReason::MarketClosed, OrderActor::Admin),
+            ]
+        );
+        // the other messages carry no reason
+        assert!(persistor
+            .orders()
+            .iter()
+            .all(|msg| msg.event.is_terminal() || msg.finish_reason.is_none() && msg.finish_actor.is_none()));
+    }
+
+    #[test]
+    fn test_query_orders() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        for user_id in [7, 8] {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, eth, &dec!(100));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, usdt, &dec!(1000));
+        }
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        // asks at 20, 22 .. 28 and bids at 11, 13 .. 19, two orders put at each time
+        let mut ids = Vec::new();
+        for i in 0..11u32 {
+            let (user_id, side, price) = match i {
+                10 => (8, OrderSide::ASK, dec!(21)),
+                _ if i % 2 == 0 => (7, OrderSide::ASK, Decimal::from(20 + i)),
+                _ => (7, OrderSide::BID, Decimal::from(10 + i)),
+            };
+            market.clock.fix(TimestampMs(1_600_000_000_000 + (i / 2) as u64 * 1000));
+            let order = market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &FeeManager::default(),
+                    &mut persistor,
+                    OrderInputBuilder::new(&market_name, user_id, side, dec!(1), price).build(),
+                )
+                .unwrap();
+            ids.push(order.id);
+        }
+        assert_eq!(market.trade_count, 0);
+        let query = |market: &Market, query: OrderQuery| {
+            let page = market.query_orders(7, &query);
+            (page.orders.iter().map(|order| order.id).collect::<Vec<_>>(), page.total, page.next)
+        };
+        let pick = |indices: &[usize]| indices.iter().map(|i| ids[*i]).collect::<Vec<_>>();
+
+        let (all, total, next) = query(&market, OrderQuery::default());
+        assert_eq!((all, total, next), (pick(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]), 10, None));
+        let bids = OrderQuery {
+            side: Some(OrderSide::BID),
+            ..Default::default()
+        };
+        assert_eq!(query(&market, bids).0, pick(&[1, 3, 5, 7, 9]));
+        let asks_in_range = OrderQuery {
+            side: Some(OrderSide::ASK),
+            price_range: Some((dec!(22), dec!(26))),
+            ..Default::default()
+        };
+        assert_eq!(query(&market, asks_in_range).0, pick(&[2, 4, 6]));
+        let by_price = OrderQuery {
+            sort_by: OrderSortBy::Price,
+            descending: true,
+            ..Default::default()
+        };
+        assert_eq!(query(&market, by_price).0, pick(&[8, 6, 4, 2, 0, 9, 7, 5, 3, 1]));
+        let bids_in_range = OrderQuery {
+            side: Some(OrderSide::BID),
+            price_range: Some((dec!(12), dec!(30))),
+            sort_by: OrderSortBy::Price,
+            descending: true,
+            ..Default::default()
+        };
+        assert_eq!(query(&market, bids_in_range).0, pick(&[9, 7, 5, 3]));
+        let (page, total, next) = query(
+            &market,
+            OrderQuery {
+                offset: 2,
+                limit: 3,
+                ..Default::default()
+            },
+        );
+        assert_eq!((page, total), (pick(&[2, 3, 4]), 10));
+        assert_eq!(next.unwrap().id, ids[4]);
+        assert!(market.query_orders(9, &OrderQuery::default()).orders.is_empty());
+
+        // the pages by price follow the cursor, the orders canceled in between shift nothing
+        let mut pagination = OrderQuery {
+            sort_by: OrderSortBy::Price,
+            limit: 4,
+            ..Default::default()
+        };
+        let (first, total, next) = query(&market, pagination.clone());
+        assert_eq!((first, total), (pick(&[1, 3, 5, 7]), 10));
+        for i in [7, 9] {
+            market.cancel(balance_manager.into(), &mut persistor, ids[i], 7).unwrap();
+        }
+        pagination.after = next;
+        let (second, total, next) = query(&market, pagination.clone());
+        assert_eq!((second, total), (pick(&[0, 2, 4, 6]), 8));
+        pagination.after = next;
+        let (third, _, next) = query(&market, pagination);
+        assert_eq!((third, next), (pick(&[8]), None));
+    }
+
+    #[test]
+    fn test_exposure_matches_book() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let users = [1, 2, 3, 4];
+        for user_id in users {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1_000));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100_000));
+        }
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::DummyPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+
+        // recomputed from the resting orders
+        let check = |market: &Market| {
+            let mut exposures: BTreeMap<u32, UserExposure> = BTreeMap::new();
+            let mut interest = OpenInterest::default();
+            for order in market.orders.values().map(OrderRc::deep) {
+                let exposure = exposures.entry(order.user).or_default();
+                exposure.order_count += 1;
+                if order.is_ask() {
+                    exposure.frozen_base += order.frozen;
+                    interest.ask_notional += order.remain * order.price;
+                } else {
+                    exposure.frozen_quote += order.frozen;
+                    interest.bid_notional += order.remain * order.price;
+                }
+            }
+            assert_eq!(market.user_exposures, exposures);
+            assert_eq!(market.open_interest(), interest);
+            for user_id in users {
+                assert_eq!(market.user_exposure(user_id), exposures.get(&user_id).copied().unwrap_or_default());
+            }
+        };
+
+        let mut rng = StdRng::seed_from_u64(4349);
+        let mut trades = 0;
+        for _ in 0..2000 {
+            let open = market.orders.values().map(OrderRc::deep).collect::<Vec<_>>();
+            let picked = (!open.is_empty()).then(|| open[rng.gen_range(0..open.len())]);
+            match (rng.gen_range(0..10), picked) {
+                (0 | 1, Some(order)) => {
+                    market.cancel(balance_manager.into(), &mut persistor, order.id, order.user).unwrap();
+                }
+                (2, Some(order)) => {
+                    let reduce_by = (order.remain / dec!(2)).round_dp_with_strategy(market.amount_prec, RoundingStrategy::ToZero);
+                    let _ = market.reduce_order(balance_manager.into(), &mut persistor, order.id, reduce_by);
+                }
+                (3, Some(order)) => {
+                    let new_price = rng.gen::<bool>().then(|| Decimal::new(rng.gen_range(9_000..11_000), 2));
+                    let new_amount = Some(order.amount + Decimal::new(rng.gen_range(-50..50), 2));
+                    let _ = market.amend_order(
+                        sequencer,
+                        balance_manager.into(),
+                        &mut update_controller,
+                        &mut persistor,
+                        order.id,
+                        new_price,
+                        new_amount,
+                    );
+                }
+                _ => {
+                    let trade_count = market.trade_count;
+                    let order_input = OrderInputBuilder::new(
+                        &market_name,
+                        users[rng.gen_range(0..users.len())],
+                        if rng.gen::<bool>() { OrderSide::BID } else { OrderSide::ASK },
+                        Decimal::new(rng.gen_range(1..500), 2),
+                        Decimal::new(rng.gen_range(9_000..11_000), 2),
+                    )
+                    .fees(dec!(0.002), dec!(0.001))
+                    .build();
+                    let _ = market.put_order(
+                        sequencer,
+                        balance_manager.into(),
+                        &mut update_controller,
+                        &FeeManager::default(),
+                        &mut persistor,
+                        order_input,
+                    );
+                    trades += market.trade_count - trade_count;
+                }
+            }
+            check(&market);
+        }
+        assert!(trades > 0);
+        assert!(!market.user_exposures.is_empty());
+
+        assert!(market.drain_all_orders(balance_manager.into(), &mut persistor) > 0);
+        check(&market);
+        assert_eq!(market.open_interest(), OpenInterest::default());
+    }
+
+    #[test]
+    fn test_maker_frozen_follows_remain() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(10));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(10));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        // whole amounts at a fine price, the quote of a trade takes all the digits of the quote asset
+        let market_conf = crate::config::Market {
+            amount_prec: 0,
+            price_prec: 8,
+            min_amount: dec!(1),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), balance_manager).unwrap();
+        let order_input = |user_id, side, amount| OrderInputBuilder::new(market.name, user_id, side, amount, dec!(0.12345679)).build();
+        let mut put = |market: &mut Market, balance_manager: &mut BalanceManager, persistor: &mut _, input| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &FeeManager::default(),
+                    persistor,
+                    input,
+                )
+                .unwrap()
+        };
+        let total = |balance_manager: &BalanceManager, user_id, asset| {
+            balance_manager.get(user_id, BalanceType::AVAILABLE, asset) + balance_manager.get(user_id, BalanceType::FREEZE, asset)
+        };
+
+        // the frozen of a partially filled bid stays at remain * price
+        let bid = put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(102, OrderSide::BID, dec!(7)),
+        );
+        for remain in [dec!(6), dec!(4)] {
+            let amount = market.get(bid.id).unwrap().remain - remain;
+            put(
+                &mut market,
+                balance_manager,
+                &mut persistor,
+                order_input(101, OrderSide::ASK, amount),
+            );
+            let frozen = market.get(bid.id).unwrap().frozen;
+            assert_eq!(frozen, remain * dec!(0.12345679));
+            assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), frozen);
+        }
+
+        // a frozen carrying the residual of an older rounding, e.g. restored from a snapshot. the fill releases it,
+        // it was stranded in the freeze once the remain reached zero
+        market.orders.get(&bid.id).unwrap().borrow_mut().frozen += dec!(0.00000003);
+        balance_manager.frozen(102, usdt, &dec!(0.00000003)).unwrap();
+        put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(101, OrderSide::ASK, dec!(4)),
+        );
+        assert!(market.get(bid.id).is_none());
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(0));
+        assert_eq!(
+            balance_manager.get(102, BalanceType::AVAILABLE, usdt),
+            dec!(10) - dec!(7) * dec!(0.12345679)
+        );
+        let residual = persistor
+            .balances()
+            .into_iter()
+            .filter(|b| b.business == "frozen_residual")
+            .collect::<Vec<_>>();
+        assert_eq!(residual.len(), 1);
+        assert_eq!(residual[0].change.parse::<Decimal>().unwrap(), dec!(0.00000003));
+
+        // the same for an ask maker, then a cancel releases exactly the rest
+        let ask = put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(101, OrderSide::ASK, dec!(2)),
+        );
+        market.orders.get(&ask.id).unwrap().borrow_mut().frozen += dec!(0.00000001);
+        balance_manager.frozen(101, eth, &dec!(0.00000001)).unwrap();
+        put(
+            &mut market,
+            balance_manager,
+            &mut persistor,
+            order_input(102, OrderSide::BID, dec!(1)),
+        );
+        assert_eq!(market.get(ask.id).unwrap().frozen, dec!(1));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(1));
+        market.cancel(balance_manager.into(), &mut persistor, ask.id, 101).unwrap();
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(0));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(0));
+
+        // nothing created or lost
+        assert_eq!(total(balance_manager, 101, eth) + total(balance_manager, 102, eth), dec!(10));
+        assert_eq!(total(balance_manager, 101, usdt) + total(balance_manager, 102, usdt), dec!(10));
+        assert_eq!(total(balance_manager, 101, eth), dec!(2));
+        assert_eq!(total(balance_manager, 101, usdt), dec!(8) * dec!(0.12345679));
+    }
+
+    #[test]
+    fn test_quote_rounded_once() {
+        let mut update_controller = BalanceUpdateController::new();
+        let market_conf = get_simple_market_config();
+        let mut market = Market::new(
+            &market_conf,
+            &Settings::default(),
+            &get_simple_balance_manager(get_simple_asset_config(8)),
+        )
+        .unwrap();
+        // the quote asset saved with fewer digits than the market was created for, a price * amount has 6
+        market.quote_prec = 4;
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(4));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(1));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(1));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let order_input = |user_id, side, amount| OrderInputBuilder::new(market.name, user_id, side, amount, dec!(1.23)).build();
+        // a bid maker then an ask taker, and the other way around
+        for (maker, taker) in [
+            (
+                order_input(102, OrderSide::BID, dec!(0.0013)),
+                order_input(101, OrderSide::ASK, dec!(0.0013)),
+            ),
+            (
+                order_input(101, OrderSide::ASK, dec!(0.0017)),
+                order_input(102, OrderSide::BID, dec!(0.0017)),
+            ),
+        ] {
+            for input in [maker, taker] {
+                market
+                    .put_order(
+                        sequencer,
+                        balance_manager.into(),
+                        &mut update_controller,
+                        &FeeManager::default(),
+                        &mut persistor,
+                        input,
+                    )
+                    .unwrap();
+            }
+        }
+
+        // 0.001599 and 0.002091 truncated
+        let trades = persistor.trades();
+        assert_eq!(
+            trades.iter().map(|trade| trade.quote_amount).collect::<Vec<_>>(),
+            vec![dec!(0.0015), dec!(0.0020)]
+        );
+        for order in persistor.orders() {
+            if order.order.remain.is_zero() {
+                assert_eq!(order.order.finished_quote.round_dp(4), order.order.finished_quote);
+            }
+        }
+        assert_eq!(market.asks.len() + market.bids.len(), 0);
+        let quote = dec!(0.0035);
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, usdt), quote);
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(1) - quote);
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(0));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, eth), dec!(0.003));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, eth), dec!(0.997));
+    }
+
+    #[test]
+    fn test_fill_outcomes() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(10000));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price, quote_limit| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .quote_limit(quote_limit)
+                .build()
+        };
+        let mut put = |market: &mut Market, input| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &FeeManager::default(),
+                    &mut persistor,
+                    input,
+                )
+                .unwrap()
+        };
+        let limit = |user_id, side, amount, price| order_input(user_id, side, OrderType::LIMIT, amount, price, dec!(0));
+        let price_cross = Some(FillOutcome::Stopped {
+            reason: MatchStopReason::PriceCross,
+        });
+
+        let ask = put(&mut market, limit(101, OrderSide::ASK, dec!(1), dec!(100)));
+        assert_eq!(ask.fill_outcome, Some(FillOutcome::BookExhausted));
+        // kept only on the order returned, not in the book
+        assert_eq!(market.get(ask.id).unwrap().fill_outcome, None);
+        let bid = put(&mut market, limit(102, OrderSide::BID, dec!(1), dec!(100)));
+        assert_eq!(bid.fill_outcome, Some(FillOutcome::Filled));
+        put(&mut market, limit(102, OrderSide::BID, dec!(1), dec!(90)));
+        assert_eq!(
+            put(&mut market, limit(101, OrderSide::ASK, dec!(1), dec!(95))).fill_outcome,
+            price_cross
+        );
+        let ask = put(&mut market, limit(101, OrderSide::ASK, dec!(1), dec!(100)));
+        assert_eq!(ask.fill_outcome, price_cross);
+
+        // the 0.5 left buys 0.005 at 100, below the min amount
+        let market_bid = put(
+            &mut market,
+            order_input(102, OrderSide::BID, OrderType::MARKET, dec!(10), dec!(0), dec!(95.5)),
+        );
+        assert_eq!(market_bid.fill_outcome, Some(FillOutcome::BudgetExhausted));
+        assert_eq!(market_bid.finished_quote, dec!(95));
+        assert_eq!(market.get(ask.id).unwrap().remain, dec!(1));
+
+        // the 0.3 left buys too little at 80 but enough at 10
+        let skipped = put(&mut market, limit(102, OrderSide::BID, dec!(1), dec!(80)));
+        put(&mut market, limit(102, OrderSide::BID, dec!(1), dec!(10)));
+        let market_ask = put(
+            &mut market,
+            order_input(101, OrderSide::ASK, OrderType::MARKET, dec!(2), dec!(0), dec!(90.3)),
+        );
+        assert_eq!(market_ask.fill_outcome, Some(FillOutcome::BudgetExhausted));
+        assert_eq!(market_ask.finished_base, dec!(1.03));
+        assert_eq!(market_ask.finished_quote, dec!(90.3));
+        assert_eq!(market.get(skipped.id).unwrap().remain, dec!(1));
+
+        let market_ask = put(
+            &mut market,
+            order_input(101, OrderSide::ASK, OrderType::MARKET, dec!(5), dec!(0), dec!(0)),
+        );
+        assert_eq!(market_ask.fill_outcome, Some(FillOutcome::BookExhausted));
+        assert_eq!(market_ask.finished_base, dec!(1.97));
+        assert!(market.bids.is_empty());
+
+        // on the finish message, and through the proto of the messages
+        let finished = persistor
+            .orders()
+            .into_iter()
+            .filter(|message| message.order.id == market_bid.id)
+            .last()
+            .unwrap()
+            .clone();
+        let json = serde_json::to_value(&finished).unwrap();
+        assert_eq!(json["order"]["fill_outcome"], serde_json::json!({"outcome": "budget_exhausted"}));
+        let stopped = Order {
+            fill_outcome: price_cross,
+            ..finished.order
+        };
+        let proto = crate::message::proto::Order::from(&stopped);
+        assert_eq!(Order::try_from(proto).unwrap().fill_outcome, price_cross);
+    }
+
+    #[test]
+    fn test_market_order_unfilled_rest() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        balance_manager.add(101, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(10));
+        balance_manager.add(102, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(10000));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price, quote_limit| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .quote_limit(quote_limit)
+                .build()
+        };
+        let mut put = |market: &mut Market, persistor: &mut crate::persist::MemBasedPersistor, input| {
+            market
+                .put_order(
+                    sequencer,
+                    balance_manager.into(),
+                    &mut update_controller,
+                    &FeeManager::default(),
+                    persistor,
+                    input,
+                )
+                .unwrap()
+        };
+        let limit = |user_id, side, amount, price| order_input(user_id, side, OrderType::LIMIT, amount, price, dec!(0));
+        let last_message = |persistor: &crate::persist::MemBasedPersistor, order_id| {
+            persistor
+                .orders()
+                .into_iter()
+                .filter(|message| message.order.id == order_id)
+                .last()
+                .unwrap()
+                .clone()
+        };
+
+        // a market bid for 3 against a book of 2
+        put(&mut market, &mut persistor, limit(101, OrderSide::ASK, dec!(1), dec!(100)));
+        put(&mut market, &mut persistor, limit(101, OrderSide::ASK, dec!(1), dec!(101)));
+        let bid = put(
+            &mut market,
+            &mut persistor,
+            order_input(102, OrderSide::BID, OrderType::MARKET, dec!(3), dec!(0), dec!(0)),
+        );
+        let message = last_message(&persistor, bid.id);
+        assert_eq!(message.event, OrderEventType::FINISH);
+        assert_eq!(message.cancel_reason, Some(OrderCancelReason::Unfilled));
+        assert_eq!(message.finish_reason, Some(FinishReason::Unmatched));
+        assert_eq!(message.order.remain, dec!(1));
+        assert_eq!(message.order.finished_base, dec!(2));
+        assert_eq!(message.order.finished_quote, dec!(201));
+
+        // a market ask for 5 against a thin book of 0.5
+        put(&mut market, &mut persistor, limit(102, OrderSide::BID, dec!(0.5), dec!(99)));
+        let ask = put(
+            &mut market,
+            &mut persistor,
+            order_input(101, OrderSide::ASK, OrderType::MARKET, dec!(5), dec!(0), dec!(0)),
+        );
+        let message = last_message(&persistor, ask.id);
+        assert_eq!(message.event, OrderEventType::FINISH);
+        assert_eq!(message.cancel_reason, Some(OrderCancelReason::Unfilled));
+        assert_eq!(message.order.finished_base, dec!(0.5));
+        assert_eq!(message.order.finished_quote, dec!(49.5));
+        assert!(market.bids.is_empty());
+
+        // a market bid spending its whole quote limit is filled, the same as one filled by the amount
+        put(&mut market, &mut persistor, limit(101, OrderSide::ASK, dec!(2), dec!(100)));
+        let spent = put(
+            &mut market,
+            &mut persistor,
+            order_input(102, OrderSide::BID, OrderType::MARKET, dec!(2), dec!(0), dec!(50)),
+        );
+        assert_eq!(spent.remain, dec!(1.5));
+        let message = last_message(&persistor, spent.id);
+        assert_eq!(message.event, OrderEventType::FINISH);
+        assert_eq!(message.finish_reason, Some(FinishReason::Filled));
+        let filled = put(
+            &mut market,
+            &mut persistor,
+            order_input(102, OrderSide::BID, OrderType::MARKET, dec!(1), dec!(0), dec!(0)),
+        );
+        let message = last_message(&persistor, filled.id);
+        assert_eq!(message.event, OrderEventType::FINISH);
+        assert_eq!(message.finish_reason, Some(FinishReason::Filled));
+        assert_eq!(message.cancel_reason, None);
+    }
+
+    #[test]
+    fn test_freeze_after_balance_taken() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(10));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(300));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let ask = OrderInputBuilder::new(market.name, 101, OrderSide::ASK, dec!(1), dec!(100)).build();
+        market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                ask,
+            )
+            .unwrap();
+        // the bid of 2 is checked against the 300 available, then another order of the user takes 150 before it
+        // is matched. the trade spends 100, so the rest can't be frozen
+        let (id, t) = (sequencer.next_order_id(), market.clock.now());
+        let bid = Order {
+            id,
+            priority: id,
+            type_: OrderType::LIMIT,
+            side: OrderSide::BID,
+            create_time: t,
+            update_time: t,
+            market: market.name.into(),
+            base: market.base.into(),
+            quote: market.quote.into(),
+            user: 102,
+            price: dec!(100),
+            amount: dec!(2),
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            remain: dec!(2),
+            frozen: dec!(0),
+            finished_base: dec!(0),
+            finished_quote: dec!(0),
+            finished_fee: dec!(0),
+            post_only: false,
+            signature: [0; 64],
+            client_order_id: None,
+            fill_outcome: None,
+        };
+        assert!(balance_manager.get(102, BalanceType::AVAILABLE, usdt) >= bid.amount * bid.price);
+        balance_manager.sub(102, BalanceType::AVAILABLE, usdt, &dec!(150)).unwrap();
+        let err = market
+            .execute_order(
+                sequencer,
+                &mut balance_manager.into(),
+                &mut update_controller,
+                &mut persistor,
+                bid,
+                &dec!(0),
+                None,
+                None,
+                true,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err,
+            MarketError::BalanceNotEnough {
+                asset: usdt.to_string(),
+                required: dec!(100),
+                available: dec!(50),
+            }
+        );
+
+        // the trade is kept, the rest never rests
+        assert_eq!(persistor.trades().len(), 1);
+        assert!(market.bids.is_empty() && market.orders.is_empty());
+        assert_eq!(market.bid_totals, BookTotals::default());
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, usdt), dec!(0));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, usdt), dec!(50));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, eth), dec!(1));
+        let message = persistor.orders().last().unwrap().clone();
+        assert_eq!(message.event, OrderEventType::FINISH);
+        assert_eq!(message.cancel_reason, Some(OrderCancelReason::BalanceNotEnough));
+        assert_eq!(message.order.remain, dec!(1));
+        assert_eq!(message.order.frozen, dec!(0));
+        // not a broken invariant, the market stays open
+        assert_eq!(market.state, MarketState::Open);
+    }
+
+    #[test]
+    fn test_quote_amount_overflow() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(1000));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &dec!(30000000000000000000000000000));
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side, type_, amount, price| {
+            OrderInputBuilder::new(&market_name, user_id, side, amount, price)
+                .type_(type_)
+                .build()
+        };
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                input,
+            )
+        };
+
+        // the price * amount of a limit order is beyond the max decimal
+        let err = put(
+            &mut market,
+            order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(100), dec!(1000000000000000000000000000)),
+        )
+        .unwrap_err();
+        assert_eq!(err, MarketError::QuoteAmountOverflow);
+        // fits alone, not with the notional of the asks
+        let near_max = dec!(50000000000000000000000000000);
+        put(&mut market, order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(1), near_max)).unwrap();
+        let err = put(&mut market, order_input(101, OrderSide::ASK, OrderType::LIMIT, dec!(1), near_max)).unwrap_err();
+        assert_eq!(err, MarketError::QuoteAmountOverflow);
+        // a market ask priced at the best bid
+        let bid_price = dec!(30000000000000000000000000000);
+        put(&mut market, order_input(102, OrderSide::BID, OrderType::LIMIT, dec!(1), bid_price)).unwrap();
+        let err = put(&mut market, order_input(101, OrderSide::ASK, OrderType::MARKET, dec!(10), dec!(0))).unwrap_err();
+        assert_eq!(err, MarketError::QuoteAmountOverflow);
+        assert_eq!((market.asks.len(), market.bids.len()), (1, 1));
+        assert_eq!(market.ask_totals.notional, near_max);
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, eth), dec!(1));
+        assert_eq!(persistor.trades().len(), 0);
+    }
+
+    #[test]
+    fn test_balance_overflow_cancels_taker() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        let price = dec!(30000000000000000000000000000);
+        // the seller would receive more than the max decimal
+        balance_manager.add(101, BalanceType::AVAILABLE, eth, &dec!(1));
+        balance_manager.add(101, BalanceType::AVAILABLE, usdt, &dec!(50000000000000000000000000000));
+        balance_manager.add(102, BalanceType::AVAILABLE, usdt, &price);
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, side| OrderInputBuilder::new(&market_name, user_id, side, dec!(1), price).build();
+        let maker = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                order_input(102, OrderSide::BID),
+            )
+            .unwrap();
+        let balances_before = balance_manager.balances.clone();
+
+        let taker = market
+            .put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                order_input(101, OrderSide::ASK),
+            )
+            .unwrap();
+        let message = persistor.orders().last().unwrap().clone();
+        assert_eq!((message.order.id, message.event), (taker.id, OrderEventType::FINISH));
+        assert_eq!(message.cancel_reason, Some(OrderCancelReason::SettlementFailed));
+        // the maker and the balances are untouched
+        assert_eq!(balance_manager.balances, balances_before);
+        let maker = market.get(maker.id).unwrap();
+        assert_eq!((maker.remain, maker.frozen), (dec!(1), price));
+        assert_eq!(market.bid_totals.frozen, price);
+        assert_eq!(persistor.trades().len(), 0);
+        assert_eq!(market.state, MarketState::Open);
+
+        // and a balance never overflows
+        assert!(balance_manager.checked_add(101, BalanceType::AVAILABLE, usdt, &price).is_err());
+        assert_eq!(
+            balance_manager.get(101, BalanceType::AVAILABLE, usdt),
+            dec!(50000000000000000000000000000)
+        );
+    }
+
+    #[test]
+    fn test_order_nonce_replay() {
+        let mut update_controller = BalanceUpdateController::new();
+        let balance_manager = &mut get_simple_balance_manager(get_simple_asset_config(8));
+        let (eth, usdt) = (&MockAsset::ETH.id(), &MockAsset::USDT.id());
+        for user_id in [101, 102] {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, eth, &dec!(100));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, usdt, &dec!(10000));
+        }
+        let sequencer = &mut Sequencer::default();
+        let mut persistor = crate::persist::MemBasedPersistor::default();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), balance_manager).unwrap();
+        let market_name = market.name.to_string();
+        let order_input = |user_id, price, nonce| {
+            OrderInputBuilder::new(&market_name, user_id, OrderSide::ASK, dec!(1), price)
+                .signature([7; 64])
+                .nonce(nonce)
+                .build()
+        };
+        let mut put = |market: &mut Market, input| {
+            market.put_order(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                input,
+            )
+        };
+
+        put(&mut market, order_input(101, dec!(100), 5)).unwrap();
+        // the same signed order again, and an older nonce
+        assert_eq!(
+            put(&mut market, order_input(101, dec!(100), 5)).unwrap_err(),
+            MarketError::NonceTooLow { nonce: 5, last: 5 }
+        );
+        assert_eq!(
+            put(&mut market, order_input(101, dec!(101), 3)).unwrap_err(),
+            MarketError::NonceTooLow { nonce: 3, last: 5 }
+        );
+        // the nonces of another user, and the orders without a nonce, are apart
+        put(&mut market, order_input(102, dec!(100), 5)).unwrap();
+        put(&mut market, order_input(101, dec!(100), 0)).unwrap();
+        put(&mut market, order_input(101, dec!(100), 0)).unwrap();
+        assert_eq!(market.user_nonces.all(), vec![(101, 5), (102, 5)]);
+        assert_eq!(market.orders.len(), 4);
+
+        // the nonces of the new orders of a replace are checked up front, nothing is applied
+        let orders_before = market.orders.len();
+        let err = market
+            .replace_orders(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                vec![],
+                vec![order_input(101, dec!(102), 6), order_input(101, dec!(103), 6)],
+            )
+            .unwrap_err();
+        assert_eq!(err, MarketError::NonceTooLow { nonce: 6, last: 6 });
+        assert_eq!(market.orders.len(), orders_before);
+        assert_eq!(market.user_nonces.last(101), 5);
+        let (_, placed) = market
+            .replace_orders(
+                sequencer,
+                balance_manager.into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                vec![],
+                vec![order_input(101, dec!(102), 6), order_input(101, dec!(103), 7)],
+            )
+            .unwrap();
+        assert_eq!(placed.len(), 2);
+        assert_eq!(market.user_nonces.last(101), 7);
+    }
 }
@@ -0,0 +1,43 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// user_id -> the last nonce accepted from the user. shared by the markets of the engine like `UserOrderIndex`.
+// the orders of a user in all markets take their nonces from this single space, the signed cancels and
+// withdrawals are to take theirs from it too, so a client keeps one increasing counter per user
+#[derive(Debug, Clone, Default)]
+pub struct UserNonces(Arc<RwLock<BTreeMap<u32, u64>>>);
+
+impl UserNonces {
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<u32, u64>> {
+        self.0.read().expect("user nonces poisoned")
+    }
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<u32, u64>> {
+        self.0.write().expect("user nonces poisoned")
+    }
+
+    // zero if the user never used a nonce
+    pub fn last(&self, user_id: u32) -> u64 {
+        self.read().get(&user_id).copied().unwrap_or(0)
+    }
+    // the nonce of an order put, a signed cancel or a withdrawal, zero is no nonce
+    pub fn accept(&self, user_id: u32, nonce: u64) {
+        if nonce == 0 {
+            return;
+        }
+        let mut nonces = self.write();
+        let last = nonces.entry(user_id).or_insert(0);
+        debug_assert!(nonce > *last, "nonce {} of user {} is not above {}", nonce, user_id, last);
+        *last = nonce.max(*last);
+    }
+
+    // for the snapshots, sorted by user
+    pub fn all(&self) -> Vec<(u32, u64)> {
+        self.read().iter().map(|(user_id, nonce)| (*user_id, *nonce)).collect()
+    }
+    pub fn restore(&self, nonces: impl IntoIterator<Item = (u32, u64)>) {
+        self.write().extend(nonces);
+    }
+    pub fn clear(&self) {
+        self.write().clear();
+    }
+}
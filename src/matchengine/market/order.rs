@@ -1,4 +1,5 @@
-use crate::types::{OrderSide, OrderType};
+use super::FillOutcome;
+use crate::types::{OrderSide, OrderType, TimestampMs};
 use crate::utils::InternedString;
 use fluidex_common::types::{BigInt, Decimal, Fr, FrExt};
 use serde::{Deserialize, Serialize};
@@ -9,13 +10,13 @@ use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 pub struct MarketKeyAsk {
     pub order_price: Decimal,
-    pub order_id: u64,
+    pub priority: u64,
 }
 
 #[derive(PartialEq, Eq)]
 pub struct MarketKeyBid {
     pub order_price: Decimal,
-    pub order_id: u64,
+    pub priority: u64,
 }
 
 impl Ord for MarketKeyBid {
@@ -24,7 +25,7 @@ impl Ord for MarketKeyBid {
         if price_order != Ordering::Equal {
             price_order
         } else {
-            self.order_id.cmp(&other.order_id)
+            self.priority.cmp(&other.priority)
         }
     }
 }
@@ -37,15 +38,15 @@ fn test_order_sort() {
     {
         let o1 = MarketKeyBid {
             order_price: Decimal::zero(),
-            order_id: 5,
+            priority: 5,
         };
         let o2 = MarketKeyBid {
             order_price: Decimal::zero(),
-            order_id: 6,
+            priority: 6,
         };
         let o3 = MarketKeyBid {
             order_price: Decimal::one(),
-            order_id: 7,
+            priority: 7,
         };
         assert!(o1 < o2);
         assert!(o3 < o2);
@@ -53,15 +54,15 @@ fn test_order_sort() {
     {
         let o1 = MarketKeyAsk {
             order_price: Decimal::zero(),
-            order_id: 5,
+            priority: 5,
         };
         let o2 = MarketKeyAsk {
             order_price: Decimal::zero(),
-            order_id: 6,
+            priority: 6,
         };
         let o3 = MarketKeyAsk {
             order_price: Decimal::one(),
-            order_id: 7,
+            priority: 7,
         };
         assert!(o1 < o2);
         assert!(o3 > o2);
@@ -96,7 +97,12 @@ pub struct Order {
     pub maker_fee: Decimal,
     // fee rate when the order be treated as a taker, not useful when post_only
     pub taker_fee: Decimal,
-    pub create_time: f64,
+    pub create_time: TimestampMs,
+    // position inside a price level, the order id unless the order lost its time priority by amendment
+    #[serde(default)]
+    pub priority: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_order_id: Option<u64>,
 
     // below are the changable parts
     // remain + finished_base == amount
@@ -106,7 +112,10 @@ pub struct Order {
     pub finished_base: Decimal,
     pub finished_quote: Decimal,
     pub finished_fee: Decimal,
-    pub update_time: f64,
+    pub update_time: TimestampMs,
+    // how its matching as a taker ended, none on the orders resting in the book
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill_outcome: Option<FillOutcome>,
 }
 
 /*
@@ -119,18 +128,26 @@ impl Order {
     pub fn get_ask_key(&self) -> MarketKeyAsk {
         MarketKeyAsk {
             order_price: self.price,
-            order_id: self.id,
+            priority: self.priority,
         }
     }
     pub fn get_bid_key(&self) -> MarketKeyBid {
         MarketKeyBid {
             order_price: self.price,
-            order_id: self.id,
+            priority: self.priority,
         }
     }
     pub fn is_ask(&self) -> bool {
         self.side == OrderSide::ASK
     }
+    // what a resting order with this remain keeps frozen
+    pub fn frozen_for(&self, remain: Decimal) -> Decimal {
+        if self.is_ask() {
+            remain
+        } else {
+            remain * self.price
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -166,17 +183,27 @@ pub struct OrderInput {
     pub amount: Decimal,
     pub price: Decimal,
     pub quote_limit: Decimal,
+    // only valid for market bid order, `amount` is the quote to spend rather than the base to buy
+    pub amount_is_quote: bool,
+    // only valid for market order, relative to the best counter price at submission time
+    pub max_slippage: Option<Decimal>,
+    // optional id chosen by the client, unique among the open orders of the user
+    pub client_order_id: Option<u64>,
     pub taker_fee: Decimal, // FIXME fee should be determined inside engine rather than take from input
     pub maker_fee: Decimal,
     pub market: String,
     pub post_only: bool,
     pub signature: [u8; 64],
+    // increasing per user and signed with the order, so a captured order can not be put again.
+    // zero for the orders without one, which are not checked
+    pub nonce: u64,
 }
 
 pub struct OrderCommitment {
-    // order_id
-    // account_id
-    // nonce
+    // signed too, so the signature of an order of a user can not be replayed for another user
+    pub account_id: u32,
+    // see `OrderInput::nonce`
+    pub nonce: u64,
     pub token_sell: Fr,
     pub token_buy: Fr,
     pub total_sell: Fr,
@@ -185,23 +212,41 @@ pub struct OrderCommitment {
 
 impl OrderCommitment {
     pub fn hash(&self) -> BigInt {
-        // consistent with https://github.com/fluidex/circuits/blob/d6e06e964b9d492f1fa5513bcc2295e7081c540d/helper.ts/state-utils.ts#L38
-        // TxType::PlaceOrder
+        // the first hash is the one of TxType::PlaceOrder in
+        // https://github.com/fluidex/circuits/blob/d6e06e964b9d492f1fa5513bcc2295e7081c540d/helper.ts/state-utils.ts#L38
         let magic_head = Fr::from_u32(4);
+        let data = Fr::hash(&[magic_head, self.token_sell, self.token_buy, self.total_sell, self.total_buy]);
+        // PROTOCOL CHANGE: it is hashed again with the account id and the nonce, which the circuits above do not do.
+        // the clients sign this hash since the nonces were added, the signatures made for the hash of the circuits
+        // alone are rejected, and the circuits have to be changed the same way to check the orders against it.
+        // the order id is not known to the client when it signs, the nonce is signed instead
+        let data = Fr::hash(&[data, Fr::from_u32(self.account_id), Fr::from_u64(self.nonce)]);
+        data.to_bigint()
+    }
+}
+
+// what a user signs to cancel an order, the market is given by its tokens like in `OrderCommitment`
+pub struct CancelCommitment {
+    pub account_id: u32,
+    pub token_base: Fr,
+    pub token_quote: Fr,
+    pub order_id: u64,
+    // see `OrderInput::nonce`
+    pub nonce: u64,
+}
+
+impl CancelCommitment {
+    pub fn hash(&self) -> BigInt {
+        // not a tx of the circuits, the head keeps the hash apart from theirs
+        let magic_head = Fr::from_u32(100);
         let data = Fr::hash(&[
             magic_head,
-            // TODO: sign nonce or order_id
-            //u32_to_fr(self.order_id),
-            self.token_sell,
-            self.token_buy,
-            self.total_sell,
-            self.total_buy,
+            Fr::from_u32(self.account_id),
+            self.token_base,
+            self.token_quote,
+            Fr::from_u64(self.order_id),
+            Fr::from_u64(self.nonce),
         ]);
-        //data = hash([data, accountID, nonce]);
-        // nonce and orderID seems redundant?
-
-        // account_id is not needed if the hash is signed later?
-        //data = hash(&[data, u32_to_fr(self.account_id)]);
         data.to_bigint()
     }
 }
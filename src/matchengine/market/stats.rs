@@ -0,0 +1,143 @@
+use crate::types::TimestampMs;
+use fluidex_common::rust_decimal::Decimal;
+use serde::Serialize;
+use std::cmp::{max, min};
+
+// 288 buckets of 5 minutes, covering the last 24 hours
+const BUCKET_SECONDS: u64 = 300;
+const BUCKET_COUNT: usize = 288;
+
+#[derive(Clone, Default)]
+struct StatsBucket {
+    // the index of the 5 minutes period since the epoch, the slot is stale when it differs
+    period: u64,
+    open: Decimal,
+    high: Decimal,
+    low: Decimal,
+    volume: Decimal,
+    quote_volume: Decimal,
+    trade_count: u64,
+}
+
+// the rolling statistics of the trades of a market, in a ring of time buckets so the memory is bounded
+pub struct MarketStats {
+    buckets: Vec<StatsBucket>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct MarketStatsInfo {
+    pub volume: Decimal,
+    pub quote_volume: Decimal,
+    // the prices are zero when there is no trade in the window
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub trade_count: u64,
+}
+
+impl Default for MarketStats {
+    fn default() -> Self {
+        Self {
+            buckets: vec![StatsBucket::default(); BUCKET_COUNT],
+        }
+    }
+}
+
+impl MarketStats {
+    fn period(timestamp: TimestampMs) -> u64 {
+        timestamp.as_secs() / BUCKET_SECONDS
+    }
+
+    pub fn record(&mut self, timestamp: TimestampMs, price: Decimal, amount: Decimal, quote_amount: Decimal) {
+        let period = Self::period(timestamp);
+        let bucket = &mut self.buckets[period as usize % BUCKET_COUNT];
+        if bucket.period != period || bucket.trade_count == 0 {
+            *bucket = StatsBucket {
+                period,
+                open: price,
+                high: price,
+                low: price,
+                ..Default::default()
+            };
+        }
+        bucket.high = max(bucket.high, price);
+        bucket.low = min(bucket.low, price);
+        // the statistics saturate rather than overflow
+        bucket.volume = bucket.volume.checked_add(amount).unwrap_or(Decimal::MAX);
+        bucket.quote_volume = bucket.quote_volume.checked_add(quote_amount).unwrap_or(Decimal::MAX);
+        bucket.trade_count += 1;
+    }
+
+    // the buckets older than 24 hours are skipped, so the window rolls even when no trade comes
+    pub fn info(&self, now: TimestampMs) -> MarketStatsInfo {
+        let current = Self::period(now);
+        let mut buckets = self
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.trade_count > 0 && bucket.period <= current && current - bucket.period < BUCKET_COUNT as u64)
+            .collect::<Vec<_>>();
+        buckets.sort_by_key(|bucket| bucket.period);
+
+        let mut info = MarketStatsInfo::default();
+        if let Some(first) = buckets.first() {
+            info.open = first.open;
+            info.high = first.high;
+            info.low = first.low;
+        }
+        for bucket in buckets {
+            info.volume = info.volume.checked_add(bucket.volume).unwrap_or(Decimal::MAX);
+            info.quote_volume = info.quote_volume.checked_add(bucket.quote_volume).unwrap_or(Decimal::MAX);
+            info.high = max(info.high, bucket.high);
+            info.low = min(info.low, bucket.low);
+            info.trade_count += bucket.trade_count;
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_stats_window() {
+        let mut stats = MarketStats::default();
+        let start = 1_600_000_200.0;
+        let at = |offset: f64| TimestampMs::from_secs_f64(start + offset);
+        assert_eq!(stats.info(at(0.0)), MarketStatsInfo::default());
+
+        stats.record(at(0.0), dec!(10), dec!(1), dec!(10));
+        stats.record(at(10.0), dec!(12), dec!(2), dec!(24));
+        // the next bucket
+        stats.record(at(300.0), dec!(9), dec!(1), dec!(9));
+        assert_eq!(
+            stats.info(at(300.0)),
+            MarketStatsInfo {
+                volume: dec!(4),
+                quote_volume: dec!(43),
+                open: dec!(10),
+                high: dec!(12),
+                low: dec!(9),
+                trade_count: 3,
+            }
+        );
+
+        // the first bucket leaves the window without any new trade
+        let info = stats.info(at(86400.0));
+        assert_eq!(
+            (info.volume, info.open, info.high, info.trade_count),
+            (dec!(1), dec!(9), dec!(9), 1)
+        );
+        assert_eq!(stats.info(at(86400.0 + 300.0)), MarketStatsInfo::default());
+
+        // the slot of the first bucket is reused a day later
+        stats.record(at(86400.0), dec!(11), dec!(5), dec!(55));
+        let info = stats.info(at(86400.0));
+        assert_eq!(
+            (info.volume, info.quote_volume, info.open, info.low),
+            (dec!(6), dec!(64), dec!(9), dec!(9))
+        );
+        assert_eq!((info.high, info.trade_count), (dec!(11), 2));
+    }
+}
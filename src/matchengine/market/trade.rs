@@ -1,6 +1,7 @@
 use crate::market::Order;
 use crate::types::MarketRole;
 use crate::types::OrderSide;
+use crate::types::TimestampMs;
 use crate::utils::InternedString;
 use fluidex_common::rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
@@ -35,10 +36,30 @@ pub struct VerboseTradeState {
     pub balance_states: Vec<VerboseBalanceState>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DiscountFee {
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+// the fee one side of a trade paid, negative for a maker rebate
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TradeFeeRecord {
+    pub trade_id: u64,
+    pub timestamp: TimestampMs,
+    pub market: String,
+    pub user_id: u32,
+    pub order_id: u64,
+    pub role: MarketRole,
+    pub asset: String,
+    pub amount: Decimal,
+    pub rate: Decimal,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Trade {
     pub id: u64,
-    pub timestamp: f64, // unix epoch timestamp,
+    pub timestamp: TimestampMs,
     pub market: String,
     pub base: String,
     pub quote: String,
@@ -55,6 +76,9 @@ pub struct Trade {
     pub bid_order_id: u64,
     pub bid_role: MarketRole,
     pub bid_fee: Decimal,
+    // the taker fee paid in the discount asset, the fee of the taker above is zero then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discount_fee: Option<DiscountFee>,
 
     // only not none when this is this order's first trade
     pub ask_order: Option<Order>,
@@ -65,3 +89,31 @@ pub struct Trade {
     #[cfg(feature = "emit_state_diff")]
     pub state_after: VerboseTradeState,
 }
+
+// a trade kept in the recent trades of a market
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TradeSummary {
+    pub id: u64,
+    pub timestamp: TimestampMs,
+    pub price: Decimal,
+    pub amount: Decimal,
+    pub quote_amount: Decimal,
+    pub taker_side: OrderSide,
+}
+
+impl From<&Trade> for TradeSummary {
+    fn from(trade: &Trade) -> Self {
+        Self {
+            id: trade.id,
+            timestamp: trade.timestamp,
+            price: trade.price,
+            amount: trade.amount,
+            quote_amount: trade.quote_amount,
+            taker_side: if trade.ask_role == MarketRole::TAKER {
+                OrderSide::ASK
+            } else {
+                OrderSide::BID
+            },
+        }
+    }
+}
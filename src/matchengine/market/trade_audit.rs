@@ -0,0 +1,272 @@
+use super::{DiscountFee, Order, Trade, VerboseTradeState};
+use fluidex_common::rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+// bumped on any change a parser of the audit lines has to know about
+pub const TRADE_AUDIT_SCHEMA_VERSION: u32 = 3;
+
+// why the matching of a taker stopped
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchStopReason {
+    Filled,
+    // the limit price does not cross the best counter price anymore
+    PriceCross,
+    // the counter price is beyond the max slippage of a market order
+    Slippage,
+    PriceDeviation,
+    QuoteLimit,
+    // canceled or repriced
+    PostOnly,
+    SelfTrade,
+    SettlementFailed,
+    InvariantViolated,
+    AmountOverflow,
+    BookExhausted,
+}
+
+// how the matching of a taker ended, set on the taker returned and on its messages
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum FillOutcome {
+    Filled,
+    // the quote limit is spent, the rest of the budget buys less than the smallest trade
+    BudgetExhausted,
+    BookExhausted,
+    Stopped { reason: MatchStopReason },
+    // a crossing post only order put one tick away from the best counter price, `from` is the price it was placed at
+    Repriced { from: Decimal },
+}
+
+impl From<MatchStopReason> for FillOutcome {
+    fn from(reason: MatchStopReason) -> Self {
+        match reason {
+            MatchStopReason::Filled => FillOutcome::Filled,
+            MatchStopReason::QuoteLimit => FillOutcome::BudgetExhausted,
+            MatchStopReason::BookExhausted => FillOutcome::BookExhausted,
+            reason => FillOutcome::Stopped { reason },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum MatchDecision {
+    Fill {
+        trade_id: u64,
+        maker_order_id: u64,
+        price: Decimal,
+        traded_base: Decimal,
+        traded_quote: Decimal,
+        ask_fee: Decimal,
+        bid_fee: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        discount_fee: Option<DiscountFee>,
+        taker_remain: Decimal,
+        maker_remain: Decimal,
+        // the orders and the balances of both sides after the trade
+        state_after: VerboseTradeState,
+    },
+    Stop {
+        reason: MatchStopReason,
+        // the trades of the taker in this matching
+        trades: u64,
+        taker_remain: Decimal,
+    },
+}
+
+// one json line of the audit log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeAuditRecord {
+    pub schema_version: u32,
+    pub market: String,
+    pub taker_order_id: u64,
+    #[serde(flatten)]
+    pub decision: MatchDecision,
+}
+
+// the matching decisions as json lines, off unless a writer is set. shared by the markets like the clock
+#[derive(Clone, Default)]
+pub struct TradeAuditLog(Option<Arc<Mutex<Box<dyn Write + Send>>>>);
+
+impl TradeAuditLog {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        TradeAuditLog(Some(Arc::new(Mutex::new(Box::new(writer)))))
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn fill(&self, market: &str, trade: &Trade, taker: &Order, maker: &Order, state_after: VerboseTradeState) {
+        self.write(TradeAuditRecord {
+            schema_version: TRADE_AUDIT_SCHEMA_VERSION,
+            market: market.to_string(),
+            taker_order_id: taker.id,
+            decision: MatchDecision::Fill {
+                trade_id: trade.id,
+                maker_order_id: maker.id,
+                price: trade.price,
+                traded_base: trade.amount,
+                traded_quote: trade.quote_amount,
+                ask_fee: trade.ask_fee,
+                bid_fee: trade.bid_fee,
+                discount_fee: trade.discount_fee.clone(),
+                taker_remain: taker.remain,
+                maker_remain: maker.remain,
+                state_after,
+            },
+        });
+    }
+    pub fn stop(&self, market: &str, taker: &Order, reason: MatchStopReason, trades: u64) {
+        self.write(TradeAuditRecord {
+            schema_version: TRADE_AUDIT_SCHEMA_VERSION,
+            market: market.to_string(),
+            taker_order_id: taker.id,
+            decision: MatchDecision::Stop {
+                reason,
+                trades,
+                taker_remain: taker.remain,
+            },
+        });
+    }
+
+    // a failed write is logged, the matching goes on
+    fn write(&self, record: TradeAuditRecord) {
+        if let Some(writer) = &self.0 {
+            let mut writer = writer.lock().unwrap();
+            let result = serde_json::to_writer(&mut *writer, &record)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.write_all(b"\n"));
+            if let Err(e) = result {
+                log::error!("write trade audit of order {} failed: {}", record.taker_order_id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceType, BalanceUpdateController};
+    use crate::config::Settings;
+    use crate::fee::FeeManager;
+    use crate::market::{Market, OrderSide};
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use crate::sequencer::Sequencer;
+    use fluidex_common::rust_decimal_macros::*;
+    use serde_json::Value;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trade_audit_lines() {
+        let mut sequencer = Sequencer::default();
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::new();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), &balance_manager).unwrap();
+        let buf = SharedBuf::default();
+        market.trade_audit = TradeAuditLog::new(buf.clone());
+        for user_id in [1, 2, 3] {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(100));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(10000));
+        }
+        // two asks, a bid taking the first and half of the second, a bid not crossing, a bid taking the rest,
+        // then a crossing post only ask
+        let orders = [
+            (1, OrderSide::ASK, dec!(1), dec!(100), false),
+            (2, OrderSide::ASK, dec!(2), dec!(101), false),
+            (3, OrderSide::BID, dec!(2), dec!(102), false),
+            (3, OrderSide::BID, dec!(1), dec!(99), false),
+            (1, OrderSide::BID, dec!(1), dec!(105), false),
+            (3, OrderSide::ASK, dec!(1), dec!(90), true),
+        ];
+        let mut ids = Vec::new();
+        for (user_id, side, amount, price, post_only) in orders {
+            let order_input = OrderInputBuilder::new(market.name, user_id, side, amount, price)
+                .fees(dec!(0.002), dec!(0.001))
+                .post_only(post_only)
+                .build();
+            let order = market
+                .put_order(
+                    &mut sequencer,
+                    (&mut balance_manager).into(),
+                    &mut update_controller,
+                    &FeeManager::default(),
+                    &mut DummyPersistor::default(),
+                    order_input,
+                )
+                .unwrap();
+            ids.push(order.id);
+        }
+
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines = output
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let decisions = lines
+            .iter()
+            .map(|line| {
+                assert_eq!(line["schema_version"], TRADE_AUDIT_SCHEMA_VERSION);
+                assert_eq!(line["market"], "ETH_USDT");
+                let taker = line["taker_order_id"].as_u64().unwrap();
+                let decision = line["decision"].as_str().unwrap().to_string();
+                let detail = if decision == "fill" {
+                    line["maker_order_id"].as_u64().unwrap().to_string()
+                } else {
+                    line["reason"].as_str().unwrap().to_string()
+                };
+                (taker, decision, detail)
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            (ids[0], "stop", "book_exhausted".to_string()),
+            (ids[1], "stop", "book_exhausted".to_string()),
+            (ids[2], "fill", ids[0].to_string()),
+            (ids[2], "fill", ids[1].to_string()),
+            (ids[2], "stop", "filled".to_string()),
+            (ids[3], "stop", "price_cross".to_string()),
+            (ids[4], "fill", ids[1].to_string()),
+            (ids[4], "stop", "filled".to_string()),
+            (ids[5], "stop", "post_only".to_string()),
+        ];
+        let expected = expected
+            .iter()
+            .map(|(taker, decision, detail)| (*taker, decision.to_string(), detail.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(decisions, expected);
+
+        // the fields of a fill
+        let fill = &lines[3];
+        let decimal = |key: &str| fill[key].as_str().unwrap().parse::<Decimal>().unwrap();
+        assert_eq!(decimal("price"), dec!(101));
+        assert_eq!(decimal("traded_base"), dec!(1));
+        assert_eq!(decimal("traded_quote"), dec!(101));
+        assert_eq!(decimal("taker_remain"), dec!(0));
+        assert_eq!(decimal("maker_remain"), dec!(1));
+        assert_eq!(decimal("ask_fee"), dec!(0.101));
+        assert_eq!(decimal("bid_fee"), dec!(0.002));
+        assert!(fill.get("discount_fee").is_none());
+        assert_eq!(fill["state_after"]["order_states"].as_array().unwrap().len(), 2);
+        assert_eq!(fill["state_after"]["balance_states"].as_array().unwrap().len(), 4);
+        assert_eq!(lines[4]["trades"], 2);
+        assert_eq!(lines[7]["trades"], 1);
+        // the records parse back
+        for line in output.lines() {
+            serde_json::from_str::<TradeAuditRecord>(line).unwrap();
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use super::{Market, Order};
+use crate::types::TimestampMs;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+type UserOrders = BTreeMap<u32, BTreeMap<&'static str, BTreeSet<u64>>>;
+
+// user_id -> market -> ids of the open orders. shared by the markets of the engine like the clock,
+// each market keeps its own orders in it, so the open orders of a user are found without a scan of every market
+#[derive(Debug, Clone, Default)]
+pub struct UserOrderIndex(Arc<RwLock<UserOrders>>);
+
+// a position in the open orders of a user, which are sorted by create time then id
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OrderCursor {
+    pub create_time: TimestampMs,
+    pub order_id: u64,
+}
+
+impl OrderCursor {
+    fn of(order: &Order) -> Self {
+        OrderCursor {
+            create_time: order.create_time,
+            order_id: order.id,
+        }
+    }
+    fn compare(&self, other: &Self) -> Ordering {
+        self.create_time.cmp(&other.create_time).then(self.order_id.cmp(&other.order_id))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OrderPagination {
+    // zero means the default
+    pub limit: usize,
+    // the orders after the cursor, from the first one if none
+    pub after: Option<OrderCursor>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct OpenOrdersPage {
+    pub orders: Vec<Order>,
+    // the open orders of the user in all markets
+    pub total: usize,
+    // passed as `after` for the next page, none on the last page
+    pub next: Option<OrderCursor>,
+}
+
+const DEFAULT_PAGE_LEN: usize = 10;
+const MAX_PAGE_LEN: usize = 100;
+
+impl UserOrderIndex {
+    fn read(&self) -> RwLockReadGuard<'_, UserOrders> {
+        self.0.read().expect("user order index poisoned")
+    }
+    fn write(&self) -> RwLockWriteGuard<'_, UserOrders> {
+        self.0.write().expect("user order index poisoned")
+    }
+
+    pub fn insert(&self, user_id: u32, market: &'static str, order_id: u64) {
+        let inserted = self.write().entry(user_id).or_default().entry(market).or_default().insert(order_id);
+        debug_assert!(inserted);
+    }
+    pub fn remove(&self, user_id: u32, market: &'static str, order_id: u64) {
+        let mut users = self.write();
+        let markets = users.get_mut(&user_id);
+        debug_assert!(markets.is_some(), "order {} of user {} not indexed", order_id, user_id);
+        if let Some(markets) = markets {
+            if let Some(ids) = markets.get_mut(market) {
+                let removed = ids.remove(&order_id);
+                debug_assert!(removed);
+                // only the users with open orders are kept, like `Market::users`
+                if ids.is_empty() {
+                    markets.remove(market);
+                }
+            }
+            if markets.is_empty() {
+                users.remove(&user_id);
+            }
+        }
+    }
+    // the market is reset
+    pub fn remove_market(&self, market: &'static str) {
+        let mut users = self.write();
+        users.retain(|_, markets| {
+            markets.remove(market);
+            !markets.is_empty()
+        });
+    }
+
+    pub fn order_count(&self, user_id: u32) -> usize {
+        self.read()
+            .get(&user_id)
+            .map_or(0, |markets| markets.values().map(BTreeSet::len).sum())
+    }
+    // market -> order ids
+    pub fn orders_of_user(&self, user_id: u32) -> Vec<(&'static str, Vec<u64>)> {
+        self.read().get(&user_id).map_or_else(Vec::new, |markets| {
+            markets
+                .iter()
+                .map(|(market, ids)| (*market, ids.iter().copied().collect()))
+                .collect()
+        })
+    }
+
+    // one page of the open orders of a user in all `markets`, oldest first
+    pub fn open_orders<K>(&self, user_id: u32, markets: &HashMap<K, Market>, pagination: &OrderPagination) -> OpenOrdersPage
+    where
+        K: std::borrow::Borrow<str> + std::hash::Hash + Eq,
+    {
+        let limit = match pagination.limit {
+            0 => DEFAULT_PAGE_LEN,
+            limit => limit.min(MAX_PAGE_LEN),
+        };
+        let mut orders = self
+            .orders_of_user(user_id)
+            .into_iter()
+            .filter_map(|(market, ids)| markets.get(market).map(|market| (market, ids)))
+            .flat_map(|(market, ids)| ids.into_iter().filter_map(move |id| market.get(id)))
+            .collect::<Vec<_>>();
+        let total = orders.len();
+        orders.sort_by(|a, b| OrderCursor::of(a).compare(&OrderCursor::of(b)));
+        if let Some(after) = &pagination.after {
+            orders.retain(|order| OrderCursor::of(order).compare(after) == Ordering::Greater);
+        }
+        let next = if orders.len() > limit {
+            orders.truncate(limit);
+            orders.last().map(OrderCursor::of)
+        } else {
+            None
+        };
+        OpenOrdersPage { orders, total, next }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceType, BalanceUpdateController};
+    use crate::config::Settings;
+    use crate::fee::FeeManager;
+    use crate::market::OrderSide;
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use crate::sequencer::Sequencer;
+    use fluidex_common::rust_decimal::Decimal;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_open_orders_across_markets() {
+        let mut sequencer = Sequencer::default();
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::new();
+        let index = UserOrderIndex::default();
+        let mut markets: HashMap<String, Market> = HashMap::new();
+        for name in ["ETH_USDT", "ETH_USDT_2", "ETH_USDT_3"] {
+            let market_conf = crate::config::Market {
+                name: name.to_string(),
+                ..get_simple_market_config()
+            };
+            let mut market = Market::new(&market_conf, &Settings::default(), &balance_manager).unwrap();
+            market.user_orders = index.clone();
+            markets.insert(name.to_string(), market);
+        }
+        for user_id in [1, 2] {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::ETH.id(), &dec!(1000));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &MockAsset::USDT.id(), &dec!(100000));
+        }
+        let mut put = |markets: &mut HashMap<String, Market>, name: &str, user_id: u32, time: f64| {
+            let market = markets.get_mut(name).unwrap();
+            market.clock.fix(TimestampMs::from_secs_f64(time));
+            let order_input = OrderInputBuilder::new(name, user_id, OrderSide::ASK, dec!(1), dec!(100) + Decimal::from(user_id))
+                .fees(dec!(0.002), dec!(0.001))
+                .build();
+            market
+                .put_order(
+                    &mut sequencer,
+                    (&mut balance_manager).into(),
+                    &mut update_controller,
+                    &FeeManager::default(),
+                    &mut DummyPersistor::default(),
+                    order_input,
+                )
+                .unwrap()
+                .id
+        };
+        // the create times interleave the markets, the last two orders are at the same time
+        let mut ids = Vec::new();
+        for (i, name) in [
+            "ETH_USDT_3",
+            "ETH_USDT",
+            "ETH_USDT_2",
+            "ETH_USDT",
+            "ETH_USDT_3",
+            "ETH_USDT_2",
+            "ETH_USDT",
+        ]
+        .iter()
+        .enumerate()
+        {
+            ids.push(put(&mut markets, name, 1, 1000.0 + i.min(5) as f64));
+        }
+        put(&mut markets, "ETH_USDT_2", 2, 1000.0);
+
+        // cancel one, and the whole of the user in a market
+        markets
+            .get_mut("ETH_USDT_2")
+            .unwrap()
+            .cancel((&mut balance_manager).into(), &mut DummyPersistor::default(), ids[2], 1)
+            .unwrap();
+        markets
+            .get_mut("ETH_USDT_3")
+            .unwrap()
+            .cancel_all_for_user((&mut balance_manager).into(), &mut DummyPersistor::default(), 1)
+            .unwrap();
+        let expected = vec![ids[1], ids[3], ids[5], ids[6]];
+        assert_eq!(index.order_count(1), expected.len());
+
+        let all = index.open_orders(1, &markets, &OrderPagination::default());
+        assert_eq!(all.orders.iter().map(|order| order.id).collect::<Vec<_>>(), expected);
+        assert_eq!(all.total, expected.len());
+        assert!(all.next.is_none());
+
+        // pages of 3, then the rest
+        let mut pagination = OrderPagination { limit: 3, after: None };
+        let first = index.open_orders(1, &markets, &pagination);
+        assert_eq!(first.orders.iter().map(|order| order.id).collect::<Vec<_>>(), expected[..3]);
+        pagination.after = first.next;
+        let second = index.open_orders(1, &markets, &pagination);
+        assert_eq!(second.orders.iter().map(|order| order.id).collect::<Vec<_>>(), expected[3..]);
+        assert!(second.next.is_none());
+
+        // closing a market drains the index too
+        let drained = markets.get_mut("ETH_USDT").unwrap();
+        drained.drain_all_orders((&mut balance_manager).into(), &mut DummyPersistor::default());
+        let rest = index.open_orders(1, &markets, &OrderPagination::default());
+        assert_eq!(rest.orders.iter().map(|order| order.id).collect::<Vec<_>>(), vec![ids[5]]);
+        assert_eq!(index.order_count(2), 1);
+        markets.get_mut("ETH_USDT_2").unwrap().reset();
+        assert_eq!(index.order_count(1), 0);
+        assert_eq!(index.order_count(2), 0);
+    }
+}
@@ -0,0 +1,245 @@
+// the metrics of the match engine for prometheus, scraped from `/metrics`. all of them are no-ops
+// unless built with the `metrics` feature, so the hot path pays nothing for them by default
+use crate::market::{MarketError, OrderSide};
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use super::*;
+    use lazy_static::lazy_static;
+    use prometheus::core::Collector;
+    use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+    use std::time::Instant;
+
+    lazy_static! {
+        static ref REGISTRY: Registry = Registry::new();
+        static ref ORDERS_RECEIVED: IntCounterVec = register(IntCounterVec::new(
+            Opts::new("matchengine_orders_received_total", "orders put, accepted or not"),
+            &["market"]
+        ));
+        static ref ORDERS_REJECTED: IntCounterVec = register(IntCounterVec::new(
+            Opts::new("matchengine_orders_rejected_total", "orders rejected, by the reason"),
+            &["market", "reason"]
+        ));
+        static ref TRADES: IntCounterVec = register(IntCounterVec::new(
+            Opts::new("matchengine_trades_total", "trades executed"),
+            &["market"]
+        ));
+        static ref MATCH_LATENCY: HistogramVec = register(HistogramVec::new(
+            HistogramOpts::new("matchengine_match_seconds", "time of a put order, the matching included")
+                .buckets(vec![0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1]),
+            &["market"]
+        ));
+        static ref BOOK_ORDERS: IntGaugeVec = register(IntGaugeVec::new(
+            Opts::new("matchengine_book_orders", "resting orders in the book"),
+            &["market", "side"]
+        ));
+        static ref PERSISTOR_AVAILABLE: IntGauge = register(IntGauge::new(
+            "matchengine_persistor_available",
+            "1 if the persistors took the last request, 0 if they were saturated"
+        ));
+        static ref PERSISTOR_SATURATED: IntCounter = register(IntCounter::new(
+            "matchengine_persistor_saturated_total",
+            "requests refused as the persistors were saturated"
+        ));
+        static ref BALANCE_DUPLICATES: IntCounter = register(IntCounter::new(
+            "matchengine_balance_update_duplicates_total",
+            "balance updates rejected as duplicates"
+        ));
+    }
+
+    fn register<T: Collector + Clone + 'static>(metric: prometheus::Result<T>) -> T {
+        let metric = metric.expect("invalid metric");
+        REGISTRY.register(Box::new(metric.clone())).expect("metric registered twice");
+        metric
+    }
+
+    pub struct MatchTimer(Instant);
+
+    impl MatchTimer {
+        pub fn start() -> Self {
+            MatchTimer(Instant::now())
+        }
+    }
+
+    pub fn order_put<T>(market: &str, result: &Result<T, MarketError>, timer: MatchTimer) {
+        MATCH_LATENCY.with_label_values(&[market]).observe(timer.0.elapsed().as_secs_f64());
+        ORDERS_RECEIVED.with_label_values(&[market]).inc();
+        if let Err(error) = result {
+            ORDERS_REJECTED.with_label_values(&[market, error.reason()]).inc();
+        }
+    }
+    pub fn trade_executed(market: &str) {
+        TRADES.with_label_values(&[market]).inc();
+    }
+    pub fn book_size(market: &str, side: OrderSide, orders: usize) {
+        let side = if side == OrderSide::ASK { "ask" } else { "bid" };
+        BOOK_ORDERS.with_label_values(&[market, side]).set(orders as i64);
+    }
+    pub fn persistor_probe(available: bool) {
+        PERSISTOR_AVAILABLE.set(available as i64);
+        if !available {
+            PERSISTOR_SATURATED.inc();
+        }
+    }
+    pub fn balance_update_duplicate() {
+        BALANCE_DUPLICATES.inc();
+    }
+
+    // the text format of prometheus
+    pub fn gather() -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&REGISTRY.gather(), &mut buf).expect("encode metrics");
+        String::from_utf8(buf).expect("metrics are utf8")
+    }
+
+    // answers every request with the metrics
+    pub async fn serve(addr: std::net::SocketAddr) -> anyhow::Result<()> {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response, Server};
+
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|_: Request<Body>| async {
+                Ok::<_, hyper::Error>(Response::new(Body::from(gather())))
+            }))
+        });
+        log::info!("serving metrics on {}", addr);
+        Server::bind(&addr).serve(make_service).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub use enabled::*;
+
+#[cfg(not(feature = "metrics"))]
+mod disabled {
+    use super::*;
+
+    pub struct MatchTimer;
+
+    impl MatchTimer {
+        #[inline(always)]
+        pub fn start() -> Self {
+            MatchTimer
+        }
+    }
+
+    #[inline(always)]
+    pub fn order_put<T>(_market: &str, _result: &Result<T, MarketError>, _timer: MatchTimer) {}
+    #[inline(always)]
+    pub fn trade_executed(_market: &str) {}
+    #[inline(always)]
+    pub fn book_size(_market: &str, _side: OrderSide, _orders: usize) {}
+    #[inline(always)]
+    pub fn persistor_probe(_available: bool) {}
+    #[inline(always)]
+    pub fn balance_update_duplicate() {}
+    pub fn gather() -> String {
+        String::new()
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub use disabled::*;
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceType, BalanceUpdateController, BalanceUpdateParams, BusinessType};
+    use crate::config::Settings;
+    use crate::fee::FeeManager;
+    use crate::market::Market;
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use crate::sequencer::Sequencer;
+    use fluidex_common::rust_decimal_macros::*;
+
+    // the value of the sample line of `name`, e.g. `name{market="x"}`
+    fn sample(metrics: &str, name: &str) -> Option<f64> {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+    }
+
+    #[test]
+    fn test_scrape_after_trades() {
+        let mut sequencer = Sequencer::default();
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::new();
+        let mut persistor = DummyPersistor::default();
+        // the registry is global, the market of this test is counted alone
+        let market_conf = crate::config::Market {
+            name: "METRICS_ETH_USDT".to_string(),
+            ..get_simple_market_config()
+        };
+        let mut market = Market::new(&market_conf, &Settings::default(), &balance_manager).unwrap();
+        for user_id in [1, 2] {
+            let deposit = |asset: String, amount| BalanceUpdateParams {
+                balance_type: BalanceType::AVAILABLE,
+                business_type: BusinessType::Deposit,
+                user_id,
+                business_id: user_id as u64,
+                asset,
+                business: "deposit".to_string(),
+                market_price: dec!(0),
+                change: amount,
+                detail: serde_json::json!({}),
+                signature: vec![],
+            };
+            update_controller
+                .update_user_balance(&mut balance_manager, &mut persistor, deposit(MockAsset::ETH.id(), dec!(10)))
+                .unwrap();
+            update_controller
+                .update_user_balance(&mut balance_manager, &mut persistor, deposit(MockAsset::USDT.id(), dec!(1000)))
+                .unwrap();
+            // a retry
+            assert!(update_controller
+                .update_user_balance(&mut balance_manager, &mut persistor, deposit(MockAsset::USDT.id(), dec!(1000)))
+                .is_err());
+        }
+
+        // two asks, a bid taking one and a half of them, a resting bid, then a bid beyond the balance
+        let orders = [
+            (1, OrderSide::ASK, dec!(1), dec!(100)),
+            (1, OrderSide::ASK, dec!(1), dec!(101)),
+            (2, OrderSide::BID, dec!(1.5), dec!(101)),
+            (2, OrderSide::BID, dec!(1), dec!(90)),
+            (2, OrderSide::BID, dec!(100), dec!(99)),
+        ];
+        for (user_id, side, amount, price) in orders {
+            let order_input = OrderInputBuilder::new(market.name, user_id, side, amount, price).build();
+            let _ = market.put_order(
+                &mut sequencer,
+                (&mut balance_manager).into(),
+                &mut update_controller,
+                &FeeManager::default(),
+                &mut persistor,
+                order_input,
+            );
+        }
+        assert_eq!(market.trade_count, 2);
+
+        let metrics = gather();
+        let market = r#"{market="METRICS_ETH_USDT"}"#;
+        assert_eq!(sample(&metrics, &format!("matchengine_orders_received_total{}", market)), Some(5.0));
+        assert_eq!(
+            sample(
+                &metrics,
+                r#"matchengine_orders_rejected_total{market="METRICS_ETH_USDT",reason="balance_not_enough"}"#
+            ),
+            Some(1.0)
+        );
+        assert_eq!(sample(&metrics, &format!("matchengine_trades_total{}", market)), Some(2.0));
+        assert_eq!(sample(&metrics, &format!("matchengine_match_seconds_count{}", market)), Some(5.0));
+        assert_eq!(
+            sample(&metrics, r#"matchengine_book_orders{market="METRICS_ETH_USDT",side="ask"}"#),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(&metrics, r#"matchengine_book_orders{market="METRICS_ETH_USDT",side="bid"}"#),
+            Some(1.0)
+        );
+        // other tests may reject duplicates at the same time
+        assert!(sample(&metrics, "matchengine_balance_update_duplicates_total").unwrap() >= 2.0);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::asset::{AssetManager, BalanceManager};
 use crate::config;
+use crate::market::{OrderInput, OrderSide, OrderType};
 use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::rust_decimal_macros::*;
 
@@ -12,6 +13,24 @@ pub fn get_simple_market_config() -> config::Market {
         price_prec: 2,
         fee_prec: 2,
         min_amount: dec!(0.01),
+        max_amount: None,
+        max_quote_amount: None,
+        min_notional: dec!(0),
+        post_only_reprice: false,
+        finish_dust_orders: false,
+        max_price_deviation: None,
+        max_open_orders_per_user: None,
+        default_maker_fee: dec!(0),
+        default_taker_fee: dec!(0),
+        fee_rounding: config::FeeRounding::ToZero,
+        min_base_fee: None,
+        min_quote_fee: None,
+        kline_intervals: config::KlineInterval::ALL.to_vec(),
+        kline_history: 100,
+        recent_trades: 100,
+        checksum_levels: 25,
+        cancel_when_halted: false,
+        aliases: Vec::new(),
     }
 }
 pub fn get_integer_prec_market_config() -> config::Market {
@@ -23,6 +42,24 @@ pub fn get_integer_prec_market_config() -> config::Market {
         price_prec: 0,
         fee_prec: 0,
         min_amount: dec!(0),
+        max_amount: None,
+        max_quote_amount: None,
+        min_notional: dec!(0),
+        post_only_reprice: false,
+        finish_dust_orders: false,
+        max_price_deviation: None,
+        max_open_orders_per_user: None,
+        default_maker_fee: dec!(0),
+        default_taker_fee: dec!(0),
+        fee_rounding: config::FeeRounding::ToZero,
+        min_base_fee: None,
+        min_quote_fee: None,
+        kline_intervals: config::KlineInterval::ALL.to_vec(),
+        kline_history: 100,
+        recent_trades: 100,
+        checksum_levels: 25,
+        cancel_when_halted: false,
+        aliases: Vec::new(),
     }
 }
 
@@ -104,3 +141,69 @@ fn get_market_base_and_quote(market: &str) -> (String, String) {
     let splits: Vec<&str> = market.split("_").collect();
     (splits[0].to_owned(), splits[1].to_owned())
 }
+
+// the orders of the tests, a limit order without fees unless set otherwise, e.g.
+// `OrderInputBuilder::new(market.name, 1, OrderSide::ASK, dec!(1), dec!(100)).fees(dec!(0.002), dec!(0.001)).build()`
+pub struct OrderInputBuilder(OrderInput);
+
+impl OrderInputBuilder {
+    pub fn new(market: &str, user_id: u32, side: OrderSide, amount: Decimal, price: Decimal) -> Self {
+        OrderInputBuilder(OrderInput {
+            user_id,
+            side,
+            type_: OrderType::LIMIT,
+            amount,
+            price,
+            quote_limit: dec!(0),
+            amount_is_quote: false,
+            max_slippage: None,
+            client_order_id: None,
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            market: market.to_string(),
+            post_only: false,
+            signature: [0; 64],
+            nonce: 0,
+        })
+    }
+    pub fn type_(mut self, type_: OrderType) -> Self {
+        self.0.type_ = type_;
+        self
+    }
+    pub fn quote_limit(mut self, quote_limit: Decimal) -> Self {
+        self.0.quote_limit = quote_limit;
+        self
+    }
+    pub fn amount_is_quote(mut self) -> Self {
+        self.0.amount_is_quote = true;
+        self
+    }
+    pub fn max_slippage(mut self, max_slippage: Decimal) -> Self {
+        self.0.max_slippage = Some(max_slippage);
+        self
+    }
+    pub fn client_order_id(mut self, client_order_id: Option<u64>) -> Self {
+        self.0.client_order_id = client_order_id;
+        self
+    }
+    pub fn fees(mut self, taker_fee: Decimal, maker_fee: Decimal) -> Self {
+        self.0.taker_fee = taker_fee;
+        self.0.maker_fee = maker_fee;
+        self
+    }
+    pub fn post_only(mut self, post_only: bool) -> Self {
+        self.0.post_only = post_only;
+        self
+    }
+    pub fn signature(mut self, signature: [u8; 64]) -> Self {
+        self.0.signature = signature;
+        self
+    }
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.0.nonce = nonce;
+        self
+    }
+    pub fn build(self) -> OrderInput {
+        self.0
+    }
+}
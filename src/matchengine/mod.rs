@@ -1,10 +1,17 @@
+pub mod admin;
 pub mod asset;
+pub mod audit;
+pub mod clock;
 pub mod controller;
 pub mod dto;
 pub mod eth_guard;
+pub mod fee;
+pub mod health;
 pub mod history;
 pub mod market;
+pub mod metrics;
 pub mod persist;
+pub mod replica;
 pub mod sequencer;
 pub mod server;
 pub mod user_manager;
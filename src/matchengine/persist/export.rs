@@ -0,0 +1,194 @@
+use crate::asset::{BalanceManager, BalanceType};
+use crate::market::{Market, MarketState, OrderSide, OrderType};
+use crate::sequencer::{Sequencer, SequencerState};
+use crate::types::TimestampMs;
+use anyhow::Result;
+use fluidex_common::rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::Write;
+
+// the decimals are written with no trailing zeros, so an equal amount is always the same string
+fn normalized(amount: &Decimal) -> String {
+    amount.normalize().to_string()
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportedMarket {
+    pub name: String,
+    pub state: MarketState,
+    pub ask_count: usize,
+    pub ask_amount: String,
+    pub ask_frozen: String,
+    pub bid_count: usize,
+    pub bid_amount: String,
+    pub bid_frozen: String,
+    pub best_ask: Option<String>,
+    pub best_bid: Option<String>,
+    pub last_price: Option<String>,
+    pub user_count: usize,
+    pub trade_count: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportedOrder {
+    pub market: String,
+    pub id: u64,
+    pub user: u32,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    pub price: String,
+    pub amount: String,
+    pub remain: String,
+    pub frozen: String,
+    pub create_time: TimestampMs,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportedBalance {
+    pub user_id: u32,
+    pub asset: String,
+    pub balance_type: BalanceType,
+    pub amount: String,
+}
+
+// one json line each. the time windowed market stats are left out, they change with the clock alone
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExportRecord {
+    Sequencer(SequencerState),
+    Market(ExportedMarket),
+    Order(ExportedOrder),
+    Balance(ExportedBalance),
+}
+
+// the engine state for an external audit: the sequencer, then every market by name followed by its open
+// orders by id, then the non zero balances by user, asset and type. the same state is always the same bytes
+pub fn export_state<'a>(
+    mut writer: impl Write,
+    sequencer: &Sequencer,
+    balance_manager: &BalanceManager,
+    markets: impl IntoIterator<Item = &'a Market>,
+) -> Result<()> {
+    let mut write = |record: ExportRecord| -> Result<()> {
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    };
+
+    write(ExportRecord::Sequencer(sequencer.state()))?;
+
+    let mut markets = markets.into_iter().collect::<Vec<_>>();
+    markets.sort_by(|a, b| a.name.cmp(&b.name));
+    for market in markets {
+        let status = market.status();
+        write(ExportRecord::Market(ExportedMarket {
+            name: status.name,
+            state: status.state,
+            ask_count: status.ask_count,
+            ask_amount: normalized(&status.ask_amount),
+            ask_frozen: normalized(&status.ask_frozen),
+            bid_count: status.bid_count,
+            bid_amount: normalized(&status.bid_amount),
+            bid_frozen: normalized(&status.bid_frozen),
+            best_ask: status.best_ask.as_ref().map(normalized),
+            best_bid: status.best_bid.as_ref().map(normalized),
+            last_price: status.last_price.as_ref().map(normalized),
+            user_count: status.user_count,
+            trade_count: status.trade_count,
+        }))?;
+        // keyed by id
+        for order in market.orders.values() {
+            let order = order.borrow();
+            write(ExportRecord::Order(ExportedOrder {
+                market: order.market.to_string(),
+                id: order.id,
+                user: order.user,
+                side: order.side,
+                order_type: order.type_,
+                price: normalized(&order.price),
+                amount: normalized(&order.amount),
+                remain: normalized(&order.remain),
+                frozen: normalized(&order.frozen),
+                create_time: order.create_time,
+            }))?;
+        }
+    }
+
+    let mut balances = balance_manager
+        .balances
+        .iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .collect::<Vec<_>>();
+    balances.sort_by_key(|(key, _)| (key.user_id, key.asset, key.balance_type as i16));
+    for (key, amount) in balances {
+        write(ExportRecord::Balance(ExportedBalance {
+            user_id: key.user_id,
+            asset: key.asset.to_string(),
+            balance_type: key.balance_type,
+            amount: normalized(amount),
+        }))?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::BalanceUpdateController;
+    use crate::config::Settings;
+    use crate::fee::FeeManager;
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use fluidex_common::rust_decimal_macros::*;
+
+    #[test]
+    fn test_export_state_deterministic() {
+        let mut sequencer = Sequencer::default();
+        let mut balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut update_controller = BalanceUpdateController::new();
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), &balance_manager).unwrap();
+        for user_id in 0..4 {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, market.base, &dec!(100.000));
+            balance_manager.add(user_id, BalanceType::AVAILABLE, market.quote, &dec!(10000));
+        }
+        let orders = [
+            (0, OrderSide::ASK, dec!(3.50), dec!(130.0)),
+            (1, OrderSide::ASK, dec!(2), dec!(128)),
+            (2, OrderSide::BID, dec!(1.0), dec!(128.00)),
+            (3, OrderSide::BID, dec!(4), dec!(125)),
+        ];
+        for (user_id, side, amount, price) in orders {
+            let order_input = OrderInputBuilder::new(market.name, user_id, side, amount, price)
+                .fees(dec!(0.002), dec!(0.001))
+                .build();
+            market
+                .put_order(
+                    &mut sequencer,
+                    (&mut balance_manager).into(),
+                    &mut update_controller,
+                    &FeeManager::default(),
+                    &mut DummyPersistor::default(),
+                    order_input,
+                )
+                .unwrap();
+        }
+        assert!(market.trade_count > 0);
+
+        let export = || {
+            let mut buf = Vec::new();
+            export_state(&mut buf, &sequencer, &balance_manager, [&market]).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let first = export();
+        assert_eq!(first, export());
+
+        let lines = first.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with(r#"{"type":"sequencer""#));
+        assert!(lines[1].starts_with(r#"{"type":"market""#));
+        // the filled bid is gone, the partially filled ask is left
+        assert_eq!(lines.iter().filter(|line| line.starts_with(r#"{"type":"order""#)).count(), 3);
+        assert!(first.contains(r#""price":"130","amount":"3.5","remain":"3.5""#), "{}", first);
+    }
+}
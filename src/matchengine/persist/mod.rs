@@ -2,3 +2,13 @@ mod state_save_load;
 pub use state_save_load::*;
 mod persistor;
 pub use persistor::*;
+mod shared_trade;
+pub use shared_trade::*;
+mod snapshot;
+pub use snapshot::*;
+mod reconcile;
+pub use reconcile::*;
+mod rollback;
+pub use rollback::*;
+mod export;
+pub use export::*;
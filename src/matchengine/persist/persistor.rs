@@ -1,28 +1,95 @@
-use crate::history::HistoryWriter;
-use crate::matchengine::market::{Order, Trade};
-use crate::message::{self, MessageManager, OrderMessage};
+use super::{SharedTrade, Tombstone};
+use crate::audit::{ConservationViolation, FrozenMismatch};
+use crate::config;
+use crate::controller::OperationLogEntry;
+use crate::history::{HistoryItem, HistoryWriter};
+use crate::matchengine::market::{DepthUpdate, Kline, MarketEvent, Order, Trade, TradeFeeRecord};
+use crate::message::proto::{self, ToKind};
+use crate::message::{self, BalanceMessage, MessageFormat, MessageManager, OrderMessage, ProducerStats};
+use crate::message::{
+    BALANCES_TOPIC, DEPOSITS_TOPIC, FEES_TOPIC, INTERNALTX_TOPIC, MARKETS_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, USER_TOPIC, WITHDRAWS_TOPIC,
+};
 pub use crate::models::{AccountDesc, BalanceHistory, InternalTx};
-use crate::types::OrderEventType;
+use crate::sequencer::MsgSeq;
+use crate::types::{OrderEventType, OrderFinish};
+use fluidex_common::utils::timeutil::current_timestamp;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 
 ///////////////////////////// PersistExector interface ////////////////////////////
 
+// whether a child of a composite takes the events now
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SinkStatus {
+    pub name: String,
+    pub critical: bool,
+    pub available: bool,
+}
+
 // TODO: fix methods, use ref or value?
 pub trait PersistExector: Send + Sync {
     fn service_available(&self) -> bool {
         true
     }
-    // make sure all data has been persisted
-    //fn flush(&self) {
-    //}
+    // make sure all data has been persisted, e.g. before a slice is made or the process exits
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    // write what has been buffered longer than the persistor waits for, called by the scheduler between the commands
+    fn flush_elapsed(&mut self) {}
+    // stop the background workers, e.g. the kafka producers, once they sent what is left. nothing is put after it
+    fn shutdown(&mut self) {}
     fn real_persist(&self) -> bool {
         true
     }
+    // the producers sending the events, by the names of the persistors
+    fn producer_stats(&self) -> Vec<(String, ProducerStats)> {
+        Vec::new()
+    }
+    // the children of a composite, none for a single persistor
+    fn sink_status(&self) -> Vec<SinkStatus> {
+        Vec::new()
+    }
     fn put_balance(&mut self, balance: &BalanceHistory);
     fn put_deposit(&mut self, balance: &BalanceHistory);
     fn put_withdraw(&mut self, balance: &BalanceHistory);
     fn put_transfer(&mut self, tx: InternalTx);
     fn put_order(&mut self, order: &Order, at_step: OrderEventType);
+    // the order is finished, canceled or expired, with the reason and who made it
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        self.put_order(order, finish.event())
+    }
+    // the price or the amount of a resting order is changed by the user
+    fn put_amended_order(&mut self, _before: &Order, after: &Order) {
+        self.put_order(after, OrderEventType::UPDATE)
+    }
     fn put_trade(&mut self, trade: &Trade);
+    // the same trade handed to several persistors, which may reuse its json
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        self.put_trade(trade.trade())
+    }
+    // two records per trade, one for each side
+    fn put_fee(&mut self, fee: &TradeFeeRecord);
+    fn put_depth_update(&mut self, _update: &DepthUpdate) {}
+    // a candle is closed
+    fn put_kline(&mut self, _kline: &Kline) {}
+    // the totals of the assets are not kept by a command, only checked when `verify_conservation` is on
+    fn put_conservation_violation(&mut self, _violation: &ConservationViolation) {}
+    // the FREEZE balance is short of the open orders, found when reconciling it
+    fn put_frozen_deficit(&mut self, _mismatch: &FrozenMismatch) {}
+    // the logged operations superseded by a rollback
+    fn put_tombstone(&mut self, _tombstone: &Tombstone) {}
+    // the operation whose events were put just before it, the same as appended to the operation log
+    fn put_operation_log(&mut self, _operation: &OperationLogEntry) {}
+    fn put_market_event(&mut self, event: MarketEvent);
     fn register_user(&mut self, user: AccountDesc);
 }
 
@@ -33,6 +100,21 @@ impl PersistExector for Box<dyn PersistExector + '_> {
     fn real_persist(&self) -> bool {
         self.as_ref().real_persist()
     }
+    fn producer_stats(&self) -> Vec<(String, ProducerStats)> {
+        self.as_ref().producer_stats()
+    }
+    fn sink_status(&self) -> Vec<SinkStatus> {
+        self.as_ref().sink_status()
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.as_mut().flush()
+    }
+    fn flush_elapsed(&mut self) {
+        self.as_mut().flush_elapsed()
+    }
+    fn shutdown(&mut self) {
+        self.as_mut().shutdown()
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
         self.as_mut().put_balance(balance)
     }
@@ -48,9 +130,42 @@ impl PersistExector for Box<dyn PersistExector + '_> {
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
         self.as_mut().put_order(order, at_step)
     }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        self.as_mut().put_finished_order(order, finish)
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        self.as_mut().put_amended_order(before, after)
+    }
     fn put_trade(&mut self, trade: &Trade) {
         self.as_mut().put_trade(trade)
     }
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        self.as_mut().put_trade_shared(trade)
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        self.as_mut().put_fee(fee)
+    }
+    fn put_depth_update(&mut self, update: &DepthUpdate) {
+        self.as_mut().put_depth_update(update)
+    }
+    fn put_kline(&mut self, kline: &Kline) {
+        self.as_mut().put_kline(kline)
+    }
+    fn put_conservation_violation(&mut self, violation: &ConservationViolation) {
+        self.as_mut().put_conservation_violation(violation)
+    }
+    fn put_frozen_deficit(&mut self, mismatch: &FrozenMismatch) {
+        self.as_mut().put_frozen_deficit(mismatch)
+    }
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        self.as_mut().put_tombstone(tombstone)
+    }
+    fn put_operation_log(&mut self, operation: &OperationLogEntry) {
+        self.as_mut().put_operation_log(operation)
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.as_mut().put_market_event(event)
+    }
     fn register_user(&mut self, user: AccountDesc) {
         self.as_mut().register_user(user)
     }
@@ -63,6 +178,21 @@ impl PersistExector for &mut Box<dyn PersistExector + '_> {
     fn real_persist(&self) -> bool {
         self.as_ref().real_persist()
     }
+    fn producer_stats(&self) -> Vec<(String, ProducerStats)> {
+        self.as_ref().producer_stats()
+    }
+    fn sink_status(&self) -> Vec<SinkStatus> {
+        self.as_ref().sink_status()
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.as_mut().flush()
+    }
+    fn flush_elapsed(&mut self) {
+        self.as_mut().flush_elapsed()
+    }
+    fn shutdown(&mut self) {
+        self.as_mut().shutdown()
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
         self.as_mut().put_balance(balance)
     }
@@ -78,9 +208,42 @@ impl PersistExector for &mut Box<dyn PersistExector + '_> {
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
         self.as_mut().put_order(order, at_step)
     }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        self.as_mut().put_finished_order(order, finish)
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        self.as_mut().put_amended_order(before, after)
+    }
     fn put_trade(&mut self, trade: &Trade) {
         self.as_mut().put_trade(trade)
     }
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        self.as_mut().put_trade_shared(trade)
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        self.as_mut().put_fee(fee)
+    }
+    fn put_depth_update(&mut self, update: &DepthUpdate) {
+        self.as_mut().put_depth_update(update)
+    }
+    fn put_kline(&mut self, kline: &Kline) {
+        self.as_mut().put_kline(kline)
+    }
+    fn put_conservation_violation(&mut self, violation: &ConservationViolation) {
+        self.as_mut().put_conservation_violation(violation)
+    }
+    fn put_frozen_deficit(&mut self, mismatch: &FrozenMismatch) {
+        self.as_mut().put_frozen_deficit(mismatch)
+    }
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        self.as_mut().put_tombstone(tombstone)
+    }
+    fn put_operation_log(&mut self, operation: &OperationLogEntry) {
+        self.as_mut().put_operation_log(operation)
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.as_mut().put_market_event(event)
+    }
     fn register_user(&mut self, user: AccountDesc) {
         self.as_mut().register_user(user)
     }
@@ -110,6 +273,8 @@ impl PersistExector for DummyPersistor {
     fn put_transfer(&mut self, _tx: InternalTx) {}
     fn put_order(&mut self, _order: &Order, _as_step: OrderEventType) {}
     fn put_trade(&mut self, _trade: &Trade) {}
+    fn put_fee(&mut self, _fee: &TradeFeeRecord) {}
+    fn put_market_event(&mut self, _event: MarketEvent) {}
     fn register_user(&mut self, _user: AccountDesc) {}
 }
 
@@ -123,72 +288,499 @@ impl PersistExector for &mut DummyPersistor {
     fn put_transfer(&mut self, _tx: InternalTx) {}
     fn put_order(&mut self, _order: &Order, _as_step: OrderEventType) {}
     fn put_trade(&mut self, _trade: &Trade) {}
+    fn put_fee(&mut self, _fee: &TradeFeeRecord) {}
+    fn put_market_event(&mut self, _event: MarketEvent) {}
     fn register_user(&mut self, _user: AccountDesc) {}
 }
 
+///////////////////////////// BufferedPersistor ////////////////////////////
+
+type BufferedEvent = Box<dyn FnOnce(&mut dyn PersistExector) + Send + Sync>;
+
+// keeps the events in order and hands them to another persistor at once by `flush_into`,
+// so the events of a command made of several steps, e.g. replacing orders, go out as one batch
+pub struct BufferedPersistor {
+    real_persist: bool,
+    events: Vec<BufferedEvent>,
+}
+impl BufferedPersistor {
+    // `real_persist` of the persistor the events are handed to
+    pub fn new(real_persist: bool) -> Self {
+        Self {
+            real_persist,
+            events: Vec::new(),
+        }
+    }
+    fn buffer(&mut self, event: impl FnOnce(&mut dyn PersistExector) + Send + Sync + 'static) {
+        self.events.push(Box::new(event));
+    }
+    pub fn flush_into(self, persistor: &mut impl PersistExector) {
+        for event in self.events {
+            event(&mut *persistor);
+        }
+    }
+}
+impl PersistExector for BufferedPersistor {
+    fn real_persist(&self) -> bool {
+        self.real_persist
+    }
+    fn put_balance(&mut self, balance: &BalanceHistory) {
+        let balance = balance.clone();
+        self.buffer(move |p| p.put_balance(&balance))
+    }
+    fn put_deposit(&mut self, balance: &BalanceHistory) {
+        let balance = balance.clone();
+        self.buffer(move |p| p.put_deposit(&balance))
+    }
+    fn put_withdraw(&mut self, balance: &BalanceHistory) {
+        let balance = balance.clone();
+        self.buffer(move |p| p.put_withdraw(&balance))
+    }
+    fn put_transfer(&mut self, tx: InternalTx) {
+        self.buffer(move |p| p.put_transfer(tx))
+    }
+    fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
+        let order = *order;
+        self.buffer(move |p| p.put_order(&order, at_step))
+    }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        let order = *order;
+        self.buffer(move |p| p.put_finished_order(&order, finish))
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        let (before, after) = (*before, *after);
+        self.buffer(move |p| p.put_amended_order(&before, &after))
+    }
+    fn put_trade(&mut self, trade: &Trade) {
+        let trade = trade.clone();
+        self.buffer(move |p| p.put_trade(&trade))
+    }
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        let trade = trade.clone();
+        self.buffer(move |p| p.put_trade_shared(&trade))
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        let fee = fee.clone();
+        self.buffer(move |p| p.put_fee(&fee))
+    }
+    fn put_depth_update(&mut self, update: &DepthUpdate) {
+        let update = update.clone();
+        self.buffer(move |p| p.put_depth_update(&update))
+    }
+    fn put_kline(&mut self, kline: &Kline) {
+        let kline = kline.clone();
+        self.buffer(move |p| p.put_kline(&kline))
+    }
+    fn put_conservation_violation(&mut self, violation: &ConservationViolation) {
+        let violation = violation.clone();
+        self.buffer(move |p| p.put_conservation_violation(&violation))
+    }
+    fn put_frozen_deficit(&mut self, mismatch: &FrozenMismatch) {
+        let mismatch = mismatch.clone();
+        self.buffer(move |p| p.put_frozen_deficit(&mismatch))
+    }
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        let tombstone = *tombstone;
+        self.buffer(move |p| p.put_tombstone(&tombstone))
+    }
+    fn put_operation_log(&mut self, operation: &OperationLogEntry) {
+        let operation = operation.clone();
+        self.buffer(move |p| p.put_operation_log(&operation))
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.buffer(move |p| p.put_market_event(event))
+    }
+    fn register_user(&mut self, user: AccountDesc) {
+        self.buffer(move |p| p.register_user(user))
+    }
+}
+
 ///////////////////////////// MemBasedPersistor ////////////////////////////
 
+// keeps the messages in memory, for the tests and debugging.
+// the typed accessors and `drain` are preferred to reading `messages` directly
 #[derive(Default)]
 pub struct MemBasedPersistor {
     pub messages: Vec<crate::message::Message>,
+    // the oldest messages are dropped beyond it, zero for no limit
+    capacity: usize,
+    dropped: usize,
 }
 impl MemBasedPersistor {
     pub fn new() -> Self {
-        Self { messages: Vec::new() }
+        Self::default()
+    }
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+    fn push(&mut self, msg: message::Message) {
+        if self.capacity > 0 && self.messages.len() >= self.capacity {
+            self.messages.remove(0);
+            self.dropped += 1;
+        }
+        self.messages.push(msg);
+    }
+    // the messages evicted because of the capacity
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+    pub fn drain(&mut self) -> Vec<message::Message> {
+        std::mem::take(&mut self.messages)
+    }
+    pub fn orders(&self) -> Vec<&OrderMessage> {
+        self.messages
+            .iter()
+            .filter_map(|msg| match msg {
+                message::Message::OrderMessage(order) => Some(order.as_ref()),
+                _ => None,
+            })
+            .collect()
+    }
+    pub fn trades(&self) -> Vec<&Trade> {
+        self.messages
+            .iter()
+            .filter_map(|msg| match msg {
+                message::Message::TradeMessage(trade) => Some(trade.as_ref()),
+                _ => None,
+            })
+            .collect()
+    }
+    pub fn fees(&self) -> Vec<&TradeFeeRecord> {
+        self.messages
+            .iter()
+            .filter_map(|msg| match msg {
+                message::Message::FeeMessage(fee) => Some(fee.as_ref()),
+                _ => None,
+            })
+            .collect()
+    }
+    // the balance changes other than the deposits and withdraws
+    pub fn balances(&self) -> Vec<&BalanceMessage> {
+        self.messages
+            .iter()
+            .filter_map(|msg| match msg {
+                message::Message::BalanceMessage(balance) => Some(balance.as_ref()),
+                _ => None,
+            })
+            .collect()
+    }
+    pub fn deposits(&self) -> Vec<&BalanceMessage> {
+        self.messages
+            .iter()
+            .filter_map(|msg| match msg {
+                message::Message::DepositMessage(balance) => Some(balance.as_ref()),
+                _ => None,
+            })
+            .collect()
+    }
+    pub fn withdraws(&self) -> Vec<&BalanceMessage> {
+        self.messages
+            .iter()
+            .filter_map(|msg| match msg {
+                message::Message::WithdrawMessage(balance) => Some(balance.as_ref()),
+                _ => None,
+            })
+            .collect()
     }
 }
 
 impl PersistExector for MemBasedPersistor {
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
-        self.messages
-            .push(message::Message::OrderMessage(Box::new(OrderMessage::from_order(order, at_step))));
+        self.push(message::Message::OrderMessage(Box::new(OrderMessage::from_order(order, at_step))));
+    }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        self.push(message::Message::OrderMessage(Box::new(OrderMessage::from_finished_order(
+            order, finish,
+        ))));
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        self.push(message::Message::OrderMessage(Box::new(OrderMessage::from_amended_order(
+            before, after,
+        ))));
     }
     fn put_trade(&mut self, trade: &Trade) {
-        self.messages.push(message::Message::TradeMessage(Box::new(trade.clone())));
+        self.push(message::Message::TradeMessage(Box::new(trade.clone())));
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        self.push(message::Message::FeeMessage(Box::new(fee.clone())));
+    }
+    fn put_depth_update(&mut self, update: &DepthUpdate) {
+        self.push(message::Message::DepthUpdateMessage(Box::new(update.clone())));
+    }
+    fn put_kline(&mut self, kline: &Kline) {
+        self.push(message::Message::KlineMessage(Box::new(kline.clone())));
+    }
+    fn put_conservation_violation(&mut self, violation: &ConservationViolation) {
+        self.push(message::Message::ConservationViolationMessage(Box::new(violation.clone())));
+    }
+    fn put_frozen_deficit(&mut self, mismatch: &FrozenMismatch) {
+        self.push(message::Message::FrozenDeficitMessage(Box::new(mismatch.clone())));
+    }
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        self.push(message::Message::TombstoneMessage(Box::new(*tombstone)));
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.push(message::Message::MarketEventMessage(Box::new(event)));
     }
     fn put_balance(&mut self, balance: &BalanceHistory) {
-        self.messages.push(message::Message::BalanceMessage(Box::new(balance.into())));
+        self.push(message::Message::BalanceMessage(Box::new(balance.into())));
     }
     fn put_deposit(&mut self, balance: &BalanceHistory) {
-        self.messages.push(message::Message::DepositMessage(Box::new(balance.into())));
+        self.push(message::Message::DepositMessage(Box::new(balance.into())));
     }
     fn put_withdraw(&mut self, balance: &BalanceHistory) {
-        self.messages.push(message::Message::WithdrawMessage(Box::new(balance.into())));
+        self.push(message::Message::WithdrawMessage(Box::new(balance.into())));
     }
     fn put_transfer(&mut self, tx: InternalTx) {
-        self.messages.push(message::Message::TransferMessage(Box::new(tx.into())));
+        self.push(message::Message::TransferMessage(Box::new(tx.into())));
     }
     fn register_user(&mut self, user: AccountDesc) {
-        self.messages.push(message::Message::UserMessage(Box::new(user.into())));
+        self.push(message::Message::UserMessage(Box::new(user.into())));
     }
 }
 
 ///////////////////////////// FileBasedPersistor ////////////////////////////
 
+// the sink of the segments, a file except in tests
+pub trait SegmentWriter: Write + Send + Sync {
+    fn sync(&mut self) -> std::io::Result<()>;
+}
+
+impl SegmentWriter for std::fs::File {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_all()
+    }
+}
+
+// consecutive failed writes before the persistor reports itself unavailable
+const MAX_WRITE_FAILURES: usize = 3;
+
+// writes one json message per line. the messages are written to `path`, which is renamed with a
+// timestamp suffix when it is rotated
 pub struct FileBasedPersistor {
-    output_file: std::fs::File,
+    path: String,
+    settings: config::FilePersistSettings,
+    output_file: BufWriter<Box<dyn SegmentWriter>>,
+    // bytes written to the current segment
+    written: u64,
+    opened_at: DateTime<Utc>,
+    last_flush: Instant,
+    rotated: Vec<String>,
+    // the lines not written because of io errors, retried on the next write
+    pending: VecDeque<String>,
+    failures: usize,
+    dropped: usize,
+    // the lines are envelopes with the seq taken by the composite, none for the legacy format
+    msg_seq: Option<MsgSeq>,
 }
 impl FileBasedPersistor {
-    pub fn new(output_file_name: &str) -> Self {
-        let output_file = std::fs::File::create(output_file_name).unwrap();
-        Self { output_file }
+    pub fn new(output_file_name: &str) -> Result<Self> {
+        Self::with_settings(output_file_name, &config::FilePersistSettings::default())
+    }
+    pub fn with_settings(output_file_name: &str, settings: &config::FilePersistSettings) -> Result<Self> {
+        let file = Self::create(output_file_name)?;
+        Ok(Self::with_writer(output_file_name, settings, file))
+    }
+    // `path` is only used to rotate the segments
+    pub fn with_writer(path: &str, settings: &config::FilePersistSettings, writer: Box<dyn SegmentWriter>) -> Self {
+        Self {
+            path: path.to_string(),
+            settings: settings.clone(),
+            output_file: BufWriter::with_capacity(settings.buffer_bytes, writer),
+            written: 0,
+            opened_at: Utc::now(),
+            last_flush: Instant::now(),
+            rotated: Vec::new(),
+            pending: VecDeque::new(),
+            failures: 0,
+            dropped: 0,
+            msg_seq: None,
+        }
+    }
+    pub fn with_envelope(mut self, msg_seq: MsgSeq) -> Self {
+        self.msg_seq = Some(msg_seq);
+        self
+    }
+    fn create(path: &str) -> Result<Box<dyn SegmentWriter>> {
+        match std::fs::File::create(path) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(e) => bail!("create {} failed: {}", path, e),
+        }
     }
     pub fn write_msg(&mut self, msg: message::Message) {
-        use std::io::Write;
-        let s = serde_json::to_string(&msg).unwrap();
-        self.output_file.write_fmt(format_args!("{}\n", s)).unwrap();
+        let line = match &self.msg_seq {
+            // the type and the value of the message are the kind and the payload of the envelope
+            Some(msg_seq) => serde_json::to_value(&msg).map(|mut value| {
+                let payload = value["value"].take().to_string();
+                let kind = value["type"].as_str().unwrap_or_default();
+                message::envelope_json(msg_seq.current(), current_timestamp(), kind, &payload)
+            }),
+            None => serde_json::to_string(&msg),
+        };
+        match line {
+            Ok(s) => self.write_line(s),
+            Err(e) => log::error!("serialize message failed: {}", e),
+        }
+    }
+
+    fn write_line(&mut self, mut s: String) {
+        s.push('\n');
+        if self.pending.len() >= self.settings.max_pending {
+            self.pending.pop_front();
+            self.dropped += 1;
+            log::error!("file persistor dropped a message, {} dropped in total", self.dropped);
+        }
+        self.pending.push_back(s);
+        match self.write_pending() {
+            Ok(()) => self.failures = 0,
+            Err(e) => {
+                self.failures += 1;
+                log::error!("file persistor write failed {} times in a row: {}", self.failures, e);
+            }
+        }
+    }
+    fn write_pending(&mut self) -> Result<()> {
+        if self.settings.hourly && Utc::now().timestamp() / 3600 != self.opened_at.timestamp() / 3600 {
+            self.rotate()?;
+        }
+        while let Some(line) = self.pending.front() {
+            self.output_file.write_all(line.as_bytes())?;
+            self.written += line.len() as u64;
+            self.pending.pop_front();
+            // a message is never split, the segment is rotated after the whole line is written
+            if self.settings.max_bytes > 0 && self.written >= self.settings.max_bytes {
+                self.rotate()?;
+            }
+        }
+        if self.last_flush.elapsed().as_secs_f64() >= self.settings.flush_interval {
+            self.output_file.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+    // the paths of the rotated segments, in the order they were written
+    pub fn rotated_segments(&self) -> &[String] {
+        &self.rotated
+    }
+    // the messages given up after the retry queue is full
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+    fn rotate(&mut self) -> Result<()> {
+        self.output_file.flush()?;
+        self.output_file.get_mut().sync()?;
+        // the sequence number keeps the names unique within a second
+        let mut rotated = format!("{}.{}-{:06}", self.path, self.opened_at.format("%Y%m%d%H%M%S"), self.rotated.len());
+        std::fs::rename(&self.path, &rotated)?;
+        let file = match Self::create(&self.path) {
+            Ok(file) => file,
+            Err(e) => {
+                // keep writing to the current segment
+                std::fs::rename(&rotated, &self.path)?;
+                return Err(e);
+            }
+        };
+        self.output_file = BufWriter::with_capacity(self.settings.buffer_bytes, file);
+        self.written = 0;
+        self.opened_at = Utc::now();
+        if self.settings.gzip {
+            // the uncompressed segment is kept if it can not be compressed
+            match gzip(&rotated) {
+                Ok(compressed) => rotated = compressed,
+                Err(e) => log::error!("compress {} failed: {}", rotated, e),
+            }
+        }
+        self.rotated.push(rotated);
+        Ok(())
     }
 }
 
+fn gzip(path: &str) -> Result<String> {
+    let compressed = format!("{}.gz", path);
+    let mut input = std::fs::File::open(path)?;
+    let output = std::fs::File::create(&compressed)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std::fs::remove_file(path)?;
+    Ok(compressed)
+}
+
+// the same as serializing a `Message::TradeMessage`, without serializing the trade again
+fn trade_message_json(trade: &SharedTrade) -> String {
+    format!("{{\"type\":\"TradeMessage\",\"value\":{}}}", trade.json())
+}
+
 impl PersistExector for FileBasedPersistor {
+    // unavailable after writes keep failing, the messages are kept until the retry queue is full
+    fn service_available(&self) -> bool {
+        self.failures < MAX_WRITE_FAILURES
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.write_pending()?;
+        self.output_file.flush()?;
+        self.output_file.get_mut().sync()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
         let msg = message::Message::OrderMessage(Box::new(OrderMessage::from_order(order, at_step)));
         self.write_msg(msg);
     }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        let msg = message::Message::OrderMessage(Box::new(OrderMessage::from_finished_order(order, finish)));
+        self.write_msg(msg);
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        let msg = message::Message::OrderMessage(Box::new(OrderMessage::from_amended_order(before, after)));
+        self.write_msg(msg);
+    }
     fn put_trade(&mut self, trade: &Trade) {
         let msg = message::Message::TradeMessage(Box::new(trade.clone()));
         self.write_msg(msg);
     }
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        let line = match &self.msg_seq {
+            Some(msg_seq) => message::envelope_json(msg_seq.current(), current_timestamp(), "TradeMessage", trade.json()),
+            None => trade_message_json(trade),
+        };
+        self.write_line(line);
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        let msg = message::Message::FeeMessage(Box::new(fee.clone()));
+        self.write_msg(msg);
+    }
+    fn put_depth_update(&mut self, update: &DepthUpdate) {
+        let msg = message::Message::DepthUpdateMessage(Box::new(update.clone()));
+        self.write_msg(msg);
+    }
+    fn put_kline(&mut self, kline: &Kline) {
+        let msg = message::Message::KlineMessage(Box::new(kline.clone()));
+        self.write_msg(msg);
+    }
+    fn put_conservation_violation(&mut self, violation: &ConservationViolation) {
+        let msg = message::Message::ConservationViolationMessage(Box::new(violation.clone()));
+        self.write_msg(msg);
+    }
+    fn put_frozen_deficit(&mut self, mismatch: &FrozenMismatch) {
+        let msg = message::Message::FrozenDeficitMessage(Box::new(mismatch.clone()));
+        self.write_msg(msg);
+    }
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        let msg = message::Message::TombstoneMessage(Box::new(*tombstone));
+        self.write_msg(msg);
+    }
+    fn put_operation_log(&mut self, operation: &OperationLogEntry) {
+        let msg = message::Message::OperationLogMessage(Box::new(operation.clone()));
+        self.write_msg(msg);
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        let msg = message::Message::MarketEventMessage(Box::new(event));
+        self.write_msg(msg);
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
         let msg = message::Message::BalanceMessage(Box::new(balance.into()));
         self.write_msg(msg);
@@ -211,15 +803,285 @@ impl PersistExector for FileBasedPersistor {
     }
 }
 
+// the queues are drained by other threads, wait for them for at most `FLUSH_TIMEOUT`
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn wait_drained(name: &str, pending: impl Fn() -> usize) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        let count = pending();
+        if count == 0 {
+            return Ok(());
+        }
+        if start.elapsed() >= FLUSH_TIMEOUT {
+            bail!("{} still has {} pending items after {:?}", name, count, FLUSH_TIMEOUT);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+///////////////////////////// BroadcastPersistor  ////////////////////////////
+
+// a message pushed to the subscribers, serialized like the messages sent to kafka
+#[derive(Debug, Clone)]
+pub struct BroadcastEvent {
+    // the users the message belongs to, e.g. both sides of a trade
+    pub users: Vec<u32>,
+    // trades, depth updates, klines and market events are public
+    pub public: bool,
+    pub json: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BroadcastFilter {
+    // the events of the user, e.g. the orders and the balance changes
+    pub user_id: Option<u32>,
+    pub public: bool,
+    // every event, e.g. for a replica of the engine
+    pub all: bool,
+}
+
+impl BroadcastFilter {
+    pub fn user(user_id: u32) -> Self {
+        Self {
+            user_id: Some(user_id),
+            ..Default::default()
+        }
+    }
+    pub fn public() -> Self {
+        Self {
+            public: true,
+            ..Default::default()
+        }
+    }
+    pub fn all() -> Self {
+        Self {
+            all: true,
+            ..Default::default()
+        }
+    }
+    pub fn with_public(mut self) -> Self {
+        self.public = true;
+        self
+    }
+    pub fn accepts(&self, event: &BroadcastEvent) -> bool {
+        self.all || (self.public && event.public) || self.user_id.map_or(false, |user_id| event.users.contains(&user_id))
+    }
+}
+
+pub struct BroadcastSubscriber {
+    receiver: broadcast::Receiver<BroadcastEvent>,
+    filter: BroadcastFilter,
+}
+
+impl BroadcastSubscriber {
+    // the next event passing the filter. a subscriber too slow to keep up skips the oldest events
+    pub async fn recv(&mut self) -> std::result::Result<BroadcastEvent, broadcast::error::RecvError> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if self.filter.accepts(&event) => return Ok(event),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => log::warn!("broadcast subscriber lagged, {} events skipped", skipped),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    // like `recv` without waiting, none when there are no more events
+    pub fn try_recv(&mut self) -> Option<BroadcastEvent> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) if self.filter.accepts(&event) => return Some(event),
+                Ok(_) => {}
+                Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
+                    log::warn!("broadcast subscriber lagged, {} events skipped", skipped)
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+// pushes the messages to the connected clients, e.g. by websocket, without going through kafka.
+// the clones share the channel, so one kept aside subscribes to the events of the one in the composite
+#[derive(Clone)]
+pub struct BroadcastPersistor {
+    sender: broadcast::Sender<BroadcastEvent>,
+    // the messages are wrapped in envelopes with the seq taken by the composite, none for the legacy format
+    msg_seq: Option<MsgSeq>,
+}
+
+impl BroadcastPersistor {
+    // `capacity` events are kept for the slow subscribers
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender, msg_seq: None }
+    }
+    // every event is sent then, so the subscribers see a gap in the seqs only if they lagged
+    pub fn with_envelope(mut self, msg_seq: MsgSeq) -> Self {
+        self.msg_seq = Some(msg_seq);
+        self
+    }
+    pub fn subscribe(&self, filter: BroadcastFilter) -> BroadcastSubscriber {
+        BroadcastSubscriber {
+            receiver: self.sender.subscribe(),
+            filter,
+        }
+    }
+    fn send(&self, users: Vec<u32>, public: bool, msg: message::Message) {
+        // nothing to do without subscribers
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let json = match &self.msg_seq {
+            Some(msg_seq) => serde_json::to_value(&msg).and_then(|value| {
+                serde_json::to_string(&message::Envelope {
+                    seq: msg_seq.current(),
+                    ts: current_timestamp(),
+                    kind: value["type"].as_str().unwrap_or_default().to_string(),
+                    payload: &value["value"],
+                })
+            }),
+            None => serde_json::to_string(&msg),
+        };
+        match json {
+            Ok(json) => self.send_json(users, public, json),
+            Err(e) => log::error!("serialize message failed: {}", e),
+        }
+    }
+
+    fn send_json(&self, users: Vec<u32>, public: bool, json: String) {
+        // fails only when all the subscribers are gone
+        self.sender.send(BroadcastEvent { users, public, json }).ok();
+    }
+}
+
+impl PersistExector for BroadcastPersistor {
+    fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
+        let msg = message::Message::OrderMessage(Box::new(OrderMessage::from_order(order, at_step)));
+        self.send(vec![order.user], false, msg);
+    }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        let msg = message::Message::OrderMessage(Box::new(OrderMessage::from_finished_order(order, finish)));
+        self.send(vec![order.user], false, msg);
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        let msg = message::Message::OrderMessage(Box::new(OrderMessage::from_amended_order(before, after)));
+        self.send(vec![after.user], false, msg);
+    }
+    fn put_trade(&mut self, trade: &Trade) {
+        let users = vec![trade.ask_user_id, trade.bid_user_id];
+        self.send(users, true, message::Message::TradeMessage(Box::new(trade.clone())));
+    }
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let users = vec![trade.trade().ask_user_id, trade.trade().bid_user_id];
+        let json = match &self.msg_seq {
+            Some(msg_seq) => message::envelope_json(msg_seq.current(), current_timestamp(), "TradeMessage", trade.json()),
+            None => trade_message_json(trade),
+        };
+        self.send_json(users, true, json);
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        self.send(vec![fee.user_id], false, message::Message::FeeMessage(Box::new(fee.clone())));
+    }
+    fn put_depth_update(&mut self, update: &DepthUpdate) {
+        self.send(vec![], true, message::Message::DepthUpdateMessage(Box::new(update.clone())));
+    }
+    fn put_kline(&mut self, kline: &Kline) {
+        self.send(vec![], true, message::Message::KlineMessage(Box::new(kline.clone())));
+    }
+    // for the subscribers of every event only
+    fn put_conservation_violation(&mut self, violation: &ConservationViolation) {
+        let msg = message::Message::ConservationViolationMessage(Box::new(violation.clone()));
+        self.send(vec![], false, msg);
+    }
+    fn put_frozen_deficit(&mut self, mismatch: &FrozenMismatch) {
+        self.send(vec![], false, message::Message::FrozenDeficitMessage(Box::new(mismatch.clone())));
+    }
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        self.send(vec![], false, message::Message::TombstoneMessage(Box::new(*tombstone)));
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.send(vec![], true, message::Message::MarketEventMessage(Box::new(event)));
+    }
+    fn put_balance(&mut self, balance: &BalanceHistory) {
+        let users = vec![balance.user_id as u32];
+        self.send(users, false, message::Message::BalanceMessage(Box::new(balance.into())));
+    }
+    fn put_deposit(&mut self, balance: &BalanceHistory) {
+        let users = vec![balance.user_id as u32];
+        self.send(users, false, message::Message::DepositMessage(Box::new(balance.into())));
+    }
+    fn put_withdraw(&mut self, balance: &BalanceHistory) {
+        let users = vec![balance.user_id as u32];
+        self.send(users, false, message::Message::WithdrawMessage(Box::new(balance.into())));
+    }
+    fn put_transfer(&mut self, tx: InternalTx) {
+        let users = vec![tx.user_from as u32, tx.user_to as u32];
+        self.send(users, false, message::Message::TransferMessage(Box::new(tx.into())));
+    }
+    fn register_user(&mut self, user: AccountDesc) {
+        let users = vec![user.id as u32];
+        self.send(users, false, message::Message::UserMessage(Box::new(user.into())));
+    }
+}
+
 ///////////////////////////// MessengerBasedPersistor  ////////////////////////////
 
 pub struct MessengerBasedPersistor {
     inner: Box<dyn MessageManager>,
+    // unavailable when the producer has so many messages not sent, zero to check `is_block` only
+    high_watermark: usize,
+    // the messages are wrapped in envelopes with the seq taken by the composite, none for the legacy format
+    msg_seq: Option<MsgSeq>,
+    format: MessageFormat,
 }
 
 impl MessengerBasedPersistor {
     pub fn new(inner: Box<dyn MessageManager>) -> Self {
-        Self { inner }
+        Self::with_high_watermark(inner, 0)
+    }
+    pub fn with_high_watermark(inner: Box<dyn MessageManager>, high_watermark: usize) -> Self {
+        Self {
+            inner,
+            high_watermark,
+            msg_seq: None,
+            format: MessageFormat::Json,
+        }
+    }
+    pub fn with_envelope(mut self, msg_seq: MsgSeq) -> Self {
+        self.msg_seq = Some(msg_seq);
+        self
+    }
+    // a protobuf event carries the seq of the envelope, zero without it
+    pub fn with_format(mut self, format: MessageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn send_proto(&mut self, topic: &'static str, kind: proto::Kind) {
+        let seq = self.msg_seq.as_ref().map(MsgSeq::current).unwrap_or_default();
+        let event = proto::Event::new(seq, current_timestamp(), kind);
+        self.inner.push_bytes(topic, event.to_bytes());
+    }
+
+    // `legacy` pushes the unwrapped message
+    fn send<M: Serialize + ToKind>(&mut self, topic: &'static str, kind: &str, msg: &M, legacy: impl FnOnce(&mut dyn MessageManager, &M)) {
+        if self.format == MessageFormat::Protobuf {
+            return self.send_proto(topic, msg.to_kind());
+        }
+        match &self.msg_seq {
+            Some(msg_seq) => match serde_json::to_string(msg) {
+                Ok(payload) => {
+                    let json = message::envelope_json(msg_seq.current(), current_timestamp(), kind, &payload);
+                    self.inner.push_json(topic, json);
+                }
+                Err(e) => log::error!("serialize message failed: {}", e),
+            },
+            None => legacy(self.inner.as_mut(), msg),
+        }
     }
 }
 
@@ -229,40 +1091,153 @@ impl PersistExector for MessengerBasedPersistor {
             log::warn!("message_manager full");
             return false;
         }
+        if self.high_watermark > 0 {
+            if let Some(stats) = self.inner.stats() {
+                let unsent = stats.pending + stats.queued_total();
+                if unsent >= self.high_watermark {
+                    log::warn!("message_manager has {} messages not sent", unsent);
+                    return false;
+                }
+            }
+        }
         true
     }
+    fn producer_stats(&self) -> Vec<(String, ProducerStats)> {
+        self.inner.stats().into_iter().map(|stats| (String::new(), stats)).collect()
+    }
+    fn flush(&mut self) -> Result<()> {
+        let inner = &self.inner;
+        wait_drained("message_manager", || inner.pending())
+    }
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
-        self.inner.push_balance_message(&balance.into());
+        let msg: BalanceMessage = balance.into();
+        self.send(BALANCES_TOPIC, "BalanceMessage", &msg, |m, msg| m.push_balance_message(msg));
     }
     fn put_deposit(&mut self, balance: &BalanceHistory) {
-        self.inner.push_deposit_message(&balance.into());
+        let msg: message::DepositMessage = balance.into();
+        self.send(DEPOSITS_TOPIC, "DepositMessage", &msg, |m, msg| m.push_deposit_message(msg));
     }
     fn put_withdraw(&mut self, balance: &BalanceHistory) {
-        self.inner.push_withdraw_message(&balance.into());
+        let msg: message::WithdrawMessage = balance.into();
+        self.send(WITHDRAWS_TOPIC, "WithdrawMessage", &msg, |m, msg| m.push_withdraw_message(msg));
     }
     fn put_transfer(&mut self, tx: InternalTx) {
-        self.inner.push_transfer_message(&tx.into());
+        let msg: message::TransferMessage = tx.into();
+        self.send(INTERNALTX_TOPIC, "TransferMessage", &msg, |m, msg| m.push_transfer_message(msg));
     }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
-        self.inner.push_order_message(&OrderMessage::from_order(order, at_step));
+        let msg = OrderMessage::from_order(order, at_step);
+        self.send(ORDERS_TOPIC, "OrderMessage", &msg, |m, msg| m.push_order_message(msg));
+    }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        let msg = OrderMessage::from_finished_order(order, finish);
+        self.send(ORDERS_TOPIC, "OrderMessage", &msg, |m, msg| m.push_order_message(msg));
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        let msg = OrderMessage::from_amended_order(before, after);
+        self.send(ORDERS_TOPIC, "OrderMessage", &msg, |m, msg| m.push_order_message(msg));
     }
     fn put_trade(&mut self, trade: &Trade) {
-        self.inner.push_trade_message(trade);
+        self.send(TRADES_TOPIC, "TradeMessage", trade, |m, trade| m.push_trade_message(trade));
+    }
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        if self.format == MessageFormat::Protobuf {
+            return self.send_proto(TRADES_TOPIC, trade.trade().to_kind());
+        }
+        match &self.msg_seq {
+            Some(msg_seq) => {
+                let json = message::envelope_json(msg_seq.current(), current_timestamp(), "TradeMessage", trade.json());
+                self.inner.push_json(TRADES_TOPIC, json);
+            }
+            None => self.inner.push_trade_json(trade.trade(), trade.json()),
+        }
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        self.send(FEES_TOPIC, "FeeMessage", fee, |m, fee| m.push_fee_message(fee));
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.send(MARKETS_TOPIC, "MarketEventMessage", &event, |m, event| {
+            m.push_market_event_message(event)
+        });
     }
     fn register_user(&mut self, user: AccountDesc) {
-        self.inner.push_user_message(&user.into());
+        let msg: message::UserMessage = user.into();
+        self.send(USER_TOPIC, "UserMessage", &msg, |m, msg| m.push_user_message(msg));
     }
 }
 
 ///////////////////////////// DBBasedPersistor  ////////////////////////////
 ///
+#[derive(Debug, Clone)]
+pub struct DBBatchConfig {
+    // the histories of every kind are written by batches up to the size, each in one transaction
+    pub batch_size: usize,
+    // a partial batch is written once its oldest item is buffered that long, checked when
+    // the next item comes and by `flush_elapsed`
+    pub window: Duration,
+    // the items buffered or not written by the writer yet, above which no more commands are accepted
+    pub max_pending: usize,
+}
+
+impl Default for DBBatchConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            window: Duration::from_millis(100),
+            max_pending: 8192,
+        }
+    }
+}
+
 pub struct DBBasedPersistor {
     inner: Box<dyn HistoryWriter>,
+    config: DBBatchConfig,
+    // in the order they came, so a fee is never written without its trade
+    batch: Vec<HistoryItem>,
+    // when the oldest buffered item came
+    batch_start: Option<Instant>,
 }
 
 impl DBBasedPersistor {
     pub fn new(inner: Box<dyn HistoryWriter>) -> Self {
-        Self { inner }
+        Self::with_batch(inner, DBBatchConfig::default())
+    }
+    pub fn with_batch(inner: Box<dyn HistoryWriter>, config: DBBatchConfig) -> Self {
+        Self {
+            inner,
+            config,
+            batch: Vec::new(),
+            batch_start: None,
+        }
+    }
+    fn buffered(&self) -> usize {
+        self.batch.len()
+    }
+    fn write_batch(&mut self) {
+        if !self.batch.is_empty() {
+            self.inner.append_batch(std::mem::take(&mut self.batch));
+        }
+        self.batch_start = None;
+    }
+    fn push(&mut self, item: HistoryItem) {
+        self.batch.push(item);
+        let batch_start = *self.batch_start.get_or_insert_with(Instant::now);
+        if self.batch.len() >= self.config.batch_size || batch_start.elapsed() >= self.config.window {
+            self.write_batch();
+        }
+    }
+    //only persist on finish
+    fn put_order_history(&mut self, order: &Order, at_step: OrderEventType, finish: Option<OrderFinish>) {
+        match at_step {
+            // canceled orders always have a non-zero remain, so they are recorded as `Cancelled`
+            OrderEventType::FINISH => self.push(HistoryItem::Order(*order, finish)),
+            OrderEventType::EXPIRED => self.push(HistoryItem::ExpiredOrder(*order)),
+            OrderEventType::PUT => (),
+            _ => (),
+        }
     }
 }
 
@@ -272,10 +1247,28 @@ impl PersistExector for DBBasedPersistor {
             log::warn!("history_writer full");
             return false;
         }
+        if self.inner.pending() + self.buffered() > self.config.max_pending {
+            log::warn!("history_writer has too many pending items");
+            return false;
+        }
         true
     }
+    fn flush(&mut self) -> Result<()> {
+        self.write_batch();
+        let inner = &self.inner;
+        wait_drained("history_writer", || inner.pending())
+    }
+    // without it the last items would wait for the next one or for the next slice
+    fn flush_elapsed(&mut self) {
+        if self
+            .batch_start
+            .map_or(false, |batch_start| batch_start.elapsed() >= self.config.window)
+        {
+            self.write_batch();
+        }
+    }
     fn put_balance(&mut self, balance: &BalanceHistory) {
-        self.inner.append_balance_history(balance.clone());
+        self.push(HistoryItem::Balance(balance.clone()));
     }
     fn put_deposit(&mut self, _balance: &BalanceHistory) {
         // TODO
@@ -284,80 +1277,1020 @@ impl PersistExector for DBBasedPersistor {
         // TODO
     }
     fn put_transfer(&mut self, tx: InternalTx) {
-        self.inner.append_internal_transfer(tx);
+        self.push(HistoryItem::Transfer(tx));
     }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
-        //only persist on finish
-        match at_step {
-            OrderEventType::FINISH => self.inner.append_order_history(order),
-            OrderEventType::EXPIRED => self.inner.append_expired_order_history(order),
-            OrderEventType::PUT => (),
-            _ => (),
-        }
+        self.put_order_history(order, at_step, None);
+    }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        self.put_order_history(order, finish.event(), Some(finish));
     }
     fn put_trade(&mut self, trade: &Trade) {
-        self.inner.append_pair_user_trade(trade);
+        self.push(HistoryItem::Trade(trade.clone()));
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        self.push(HistoryItem::Fee(fee.clone()));
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.push(HistoryItem::MarketEvent(event));
+    }
+    // with the histories buffered before the rollback, written at once
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        self.batch.push(HistoryItem::Tombstone(*tombstone));
+        self.write_batch();
+    }
+    // in the transaction of its histories unless the batch is full before it
+    fn put_operation_log(&mut self, operation: &OperationLogEntry) {
+        self.push(HistoryItem::OperationLog(operation.clone()));
     }
     fn register_user(&mut self, user: AccountDesc) {
-        self.inner.append_user(user);
+        self.push(HistoryItem::User(user));
     }
 }
 
 ///////////////////////////// CompositePersistor  ////////////////////////////
 ///
+
+// the kinds of the events a child of `CompositePersistor` receives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventFilter(u32);
+
+impl EventFilter {
+    pub const ORDERS: EventFilter = EventFilter(1);
+    // the trades and their fees
+    pub const TRADES: EventFilter = EventFilter(1 << 1);
+    pub const BALANCES: EventFilter = EventFilter(1 << 2);
+    pub const DEPOSITS: EventFilter = EventFilter(1 << 3);
+    pub const WITHDRAWS: EventFilter = EventFilter(1 << 4);
+    pub const TRANSFERS: EventFilter = EventFilter(1 << 5);
+    pub const USERS: EventFilter = EventFilter(1 << 6);
+    // the depth updates, the klines and the market events
+    pub const MARKETS: EventFilter = EventFilter(1 << 7);
+    // the conservation violations and the frozen deficits
+    pub const ALERTS: EventFilter = EventFilter(1 << 8);
+    pub const ALL: EventFilter = EventFilter(u32::MAX);
+
+    pub fn contains(self, other: EventFilter) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for EventFilter {
+    type Output = EventFilter;
+    fn bitor(self, other: EventFilter) -> EventFilter {
+        EventFilter(self.0 | other.0)
+    }
+}
+
+struct CompositeChild {
+    name: String,
+    filter: EventFilter,
+    // the service is unavailable when a critical child is
+    critical: bool,
+    persistor: Box<dyn PersistExector>,
+}
+
 #[derive(Default)]
 pub struct CompositePersistor {
-    persistors: Vec<Box<dyn PersistExector>>,
+    persistors: Vec<CompositeChild>,
+    // the next id is taken for each event, so it has the same seq in every enveloping child
+    msg_seq: Option<MsgSeq>,
 }
 
 impl CompositePersistor {
+    // receives every event, named by its position
     pub fn add_persistor(&mut self, p: Box<dyn PersistExector>) {
-        self.persistors.push(p)
+        let name = format!("persistor_{}", self.persistors.len());
+        self.add_persistor_with_filter(&name, p, EventFilter::ALL)
+    }
+    pub fn add_persistor_with_filter(&mut self, name: &str, p: Box<dyn PersistExector>, filter: EventFilter) {
+        self.persistors.push(CompositeChild {
+            name: name.to_string(),
+            filter,
+            critical: true,
+            persistor: p,
+        })
+    }
+    // detach a failing sink, the events are no longer sent to it
+    pub fn remove_persistor(&mut self, name: &str) -> Option<Box<dyn PersistExector>> {
+        let index = self.persistors.iter().position(|child| child.name == name)?;
+        Some(self.persistors.remove(index).persistor)
+    }
+    // the children are critical when added. returns false if there is no such child
+    pub fn set_critical(&mut self, name: &str, critical: bool) -> bool {
+        match self.persistors.iter_mut().find(|child| child.name == name) {
+            Some(child) => {
+                child.critical = critical;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn set_msg_seq(&mut self, msg_seq: MsgSeq) {
+        self.msg_seq = Some(msg_seq);
+    }
+    fn stamp(&self) {
+        if let Some(msg_seq) = &self.msg_seq {
+            msg_seq.next();
+        }
+    }
+    fn children(&mut self, kind: EventFilter) -> impl Iterator<Item = &mut Box<dyn PersistExector>> {
+        self.persistors
+            .iter_mut()
+            .filter(move |child| child.filter.contains(kind))
+            .map(|child| &mut child.persistor)
     }
 }
 
 impl PersistExector for CompositePersistor {
     fn service_available(&self) -> bool {
-        for p in &self.persistors {
-            if !p.service_available() {
-                return false;
+        self.persistors
+            .iter()
+            .all(|child| !child.critical || child.persistor.service_available())
+    }
+    fn producer_stats(&self) -> Vec<(String, ProducerStats)> {
+        let mut all = Vec::new();
+        for child in &self.persistors {
+            for (name, stats) in child.persistor.producer_stats() {
+                let name = if name.is_empty() {
+                    child.name.clone()
+                } else {
+                    format!("{}.{}", child.name, name)
+                };
+                all.push((name, stats));
             }
         }
-        true
+        all
+    }
+    fn sink_status(&self) -> Vec<SinkStatus> {
+        self.persistors
+            .iter()
+            .map(|child| SinkStatus {
+                name: child.name.clone(),
+                critical: child.critical,
+                available: child.persistor.service_available(),
+            })
+            .collect()
+    }
+    // every persistor is flushed even if an earlier one fails, the first error is returned
+    fn flush(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for p in self.children(EventFilter::ALL) {
+            if let Err(e) = p.flush() {
+                log::error!("flush persistor failed: {}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+    fn flush_elapsed(&mut self) {
+        for p in self.children(EventFilter::ALL) {
+            p.flush_elapsed();
+        }
+    }
+    fn shutdown(&mut self) {
+        for p in self.children(EventFilter::ALL) {
+            p.shutdown();
+        }
     }
     fn put_balance(&mut self, balance: &BalanceHistory) {
-        for p in &mut self.persistors {
+        self.stamp();
+        for p in self.children(EventFilter::BALANCES) {
             p.put_balance(balance);
         }
     }
     fn put_deposit(&mut self, balance: &BalanceHistory) {
-        for p in &mut self.persistors {
+        self.stamp();
+        for p in self.children(EventFilter::DEPOSITS) {
             p.put_deposit(balance);
         }
     }
     fn put_withdraw(&mut self, balance: &BalanceHistory) {
-        for p in &mut self.persistors {
+        self.stamp();
+        for p in self.children(EventFilter::WITHDRAWS) {
             p.put_withdraw(balance);
         }
     }
     fn put_transfer(&mut self, tx: InternalTx) {
-        for p in &mut self.persistors {
+        self.stamp();
+        for p in self.children(EventFilter::TRANSFERS) {
             p.put_transfer(tx.clone());
         }
     }
     fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
-        for p in &mut self.persistors {
+        self.stamp();
+        for p in self.children(EventFilter::ORDERS) {
             p.put_order(order, at_step);
         }
     }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        self.stamp();
+        for p in self.children(EventFilter::ORDERS) {
+            p.put_finished_order(order, finish);
+        }
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        self.stamp();
+        for p in self.children(EventFilter::ORDERS) {
+            p.put_amended_order(before, after);
+        }
+    }
     fn put_trade(&mut self, trade: &Trade) {
-        for p in &mut self.persistors {
-            p.put_trade(trade);
+        self.put_trade_shared(&SharedTrade::new(trade.clone()));
+    }
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        self.stamp();
+        for p in self.children(EventFilter::TRADES) {
+            p.put_trade_shared(trade);
+        }
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        self.stamp();
+        for p in self.children(EventFilter::TRADES) {
+            p.put_fee(fee);
+        }
+    }
+    fn put_depth_update(&mut self, update: &DepthUpdate) {
+        self.stamp();
+        for p in self.children(EventFilter::MARKETS) {
+            p.put_depth_update(update);
+        }
+    }
+    fn put_kline(&mut self, kline: &Kline) {
+        self.stamp();
+        for p in self.children(EventFilter::MARKETS) {
+            p.put_kline(kline);
+        }
+    }
+    fn put_conservation_violation(&mut self, violation: &ConservationViolation) {
+        self.stamp();
+        for p in self.children(EventFilter::ALERTS) {
+            p.put_conservation_violation(violation);
+        }
+    }
+    fn put_frozen_deficit(&mut self, mismatch: &FrozenMismatch) {
+        self.stamp();
+        for p in self.children(EventFilter::ALERTS) {
+            p.put_frozen_deficit(mismatch);
+        }
+    }
+    // every child is told whatever it receives
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        self.stamp();
+        for child in &mut self.persistors {
+            child.persistor.put_tombstone(tombstone);
+        }
+    }
+    // not stamped, it is no event of its own but follows the events of the operation. it has the log id instead
+    fn put_operation_log(&mut self, operation: &OperationLogEntry) {
+        for child in &mut self.persistors {
+            child.persistor.put_operation_log(operation);
+        }
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.stamp();
+        for p in self.children(EventFilter::MARKETS) {
+            p.put_market_event(event.clone());
         }
     }
     fn register_user(&mut self, user: AccountDesc) {
-        for p in &mut self.persistors {
+        self.stamp();
+        for p in self.children(EventFilter::USERS) {
             p.register_user(user.clone());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingPersistor {}
+    impl PersistExector for FailingPersistor {
+        fn flush(&mut self) -> Result<()> {
+            bail!("disk full")
+        }
+        fn put_balance(&mut self, _balance: &BalanceHistory) {}
+        fn put_deposit(&mut self, _balance: &BalanceHistory) {}
+        fn put_withdraw(&mut self, _balance: &BalanceHistory) {}
+        fn put_transfer(&mut self, _tx: InternalTx) {}
+        fn put_order(&mut self, _order: &Order, _as_step: OrderEventType) {}
+        fn put_trade(&mut self, _trade: &Trade) {}
+        fn put_fee(&mut self, _fee: &TradeFeeRecord) {}
+        fn put_market_event(&mut self, _event: MarketEvent) {}
+        fn register_user(&mut self, _user: AccountDesc) {}
+    }
+
+    fn user(id: i32) -> AccountDesc {
+        AccountDesc {
+            id,
+            l1_address: String::new(),
+            l2_pubkey: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut persistor = MemBasedPersistor::default();
+        persistor.register_user(user(1));
+        persistor.flush().unwrap();
+        assert_eq!(persistor.messages.len(), 1);
+
+        let path = std::env::temp_dir().join(format!("test_flush_{}.txt", std::process::id()));
+        let mut persistor = FileBasedPersistor::new(path.to_str().unwrap()).unwrap();
+        persistor.register_user(user(1));
+        persistor.register_user(user(2));
+        persistor.flush().unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(content.lines().count(), 2);
+
+        // the persistors after a failing one are flushed too
+        let mut composite = CompositePersistor::default();
+        composite.add_persistor(Box::new(DummyPersistor::new()));
+        composite.add_persistor(Box::new(FailingPersistor {}));
+        composite.add_persistor(Box::new(MemBasedPersistor::default()));
+        assert_eq!(composite.flush().unwrap_err().to_string(), "disk full");
+        let mut composite = CompositePersistor::default();
+        composite.add_persistor(Box::new(MemBasedPersistor::default()));
+        composite.flush().unwrap();
+    }
+
+    // a MemBasedPersistor whose messages are still readable after it is added to a composite
+    #[derive(Clone, Default)]
+    struct SharedMem(std::sync::Arc<std::sync::Mutex<MemBasedPersistor>>);
+    impl SharedMem {
+        fn kinds(&self) -> Vec<String> {
+            let persistor = self.0.lock().unwrap();
+            persistor
+                .messages
+                .iter()
+                .map(|message| serde_json::to_value(message).unwrap()["type"].as_str().unwrap().to_string())
+                .collect()
+        }
+    }
+    impl PersistExector for SharedMem {
+        fn put_balance(&mut self, balance: &BalanceHistory) {
+            self.0.lock().unwrap().put_balance(balance)
+        }
+        fn put_deposit(&mut self, balance: &BalanceHistory) {
+            self.0.lock().unwrap().put_deposit(balance)
+        }
+        fn put_withdraw(&mut self, balance: &BalanceHistory) {
+            self.0.lock().unwrap().put_withdraw(balance)
+        }
+        fn put_transfer(&mut self, tx: InternalTx) {
+            self.0.lock().unwrap().put_transfer(tx)
+        }
+        fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
+            self.0.lock().unwrap().put_order(order, at_step)
+        }
+        fn put_trade(&mut self, trade: &Trade) {
+            self.0.lock().unwrap().put_trade(trade)
+        }
+        fn put_fee(&mut self, fee: &TradeFeeRecord) {
+            self.0.lock().unwrap().put_fee(fee)
+        }
+        fn put_market_event(&mut self, event: MarketEvent) {
+            self.0.lock().unwrap().put_market_event(event)
+        }
+        fn register_user(&mut self, user: AccountDesc) {
+            self.0.lock().unwrap().register_user(user)
+        }
+    }
+
+    #[test]
+    fn test_composite_routing() {
+        let balance = BalanceHistory {
+            time: fluidex_common::utils::timeutil::FTimestamp(0.0).into(),
+            user_id: 1,
+            business_id: 1,
+            asset: "USDT".to_string(),
+            business: "deposit".to_string(),
+            market_price: Default::default(),
+            change: Default::default(),
+            balance: Default::default(),
+            balance_available: Default::default(),
+            balance_frozen: Default::default(),
+            detail: "{}".to_string(),
+            signature: vec![],
+        };
+        let (db, mq, audit) = (SharedMem::default(), SharedMem::default(), SharedMem::default());
+        let mut composite = CompositePersistor::default();
+        composite.add_persistor_with_filter("db", Box::new(db.clone()), EventFilter::ALL);
+        composite.add_persistor_with_filter(
+            "mq",
+            Box::new(mq.clone()),
+            EventFilter::USERS | EventFilter::DEPOSITS | EventFilter::TRADES,
+        );
+        composite.add_persistor_with_filter("audit", Box::new(audit.clone()), EventFilter::BALANCES);
+
+        composite.put_balance(&balance);
+        composite.put_deposit(&balance);
+        composite.register_user(user(1));
+        assert_eq!(db.kinds(), vec!["BalanceMessage", "DepositMessage", "UserMessage"]);
+        assert_eq!(mq.kinds(), vec!["DepositMessage", "UserMessage"]);
+        assert_eq!(audit.kinds(), vec!["BalanceMessage"]);
+
+        // a removed sink receives nothing more
+        assert!(composite.remove_persistor("mq").is_some());
+        assert!(composite.remove_persistor("mq").is_none());
+        composite.register_user(user(2));
+        assert_eq!(mq.kinds().len(), 2);
+        assert_eq!(db.kinds().len(), 4);
+
+        // only the critical children decide the availability
+        let mut composite = CompositePersistor::default();
+        composite.add_persistor(Box::new(DummyPersistor::new()));
+        composite.add_persistor_with_filter("blocked", Box::new(BlockedPersistor {}), EventFilter::ALL);
+        assert!(!composite.service_available());
+        assert!(composite.set_critical("blocked", false));
+        assert!(composite.service_available());
+        assert!(!composite.set_critical("unknown", false));
+        let status = composite.sink_status();
+        assert_eq!(
+            status.iter().map(|s| (s.critical, s.available)).collect::<Vec<_>>(),
+            vec![(true, true), (false, false)]
+        );
+    }
+
+    struct BlockedPersistor {}
+    impl PersistExector for BlockedPersistor {
+        fn service_available(&self) -> bool {
+            false
+        }
+        fn put_balance(&mut self, _balance: &BalanceHistory) {}
+        fn put_deposit(&mut self, _balance: &BalanceHistory) {}
+        fn put_withdraw(&mut self, _balance: &BalanceHistory) {}
+        fn put_transfer(&mut self, _tx: InternalTx) {}
+        fn put_order(&mut self, _order: &Order, _as_step: OrderEventType) {}
+        fn put_trade(&mut self, _trade: &Trade) {}
+        fn put_fee(&mut self, _fee: &TradeFeeRecord) {}
+        fn put_market_event(&mut self, _event: MarketEvent) {}
+        fn register_user(&mut self, _user: AccountDesc) {}
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = std::env::temp_dir().join(format!("test_file_rotation_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output.txt");
+        let settings = config::FilePersistSettings {
+            max_bytes: 1024,
+            gzip: true,
+            ..Default::default()
+        };
+        let mut persistor = FileBasedPersistor::with_settings(path.to_str().unwrap(), &settings).unwrap();
+        for id in 0..200 {
+            persistor.register_user(user(id));
+        }
+        persistor.flush().unwrap();
+        let segments = persistor.rotated_segments().to_vec();
+        assert!(segments.len() > 1);
+
+        let mut lines = Vec::new();
+        for segment in &segments {
+            assert!(segment.ends_with(".gz"));
+            let mut content = String::new();
+            GzDecoder::new(std::fs::File::open(segment).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+            lines.extend(content.lines().map(str::to_string));
+        }
+        lines.extend(std::fs::read_to_string(&path).unwrap().lines().map(str::to_string));
+        std::fs::remove_dir_all(&dir).ok();
+
+        // every message is written once, in order, as a whole line
+        let ids: Vec<i64> = lines
+            .iter()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["value"]["user_id"]
+                    .as_i64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(ids, (0..200).collect::<Vec<_>>());
+    }
+
+    // a segment writer failing while `fail` is set, keeping what it has written
+    #[derive(Clone, Default)]
+    struct FlakyWriter {
+        fail: std::sync::Arc<std::sync::atomic::AtomicBool>,
+        data: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+    impl FlakyWriter {
+        fn set_fail(&self, fail: bool) {
+            self.fail.store(fail, std::sync::atomic::Ordering::SeqCst)
+        }
+        fn user_ids(&self) -> Vec<i64> {
+            let data = self.data.lock().unwrap();
+            std::str::from_utf8(&data)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    serde_json::from_str::<serde_json::Value>(line).unwrap()["value"]["user_id"]
+                        .as_i64()
+                        .unwrap()
+                })
+                .collect()
+        }
+    }
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.fail.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "disk full"));
+            }
+            self.data.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl SegmentWriter for FlakyWriter {
+        fn sync(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_file_io_errors() {
+        let dir = std::env::temp_dir().join(format!("test_file_io_errors_{}", std::process::id()));
+        assert!(FileBasedPersistor::new(dir.join("missing").join("output.txt").to_str().unwrap()).is_err());
+
+        let settings = config::FilePersistSettings {
+            // every message goes to the writer at once
+            buffer_bytes: 0,
+            max_pending: 4,
+            ..Default::default()
+        };
+        let writer = FlakyWriter::default();
+        let mut persistor = FileBasedPersistor::with_writer("unused", &settings, Box::new(writer.clone()));
+        persistor.register_user(user(0));
+        writer.set_fail(true);
+        for id in 1..3 {
+            persistor.register_user(user(id));
+        }
+        assert!(persistor.service_available());
+        assert!(persistor.flush().is_err());
+        persistor.register_user(user(3));
+        assert!(!persistor.service_available());
+
+        // the failed messages are retried once the writer recovers
+        writer.set_fail(false);
+        persistor.register_user(user(4));
+        assert!(persistor.service_available());
+        assert_eq!(writer.user_ids(), vec![0, 1, 2, 3, 4]);
+
+        // the oldest messages are dropped when the retry queue is full
+        writer.set_fail(true);
+        for id in 5..11 {
+            persistor.register_user(user(id));
+        }
+        assert_eq!(persistor.dropped(), 2);
+        writer.set_fail(false);
+        persistor.flush().unwrap();
+        assert_eq!(writer.user_ids(), vec![0, 1, 2, 3, 4, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn test_mem_capacity() {
+        let history = |user_id| BalanceHistory {
+            time: fluidex_common::utils::timeutil::FTimestamp(0.0).into(),
+            user_id,
+            business_id: 0,
+            asset: "ETH".to_string(),
+            business: "test".to_string(),
+            market_price: Default::default(),
+            change: Default::default(),
+            balance: Default::default(),
+            balance_available: Default::default(),
+            balance_frozen: Default::default(),
+            detail: "{}".to_string(),
+            signature: vec![],
+        };
+        let mut persistor = MemBasedPersistor::with_capacity(3);
+        persistor.register_user(user(1));
+        persistor.put_deposit(&history(1));
+        persistor.put_balance(&history(2));
+        persistor.put_withdraw(&history(3));
+        persistor.put_balance(&history(4));
+        // the user and the deposit are evicted
+        assert_eq!(persistor.dropped(), 2);
+        assert!(persistor.deposits().is_empty());
+        let users = |balances: Vec<&BalanceMessage>| balances.iter().map(|balance| balance.user_id).collect::<Vec<_>>();
+        assert_eq!(users(persistor.balances()), vec![2, 4]);
+        assert_eq!(users(persistor.withdraws()), vec![3]);
+        assert!(persistor.orders().is_empty() && persistor.trades().is_empty() && persistor.fees().is_empty());
+
+        assert_eq!(persistor.drain().len(), 3);
+        assert!(persistor.messages.is_empty());
+        assert_eq!(persistor.dropped(), 2);
+
+        // no limit by default
+        let mut persistor = MemBasedPersistor::new();
+        for id in 0..100 {
+            persistor.register_user(user(id));
+        }
+        assert_eq!((persistor.messages.len(), persistor.dropped()), (100, 0));
+    }
+
+    fn balance(business_id: i64) -> BalanceHistory {
+        BalanceHistory {
+            time: fluidex_common::utils::timeutil::FTimestamp(0.0).into(),
+            user_id: 1,
+            business_id,
+            asset: "ETH".to_string(),
+            business: "trade".to_string(),
+            market_price: Default::default(),
+            change: Default::default(),
+            balance: Default::default(),
+            balance_available: Default::default(),
+            balance_frozen: Default::default(),
+            detail: "{}".to_string(),
+            signature: vec![],
+        }
+    }
+
+    fn order(id: u64, user: u32) -> Order {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "base": "ETH", "quote": "USDT", "market": "ETH_USDT", "type": "LIMIT", "side": "ASK",
+            "user": user, "post_only": false, "signature": "00".repeat(64), "price": "1", "amount": "1",
+            "maker_fee": "0", "taker_fee": "0", "create_time": 0.0, "remain": "1", "frozen": "1",
+            "finished_base": "0", "finished_quote": "0", "finished_fee": "0", "update_time": 0.0,
+        }))
+        .unwrap()
+    }
+
+    fn trade(id: u64, ask_user_id: u32, bid_user_id: u32) -> Trade {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "timestamp": 0.0, "market": "ETH_USDT", "base": "ETH", "quote": "USDT",
+            "price": "1", "amount": "1", "quote_amount": "1",
+            "ask_user_id": ask_user_id, "ask_order_id": 3, "ask_role": "MAKER", "ask_fee": "0",
+            "bid_user_id": bid_user_id, "bid_order_id": 4, "bid_role": "TAKER", "bid_fee": "0",
+            "ask_order": null, "bid_order": null,
+            "state_before": {"order_states": [], "balance_states": []},
+            "state_after": {"order_states": [], "balance_states": []},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_broadcast_filters() {
+        let trade = trade(1, 3, 4);
+
+        let broadcast = BroadcastPersistor::new(16);
+        let mut own_and_public = broadcast.subscribe(BroadcastFilter::user(1).with_public());
+        let mut own = broadcast.subscribe(BroadcastFilter::user(2));
+        let mut composite = CompositePersistor::default();
+        composite.add_persistor(Box::new(broadcast));
+        composite.put_trade(&trade);
+        composite.put_order(&order(1, 1), OrderEventType::PUT);
+        composite.put_order(&order(2, 2), OrderEventType::PUT);
+
+        let received = |subscriber: &mut BroadcastSubscriber| {
+            std::iter::from_fn(|| subscriber.try_recv())
+                .map(|event| {
+                    let value: serde_json::Value = serde_json::from_str(&event.json).unwrap();
+                    let id = value["value"]["order"]["id"].as_u64().or_else(|| value["value"]["id"].as_u64());
+                    (value["type"].as_str().unwrap().to_string(), id.unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            received(&mut own_and_public),
+            vec![("TradeMessage".to_string(), 1), ("OrderMessage".to_string(), 1)]
+        );
+        assert_eq!(received(&mut own), vec![("OrderMessage".to_string(), 2)]);
+    }
+
+    // records the batches, e.g. [("trade", 1), ("fee", 1)]
+    #[derive(Clone, Default)]
+    struct BatchRecorder {
+        batches: std::sync::Arc<std::sync::Mutex<Vec<Vec<(&'static str, u64)>>>>,
+        pending: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+    impl BatchRecorder {
+        fn take(&self) -> Vec<Vec<(&'static str, u64)>> {
+            std::mem::take(&mut *self.batches.lock().unwrap())
+        }
+    }
+    impl HistoryWriter for BatchRecorder {
+        fn is_block(&self) -> bool {
+            false
+        }
+        fn pending(&self) -> usize {
+            self.pending.load(std::sync::atomic::Ordering::SeqCst)
+        }
+        fn append_balance_history(&mut self, _data: BalanceHistory) {}
+        fn append_internal_transfer(&mut self, _data: InternalTx) {}
+        fn append_user(&mut self, _user: AccountDesc) {}
+        fn append_order_history(&mut self, _order: &Order, _finish: Option<OrderFinish>) {}
+        fn append_expired_order_history(&mut self, _order: &Order) {}
+        fn append_pair_user_trade(&mut self, _trade: &Trade) {}
+        fn append_trade_fee(&mut self, _fee: &TradeFeeRecord) {}
+        fn append_market_event(&mut self, _event: &MarketEvent) {}
+        fn append_batch(&mut self, batch: Vec<HistoryItem>) {
+            let items = batch
+                .iter()
+                .map(|item| match item {
+                    HistoryItem::Balance(data) => ("balance", data.business_id as u64),
+                    HistoryItem::Transfer(_) => ("transfer", 0),
+                    HistoryItem::User(user) => ("user", user.id as u64),
+                    HistoryItem::Order(order, _) => ("order", order.id),
+                    HistoryItem::ExpiredOrder(order) => ("expired", order.id),
+                    HistoryItem::Trade(trade) => ("trade", trade.id),
+                    HistoryItem::Fee(fee) => ("fee", fee.trade_id),
+                    HistoryItem::MarketEvent(_) => ("market_event", 0),
+                    HistoryItem::Tombstone(tombstone) => ("tombstone", tombstone.from),
+                    HistoryItem::OperationLog(operation) => ("operation_log", operation.id),
+                })
+                .collect();
+            self.batches.lock().unwrap().push(items)
+        }
+    }
+
+    fn fee(trade_id: u64) -> TradeFeeRecord {
+        serde_json::from_value(serde_json::json!({
+            "trade_id": trade_id, "timestamp": 0.0, "market": "ETH_USDT", "user_id": 1, "order_id": 3,
+            "role": "MAKER", "asset": "USDT", "amount": "0", "rate": "0",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_db_batches() {
+        let writer = BatchRecorder::default();
+        let config = DBBatchConfig {
+            batch_size: 5,
+            window: Duration::from_secs(3600),
+            max_pending: 10,
+        };
+        let mut persistor = DBBasedPersistor::with_batch(Box::new(writer.clone()), config);
+        persistor.put_balance(&balance(1));
+        persistor.put_trade(&trade(1, 1, 2));
+        persistor.put_fee(&fee(1));
+        persistor.put_order(&order(5, 1), OrderEventType::PUT);
+        persistor.put_order(&order(6, 1), OrderEventType::FINISH);
+        persistor.put_operation_log(&OperationLogEntry {
+            id: 1,
+            time: 0.0,
+            method: "order_put".to_string(),
+            params: "{}".to_string(),
+            failed: false,
+        });
+        // a full batch is written at once, every kind together and in the order they came
+        assert_eq!(
+            writer.take(),
+            vec![vec![("balance", 1), ("trade", 1), ("fee", 1), ("order", 6), ("operation_log", 1)]]
+        );
+
+        persistor.put_order(&order(7, 1), OrderEventType::FINISH);
+        persistor.put_order(&order(8, 1), OrderEventType::EXPIRED);
+        persistor.register_user(user(9));
+        // the partial batch is written by flush
+        assert!(persistor.service_available());
+        writer.pending.store(8, std::sync::atomic::Ordering::SeqCst);
+        // 8 not written by the writer yet and 3 buffered
+        assert!(!persistor.service_available());
+        writer.pending.store(0, std::sync::atomic::Ordering::SeqCst);
+        persistor.flush().unwrap();
+        assert_eq!(writer.take(), vec![vec![("order", 7), ("expired", 8), ("user", 9)]]);
+
+        // a tombstone is written at once with the histories before it
+        persistor.put_balance(&balance(2));
+        persistor.put_tombstone(&Tombstone {
+            from: 3,
+            to: 4,
+            order_id: 0,
+            trade_id: 0,
+            time: 0.0,
+        });
+        assert_eq!(writer.take(), vec![vec![("balance", 2), ("tombstone", 3)]]);
+
+        // without a window every item is written at once
+        let config = DBBatchConfig {
+            window: Duration::ZERO,
+            ..DBBatchConfig::default()
+        };
+        let mut persistor = DBBasedPersistor::with_batch(Box::new(writer.clone()), config);
+        persistor.put_trade(&trade(3, 1, 2));
+        persistor.put_balance(&balance(5));
+        assert_eq!(writer.take(), vec![vec![("trade", 3)], vec![("balance", 5)]]);
+
+        // a partial batch is written once the window elapses, without another item coming
+        let config = DBBatchConfig {
+            window: Duration::from_millis(100),
+            ..DBBatchConfig::default()
+        };
+        let mut persistor = DBBasedPersistor::with_batch(Box::new(writer.clone()), config);
+        persistor.put_trade(&trade(4, 1, 2));
+        persistor.flush_elapsed();
+        assert!(writer.take().is_empty());
+        std::thread::sleep(Duration::from_millis(110));
+        persistor.flush_elapsed();
+        assert_eq!(writer.take(), vec![vec![("trade", 4)]]);
+    }
+
+    #[test]
+    fn test_shared_trade() {
+        let trade = trade(1, 3, 4);
+        let expected = serde_json::to_string(&message::Message::TradeMessage(Box::new(trade.clone()))).unwrap();
+
+        let writer = FlakyWriter::default();
+        let settings = config::FilePersistSettings::default();
+        let broadcast = BroadcastPersistor::new(16);
+        let mut subscriber = broadcast.subscribe(BroadcastFilter::public());
+        let mut composite = CompositePersistor::default();
+        composite.add_persistor(Box::new(FileBasedPersistor::with_writer(
+            "unused",
+            &settings,
+            Box::new(writer.clone()),
+        )));
+        composite.add_persistor(Box::new(broadcast));
+        composite.add_persistor(Box::new(MemBasedPersistor::default()));
+
+        let shared = SharedTrade::new(trade);
+        assert!(!shared.serialized());
+        composite.put_trade_shared(&shared);
+        composite.flush().unwrap();
+        assert!(shared.serialized());
+        // the same lines as the ones serialized from the message
+        let written = String::from_utf8(writer.data.lock().unwrap().clone()).unwrap();
+        assert_eq!(written, format!("{}\n", expected));
+        assert_eq!(subscriber.try_recv().unwrap().json, expected);
+    }
+
+    #[test]
+    fn test_shared_trade_serialized_once() {
+        let settings = config::FilePersistSettings::default();
+        let broadcast = BroadcastPersistor::new(16);
+        let mut subscriber = broadcast.subscribe(BroadcastFilter::public());
+        let mut composite = CompositePersistor::default();
+        let writers = [FlakyWriter::default(), FlakyWriter::default()];
+        for writer in &writers {
+            composite.add_persistor(Box::new(FileBasedPersistor::with_writer(
+                "unused",
+                &settings,
+                Box::new(writer.clone()),
+            )));
+        }
+        composite.add_persistor(Box::new(broadcast));
+
+        let trades = (0..10).map(|id| SharedTrade::new(trade(id, 3, 4))).collect::<Vec<_>>();
+        for trade in &trades {
+            composite.put_trade_shared(trade);
+        }
+        composite.flush().unwrap();
+        // once for all the three children
+        assert!(trades.iter().all(|trade| trade.serializations() == 1));
+        for writer in &writers {
+            assert_eq!(String::from_utf8(writer.data.lock().unwrap().clone()).unwrap().lines().count(), 10);
+        }
+        for trade in &trades {
+            assert_eq!(subscriber.try_recv().unwrap().json, trade_message_json(trade));
+        }
+
+        // not at all when no child asks for the json
+        let mut composite = CompositePersistor::default();
+        composite.add_persistor(Box::new(MemBasedPersistor::default()));
+        let trade = SharedTrade::new(trade(10, 3, 4));
+        composite.put_trade_shared(&trade);
+        assert_eq!(trade.serializations(), 0);
+    }
+
+    // keeps the json and the bytes pushed, the messages are all enveloped or encoded
+    #[derive(Clone, Default)]
+    struct JsonRecorder(
+        std::sync::Arc<std::sync::Mutex<Vec<(&'static str, String)>>>,
+        std::sync::Arc<std::sync::Mutex<Vec<(&'static str, Vec<u8>)>>>,
+    );
+    impl MessageManager for JsonRecorder {
+        fn is_block(&self) -> bool {
+            false
+        }
+        fn push_order_message(&mut self, _order: &OrderMessage) {}
+        fn push_trade_message(&mut self, _trade: &Trade) {}
+        fn push_fee_message(&mut self, _fee: &TradeFeeRecord) {}
+        fn push_market_event_message(&mut self, _event: &MarketEvent) {}
+        fn push_balance_message(&mut self, _balance: &BalanceMessage) {}
+        fn push_deposit_message(&mut self, _balance: &message::DepositMessage) {}
+        fn push_withdraw_message(&mut self, _balance: &message::WithdrawMessage) {}
+        fn push_transfer_message(&mut self, _tx: &message::TransferMessage) {}
+        fn push_user_message(&mut self, _user: &message::UserMessage) {}
+        fn push_json(&mut self, topic: &'static str, json: String) {
+            self.0.lock().unwrap().push((topic, json))
+        }
+        fn push_bytes(&mut self, topic: &'static str, bytes: Vec<u8>) {
+            self.1.lock().unwrap().push((topic, bytes))
+        }
+    }
+
+    #[test]
+    fn test_message_envelope() {
+        let msg_seq = MsgSeq::default();
+        let recorder = JsonRecorder::default();
+        let writer = FlakyWriter::default();
+        let settings = config::FilePersistSettings::default();
+        let mut composite = CompositePersistor::default();
+        composite.set_msg_seq(msg_seq.clone());
+        composite.add_persistor(Box::new(
+            MessengerBasedPersistor::new(Box::new(recorder.clone())).with_envelope(msg_seq.clone()),
+        ));
+        composite.add_persistor(Box::new(
+            FileBasedPersistor::with_writer("unused", &settings, Box::new(writer.clone())).with_envelope(msg_seq.clone()),
+        ));
+
+        composite.register_user(user(1));
+        composite.put_order(&order(2, 1), OrderEventType::PUT);
+        composite.put_trade(&trade(3, 1, 4));
+        composite.put_balance(&balance(5));
+        composite.put_order(&order(2, 1), OrderEventType::FINISH);
+        composite.flush().unwrap();
+
+        let sent = recorder.0.lock().unwrap().clone();
+        let envelopes = sent
+            .iter()
+            .map(|(_, json)| serde_json::from_str::<message::Envelope<serde_json::Value>>(json).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(envelopes.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(
+            envelopes.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>(),
+            vec!["UserMessage", "OrderMessage", "TradeMessage", "BalanceMessage", "OrderMessage"]
+        );
+        assert_eq!(
+            sent.iter().map(|(topic, _)| *topic).collect::<Vec<_>>(),
+            vec![USER_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, BALANCES_TOPIC, ORDERS_TOPIC]
+        );
+        assert_eq!(msg_seq.current(), 5);
+
+        // the payloads are the unwrapped messages
+        let trade_envelope: message::Envelope<Trade> = serde_json::from_str(&sent[2].1).unwrap();
+        assert_eq!(trade_envelope.payload.id, 3);
+        let order_envelope: message::Envelope<OrderMessage> = serde_json::from_str(&sent[4].1).unwrap();
+        assert_eq!(
+            (order_envelope.payload.order.id, order_envelope.payload.event),
+            (2, OrderEventType::FINISH)
+        );
+        let json = serde_json::to_string(&trade_envelope).unwrap();
+        assert_eq!(serde_json::from_str::<message::Envelope<Trade>>(&json).unwrap().seq, 3);
+
+        // the file has the same envelopes
+        let written = String::from_utf8(writer.data.lock().unwrap().clone()).unwrap();
+        let lines = written
+            .lines()
+            .map(|line| serde_json::from_str::<message::Envelope<serde_json::Value>>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        for (line, envelope) in lines.iter().zip(&envelopes) {
+            assert_eq!(
+                (line.seq, &line.kind, &line.payload),
+                (envelope.seq, &envelope.kind, &envelope.payload)
+            );
+        }
+    }
+
+    #[test]
+    fn test_messenger_protobuf() {
+        let msg_seq = MsgSeq::default();
+        let recorder = JsonRecorder::default();
+        let mut composite = CompositePersistor::default();
+        composite.set_msg_seq(msg_seq.clone());
+        let messenger = MessengerBasedPersistor::new(Box::new(recorder.clone()))
+            .with_envelope(msg_seq)
+            .with_format(MessageFormat::Protobuf);
+        composite.add_persistor(Box::new(messenger));
+
+        composite.put_order(&order(2, 1), OrderEventType::PUT);
+        composite.put_trade(&trade(3, 1, 4));
+        composite.put_balance(&balance(5));
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        let sent = recorder.1.lock().unwrap().clone();
+        assert_eq!(
+            sent.iter().map(|(topic, _)| *topic).collect::<Vec<_>>(),
+            vec![ORDERS_TOPIC, TRADES_TOPIC, BALANCES_TOPIC]
+        );
+        let events = sent.iter().map(|(_, bytes)| proto::decode(bytes).unwrap()).collect::<Vec<_>>();
+        assert_eq!(events.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+        match &events[1].payload {
+            message::Message::TradeMessage(trade) => assert_eq!((trade.id, trade.bid_user_id), (3, 4)),
+            other => panic!("unexpected message {:?}", other),
+        }
+        // the same values as the json messages
+        let json = |msg: &message::Message| serde_json::to_value(msg).unwrap()["value"].clone();
+        assert_eq!(
+            json(&events[0].payload),
+            serde_json::to_value(OrderMessage::from_order(&order(2, 1), OrderEventType::PUT)).unwrap()
+        );
+        assert_eq!(
+            json(&events[2].payload),
+            serde_json::to_value(BalanceMessage::from(&balance(5))).unwrap()
+        );
+    }
+}
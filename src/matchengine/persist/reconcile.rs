@@ -0,0 +1,365 @@
+use super::{PersistExector, SharedTrade, SinkStatus, Tombstone};
+use crate::audit::{ConservationViolation, FrozenMismatch};
+use crate::market::{DepthUpdate, Kline, MarketEvent, Order, Trade, TradeFeeRecord};
+use crate::message::producer::read_wal;
+use crate::message::ProducerStats;
+use crate::models::{AccountDesc, BalanceHistory, InternalTx};
+use crate::sequencer::{MsgSeq, SequencerState};
+use crate::types::{OrderEventType, OrderFinish};
+
+use anyhow::Result;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// how far the sinks got before the engine stopped
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Watermarks {
+    // the largest ids in the history db and the operation log, see `load_persisted_ids`
+    pub db: SequencerState,
+    // the largest seq kafka has or gets from the wal, none if unknown
+    pub kafka_msg_id: Option<u64>,
+}
+
+impl Watermarks {
+    pub fn new(db: SequencerState) -> Self {
+        Self { db, kafka_msg_id: None }
+    }
+    // the messages left in the wal are sent on start, and the ones before them were delivered
+    pub fn with_wal(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        self.kafka_msg_id = read_wal(path.as_ref())?.iter().filter_map(|entry| entry.seq()).max();
+        Ok(self)
+    }
+    // whether the db has the events of an operation replayed from `before`.
+    // an operation issuing no ids is judged by the ids before it, so the ones after the last persisted id are emitted again,
+    // duplicated rather than lost
+    fn db_has(&self, before: &SequencerState) -> bool {
+        before.order_id < self.db.order_id || before.trade_id < self.db.trade_id
+    }
+    fn kafka_has(&self, seq: u64) -> bool {
+        self.kafka_msg_id.map_or(true, |id| seq <= id)
+    }
+}
+
+// how the restored state, the operation log and the history db disagree after a crash
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartupMismatch {
+    Consistent,
+    // the history has ids the log never replayed, e.g. the tail of the log was not written.
+    // nothing is emitted again, the sequencer skips the ids by `Sequencer::recover`
+    DbAheadOfLog,
+    // the log has operations the history missed, e.g. the history writer was behind.
+    // their events are emitted again by the replay
+    LogAheadOfDb,
+    // the snapshot is newer than the log and the history, nothing is replayed.
+    // the events between the history and the snapshot can not be emitted again
+    SnapshotAhead,
+}
+
+impl StartupMismatch {
+    // `restored_op_id` is the operation of the snapshot or the slice, `replayed` the sequencer after the replay
+    pub fn classify(restored_op_id: u64, replayed: &SequencerState, watermarks: &Watermarks) -> Self {
+        let db = &watermarks.db;
+        if restored_op_id > db.operation_log_id {
+            StartupMismatch::SnapshotAhead
+        } else if db.order_id > replayed.order_id || db.trade_id > replayed.trade_id {
+            StartupMismatch::DbAheadOfLog
+        } else if replayed.order_id > db.order_id || replayed.trade_id > db.trade_id {
+            StartupMismatch::LogAheadOfDb
+        } else {
+            StartupMismatch::Consistent
+        }
+    }
+    pub fn log(&self, restored_op_id: u64, replayed: &SequencerState, watermarks: &Watermarks) {
+        match self {
+            StartupMismatch::Consistent => log::info!("history consistent with the operation log"),
+            StartupMismatch::DbAheadOfLog => log::warn!("history {:?} ahead of the operation log {:?}", watermarks.db, replayed),
+            StartupMismatch::LogAheadOfDb => log::warn!(
+                "operation log {:?} ahead of the history {:?}, the events beyond are emitted again",
+                replayed,
+                watermarks.db
+            ),
+            StartupMismatch::SnapshotAhead => log::error!(
+                "snapshot of operation {} ahead of the operation log and the history {:?}, the events between are LOST",
+                restored_op_id,
+                watermarks.db
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayReport {
+    // the operations before the restored offset
+    pub skipped: u64,
+    pub suppressed: u64,
+    pub emitted: u64,
+}
+
+struct ReplayFilter {
+    inner: Box<dyn PersistExector>,
+    msg_seq: MsgSeq,
+    watermarks: Watermarks,
+    db_has_op: bool,
+    report: ReplayReport,
+}
+
+impl ReplayFilter {
+    // the composite persistor takes a seq for each event, so a dropped event takes it too,
+    // and the emitted ones have the seqs they had
+    fn pass(&mut self) -> bool {
+        let seq = self.msg_seq.current() + 1;
+        if self.db_has_op && self.watermarks.kafka_has(seq) {
+            self.msg_seq.next();
+            self.report.suppressed += 1;
+            false
+        } else {
+            self.report.emitted += 1;
+            true
+        }
+    }
+}
+
+// the persistor of a replay after a crash, it drops the events the sinks have and emits the others to the real persistor.
+// it is shared with the controller which starts each operation and takes the real persistor back at the end
+#[derive(Clone)]
+pub struct ReplayPersistor(Arc<Mutex<ReplayFilter>>);
+
+impl ReplayPersistor {
+    pub fn new(inner: Box<dyn PersistExector>, msg_seq: MsgSeq, watermarks: Watermarks) -> Self {
+        Self(Arc::new(Mutex::new(ReplayFilter {
+            inner,
+            msg_seq,
+            watermarks,
+            db_has_op: false,
+            report: ReplayReport::default(),
+        })))
+    }
+    // before an operation is replayed, with the sequencer at the time
+    pub fn begin(&self, before: &SequencerState) {
+        let mut filter = self.0.lock().unwrap();
+        filter.db_has_op = filter.watermarks.db_has(before);
+    }
+    pub fn skip(&self) {
+        self.0.lock().unwrap().report.skipped += 1;
+    }
+    // panics unless the other clones are dropped
+    pub fn into_inner(self) -> (Box<dyn PersistExector>, ReplayReport) {
+        let filter = match Arc::try_unwrap(self.0) {
+            Ok(filter) => filter.into_inner().unwrap(),
+            Err(_) => panic!("replay persistor still in use"),
+        };
+        (filter.inner, filter.report)
+    }
+    fn put(&self, f: impl FnOnce(&mut dyn PersistExector)) {
+        let mut filter = self.0.lock().unwrap();
+        if filter.pass() {
+            f(filter.inner.as_mut());
+        }
+    }
+}
+
+impl PersistExector for ReplayPersistor {
+    fn service_available(&self) -> bool {
+        self.0.lock().unwrap().inner.service_available()
+    }
+    fn real_persist(&self) -> bool {
+        self.0.lock().unwrap().inner.real_persist()
+    }
+    fn producer_stats(&self) -> Vec<(String, ProducerStats)> {
+        self.0.lock().unwrap().inner.producer_stats()
+    }
+    fn sink_status(&self) -> Vec<SinkStatus> {
+        self.0.lock().unwrap().inner.sink_status()
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.0.lock().unwrap().inner.flush()
+    }
+    fn flush_elapsed(&mut self) {
+        self.0.lock().unwrap().inner.flush_elapsed()
+    }
+    fn put_balance(&mut self, balance: &BalanceHistory) {
+        self.put(|p| p.put_balance(balance))
+    }
+    fn put_deposit(&mut self, balance: &BalanceHistory) {
+        self.put(|p| p.put_deposit(balance))
+    }
+    fn put_withdraw(&mut self, balance: &BalanceHistory) {
+        self.put(|p| p.put_withdraw(balance))
+    }
+    fn put_transfer(&mut self, tx: InternalTx) {
+        self.put(|p| p.put_transfer(tx))
+    }
+    fn put_order(&mut self, order: &Order, at_step: OrderEventType) {
+        self.put(|p| p.put_order(order, at_step))
+    }
+    fn put_finished_order(&mut self, order: &Order, finish: OrderFinish) {
+        self.put(|p| p.put_finished_order(order, finish))
+    }
+    fn put_amended_order(&mut self, before: &Order, after: &Order) {
+        self.put(|p| p.put_amended_order(before, after))
+    }
+    fn put_trade(&mut self, trade: &Trade) {
+        self.put(|p| p.put_trade(trade))
+    }
+    fn put_trade_shared(&mut self, trade: &SharedTrade) {
+        self.put(|p| p.put_trade_shared(trade))
+    }
+    fn put_fee(&mut self, fee: &TradeFeeRecord) {
+        self.put(|p| p.put_fee(fee))
+    }
+    fn put_depth_update(&mut self, update: &DepthUpdate) {
+        self.put(|p| p.put_depth_update(update))
+    }
+    fn put_kline(&mut self, kline: &Kline) {
+        self.put(|p| p.put_kline(kline))
+    }
+    fn put_conservation_violation(&mut self, violation: &ConservationViolation) {
+        self.put(|p| p.put_conservation_violation(violation))
+    }
+    fn put_frozen_deficit(&mut self, mismatch: &FrozenMismatch) {
+        self.put(|p| p.put_frozen_deficit(mismatch))
+    }
+    fn put_tombstone(&mut self, tombstone: &Tombstone) {
+        self.put(|p| p.put_tombstone(tombstone))
+    }
+    fn put_market_event(&mut self, event: MarketEvent) {
+        self.put(|p| p.put_market_event(event))
+    }
+    fn register_user(&mut self, user: AccountDesc) {
+        self.put(|p| p.register_user(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market::MarketEventKind;
+    use crate::message::producer::WalEntry;
+    use crate::message::{envelope_json, MessageFormat, TRADES_TOPIC};
+    use crate::persist::CompositePersistor;
+    use crate::sequencer::Sequencer;
+    use fluidex_common::rust_decimal::Decimal;
+
+    // the trade ids of the price updates with their seqs
+    #[derive(Clone)]
+    struct Recorder {
+        msg_seq: MsgSeq,
+        events: Arc<Mutex<Vec<(u64, u64)>>>,
+    }
+    impl PersistExector for Recorder {
+        fn put_balance(&mut self, _balance: &BalanceHistory) {}
+        fn put_deposit(&mut self, _balance: &BalanceHistory) {}
+        fn put_withdraw(&mut self, _balance: &BalanceHistory) {}
+        fn put_transfer(&mut self, _tx: InternalTx) {}
+        fn put_order(&mut self, _order: &Order, _at_step: OrderEventType) {}
+        fn put_trade(&mut self, _trade: &Trade) {}
+        fn put_fee(&mut self, _fee: &TradeFeeRecord) {}
+        fn put_market_event(&mut self, event: MarketEvent) {
+            if let MarketEventKind::PriceUpdated { trade_id, .. } = event.kind {
+                self.events.lock().unwrap().push((self.msg_seq.current(), trade_id));
+            }
+        }
+        fn register_user(&mut self, _user: AccountDesc) {}
+    }
+
+    // replays an operation for each trade from the restored state, each making a trade and its event
+    fn replay(watermarks: Watermarks, restored: &SequencerState, trades: u64) -> (Vec<(u64, u64)>, ReplayReport, SequencerState) {
+        let mut sequencer = Sequencer::from_state(restored);
+        let recorder = Recorder {
+            msg_seq: sequencer.msg_seq(),
+            events: Default::default(),
+        };
+        let mut composite = CompositePersistor::default();
+        composite.set_msg_seq(sequencer.msg_seq());
+        composite.add_persistor(Box::new(recorder.clone()));
+        let mut persistor = ReplayPersistor::new(Box::new(composite), sequencer.msg_seq(), watermarks);
+        for op in 1..=trades {
+            if op <= restored.operation_log_id {
+                persistor.skip();
+                continue;
+            }
+            persistor.begin(&sequencer.state());
+            let trade_id = sequencer.next_trade_id();
+            persistor.put_market_event(MarketEvent {
+                timestamp: Default::default(),
+                market: "ETH_USDT".to_string(),
+                kind: MarketEventKind::PriceUpdated {
+                    price: Decimal::new(1, 0),
+                    trade_id,
+                },
+            });
+            sequencer.set_operation_log_id(op);
+        }
+        let (_, report) = persistor.into_inner();
+        let events = recorder.events.lock().unwrap().clone();
+        (events, report, sequencer.state())
+    }
+
+    fn db(trade_id: u64, operation_log_id: u64) -> Watermarks {
+        Watermarks::new(SequencerState {
+            trade_id,
+            operation_log_id,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_startup_mismatch() {
+        let start = SequencerState::default();
+
+        // log ahead of db: the trades beyond the history are emitted again with their seqs
+        let watermarks = db(2, 4);
+        let (events, report, replayed) = replay(watermarks, &start, 4);
+        assert_eq!(events, vec![(3, 3), (4, 4)]);
+        assert_eq!((report.suppressed, report.emitted), (2, 2));
+        assert_eq!(StartupMismatch::classify(0, &replayed, &watermarks), StartupMismatch::LogAheadOfDb);
+        // kafka only has the first one, the second is emitted again for it
+        let (events, _, _) = replay(
+            Watermarks {
+                kafka_msg_id: Some(1),
+                ..watermarks
+            },
+            &start,
+            4,
+        );
+        assert_eq!(events, vec![(2, 2), (3, 3), (4, 4)]);
+
+        // db ahead of log: nothing is emitted again, and the ids in the history are skipped
+        let watermarks = db(5, 3);
+        let (events, report, replayed) = replay(watermarks, &start, 3);
+        assert!(events.is_empty());
+        assert_eq!(report.suppressed, 3);
+        assert_eq!(StartupMismatch::classify(0, &replayed, &watermarks), StartupMismatch::DbAheadOfLog);
+        let mut sequencer = Sequencer::from_state(&replayed);
+        sequencer.recover(&watermarks.db);
+        assert_eq!(sequencer.next_trade_id(), 6);
+
+        // snapshot newer than both: the log is not replayed
+        let watermarks = db(2, 3);
+        let snapshot = SequencerState {
+            trade_id: 5,
+            operation_log_id: 5,
+            ..Default::default()
+        };
+        let (events, report, replayed) = replay(watermarks, &snapshot, 3);
+        assert!(events.is_empty());
+        assert_eq!(report.skipped, 3);
+        assert_eq!(replayed, snapshot);
+        assert_eq!(StartupMismatch::classify(5, &replayed, &watermarks), StartupMismatch::SnapshotAhead);
+
+        let watermarks = db(3, 3);
+        let (events, _, replayed) = replay(watermarks, &start, 3);
+        assert!(events.is_empty());
+        assert_eq!(StartupMismatch::classify(0, &replayed, &watermarks), StartupMismatch::Consistent);
+    }
+
+    #[test]
+    fn test_wal_seq() {
+        let payload = envelope_json(7, 0.0, "trade", "{}");
+        assert_eq!(
+            WalEntry::new(1, TRADES_TOPIC, MessageFormat::Json, payload.as_bytes()).seq(),
+            Some(7)
+        );
+        assert_eq!(WalEntry::new(1, TRADES_TOPIC, MessageFormat::Json, b"{}").seq(), None);
+    }
+}
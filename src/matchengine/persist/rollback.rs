@@ -0,0 +1,402 @@
+use super::Snapshot;
+use crate::controller::OperationLogEntry;
+use crate::models;
+use crate::sequencer::Sequencer;
+use crate::types::SimpleResult;
+
+use anyhow::{bail, Result};
+use fluidex_common::utils::timeutil::FTimestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const DEFAULT_SNAPSHOT_HISTORY: usize = 8;
+
+// the logged operations `from..=to` superseded by a rollback. a later replay skips them and issues the ids
+// after the ones issued before the rollback, so it makes the same state as the rollback did
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub time: f64,
+    pub from: u64,
+    pub to: u64,
+    pub order_id: u64,
+    pub trade_id: u64,
+}
+
+impl Tombstone {
+    pub fn covers(&self, operation_log_id: u64) -> bool {
+        (self.from..=self.to).contains(&operation_log_id)
+    }
+    // instead of replaying a superseded operation
+    pub fn skip(&self, operation_log_id: u64, sequencer: &mut Sequencer) {
+        if operation_log_id == self.to {
+            sequencer.set_order_id(sequencer.get_order_id().max(self.order_id));
+            sequencer.set_trade_id(sequencer.get_trade_id().max(self.trade_id));
+        }
+        sequencer.set_operation_log_id(operation_log_id);
+    }
+}
+
+impl<'r> From<&'r models::OperationLogTombstone> for Tombstone {
+    fn from(tombstone: &'r models::OperationLogTombstone) -> Self {
+        Self {
+            time: FTimestamp::from(&tombstone.time).0,
+            from: tombstone.from_id as u64,
+            to: tombstone.to_id as u64,
+            order_id: tombstone.end_order_id as u64,
+            trade_id: tombstone.end_trade_id as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Tombstones(Vec<Tombstone>);
+
+impl Tombstones {
+    pub fn push(&mut self, tombstone: Tombstone) {
+        self.0.push(tombstone);
+    }
+    pub fn find(&self, operation_log_id: u64) -> Option<&Tombstone> {
+        self.0.iter().find(|tombstone| tombstone.covers(operation_log_id))
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromIterator<Tombstone> for Tombstones {
+    fn from_iter<I: IntoIterator<Item = Tombstone>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+// the latest snapshots by their operations, the oldest ones are dropped when it is full
+pub struct SnapshotHistory {
+    snapshots: BTreeMap<u64, Snapshot>,
+    capacity: usize,
+}
+
+impl Default for SnapshotHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_SNAPSHOT_HISTORY)
+    }
+}
+
+impl SnapshotHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            snapshots: BTreeMap::new(),
+            capacity,
+        }
+    }
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.snapshots.insert(snapshot.sequencer.operation_log_id, snapshot);
+        while self.snapshots.len() > self.capacity {
+            let oldest = *self.snapshots.keys().next().unwrap();
+            self.snapshots.remove(&oldest);
+        }
+    }
+    pub fn at_or_before(&self, operation_log_id: u64) -> Option<&Snapshot> {
+        self.snapshots.range(..=operation_log_id).next_back().map(|(_, snapshot)| snapshot)
+    }
+    // the ones after a rollback are of the superseded operations
+    pub fn truncate_after(&mut self, operation_log_id: u64) {
+        self.snapshots.retain(|id, _| *id <= operation_log_id);
+    }
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+}
+
+// the state a rollback rewinds
+pub trait Rewind {
+    // the markets, the balances and the sequencer as before any operation
+    fn reset(&mut self);
+    fn load_snapshot(&mut self, snapshot: Snapshot) -> SimpleResult;
+    // with the persistence disabled, as at start
+    fn replay_logged(&mut self, op: &OperationLogEntry) -> SimpleResult;
+    fn sequencer(&mut self) -> &mut Sequencer;
+}
+
+// rewinds to the state right after `target`, from the latest snapshot before it and the logged operations `ops`.
+// the operations after it are superseded by the returned tombstone, and the sequencer goes on after them.
+// the state is undefined if it fails
+pub fn rollback_to(
+    engine: &mut impl Rewind,
+    target: u64,
+    snapshots: &SnapshotHistory,
+    ops: impl IntoIterator<Item = OperationLogEntry>,
+    time: f64,
+) -> Result<Tombstone> {
+    let before = engine.sequencer().state();
+    if target >= before.operation_log_id {
+        bail!("operation {} is not before the last one {}", target, before.operation_log_id);
+    }
+    engine.reset();
+    if let Some(snapshot) = snapshots.at_or_before(target) {
+        log::info!("rollback from the snapshot of operation {}", snapshot.sequencer.operation_log_id);
+        engine.load_snapshot(snapshot.clone())?;
+    }
+    let start = engine.sequencer().get_operation_log_id();
+    for op in ops {
+        if op.id <= start {
+            continue;
+        }
+        if op.id > target {
+            break;
+        }
+        engine.replay_logged(&op)?;
+    }
+    let replayed = engine.sequencer().get_operation_log_id();
+    if replayed != target {
+        bail!("operation log ends at {} before {}", replayed, target);
+    }
+    let tombstone = Tombstone {
+        time,
+        from: target + 1,
+        to: before.operation_log_id,
+        order_id: before.order_id,
+        trade_id: before.trade_id,
+    };
+    tombstone.skip(tombstone.to, engine.sequencer());
+    // the messages are not sent again either
+    let sequencer = engine.sequencer();
+    sequencer.set_msg_id(sequencer.get_msg_id().max(before.msg_id));
+    log::warn!("rolled back to operation {}, superseded {:?}", target, tombstone);
+    Ok(tombstone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceManager, BalanceType, BalanceUpdateController, WithdrawManager};
+    use crate::config::Settings;
+    use crate::fee::FeeManager;
+    use crate::market::{Market, MarketStatus, OrderSide, UserNonces};
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use crate::types::TimestampMs;
+    use crate::user_manager::UserManager;
+    use fluidex_common::rust_decimal::Decimal;
+    use fluidex_common::rust_decimal_macros::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+
+    #[derive(Clone, Copy, Serialize, Deserialize)]
+    enum Op {
+        Deposit(u32, bool, Decimal),
+        Put(u32, OrderSide, Decimal, Decimal),
+        Cancel(u32, u64),
+    }
+
+    struct Engine {
+        sequencer: Sequencer,
+        balance_manager: BalanceManager,
+        withdraw_manager: WithdrawManager,
+        update_controller: BalanceUpdateController,
+        user_nonces: UserNonces,
+        markets: HashMap<String, Market>,
+        tombstones: Tombstones,
+    }
+
+    fn new_engine() -> Engine {
+        let balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let mut market = Market::new(&get_simple_market_config(), &Settings::default(), &balance_manager).unwrap();
+        let user_nonces = UserNonces::default();
+        market.user_nonces = user_nonces.clone();
+        Engine {
+            sequencer: Sequencer::default(),
+            balance_manager,
+            withdraw_manager: WithdrawManager::new(),
+            update_controller: BalanceUpdateController::new(),
+            user_nonces,
+            markets: [(market.name.to_string(), market)].into_iter().collect(),
+            tombstones: Tombstones::default(),
+        }
+    }
+
+    impl Rewind for Engine {
+        fn reset(&mut self) {
+            self.sequencer.reset();
+            for market in self.markets.values_mut() {
+                market.reset();
+            }
+            self.update_controller.reset();
+            self.withdraw_manager.reset();
+            self.balance_manager.reset();
+            self.user_nonces.clear();
+        }
+        fn load_snapshot(&mut self, snapshot: Snapshot) -> SimpleResult {
+            snapshot.restore(
+                &mut self.sequencer,
+                &mut self.balance_manager,
+                &mut self.withdraw_manager,
+                &self.user_nonces,
+                &mut UserManager::new(),
+                &mut FeeManager::default(),
+                &mut self.markets,
+            )
+        }
+        // as the controller replays an operation
+        fn replay_logged(&mut self, op: &OperationLogEntry) -> SimpleResult {
+            if let Some(tombstone) = self.tombstones.find(op.id).copied() {
+                tombstone.skip(op.id, &mut self.sequencer);
+                return Ok(());
+            }
+            let market = self.markets.values_mut().next().unwrap();
+            market.clock.fix(TimestampMs::from_secs_f64(op.time));
+            match serde_json::from_str(&op.params)? {
+                Op::Deposit(user_id, is_base, amount) => {
+                    let asset = if is_base { market.base } else { market.quote };
+                    self.balance_manager.add(user_id, BalanceType::AVAILABLE, asset, &amount);
+                }
+                Op::Put(user_id, side, amount, price) => {
+                    let order_input = OrderInputBuilder::new(market.name, user_id, side, amount, price)
+                        .fees(dec!(0.002), dec!(0.001))
+                        .build();
+                    // rejected the same way when replayed
+                    let _ = market.put_order(
+                        &mut self.sequencer,
+                        (&mut self.balance_manager).into(),
+                        &mut self.update_controller,
+                        &FeeManager::default(),
+                        &mut DummyPersistor::default(),
+                        order_input,
+                    );
+                }
+                Op::Cancel(user_id, order_id) => {
+                    market.cancel(
+                        (&mut self.balance_manager).into(),
+                        &mut DummyPersistor::default(),
+                        order_id,
+                        user_id,
+                    )?;
+                }
+            }
+            market.clock.release();
+            self.sequencer.set_operation_log_id(op.id);
+            Ok(())
+        }
+        fn sequencer(&mut self) -> &mut Sequencer {
+            &mut self.sequencer
+        }
+    }
+
+    fn take_snapshot(engine: &Engine, time: f64) -> Snapshot {
+        Snapshot::take(
+            time,
+            &engine.sequencer,
+            &engine.balance_manager,
+            &engine.withdraw_manager,
+            &engine.user_nonces,
+            &UserManager::new(),
+            &FeeManager::default(),
+            engine.markets.values(),
+            &[],
+        )
+    }
+
+    // the balances and the markets, the sequencer is compared by the callers
+    fn assert_same_state(a: &Engine, b: &Engine) {
+        let nonzero = |engine: &Engine| {
+            let mut balances = engine
+                .balance_manager
+                .balances
+                .iter()
+                .filter(|(_, amount)| !amount.is_zero())
+                .map(|(key, amount)| (key.clone(), *amount))
+                .collect::<Vec<_>>();
+            balances.sort_by_key(|(key, _)| (key.user_id, key.asset, key.balance_type as i16));
+            balances
+        };
+        assert_eq!(nonzero(a), nonzero(b));
+        for (name, market) in &a.markets {
+            let other = &b.markets[name];
+            let status = |market: &Market| MarketStatus {
+                stats: Default::default(),
+                ..market.status()
+            };
+            assert_eq!(status(market), status(other));
+            let orders = |market: &Market| {
+                let orders = market.orders.values().map(|order| *order.borrow()).collect::<Vec<_>>();
+                serde_json::to_value(orders).unwrap()
+            };
+            assert_eq!(orders(market), orders(other));
+            assert_eq!(market.asks.keys().collect::<Vec<_>>(), other.asks.keys().collect::<Vec<_>>());
+            assert_eq!(market.bids.keys().collect::<Vec<_>>(), other.bids.keys().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_rollback_to() {
+        // a randomized session of 200 operations, snapshotted every 50
+        let mut rng = StdRng::seed_from_u64(4344);
+        let mut original = new_engine();
+        let mut snapshots = SnapshotHistory::default();
+        let mut log = Vec::new();
+        for id in 1..=200 {
+            let time = 1_600_000_000.0 + id as f64 * 0.51;
+            let market = original.markets.values().next().unwrap();
+            let open_orders = market.orders.values().map(|order| *order.borrow()).collect::<Vec<_>>();
+            let op = match rng.gen_range(0..8) {
+                0 => Op::Deposit(rng.gen_range(0..4), rng.gen(), Decimal::from(rng.gen_range(100..10_000))),
+                1 if !open_orders.is_empty() => {
+                    let order = &open_orders[rng.gen_range(0..open_orders.len())];
+                    Op::Cancel(order.user, order.id)
+                }
+                _ => {
+                    let side = if rng.gen::<bool>() { OrderSide::BID } else { OrderSide::ASK };
+                    let amount = Decimal::from(rng.gen_range(1..20));
+                    Op::Put(rng.gen_range(0..4), side, amount, Decimal::from(rng.gen_range(120..140)))
+                }
+            };
+            let entry = OperationLogEntry {
+                id,
+                time,
+                method: "op".to_string(),
+                params: serde_json::to_string(&op).unwrap(),
+                failed: false,
+            };
+            original.replay_logged(&entry).unwrap();
+            log.push(entry);
+            if id % 50 == 0 {
+                snapshots.push(take_snapshot(&original, time));
+            }
+        }
+        let before = original.sequencer.state();
+        assert!(original.markets.values().next().unwrap().trade_count > 0);
+        assert!(rollback_to(&mut original, 200, &snapshots, log.clone(), 0.0).is_err());
+
+        // from the snapshot of 100, replaying 101..=120
+        assert_eq!(snapshots.at_or_before(120).unwrap().sequencer.operation_log_id, 100);
+        let tombstone = rollback_to(&mut original, 120, &snapshots, log.clone(), 1_700_000_000.0).unwrap();
+        assert_eq!((tombstone.from, tombstone.to), (121, 200));
+        assert_eq!((tombstone.order_id, tombstone.trade_id), (before.order_id, before.trade_id));
+
+        let mut expected = new_engine();
+        for op in &log[..120] {
+            expected.replay_logged(op).unwrap();
+        }
+        assert_same_state(&original, &expected);
+        // the ids go on after the superseded operations
+        assert_eq!(original.sequencer.state(), before);
+
+        // a later full replay skips the superseded operations the same way
+        let mut replayed = new_engine();
+        replayed.tombstones.push(tombstone);
+        for op in &log {
+            replayed.replay_logged(op).unwrap();
+        }
+        assert_same_state(&replayed, &original);
+        assert_eq!(replayed.sequencer.state(), original.sequencer.state());
+
+        snapshots.truncate_after(120);
+        assert_eq!(snapshots.len(), 2);
+    }
+}
@@ -0,0 +1,53 @@
+use crate::matchengine::market::Trade;
+
+use once_cell::sync::OnceCell;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// a trade handed to all the persistors of a composite, so it is neither cloned nor serialized
+// for each of them. the json is made when it is first asked for
+#[derive(Debug, Clone)]
+pub struct SharedTrade {
+    trade: Arc<Trade>,
+    json: Arc<OnceCell<String>>,
+    // how many times the json is made, checked by the tests of the persistors
+    #[cfg(test)]
+    serializations: Arc<AtomicUsize>,
+}
+
+impl SharedTrade {
+    pub fn new(trade: Trade) -> Self {
+        Self {
+            trade: Arc::new(trade),
+            json: Arc::new(OnceCell::new()),
+            #[cfg(test)]
+            serializations: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    pub fn trade(&self) -> &Trade {
+        &self.trade
+    }
+    // the same as `serde_json::to_string(trade)`, i.e. the trade messages sent to kafka
+    pub fn json(&self) -> &str {
+        self.json.get_or_init(|| {
+            #[cfg(test)]
+            self.serializations.fetch_add(1, Ordering::SeqCst);
+            serde_json::to_string(&*self.trade).unwrap()
+        })
+    }
+    // whether the json has been made
+    pub fn serialized(&self) -> bool {
+        self.json.get().is_some()
+    }
+    #[cfg(test)]
+    pub fn serializations(&self) -> usize {
+        self.serializations.load(Ordering::SeqCst)
+    }
+}
+
+impl From<Trade> for SharedTrade {
+    fn from(trade: Trade) -> Self {
+        Self::new(trade)
+    }
+}
@@ -0,0 +1,476 @@
+use crate::asset::{BalanceManager, BalanceMapKey, LockRecord, PendingWithdraw, WithdrawManager};
+use crate::fee::FeeManager;
+use crate::market::{Market, MarketState, Order, UserNonces};
+use crate::sequencer::{Sequencer, SequencerState};
+use crate::user_manager::{UserInfo, UserManager};
+use anyhow::{anyhow, bail, Result};
+use fluidex_common::rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+// bumped when the format changes, a snapshot of another version is not loaded
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketSnapshot {
+    pub name: String,
+    pub state: MarketState,
+    pub price: Decimal,
+    pub trade_count: u64,
+    // as changed by `update_fees` and `update_params` since the market was loaded
+    pub default_maker_fee: Decimal,
+    pub default_taker_fee: Decimal,
+    pub amount_prec: u32,
+    pub price_prec: u32,
+    pub min_amount: Decimal,
+    // the books and the orders of the users are rebuilt from them
+    pub orders: Vec<Order>,
+}
+
+// the full state of the engine. it is restored into a fresh engine, then the operations
+// after `sequencer.operation_log_id` are replayed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub version: u32,
+    pub time: f64,
+    pub sequencer: SequencerState,
+    pub balances: Vec<(BalanceMapKey, Decimal)>,
+    pub locks: Vec<LockRecord>,
+    pub withdraws: Vec<PendingWithdraw>,
+    // the last nonce of each user, none in the snapshots written before the nonces
+    #[serde(default)]
+    pub nonces: Vec<(u32, u64)>,
+    // the registered users by id, the replayed registrations go on after them
+    pub users: Vec<(u32, UserInfo)>,
+    // the users paying their taker fees in the discount asset
+    pub discount_users: Vec<u32>,
+    pub markets: Vec<MarketSnapshot>,
+    // the configured markets closed since, they are removed again as the snapshot is restored
+    pub closed_markets: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn take<'a>(
+        time: f64,
+        sequencer: &Sequencer,
+        balance_manager: &BalanceManager,
+        withdraw_manager: &WithdrawManager,
+        user_nonces: &UserNonces,
+        user_manager: &UserManager,
+        fee_manager: &FeeManager,
+        markets: impl IntoIterator<Item = &'a Market>,
+        closed_markets: &[String],
+    ) -> Self {
+        let mut balances = balance_manager
+            .balances
+            .iter()
+            .map(|(key, amount)| (key.clone(), *amount))
+            .collect::<Vec<_>>();
+        balances.sort_by_key(|(key, _)| (key.user_id, key.asset, key.balance_type as i16));
+        let mut locks = balance_manager.locks.values().cloned().collect::<Vec<_>>();
+        locks.sort_by_key(|lock| lock.lock_id);
+        let mut users = user_manager.users.iter().map(|(id, user)| (*id, user.clone())).collect::<Vec<_>>();
+        users.sort_by_key(|(id, _)| *id);
+        let mut discount_users = fee_manager.discount_users.iter().copied().collect::<Vec<_>>();
+        discount_users.sort_unstable();
+        let mut markets = markets
+            .into_iter()
+            .map(|market| MarketSnapshot {
+                name: market.name.to_string(),
+                state: market.state,
+                price: market.price,
+                trade_count: market.trade_count,
+                default_maker_fee: market.default_maker_fee,
+                default_taker_fee: market.default_taker_fee,
+                amount_prec: market.amount_prec,
+                price_prec: market.price_prec,
+                min_amount: market.min_amount,
+                orders: market.orders.values().map(|order| *order.borrow()).collect(),
+            })
+            .collect::<Vec<_>>();
+        markets.sort_by(|a, b| a.name.cmp(&b.name));
+        Self {
+            version: SNAPSHOT_VERSION,
+            time,
+            sequencer: sequencer.state(),
+            balances,
+            locks,
+            withdraws: withdraw_manager.pending.values().cloned().collect(),
+            nonces: user_nonces.all(),
+            users,
+            discount_users,
+            markets,
+            closed_markets: closed_markets.to_vec(),
+        }
+    }
+
+    // into a fresh state, e.g. after `Controller::reset_state`
+    pub fn restore(
+        self,
+        sequencer: &mut Sequencer,
+        balance_manager: &mut BalanceManager,
+        withdraw_manager: &mut WithdrawManager,
+        user_nonces: &UserNonces,
+        user_manager: &mut UserManager,
+        fee_manager: &mut FeeManager,
+        markets: &mut HashMap<String, Market>,
+    ) -> Result<()> {
+        if self.version != SNAPSHOT_VERSION {
+            bail!("snapshot version {} is not supported", self.version);
+        }
+        if let Some(unknown) = self.markets.iter().find(|snapshot| !markets.contains_key(&snapshot.name)) {
+            bail!("market {} of the snapshot is not found", unknown.name);
+        }
+        sequencer.restore(&self.sequencer);
+        for (key, amount) in &self.balances {
+            balance_manager.set(key.user_id, key.balance_type, &key.asset, amount);
+        }
+        for lock in self.locks {
+            balance_manager.locks.insert(lock.lock_id, lock);
+        }
+        for withdraw in self.withdraws {
+            withdraw_manager.insert(withdraw);
+        }
+        user_nonces.restore(self.nonces);
+        user_manager.users = self.users.into_iter().collect();
+        fee_manager.discount_users = self.discount_users.into_iter().collect();
+        for name in &self.closed_markets {
+            markets.remove(name);
+        }
+        for snapshot in self.markets {
+            let market = markets
+                .get_mut(&snapshot.name)
+                .ok_or_else(|| anyhow!("market {} not found", snapshot.name))?;
+            market.state = snapshot.state;
+            market.price = snapshot.price;
+            market.trade_count = snapshot.trade_count;
+            market.default_maker_fee = snapshot.default_maker_fee;
+            market.default_taker_fee = snapshot.default_taker_fee;
+            market.amount_prec = snapshot.amount_prec;
+            market.price_prec = snapshot.price_prec;
+            market.min_amount = snapshot.min_amount;
+            for mut order in snapshot.orders {
+                // interned by the market rather than by the deserializer
+                order.market = market.name.into();
+                order.base = market.base.into();
+                order.quote = market.quote.into();
+                market.insert_order_into_orderbook(order);
+            }
+        }
+        Ok(())
+    }
+
+    // written to a temporary file first, so a crash never leaves a partial snapshot
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(std::fs::File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // none if there is no snapshot yet
+    pub fn read(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_reader(BufReader::new(file))?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceType, BalanceUpdateController};
+    use crate::config::{self, Settings};
+    use crate::fee::FeeManager;
+    use crate::market::{MarketStatus, OrderSide};
+    use crate::matchengine::mock::*;
+    use crate::persist::DummyPersistor;
+    use crate::types::TimestampMs;
+    use fluidex_common::rust_decimal_macros::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[derive(Clone, Copy)]
+    enum Op {
+        Deposit(u32, bool, Decimal),
+        Put(u32, OrderSide, Decimal, Decimal, u64),
+        Cancel(u32, u64),
+        Fees(Decimal, Decimal),
+        Params(u32, u32, Decimal),
+        Close,
+    }
+
+    // the orders go to the first one, the other one is closed
+    const MARKET: &str = "ETH_USDT";
+    const CLOSED_MARKET: &str = "ETH_USDT_OLD";
+
+    struct Engine {
+        sequencer: Sequencer,
+        balance_manager: BalanceManager,
+        withdraw_manager: WithdrawManager,
+        update_controller: BalanceUpdateController,
+        user_nonces: UserNonces,
+        user_manager: UserManager,
+        markets: HashMap<String, Market>,
+        closed_markets: Vec<String>,
+    }
+
+    fn new_engine() -> Engine {
+        let balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let user_nonces = UserNonces::default();
+        let markets = [MARKET, CLOSED_MARKET]
+            .iter()
+            .map(|name| {
+                let config = config::Market {
+                    name: name.to_string(),
+                    ..get_simple_market_config()
+                };
+                let mut market = Market::new(&config, &Settings::default(), &balance_manager).unwrap();
+                market.user_nonces = user_nonces.clone();
+                (name.to_string(), market)
+            })
+            .collect();
+        Engine {
+            sequencer: Sequencer::default(),
+            balance_manager,
+            withdraw_manager: WithdrawManager::new(),
+            update_controller: BalanceUpdateController::new(),
+            user_nonces,
+            user_manager: UserManager::new(),
+            markets,
+            closed_markets: Vec::new(),
+        }
+    }
+
+    // as the controller replays an operation, at the logged time
+    fn execute(engine: &mut Engine, time: f64, op: Op) {
+        engine.sequencer.next_operation_log_id();
+        if let Op::Close = op {
+            let mut market = engine.markets.remove(CLOSED_MARKET).unwrap();
+            market.drain_all_orders((&mut engine.balance_manager).into(), &mut DummyPersistor::default());
+            engine.closed_markets.push(CLOSED_MARKET.to_string());
+            return;
+        }
+        let market = engine.markets.get_mut(MARKET).unwrap();
+        market.clock.fix(TimestampMs::from_secs_f64(time));
+        match op {
+            Op::Deposit(user_id, is_base, amount) => {
+                let asset = if is_base { market.base } else { market.quote };
+                engine.balance_manager.add(user_id, BalanceType::AVAILABLE, asset, &amount);
+                // registered on the first deposit
+                engine.user_manager.users.entry(user_id).or_insert_with(|| UserInfo {
+                    l1_address: format!("0x{:040x}", user_id),
+                    l2_pubkey: format!("0x{:064x}", user_id),
+                });
+            }
+            Op::Put(user_id, side, amount, price, nonce) => {
+                let order_input = OrderInputBuilder::new(market.name, user_id, side, amount, price)
+                    .fees(dec!(0.002), dec!(0.001))
+                    .nonce(nonce)
+                    .build();
+                // rejected the same way when replayed
+                let _ = market.put_order(
+                    &mut engine.sequencer,
+                    (&mut engine.balance_manager).into(),
+                    &mut engine.update_controller,
+                    &FeeManager::default(),
+                    &mut DummyPersistor::default(),
+                    order_input,
+                );
+            }
+            Op::Cancel(user_id, order_id) => {
+                market
+                    .cancel(
+                        (&mut engine.balance_manager).into(),
+                        &mut DummyPersistor::default(),
+                        order_id,
+                        user_id,
+                    )
+                    .unwrap();
+            }
+            Op::Fees(maker_fee, taker_fee) => market.update_fees(maker_fee, taker_fee).unwrap(),
+            Op::Params(amount_prec, price_prec, min_amount) => {
+                market
+                    .update_params(
+                        (&mut engine.balance_manager).into(),
+                        &mut DummyPersistor::default(),
+                        amount_prec,
+                        price_prec,
+                        min_amount,
+                    )
+                    .unwrap();
+            }
+            Op::Close => unreachable!(),
+        }
+        market.clock.release();
+    }
+
+    fn assert_same_state(a: &Engine, b: &Engine) {
+        assert_eq!(a.sequencer.state(), b.sequencer.state());
+        assert_eq!(a.user_nonces.all(), b.user_nonces.all());
+        assert_eq!(a.user_manager.users, b.user_manager.users);
+        let nonzero = |engine: &Engine| {
+            let mut balances = engine
+                .balance_manager
+                .balances
+                .iter()
+                .filter(|(_, amount)| !amount.is_zero())
+                .map(|(key, amount)| (key.clone(), *amount))
+                .collect::<Vec<_>>();
+            balances.sort_by_key(|(key, _)| (key.user_id, key.asset, key.balance_type as i16));
+            balances
+        };
+        assert_eq!(nonzero(a), nonzero(b));
+        assert_eq!(a.closed_markets, b.closed_markets);
+        let mut names = a.markets.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut other_names = b.markets.keys().collect::<Vec<_>>();
+        other_names.sort();
+        assert_eq!(names, other_names);
+        for (name, market) in &a.markets {
+            let other = &b.markets[name];
+            // the trade stats are not in the snapshots
+            let status = |market: &Market| MarketStatus {
+                stats: Default::default(),
+                ..market.status()
+            };
+            assert_eq!(status(market), status(other));
+            let params = |market: &Market| {
+                (
+                    market.default_maker_fee,
+                    market.default_taker_fee,
+                    market.amount_prec,
+                    market.price_prec,
+                    market.min_amount,
+                )
+            };
+            assert_eq!(params(market), params(other));
+            let orders = |market: &Market| {
+                let orders = market.orders.values().map(|order| *order.borrow()).collect::<Vec<_>>();
+                serde_json::to_value(orders).unwrap()
+            };
+            assert_eq!(orders(market), orders(other));
+            assert_eq!(market.asks.keys().collect::<Vec<_>>(), other.asks.keys().collect::<Vec<_>>());
+            assert_eq!(market.bids.keys().collect::<Vec<_>>(), other.bids.keys().collect::<Vec<_>>());
+            assert_eq!(market.users.keys().collect::<Vec<_>>(), other.users.keys().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let dir = std::env::temp_dir().join(format!("test_snapshot_restore_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+        std::fs::remove_file(&path).ok();
+        assert!(Snapshot::read(&path).unwrap().is_none());
+
+        // a randomized session, snapshotted halfway
+        let mut rng = StdRng::seed_from_u64(4342);
+        let mut original = new_engine();
+        let mut log = Vec::new();
+        for n in 0..400 {
+            let time = 1_600_000_000.0 + n as f64 * 0.51;
+            let market = &original.markets[MARKET];
+            let open_orders = market.orders.values().map(|order| *order.borrow()).collect::<Vec<_>>();
+            let op = match rng.gen_range(0..20) {
+                // before the snapshot
+                _ if n == 150 => Op::Close,
+                0 | 1 => Op::Deposit(rng.gen_range(0..4), rng.gen(), Decimal::from(rng.gen_range(100..10_000))),
+                2 | 3 if !open_orders.is_empty() => {
+                    let order = &open_orders[rng.gen_range(0..open_orders.len())];
+                    Op::Cancel(order.user, order.id)
+                }
+                4 => Op::Fees(Decimal::new(rng.gen_range(0..3), 3), Decimal::new(rng.gen_range(3..6), 3)),
+                // the amounts and the prices are integers, so only the min amount may cancel the orders
+                5 => {
+                    let (amount_prec, price_prec) = [(4, 2), (2, 2), (0, 0)][rng.gen_range(0..3)];
+                    Op::Params(amount_prec, price_prec, Decimal::from(rng.gen_range(0..3)))
+                }
+                _ => {
+                    let side = if rng.gen::<bool>() { OrderSide::BID } else { OrderSide::ASK };
+                    let amount = Decimal::from(rng.gen_range(1..20));
+                    // increasing for every user, an order without a nonce now and then
+                    let nonce = if rng.gen_range(0..4) == 0 { 0 } else { n + 1 };
+                    Op::Put(rng.gen_range(0..4), side, amount, Decimal::from(rng.gen_range(120..140)), nonce)
+                }
+            };
+            execute(&mut original, time, op);
+            log.push((time, op));
+            if n == 199 {
+                Snapshot::take(
+                    time,
+                    &original.sequencer,
+                    &original.balance_manager,
+                    &original.withdraw_manager,
+                    &original.user_nonces,
+                    &original.user_manager,
+                    &FeeManager::default(),
+                    original.markets.values(),
+                    &original.closed_markets,
+                )
+                .write(&path)
+                .unwrap();
+            }
+        }
+        let market = &original.markets[MARKET];
+        assert!(market.trade_count > 0);
+        assert_ne!(market.min_amount, get_simple_market_config().min_amount);
+        assert!(!market.default_taker_fee.is_zero());
+        assert!(!original.user_nonces.all().is_empty());
+        assert!(!original.user_manager.users.is_empty());
+        assert!(!original.markets.contains_key(CLOSED_MARKET));
+
+        let mut full_replay = new_engine();
+        for (time, op) in log.iter().copied() {
+            execute(&mut full_replay, time, op);
+        }
+
+        // restored from the snapshot, the operations after it are replayed
+        let snapshot = Snapshot::read(&path).unwrap().unwrap();
+        let offset = snapshot.sequencer.operation_log_id as usize;
+        assert_eq!(offset, 200);
+        let mut restored = new_engine();
+        snapshot
+            .restore(
+                &mut restored.sequencer,
+                &mut restored.balance_manager,
+                &mut restored.withdraw_manager,
+                &restored.user_nonces,
+                &mut restored.user_manager,
+                &mut FeeManager::default(),
+                &mut restored.markets,
+            )
+            .unwrap();
+        for (time, op) in log[offset..].iter().copied() {
+            execute(&mut restored, time, op);
+        }
+        assert_same_state(&restored, &full_replay);
+        assert_same_state(&restored, &original);
+
+        // another version is rejected
+        let mut snapshot = Snapshot::read(&path).unwrap().unwrap();
+        snapshot.version += 1;
+        let mut engine = new_engine();
+        assert!(snapshot
+            .restore(
+                &mut engine.sequencer,
+                &mut engine.balance_manager,
+                &mut engine.withdraw_manager,
+                &engine.user_nonces,
+                &mut engine.user_manager,
+                &mut FeeManager::default(),
+                &mut engine.markets
+            )
+            .is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
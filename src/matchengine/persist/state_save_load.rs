@@ -1,16 +1,18 @@
 use crate::asset;
 use crate::asset::BalanceManager;
-use crate::controller::Controller;
+use crate::controller::{Controller, OperationLogEntry};
 use crate::database;
 use crate::market::Order;
 use crate::models;
+use crate::persist::{ReplayReport, Snapshot, StartupMismatch, Tombstone, Tombstones, Watermarks};
+use crate::sequencer::SequencerState;
 use crate::sqlxextend::*;
 use crate::types;
-use crate::types::SimpleResult;
+use crate::types::{SimpleResult, TimestampMs};
 use crate::{config, storage};
 use arrayref::array_ref;
-use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
-use models::{tablenames, BalanceSlice, BalanceSliceInsert, OperationLog, OrderSlice, SliceHistory};
+use fluidex_common::utils::timeutil::current_timestamp;
+use models::{tablenames, BalanceSlice, BalanceSliceInsert, LockSlice, NonceSlice, OperationLog, OrderSlice, SliceHistory, WithdrawSlice};
 use sqlx::migrate::Migrator;
 use sqlx::Connection;
 use std::convert::TryFrom;
@@ -60,6 +62,21 @@ fn sqlverf_load_slice_from_db() -> impl std::any::Any {
             slice_id,
             order_id
         ),
+        sqlx::query!(
+            "select * from withdraw_slice where slice_id = $1 and business_id > $2 order by business_id asc limit 1000",
+            slice_id,
+            order_id
+        ),
+        sqlx::query!(
+            "select * from lock_slice where slice_id = $1 and lock_id > $2 order by lock_id asc limit 1000",
+            slice_id,
+            order_id
+        ),
+        sqlx::query!(
+            "select * from nonce_slice where slice_id = $1 and user_id > $2 order by user_id asc limit 1000",
+            slice_id,
+            0
+        ),
     )
 }
 
@@ -82,6 +99,33 @@ fn utest_load_slice_from_db() {
         ),
         "select * from order_slice where slice_id = $1 and id > $2 order by id asc limit 1000"
     );
+
+    assert_eq!(
+        format!(
+            "select * from {} where slice_id = $1 and business_id > $2 order by business_id asc limit {}",
+            tablenames::WITHDRAWSLICE,
+            database::QUERY_LIMIT
+        ),
+        "select * from withdraw_slice where slice_id = $1 and business_id > $2 order by business_id asc limit 1000"
+    );
+
+    assert_eq!(
+        format!(
+            "select * from {} where slice_id = $1 and lock_id > $2 order by lock_id asc limit {}",
+            tablenames::LOCKSLICE,
+            database::QUERY_LIMIT
+        ),
+        "select * from lock_slice where slice_id = $1 and lock_id > $2 order by lock_id asc limit 1000"
+    );
+
+    assert_eq!(
+        format!(
+            "select * from {} where slice_id = $1 and user_id > $2 order by user_id asc limit {}",
+            tablenames::NONCESLICE,
+            database::QUERY_LIMIT
+        ),
+        "select * from nonce_slice where slice_id = $1 and user_id > $2 order by user_id asc limit 1000"
+    );
 }
 
 pub async fn load_slice_from_db(conn: &mut ConnectionType, slice_id: i64, controller: &mut Controller) {
@@ -135,10 +179,12 @@ pub async fn load_slice_from_db(conn: &mut ConnectionType, slice_id: i64, contro
             let market = controller.markets.get_mut(&order.market).unwrap();
             let order = Order {
                 id: order.id as u64,
+                priority: order.priority as u64,
+                client_order_id: order.client_order_id.map(|id| id as u64),
                 type_: order.order_type,
                 side: order.order_side,
-                create_time: FTimestamp::from(&order.create_time).0,
-                update_time: FTimestamp::from(&order.update_time).0,
+                create_time: TimestampMs::from(&order.create_time),
+                update_time: TimestampMs::from(&order.update_time),
                 market: market.name.into(),
                 base: market.base.into(),
                 quote: market.quote.into(),
@@ -152,6 +198,7 @@ pub async fn load_slice_from_db(conn: &mut ConnectionType, slice_id: i64, contro
                 finished_base: order.finished_base,
                 finished_quote: order.finished_quote,
                 finished_fee: order.finished_fee,
+                fill_outcome: None,
                 post_only: order.post_only,
                 signature: match order.signature.len() == 64 {
                     true => *array_ref!(order.signature[..64], 0, 64),
@@ -170,6 +217,92 @@ pub async fn load_slice_from_db(conn: &mut ConnectionType, slice_id: i64, contro
             break;
         }
     }
+    // load pending withdraws
+    let mut business_id: i64 = -1;
+    let withdraw_query = format!(
+        "select * from {} where slice_id = $1 and business_id > $2 order by business_id asc limit {}",
+        tablenames::WITHDRAWSLICE,
+        database::QUERY_LIMIT
+    );
+    loop {
+        let withdraws: Vec<WithdrawSlice> = sqlx::query_as(&withdraw_query)
+            .bind(slice_id)
+            .bind(business_id)
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        for withdraw in &withdraws {
+            controller.withdraw_manager.insert(asset::PendingWithdraw {
+                business_id: withdraw.business_id as u64,
+                user_id: withdraw.user_id as u32,
+                asset: withdraw.asset.clone(),
+                business: withdraw.business.clone(),
+                amount: withdraw.amount,
+            });
+        }
+        if let Some(last_withdraw) = withdraws.last() {
+            business_id = last_withdraw.business_id;
+        }
+        if withdraws.len() as i64 != database::QUERY_LIMIT {
+            break;
+        }
+    }
+    // load balance locks, the LOCK balances are loaded with the other balances
+    let mut lock_id: i64 = -1;
+    let lock_query = format!(
+        "select * from {} where slice_id = $1 and lock_id > $2 order by lock_id asc limit {}",
+        tablenames::LOCKSLICE,
+        database::QUERY_LIMIT
+    );
+    loop {
+        let locks: Vec<LockSlice> = sqlx::query_as(&lock_query)
+            .bind(slice_id)
+            .bind(lock_id)
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        for lock in &locks {
+            controller.balance_manager.locks.insert(
+                lock.lock_id as u64,
+                asset::LockRecord {
+                    lock_id: lock.lock_id as u64,
+                    user_id: lock.user_id as u32,
+                    asset: lock.asset.clone(),
+                    amount: lock.amount,
+                },
+            );
+        }
+        if let Some(last_lock) = locks.last() {
+            lock_id = last_lock.lock_id;
+        }
+        if locks.len() as i64 != database::QUERY_LIMIT {
+            break;
+        }
+    }
+    // load the last nonces of the users
+    let mut user_id: i32 = -1;
+    let nonce_query = format!(
+        "select * from {} where slice_id = $1 and user_id > $2 order by user_id asc limit {}",
+        tablenames::NONCESLICE,
+        database::QUERY_LIMIT
+    );
+    loop {
+        let nonces: Vec<NonceSlice> = sqlx::query_as(&nonce_query)
+            .bind(slice_id)
+            .bind(user_id)
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+        controller
+            .user_nonces
+            .restore(nonces.iter().map(|nonce| (nonce.user_id as u32, nonce.nonce as u64)));
+        if let Some(last_nonce) = nonces.last() {
+            user_id = last_nonce.user_id;
+        }
+        if nonces.len() as i64 != database::QUERY_LIMIT {
+            break;
+        }
+    }
 }
 
 #[cfg(sqlxverf)]
@@ -193,7 +326,14 @@ fn utest_load_operation_log_from_db() {
     );
 }
 
-pub async fn load_operation_log_from_db(conn: &mut ConnectionType, operation_log_start_id: u64, controller: &mut Controller) {
+// `watermarks` are given after a crash, the events the sinks have not got are emitted again by the replay
+pub async fn load_operation_log_from_db(
+    conn: &mut ConnectionType,
+    operation_log_start_id: u64,
+    controller: &mut Controller,
+    watermarks: Option<&Watermarks>,
+) -> ReplayReport {
+    let mut report = ReplayReport::default();
     // LOAD operation_log
     let mut operation_log_start_id = operation_log_start_id as i64; // exclusive
     let query = format!(
@@ -213,13 +353,53 @@ pub async fn load_operation_log_from_db(conn: &mut ConnectionType, operation_log
             break;
         }
         operation_log_start_id = operation_logs.last().unwrap().id;
-        for log in operation_logs {
-            log::info!("replay {} {}", &log.method, &log.params);
-            controller.replay(&log.method, &log.params).unwrap();
+        let ops = operation_logs.into_iter().map(OperationLogEntry::from);
+        match watermarks {
+            Some(watermarks) => {
+                let batch = controller.replay_reconciled(ops, watermarks).unwrap();
+                report.skipped += batch.skipped;
+                report.suppressed += batch.suppressed;
+                report.emitted += batch.emitted;
+            }
+            None => controller.replay_operations(ops).unwrap(),
         }
     }
     controller.sequencer.set_operation_log_id(operation_log_start_id as u64);
     log::info!("set operation_log_id to {}", operation_log_start_id);
+    report
+}
+
+// the logged operations `after..=until`, e.g. for a rollback
+pub async fn load_operations(conn: &mut ConnectionType, after: u64, until: u64) -> anyhow::Result<Vec<OperationLogEntry>> {
+    let query = format!(
+        "select * from {} where id > $1 and id <= $2 order by id asc",
+        tablenames::OPERATIONLOG
+    );
+    let operation_logs: Vec<OperationLog> = sqlx::query_as(&query)
+        .bind(after as i64)
+        .bind(until as i64)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(operation_logs.into_iter().map(OperationLogEntry::from).collect())
+}
+
+#[cfg(sqlxverf)]
+fn sqlverf_load_tombstones() -> impl std::any::Any {
+    sqlx::query!("select * from operation_log_tombstone order by from_id asc")
+}
+
+#[test]
+fn utest_load_tombstones() {
+    assert_eq!(
+        format!("select * from {} order by from_id asc", tablenames::TOMBSTONE),
+        "select * from operation_log_tombstone order by from_id asc"
+    );
+}
+
+pub async fn load_tombstones(conn: &mut ConnectionType) -> anyhow::Result<Tombstones> {
+    let query = format!("select * from {} order by from_id asc", tablenames::TOMBSTONE);
+    let tombstones: Vec<models::OperationLogTombstone> = sqlx::query_as(&query).fetch_all(&mut *conn).await?;
+    Ok(tombstones.iter().map(Tombstone::from).collect())
 }
 
 pub use storage::config::MarketConfigs;
@@ -234,7 +414,48 @@ pub async fn init_config_from_db(conn: &mut ConnectionType, config: &mut config:
 }
 
 pub async fn init_from_db(conn: &mut ConnectionType, controller: &mut Controller) -> anyhow::Result<()> {
+    let operation_log_id = restore_from_db(conn, controller).await?;
+    load_operation_log_from_db(conn, operation_log_id, controller, None).await;
+    Ok(())
+}
+
+// `init_from_db` after a crash, the log is replayed strictly beyond the restored state and the events the sinks
+// have not got by the watermarks are emitted again. the sequencer still has to recover the ids of the history
+pub async fn init_reconciled_from_db(
+    conn: &mut ConnectionType,
+    watermarks: &Watermarks,
+    controller: &mut Controller,
+) -> anyhow::Result<StartupMismatch> {
+    let restored_op_id = restore_from_db(conn, controller).await?;
+    let report = load_operation_log_from_db(conn, restored_op_id, controller, Some(watermarks)).await;
+    let replayed = controller.sequencer.state();
+    let mismatch = StartupMismatch::classify(restored_op_id, &replayed, watermarks);
+    mismatch.log(restored_op_id, &replayed, watermarks);
+    log::info!("replay after the restored operation {}: {:?}", restored_op_id, report);
+    Ok(mismatch)
+}
+
+// the snapshot or the last slice, returns the last operation in it
+async fn restore_from_db(conn: &mut ConnectionType, controller: &mut Controller) -> anyhow::Result<u64> {
+    // before any replay
+    controller.tombstones = load_tombstones(conn).await?;
     let last_slice = get_last_slice(conn).await;
+    let snapshot = if controller.settings.snapshot_path.is_empty() {
+        None
+    } else {
+        Snapshot::read(&controller.settings.snapshot_path)?
+    };
+    // the snapshot file is preferred unless the last slice is newer
+    let slice_log_id = last_slice.as_ref().map(|slice| slice.end_operation_log_id as u64);
+    if let Some(snapshot) = snapshot.filter(|snapshot| slice_log_id.map_or(true, |id| snapshot.sequencer.operation_log_id >= id)) {
+        let operation_log_id = snapshot.sequencer.operation_log_id;
+        log::info!("load snapshot of operation log {}", operation_log_id);
+        if controller.settings.snapshot_history > 0 {
+            controller.snapshots.push(snapshot.clone());
+        }
+        controller.load_snapshot(snapshot)?;
+        return Ok(operation_log_id);
+    }
     let mut end_operation_log_id = 0;
     if let Some(slice) = last_slice {
         log::debug!("last slice {:?}", slice);
@@ -242,10 +463,57 @@ pub async fn init_from_db(conn: &mut ConnectionType, controller: &mut Controller
         end_operation_log_id = slice.end_operation_log_id;
         controller.sequencer.set_order_id(slice.end_order_id as u64);
         controller.sequencer.set_trade_id(slice.end_trade_id as u64);
-        log::info!("set order_id and trade_id to {} {}", slice.end_order_id, slice.end_trade_id);
+        controller.sequencer.set_msg_id(slice.end_msg_id as u64);
+        log::info!(
+            "set order_id, trade_id and msg_id to {} {} {}",
+            slice.end_order_id,
+            slice.end_trade_id,
+            slice.end_msg_id
+        );
     }
-    load_operation_log_from_db(conn, end_operation_log_id as u64, controller).await;
-    Ok(())
+    controller.refresh_fee_discount_prices();
+    Ok(end_operation_log_id as u64)
+}
+
+#[cfg(sqlxverf)]
+fn sqlverf_load_persisted_ids() -> impl std::any::Any {
+    (
+        sqlx::query!("select coalesce(max(id), 0) from order_history"),
+        sqlx::query!("select coalesce(max(trade_id), 0) from user_trade"),
+        sqlx::query!("select coalesce(max(id), 0) from operation_log"),
+    )
+}
+
+fn max_id_query(table: &str, column: &str) -> String {
+    format!("select coalesce(max({}), 0) from {}", column, table)
+}
+
+#[test]
+fn utest_load_persisted_ids() {
+    assert_eq!(
+        max_id_query(tablenames::USERTRADE, "trade_id"),
+        "select coalesce(max(trade_id), 0) from user_trade"
+    );
+}
+
+// the largest ids in the history db and the operation log. the sequencer is recovered with them after the replay,
+// in case the replay missed some, e.g. the history was written beyond the logs kept
+pub async fn load_persisted_ids(log_conn: &mut ConnectionType, history_conn: &mut ConnectionType) -> anyhow::Result<SequencerState> {
+    let order_id: i64 = sqlx::query_scalar(&max_id_query(tablenames::ORDERHISTORY, "id"))
+        .fetch_one(&mut *history_conn)
+        .await?;
+    let trade_id: i64 = sqlx::query_scalar(&max_id_query(tablenames::USERTRADE, "trade_id"))
+        .fetch_one(&mut *history_conn)
+        .await?;
+    let operation_log_id: i64 = sqlx::query_scalar(&max_id_query(tablenames::OPERATIONLOG, "id"))
+        .fetch_one(&mut *log_conn)
+        .await?;
+    Ok(SequencerState {
+        order_id: order_id as u64,
+        trade_id: trade_id as u64,
+        operation_log_id: operation_log_id as u64,
+        msg_id: 0,
+    })
 }
 
 const DUMPING_SET_LIMIT: usize = 100000;
@@ -301,7 +569,7 @@ pub async fn dump_balance(conn: &mut ConnectionType, slice_id: i64, balance_mana
         BalanceSliceInsert {
             slice_id,
             user_id: k.user_id as i32,
-            asset: k.asset.clone(),
+            asset: k.asset.to_string(),
             t: k.balance_type as i16,
             balance: *v,
         }
@@ -325,8 +593,8 @@ pub async fn dump_orders(conn: &mut ConnectionType, slice_id: i64, controller: &
                 slice_id,
                 order_type: order.type_,
                 order_side: order.side,
-                create_time: FTimestamp(order.create_time).into(),
-                update_time: FTimestamp(order.update_time).into(),
+                create_time: order.create_time.into(),
+                update_time: order.update_time.into(),
                 user_id: order.user as i32,
                 market: order.market.to_string(),
                 price: order.price,
@@ -340,6 +608,8 @@ pub async fn dump_orders(conn: &mut ConnectionType, slice_id: i64, controller: &
                 finished_fee: order.finished_fee,
                 post_only: order.post_only,
                 signature: order.signature.to_vec(),
+                priority: order.priority as i64,
+                client_order_id: order.client_order_id.map(|id| id as i64),
             }
         });
 
@@ -348,6 +618,47 @@ pub async fn dump_orders(conn: &mut ConnectionType, slice_id: i64, controller: &
     Ok(())
 }
 
+pub async fn dump_withdraws(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
+    let records_iter = controller.withdraw_manager.pending.values().map(|withdraw| WithdrawSlice {
+        slice_id,
+        business_id: withdraw.business_id as i64,
+        user_id: withdraw.user_id as i32,
+        asset: withdraw.asset.clone(),
+        business: withdraw.business.clone(),
+        amount: withdraw.amount,
+    });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} pending withdraws done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_locks(conn: &mut ConnectionType, slice_id: i64, balance_manager: &BalanceManager) -> SimpleResult {
+    let records_iter = balance_manager.locks.values().map(|lock| LockSlice {
+        slice_id,
+        lock_id: lock.lock_id as i64,
+        user_id: lock.user_id as i32,
+        asset: lock.asset.clone(),
+        amount: lock.amount,
+    });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} balance locks done", insert_count);
+    Ok(())
+}
+
+pub async fn dump_nonces(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
+    let records_iter = controller.user_nonces.all().into_iter().map(|(user_id, nonce)| NonceSlice {
+        slice_id,
+        user_id: user_id as i32,
+        nonce: nonce as i64,
+    });
+
+    let insert_count = dump_records(records_iter, DUMPING_SET_LIMIT, conn).await?;
+    log::debug!("persist {} user nonces done", insert_count);
+    Ok(())
+}
+
 pub async fn update_slice_history(conn: &mut ConnectionType, slice_id: i64, controller: &Controller) -> SimpleResult {
     let sequencer = &controller.sequencer;
     let slice_history = SliceHistory {
@@ -355,6 +666,7 @@ pub async fn update_slice_history(conn: &mut ConnectionType, slice_id: i64, cont
         end_operation_log_id: sequencer.get_operation_log_id() as i64,
         end_order_id: sequencer.get_order_id() as i64,
         end_trade_id: sequencer.get_trade_id() as i64,
+        end_msg_id: sequencer.get_msg_id() as i64,
     };
 
     slice_history.sql_query(conn).await?;
@@ -365,6 +677,9 @@ pub async fn dump_to_db(conn: &mut ConnectionType, slice_id: i64, controller: &C
     log::info!("persisting orders and balances to db");
     dump_orders(conn, slice_id, controller).await?;
     dump_balance(conn, slice_id, &controller.balance_manager).await?;
+    dump_withdraws(conn, slice_id, controller).await?;
+    dump_locks(conn, slice_id, &controller.balance_manager).await?;
+    dump_nonces(conn, slice_id, controller).await?;
     update_slice_history(conn, slice_id, controller).await?;
     Ok(())
 }
@@ -394,6 +709,18 @@ pub async fn delete_slice(conn: &mut ConnectionType, slice_id: i64) -> SimpleRes
         .bind(slice_id)
         .execute(&mut *conn)
         .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::WITHDRAWSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::LOCKSLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("delete from {} where slice_id = $1", tablenames::NONCESLICE))
+        .bind(slice_id)
+        .execute(&mut *conn)
+        .await?;
     sqlx::query(&format!("delete from {} where time = $1", tablenames::SLICEHISTORY))
         .bind(slice_id)
         .execute(&mut *conn)
@@ -446,6 +773,13 @@ pub async fn make_slice(controller: &Controller) -> SimpleResult {
     let mut conn = ConnectionType::connect(url).await?;
     let slice_id = current_timestamp() as i64;
     let timing = Instant::now();
+    let snapshot_path = &controller.settings.snapshot_path;
+    if !snapshot_path.is_empty() {
+        // the slice in the db is still made if the file can not be written
+        if let Err(e) = controller.make_snapshot().write(snapshot_path) {
+            log::error!("write snapshot to {} failed: {}", snapshot_path, e);
+        }
+    }
     dump_to_db(&mut conn, slice_id, controller).await?;
     clear_slice(&mut conn, slice_id).await?;
     log::info!(
@@ -0,0 +1,448 @@
+use crate::asset::{BalanceManager, BalanceType};
+use crate::config;
+use crate::controller::Controller;
+use crate::market::{DepthUpdate, Market, MarketDepth, MarketEvent, MarketEventKind, MarketState, Order, OrderType, Trade};
+use crate::message::{BalanceMessage, Envelope, OrderMessage};
+use crate::persist::{BroadcastSubscriber, Snapshot, SNAPSHOT_VERSION};
+
+use anyhow::{anyhow, bail, Result};
+use fluidex_common::rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplicaApply {
+    Applied,
+    // seen already, e.g. sent again after a reconnect
+    Duplicate,
+    // the events are dropped until the replica is resynced from a snapshot
+    ResyncNeeded,
+}
+
+// the books and the balances of the engine, rebuilt from its events in envelopes so the reads are
+// served without the matching thread. the events must come with every seq, e.g. from the replica feed
+// of the controller: the kafka topics do not carry the depth updates and the klines, their seqs look like gaps.
+// the balances are replicated as the totals of the balance messages, with the frozen amounts of the resting
+// orders moved out of AVAILABLE. a lock is not in the events, so LOCK and WITHDRAWING are the ones of the last resync
+pub struct ReplicaState {
+    markets: HashMap<String, Market>,
+    // AVAILABLE + FREEZE of (user, asset)
+    totals: HashMap<(u32, String), Decimal>,
+    // the frozen amounts of the resting orders of (user, asset)
+    frozen: HashMap<(u32, String), Decimal>,
+    held: HashMap<(u32, BalanceType, String), Decimal>,
+    last_seq: u64,
+    needs_resync: bool,
+}
+
+impl ReplicaState {
+    // the markets of the settings, empty until resynced
+    pub fn new(settings: &config::Settings) -> Result<Self> {
+        let balance_manager = BalanceManager::new(&settings.assets)?;
+        let mut markets = HashMap::new();
+        for entry in &settings.markets {
+            markets.insert(entry.name.clone(), Market::new(entry, settings, &balance_manager)?);
+        }
+        Ok(Self {
+            markets,
+            totals: HashMap::new(),
+            frozen: HashMap::new(),
+            held: HashMap::new(),
+            last_seq: 0,
+            needs_resync: true,
+        })
+    }
+
+    pub fn needs_resync(&self) -> bool {
+        self.needs_resync
+    }
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    // the state of the snapshot, the events up to its `msg_id` are duplicates then
+    pub fn resync(&mut self, snapshot: &Snapshot) -> Result<()> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            bail!("snapshot version {} is not supported", snapshot.version);
+        }
+        if let Some(unknown) = snapshot.markets.iter().find(|market| !self.markets.contains_key(&market.name)) {
+            bail!("market {} of the snapshot is not found", unknown.name);
+        }
+        for name in &snapshot.closed_markets {
+            self.markets.remove(name);
+        }
+        for market in self.markets.values_mut() {
+            market.reset();
+        }
+        self.totals.clear();
+        self.frozen.clear();
+        self.held.clear();
+        for (key, amount) in &snapshot.balances {
+            match key.balance_type {
+                BalanceType::AVAILABLE | BalanceType::FREEZE => {
+                    *self.totals.entry((key.user_id, key.asset.to_string())).or_default() += amount;
+                }
+                balance_type => {
+                    self.held.insert((key.user_id, balance_type, key.asset.to_string()), *amount);
+                }
+            }
+        }
+        for snapshot in &snapshot.markets {
+            let market = self.markets.get_mut(&snapshot.name).unwrap();
+            market.state = snapshot.state;
+            market.price = snapshot.price;
+            market.trade_count = snapshot.trade_count;
+            // the frozen amounts are counted as the orders are put
+            for order in &snapshot.orders {
+                self.put_order(*order);
+            }
+        }
+        self.last_seq = snapshot.sequencer.msg_id;
+        self.needs_resync = false;
+        log::info!("replica resynced at seq {}", self.last_seq);
+        Ok(())
+    }
+
+    // an envelope as sent to kafka or by the replica feed
+    pub fn apply_json(&mut self, json: &str) -> Result<ReplicaApply> {
+        let envelope: Envelope<serde_json::Value> = serde_json::from_str(json)?;
+        Ok(self.apply(envelope))
+    }
+
+    pub fn apply(&mut self, envelope: Envelope<serde_json::Value>) -> ReplicaApply {
+        if self.needs_resync {
+            return ReplicaApply::ResyncNeeded;
+        }
+        if envelope.seq <= self.last_seq {
+            return ReplicaApply::Duplicate;
+        }
+        if envelope.seq != self.last_seq + 1 {
+            log::warn!("replica expects seq {} but got {}, resync needed", self.last_seq + 1, envelope.seq);
+            self.needs_resync = true;
+            return ReplicaApply::ResyncNeeded;
+        }
+        self.last_seq = envelope.seq;
+        if let Err(e) = self.apply_payload(&envelope.kind, envelope.payload) {
+            log::warn!(
+                "replica failed to apply {} of seq {}, resync needed: {}",
+                envelope.kind,
+                envelope.seq,
+                e
+            );
+            self.needs_resync = true;
+            return ReplicaApply::ResyncNeeded;
+        }
+        ReplicaApply::Applied
+    }
+
+    fn apply_payload(&mut self, kind: &str, payload: serde_json::Value) -> Result<()> {
+        match kind {
+            "OrderMessage" => {
+                let msg: OrderMessage = serde_json::from_value(payload)?;
+                if msg.event.is_terminal() {
+                    self.remove_order(&msg.order.market, msg.order.id);
+                } else {
+                    self.put_order(msg.order);
+                }
+            }
+            "TradeMessage" => self.apply_trade(&serde_json::from_value(payload)?),
+            "BalanceMessage" => {
+                let msg: BalanceMessage = serde_json::from_value(payload)?;
+                let total = Decimal::from_str(&msg.balance_available)? + Decimal::from_str(&msg.balance_frozen)?;
+                let key = (msg.user_id, msg.asset);
+                if total.is_zero() {
+                    self.totals.remove(&key);
+                } else {
+                    self.totals.insert(key, total);
+                }
+            }
+            "DepthUpdateMessage" => {
+                let update: DepthUpdate = serde_json::from_value(payload)?;
+                if let Some(market) = self.markets.get_mut(&update.market) {
+                    market.depth_seq = update.seq;
+                }
+            }
+            "MarketEventMessage" => {
+                let event: MarketEvent = serde_json::from_value(payload)?;
+                if let Some(market) = self.markets.get_mut(&event.market) {
+                    match event.kind {
+                        MarketEventKind::PriceUpdated { price, .. } => market.price = price,
+                        MarketEventKind::Halted { state, .. } => market.state = state,
+                        MarketEventKind::Resumed { .. } => market.state = MarketState::Open,
+                        MarketEventKind::Created { .. } | MarketEventKind::InvariantViolated { .. } => {}
+                    }
+                }
+            }
+            // the state is rewound without the events of the superseded operations
+            "TombstoneMessage" => bail!("the engine rolled back"),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // the balances are settled by the balance messages before the trade, only the orders are updated here
+    fn apply_trade(&mut self, trade: &Trade) {
+        let market = match self.markets.get_mut(&trade.market) {
+            Some(market) => market,
+            None => return,
+        };
+        market.price = trade.price;
+        market.trade_count += 1;
+        let orders = [(trade.ask_order_id, trade.ask_fee), (trade.bid_order_id, trade.bid_fee)]
+            .iter()
+            .filter_map(|(order_id, fee)| market.orders.get(order_id).map(|order| (order.deep(), *fee)))
+            .collect::<Vec<_>>();
+        // a taker is put before its trades, and finished or left resting after them
+        for (mut order, fee) in orders {
+            order.remain -= trade.amount;
+            order.finished_base += trade.amount;
+            order.finished_quote += trade.quote_amount;
+            order.finished_fee += fee;
+            self.put_order(order);
+        }
+    }
+
+    fn add_frozen(&mut self, order: &Order, amount: Decimal) {
+        let asset = if order.is_ask() { order.base } else { order.quote };
+        let key = (order.user, asset.to_string());
+        let frozen = self.frozen.entry(key.clone()).or_default();
+        *frozen += amount;
+        if frozen.is_zero() {
+            self.frozen.remove(&key);
+        }
+    }
+
+    // the limit orders rest in the book, frozen as the market freezes them
+    fn put_order(&mut self, mut order: Order) {
+        let market = match self.markets.get_mut(&*order.market) {
+            Some(market) => market,
+            None => return,
+        };
+        let before = market.orders.get(&order.id).map(|order| order.deep());
+        if let Some(before) = &before {
+            market.remove_order_from_orderbook(before);
+        }
+        let after = if order.type_ == OrderType::LIMIT {
+            order.market = market.name.into();
+            order.base = market.base.into();
+            order.quote = market.quote.into();
+            Some(market.insert_order_into_orderbook(order))
+        } else {
+            None
+        };
+        if let Some(before) = before {
+            self.add_frozen(&before, -before.frozen);
+        }
+        if let Some(after) = after {
+            self.add_frozen(&after, after.frozen);
+        }
+    }
+
+    fn remove_order(&mut self, market: &str, order_id: u64) {
+        let market = match self.markets.get_mut(market) {
+            Some(market) => market,
+            None => return,
+        };
+        if let Some(order) = market.orders.get(&order_id).map(|order| order.deep()) {
+            market.remove_order_from_orderbook(&order);
+            self.add_frozen(&order, -order.frozen);
+        }
+    }
+
+    pub fn market(&self, name: &str) -> Option<&Market> {
+        self.markets.get(name)
+    }
+    pub fn depth(&self, market: &str, limit: usize, interval: &Decimal) -> Result<MarketDepth> {
+        let market = self.markets.get(market).ok_or_else(|| anyhow!("invalid market"))?;
+        Ok(market.depth(limit, interval)?)
+    }
+    pub fn get_order_of_user(&self, market: &str, user_id: u32) -> Vec<Order> {
+        self.markets
+            .get(market)
+            .map(|market| market.get_order_of_user(user_id))
+            .unwrap_or_default()
+    }
+    pub fn get(&self, user_id: u32, balance_type: BalanceType, asset: &str) -> Decimal {
+        let key = (user_id, asset.to_string());
+        let frozen = self.frozen.get(&key).copied().unwrap_or_default();
+        match balance_type {
+            BalanceType::AVAILABLE => self.totals.get(&key).copied().unwrap_or_default() - frozen,
+            BalanceType::FREEZE => frozen,
+            balance_type => self.held.get(&(user_id, balance_type, key.1)).copied().unwrap_or_default(),
+        }
+    }
+}
+
+// applies the events of the feed, and resyncs the replica from a snapshot of the engine on a gap,
+// e.g. after the subscriber lagged. returns when the feed is closed
+pub async fn follow(replica: Arc<RwLock<ReplicaState>>, mut subscriber: BroadcastSubscriber, stub: Arc<RwLock<Controller>>) {
+    while let Ok(event) = subscriber.recv().await {
+        let mut replica = replica.write().await;
+        if let Err(e) = replica.apply_json(&event.json) {
+            log::error!("replica event dropped: {}", e);
+        }
+        if replica.needs_resync() {
+            // the events before the snapshot are duplicates then
+            let snapshot = stub.read().await.make_snapshot();
+            if let Err(e) = replica.resync(&snapshot) {
+                log::error!("resync replica failed: {}", e);
+            }
+        }
+    }
+    log::info!("replica feed closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::{BalanceUpdateController, BalanceUpdateParams, BusinessType, WithdrawManager};
+    use crate::config::Settings;
+    use crate::fee::FeeManager;
+    use crate::market::OrderSide;
+    use crate::matchengine::mock::*;
+    use crate::persist::{BroadcastFilter, BroadcastPersistor, CompositePersistor};
+    use crate::sequencer::Sequencer;
+    use crate::user_manager::UserManager;
+    use fluidex_common::rust_decimal_macros::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_replica_follows_engine() {
+        let settings = Settings {
+            assets: get_simple_asset_config(8),
+            markets: vec![get_simple_market_config()],
+            ..Settings::default()
+        };
+        let mut sequencer = Sequencer::default();
+        let mut balance_manager = BalanceManager::new(&settings.assets).unwrap();
+        let mut update_controller = BalanceUpdateController::new();
+        let mut market = Market::new(&settings.markets[0], &settings, &balance_manager).unwrap();
+        let feed = BroadcastPersistor::new(1024).with_envelope(sequencer.msg_seq());
+        let mut subscriber = feed.subscribe(BroadcastFilter::all());
+        let mut persistor = CompositePersistor::default();
+        persistor.set_msg_seq(sequencer.msg_seq());
+        persistor.add_persistor(Box::new(feed));
+
+        let mut replica = ReplicaState::new(&settings).unwrap();
+        assert!(replica.needs_resync());
+        let snapshot = Snapshot::take(
+            0.0,
+            &sequencer,
+            &balance_manager,
+            &WithdrawManager::new(),
+            &market.user_nonces,
+            &UserManager::new(),
+            &FeeManager::default(),
+            [&market],
+            &[],
+        );
+        replica.resync(&snapshot).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(4346);
+        for n in 0..600u64 {
+            let user_id = rng.gen_range(1..5);
+            let open_orders = market.orders.values().map(|order| order.deep()).collect::<Vec<_>>();
+            match rng.gen_range(0..8) {
+                // a deposit is two events, the first is lost halfway
+                choice if choice == 0 || n == 300 => {
+                    let asset = if rng.gen::<bool>() { market.base } else { market.quote };
+                    update_controller
+                        .update_user_balance(
+                            &mut balance_manager,
+                            &mut persistor,
+                            BalanceUpdateParams {
+                                balance_type: BalanceType::AVAILABLE,
+                                business_type: BusinessType::Deposit,
+                                user_id,
+                                business_id: n,
+                                asset: asset.to_string(),
+                                business: "deposit".to_string(),
+                                market_price: dec!(0),
+                                change: Decimal::from(rng.gen_range(100..10_000)),
+                                detail: serde_json::json!({}),
+                                signature: vec![],
+                            },
+                        )
+                        .unwrap();
+                }
+                1 if !open_orders.is_empty() => {
+                    let order = &open_orders[rng.gen_range(0..open_orders.len())];
+                    market
+                        .cancel((&mut balance_manager).into(), &mut persistor, order.id, order.user)
+                        .unwrap();
+                }
+                _ => {
+                    let order_input = OrderInputBuilder::new(
+                        market.name,
+                        user_id,
+                        if rng.gen::<bool>() { OrderSide::BID } else { OrderSide::ASK },
+                        Decimal::new(rng.gen_range(1..2000), 2),
+                        Decimal::new(rng.gen_range(12_000..14_000), 2),
+                    )
+                    .fees(dec!(0.002), dec!(0.001))
+                    .build();
+                    // rejected when the balance is not enough
+                    let _ = market.put_order(
+                        &mut sequencer,
+                        (&mut balance_manager).into(),
+                        &mut update_controller,
+                        &FeeManager::default(),
+                        &mut persistor,
+                        order_input,
+                    );
+                }
+            }
+            let mut events = std::iter::from_fn(|| subscriber.try_recv()).collect::<Vec<_>>();
+            if n == 300 {
+                events.remove(0);
+            }
+            for event in events {
+                replica.apply_json(&event.json).unwrap();
+            }
+            if n == 300 {
+                assert!(replica.needs_resync());
+                let snapshot = Snapshot::take(
+                    0.0,
+                    &sequencer,
+                    &balance_manager,
+                    &WithdrawManager::new(),
+                    &market.user_nonces,
+                    &UserManager::new(),
+                    &FeeManager::default(),
+                    [&market],
+                    &[],
+                );
+                replica.resync(&snapshot).unwrap();
+            }
+            assert!(!replica.needs_resync());
+        }
+        assert!(market.trade_count > 0);
+        assert_eq!(replica.last_seq(), sequencer.msg_seq().current());
+
+        let depth = market.depth(100, &dec!(0)).unwrap();
+        let replica_depth = replica.depth(&market.name, 100, &dec!(0)).unwrap();
+        assert!(!depth.asks.is_empty() && !depth.bids.is_empty());
+        assert_eq!(replica_depth.asks, depth.asks);
+        assert_eq!(replica_depth.bids, depth.bids);
+        assert_eq!(replica_depth.seq, depth.seq);
+        assert_eq!(replica_depth.checksum, depth.checksum);
+        for (key, amount) in &balance_manager.balances {
+            assert_eq!(&replica.get(key.user_id, key.balance_type, &key.asset), amount, "{:?}", key);
+        }
+        for user_id in 1..5 {
+            let fields = |orders: Vec<Order>| {
+                orders
+                    .into_iter()
+                    .map(|order| (order.id, order.price, order.remain, order.frozen))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                fields(replica.get_order_of_user(&market.name, user_id)),
+                fields(market.get_order_of_user(user_id))
+            );
+        }
+    }
+}
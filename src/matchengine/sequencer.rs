@@ -1,12 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// the id of the messages, shared by the sequencer and the persistors enveloping the messages
+#[derive(Debug, Clone, Default)]
+pub struct MsgSeq(Arc<AtomicU64>);
+
+impl MsgSeq {
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    pub fn current(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+    pub fn set(&self, id: u64) {
+        self.0.store(id, Ordering::SeqCst)
+    }
+}
+
+// the last issued ids, saved in the slices and recovered on start
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SequencerState {
+    pub order_id: u64,
+    pub trade_id: u64,
+    pub operation_log_id: u64,
+    pub msg_id: u64,
+}
+
 #[derive(Default)]
 pub struct Sequencer {
     order_id: u64,
     trade_id: u64,
-    msg_id: u64,
+    msg_id: MsgSeq,
     operation_log_id: u64,
 }
 
 impl Sequencer {
+    pub fn from_state(state: &SequencerState) -> Self {
+        let mut sequencer = Self::default();
+        sequencer.restore(state);
+        sequencer
+    }
+    pub fn state(&self) -> SequencerState {
+        SequencerState {
+            order_id: self.order_id,
+            trade_id: self.trade_id,
+            operation_log_id: self.operation_log_id,
+            msg_id: self.get_msg_id(),
+        }
+    }
+    pub fn restore(&mut self, state: &SequencerState) {
+        self.set_order_id(state.order_id);
+        self.set_trade_id(state.trade_id);
+        self.set_operation_log_id(state.operation_log_id);
+        self.set_msg_id(state.msg_id);
+    }
+    // `persisted` are the largest ids found in the history, e.g. written before a crash which the replay missed.
+    // the ids are only raised, so no issued id is issued again
+    pub fn recover(&mut self, persisted: &SequencerState) {
+        let current = self.state();
+        let recovered = SequencerState {
+            order_id: current.order_id.max(persisted.order_id),
+            trade_id: current.trade_id.max(persisted.trade_id),
+            operation_log_id: current.operation_log_id.max(persisted.operation_log_id),
+            msg_id: current.msg_id.max(persisted.msg_id),
+        };
+        if recovered != current {
+            log::warn!(
+                "sequencer behind the persisted ids, recovered from {:?} to {:?}",
+                current,
+                recovered
+            );
+            self.restore(&recovered);
+        }
+    }
     pub fn reset(&mut self) {
         self.set_operation_log_id(0);
         self.set_order_id(0);
@@ -27,8 +95,10 @@ impl Sequencer {
         self.operation_log_id
     }
     pub fn next_msg_id(&mut self) -> u64 {
-        self.msg_id += 1;
-        self.msg_id
+        self.msg_id.next()
+    }
+    pub fn msg_seq(&self) -> MsgSeq {
+        self.msg_id.clone()
     }
     pub fn get_operation_log_id(&self) -> u64 {
         self.operation_log_id
@@ -40,7 +110,7 @@ impl Sequencer {
         self.order_id
     }
     pub fn get_msg_id(&self) -> u64 {
-        self.msg_id
+        self.msg_id.current()
     }
     pub fn set_operation_log_id(&mut self, id: u64) {
         log::debug!("set operation_log id {}", id);
@@ -56,6 +126,240 @@ impl Sequencer {
     }
     pub fn set_msg_id(&mut self, id: u64) {
         log::debug!("set msg id {}", id);
-        self.msg_id = id;
+        self.msg_id.set(id);
+    }
+}
+
+// what the markets take the order ids and the trade ids from
+pub trait IdAllocator {
+    fn next_order_id(&mut self) -> u64;
+    fn next_trade_id(&mut self) -> u64;
+    // gives back the id of a trade which failed to settle, unless a later one is taken already
+    fn cancel_trade_id(&mut self, id: u64);
+}
+
+impl IdAllocator for Sequencer {
+    fn next_order_id(&mut self) -> u64 {
+        Sequencer::next_order_id(self)
+    }
+    fn next_trade_id(&mut self) -> u64 {
+        Sequencer::next_trade_id(self)
+    }
+    fn cancel_trade_id(&mut self, id: u64) {
+        if self.trade_id == id {
+            self.trade_id -= 1;
+        }
+    }
+}
+
+// a sequencer shared by the market tasks, each of them allocates with a `&AtomicSequencer`
+#[derive(Debug, Default)]
+pub struct AtomicSequencer {
+    order_id: AtomicU64,
+    trade_id: AtomicU64,
+    msg_id: MsgSeq,
+    operation_log_id: AtomicU64,
+}
+
+impl AtomicSequencer {
+    pub fn from_state(state: &SequencerState) -> Self {
+        let sequencer = Self::default();
+        sequencer.restore(state);
+        sequencer
+    }
+    pub fn state(&self) -> SequencerState {
+        SequencerState {
+            order_id: self.get_order_id(),
+            trade_id: self.get_trade_id(),
+            operation_log_id: self.get_operation_log_id(),
+            msg_id: self.get_msg_id(),
+        }
+    }
+    pub fn restore(&self, state: &SequencerState) {
+        self.set_order_id(state.order_id);
+        self.set_trade_id(state.trade_id);
+        self.set_operation_log_id(state.operation_log_id);
+        self.set_msg_id(state.msg_id);
+    }
+    pub fn next_order_id(&self) -> u64 {
+        self.order_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    pub fn next_trade_id(&self) -> u64 {
+        self.trade_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    pub fn next_operation_log_id(&self) -> u64 {
+        self.operation_log_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+    pub fn next_msg_id(&self) -> u64 {
+        self.msg_id.next()
+    }
+    pub fn msg_seq(&self) -> MsgSeq {
+        self.msg_id.clone()
+    }
+    // the ids after the current ones, `count` of them
+    pub fn take_order_ids(&self, count: u64) -> Range<u64> {
+        let last = self.order_id.fetch_add(count, Ordering::SeqCst);
+        last + 1..last + count + 1
+    }
+    pub fn take_trade_ids(&self, count: u64) -> Range<u64> {
+        let last = self.trade_id.fetch_add(count, Ordering::SeqCst);
+        last + 1..last + count + 1
+    }
+    pub fn get_operation_log_id(&self) -> u64 {
+        self.operation_log_id.load(Ordering::SeqCst)
+    }
+    pub fn get_trade_id(&self) -> u64 {
+        self.trade_id.load(Ordering::SeqCst)
+    }
+    pub fn get_order_id(&self) -> u64 {
+        self.order_id.load(Ordering::SeqCst)
+    }
+    pub fn get_msg_id(&self) -> u64 {
+        self.msg_id.current()
+    }
+    pub fn set_operation_log_id(&self, id: u64) {
+        log::debug!("set operation_log id {}", id);
+        self.operation_log_id.store(id, Ordering::SeqCst);
+    }
+    pub fn set_trade_id(&self, id: u64) {
+        log::debug!("set trade id {}", id);
+        self.trade_id.store(id, Ordering::SeqCst);
+    }
+    pub fn set_order_id(&self, id: u64) {
+        log::debug!("set order id {}", id);
+        self.order_id.store(id, Ordering::SeqCst);
+    }
+    pub fn set_msg_id(&self, id: u64) {
+        log::debug!("set msg id {}", id);
+        self.msg_id.set(id);
+    }
+}
+
+impl IdAllocator for &AtomicSequencer {
+    fn next_order_id(&mut self) -> u64 {
+        AtomicSequencer::next_order_id(self)
+    }
+    fn next_trade_id(&mut self) -> u64 {
+        AtomicSequencer::next_trade_id(self)
+    }
+    fn cancel_trade_id(&mut self, id: u64) {
+        let _ = self.trade_id.compare_exchange(id, id - 1, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+// the ids of a market task, leased from the shared sequencer by blocks so the tasks rarely contend.
+// the ids are unique and ordered roughly by time, within the ids of a block
+pub struct ShardedAllocator {
+    sequencer: Arc<AtomicSequencer>,
+    block: u64,
+    order_ids: Range<u64>,
+    trade_ids: Range<u64>,
+}
+
+impl ShardedAllocator {
+    pub fn new(sequencer: Arc<AtomicSequencer>, block: u64) -> Self {
+        Self {
+            sequencer,
+            block: block.max(1),
+            order_ids: 0..0,
+            trade_ids: 0..0,
+        }
+    }
+}
+
+impl IdAllocator for ShardedAllocator {
+    fn next_order_id(&mut self) -> u64 {
+        if self.order_ids.is_empty() {
+            self.order_ids = self.sequencer.take_order_ids(self.block);
+        }
+        self.order_ids.next().unwrap()
+    }
+    fn next_trade_id(&mut self) -> u64 {
+        if self.trade_ids.is_empty() {
+            self.trade_ids = self.sequencer.take_trade_ids(self.block);
+        }
+        self.trade_ids.next().unwrap()
+    }
+    fn cancel_trade_id(&mut self, id: u64) {
+        if self.trade_ids.start == id + 1 {
+            self.trade_ids.start = id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequencer_restart() {
+        let mut sequencer = Sequencer::default();
+        let msg_seq = sequencer.msg_seq();
+        let issued_orders = (0..5).map(|_| sequencer.next_order_id()).collect::<Vec<_>>();
+        let issued_trades = (0..3).map(|_| sequencer.next_trade_id()).collect::<Vec<_>>();
+        sequencer.next_operation_log_id();
+        msg_seq.next();
+
+        // restarted from the saved state
+        let saved = serde_json::to_string(&sequencer.state()).unwrap();
+        let mut restarted = Sequencer::from_state(&serde_json::from_str(&saved).unwrap());
+        assert_eq!(restarted.state(), sequencer.state());
+        assert!(!issued_orders.contains(&restarted.next_order_id()));
+        assert!(!issued_trades.contains(&restarted.next_trade_id()));
+        assert_eq!((restarted.next_operation_log_id(), restarted.next_msg_id()), (2, 2));
+
+        // restarted from an older slice, the history has more orders but fewer trades
+        let mut restarted = Sequencer::from_state(&SequencerState {
+            order_id: 2,
+            trade_id: 3,
+            ..Default::default()
+        });
+        restarted.recover(&SequencerState {
+            order_id: 5,
+            trade_id: 1,
+            ..Default::default()
+        });
+        assert_eq!((restarted.next_order_id(), restarted.next_trade_id()), (6, 4));
+        restarted.recover(&SequencerState::default());
+        assert_eq!(restarted.get_order_id(), 6);
+    }
+
+    #[test]
+    fn test_concurrent_order_ids() {
+        let sequencer = Arc::new(AtomicSequencer::default());
+        let allocate = |mut allocator: Box<dyn IdAllocator + Send>| {
+            std::thread::spawn(move || (0..10000).map(|_| allocator.next_order_id()).collect::<Vec<_>>())
+        };
+        let threads = (0..8)
+            .map(|n| {
+                let sequencer = sequencer.clone();
+                if n % 2 == 0 {
+                    allocate(Box::new(ShardedAllocator::new(sequencer, 64)))
+                } else {
+                    std::thread::spawn(move || (0..10000).map(|_| sequencer.next_order_id()).collect::<Vec<_>>())
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut ids = std::collections::HashSet::new();
+        for thread in threads {
+            let issued = thread.join().unwrap();
+            // the ids of a task keep increasing
+            assert!(issued.windows(2).all(|pair| pair[0] < pair[1]));
+            for id in issued {
+                assert!(ids.insert(id), "order id {} issued twice", id);
+            }
+        }
+        assert_eq!(ids.len(), 80000);
+        assert!(sequencer.get_order_id() >= 80000);
+
+        // a failed trade gives its id back
+        let mut allocator = &*sequencer;
+        let id = allocator.next_trade_id();
+        allocator.cancel_trade_id(id);
+        assert_eq!(allocator.next_trade_id(), id);
+        let mut sharded = ShardedAllocator::new(sequencer.clone(), 4);
+        let id = sharded.next_trade_id();
+        sharded.cancel_trade_id(id);
+        assert_eq!(sharded.next_trade_id(), id);
     }
 }
@@ -1,5 +1,8 @@
-use crate::config::{OrderSignatrueCheck, Settings};
-use crate::controller::Controller;
+use crate::admin;
+use crate::config::Settings;
+use crate::controller::{self, Controller};
+use crate::health;
+use crate::replica::{self, ReplicaState};
 
 use std::fmt::Debug;
 use std::pin::Pin;
@@ -11,20 +14,22 @@ use tonic::{self, Request, Response, Status};
 
 const MAX_BATCH_ORDER_NUM: usize = 40;
 
-type StubType = Arc<RwLock<Controller>>;
-type ControllerAction = Box<dyn FnOnce(StubType) -> Pin<Box<dyn futures::Future<Output = ()> + Send>> + Send>;
+pub(crate) type StubType = Arc<RwLock<Controller>>;
+pub(crate) type ControllerAction = Box<dyn FnOnce(StubType) -> Pin<Box<dyn futures::Future<Output = ()> + Send>> + Send>;
 
 pub struct GrpcHandler {
     stub: StubType,
+    // serves the depth while it is in step with the engine
+    replica: Option<Arc<RwLock<ReplicaState>>>,
     settings: Settings,
     task_dispatcher: mpsc::Sender<ControllerAction>,
     set_close: Option<oneshot::Sender<()>>,
 }
 
-struct ControllerDispatch<OT>(ControllerAction, oneshot::Receiver<OT>);
+pub(crate) struct ControllerDispatch<OT>(pub(crate) ControllerAction, pub(crate) oneshot::Receiver<OT>);
 
 impl<OT: 'static + Debug + Send> ControllerDispatch<OT> {
-    fn new<T>(f: T) -> Self
+    pub(crate) fn new<T>(f: T) -> Self
     where
         T: for<'c> FnOnce(&'c mut Controller) -> Pin<Box<dyn futures::Future<Output = OT> + Send + 'c>>,
         T: Send + 'static,
@@ -36,7 +41,13 @@ impl<OT: 'static + Debug + Send> ControllerDispatch<OT> {
                 move |ctrl: StubType| -> Pin<Box<dyn futures::Future<Output = ()> + Send + 'static>> {
                     Box::pin(async move {
                         let mut wg = ctrl.write().await;
-                        if let Err(t) = tx.send(f(&mut wg).await) {
+                        // the operation runs at the time it is logged with, as it is replayed
+                        let fixed = wg.clock.fix_now();
+                        let ret = f(&mut wg).await;
+                        if fixed {
+                            wg.clock.release();
+                        }
+                        if let Err(t) = tx.send(ret) {
                             log::error!("Controller action can not be return: {:?}", t);
                         }
                     })
@@ -61,6 +72,29 @@ fn map_dispatch_ret<OT: 'static>(recv_ret: Result<ControllerRet<OT>, oneshot::er
     }
 }
 
+// the messages are defined by orchestra, so the signature of a request whose message has no field for it,
+// e.g. a cancel, is given as the hex `signature` of the metadata
+fn metadata_signature<T>(request: &Request<T>) -> Result<Option<String>, Status> {
+    request
+        .metadata()
+        .get("signature")
+        .map(|signature| signature.to_str().map(str::to_owned))
+        .transpose()
+        .map_err(|_| Status::invalid_argument("invalid signature"))
+}
+
+// the `nonce` of the metadata, which is signed along with an order or a cancel. zero if not given
+fn metadata_nonce<T>(request: &Request<T>) -> Result<u64, Status> {
+    match request.metadata().get("nonce") {
+        Some(nonce) => nonce
+            .to_str()
+            .ok()
+            .and_then(|nonce| nonce.parse().ok())
+            .ok_or_else(|| Status::invalid_argument("invalid nonce")),
+        None => Ok(0),
+    }
+}
+
 pub struct ServerLeave(mpsc::Sender<ControllerAction>, oneshot::Sender<()>);
 
 impl ServerLeave {
@@ -73,11 +107,56 @@ impl ServerLeave {
 impl GrpcHandler {
     pub fn new(stub: Controller, settings: Settings) -> Self {
         let mut persist_interval = tokio::time::interval(std::time::Duration::from_secs(stub.settings.persist_interval as u64));
-
+        let mut batch_interval = tokio::time::interval(crate::persist::DBBatchConfig::default().window);
+
+        // with the markets of the engine, resynced on the first event if this fails
+        let replica_feed = stub
+            .subscribe_replica()
+            .and_then(|(subscriber, snapshot)| match ReplicaState::new(&stub.settings) {
+                Ok(mut state) => {
+                    if let Err(e) = state.resync(&snapshot) {
+                        log::error!("resync replica failed: {}", e);
+                    }
+                    Some((Arc::new(RwLock::new(state)), subscriber))
+                }
+                Err(e) => {
+                    log::error!("create replica failed: {}", e);
+                    None
+                }
+            });
+        let health_addr = stub.settings.health_listen.parse::<std::net::SocketAddr>();
         let stub = Arc::new(RwLock::new(stub));
+        match health_addr {
+            Ok(addr) => {
+                let stub = stub.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = health::serve(addr, stub).await {
+                        log::error!("health server failed: {}", e);
+                    }
+                });
+            }
+            Err(e) if !settings.health_listen.is_empty() => log::error!("invalid health_listen {}: {}", settings.health_listen, e),
+            Err(_) => {}
+        }
+        let replica = replica_feed.map(|(state, subscriber)| {
+            tokio::spawn(replica::follow(state.clone(), subscriber, stub.clone()));
+            state
+        });
         //we always wait so the size of channel is no matter
         let (tx, mut rx) = mpsc::channel(16);
         let (tx_close, mut rx_close) = oneshot::channel();
+        match settings.admin_listen.parse::<std::net::SocketAddr>() {
+            Ok(addr) => {
+                let (stub, dispatcher) = (stub.clone(), tx.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = admin::serve(addr, stub, dispatcher).await {
+                        log::error!("admin server failed: {}", e);
+                    }
+                });
+            }
+            Err(e) if !settings.admin_listen.is_empty() => log::error!("invalid admin_listen {}: {}", settings.admin_listen, e),
+            Err(_) => {}
+        }
 
         let stub_for_dispatch = stub.clone();
 
@@ -86,6 +165,7 @@ impl GrpcHandler {
             set_close: Some(tx_close),
             settings,
             stub,
+            replica,
         };
 
         tokio::spawn(async move {
@@ -96,11 +176,19 @@ impl GrpcHandler {
                         let task = may_task.expect("Server scheduler has unexpected exit");
                         task(stub_for_dispatch.clone()).await;
                     }
+                    _ = batch_interval.tick() => {
+                        stub_for_dispatch.write().await.flush_elapsed_batches();
+                    }
                     _ = persist_interval.tick() => {
-                        let stub_rd = stub_for_dispatch.read().await;
+                        let mut stub_wr = stub_for_dispatch.write().await;
+                        // the messages of the commands before the slice should not be lost
+                        if let Err(e) = stub_wr.flush_persistors() {
+                            log::error!("flush persistors before persisting failed: {}", e);
+                        }
+                        stub_wr.keep_snapshot();
                         log::info!("Start a persisting task");
                         unsafe {
-                            crate::persist::fork_and_make_slice(&*stub_rd);
+                            crate::persist::fork_and_make_slice(&*stub_wr);
                         }
                     }
                     _ = &mut rx_close => {
@@ -115,6 +203,11 @@ impl GrpcHandler {
             while let Some(task) = rx.recv().await {
                 task(stub_for_dispatch.clone()).await;
             }
+            let mut stub_wr = stub_for_dispatch.write().await;
+            if let Err(e) = stub_wr.flush_persistors() {
+                log::error!("flush persistors on shutdown failed: {}", e);
+            }
+            stub_wr.shutdown_persistors();
 
             log::warn!("Server scheduler has exited");
         });
@@ -129,30 +222,12 @@ impl GrpcHandler {
         )
     }
 
-    async fn check_order_signature(&self, req: &OrderPutRequest) -> Result<(), Status> {
-        if self.settings.check_eddsa_signatue == OrderSignatrueCheck::Needed
-            || self.settings.check_eddsa_signatue == OrderSignatrueCheck::Auto && !req.signature.is_empty()
-        {
-            // check order signature here
+    async fn check_order_signature(&self, req: &OrderPutRequest, nonce: u64) -> Result<(), Status> {
+        if self.settings.check_eddsa_signatue.verifies(&req.signature) {
             // order signature checking is not 'write' op, so it need not to be moved into the main thread
-            // it is better to finish it here
-            // TODO: refactor
-            let stub = self.stub.read().await;
-            if !stub.markets.contains_key(&req.market) {
-                return Err(Status::invalid_argument("invalid market"));
-            }
-            let market = stub.markets.get(&req.market).unwrap();
-            let order = stub
-                .balance_manager
-                .asset_manager
-                .commit_order(req, market)
-                .map_err(|_| Status::invalid_argument("invalid order params"))?;
-            let msg = order.hash();
-            if !stub.user_manager.verify_signature(req.user_id, msg, &req.signature) {
-                return Err(Status::invalid_argument("invalid signature"));
-            }
+            // it is better to finish it here, nothing of a refused order is logged or emitted then
+            self.stub.read().await.check_order_signature(req, nonce)?;
         }
-
         Ok(())
     }
 }
@@ -177,8 +252,15 @@ impl matchengine_server::Matchengine for GrpcHandler {
         &self,
         request: tonic::Request<OrderBookDepthRequest>,
     ) -> Result<tonic::Response<OrderBookDepthResponse>, tonic::Status> {
+        if let Some(replica) = &self.replica {
+            let replica = replica.read().await;
+            // an alias is resolved by the engine
+            if let Some(market) = replica.market(&request.get_ref().market).filter(|_| !replica.needs_resync()) {
+                return controller::order_book_depth_of(market, request.get_ref());
+            }
+        }
         let stub = self.stub.read().await;
-        Ok(Response::new(stub.order_book_depth(request.into_inner())?))
+        stub.order_book_depth(request.into_inner())
     }
     async fn order_detail(&self, request: tonic::Request<OrderDetailRequest>) -> Result<tonic::Response<OrderInfo>, tonic::Status> {
         let stub = self.stub.read().await;
@@ -214,11 +296,13 @@ impl matchengine_server::Matchengine for GrpcHandler {
     }
 
     async fn order_put(&self, request: Request<OrderPutRequest>) -> Result<Response<OrderInfo>, Status> {
+        let nonce = metadata_nonce(&request)?;
         let req = request.into_inner();
-        self.check_order_signature(&req).await?;
+        self.check_order_signature(&req, nonce).await?;
 
+        let req = controller::SignedOrderPutRequest { order: req, nonce };
         let ControllerDispatch(act, rt) =
-            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.order_put(true, req) }));
+            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.signed_order_put(true, req) }));
 
         self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
         map_dispatch_ret(rt.await)
@@ -233,7 +317,7 @@ impl matchengine_server::Matchengine for GrpcHandler {
             )));
         }
         for order_req in &req.orders {
-            self.check_order_signature(order_req).await?;
+            self.check_order_signature(order_req, 0).await?;
         }
 
         let ControllerDispatch(act, rt) =
@@ -244,8 +328,26 @@ impl matchengine_server::Matchengine for GrpcHandler {
     }
 
     async fn order_cancel(&self, request: tonic::Request<OrderCancelRequest>) -> Result<tonic::Response<OrderInfo>, tonic::Status> {
-        let ControllerDispatch(act, rt) =
-            ControllerDispatch::new(move |ctrl: &mut Controller| Box::pin(async move { ctrl.order_cancel(true, request.into_inner()) }));
+        let signature = metadata_signature(&request)?;
+        let nonce = metadata_nonce(&request)?;
+        let req = request.into_inner();
+        let ControllerDispatch(act, rt) = ControllerDispatch::new(move |ctrl: &mut Controller| {
+            Box::pin(async move {
+                match signature {
+                    Some(signature) => ctrl.signed_order_cancel(
+                        true,
+                        controller::SignedOrderCancelRequest {
+                            user_id: req.user_id,
+                            market: req.market,
+                            order_id: req.order_id,
+                            nonce,
+                            signature,
+                        },
+                    ),
+                    None => ctrl.order_cancel(true, req),
+                }
+            })
+        });
 
         self.task_dispatcher.send(act).await.map_err(map_dispatch_err)?;
         map_dispatch_ret(rt.await)
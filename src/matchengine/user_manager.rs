@@ -68,3 +68,102 @@ impl Default for UserManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{OrderSignatrueCheck, Settings};
+    use crate::market::Market;
+    use crate::matchengine::mock::*;
+    use orchestra::rpc::exchange::{OrderPutRequest, OrderSide, OrderType};
+
+    #[test]
+    fn test_order_signature() {
+        let balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let market = Market::new(&get_simple_market_config(), &Settings::default(), &balance_manager).unwrap();
+        let key = babyjubjub_rs::new_key();
+        let mut user_manager = UserManager::new();
+        // two users with the same key
+        for user_id in [1, 2] {
+            user_manager.users.insert(
+                user_id,
+                UserInfo {
+                    l1_address: String::new(),
+                    l2_pubkey: hex::encode(key.public().compress()),
+                },
+            );
+        }
+        let req = |user_id, amount: &str| OrderPutRequest {
+            user_id,
+            market: market.name.to_string(),
+            order_side: OrderSide::Ask as i32,
+            order_type: OrderType::Limit as i32,
+            amount: amount.to_string(),
+            price: "100".to_string(),
+            ..Default::default()
+        };
+        let hash = |req: &OrderPutRequest| balance_manager.asset_manager.commit_order(req, 0, &market).unwrap().hash();
+        let signature = hex::encode(key.sign(hash(&req(1, "1.5"))).unwrap().compress());
+
+        assert!(user_manager.verify_signature(1, hash(&req(1, "1.5")), &signature));
+        // a tampered order, the same order of another user and an unknown user
+        assert!(!user_manager.verify_signature(1, hash(&req(1, "2.5")), &signature));
+        assert!(!user_manager.verify_signature(2, hash(&req(2, "1.5")), &signature));
+        assert!(!user_manager.verify_signature(3, hash(&req(3, "1.5")), &signature));
+        assert!(!user_manager.verify_signature(1, hash(&req(1, "1.5")), "00"));
+        // signed with another nonce
+        let commitment = balance_manager.asset_manager.commit_order(&req(1, "1.5"), 1, &market).unwrap();
+        assert!(!user_manager.verify_signature(1, commitment.hash(), &signature));
+
+        // an empty signature is verified unless the check is auto
+        assert!(!OrderSignatrueCheck::Auto.verifies(""));
+        assert!(OrderSignatrueCheck::LogOnly.verifies(""));
+        assert!(!OrderSignatrueCheck::None.verifies(&signature));
+    }
+
+    #[test]
+    fn test_cancel_and_withdraw_signature() {
+        let balance_manager = get_simple_balance_manager(get_simple_asset_config(8));
+        let market = Market::new(&get_simple_market_config(), &Settings::default(), &balance_manager).unwrap();
+        let asset_manager = &balance_manager.asset_manager;
+        let (key, other_key) = (babyjubjub_rs::new_key(), babyjubjub_rs::new_key());
+        let mut user_manager = UserManager::new();
+        for (user_id, key) in [(1, &key), (2, &other_key)] {
+            user_manager.users.insert(
+                user_id,
+                UserInfo {
+                    l1_address: String::new(),
+                    l2_pubkey: hex::encode(key.public().compress()),
+                },
+            );
+        }
+        let sign = |hash| hex::encode(key.sign(hash).unwrap().compress());
+        let sign_other = |hash| hex::encode(other_key.sign(hash).unwrap().compress());
+
+        let cancel = |user_id, order_id, nonce| asset_manager.commit_cancel(user_id, &market, order_id, nonce).unwrap().hash();
+        let signature = sign(cancel(1, 7, 3));
+        assert!(user_manager.verify_signature(1, cancel(1, 7, 3), &signature));
+        // another order, another nonce, and signed by the key of another user
+        assert!(!user_manager.verify_signature(1, cancel(1, 8, 3), &signature));
+        assert!(!user_manager.verify_signature(1, cancel(1, 7, 4), &signature));
+        assert!(!user_manager.verify_signature(1, cancel(1, 7, 3), &sign_other(cancel(1, 7, 3))));
+        assert!(!user_manager.verify_signature(2, cancel(2, 7, 3), &signature));
+
+        let withdraw = |user_id, asset: &str, amount: &str, business_id, nonce| {
+            asset_manager
+                .commit_withdraw(user_id, asset, &amount.parse().unwrap(), business_id, nonce)
+                .unwrap()
+                .hash()
+        };
+        let eth = MockAsset::ETH.id();
+        let signature = sign(withdraw(1, &eth, "1.5", 10, 4));
+        assert!(user_manager.verify_signature(1, withdraw(1, &eth, "1.5", 10, 4), &signature));
+        assert!(!user_manager.verify_signature(1, withdraw(1, &eth, "2.5", 10, 4), &signature));
+        assert!(!user_manager.verify_signature(1, withdraw(1, &MockAsset::USDT.id(), "1.5", 10, 4), &signature));
+        assert!(!user_manager.verify_signature(1, withdraw(1, &eth, "1.5", 11, 4), &signature));
+        assert!(!user_manager.verify_signature(1, withdraw(1, &eth, "1.5", 10, 4), &sign_other(withdraw(1, &eth, "1.5", 10, 4))));
+        // a cancel signature is not a withdraw signature
+        assert!(!user_manager.verify_signature(1, withdraw(1, &eth, "1.5", 10, 4), &sign(cancel(1, 10, 4))));
+        assert!(asset_manager.commit_withdraw(1, "DOGE", &"1".parse().unwrap(), 10, 4).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_DEAD_LETTERS: usize = 10000;
+
+// a message kafka did not accept, with the topic and the payload as they were sent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub key: String,
+    pub payload: String,
+    pub error: String,
+}
+
+// the latest dead letters, the oldest ones are evicted when it is full.
+// every dead letter is also appended to the file if there is one
+pub struct DeadLetterQueue {
+    letters: VecDeque<DeadLetter>,
+    capacity: usize,
+    path: Option<PathBuf>,
+    total: u64,
+    evicted: u64,
+}
+
+// shared by the producer thread and the operator
+pub type SharedDeadLetters = Arc<Mutex<DeadLetterQueue>>;
+
+impl Default for DeadLetterQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEAD_LETTERS, None)
+    }
+}
+
+impl DeadLetterQueue {
+    pub fn new(capacity: usize, path: Option<PathBuf>) -> Self {
+        Self {
+            letters: VecDeque::new(),
+            capacity,
+            path,
+            total: 0,
+            evicted: 0,
+        }
+    }
+    pub fn shared(self) -> SharedDeadLetters {
+        Arc::new(Mutex::new(self))
+    }
+    pub fn push(&mut self, letter: DeadLetter) {
+        log::error!("kafka message of {} moved to the dead letters: {}", letter.topic, letter.error);
+        if let Some(path) = &self.path {
+            if let Err(e) = append_letter(path, &letter) {
+                log::error!("write dead letter failed: {}", e);
+            }
+        }
+        if self.letters.len() >= self.capacity {
+            self.letters.pop_front();
+            self.evicted += 1;
+        }
+        self.letters.push_back(letter);
+        self.total += 1;
+    }
+    pub fn len(&self) -> usize {
+        self.letters.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.letters.is_empty()
+    }
+    // all the dead letters so far, including the drained and the evicted ones
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+    // only left in the file, if there is one
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+    pub fn drain(&mut self) -> Vec<DeadLetter> {
+        self.letters.drain(..).collect()
+    }
+    // e.g. the ones failed to be enqueued again
+    pub fn push_front(&mut self, letters: Vec<DeadLetter>) {
+        for letter in letters.into_iter().rev() {
+            self.letters.push_front(letter);
+        }
+    }
+}
+
+fn append_letter(path: &Path, letter: &DeadLetter) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(letter)?)?;
+    Ok(())
+}
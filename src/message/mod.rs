@@ -1,17 +1,23 @@
 use crate::market::Order;
 pub use crate::models::{AccountDesc, BalanceHistory, InternalTx};
-use crate::types::OrderEventType;
+use crate::types::{FinishReason, OrderActor, OrderCancelReason, OrderEventType, OrderFinish, TimestampMs};
 
 use anyhow::Result;
 use fluidex_common::utils::timeutil::FTimestamp;
 use serde::{Deserialize, Serialize};
 
 pub mod consumer;
+pub mod dead_letter;
 pub mod persist;
 pub mod producer;
+pub mod proto;
 
+pub use crate::config::MessageFormat;
+
+pub use producer::ProducerStats;
 pub use producer::{
-    BALANCES_TOPIC, DEPOSITS_TOPIC, INTERNALTX_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, UNIFY_TOPIC, USER_TOPIC, WITHDRAWS_TOPIC,
+    BALANCES_TOPIC, DEPOSITS_TOPIC, FEES_TOPIC, INTERNALTX_TOPIC, MARKETS_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, UNIFY_TOPIC, USER_TOPIC,
+    WITHDRAWS_TOPIC,
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -33,7 +39,7 @@ impl From<AccountDesc> for UserMessage {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BalanceMessage {
-    pub timestamp: f64,
+    pub timestamp: TimestampMs,
     pub user_id: u32,
     pub business_id: u64,
     pub asset: String,
@@ -50,7 +56,7 @@ pub struct BalanceMessage {
 impl From<&BalanceHistory> for BalanceMessage {
     fn from(balance: &BalanceHistory) -> Self {
         Self {
-            timestamp: balance.time.timestamp() as f64,
+            timestamp: TimestampMs::from(&balance.time),
             user_id: balance.user_id as u32,
             business_id: balance.business_id as u64,
             asset: balance.asset.clone(),
@@ -68,7 +74,7 @@ impl From<&BalanceHistory> for BalanceMessage {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DepositMessage {
-    pub timestamp: f64,
+    pub timestamp: TimestampMs,
     pub user_id: u32,
     pub asset: String,
     pub business: String,
@@ -82,7 +88,7 @@ pub struct DepositMessage {
 impl From<&BalanceHistory> for DepositMessage {
     fn from(balance: &BalanceHistory) -> Self {
         Self {
-            timestamp: balance.time.timestamp() as f64,
+            timestamp: TimestampMs::from(&balance.time),
             user_id: balance.user_id as u32,
             asset: balance.asset.clone(),
             business: balance.business.clone(),
@@ -97,7 +103,7 @@ impl From<&BalanceHistory> for DepositMessage {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WithdrawMessage {
-    pub timestamp: f64,
+    pub timestamp: TimestampMs,
     pub user_id: u32,
     pub asset: String,
     pub business: String,
@@ -112,7 +118,7 @@ pub struct WithdrawMessage {
 impl From<&BalanceHistory> for WithdrawMessage {
     fn from(balance: &BalanceHistory) -> Self {
         Self {
-            timestamp: balance.time.timestamp() as f64,
+            timestamp: TimestampMs::from(&balance.time),
             user_id: balance.user_id as u32,
             asset: balance.asset.clone(),
             business: balance.business.clone(),
@@ -155,6 +161,18 @@ pub struct OrderMessage {
     pub order: Order,
     pub base: String,
     pub quote: String,
+    // only not none when the order is canceled by the engine, the event is FINISH
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancel_reason: Option<OrderCancelReason>,
+    // only not none when the event is terminal. a FINISH is a cancellation unless it is `filled` or `dust`,
+    // e.g. `canceled` by the user or `market_closed` by the admin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<FinishReason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_actor: Option<OrderActor>,
+    // only not none when the order is amended, `order` is the amended one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_before: Option<Order>,
 }
 
 impl OrderMessage {
@@ -164,11 +182,36 @@ impl OrderMessage {
             order: *order,
             base: order.base.to_string(),
             quote: order.quote.to_string(),
+            cancel_reason: None,
+            finish_reason: None,
+            finish_actor: None,
+            order_before: None,
+        }
+    }
+    pub fn from_finished_order(order: &Order, finish: OrderFinish) -> Self {
+        Self {
+            cancel_reason: finish.reason.cancel_reason(),
+            finish_reason: Some(finish.reason),
+            finish_actor: Some(finish.actor),
+            ..Self::from_order(order, finish.event())
+        }
+    }
+    pub fn from_amended_order(before: &Order, after: &Order) -> Self {
+        Self {
+            order_before: Some(*before),
+            ..Self::from_order(after, OrderEventType::UPDATE)
         }
     }
 }
 //re-export from market, act as TradeMessage
+pub use crate::audit::{ConservationViolation, FrozenMismatch};
+pub use crate::controller::OperationLogEntry;
+pub use crate::market::DepthUpdate;
+pub use crate::market::Kline;
+pub use crate::market::MarketEvent;
 pub use crate::market::Trade;
+pub use crate::market::TradeFeeRecord;
+pub use crate::persist::Tombstone;
 
 //TODO: senderstatus is not used anymore?
 #[derive(Serialize, Deserialize)]
@@ -178,44 +221,129 @@ pub struct MessageSenderStatus {
     balances_len: usize,
 }
 
+// the envelope of a message for the consumers to dedup and detect gaps, `kind` is the name of the message
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Envelope<T> {
+    pub seq: u64,
+    pub ts: f64,
+    pub kind: String,
+    pub payload: T,
+}
+
+// the same as serializing an `Envelope`, with the payload serialized already
+pub fn envelope_json(seq: u64, ts: f64, kind: &str, payload: &str) -> String {
+    format!(
+        "{{\"seq\":{},\"ts\":{},\"kind\":{},\"payload\":{}}}",
+        seq,
+        serde_json::to_string(&ts).unwrap(),
+        serde_json::to_string(kind).unwrap(),
+        payload
+    )
+}
+
 pub trait MessageManager: Sync + Send {
     //fn push_message(&mut self, msg: &Message);
     fn is_block(&self) -> bool;
+    // the messages not taken by the producer yet
+    fn pending(&self) -> usize {
+        0
+    }
+    // none if the manager does not run a producer
+    fn stats(&self) -> Option<ProducerStats> {
+        None
+    }
+    // stops the producer once it drained or spilled the messages, no message is pushed after it
+    fn shutdown(&mut self) {}
     fn push_order_message(&mut self, order: &OrderMessage);
     fn push_trade_message(&mut self, trade: &Trade);
+    // `json` is the serialized trade, for the managers sending json anyway
+    fn push_trade_json(&mut self, trade: &Trade, _json: &str) {
+        self.push_trade_message(trade)
+    }
+    fn push_fee_message(&mut self, fee: &TradeFeeRecord);
+    fn push_market_event_message(&mut self, event: &MarketEvent);
     fn push_balance_message(&mut self, balance: &BalanceMessage);
     fn push_deposit_message(&mut self, balance: &DepositMessage);
     fn push_withdraw_message(&mut self, balance: &WithdrawMessage);
     fn push_transfer_message(&mut self, tx: &TransferMessage);
     fn push_user_message(&mut self, user: &UserMessage);
+    // a message serialized already, e.g. in an envelope
+    fn push_json(&mut self, topic: &'static str, json: String);
+    // a message encoded already, e.g. in protobuf
+    fn push_bytes(&mut self, topic: &'static str, bytes: Vec<u8>);
 }
 
 pub struct RdProducerStub<T> {
-    pub sender: crossbeam_channel::Sender<(&'static str, String)>,
+    pub sender: crossbeam_channel::Sender<(&'static str, Vec<u8>)>,
+    stats: std::sync::Arc<std::sync::Mutex<ProducerStats>>,
+    shutdown: crossbeam_channel::Sender<()>,
+    thread: Option<std::thread::JoinHandle<()>>,
     _phantom: std::marker::PhantomData<T>,
 }
 
 impl<T> RdProducerStub<T> {
-    fn push_message_and_topic(&self, message: String, topic_name: &'static str) {
+    fn push_message_and_topic(&self, message: impl Into<Vec<u8>>, topic_name: &'static str) {
         //log::debug!("KAFKA: push {} message: {}", topic_name, message);
-        self.sender.try_send((topic_name, message)).unwrap();
+        self.sender.try_send((topic_name, message.into())).unwrap();
+    }
+
+    // sends the dead letters again, e.g. once kafka recovers. the ones not sent are kept in the queue
+    pub fn requeue_dead_letters(&self, dead_letters: &dead_letter::SharedDeadLetters) -> usize {
+        let mut queue = dead_letters.lock().unwrap();
+        let mut letters = queue.drain().into_iter();
+        let mut count = 0;
+        let mut unsent = Vec::new();
+        for letter in &mut letters {
+            let topic = match producer::known_topic(&letter.topic) {
+                Some(topic) => topic,
+                None => {
+                    log::error!("dead letter of unknown topic {} dropped", letter.topic);
+                    continue;
+                }
+            };
+            if let Err(e) = self.sender.try_send((topic, letter.payload.clone().into_bytes())) {
+                log::warn!("requeue dead letters stopped: {}", e);
+                unsent.push(letter);
+                break;
+            }
+            count += 1;
+        }
+        unsent.extend(letters);
+        queue.push_front(unsent);
+        count
     }
 }
 
 impl<T: producer::MessageScheme + 'static> RdProducerStub<T> {
     pub fn new_and_run(brokers: &str) -> Result<Self> {
+        Self::new_and_run_with(brokers, &Default::default(), T::default(), producer::DEFAULT_SHUTDOWN_TIMEOUT)
+    }
+
+    // `settings` are the kafka settings of the deployment, e.g. the credentials.
+    // the producer takes at most `shutdown_timeout` to send the messages left on shutdown
+    pub fn new_and_run_with(
+        brokers: &str,
+        settings: &std::collections::HashMap<String, String>,
+        message_scheme: T,
+        shutdown_timeout: std::time::Duration,
+    ) -> Result<Self> {
         //now the channel is just need to provide a small buffer which is
         //enough to accommodate a pluse request in some time slice of thread
         let (sender, receiver) = crossbeam_channel::bounded(2048);
 
         let producer_context: producer::RdProducerContext<T> = Default::default();
+        let stats = producer_context.stats();
 
-        let kafkaproducer = producer_context.new_producer(brokers)?;
-        std::thread::spawn(move || {
-            producer::RdProducerContext::<T>::run_default(kafkaproducer, receiver);
+        let kafkaproducer = producer_context.new_producer(brokers, settings)?;
+        let (shutdown, shutdown_receiver) = crossbeam_channel::bounded(1);
+        let thread = std::thread::spawn(move || {
+            producer::RdProducerContext::<T>::run(kafkaproducer, message_scheme, receiver, shutdown_receiver, shutdown_timeout);
         });
         Ok(Self {
             sender,
+            stats,
+            shutdown,
+            thread: Some(thread),
             _phantom: std::marker::PhantomData,
         })
     }
@@ -247,6 +375,22 @@ impl<T: producer::MessageScheme> MessageManager for RdProducerStub<T> {
         //self.sender.len() >= (self.sender.capacity().unwrap() as f64 * 0.9) as usize
         self.sender.len() >= (self.sender.capacity().unwrap() - 1000)
     }
+    fn pending(&self) -> usize {
+        self.sender.len()
+    }
+    fn stats(&self) -> Option<ProducerStats> {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.pending = self.sender.len();
+        Some(stats)
+    }
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.shutdown.try_send(());
+            if thread.join().is_err() {
+                log::error!("kafka producer thread panicked");
+            }
+        }
+    }
     fn push_order_message(&mut self, order: &OrderMessage) {
         let message = serde_json::to_string(&order).unwrap();
         self.push_message_and_topic(message, ORDERS_TOPIC)
@@ -255,6 +399,23 @@ impl<T: producer::MessageScheme> MessageManager for RdProducerStub<T> {
         let message = serde_json::to_string(&trade).unwrap();
         self.push_message_and_topic(message, TRADES_TOPIC)
     }
+    fn push_trade_json(&mut self, _trade: &Trade, json: &str) {
+        self.push_message_and_topic(json.to_string(), TRADES_TOPIC)
+    }
+    fn push_json(&mut self, topic: &'static str, json: String) {
+        self.push_message_and_topic(json, topic)
+    }
+    fn push_bytes(&mut self, topic: &'static str, bytes: Vec<u8>) {
+        self.push_message_and_topic(bytes, topic)
+    }
+    fn push_fee_message(&mut self, fee: &TradeFeeRecord) {
+        let message = serde_json::to_string(&fee).unwrap();
+        self.push_message_and_topic(message, FEES_TOPIC)
+    }
+    fn push_market_event_message(&mut self, event: &MarketEvent) {
+        let message = serde_json::to_string(&event).unwrap();
+        self.push_message_and_topic(message, MARKETS_TOPIC)
+    }
     fn push_balance_message(&mut self, balance: &BalanceMessage) {
         let message = serde_json::to_string(&balance).unwrap();
         self.push_message_and_topic(message, BALANCES_TOPIC)
@@ -294,6 +455,14 @@ pub enum Message {
     DepositMessage(Box<BalanceMessage>),
     OrderMessage(Box<OrderMessage>),
     TradeMessage(Box<Trade>),
+    FeeMessage(Box<TradeFeeRecord>),
+    DepthUpdateMessage(Box<DepthUpdate>),
+    KlineMessage(Box<Kline>),
+    MarketEventMessage(Box<MarketEvent>),
+    ConservationViolationMessage(Box<ConservationViolation>),
+    FrozenDeficitMessage(Box<FrozenMismatch>),
+    TombstoneMessage(Box<Tombstone>),
+    OperationLogMessage(Box<OperationLogEntry>),
     TransferMessage(Box<TransferMessage>),
     UserMessage(Box<UserMessage>),
     WithdrawMessage(Box<BalanceMessage>),
@@ -560,7 +560,7 @@ use fluidex_common::utils::timeutil::FTimestamp;
 impl<'r> From<&'r super::Trade> for models::MarketTrade {
     fn from(origin: &'r super::Trade) -> Self {
         models::MarketTrade {
-            time: FTimestamp(origin.timestamp).into(),
+            time: origin.timestamp.into(),
             market: origin.market.clone(),
             trade_id: origin.id as i64,
             price: origin.price,
@@ -581,7 +581,11 @@ use std::str::FromStr;
 
 impl<'r> From<&'r super::OrderMessage> for models::OrderHistory {
     fn from(origin: &'r super::OrderMessage) -> Self {
-        models::OrderHistory::from(&origin.order)
+        models::OrderHistory {
+            finish_reason: origin.finish_reason,
+            finish_actor: origin.finish_actor,
+            ..models::OrderHistory::from(&origin.order)
+        }
     }
 }
 
@@ -605,7 +609,7 @@ fn decimal_warning<E: std::error::Error>(e: E) -> DecimalDbType {
 impl<'r> From<&'r super::BalanceMessage> for models::BalanceHistory {
     fn from(origin: &'r super::BalanceMessage) -> Self {
         models::BalanceHistory {
-            time: FTimestamp::from(&origin.timestamp).into(),
+            time: origin.timestamp.into(),
             user_id: origin.user_id as i32,
             business_id: origin.business_id as i64,
             asset: origin.asset.clone(),
@@ -627,7 +631,7 @@ impl MsgDataTransformer<models::UserTrade> for AskTrade {
     type MsgType = super::Trade;
     fn into(trade: &Self::MsgType) -> Option<models::UserTrade> {
         Some(models::UserTrade {
-            time: FTimestamp(trade.timestamp).into(),
+            time: trade.timestamp.into(),
             user_id: trade.ask_user_id as i32,
             market: trade.market.clone(),
             trade_id: trade.id as i64,
@@ -650,7 +654,7 @@ impl MsgDataTransformer<models::UserTrade> for BidTrade {
     type MsgType = super::Trade;
     fn into(trade: &Self::MsgType) -> Option<models::UserTrade> {
         Some(models::UserTrade {
-            time: FTimestamp(trade.timestamp).into(),
+            time: trade.timestamp.into(),
             user_id: trade.bid_user_id as i32,
             market: trade.market.clone(),
             trade_id: trade.id as i64,
@@ -1,33 +1,163 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use crossbeam_channel::{RecvTimeoutError, TryRecvError};
 use fluidex_common::rdkafka::client::ClientContext;
 use fluidex_common::rdkafka::config::ClientConfig;
 use fluidex_common::rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use fluidex_common::rdkafka::message::{OwnedHeaders, ToBytes};
 use fluidex_common::rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
 use fluidex_common::rdkafka::util::{IntoOpaque, Timeout};
-use std::time::Duration;
+use fluidex_common::utils::timeutil::current_timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub type SimpleDeliverResult = Result<(), KafkaError>;
 
+// the settings of the scheme overridden by the ones of the deployment, e.g. the credentials of the cluster
+pub fn merge_settings<T: MessageScheme>(overrides: &HashMap<String, String>) -> Result<Vec<(String, String)>> {
+    let mut settings: Vec<(String, String)> = T::settings().into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+    let protected = T::protected_settings();
+    let mut keys = overrides.keys().collect::<Vec<_>>();
+    keys.sort();
+    for key in keys {
+        let value = &overrides[key];
+        if key == "bootstrap.servers" {
+            bail!("kafka setting bootstrap.servers is set by brokers");
+        }
+        match settings.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) if v == value => {}
+            Some(_) if protected.contains(&key.as_str()) => {
+                bail!("kafka setting {} is required by the message scheme and can not be changed", key)
+            }
+            Some((_, v)) => *v = value.clone(),
+            None => settings.push((key.clone(), value.clone())),
+        }
+    }
+    check_security(&settings)?;
+    Ok(settings)
+}
+
+fn check_security(settings: &[(String, String)]) -> Result<()> {
+    let get = |key: &str| settings.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+    let has_prefix = |prefix: &str| settings.iter().any(|(k, _)| k.starts_with(prefix));
+    let protocol = get("security.protocol").unwrap_or("plaintext").to_lowercase();
+    let (sasl, ssl) = match protocol.as_str() {
+        "plaintext" => (false, false),
+        "ssl" => (false, true),
+        "sasl_plaintext" => (true, false),
+        "sasl_ssl" => (true, true),
+        _ => bail!("invalid kafka security.protocol {}", protocol),
+    };
+    if !sasl && has_prefix("sasl.") {
+        bail!("kafka sasl settings need security.protocol sasl_plaintext or sasl_ssl");
+    }
+    if !ssl && has_prefix("ssl.") {
+        bail!("kafka ssl settings need security.protocol ssl or sasl_ssl");
+    }
+    if sasl {
+        let mechanism = match get("sasl.mechanism").or_else(|| get("sasl.mechanisms")) {
+            Some(mechanism) => mechanism.to_uppercase(),
+            None => bail!("kafka security.protocol {} needs sasl.mechanism", protocol),
+        };
+        let with_password = mechanism == "PLAIN" || mechanism.starts_with("SCRAM-");
+        if with_password && (get("sasl.username").is_none() || get("sasl.password").is_none()) {
+            bail!("kafka sasl.mechanism {} needs sasl.username and sasl.password", mechanism);
+        }
+    }
+    Ok(())
+}
+
+// the messages a scheme buffers for a topic before it stops absorbing new ones
+pub const DEFAULT_QUEUE_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProducerStats {
+    // the messages buffered by the scheme, by topic
+    pub queued: BTreeMap<String, usize>,
+    // the messages in the channel, not taken by the producer thread yet
+    pub pending: usize,
+    // handed to the kafka producer
+    pub sent: u64,
+    pub delivery_failures: u64,
+    pub last_delivered: Option<f64>,
+    // how many times the kafka producer queue was full
+    pub queue_full: u64,
+}
+
+impl ProducerStats {
+    pub fn queued_total(&self) -> usize {
+        self.queued.values().sum()
+    }
+    fn on_delivered(&mut self, result: &SimpleDeliverResult) {
+        match result {
+            Ok(()) => self.last_delivered = Some(current_timestamp()),
+            Err(_) => self.delivery_failures += 1,
+        }
+    }
+}
+
+// the messages not delivered when a producer stopped, by their sequences in the scheme
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpillReport {
+    pub count: usize,
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+}
+
+impl SpillReport {
+    pub fn of(seqs: impl IntoIterator<Item = u64>) -> Self {
+        seqs.into_iter().fold(Self::default(), |report, seq| Self {
+            count: report.count + 1,
+            first: Some(report.first.map_or(seq, |first| first.min(seq))),
+            last: Some(report.last.map_or(seq, |last| last.max(seq))),
+        })
+    }
+}
+
+// the time a producer takes to send the messages left when it stops, the rest are spilled
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub trait MessageScheme: Default + Sync + Send {
     type DeliverOpaque: IntoOpaque;
+    // the payload of the records, bytes if the messages may be encoded in protobuf
+    type Payload: ToBytes + ?Sized;
     type K: Into<String>;
     type V: Into<String>;
 
     fn settings() -> Vec<(Self::K, Self::V)> {
         vec![]
     }
+    // the settings the scheme relies on, which the deployment can not change
+    fn protected_settings() -> Vec<&'static str> {
+        vec![]
+    }
     fn is_full(&self) -> bool;
-    fn on_message(&mut self, title_tip: &'static str, message: String);
-    fn pop_up(&mut self) -> Option<BaseRecord<'_, str, str, Self::DeliverOpaque>>;
+    fn on_message(&mut self, title_tip: &'static str, message: Vec<u8>);
+    // the record key of a message, which decides its partition
+    fn message_key(&self, title_tip: &str, message: &[u8]) -> String {
+        entity_key(title_tip, std::str::from_utf8(message).unwrap_or_default())
+    }
+    fn pop_up(&mut self) -> Option<BaseRecord<'_, str, Self::Payload, Self::DeliverOpaque>>;
     fn commit(&mut self, isfailed: Option<Self::DeliverOpaque>);
     fn deliver_commit(&mut self, result: SimpleDeliverResult, opaque: Self::DeliverOpaque);
+    // called when the producer stops, with the messages which are not delivered
+    fn spill(&mut self) -> SpillReport {
+        SpillReport::default()
+    }
+    fn stats(&self) -> ProducerStats {
+        ProducerStats::default()
+    }
 }
 
 pub struct RdProducerContext<T: MessageScheme> {
     //we use unboound channel to simulate a continuation(?)
     delivery_record: crossbeam_channel::Sender<(SimpleDeliverResult, T::DeliverOpaque)>,
     delivery_record_get: crossbeam_channel::Receiver<(SimpleDeliverResult, T::DeliverOpaque)>,
+    // published by the producer thread from time to time
+    stats: Arc<Mutex<ProducerStats>>,
     //_phantom : std::marker::PhantomData<T>,
 }
 
@@ -38,6 +168,7 @@ impl<T: MessageScheme> Default for RdProducerContext<T> {
         Self {
             delivery_record: s,
             delivery_record_get: r,
+            stats: Default::default(),
         }
     }
 }
@@ -62,10 +193,15 @@ impl<T: MessageScheme> ProducerContext for RdProducerContext<T> {
 //it simply block the Sender side of crossbeam_channel when the deliver queue is full, and quit
 //only when the sender side is closed
 impl<T: MessageScheme> RdProducerContext<T> {
-    pub fn new_producer(self, brokers: &str) -> Result<BaseProducer<Self>> {
+    pub fn stats(&self) -> Arc<Mutex<ProducerStats>> {
+        self.stats.clone()
+    }
+
+    // `settings` of the deployment are applied after the ones of the scheme
+    pub fn new_producer(self, brokers: &str, settings: &HashMap<String, String>) -> Result<BaseProducer<Self>> {
         let mut config = ClientConfig::new();
         config.set("bootstrap.servers", brokers);
-        T::settings().into_iter().for_each(|item| {
+        merge_settings::<T>(settings)?.into_iter().for_each(|item| {
             let (k, v) = item;
             config.set(k, v);
         });
@@ -74,46 +210,73 @@ impl<T: MessageScheme> RdProducerContext<T> {
         Ok(producer)
     }
 
-    pub fn run_default(producer: BaseProducer<Self>, receiver: crossbeam_channel::Receiver<(&'static str, String)>) {
+    pub fn run_default(producer: BaseProducer<Self>, receiver: crossbeam_channel::Receiver<(&'static str, Vec<u8>)>) {
         let message_scheme = T::default();
-        Self::run(producer, message_scheme, receiver);
+        Self::run(
+            producer,
+            message_scheme,
+            receiver,
+            crossbeam_channel::never(),
+            DEFAULT_SHUTDOWN_TIMEOUT,
+        );
     }
 
-    pub fn run(producer: BaseProducer<Self>, mut message_scheme: T, receiver: crossbeam_channel::Receiver<(&'static str, String)>) {
-        Self::run_loop(&producer, &mut message_scheme, receiver);
+    // runs until the senders are gone or a shutdown is signaled, then drains the scheme for at most `shutdown_timeout`
+    pub fn run(
+        producer: BaseProducer<Self>,
+        mut message_scheme: T,
+        receiver: crossbeam_channel::Receiver<(&'static str, Vec<u8>)>,
+        shutdown: crossbeam_channel::Receiver<()>,
+        shutdown_timeout: Duration,
+    ) {
+        Self::run_loop(&producer, &mut message_scheme, receiver, shutdown);
+        drain(&producer, &mut message_scheme, shutdown_timeout);
+        Self::publish_stats(&producer, &message_scheme, None);
+        log::info!("kafka producer running terminated");
+    }
 
-        //flush producer before exit
-        while let Some(msg) = message_scheme.pop_up() {
-            let send_ret = match producer.send(msg) {
-                Ok(_) => None,
-                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), rec)) => {
-                    //when queue is full, simply made some polling and retry
-                    producer.poll(Duration::from_millis(100));
-                    Some(rec.delivery_opaque)
-                }
-                Err((err, _)) => {
-                    log::error!("kafka encounter error when shutdown: {}", err);
-                    //TODO: so what should we do? try handling / waiting or just quit?
-                    return;
-                }
-            };
-            message_scheme.commit(send_ret);
-        }
+    // `queue_full` is counted by the running loop
+    fn publish_stats(producer: &BaseProducer<Self>, message_scheme: &T, queue_full: Option<u64>) {
+        let mut stats = producer.context().stats.lock().unwrap();
+        let queue_full = queue_full.unwrap_or(stats.queue_full);
+        *stats = ProducerStats {
+            queue_full,
+            ..message_scheme.stats()
+        };
+    }
 
-        producer.flush(Timeout::Never);
-        log::info!("kafka producer running terminated");
+    fn commit_deliveries(producer: &BaseProducer<Self>, message_scheme: &mut T) {
+        while let Ok((result, opaque)) = producer.context().delivery_record_get.try_recv() {
+            message_scheme.deliver_commit(result, opaque);
+        }
     }
 
-    fn run_loop(producer: &BaseProducer<Self>, message_scheme: &mut T, receiver: crossbeam_channel::Receiver<(&'static str, String)>) {
+    fn run_loop(
+        producer: &BaseProducer<Self>,
+        message_scheme: &mut T,
+        receiver: crossbeam_channel::Receiver<(&'static str, Vec<u8>)>,
+        shutdown: crossbeam_channel::Receiver<()>,
+    ) {
         let timeout_interval = Duration::from_millis(100);
-        let delivery_report = &producer.context().delivery_record_get;
         // last_poll == 0 means msg canot be sent out
         let mut last_poll: i32 = 0;
         let mut producer_queue_full = false;
+        let mut queue_full_count = 0;
+        let mut last_published = Instant::now();
 
         loop {
             let mut is_idle = true;
 
+            if shutdown.try_recv().is_ok() {
+                // the messages sent before the shutdown are drained with the ones in the scheme
+                for (topic, message) in receiver.try_iter() {
+                    message_scheme.on_message(topic, message);
+                }
+                log::info!("kafka producer is shutting down");
+                Self::publish_stats(producer, message_scheme, Some(queue_full_count));
+                return;
+            }
+
             //current implement in mod.rs lead to arbitrary dropping of messages
             //in the flush() method, I try to fix it here ...
             //basically, it should be enough to make use of the ability of
@@ -139,6 +302,7 @@ impl<T: MessageScheme> RdProducerContext<T> {
                     Err(TryRecvError::Empty) => {}
                     Err(TryRecvError::Disconnected) => {
                         log::info!("kafka producer disconnected");
+                        Self::publish_stats(producer, message_scheme, Some(queue_full_count));
                         return;
                     }
                 };
@@ -151,6 +315,7 @@ impl<T: MessageScheme> RdProducerContext<T> {
                     Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), rec)) => {
                         //flag is clear when we had polled something
                         producer_queue_full = true;
+                        queue_full_count += 1;
                         log::warn!("kafka sender buffer is full");
                         Some(rec.delivery_opaque)
                     }
@@ -170,8 +335,10 @@ impl<T: MessageScheme> RdProducerContext<T> {
             };
             last_poll = producer.poll(poll_dur);
             producer_queue_full = producer_queue_full && last_poll == 0;
-            while let Ok((result, opaque)) = delivery_report.try_recv() {
-                message_scheme.deliver_commit(result, opaque);
+            Self::commit_deliveries(producer, message_scheme);
+            if last_published.elapsed() >= timeout_interval {
+                Self::publish_stats(producer, message_scheme, Some(queue_full_count));
+                last_published = Instant::now();
             }
 
             if is_idle {
@@ -182,29 +349,223 @@ impl<T: MessageScheme> RdProducerContext<T> {
     }
 }
 
+// what a stopping producer drains the messages to, the kafka producer or a mock in the tests
+pub trait DrainTarget<T: MessageScheme> {
+    // the opaque of the record is returned if it is not sent
+    fn send(&self, record: BaseRecord<'_, str, T::Payload, T::DeliverOpaque>) -> std::result::Result<(), (KafkaError, T::DeliverOpaque)>;
+    fn poll(&self, timeout: Duration);
+    // whether every sent message is delivered in time
+    fn flush(&self, timeout: Duration) -> bool;
+    fn commit_deliveries(&self, message_scheme: &mut T);
+}
+
+impl<T: MessageScheme> DrainTarget<T> for BaseProducer<RdProducerContext<T>> {
+    fn send(&self, record: BaseRecord<'_, str, T::Payload, T::DeliverOpaque>) -> std::result::Result<(), (KafkaError, T::DeliverOpaque)> {
+        BaseProducer::send(self, record).map_err(|(err, record)| (err, record.delivery_opaque))
+    }
+    fn poll(&self, timeout: Duration) {
+        BaseProducer::poll(self, timeout);
+    }
+    fn flush(&self, timeout: Duration) -> bool {
+        Producer::flush(self, Timeout::After(timeout));
+        self.in_flight_count() == 0
+    }
+    fn commit_deliveries(&self, message_scheme: &mut T) {
+        RdProducerContext::<T>::commit_deliveries(self, message_scheme)
+    }
+}
+
+// sends the messages left in the scheme until the timeout, then spills the ones not delivered,
+// so nothing is abandoned silently even if kafka is down
+pub fn drain<T: MessageScheme, P: DrainTarget<T>>(producer: &P, message_scheme: &mut T, timeout: Duration) -> SpillReport {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        let msg = match message_scheme.pop_up() {
+            Some(msg) => msg,
+            None => break,
+        };
+        match producer.send(msg) {
+            Ok(()) => message_scheme.commit(None),
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), opaque)) => {
+                //when queue is full, simply made some polling and retry
+                message_scheme.commit(Some(opaque));
+                producer.poll(Duration::from_millis(100).min(deadline.saturating_duration_since(Instant::now())));
+            }
+            Err((err, opaque)) => {
+                log::error!("kafka encounter error when shutdown: {}", err);
+                message_scheme.commit(Some(opaque));
+                break;
+            }
+        }
+        producer.commit_deliveries(message_scheme);
+    }
+
+    if !producer.flush(deadline.saturating_duration_since(Instant::now())) {
+        log::warn!("kafka producer not flushed in {:?}", timeout);
+    }
+    producer.commit_deliveries(message_scheme);
+    let report = message_scheme.spill();
+    if report.count > 0 {
+        log::error!(
+            "{} messages not delivered on shutdown, from {:?} to {:?}",
+            report.count,
+            report.first,
+            report.last
+        );
+    }
+    report
+}
+
 pub const BALANCES_TOPIC: &str = "balances";
 pub const DEPOSITS_TOPIC: &str = "deposits";
+pub const FEES_TOPIC: &str = "fees";
 pub const INTERNALTX_TOPIC: &str = "internaltransfer";
+pub const MARKETS_TOPIC: &str = "markets";
 pub const ORDERS_TOPIC: &str = "orders";
 pub const TRADES_TOPIC: &str = "trades";
 pub const UNIFY_TOPIC: &str = "unifyevents";
 pub const USER_TOPIC: &str = "registeruser";
 pub const WITHDRAWS_TOPIC: &str = "withdraws";
 
+// the header of the unify events carrying the entity key, their record key is the topic
+pub const ENTITY_KEY_HEADER: &str = "entity";
+
+// the market of the trades, orders, fees and market events, the user of the others,
+// so the messages of a market or a user stay in one partition. empty if there is no such field
+pub fn entity_key(title_tip: &str, message: &str) -> String {
+    let value = match serde_json::from_str::<serde_json::Value>(message) {
+        Ok(value) => value,
+        Err(_) => return String::new(),
+    };
+    let field = match title_tip {
+        ORDERS_TOPIC => &value["order"]["market"],
+        TRADES_TOPIC | FEES_TOPIC | MARKETS_TOPIC => &value["market"],
+        BALANCES_TOPIC | DEPOSITS_TOPIC | WITHDRAWS_TOPIC | USER_TOPIC => &value["user_id"],
+        INTERNALTX_TOPIC => &value["user_from"],
+        _ => return String::new(),
+    };
+    match field {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Number(n) => n.to_string(),
+        _ => String::new(),
+    }
+}
+
+use super::dead_letter::{DeadLetter, DeadLetterQueue, SharedDeadLetters};
+use super::MessageFormat;
 use std::collections::LinkedList;
 
+// the arrival, key, payload and the failed sends of a queued message
+type Queued = (u64, String, String, u32);
+
+// the messages are sent by their arrivals across the topics, a failed one is sent again before the later ones
 #[derive(Default)]
 pub struct SimpleMessageScheme {
-    balances_list: LinkedList<String>,
-    internaltxs_list: LinkedList<String>,
-    orders_list: LinkedList<String>,
-    trades_list: LinkedList<String>,
-    users_list: LinkedList<String>,
-    last_poped: Option<(&'static str, String)>,
+    balances_list: LinkedList<Queued>,
+    deposits_list: LinkedList<Queued>,
+    fees_list: LinkedList<Queued>,
+    internaltxs_list: LinkedList<Queued>,
+    markets_list: LinkedList<Queued>,
+    orders_list: LinkedList<Queued>,
+    trades_list: LinkedList<Queued>,
+    users_list: LinkedList<Queued>,
+    withdraws_list: LinkedList<Queued>,
+    next_arrival: u64,
+    // the topic and the queued message
+    last_poped: Option<(&'static str, Queued)>,
+    // sent to the producer but not confirmed yet, by the ids in the delivery opaques
+    in_flight: HashMap<u64, (&'static str, Queued)>,
+    next_id: u64,
+    dead_letters: SharedDeadLetters,
+    // DEFAULT_QUEUE_LIMIT if not set
+    queue_limit: Option<usize>,
+    counters: ProducerStats,
+}
+
+// a message is moved to the dead letters after failing to be sent so many times
+pub const MAX_SEND_RETRIES: u32 = 1000;
+
+impl SimpleMessageScheme {
+    pub fn with_dead_letters(dead_letters: SharedDeadLetters) -> Self {
+        Self {
+            dead_letters,
+            ..Default::default()
+        }
+    }
+    pub fn dead_letters(&self) -> SharedDeadLetters {
+        self.dead_letters.clone()
+    }
+    pub fn with_queue_limit(mut self, limit: usize) -> Self {
+        self.queue_limit = Some(limit);
+        self
+    }
+    fn lists(&self) -> [(&'static str, &LinkedList<Queued>); 9] {
+        [
+            (BALANCES_TOPIC, &self.balances_list),
+            (DEPOSITS_TOPIC, &self.deposits_list),
+            (FEES_TOPIC, &self.fees_list),
+            (INTERNALTX_TOPIC, &self.internaltxs_list),
+            (MARKETS_TOPIC, &self.markets_list),
+            (ORDERS_TOPIC, &self.orders_list),
+            (TRADES_TOPIC, &self.trades_list),
+            (USER_TOPIC, &self.users_list),
+            (WITHDRAWS_TOPIC, &self.withdraws_list),
+        ]
+    }
+    // enqueues the dead letters again, returns how many are enqueued
+    pub fn drain_dead_letters(&mut self) -> usize {
+        let letters = self.dead_letters.lock().unwrap().drain();
+        let mut count = 0;
+        for letter in letters {
+            match known_topic(&letter.topic) {
+                Some(topic) => {
+                    self.push(topic, letter.key, letter.payload, 0);
+                    count += 1;
+                }
+                None => log::error!("dead letter of unknown topic {} dropped", letter.topic),
+            }
+        }
+        count
+    }
+
+    fn lists_mut(&mut self) -> [(&'static str, &mut LinkedList<Queued>); 9] {
+        [
+            (BALANCES_TOPIC, &mut self.balances_list),
+            (DEPOSITS_TOPIC, &mut self.deposits_list),
+            (FEES_TOPIC, &mut self.fees_list),
+            (INTERNALTX_TOPIC, &mut self.internaltxs_list),
+            (MARKETS_TOPIC, &mut self.markets_list),
+            (ORDERS_TOPIC, &mut self.orders_list),
+            (TRADES_TOPIC, &mut self.trades_list),
+            (USER_TOPIC, &mut self.users_list),
+            (WITHDRAWS_TOPIC, &mut self.withdraws_list),
+        ]
+    }
+
+    fn list_mut(&mut self, title_tip: &str) -> Option<&mut LinkedList<Queued>> {
+        let list = self
+            .lists_mut()
+            .into_iter()
+            .find(|(topic, _)| *topic == title_tip)
+            .map(|(_, list)| list);
+        if list.is_none() {
+            log::warn!("message of unknown topic {} dropped", title_tip);
+        }
+        list
+    }
+
+    fn push(&mut self, title_tip: &'static str, key: String, message: String, retries: u32) {
+        let arrival = self.next_arrival;
+        if let Some(list) = self.list_mut(title_tip) {
+            list.push_back((arrival, key, message, retries));
+            self.next_arrival += 1;
+        }
+    }
 }
 
 impl MessageScheme for SimpleMessageScheme {
-    type DeliverOpaque = ();
+    type DeliverOpaque = Box<u64>;
+    type Payload = str;
     type K = &'static str;
     type V = &'static str;
 
@@ -212,83 +573,273 @@ impl MessageScheme for SimpleMessageScheme {
         vec![("queue.buffering.max.ms", "1")]
     }
     fn is_full(&self) -> bool {
-        self.balances_list.len() >= 100
-            || self.internaltxs_list.len() >= 100
-            || self.orders_list.len() >= 100
-            || self.trades_list.len() >= 100
-            || self.users_list.len() >= 100
-    }
-
-    fn on_message(&mut self, title_tip: &'static str, message: String) {
-        let list = match title_tip {
-            BALANCES_TOPIC => &mut self.balances_list,
-            INTERNALTX_TOPIC => &mut self.internaltxs_list,
-            ORDERS_TOPIC => &mut self.orders_list,
-            TRADES_TOPIC => &mut self.trades_list,
-            USER_TOPIC => &mut self.users_list,
-            _ => return,
-        };
-
-        list.push_back(message);
+        let limit = self.queue_limit.unwrap_or(DEFAULT_QUEUE_LIMIT);
+        self.lists().iter().any(|(_, list)| list.len() >= limit)
     }
 
-    fn pop_up(&mut self) -> Option<BaseRecord<'_, str, str, Self::DeliverOpaque>> {
-        //we select the list with most size (so message stream is never ordering)
-        let mut len = self.balances_list.len();
-        let mut list = &mut self.balances_list;
-        let mut topic_name = BALANCES_TOPIC;
-
-        let mut candi_list = [
-            &mut self.internaltxs_list,
-            &mut self.orders_list,
-            &mut self.trades_list,
-            &mut self.users_list,
-        ];
-        let iters = [INTERNALTX_TOPIC, ORDERS_TOPIC, TRADES_TOPIC, USER_TOPIC]
-            .iter()
-            .zip(&mut candi_list);
-
-        for i in iters.into_iter() {
-            let (tp_name, l) = i;
-            if l.len() > len {
-                len = l.len();
-                list = *l;
-                topic_name = tp_name;
+    fn on_message(&mut self, title_tip: &'static str, message: Vec<u8>) {
+        match String::from_utf8(message) {
+            Ok(message) => {
+                let key = self.message_key(title_tip, message.as_bytes());
+                self.push(title_tip, key, message, 0);
             }
+            // only the unify events may be encoded in protobuf
+            Err(_) => log::error!("message of {} is not json, dropped", title_tip),
         }
+    }
 
-        self.last_poped = list.pop_front().map(|str| (topic_name, str));
+    fn pop_up(&mut self) -> Option<BaseRecord<'_, str, str, Self::DeliverOpaque>> {
+        //we select the list with the earliest message, so the messages keep their order across the topics
+        let poped = self
+            .lists_mut()
+            .into_iter()
+            .filter_map(|(topic_name, list)| {
+                let arrival = list.front()?.0;
+                Some((arrival, topic_name, list))
+            })
+            .min_by_key(|(arrival, _, _)| *arrival)
+            .and_then(|(_, topic_name, list)| list.pop_front().map(|queued| (topic_name, queued)));
+        self.last_poped = poped;
 
+        let id = self.next_id;
         self.last_poped.as_ref().map(|poped_ret| {
-            let (topic_name, str) = poped_ret;
-            BaseRecord::to(topic_name).key("").payload(AsRef::as_ref(str))
+            let (topic_name, (_, key, str, _)) = poped_ret;
+            BaseRecord::with_opaque_to(topic_name, Box::new(id))
+                .key(AsRef::as_ref(key))
+                .payload(AsRef::as_ref(str))
         })
     }
 
     fn commit(&mut self, isfailed: Option<Self::DeliverOpaque>) {
-        if isfailed.is_some() {
-            //push the poped message back
-            let (topic_name, str) = self.last_poped.take().unwrap();
-            self.on_message(topic_name, str);
+        let (topic_name, (arrival, key, str, retries)) = self.last_poped.take().unwrap();
+        if isfailed.is_none() {
+            self.in_flight.insert(self.next_id, (topic_name, (arrival, key, str, retries)));
+            self.next_id += 1;
+            self.counters.sent += 1;
+        } else if retries + 1 >= MAX_SEND_RETRIES {
+            self.dead_letters.lock().unwrap().push(DeadLetter {
+                topic: topic_name.to_string(),
+                key,
+                payload: str,
+                error: format!("failed to send {} times", retries + 1),
+            });
+        } else if let Some(list) = self.list_mut(topic_name) {
+            //put the poped message back ahead of the later ones
+            list.push_front((arrival, key, str, retries + 1));
         }
     }
-    fn deliver_commit(&mut self, result: SimpleDeliverResult, _opaque: Self::DeliverOpaque) {
+    fn deliver_commit(&mut self, result: SimpleDeliverResult, opaque: Self::DeliverOpaque) {
+        let sent = self.in_flight.remove(&*opaque);
+        self.counters.on_delivered(&result);
         if let Err(e) = result {
-            log::error!("kafka send err: {}, MESSAGE LOST", e);
+            match sent {
+                Some((topic_name, (_, key, str, _))) => self.dead_letters.lock().unwrap().push(DeadLetter {
+                    topic: topic_name.to_string(),
+                    key,
+                    payload: str,
+                    error: e.to_string(),
+                }),
+                None => log::error!("kafka send err: {}, MESSAGE LOST", e),
+            }
+        }
+    }
+    // the dead letters keep the messages not delivered, the ones in flight may have been delivered
+    fn spill(&mut self) -> SpillReport {
+        let mut spilled = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .map(|(_, sent)| (sent, "not confirmed before the producer stopped"))
+            .collect::<Vec<_>>();
+        for (topic, list) in self.lists_mut() {
+            let queued = std::mem::take(list).into_iter();
+            spilled.extend(queued.map(|queued| ((topic, queued), "not sent before the producer stopped")));
+        }
+        let report = SpillReport::of(spilled.iter().map(|((_, (arrival, ..)), _)| *arrival));
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        for ((topic, (_, key, payload, _)), error) in spilled {
+            dead_letters.push(DeadLetter {
+                topic: topic.to_string(),
+                key,
+                payload,
+                error: error.to_string(),
+            });
+        }
+        report
+    }
+    fn stats(&self) -> ProducerStats {
+        let queued = self.lists().iter().map(|(topic, list)| (topic.to_string(), list.len())).collect();
+        ProducerStats {
+            queued,
+            ..self.counters.clone()
         }
     }
 }
 
+// an undelivered message of the full order scheme, kept in the wal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub deliver_cnt: u64,
+    pub topic: String,
+    // hex encoded for protobuf
+    pub payload: String,
+    #[serde(default)]
+    pub format: MessageFormat,
+}
+
+impl WalEntry {
+    pub fn new(deliver_cnt: u64, topic: &str, format: MessageFormat, payload: &[u8]) -> Self {
+        let payload = match format {
+            MessageFormat::Json => String::from_utf8_lossy(payload).into_owned(),
+            MessageFormat::Protobuf => hex::encode(payload),
+        };
+        Self {
+            deliver_cnt,
+            topic: topic.to_string(),
+            payload,
+            format,
+        }
+    }
+    pub fn payload_bytes(&self) -> Result<Vec<u8>> {
+        Ok(match self.format {
+            MessageFormat::Json => self.payload.clone().into_bytes(),
+            MessageFormat::Protobuf => hex::decode(&self.payload)?,
+        })
+    }
+    // the seq of the envelope, none for the messages sent without it
+    pub fn seq(&self) -> Option<u64> {
+        let payload = self.payload_bytes().ok()?;
+        let seq = match self.format {
+            MessageFormat::Json => serde_json::from_slice::<super::Envelope<serde_json::Value>>(&payload).ok()?.seq,
+            MessageFormat::Protobuf => <super::proto::Event as prost::Message>::decode(&payload[..]).ok()?.seq,
+        };
+        Some(seq).filter(|seq| *seq > 0)
+    }
+}
+
+// the entity key of a payload in either format
+fn payload_key(format: MessageFormat, title_tip: &str, payload: &[u8]) -> String {
+    match format {
+        MessageFormat::Json => entity_key(title_tip, std::str::from_utf8(payload).unwrap_or_default()),
+        MessageFormat::Protobuf => super::proto::entity_key(payload),
+    }
+}
+
+pub fn known_topic(name: &str) -> Option<&'static str> {
+    [
+        BALANCES_TOPIC,
+        DEPOSITS_TOPIC,
+        FEES_TOPIC,
+        INTERNALTX_TOPIC,
+        MARKETS_TOPIC,
+        ORDERS_TOPIC,
+        TRADES_TOPIC,
+        USER_TOPIC,
+        WITHDRAWS_TOPIC,
+    ]
+    .iter()
+    .find(|topic| **topic == name)
+    .copied()
+}
+
+// the entries ordered by deliver_cnt, empty if there is no wal
+pub fn read_wal(path: &Path) -> Result<Vec<WalEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for line in BufReader::new(std::fs::File::open(path)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            entries.push(serde_json::from_str::<WalEntry>(&line)?);
+        }
+    }
+    entries.sort_by_key(|entry| entry.deliver_cnt);
+    Ok(entries)
+}
+
+fn append_wal(path: &Path, entries: &[WalEntry]) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    for entry in entries {
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
 #[derive(Default)]
 pub struct FullOrderMessageScheme {
-    ordered_list: LinkedList<(&'static str, String)>,
+    // the topic, key and payload
+    ordered_list: LinkedList<(&'static str, String, Vec<u8>)>,
+    // sent to the producer but not confirmed yet, with their deliver_cnt
+    in_flight: LinkedList<(u64, &'static str, Vec<u8>)>,
     //two counters is used to assigned and verify for delivery
     deliver_cnt: u64,
     commited_cnt: u64,
+    // the messages failed to deliver are appended here, and sent first on the next start
+    wal_path: Option<PathBuf>,
+    // DEFAULT_QUEUE_LIMIT if not set
+    queue_limit: Option<usize>,
+    // how the messenger encodes the messages, for their keys and the wal
+    format: MessageFormat,
+    counters: ProducerStats,
+}
+
+impl FullOrderMessageScheme {
+    // requeues the messages left in the wal ahead of the new ones
+    pub fn with_recovery(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut scheme = Self {
+            wal_path: Some(path.to_path_buf()),
+            ..Default::default()
+        };
+        for entry in read_wal(path)? {
+            match (known_topic(&entry.topic), entry.payload_bytes()) {
+                (Some(topic), Ok(payload)) => {
+                    let key = payload_key(entry.format, topic, &payload);
+                    scheme.ordered_list.push_back((topic, key, payload));
+                }
+                (None, _) => log::error!("message of unknown topic {} in the wal, MESSAGE LOST", entry.topic),
+                (_, Err(e)) => log::error!("malformed message {} in the wal: {}, MESSAGE LOST", entry.deliver_cnt, e),
+            }
+        }
+        if path.exists() {
+            // the recovered messages are spilled again if they still can not be delivered
+            std::fs::remove_file(path)?;
+            log::info!(
+                "{} undelivered messages recovered from {}",
+                scheme.ordered_list.len(),
+                path.display()
+            );
+        }
+        Ok(scheme)
+    }
+
+    pub fn with_queue_limit(mut self, limit: usize) -> Self {
+        self.queue_limit = Some(limit);
+        self
+    }
+
+    pub fn with_format(mut self, format: MessageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn write_wal(&self, entries: &[WalEntry]) {
+        if entries.is_empty() {
+            return;
+        }
+        match &self.wal_path {
+            Some(path) => match append_wal(path, entries) {
+                Ok(()) => log::warn!("{} undelivered messages spilled to {}", entries.len(), path.display()),
+                Err(e) => log::error!("write kafka wal failed: {}, {} MESSAGES LOST", e, entries.len()),
+            },
+            None => log::error!("no kafka wal, {} MESSAGES LOST", entries.len()),
+        }
+    }
 }
 
 impl MessageScheme for FullOrderMessageScheme {
     type DeliverOpaque = Box<u64>;
+    type Payload = [u8];
     type K = &'static str;
     type V = &'static str;
 
@@ -303,35 +854,46 @@ impl MessageScheme for FullOrderMessageScheme {
             ("delivery.timeout.ms", "2147483647"),
         ]
     }
+    fn protected_settings() -> Vec<&'static str> {
+        vec!["enable.idempotence", "max.in.flight.requests.per.connection", "delivery.timeout.ms"]
+    }
     fn is_full(&self) -> bool {
-        self.ordered_list.len() >= 100
+        self.ordered_list.len() >= self.queue_limit.unwrap_or(DEFAULT_QUEUE_LIMIT)
     }
 
-    fn on_message(&mut self, title_tip: &'static str, message: String) {
+    fn on_message(&mut self, title_tip: &'static str, message: Vec<u8>) {
         match title_tip {
-            DEPOSITS_TOPIC | INTERNALTX_TOPIC | ORDERS_TOPIC | TRADES_TOPIC | USER_TOPIC | WITHDRAWS_TOPIC => {
-                self.ordered_list.push_back((title_tip, message))
+            DEPOSITS_TOPIC | INTERNALTX_TOPIC | MARKETS_TOPIC | ORDERS_TOPIC | TRADES_TOPIC | USER_TOPIC | WITHDRAWS_TOPIC => {
+                let key = self.message_key(title_tip, &message);
+                self.ordered_list.push_back((title_tip, key, message))
             }
             _ => {}
         };
     }
+    fn message_key(&self, title_tip: &str, message: &[u8]) -> String {
+        payload_key(self.format, title_tip, message)
+    }
 
-    fn pop_up(&mut self) -> Option<BaseRecord<'_, str, str, Self::DeliverOpaque>> {
+    fn pop_up(&mut self) -> Option<BaseRecord<'_, str, [u8], Self::DeliverOpaque>> {
         if self.ordered_list.is_empty() {
             return None;
         }
-        let (title_tip, message) = self.ordered_list.front().unwrap();
+        let (title_tip, key, message) = self.ordered_list.front().unwrap();
+        // the key stays the topic for the consumers telling the message types by it
         Some(
             BaseRecord::with_opaque_to(UNIFY_TOPIC, Box::new(self.deliver_cnt))
                 .key(*title_tip)
-                .payload(AsRef::as_ref(message)),
+                .payload(message.as_slice())
+                .headers(OwnedHeaders::new().add(ENTITY_KEY_HEADER, key.as_str())),
         )
     }
 
     fn commit(&mut self, isfailed: Option<Self::DeliverOpaque>) {
         if isfailed.is_none() {
-            self.ordered_list.pop_front();
+            let (topic, _, message) = self.ordered_list.pop_front().unwrap();
+            self.in_flight.push_back((self.deliver_cnt, topic, message));
             self.deliver_cnt += 1;
+            self.counters.sent += 1;
         } else {
             //sanity check
             assert!(*isfailed.unwrap() == self.deliver_cnt);
@@ -340,12 +902,472 @@ impl MessageScheme for FullOrderMessageScheme {
     fn deliver_commit(&mut self, result: SimpleDeliverResult, opaque: Self::DeliverOpaque) {
         //sanity check: verify we are keeping order
         assert!(*opaque == self.commited_cnt);
+        let (deliver_cnt, topic, payload) = self.in_flight.pop_front().unwrap();
+        assert!(deliver_cnt == self.commited_cnt);
         self.commited_cnt += 1;
+        self.counters.on_delivered(&result);
         log::debug!("kafka unify messenger has confirm deliver till {}", self.commited_cnt);
 
         if let Err(e) = result {
-            //TODO: should we panic ?
-            log::error!("kafka send err: {}, MESSAGE LOST", e);
+            // the delivery never times out, so the failure is final
+            log::error!("kafka send err: {}, message {} spilled", e, deliver_cnt);
+            self.write_wal(&[WalEntry::new(deliver_cnt, topic, self.format, &payload)]);
+        }
+    }
+    // the ones in flight may have been delivered, so they may be sent twice
+    fn spill(&mut self) -> SpillReport {
+        let in_flight = std::mem::take(&mut self.in_flight);
+        let ordered = std::mem::take(&mut self.ordered_list);
+        let pending = ordered
+            .into_iter()
+            .zip(self.deliver_cnt..)
+            .map(|((topic, _, message), cnt)| (cnt, topic, message));
+        let entries = in_flight
+            .into_iter()
+            .chain(pending)
+            .map(|(deliver_cnt, topic, payload)| WalEntry::new(deliver_cnt, topic, self.format, &payload))
+            .collect::<Vec<_>>();
+        self.write_wal(&entries);
+        SpillReport::of(entries.iter().map(|entry| entry.deliver_cnt))
+    }
+    fn stats(&self) -> ProducerStats {
+        let mut queued = BTreeMap::new();
+        for (topic, _, _) in &self.ordered_list {
+            *queued.entry(topic.to_string()).or_default() += 1;
+        }
+        ProducerStats {
+            queued,
+            ..self.counters.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::dead_letter::DeadLetterQueue;
+
+    const TOPICS: [&str; 9] = [
+        BALANCES_TOPIC,
+        DEPOSITS_TOPIC,
+        FEES_TOPIC,
+        INTERNALTX_TOPIC,
+        MARKETS_TOPIC,
+        ORDERS_TOPIC,
+        TRADES_TOPIC,
+        USER_TOPIC,
+        WITHDRAWS_TOPIC,
+    ];
+
+    #[test]
+    fn test_simple_scheme_topics() {
+        let mut scheme = SimpleMessageScheme::default();
+        for (i, topic) in TOPICS.iter().enumerate() {
+            for n in 0..=i {
+                scheme.on_message(*topic, format!("{}-{}", topic, n).into());
+            }
+        }
+        assert!(!scheme.is_full());
+        for _ in 0..100 {
+            scheme.on_message(WITHDRAWS_TOPIC, "withdraw".into());
+        }
+        assert!(scheme.is_full());
+
+        // a failed message is popped again
+        let first = scheme.pop_up().map(|record| record.topic.to_string());
+        assert_eq!(first.as_deref(), Some(BALANCES_TOPIC));
+        scheme.commit(Some(Box::new(0)));
+
+        let mut popped = std::collections::BTreeMap::<String, usize>::new();
+        while let Some(topic) = scheme.pop_up().map(|record| record.topic.to_string()) {
+            *popped.entry(topic).or_default() += 1;
+            scheme.commit(None);
+        }
+        assert!(!scheme.is_full());
+        for (i, topic) in TOPICS.iter().enumerate() {
+            let expected = if *topic == WITHDRAWS_TOPIC { i + 1 + 100 } else { i + 1 };
+            assert_eq!(popped.get(*topic), Some(&expected), "{}", topic);
+        }
+    }
+
+    // pops and commits the messages, failing the ones in `failures` by their pops
+    fn publish_order(scheme: &mut SimpleMessageScheme, failures: &[usize]) -> Vec<String> {
+        let mut published = Vec::new();
+        let mut pops = 0;
+        while let Some(payload) = scheme.pop_up().and_then(|record| record.payload.map(str::to_string)) {
+            if failures.contains(&pops) {
+                scheme.commit(Some(Box::new(0)));
+            } else {
+                published.push(payload);
+                scheme.commit(None);
+            }
+            pops += 1;
+        }
+        published
+    }
+
+    #[test]
+    fn test_simple_scheme_order() {
+        let mut scheme = SimpleMessageScheme::default();
+        let messages = [
+            (ORDERS_TOPIC, "o1"),
+            (BALANCES_TOPIC, "b1"),
+            (TRADES_TOPIC, "t1"),
+            (BALANCES_TOPIC, "b2"),
+            (ORDERS_TOPIC, "o2"),
+            (FEES_TOPIC, "f1"),
+            (TRADES_TOPIC, "t2"),
+        ];
+        for (topic, payload) in messages.iter() {
+            scheme.on_message(*topic, payload.as_bytes().to_vec());
+        }
+        // published as they came, not drained by the longest topic
+        assert_eq!(publish_order(&mut scheme, &[]), vec!["o1", "b1", "t1", "b2", "o2", "f1", "t2"]);
+
+        // a failed message is sent again before the later ones of its topic and of the others
+        for (topic, payload) in messages.iter() {
+            scheme.on_message(*topic, payload.as_bytes().to_vec());
         }
+        assert_eq!(
+            publish_order(&mut scheme, &[0, 1, 4]),
+            vec!["o1", "b1", "t1", "b2", "o2", "f1", "t2"]
+        );
+
+        // a burst of a topic does not hold back an earlier message of another one
+        scheme.on_message(USER_TOPIC, "u1".into());
+        for n in 0..50 {
+            scheme.on_message(TRADES_TOPIC, format!("t{}", n).into());
+        }
+        scheme.on_message(ORDERS_TOPIC, "o1".into());
+        let published = publish_order(&mut scheme, &[]);
+        assert_eq!(published.first().map(String::as_str), Some("u1"));
+        assert_eq!(published.last().map(String::as_str), Some("o1"));
+        assert_eq!(published[1..51], (0..50).map(|n| format!("t{}", n)).collect::<Vec<_>>()[..]);
+    }
+
+    fn pop_all(scheme: &mut FullOrderMessageScheme) -> Vec<String> {
+        let mut payloads = Vec::new();
+        while let Some(payload) = scheme
+            .pop_up()
+            .and_then(|record| record.payload.map(|p| String::from_utf8(p.to_vec()).unwrap()))
+        {
+            payloads.push(payload);
+            scheme.commit(None);
+        }
+        payloads
+    }
+
+    #[test]
+    fn test_full_order_wal() {
+        let dir = std::env::temp_dir().join(format!("test_full_order_wal_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kafka.wal");
+        std::fs::remove_file(&path).ok();
+
+        let mut scheme = FullOrderMessageScheme::with_recovery(&path).unwrap();
+        for n in 0..6 {
+            scheme.on_message(if n % 2 == 0 { ORDERS_TOPIC } else { TRADES_TOPIC }, format!("m{}", n).into());
+        }
+        // 0..3 are sent, 0 is delivered and 1 fails
+        for _ in 0..3 {
+            assert!(scheme.pop_up().is_some());
+            scheme.commit(None);
+        }
+        scheme.deliver_commit(Ok(()), Box::new(0));
+        let failure = KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut);
+        scheme.deliver_commit(Err(failure), Box::new(1));
+        assert_eq!(read_wal(&path).unwrap().len(), 1);
+        // the producer stops with 2 in flight and 3..6 not sent
+        scheme.spill();
+        let entries = read_wal(&path).unwrap();
+        assert_eq!(
+            entries.iter().map(|entry| entry.deliver_cnt).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(entries[0].topic, TRADES_TOPIC);
+
+        // the recovered messages are sent first, in the same order
+        let mut scheme = FullOrderMessageScheme::with_recovery(&path).unwrap();
+        assert!(!path.exists());
+        scheme.on_message(ORDERS_TOPIC, "m6".into());
+        assert_eq!(pop_all(&mut scheme), vec!["m1", "m2", "m3", "m4", "m5", "m6"]);
+        for cnt in 0..6 {
+            scheme.deliver_commit(Ok(()), Box::new(cnt));
+        }
+        scheme.spill();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_protobuf_wal() {
+        use crate::message::proto::{Event, Kind, User};
+        use fluidex_common::rdkafka::message::Headers;
+
+        let dir = std::env::temp_dir().join(format!("test_protobuf_wal_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kafka.wal");
+        std::fs::remove_file(&path).ok();
+
+        let user = Kind::User(User {
+            user_id: 9,
+            ..Default::default()
+        });
+        let bytes = Event::new(1, 0.0, user).to_bytes();
+        let mut scheme = FullOrderMessageScheme::with_recovery(&path)
+            .unwrap()
+            .with_format(MessageFormat::Protobuf);
+        scheme.on_message(USER_TOPIC, bytes.clone());
+        scheme.spill();
+        let entries = read_wal(&path).unwrap();
+        assert_eq!(entries[0].format, MessageFormat::Protobuf);
+        assert_eq!(entries[0].payload, hex::encode(&bytes));
+
+        // recovered as the same bytes, keyed by the decoded user
+        let mut scheme = FullOrderMessageScheme::with_recovery(&path).unwrap();
+        let record = scheme.pop_up().unwrap();
+        assert_eq!(record.payload, Some(bytes.as_slice()));
+        let header = record.headers.as_ref().and_then(|headers| headers.get(0));
+        assert_eq!(header, Some((ENTITY_KEY_HEADER, "9".as_bytes())));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_message_keys() {
+        use fluidex_common::rdkafka::message::Headers;
+        use serde_json::json;
+
+        let trade = json!({"id": 1, "market": "ETH_USDT", "ask_user_id": 1, "bid_user_id": 2}).to_string();
+        let order = json!({"event": "PUT", "order": {"id": 3, "market": "BTC_USDT", "user": 4}, "base": "BTC"}).to_string();
+        let balance = json!({"user_id": 5, "asset": "USDT", "change": "1"}).to_string();
+        let transfer = json!({"user_from": 6, "user_to": 7, "asset": "USDT"}).to_string();
+        assert_eq!(entity_key(TRADES_TOPIC, &trade), "ETH_USDT");
+        assert_eq!(entity_key(ORDERS_TOPIC, &order), "BTC_USDT");
+        assert_eq!(entity_key(BALANCES_TOPIC, &balance), "5");
+        assert_eq!(entity_key(DEPOSITS_TOPIC, &balance), "5");
+        assert_eq!(entity_key(WITHDRAWS_TOPIC, &balance), "5");
+        assert_eq!(entity_key(INTERNALTX_TOPIC, &transfer), "6");
+        // no key for a missing field or a malformed payload
+        assert_eq!(entity_key(ORDERS_TOPIC, &trade), "");
+        assert_eq!(entity_key(TRADES_TOPIC, "not json"), "");
+
+        let mut scheme = SimpleMessageScheme::default();
+        scheme.on_message(TRADES_TOPIC, trade.clone().into());
+        let key = scheme.pop_up().and_then(|record| record.key.map(str::to_string));
+        assert_eq!(key.as_deref(), Some("ETH_USDT"));
+        // a failed message keeps its key
+        scheme.commit(Some(Box::new(0)));
+        let key = scheme.pop_up().and_then(|record| record.key.map(str::to_string));
+        assert_eq!(key.as_deref(), Some("ETH_USDT"));
+
+        // the unify events are keyed by the topic, with the entity in a header
+        let mut scheme = FullOrderMessageScheme::default();
+        scheme.on_message(TRADES_TOPIC, trade.into());
+        let record = scheme.pop_up().unwrap();
+        assert_eq!(record.key, Some(TRADES_TOPIC));
+        let header = record.headers.as_ref().and_then(|headers| headers.get(0));
+        assert_eq!(header, Some((ENTITY_KEY_HEADER, "ETH_USDT".as_bytes())));
+    }
+
+    fn record_of(scheme: &mut SimpleMessageScheme) -> Option<(String, String, String)> {
+        scheme.pop_up().map(|record| {
+            let key = record.key.unwrap_or_default().to_string();
+            (record.topic.to_string(), key, record.payload.unwrap_or_default().to_string())
+        })
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let dir = std::env::temp_dir().join(format!("test_dead_letters_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dead_letters.txt");
+        std::fs::remove_file(&path).ok();
+        let dead_letters = DeadLetterQueue::new(3, Some(path.clone())).shared();
+        let mut scheme = SimpleMessageScheme::with_dead_letters(dead_letters.clone());
+
+        let messages = [
+            (TRADES_TOPIC, r#"{"market":"ETH_USDT","id":1}"#),
+            (BALANCES_TOPIC, r#"{"user_id":2,"change":"1.50"}"#),
+            (ORDERS_TOPIC, r#"{"order":{"market":"BTC_USDT","id":3}}"#),
+        ];
+        let mut sent = Vec::new();
+        for (topic, payload) in messages.iter() {
+            scheme.on_message(*topic, payload.as_bytes().to_vec());
+            sent.push(record_of(&mut scheme).unwrap());
+            scheme.commit(None);
+        }
+        // the first and the last deliveries fail
+        let failure = || Err(KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut));
+        scheme.deliver_commit(failure(), Box::new(0));
+        scheme.deliver_commit(Ok(()), Box::new(1));
+        scheme.deliver_commit(failure(), Box::new(2));
+        assert_eq!(dead_letters.lock().unwrap().len(), 2);
+
+        // a message out of retries is a dead letter too
+        scheme.on_message(DEPOSITS_TOPIC, r#"{"user_id":4}"#.into());
+        for _ in 0..MAX_SEND_RETRIES {
+            sent.push(record_of(&mut scheme).unwrap());
+            scheme.commit(Some(Box::new(3)));
+        }
+        assert!(record_of(&mut scheme).is_none());
+        {
+            let queue = dead_letters.lock().unwrap();
+            assert_eq!((queue.len(), queue.total(), queue.evicted()), (3, 3, 0));
+        }
+        let lines = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(lines.lines().count(), 3);
+
+        // enqueued again, the records are the same as the ones sent
+        assert_eq!(scheme.drain_dead_letters(), 3);
+        assert!(dead_letters.lock().unwrap().is_empty());
+        let mut requeued = std::iter::from_fn(|| record_of(&mut scheme)).collect::<Vec<_>>();
+        requeued.sort();
+        let mut expected = vec![sent[0].clone(), sent[2].clone(), sent.last().unwrap().clone()];
+        expected.sort();
+        assert_eq!(requeued, expected);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_producer_stats() {
+        let mut scheme = SimpleMessageScheme::default().with_queue_limit(2);
+        scheme.on_message(TRADES_TOPIC, "t1".into());
+        scheme.on_message(ORDERS_TOPIC, "o1".into());
+        assert!(!scheme.is_full());
+        scheme.on_message(TRADES_TOPIC, "t2".into());
+        assert!(scheme.is_full());
+        for _ in 0..2 {
+            assert!(scheme.pop_up().is_some());
+            scheme.commit(None);
+        }
+        scheme.deliver_commit(Ok(()), Box::new(0));
+        let failure = KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut);
+        scheme.deliver_commit(Err(failure), Box::new(1));
+
+        let stats = scheme.stats();
+        assert_eq!((stats.sent, stats.delivery_failures), (2, 1));
+        assert!(stats.last_delivered.is_some());
+        assert_eq!(stats.queued_total(), 1);
+        assert_eq!(stats.queued.get(TRADES_TOPIC), Some(&1));
+
+        let mut scheme = FullOrderMessageScheme::default().with_queue_limit(2);
+        scheme.on_message(TRADES_TOPIC, "t1".into());
+        scheme.on_message(USER_TOPIC, "u1".into());
+        assert!(scheme.is_full());
+        assert!(scheme.pop_up().is_some());
+        scheme.commit(None);
+        let stats = scheme.stats();
+        assert_eq!((stats.sent, stats.delivery_failures, stats.last_delivered), (1, 0, None));
+        assert_eq!(stats.queued.into_iter().collect::<Vec<_>>(), vec![(USER_TOPIC.to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_merge_settings() {
+        let overrides = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+        let get = |settings: &[(String, String)], key: &str| settings.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+
+        // the deployment overrides the defaults of the scheme and adds its own
+        let settings = merge_settings::<SimpleMessageScheme>(&overrides(&[("queue.buffering.max.ms", "5"), ("linger.ms", "2")])).unwrap();
+        assert_eq!(settings.len(), 2);
+        assert_eq!(get(&settings, "queue.buffering.max.ms").as_deref(), Some("5"));
+        assert_eq!(get(&settings, "linger.ms").as_deref(), Some("2"));
+        assert!(merge_settings::<SimpleMessageScheme>(&overrides(&[("bootstrap.servers", "other:9092")])).is_err());
+
+        // the ordering settings of the full order scheme are kept
+        let err = merge_settings::<FullOrderMessageScheme>(&overrides(&[("enable.idempotence", "false")])).unwrap_err();
+        assert!(err.to_string().contains("enable.idempotence"));
+        let settings = merge_settings::<FullOrderMessageScheme>(&overrides(&[("enable.idempotence", "true"), ("linger.ms", "2")])).unwrap();
+        assert_eq!(get(&settings, "max.in.flight.requests.per.connection").as_deref(), Some("1"));
+        // the protected settings are free for the other schemes
+        assert!(merge_settings::<SimpleMessageScheme>(&overrides(&[("enable.idempotence", "false")])).is_ok());
+
+        // security
+        let sasl = [
+            ("security.protocol", "SASL_SSL"),
+            ("sasl.mechanism", "SCRAM-SHA-512"),
+            ("sasl.username", "engine"),
+            ("sasl.password", "secret"),
+            ("ssl.ca.location", "/etc/kafka/ca.pem"),
+        ];
+        assert!(merge_settings::<FullOrderMessageScheme>(&overrides(&sasl)).is_ok());
+        assert!(merge_settings::<SimpleMessageScheme>(&overrides(&sasl[..3])).is_err());
+        assert!(merge_settings::<SimpleMessageScheme>(&overrides(&sasl[..1])).is_err());
+        assert!(merge_settings::<SimpleMessageScheme>(&overrides(&[("security.protocol", "tls")])).is_err());
+        assert!(merge_settings::<SimpleMessageScheme>(&overrides(&[("sasl.username", "engine")])).is_err());
+        assert!(merge_settings::<SimpleMessageScheme>(&overrides(&[
+            ("security.protocol", "sasl_plaintext"),
+            ("ssl.ca.location", "ca.pem")
+        ]))
+        .is_err());
+        let gssapi = [("security.protocol", "sasl_plaintext"), ("sasl.mechanisms", "GSSAPI")];
+        assert!(merge_settings::<SimpleMessageScheme>(&overrides(&gssapi)).is_ok());
+    }
+
+    // kafka is down, no message is taken
+    struct QueueFullTarget;
+
+    impl<T: MessageScheme> DrainTarget<T> for QueueFullTarget {
+        fn send(
+            &self,
+            record: BaseRecord<'_, str, T::Payload, T::DeliverOpaque>,
+        ) -> std::result::Result<(), (KafkaError, T::DeliverOpaque)> {
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), record.delivery_opaque))
+        }
+        fn poll(&self, timeout: Duration) {
+            std::thread::sleep(timeout.min(Duration::from_millis(1)));
+        }
+        fn flush(&self, _timeout: Duration) -> bool {
+            false
+        }
+        fn commit_deliveries(&self, _message_scheme: &mut T) {}
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        let dir = std::env::temp_dir().join(format!("test_drain_timeout_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kafka.wal");
+        std::fs::remove_file(&path).ok();
+
+        let mut scheme = FullOrderMessageScheme::with_recovery(&path).unwrap();
+        for n in 0..5 {
+            scheme.on_message(ORDERS_TOPIC, format!("m{}", n).into());
+        }
+        // 0..2 are sent but not delivered when the producer stops
+        for _ in 0..2 {
+            assert!(scheme.pop_up().is_some());
+            scheme.commit(None);
+        }
+        let start = Instant::now();
+        let report = drain(&QueueFullTarget, &mut scheme, Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            report,
+            SpillReport {
+                count: 5,
+                first: Some(0),
+                last: Some(4),
+            }
+        );
+        let entries = read_wal(&path).unwrap();
+        assert_eq!(
+            entries.iter().map(|entry| entry.deliver_cnt).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+
+        // the simple scheme spills to the dead letters
+        let dead_letters = DeadLetterQueue::default().shared();
+        let mut scheme = SimpleMessageScheme::with_dead_letters(dead_letters.clone());
+        scheme.on_message(ORDERS_TOPIC, "o".into());
+        scheme.on_message(BALANCES_TOPIC, "b".into());
+        assert!(scheme.pop_up().is_some());
+        scheme.commit(None);
+        let report = scheme.spill();
+        assert_eq!((report.count, report.first, report.last), (2, Some(0), Some(1)));
+        let letters = dead_letters.lock().unwrap().drain();
+        assert_eq!(letters.len(), 2);
+        assert!(letters.iter().any(|letter| letter.error.contains("not confirmed")));
+        assert!(letters.iter().any(|letter| letter.error.contains("not sent")));
+        std::fs::remove_dir_all(&dir).ok();
     }
 }
@@ -0,0 +1,1363 @@
+// the protobuf encoding of the messages, for the consumers of the unify events which find json too large or slow.
+// the decimals are kept as strings and the enums as their names in json, so both formats carry the same values.
+// the orders have their own message since the OrderInfo of orchestra lacks the fields to rebuild one
+use crate::audit::{ConservationViolation as AuditViolation, FrozenMismatch as AuditMismatch};
+use crate::config::KlineInterval;
+use crate::market;
+use crate::message::{self as msg, Envelope};
+use crate::persist;
+use crate::types::TimestampMs;
+use crate::utils::{intern_string, InternedString};
+
+use anyhow::{anyhow, bail, Result};
+use fluidex_common::rust_decimal::Decimal;
+use prost::Message as _;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::{TryFrom, TryInto};
+use std::str::FromStr;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Order {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(string, tag = "2")]
+    pub base: String,
+    #[prost(string, tag = "3")]
+    pub quote: String,
+    #[prost(string, tag = "4")]
+    pub market: String,
+    #[prost(string, tag = "5")]
+    pub order_type: String,
+    #[prost(string, tag = "6")]
+    pub side: String,
+    #[prost(uint32, tag = "7")]
+    pub user: u32,
+    #[prost(bool, tag = "8")]
+    pub post_only: bool,
+    #[prost(bytes = "vec", tag = "9")]
+    pub signature: Vec<u8>,
+    #[prost(string, tag = "10")]
+    pub price: String,
+    #[prost(string, tag = "11")]
+    pub amount: String,
+    #[prost(string, tag = "12")]
+    pub maker_fee: String,
+    #[prost(string, tag = "13")]
+    pub taker_fee: String,
+    #[prost(double, tag = "14")]
+    pub create_time: f64,
+    #[prost(uint64, tag = "15")]
+    pub priority: u64,
+    #[prost(uint64, optional, tag = "16")]
+    pub client_order_id: Option<u64>,
+    #[prost(string, tag = "17")]
+    pub remain: String,
+    #[prost(string, tag = "18")]
+    pub frozen: String,
+    #[prost(string, tag = "19")]
+    pub finished_base: String,
+    #[prost(string, tag = "20")]
+    pub finished_quote: String,
+    #[prost(string, tag = "21")]
+    pub finished_fee: String,
+    #[prost(double, tag = "22")]
+    pub update_time: f64,
+    // the json of the fill outcome
+    #[prost(string, optional, tag = "23")]
+    pub fill_outcome: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrderMessage {
+    #[prost(string, tag = "1")]
+    pub event: String,
+    #[prost(message, optional, tag = "2")]
+    pub order: Option<Order>,
+    #[prost(string, tag = "3")]
+    pub base: String,
+    #[prost(string, tag = "4")]
+    pub quote: String,
+    #[prost(string, optional, tag = "5")]
+    pub cancel_reason: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub finish_reason: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub finish_actor: Option<String>,
+    #[prost(message, optional, tag = "8")]
+    pub order_before: Option<Order>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DiscountFee {
+    #[prost(string, tag = "1")]
+    pub asset: String,
+    #[prost(string, tag = "2")]
+    pub amount: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrderState {
+    #[prost(uint32, tag = "1")]
+    pub user_id: u32,
+    #[prost(uint64, tag = "2")]
+    pub order_id: u64,
+    #[prost(string, tag = "3")]
+    pub order_side: String,
+    #[prost(string, tag = "4")]
+    pub finished_base: String,
+    #[prost(string, tag = "5")]
+    pub finished_quote: String,
+    #[prost(string, tag = "6")]
+    pub finished_fee: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BalanceState {
+    #[prost(uint32, tag = "1")]
+    pub user_id: u32,
+    #[prost(string, tag = "2")]
+    pub asset: String,
+    #[prost(string, tag = "3")]
+    pub balance: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TradeState {
+    #[prost(message, repeated, tag = "1")]
+    pub order_states: Vec<OrderState>,
+    #[prost(message, repeated, tag = "2")]
+    pub balance_states: Vec<BalanceState>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Trade {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(double, tag = "2")]
+    pub timestamp: f64,
+    #[prost(string, tag = "3")]
+    pub market: String,
+    #[prost(string, tag = "4")]
+    pub base: String,
+    #[prost(string, tag = "5")]
+    pub quote: String,
+    #[prost(string, tag = "6")]
+    pub price: String,
+    #[prost(string, tag = "7")]
+    pub amount: String,
+    #[prost(string, tag = "8")]
+    pub quote_amount: String,
+    #[prost(uint32, tag = "9")]
+    pub ask_user_id: u32,
+    #[prost(uint64, tag = "10")]
+    pub ask_order_id: u64,
+    #[prost(string, tag = "11")]
+    pub ask_role: String,
+    #[prost(string, tag = "12")]
+    pub ask_fee: String,
+    #[prost(uint32, tag = "13")]
+    pub bid_user_id: u32,
+    #[prost(uint64, tag = "14")]
+    pub bid_order_id: u64,
+    #[prost(string, tag = "15")]
+    pub bid_role: String,
+    #[prost(string, tag = "16")]
+    pub bid_fee: String,
+    #[prost(message, optional, tag = "17")]
+    pub discount_fee: Option<DiscountFee>,
+    #[prost(message, optional, tag = "18")]
+    pub ask_order: Option<Order>,
+    #[prost(message, optional, tag = "19")]
+    pub bid_order: Option<Order>,
+    // none unless the engine emits the state diffs
+    #[prost(message, optional, tag = "20")]
+    pub state_before: Option<TradeState>,
+    #[prost(message, optional, tag = "21")]
+    pub state_after: Option<TradeState>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Fee {
+    #[prost(uint64, tag = "1")]
+    pub trade_id: u64,
+    #[prost(double, tag = "2")]
+    pub timestamp: f64,
+    #[prost(string, tag = "3")]
+    pub market: String,
+    #[prost(uint32, tag = "4")]
+    pub user_id: u32,
+    #[prost(uint64, tag = "5")]
+    pub order_id: u64,
+    #[prost(string, tag = "6")]
+    pub role: String,
+    #[prost(string, tag = "7")]
+    pub asset: String,
+    #[prost(string, tag = "8")]
+    pub amount: String,
+    #[prost(string, tag = "9")]
+    pub rate: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DepthUpdate {
+    #[prost(string, tag = "1")]
+    pub market: String,
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+    #[prost(string, tag = "3")]
+    pub side: String,
+    #[prost(string, tag = "4")]
+    pub price: String,
+    #[prost(string, tag = "5")]
+    pub new_amount: String,
+    #[prost(uint32, optional, tag = "6")]
+    pub checksum: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Kline {
+    #[prost(string, tag = "1")]
+    pub market: String,
+    #[prost(string, tag = "2")]
+    pub interval: String,
+    #[prost(uint64, tag = "3")]
+    pub start: u64,
+    #[prost(string, tag = "4")]
+    pub open: String,
+    #[prost(string, tag = "5")]
+    pub high: String,
+    #[prost(string, tag = "6")]
+    pub low: String,
+    #[prost(string, tag = "7")]
+    pub close: String,
+    #[prost(string, tag = "8")]
+    pub volume: String,
+    #[prost(string, tag = "9")]
+    pub quote_volume: String,
+    #[prost(uint64, tag = "10")]
+    pub trade_count: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MarketEvent {
+    #[prost(double, tag = "1")]
+    pub timestamp: f64,
+    #[prost(string, tag = "2")]
+    pub market: String,
+    // the event and its fields in json, they are rare and differ by the event
+    #[prost(string, tag = "3")]
+    pub kind: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConservationViolation {
+    #[prost(double, tag = "1")]
+    pub timestamp: f64,
+    #[prost(string, tag = "2")]
+    pub operation: String,
+    #[prost(string, tag = "3")]
+    pub asset: String,
+    #[prost(string, tag = "4")]
+    pub expected_change: String,
+    #[prost(string, tag = "5")]
+    pub actual_change: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FrozenMismatch {
+    #[prost(uint32, tag = "1")]
+    pub user_id: u32,
+    #[prost(string, tag = "2")]
+    pub asset: String,
+    #[prost(string, tag = "3")]
+    pub expected: String,
+    #[prost(string, tag = "4")]
+    pub actual: String,
+    #[prost(string, tag = "5")]
+    pub delta: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Tombstone {
+    #[prost(double, tag = "1")]
+    pub time: f64,
+    #[prost(uint64, tag = "2")]
+    pub from: u64,
+    #[prost(uint64, tag = "3")]
+    pub to: u64,
+    #[prost(uint64, tag = "4")]
+    pub order_id: u64,
+    #[prost(uint64, tag = "5")]
+    pub trade_id: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OperationLog {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(double, tag = "2")]
+    pub time: f64,
+    #[prost(string, tag = "3")]
+    pub method: String,
+    #[prost(string, tag = "4")]
+    pub params: String,
+    #[prost(bool, tag = "5")]
+    pub failed: bool,
+}
+
+// the balance, deposit and withdraw messages, the deposits and withdraws leave the fields they lack empty
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Balance {
+    #[prost(double, tag = "1")]
+    pub timestamp: f64,
+    #[prost(uint32, tag = "2")]
+    pub user_id: u32,
+    #[prost(uint64, tag = "3")]
+    pub business_id: u64,
+    #[prost(string, tag = "4")]
+    pub asset: String,
+    #[prost(string, tag = "5")]
+    pub business: String,
+    #[prost(string, tag = "6")]
+    pub market_price: String,
+    #[prost(string, tag = "7")]
+    pub change: String,
+    #[prost(string, tag = "8")]
+    pub balance: String,
+    #[prost(string, tag = "9")]
+    pub balance_available: String,
+    #[prost(string, tag = "10")]
+    pub balance_frozen: String,
+    #[prost(string, tag = "11")]
+    pub detail: String,
+    #[prost(string, tag = "12")]
+    pub signature: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Transfer {
+    #[prost(double, tag = "1")]
+    pub time: f64,
+    #[prost(uint32, tag = "2")]
+    pub user_from: u32,
+    #[prost(uint32, tag = "3")]
+    pub user_to: u32,
+    #[prost(string, tag = "4")]
+    pub asset: String,
+    #[prost(string, tag = "5")]
+    pub amount: String,
+    #[prost(string, tag = "6")]
+    pub signature: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct User {
+    #[prost(uint32, tag = "1")]
+    pub user_id: u32,
+    #[prost(string, tag = "2")]
+    pub l1_address: String,
+    #[prost(string, tag = "3")]
+    pub l2_pubkey: String,
+}
+
+// a message with its seq and timestamp, the seq is zero unless the messages are enveloped
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Event {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(double, tag = "2")]
+    pub ts: f64,
+    #[prost(oneof = "Kind", tags = "3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17")]
+    pub kind: Option<Kind>,
+}
+
+// the variants of `message::Message`
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Kind {
+    #[prost(message, tag = "3")]
+    Balance(Balance),
+    #[prost(message, tag = "4")]
+    Deposit(Balance),
+    #[prost(message, tag = "5")]
+    Order(OrderMessage),
+    #[prost(message, tag = "6")]
+    Trade(Trade),
+    #[prost(message, tag = "7")]
+    Fee(Fee),
+    #[prost(message, tag = "8")]
+    DepthUpdate(DepthUpdate),
+    #[prost(message, tag = "9")]
+    Kline(Kline),
+    #[prost(message, tag = "10")]
+    MarketEvent(MarketEvent),
+    #[prost(message, tag = "11")]
+    ConservationViolation(ConservationViolation),
+    #[prost(message, tag = "12")]
+    FrozenDeficit(FrozenMismatch),
+    #[prost(message, tag = "13")]
+    Transfer(Transfer),
+    #[prost(message, tag = "14")]
+    User(User),
+    #[prost(message, tag = "15")]
+    Withdraw(Balance),
+    #[prost(message, tag = "16")]
+    Tombstone(Tombstone),
+    #[prost(message, tag = "17")]
+    OperationLog(OperationLog),
+}
+
+impl Kind {
+    // the same as the type of the json message
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Balance(_) => "BalanceMessage",
+            Kind::Deposit(_) => "DepositMessage",
+            Kind::Order(_) => "OrderMessage",
+            Kind::Trade(_) => "TradeMessage",
+            Kind::Fee(_) => "FeeMessage",
+            Kind::DepthUpdate(_) => "DepthUpdateMessage",
+            Kind::Kline(_) => "KlineMessage",
+            Kind::MarketEvent(_) => "MarketEventMessage",
+            Kind::ConservationViolation(_) => "ConservationViolationMessage",
+            Kind::FrozenDeficit(_) => "FrozenDeficitMessage",
+            Kind::Transfer(_) => "TransferMessage",
+            Kind::User(_) => "UserMessage",
+            Kind::Withdraw(_) => "WithdrawMessage",
+            Kind::Tombstone(_) => "TombstoneMessage",
+            Kind::OperationLog(_) => "OperationLogMessage",
+        }
+    }
+}
+
+impl Event {
+    pub fn new(seq: u64, ts: f64, kind: Kind) -> Self {
+        Self { seq, ts, kind: Some(kind) }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        // a vec grows as needed
+        self.encode(&mut buf).unwrap();
+        buf
+    }
+}
+
+// the messages the messenger sends
+pub trait ToKind {
+    fn to_kind(&self) -> Kind;
+}
+
+// the payload of an event, like the one of an `Envelope`
+pub fn decode(bytes: &[u8]) -> Result<Envelope<msg::Message>> {
+    let event = Event::decode(bytes)?;
+    let kind = event.kind.ok_or_else(|| anyhow!("event without a message"))?;
+    Ok(Envelope {
+        seq: event.seq,
+        ts: event.ts,
+        kind: kind.name().to_string(),
+        payload: kind.try_into()?,
+    })
+}
+
+// the same as `producer::entity_key` for the json events
+pub fn entity_key(bytes: &[u8]) -> String {
+    let kind = match Event::decode(bytes) {
+        Ok(Event { kind: Some(kind), .. }) => kind,
+        _ => return String::new(),
+    };
+    match kind {
+        Kind::Order(order) => order.order.map(|order| order.market).unwrap_or_default(),
+        Kind::Trade(Trade { market, .. }) | Kind::Fee(Fee { market, .. }) | Kind::MarketEvent(MarketEvent { market, .. }) => market,
+        Kind::Balance(balance) | Kind::Deposit(balance) | Kind::Withdraw(balance) => balance.user_id.to_string(),
+        Kind::User(user) => user.user_id.to_string(),
+        Kind::Transfer(tx) => tx.user_from.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn enum_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+fn parse_enum<T: DeserializeOwned>(name: &str) -> Result<T> {
+    serde_json::from_value(serde_json::Value::String(name.to_string())).map_err(|_| anyhow!("invalid enum value {}", name))
+}
+
+fn parse_decimal(s: &str) -> Result<Decimal> {
+    Decimal::from_str(s).map_err(|_| anyhow!("invalid decimal {}", s))
+}
+
+fn intern(s: &str) -> InternedString {
+    intern_string(s).into()
+}
+
+impl From<&market::Order> for Order {
+    fn from(o: &market::Order) -> Self {
+        Self {
+            id: o.id,
+            base: o.base.to_string(),
+            quote: o.quote.to_string(),
+            market: o.market.to_string(),
+            order_type: enum_name(&o.type_),
+            side: enum_name(&o.side),
+            user: o.user,
+            post_only: o.post_only,
+            signature: o.signature.to_vec(),
+            price: o.price.to_string(),
+            amount: o.amount.to_string(),
+            maker_fee: o.maker_fee.to_string(),
+            taker_fee: o.taker_fee.to_string(),
+            create_time: o.create_time.as_secs_f64(),
+            priority: o.priority,
+            client_order_id: o.client_order_id,
+            remain: o.remain.to_string(),
+            frozen: o.frozen.to_string(),
+            finished_base: o.finished_base.to_string(),
+            finished_quote: o.finished_quote.to_string(),
+            finished_fee: o.finished_fee.to_string(),
+            update_time: o.update_time.as_secs_f64(),
+            fill_outcome: o.fill_outcome.as_ref().map(|outcome| serde_json::to_string(outcome).unwrap()),
+        }
+    }
+}
+
+impl TryFrom<Order> for market::Order {
+    type Error = anyhow::Error;
+
+    fn try_from(o: Order) -> Result<Self> {
+        if o.signature.len() != 64 {
+            bail!("invalid signature length {}", o.signature.len());
+        }
+        let mut signature = [0; 64];
+        signature.copy_from_slice(&o.signature);
+        Ok(Self {
+            id: o.id,
+            base: intern(&o.base),
+            quote: intern(&o.quote),
+            market: intern(&o.market),
+            type_: parse_enum(&o.order_type)?,
+            side: parse_enum(&o.side)?,
+            user: o.user,
+            post_only: o.post_only,
+            signature,
+            price: parse_decimal(&o.price)?,
+            amount: parse_decimal(&o.amount)?,
+            maker_fee: parse_decimal(&o.maker_fee)?,
+            taker_fee: parse_decimal(&o.taker_fee)?,
+            create_time: TimestampMs::from_secs_f64(o.create_time),
+            priority: o.priority,
+            client_order_id: o.client_order_id,
+            remain: parse_decimal(&o.remain)?,
+            frozen: parse_decimal(&o.frozen)?,
+            finished_base: parse_decimal(&o.finished_base)?,
+            finished_quote: parse_decimal(&o.finished_quote)?,
+            finished_fee: parse_decimal(&o.finished_fee)?,
+            update_time: TimestampMs::from_secs_f64(o.update_time),
+            fill_outcome: o
+                .fill_outcome
+                .as_deref()
+                .map(|json| serde_json::from_str(json).map_err(|_| anyhow!("invalid fill outcome {}", json)))
+                .transpose()?,
+        })
+    }
+}
+
+impl From<&msg::OrderMessage> for OrderMessage {
+    fn from(o: &msg::OrderMessage) -> Self {
+        Self {
+            event: enum_name(&o.event),
+            order: Some((&o.order).into()),
+            base: o.base.clone(),
+            quote: o.quote.clone(),
+            cancel_reason: o.cancel_reason.as_ref().map(enum_name),
+            finish_reason: o.finish_reason.as_ref().map(enum_name),
+            finish_actor: o.finish_actor.as_ref().map(enum_name),
+            order_before: o.order_before.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl TryFrom<OrderMessage> for msg::OrderMessage {
+    type Error = anyhow::Error;
+
+    fn try_from(o: OrderMessage) -> Result<Self> {
+        Ok(Self {
+            event: parse_enum(&o.event)?,
+            order: o.order.ok_or_else(|| anyhow!("order message without the order"))?.try_into()?,
+            base: o.base,
+            quote: o.quote,
+            cancel_reason: o.cancel_reason.as_deref().map(parse_enum).transpose()?,
+            finish_reason: o.finish_reason.as_deref().map(parse_enum).transpose()?,
+            finish_actor: o.finish_actor.as_deref().map(parse_enum).transpose()?,
+            order_before: o.order_before.map(TryInto::try_into).transpose()?,
+        })
+    }
+}
+
+impl From<&market::VerboseTradeState> for TradeState {
+    fn from(state: &market::VerboseTradeState) -> Self {
+        Self {
+            order_states: state
+                .order_states
+                .iter()
+                .map(|s| OrderState {
+                    user_id: s.user_id,
+                    order_id: s.order_id,
+                    order_side: enum_name(&s.order_side),
+                    finished_base: s.finished_base.to_string(),
+                    finished_quote: s.finished_quote.to_string(),
+                    finished_fee: s.finished_fee.to_string(),
+                })
+                .collect(),
+            balance_states: state
+                .balance_states
+                .iter()
+                .map(|s| BalanceState {
+                    user_id: s.user_id,
+                    asset: s.asset.to_string(),
+                    balance: s.balance.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<TradeState> for market::VerboseTradeState {
+    type Error = anyhow::Error;
+
+    fn try_from(state: TradeState) -> Result<Self> {
+        let order_states = state
+            .order_states
+            .into_iter()
+            .map(|s| {
+                Ok(market::VerboseOrderState {
+                    user_id: s.user_id,
+                    order_id: s.order_id,
+                    order_side: parse_enum(&s.order_side)?,
+                    finished_base: parse_decimal(&s.finished_base)?,
+                    finished_quote: parse_decimal(&s.finished_quote)?,
+                    finished_fee: parse_decimal(&s.finished_fee)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let balance_states = state
+            .balance_states
+            .into_iter()
+            .map(|s| {
+                Ok(market::VerboseBalanceState {
+                    user_id: s.user_id,
+                    asset: intern(&s.asset),
+                    balance: parse_decimal(&s.balance)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            order_states,
+            balance_states,
+        })
+    }
+}
+
+impl From<&market::Trade> for Trade {
+    fn from(t: &market::Trade) -> Self {
+        Self {
+            id: t.id,
+            timestamp: t.timestamp.as_secs_f64(),
+            market: t.market.clone(),
+            base: t.base.clone(),
+            quote: t.quote.clone(),
+            price: t.price.to_string(),
+            amount: t.amount.to_string(),
+            quote_amount: t.quote_amount.to_string(),
+            ask_user_id: t.ask_user_id,
+            ask_order_id: t.ask_order_id,
+            ask_role: enum_name(&t.ask_role),
+            ask_fee: t.ask_fee.to_string(),
+            bid_user_id: t.bid_user_id,
+            bid_order_id: t.bid_order_id,
+            bid_role: enum_name(&t.bid_role),
+            bid_fee: t.bid_fee.to_string(),
+            discount_fee: t.discount_fee.as_ref().map(|fee| DiscountFee {
+                asset: fee.asset.clone(),
+                amount: fee.amount.to_string(),
+            }),
+            ask_order: t.ask_order.as_ref().map(Into::into),
+            bid_order: t.bid_order.as_ref().map(Into::into),
+            #[cfg(feature = "emit_state_diff")]
+            state_before: Some((&t.state_before).into()),
+            #[cfg(feature = "emit_state_diff")]
+            state_after: Some((&t.state_after).into()),
+            #[cfg(not(feature = "emit_state_diff"))]
+            state_before: None,
+            #[cfg(not(feature = "emit_state_diff"))]
+            state_after: None,
+        }
+    }
+}
+
+impl TryFrom<Trade> for market::Trade {
+    type Error = anyhow::Error;
+
+    fn try_from(t: Trade) -> Result<Self> {
+        Ok(Self {
+            id: t.id,
+            timestamp: TimestampMs::from_secs_f64(t.timestamp),
+            market: t.market,
+            base: t.base,
+            quote: t.quote,
+            price: parse_decimal(&t.price)?,
+            amount: parse_decimal(&t.amount)?,
+            quote_amount: parse_decimal(&t.quote_amount)?,
+            ask_user_id: t.ask_user_id,
+            ask_order_id: t.ask_order_id,
+            ask_role: parse_enum(&t.ask_role)?,
+            ask_fee: parse_decimal(&t.ask_fee)?,
+            bid_user_id: t.bid_user_id,
+            bid_order_id: t.bid_order_id,
+            bid_role: parse_enum(&t.bid_role)?,
+            bid_fee: parse_decimal(&t.bid_fee)?,
+            discount_fee: t
+                .discount_fee
+                .map(|fee| {
+                    Ok::<_, anyhow::Error>(market::DiscountFee {
+                        amount: parse_decimal(&fee.amount)?,
+                        asset: fee.asset,
+                    })
+                })
+                .transpose()?,
+            ask_order: t.ask_order.map(TryInto::try_into).transpose()?,
+            bid_order: t.bid_order.map(TryInto::try_into).transpose()?,
+            #[cfg(feature = "emit_state_diff")]
+            state_before: t.state_before.map(TryInto::try_into).transpose()?.unwrap_or_default(),
+            #[cfg(feature = "emit_state_diff")]
+            state_after: t.state_after.map(TryInto::try_into).transpose()?.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<&market::TradeFeeRecord> for Fee {
+    fn from(f: &market::TradeFeeRecord) -> Self {
+        Self {
+            trade_id: f.trade_id,
+            timestamp: f.timestamp.as_secs_f64(),
+            market: f.market.clone(),
+            user_id: f.user_id,
+            order_id: f.order_id,
+            role: enum_name(&f.role),
+            asset: f.asset.clone(),
+            amount: f.amount.to_string(),
+            rate: f.rate.to_string(),
+        }
+    }
+}
+
+impl TryFrom<Fee> for market::TradeFeeRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(f: Fee) -> Result<Self> {
+        Ok(Self {
+            trade_id: f.trade_id,
+            timestamp: TimestampMs::from_secs_f64(f.timestamp),
+            market: f.market,
+            user_id: f.user_id,
+            order_id: f.order_id,
+            role: parse_enum(&f.role)?,
+            asset: f.asset,
+            amount: parse_decimal(&f.amount)?,
+            rate: parse_decimal(&f.rate)?,
+        })
+    }
+}
+
+impl From<&market::DepthUpdate> for DepthUpdate {
+    fn from(d: &market::DepthUpdate) -> Self {
+        Self {
+            market: d.market.clone(),
+            seq: d.seq,
+            side: enum_name(&d.side),
+            price: d.price.to_string(),
+            new_amount: d.new_amount.to_string(),
+            checksum: d.checksum,
+        }
+    }
+}
+
+impl TryFrom<DepthUpdate> for market::DepthUpdate {
+    type Error = anyhow::Error;
+
+    fn try_from(d: DepthUpdate) -> Result<Self> {
+        Ok(Self {
+            market: d.market,
+            seq: d.seq,
+            side: parse_enum(&d.side)?,
+            price: parse_decimal(&d.price)?,
+            new_amount: parse_decimal(&d.new_amount)?,
+            checksum: d.checksum,
+        })
+    }
+}
+
+impl From<&market::Kline> for Kline {
+    fn from(k: &market::Kline) -> Self {
+        Self {
+            market: k.market.clone(),
+            interval: enum_name(&k.interval),
+            start: k.start,
+            open: k.open.to_string(),
+            high: k.high.to_string(),
+            low: k.low.to_string(),
+            close: k.close.to_string(),
+            volume: k.volume.to_string(),
+            quote_volume: k.quote_volume.to_string(),
+            trade_count: k.trade_count,
+        }
+    }
+}
+
+impl TryFrom<Kline> for market::Kline {
+    type Error = anyhow::Error;
+
+    fn try_from(k: Kline) -> Result<Self> {
+        Ok(Self {
+            market: k.market,
+            interval: parse_enum::<KlineInterval>(&k.interval)?,
+            start: k.start,
+            open: parse_decimal(&k.open)?,
+            high: parse_decimal(&k.high)?,
+            low: parse_decimal(&k.low)?,
+            close: parse_decimal(&k.close)?,
+            volume: parse_decimal(&k.volume)?,
+            quote_volume: parse_decimal(&k.quote_volume)?,
+            trade_count: k.trade_count,
+        })
+    }
+}
+
+impl From<&market::MarketEvent> for MarketEvent {
+    fn from(e: &market::MarketEvent) -> Self {
+        Self {
+            timestamp: e.timestamp.as_secs_f64(),
+            market: e.market.clone(),
+            kind: serde_json::to_string(&e.kind).unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<MarketEvent> for market::MarketEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(e: MarketEvent) -> Result<Self> {
+        Ok(Self {
+            timestamp: TimestampMs::from_secs_f64(e.timestamp),
+            market: e.market,
+            kind: serde_json::from_str(&e.kind)?,
+        })
+    }
+}
+
+impl From<&AuditViolation> for ConservationViolation {
+    fn from(v: &AuditViolation) -> Self {
+        Self {
+            timestamp: v.timestamp,
+            operation: v.operation.clone(),
+            asset: v.asset.clone(),
+            expected_change: v.expected_change.to_string(),
+            actual_change: v.actual_change.to_string(),
+        }
+    }
+}
+
+impl TryFrom<ConservationViolation> for AuditViolation {
+    type Error = anyhow::Error;
+
+    fn try_from(v: ConservationViolation) -> Result<Self> {
+        Ok(Self {
+            timestamp: v.timestamp,
+            operation: v.operation,
+            asset: v.asset,
+            expected_change: parse_decimal(&v.expected_change)?,
+            actual_change: parse_decimal(&v.actual_change)?,
+        })
+    }
+}
+
+impl From<&AuditMismatch> for FrozenMismatch {
+    fn from(m: &AuditMismatch) -> Self {
+        Self {
+            user_id: m.user_id,
+            asset: m.asset.clone(),
+            expected: m.expected.to_string(),
+            actual: m.actual.to_string(),
+            delta: m.delta.to_string(),
+        }
+    }
+}
+
+impl TryFrom<FrozenMismatch> for AuditMismatch {
+    type Error = anyhow::Error;
+
+    fn try_from(m: FrozenMismatch) -> Result<Self> {
+        Ok(Self {
+            user_id: m.user_id,
+            asset: m.asset,
+            expected: parse_decimal(&m.expected)?,
+            actual: parse_decimal(&m.actual)?,
+            delta: parse_decimal(&m.delta)?,
+        })
+    }
+}
+
+impl From<&persist::Tombstone> for Tombstone {
+    fn from(t: &persist::Tombstone) -> Self {
+        Self {
+            time: t.time,
+            from: t.from,
+            to: t.to,
+            order_id: t.order_id,
+            trade_id: t.trade_id,
+        }
+    }
+}
+
+impl From<Tombstone> for persist::Tombstone {
+    fn from(t: Tombstone) -> Self {
+        Self {
+            time: t.time,
+            from: t.from,
+            to: t.to,
+            order_id: t.order_id,
+            trade_id: t.trade_id,
+        }
+    }
+}
+
+impl From<&msg::OperationLogEntry> for OperationLog {
+    fn from(o: &msg::OperationLogEntry) -> Self {
+        Self {
+            id: o.id,
+            time: o.time,
+            method: o.method.clone(),
+            params: o.params.clone(),
+            failed: o.failed,
+        }
+    }
+}
+
+impl From<OperationLog> for msg::OperationLogEntry {
+    fn from(o: OperationLog) -> Self {
+        Self {
+            id: o.id,
+            time: o.time,
+            method: o.method,
+            params: o.params,
+            failed: o.failed,
+        }
+    }
+}
+
+impl From<&msg::BalanceMessage> for Balance {
+    fn from(b: &msg::BalanceMessage) -> Self {
+        Self {
+            timestamp: b.timestamp.as_secs_f64(),
+            user_id: b.user_id,
+            business_id: b.business_id,
+            asset: b.asset.clone(),
+            business: b.business.clone(),
+            market_price: b.market_price.clone(),
+            change: b.change.clone(),
+            balance: b.balance.clone(),
+            balance_available: b.balance_available.clone(),
+            balance_frozen: b.balance_frozen.clone(),
+            detail: b.detail.clone(),
+            signature: b.signature.clone(),
+        }
+    }
+}
+
+impl From<Balance> for msg::BalanceMessage {
+    fn from(b: Balance) -> Self {
+        Self {
+            timestamp: TimestampMs::from_secs_f64(b.timestamp),
+            user_id: b.user_id,
+            business_id: b.business_id,
+            asset: b.asset,
+            business: b.business,
+            market_price: b.market_price,
+            change: b.change,
+            balance: b.balance,
+            balance_available: b.balance_available,
+            balance_frozen: b.balance_frozen,
+            detail: b.detail,
+            signature: b.signature,
+        }
+    }
+}
+
+impl From<&msg::DepositMessage> for Balance {
+    fn from(d: &msg::DepositMessage) -> Self {
+        Self {
+            timestamp: d.timestamp.as_secs_f64(),
+            user_id: d.user_id,
+            asset: d.asset.clone(),
+            business: d.business.clone(),
+            change: d.change.clone(),
+            balance: d.balance.clone(),
+            balance_available: d.balance_available.clone(),
+            balance_frozen: d.balance_frozen.clone(),
+            detail: d.detail.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Balance> for msg::DepositMessage {
+    fn from(b: Balance) -> Self {
+        Self {
+            timestamp: TimestampMs::from_secs_f64(b.timestamp),
+            user_id: b.user_id,
+            asset: b.asset,
+            business: b.business,
+            change: b.change,
+            balance: b.balance,
+            balance_available: b.balance_available,
+            balance_frozen: b.balance_frozen,
+            detail: b.detail,
+        }
+    }
+}
+
+impl From<&msg::WithdrawMessage> for Balance {
+    fn from(w: &msg::WithdrawMessage) -> Self {
+        Self {
+            timestamp: w.timestamp.as_secs_f64(),
+            user_id: w.user_id,
+            asset: w.asset.clone(),
+            business: w.business.clone(),
+            change: w.change.clone(),
+            balance: w.balance.clone(),
+            balance_available: w.balance_available.clone(),
+            balance_frozen: w.balance_frozen.clone(),
+            detail: w.detail.clone(),
+            signature: w.signature.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Balance> for msg::WithdrawMessage {
+    fn from(b: Balance) -> Self {
+        Self {
+            timestamp: TimestampMs::from_secs_f64(b.timestamp),
+            user_id: b.user_id,
+            asset: b.asset,
+            business: b.business,
+            change: b.change,
+            balance: b.balance,
+            balance_available: b.balance_available,
+            balance_frozen: b.balance_frozen,
+            detail: b.detail,
+            signature: b.signature,
+        }
+    }
+}
+
+impl From<&msg::TransferMessage> for Transfer {
+    fn from(t: &msg::TransferMessage) -> Self {
+        Self {
+            time: t.time,
+            user_from: t.user_from,
+            user_to: t.user_to,
+            asset: t.asset.clone(),
+            amount: t.amount.clone(),
+            signature: t.signature.clone(),
+        }
+    }
+}
+
+impl From<Transfer> for msg::TransferMessage {
+    fn from(t: Transfer) -> Self {
+        Self {
+            time: t.time,
+            user_from: t.user_from,
+            user_to: t.user_to,
+            asset: t.asset,
+            amount: t.amount,
+            signature: t.signature,
+        }
+    }
+}
+
+impl From<&msg::UserMessage> for User {
+    fn from(u: &msg::UserMessage) -> Self {
+        Self {
+            user_id: u.user_id,
+            l1_address: u.l1_address.clone(),
+            l2_pubkey: u.l2_pubkey.clone(),
+        }
+    }
+}
+
+impl From<User> for msg::UserMessage {
+    fn from(u: User) -> Self {
+        Self {
+            user_id: u.user_id,
+            l1_address: u.l1_address,
+            l2_pubkey: u.l2_pubkey,
+        }
+    }
+}
+
+impl ToKind for msg::BalanceMessage {
+    fn to_kind(&self) -> Kind {
+        Kind::Balance(self.into())
+    }
+}
+
+impl ToKind for msg::DepositMessage {
+    fn to_kind(&self) -> Kind {
+        Kind::Deposit(self.into())
+    }
+}
+
+impl ToKind for msg::WithdrawMessage {
+    fn to_kind(&self) -> Kind {
+        Kind::Withdraw(self.into())
+    }
+}
+
+impl ToKind for msg::TransferMessage {
+    fn to_kind(&self) -> Kind {
+        Kind::Transfer(self.into())
+    }
+}
+
+impl ToKind for msg::OrderMessage {
+    fn to_kind(&self) -> Kind {
+        Kind::Order(self.into())
+    }
+}
+
+impl ToKind for market::Trade {
+    fn to_kind(&self) -> Kind {
+        Kind::Trade(self.into())
+    }
+}
+
+impl ToKind for market::TradeFeeRecord {
+    fn to_kind(&self) -> Kind {
+        Kind::Fee(self.into())
+    }
+}
+
+impl ToKind for market::MarketEvent {
+    fn to_kind(&self) -> Kind {
+        Kind::MarketEvent(self.into())
+    }
+}
+
+impl ToKind for msg::UserMessage {
+    fn to_kind(&self) -> Kind {
+        Kind::User(self.into())
+    }
+}
+
+impl ToKind for msg::Message {
+    fn to_kind(&self) -> Kind {
+        match self {
+            msg::Message::BalanceMessage(m) => Kind::Balance(m.as_ref().into()),
+            msg::Message::DepositMessage(m) => Kind::Deposit(m.as_ref().into()),
+            msg::Message::OrderMessage(m) => Kind::Order(m.as_ref().into()),
+            msg::Message::TradeMessage(m) => Kind::Trade(m.as_ref().into()),
+            msg::Message::FeeMessage(m) => Kind::Fee(m.as_ref().into()),
+            msg::Message::DepthUpdateMessage(m) => Kind::DepthUpdate(m.as_ref().into()),
+            msg::Message::KlineMessage(m) => Kind::Kline(m.as_ref().into()),
+            msg::Message::MarketEventMessage(m) => Kind::MarketEvent(m.as_ref().into()),
+            msg::Message::ConservationViolationMessage(m) => Kind::ConservationViolation(m.as_ref().into()),
+            msg::Message::FrozenDeficitMessage(m) => Kind::FrozenDeficit(m.as_ref().into()),
+            msg::Message::TransferMessage(m) => Kind::Transfer(m.as_ref().into()),
+            msg::Message::UserMessage(m) => Kind::User(m.as_ref().into()),
+            msg::Message::WithdrawMessage(m) => Kind::Withdraw(m.as_ref().into()),
+            msg::Message::TombstoneMessage(m) => Kind::Tombstone(m.as_ref().into()),
+            msg::Message::OperationLogMessage(m) => Kind::OperationLog(m.as_ref().into()),
+        }
+    }
+}
+
+impl TryFrom<Kind> for msg::Message {
+    type Error = anyhow::Error;
+
+    fn try_from(kind: Kind) -> Result<Self> {
+        Ok(match kind {
+            Kind::Balance(m) => msg::Message::BalanceMessage(Box::new(m.into())),
+            Kind::Deposit(m) => msg::Message::DepositMessage(Box::new(m.into())),
+            Kind::Order(m) => msg::Message::OrderMessage(Box::new(m.try_into()?)),
+            Kind::Trade(m) => msg::Message::TradeMessage(Box::new(m.try_into()?)),
+            Kind::Fee(m) => msg::Message::FeeMessage(Box::new(m.try_into()?)),
+            Kind::DepthUpdate(m) => msg::Message::DepthUpdateMessage(Box::new(m.try_into()?)),
+            Kind::Kline(m) => msg::Message::KlineMessage(Box::new(m.try_into()?)),
+            Kind::MarketEvent(m) => msg::Message::MarketEventMessage(Box::new(m.try_into()?)),
+            Kind::ConservationViolation(m) => msg::Message::ConservationViolationMessage(Box::new(m.try_into()?)),
+            Kind::FrozenDeficit(m) => msg::Message::FrozenDeficitMessage(Box::new(m.try_into()?)),
+            Kind::Transfer(m) => msg::Message::TransferMessage(Box::new(m.into())),
+            Kind::User(m) => msg::Message::UserMessage(Box::new(m.into())),
+            Kind::Withdraw(m) => msg::Message::WithdrawMessage(Box::new(m.into())),
+            Kind::Tombstone(m) => msg::Message::TombstoneMessage(Box::new(m.into())),
+            Kind::OperationLog(m) => msg::Message::OperationLogMessage(Box::new(m.into())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn order_json(id: u64) -> serde_json::Value {
+        json!({
+            "id": id, "base": "ETH", "quote": "USDT", "market": "ETH_USDT", "type": "LIMIT", "side": "BID",
+            "user": 7, "post_only": true, "signature": "ab".repeat(64), "price": "1850.25", "amount": "1.5000",
+            "maker_fee": "0.001", "taker_fee": "0.002", "create_time": 1630000000125u64, "priority": id, "client_order_id": 42,
+            "remain": "0.5000", "frozen": "925.125", "finished_base": "1.0000", "finished_quote": "1850.25",
+            "finished_fee": "0.00185", "update_time": 1630000001500u64,
+        })
+    }
+
+    fn trade_json(id: u64) -> serde_json::Value {
+        json!({
+            "id": id, "timestamp": 1630000001500u64, "market": "ETH_USDT", "base": "ETH", "quote": "USDT",
+            "price": "1850.25", "amount": "1.0000", "quote_amount": "1850.25",
+            "ask_user_id": 8, "ask_order_id": 3, "ask_role": "MAKER", "ask_fee": "-0.0001",
+            "bid_user_id": 7, "bid_order_id": id, "bid_role": "TAKER", "bid_fee": "0",
+            "discount_fee": {"asset": "DIS", "amount": "0.5"},
+            "ask_order": null, "bid_order": order_json(id),
+            "state_before": {
+                "order_states": [{"user_id": 7, "order_id": id, "order_side": "BID", "finished_base": "0",
+                    "finished_quote": "0", "finished_fee": "0"}],
+                "balance_states": [{"user_id": 7, "asset": "USDT", "balance": "10000"}],
+            },
+            "state_after": {
+                "order_states": [{"user_id": 7, "order_id": id, "order_side": "BID", "finished_base": "1.0000",
+                    "finished_quote": "1850.25", "finished_fee": "0"}],
+                "balance_states": [{"user_id": 7, "asset": "USDT", "balance": "8149.75"}],
+            },
+        })
+    }
+
+    fn balance_json() -> serde_json::Value {
+        json!({
+            "timestamp": 1630000000000u64, "user_id": 7, "business_id": 11, "asset": "USDT", "business": "trade",
+            "market_price": "1", "change": "-1850.25", "balance": "8149.75", "balance_available": "8149.75",
+            "balance_frozen": "0", "detail": "{\"id\":11}", "signature": "",
+        })
+    }
+
+    fn messages() -> Vec<serde_json::Value> {
+        vec![
+            json!({"type": "BalanceMessage", "value": balance_json()}),
+            json!({"type": "DepositMessage", "value": balance_json()}),
+            json!({"type": "WithdrawMessage", "value": balance_json()}),
+            json!({"type": "OrderMessage", "value": {"event": "PUT", "order": order_json(1), "base": "ETH", "quote": "USDT"}}),
+            json!({"type": "OrderMessage", "value": {
+                "event": "FINISH", "order": order_json(1), "base": "ETH", "quote": "USDT",
+                "cancel_reason": "self_trade", "finish_reason": "self_trade", "finish_actor": "system",
+            }}),
+            json!({"type": "OrderMessage", "value": {
+                "event": "UPDATE", "order": order_json(1), "base": "ETH", "quote": "USDT", "order_before": order_json(1),
+            }}),
+            json!({"type": "TradeMessage", "value": trade_json(5)}),
+            json!({"type": "FeeMessage", "value": {
+                "trade_id": 5, "timestamp": 1630000001500u64, "market": "ETH_USDT", "user_id": 8, "order_id": 3,
+                "role": "MAKER", "asset": "USDT", "amount": "-0.0001", "rate": "-0.0001",
+            }}),
+            json!({"type": "DepthUpdateMessage", "value": {
+                "market": "ETH_USDT", "seq": 9, "side": "ASK", "price": "1850.25", "new_amount": "0", "checksum": 123456,
+            }}),
+            json!({"type": "KlineMessage", "value": {
+                "market": "ETH_USDT", "interval": "1m", "start": 1629999960, "open": "1850", "high": "1851",
+                "low": "1849.5", "close": "1850.25", "volume": "3.5", "quote_volume": "6475.5", "trade_count": 4,
+            }}),
+            json!({"type": "MarketEventMessage", "value": {
+                "timestamp": 1630000000000u64, "market": "ETH_USDT", "event": "halted", "state": "cancel_only", "reason": "maintenance",
+            }}),
+            json!({"type": "ConservationViolationMessage", "value": {
+                "timestamp": 1630000000.0, "operation": "deposit", "asset": "USDT", "expected_change": "10", "actual_change": "20",
+            }}),
+            json!({"type": "FrozenDeficitMessage", "value": {
+                "user_id": 7, "asset": "USDT", "expected": "925.125", "actual": "900", "delta": "-25.125",
+            }}),
+            json!({"type": "TransferMessage", "value": {
+                "time": 1630000000.0, "user_from": 7, "user_to": 8, "asset": "USDT", "amount": "10.5", "signature": "",
+            }}),
+            json!({"type": "UserMessage", "value": {"user_id": 7, "l1_address": "0xabc", "l2_pubkey": "0xdef"}}),
+            json!({"type": "TombstoneMessage", "value": {
+                "time": 1630000000.5, "from": 121, "to": 200, "order_id": 310, "trade_id": 95,
+            }}),
+            json!({"type": "OperationLogMessage", "value": {
+                "id": 201, "time": 1630000001.25, "method": "order_cancel",
+                "params": "{\"user_id\":7,\"market\":\"ETH_USDT\",\"order_id\":310}", "failed": false,
+            }}),
+        ]
+    }
+
+    #[test]
+    fn test_proto_round_trip() {
+        for value in messages() {
+            let message: msg::Message = serde_json::from_value(value.clone()).unwrap();
+            let bytes = Event::new(3, 1630000002.0, message.to_kind()).to_bytes();
+            let decoded = decode(&bytes).unwrap();
+            assert_eq!((decoded.seq, decoded.ts), (3, 1630000002.0));
+            assert_eq!(decoded.kind, value["type"]);
+            // the same json as the original one, the decimals keep their scales
+            assert_eq!(serde_json::to_value(&decoded.payload).unwrap(), value, "{}", decoded.kind);
+        }
+
+        // the messages the messenger sends besides the variants
+        let deposit: msg::DepositMessage = serde_json::from_value(balance_json()).unwrap();
+        match decode(&Event::new(0, 0.0, deposit.to_kind()).to_bytes()).unwrap().payload {
+            msg::Message::DepositMessage(balance) => {
+                let decoded: msg::DepositMessage = proto_balance(&balance).into();
+                assert_eq!(serde_json::to_value(decoded).unwrap(), serde_json::to_value(&deposit).unwrap());
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        let trade: market::Trade = serde_json::from_value(trade_json(5)).unwrap();
+        assert_eq!(entity_key(&Event::new(0, 0.0, trade.to_kind()).to_bytes()), "ETH_USDT");
+        let user = msg::UserMessage {
+            user_id: 7,
+            l1_address: String::new(),
+            l2_pubkey: String::new(),
+        };
+        assert_eq!(entity_key(&Event::new(0, 0.0, user.to_kind()).to_bytes()), "7");
+        assert!(decode(b"not protobuf").is_err());
+        assert_eq!(entity_key(b"not protobuf"), "");
+    }
+
+    fn proto_balance(balance: &msg::BalanceMessage) -> Balance {
+        balance.into()
+    }
+
+    #[test]
+    fn test_payload_size() {
+        let trades = (0..100)
+            .map(|id| serde_json::from_value::<market::Trade>(trade_json(id)).unwrap())
+            .collect::<Vec<_>>();
+        let json = trades.iter().map(|trade| serde_json::to_vec(trade).unwrap()).collect::<Vec<_>>();
+        let protobuf = trades
+            .iter()
+            .map(|trade| Event::new(trade.id, trade.timestamp.as_secs_f64(), trade.to_kind()).to_bytes())
+            .collect::<Vec<_>>();
+        // every trade is smaller, and decoded into the same message as the json
+        for ((trade, json), protobuf) in trades.iter().zip(&json).zip(&protobuf) {
+            assert!(protobuf.len() < json.len());
+            assert_eq!(
+                serde_json::to_value(decode(protobuf).unwrap().payload).unwrap(),
+                serde_json::to_value(msg::Message::TradeMessage(Box::new(trade.clone()))).unwrap()
+            );
+        }
+    }
+}
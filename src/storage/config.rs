@@ -30,6 +30,7 @@ impl From<MarketDesc> for config::Market {
             fee_prec: origin.precision_fee as u32,
             name: market_name,
             min_amount: origin.min_amount,
+            ..Default::default()
         }
     }
 }
@@ -36,8 +36,14 @@ pub mod tablenames {
     pub const ORDERSLICE: &str = "order_slice";
     pub const BALANCESLICE: &str = "balance_slice";
     pub const SLICEHISTORY: &str = "slice_history";
+    pub const WITHDRAWSLICE: &str = "withdraw_slice";
+    pub const LOCKSLICE: &str = "lock_slice";
+    pub const NONCESLICE: &str = "nonce_slice";
     pub const MARKETTRADE: &str = "market_trade";
     pub const INTERNALTX: &str = "internal_tx";
+    pub const TRADEFEE: &str = "trade_fee";
+    pub const MARKETEVENT: &str = "market_event";
+    pub const TOMBSTONE: &str = "operation_log_tombstone";
 }
 
 use tablenames::*;
@@ -126,6 +132,10 @@ pub struct OrderHistory {
     pub finished_fee: DecimalDbType,
     pub post_only: bool,
     pub signature: Vec<u8>,
+    pub client_order_id: Option<i64>,
+    // none for the orders finished before the reasons were recorded
+    pub finish_reason: Option<types::FinishReason>,
+    pub finish_actor: Option<types::OrderActor>,
 }
 
 #[derive(sqlx::FromRow, Debug, Clone)]
@@ -145,6 +155,38 @@ pub struct UserTrade {
     pub counter_order_fee: DecimalDbType,
 }
 
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct TradeFee {
+    pub time: TimestampDbType,
+    pub trade_id: i64,
+    pub market: String,
+    pub user_id: i32,
+    pub order_id: i64,
+    pub role: i16,
+    pub asset: String,
+    pub amount: DecimalDbType,
+    pub rate: DecimalDbType,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct MarketEventHistory {
+    pub time: TimestampDbType,
+    pub market: String,
+    pub event: String,
+    // the event in json
+    pub detail: String,
+}
+
+// the operations `from_id..=to_id` superseded by a rollback
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct OperationLogTombstone {
+    pub time: TimestampDbType,
+    pub from_id: i64,
+    pub to_id: i64,
+    pub end_order_id: i64,
+    pub end_trade_id: i64,
+}
+
 // Can the following struct be auto generated in diesel?
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct OperationLog {
@@ -153,6 +195,8 @@ pub struct OperationLog {
     pub method: String,
     // TODO: change it to jsonb
     pub params: String,
+    // the operation failed after it changed the state, so its replay fails too
+    pub failed: bool,
 }
 
 //Notice this is used for query the full columns but not for insert
@@ -199,6 +243,37 @@ pub struct OrderSlice {
     pub finished_fee: DecimalDbType,
     pub post_only: bool,
     pub signature: Vec<u8>,
+    pub priority: i64,
+    pub client_order_id: Option<i64>,
+}
+
+// the pending withdraws of a slice, their amounts are in the WITHDRAWING balances
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct WithdrawSlice {
+    pub slice_id: i64,
+    pub business_id: i64,
+    pub user_id: i32,
+    pub asset: String,
+    pub business: String,
+    pub amount: DecimalDbType,
+}
+
+// the balance locks of a slice, their amounts are in the LOCK balances
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct LockSlice {
+    pub slice_id: i64,
+    pub lock_id: i64,
+    pub user_id: i32,
+    pub asset: String,
+    pub amount: DecimalDbType,
+}
+
+// the last nonce of each user in a slice
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct NonceSlice {
+    pub slice_id: i64,
+    pub user_id: i32,
+    pub nonce: i64,
 }
 
 // xx_id here means the last persisted entry id
@@ -208,6 +283,8 @@ pub struct SliceHistory {
     pub end_operation_log_id: i64,
     pub end_order_id: i64,
     pub end_trade_id: i64,
+    // the seq of the last message, zero in the slices made before it is saved
+    pub end_msg_id: i64,
 }
 
 #[derive(sqlx::FromRow, Debug, Clone, Serialize, Apiv2Schema)]
@@ -340,12 +417,75 @@ impl sqlxextend::BindQueryArg<'_, DbType> for UserTrade {
 
 impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for UserTrade {}
 
+/* --------------------- models::TradeFee -----------------------------*/
+impl sqlxextend::TableSchemas for TradeFee {
+    fn table_name() -> &'static str {
+        TRADEFEE
+    }
+    const ARGN: i32 = 9;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for TradeFee {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.time);
+        arg.add(self.trade_id);
+        arg.add(&self.market);
+        arg.add(self.user_id);
+        arg.add(self.order_id);
+        arg.add(self.role);
+        arg.add(&self.asset);
+        arg.add(&self.amount);
+        arg.add(&self.rate);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for TradeFee {}
+
+/* --------------------- models::MarketEventHistory -----------------------------*/
+impl sqlxextend::TableSchemas for MarketEventHistory {
+    fn table_name() -> &'static str {
+        MARKETEVENT
+    }
+    const ARGN: i32 = 4;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for MarketEventHistory {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.time);
+        arg.add(&self.market);
+        arg.add(&self.event);
+        arg.add(&self.detail);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for MarketEventHistory {}
+
+/* --------------------- models::OperationLogTombstone -----------------------------*/
+impl sqlxextend::TableSchemas for OperationLogTombstone {
+    fn table_name() -> &'static str {
+        TOMBSTONE
+    }
+    const ARGN: i32 = 5;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for OperationLogTombstone {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.time);
+        arg.add(self.from_id);
+        arg.add(self.to_id);
+        arg.add(self.end_order_id);
+        arg.add(self.end_trade_id);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for OperationLogTombstone {}
+
 /* --------------------- models::OrderHistory -----------------------------*/
 impl sqlxextend::TableSchemas for OrderHistory {
     fn table_name() -> &'static str {
         ORDERHISTORY
     }
-    const ARGN: i32 = 17;
+    const ARGN: i32 = 20;
     //fn default_argsn() -> Vec<i32>{ vec![1] }
 }
 
@@ -368,6 +508,9 @@ impl sqlxextend::BindQueryArg<'_, DbType> for OrderHistory {
         arg.add(&self.status);
         arg.add(&self.post_only);
         arg.add(&self.signature);
+        arg.add(self.client_order_id);
+        arg.add(self.finish_reason);
+        arg.add(self.finish_actor);
     }
 }
 
@@ -375,7 +518,7 @@ impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for OrderHistor
 
 /* --------------------- models::OperationLog -----------------------------*/
 impl sqlxextend::TableSchemas for OperationLog {
-    const ARGN: i32 = 4;
+    const ARGN: i32 = 5;
     fn table_name() -> &'static str {
         OPERATIONLOG
     }
@@ -387,6 +530,7 @@ impl sqlxextend::BindQueryArg<'_, DbType> for OperationLog {
         arg.add(self.time);
         arg.add(&self.method);
         arg.add(&self.params);
+        arg.add(self.failed);
     }
 }
 
@@ -398,7 +542,7 @@ impl sqlxextend::TableSchemas for OrderSlice {
     fn table_name() -> &'static str {
         ORDERSLICE
     }
-    const ARGN: i32 = 19;
+    const ARGN: i32 = 21;
     //fn default_argsn() -> Vec<i32>{ vec![1] }
 }
 
@@ -423,6 +567,8 @@ impl sqlxextend::BindQueryArg<'_, DbType> for OrderSlice {
         arg.add(&self.finished_fee);
         arg.add(&self.post_only);
         arg.add(&self.signature);
+        arg.add(self.priority);
+        arg.add(self.client_order_id);
     }
 }
 
@@ -452,13 +598,75 @@ impl sqlxextend::BindQueryArg<'_, DbType> for BalanceSliceInsert {
 
 impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for BalanceSliceInsert {}
 
+/* --------------------- models::WithdrawSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for WithdrawSlice {
+    fn table_name() -> &'static str {
+        WITHDRAWSLICE
+    }
+    const ARGN: i32 = 6;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for WithdrawSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.business_id);
+        arg.add(self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.business);
+        arg.add(&self.amount);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for WithdrawSlice {}
+
+/* --------------------- models::LockSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for LockSlice {
+    fn table_name() -> &'static str {
+        LOCKSLICE
+    }
+    const ARGN: i32 = 5;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for LockSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.lock_id);
+        arg.add(self.user_id);
+        arg.add(&self.asset);
+        arg.add(&self.amount);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for LockSlice {}
+
+/* --------------------- models::NonceSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for NonceSlice {
+    fn table_name() -> &'static str {
+        NONCESLICE
+    }
+    const ARGN: i32 = 3;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for NonceSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(self.user_id);
+        arg.add(self.nonce);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for NonceSlice {}
+
 /* --------------------- models::SliceHistory -----------------------------*/
 
 impl sqlxextend::TableSchemas for SliceHistory {
     fn table_name() -> &'static str {
         SLICEHISTORY
     }
-    const ARGN: i32 = 4;
+    const ARGN: i32 = 5;
     fn default_argsn() -> Vec<i32> {
         vec![1]
     }
@@ -470,6 +678,7 @@ impl sqlxextend::BindQueryArg<'_, DbType> for SliceHistory {
         arg.add(self.end_operation_log_id);
         arg.add(self.end_order_id);
         arg.add(self.end_trade_id);
+        arg.add(self.end_msg_id);
     }
 }
 
@@ -2,6 +2,9 @@ use num_enum::TryFromPrimitive;
 use paperclip::actix::Apiv2Schema;
 use serde::{Deserialize, Serialize};
 
+mod timestamp;
+pub use timestamp::TimestampMs;
+
 pub type SimpleResult = anyhow::Result<()>;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, TryFromPrimitive)]
@@ -35,10 +38,135 @@ pub enum OrderType {
 pub enum OrderEventType {
     PUT = 1,
     UPDATE = 2,
+    // filled or canceled alike, there is no event of its own for a cancellation.
+    // the consumers tell them apart by the `finish_reason` of the message
     FINISH = 3,
     EXPIRED = 4,
 }
 
+impl OrderEventType {
+    // the order is removed from the market after a terminal event
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, OrderEventType::FINISH | OrderEventType::EXPIRED)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderCancelReason {
+    // a post only order would cross the book
+    PostOnlyCross,
+    // the order would match another order of the same user
+    SelfTrade,
+    // the execution price would leave the allowed price band
+    PriceDeviation,
+    // the resting order does not fit the new precision or min amount of the market
+    MarketParamsChanged,
+    // the balances could not be updated for a trade, nothing of the trade is applied
+    SettlementFailed,
+    // an invariant of the matching is broken, the market is halted
+    InvariantViolated,
+    // the rest of a market order, the book ran out or the matching stopped before the order was filled
+    Unfilled,
+    // the balance checked when the order was put was taken before the rest of the order could be frozen
+    BalanceNotEnough,
+    // an amount of a trade overflows, the taker is canceled before the trade
+    AmountOverflow,
+}
+
+// why an order is finished, either leaving the book or never put into it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Filled,
+    // the rest of a market order found no counter orders, reported with the `Unfilled` cancel reason
+    Unmatched,
+    // the remain is below the min amount of the market
+    Dust,
+    // canceled on request
+    Canceled,
+    Expired,
+    MarketClosed,
+    PostOnlyCross,
+    SelfTrade,
+    PriceDeviation,
+    MarketParamsChanged,
+    SettlementFailed,
+    InvariantViolated,
+    BalanceNotEnough,
+    AmountOverflow,
+}
+
+impl From<OrderCancelReason> for FinishReason {
+    fn from(reason: OrderCancelReason) -> Self {
+        match reason {
+            OrderCancelReason::PostOnlyCross => FinishReason::PostOnlyCross,
+            OrderCancelReason::SelfTrade => FinishReason::SelfTrade,
+            OrderCancelReason::PriceDeviation => FinishReason::PriceDeviation,
+            OrderCancelReason::MarketParamsChanged => FinishReason::MarketParamsChanged,
+            OrderCancelReason::SettlementFailed => FinishReason::SettlementFailed,
+            OrderCancelReason::InvariantViolated => FinishReason::InvariantViolated,
+            OrderCancelReason::Unfilled => FinishReason::Unmatched,
+            OrderCancelReason::BalanceNotEnough => FinishReason::BalanceNotEnough,
+            OrderCancelReason::AmountOverflow => FinishReason::AmountOverflow,
+        }
+    }
+}
+
+impl FinishReason {
+    // the orders canceled by the engine, reported as FINISH with the reason in the `cancel_reason` of the message
+    pub fn cancel_reason(self) -> Option<OrderCancelReason> {
+        match self {
+            FinishReason::PostOnlyCross => Some(OrderCancelReason::PostOnlyCross),
+            FinishReason::SelfTrade => Some(OrderCancelReason::SelfTrade),
+            FinishReason::PriceDeviation => Some(OrderCancelReason::PriceDeviation),
+            FinishReason::MarketParamsChanged => Some(OrderCancelReason::MarketParamsChanged),
+            FinishReason::SettlementFailed => Some(OrderCancelReason::SettlementFailed),
+            FinishReason::InvariantViolated => Some(OrderCancelReason::InvariantViolated),
+            FinishReason::Unmatched => Some(OrderCancelReason::Unfilled),
+            FinishReason::BalanceNotEnough => Some(OrderCancelReason::BalanceNotEnough),
+            FinishReason::AmountOverflow => Some(OrderCancelReason::AmountOverflow),
+            _ => None,
+        }
+    }
+}
+
+// who made an order finish
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, sqlx::Type, Apiv2Schema)]
+#[sqlx(type_name = "varchar")]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum OrderActor {
+    User,
+    Admin,
+    // the matching engine itself
+    System,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct OrderFinish {
+    pub reason: FinishReason,
+    pub actor: OrderActor,
+}
+
+impl OrderFinish {
+    pub fn new(reason: FinishReason, actor: OrderActor) -> Self {
+        Self { reason, actor }
+    }
+    pub fn system(reason: FinishReason) -> Self {
+        Self::new(reason, OrderActor::System)
+    }
+    // the event of the order messages, the same as before the reasons were added
+    pub fn event(&self) -> OrderEventType {
+        match self.reason {
+            FinishReason::Expired => OrderEventType::EXPIRED,
+            _ => OrderEventType::FINISH,
+        }
+    }
+}
+
 //pub type DbType = diesel::mysql::Mysql;
 //pub type ConnectionType = diesel::mysql::MysqlConnection;
 pub type DbType = sqlx::Postgres;
@@ -0,0 +1,138 @@
+use chrono::NaiveDateTime;
+use core::fmt;
+use fluidex_common::utils::timeutil::{current_timestamp, FTimestamp};
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+// milliseconds since the unix epoch, exact unlike the float seconds it replaces.
+// serialized as an integer, the legacy float seconds are still accepted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimestampMs(pub u64);
+
+impl TimestampMs {
+    pub fn now() -> Self {
+        Self::from_secs_f64(current_timestamp())
+    }
+    pub fn from_secs_f64(secs: f64) -> Self {
+        TimestampMs((secs * 1000.0).round().max(0.0) as u64)
+    }
+    pub fn as_millis(self) -> u64 {
+        self.0
+    }
+    pub fn as_secs(self) -> u64 {
+        self.0 / 1000
+    }
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+}
+
+impl From<FTimestamp> for TimestampMs {
+    fn from(ts: FTimestamp) -> Self {
+        Self::from_secs_f64(ts.0)
+    }
+}
+
+impl From<TimestampMs> for FTimestamp {
+    fn from(ts: TimestampMs) -> Self {
+        FTimestamp(ts.as_secs_f64())
+    }
+}
+
+impl From<&NaiveDateTime> for TimestampMs {
+    fn from(time: &NaiveDateTime) -> Self {
+        TimestampMs(time.timestamp_millis().max(0) as u64)
+    }
+}
+
+impl From<TimestampMs> for NaiveDateTime {
+    fn from(ts: TimestampMs) -> Self {
+        NaiveDateTime::from_timestamp((ts.0 / 1000) as i64, (ts.0 % 1000) as u32 * 1_000_000)
+    }
+}
+
+impl Serialize for TimestampMs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimestampMs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TimestampVisitor;
+
+        impl<'de> Visitor<'de> for TimestampVisitor {
+            type Value = TimestampMs;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("milliseconds as an integer or seconds as a float")
+            }
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(TimestampMs(v))
+            }
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                if v < 0 {
+                    return Err(E::invalid_value(Unexpected::Signed(v), &self));
+                }
+                Ok(TimestampMs(v as u64))
+            }
+            // written before the milliseconds, e.g. 1628000000.123
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                if !v.is_finite() || v < 0.0 {
+                    return Err(E::invalid_value(Unexpected::Float(v), &self));
+                }
+                Ok(TimestampMs::from_secs_f64(v))
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market::Order;
+    use crate::message::OrderMessage;
+    use serde_json::json;
+
+    #[test]
+    fn test_legacy_order_message() {
+        let order = |create_time: serde_json::Value, update_time: serde_json::Value| {
+            json!({
+                "id": 3, "base": "ETH", "quote": "USDT", "market": "ETH_USDT", "type": "LIMIT", "side": "BID", "user": 7,
+                "post_only": false, "signature": "00".repeat(64), "price": "100", "amount": "1", "maker_fee": "0.001",
+                "taker_fee": "0.002", "create_time": create_time, "remain": "1", "frozen": "100", "finished_base": "0",
+                "finished_quote": "0", "finished_fee": "0", "update_time": update_time,
+            })
+        };
+        let message = |order: serde_json::Value| json!({"event": "PUT", "order": order, "base": "ETH", "quote": "USDT"});
+
+        // float seconds with the float noise, rounded to the millisecond
+        let legacy = message(order(json!(1628000000.1230001), json!(1628000001.5)));
+        let legacy: OrderMessage = serde_json::from_value(legacy).unwrap();
+        assert_eq!(legacy.order.create_time, TimestampMs(1628000000123));
+        assert_eq!(legacy.order.update_time, TimestampMs(1628000001500));
+        assert_eq!(
+            serde_json::from_value::<Order>(order(json!(0.0), json!(1628000000.0009)))
+                .unwrap()
+                .update_time,
+            TimestampMs(1628000000001)
+        );
+
+        // written back as integers, read back the same
+        let json = serde_json::to_value(&legacy).unwrap();
+        assert_eq!(json["order"]["create_time"], json!(1628000000123u64));
+        let current: OrderMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(current.order.create_time, legacy.order.create_time);
+
+        assert!(serde_json::from_value::<TimestampMs>(json!(-1)).is_err());
+        assert!(serde_json::from_value::<TimestampMs>(json!("1628000000")).is_err());
+        let time = NaiveDateTime::from(TimestampMs(1628000000123));
+        assert_eq!(TimestampMs::from(&time), TimestampMs(1628000000123));
+        assert_eq!(
+            TimestampMs::from(FTimestamp::from(TimestampMs(1628000000123))),
+            TimestampMs(1628000000123)
+        );
+    }
+}
@@ -15,7 +15,8 @@ pub fn intern_string(s: &str) -> &'static str {
         .or_insert_with(|| Box::leak(s.to_string().into_boxed_str()))
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+// compared and hashed by the content, the same string may be leaked more than once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct InternedString(&'static str);
 
 impl From<&'static str> for InternedString {